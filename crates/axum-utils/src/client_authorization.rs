@@ -79,18 +79,22 @@ pub enum Credentials {
 }
 
 impl Credentials {
-    pub async fn fetch(
-        &self,
-        executor: impl PgExecutor<'_>,
-    ) -> Result<Client<PostgresqlBackend>, ClientFetchError> {
-        let client_id = match self {
+    /// The ID of the client these credentials are for
+    #[must_use]
+    pub fn client_id(&self) -> &str {
+        match self {
             Credentials::None { client_id }
             | Credentials::ClientSecretBasic { client_id, .. }
             | Credentials::ClientSecretPost { client_id, .. }
             | Credentials::ClientAssertionJwtBearer { client_id, .. } => client_id,
-        };
+        }
+    }
 
-        lookup_client_by_client_id(executor, client_id).await
+    pub async fn fetch(
+        &self,
+        executor: impl PgExecutor<'_>,
+    ) -> Result<Client<PostgresqlBackend>, ClientFetchError> {
+        lookup_client_by_client_id(executor, self.client_id()).await
     }
 
     #[tracing::instrument(skip_all, err)]
@@ -111,15 +115,7 @@ impl Credentials {
                 Credentials::ClientSecretBasic { client_secret, .. },
                 OAuthClientAuthenticationMethod::ClientSecretBasic,
             ) => {
-                // Decrypt the client_secret
-                let encrypted_client_secret = client
-                    .encrypted_client_secret
-                    .as_ref()
-                    .ok_or(CredentialsVerificationError::InvalidClientConfig)?;
-
-                let decrypted_client_secret = encrypter
-                    .decrypt_string(encrypted_client_secret)
-                    .map_err(|_e| CredentialsVerificationError::DecryptionError)?;
+                let decrypted_client_secret = decrypt_client_secret(encrypter, client)?;
 
                 // Check if the client_secret matches
                 if client_secret.as_bytes() != decrypted_client_secret {
@@ -147,15 +143,7 @@ impl Credentials {
                 Credentials::ClientAssertionJwtBearer { jwt, header, .. },
                 OAuthClientAuthenticationMethod::ClientSecretJwt,
             ) => {
-                // Decrypt the client_secret
-                let encrypted_client_secret = client
-                    .encrypted_client_secret
-                    .as_ref()
-                    .ok_or(CredentialsVerificationError::InvalidClientConfig)?;
-
-                let decrypted_client_secret = encrypter
-                    .decrypt_string(encrypted_client_secret)
-                    .map_err(|_e| CredentialsVerificationError::DecryptionError)?;
+                let decrypted_client_secret = decrypt_client_secret(encrypter, client)?;
 
                 let store = SharedSecret::new(&decrypted_client_secret);
                 let fut = jwt.verify(header, &store);
@@ -171,6 +159,21 @@ impl Credentials {
     }
 }
 
+/// Decrypt the secret of a client, which is bound to its client ID
+fn decrypt_client_secret<S: StorageBackend>(
+    encrypter: &Encrypter,
+    client: &Client<S>,
+) -> Result<Vec<u8>, CredentialsVerificationError> {
+    let encrypted_client_secret = client
+        .encrypted_client_secret
+        .as_ref()
+        .ok_or(CredentialsVerificationError::InvalidClientConfig)?;
+
+    encrypter
+        .decrypt_string_with_ad(client.client_id.as_bytes(), encrypted_client_secret)
+        .map_err(|_e| CredentialsVerificationError::DecryptionError)
+}
+
 fn jwks_key_store(jwks: &JwksOrJwksUri) -> Either<StaticJwksStore, DynamicJwksStore> {
     // Assert that the output is both a VerifyingKeystore and Send
     fn assert<T: Send + VerifyingKeystore>(t: T) -> T {
@@ -401,9 +404,38 @@ where
 mod tests {
     use axum::body::{Bytes, Full};
     use http::{Method, Request};
+    use mas_data_model::ClientType;
 
     use super::*;
 
+    fn client(encrypter: &Encrypter, client_secret: &str) -> Client<()> {
+        Client {
+            data: (),
+            client_id: "client-id".to_string(),
+            client_type: ClientType::Confidential,
+            encrypted_client_secret: Some(
+                encrypter
+                    .encrypt_to_string_with_ad(b"client-id", client_secret.as_bytes())
+                    .unwrap(),
+            ),
+            redirect_uris: Vec::new(),
+            response_types: Vec::new(),
+            grant_types: Vec::new(),
+            contacts: Vec::new(),
+            client_name: None,
+            logo_uri: None,
+            client_uri: None,
+            policy_uri: None,
+            tos_uri: None,
+            jwks: None,
+            id_token_signed_response_alg: None,
+            userinfo_signed_response_alg: None,
+            token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::ClientSecretBasic),
+            token_endpoint_auth_signing_alg: None,
+            initiate_login_uri: None,
+        }
+    }
+
     #[tokio::test]
     async fn none_test() {
         let mut req = RequestParts::new(
@@ -599,4 +631,69 @@ mod tests {
         assert_eq!(client_id, "client-id");
         // TODO: test more things
     }
+
+    #[tokio::test]
+    async fn client_secret_basic_verification() {
+        let encrypter = Encrypter::new(&[0x42; 32]);
+        let client = client(&encrypter, "client-secret");
+        let method = OAuthClientAuthenticationMethod::ClientSecretBasic;
+
+        let credentials = Credentials::ClientSecretBasic {
+            client_id: "client-id".to_string(),
+            client_secret: "client-secret".to_string(),
+        };
+        assert!(credentials
+            .verify(&encrypter, method, &client)
+            .await
+            .is_ok());
+
+        let credentials = Credentials::ClientSecretBasic {
+            client_id: "client-id".to_string(),
+            client_secret: "wrong-secret".to_string(),
+        };
+        assert!(matches!(
+            credentials.verify(&encrypter, method, &client).await,
+            Err(CredentialsVerificationError::ClientSecretMismatch),
+        ));
+
+        // Secrets sent in the form don't match the registered method
+        let credentials = Credentials::ClientSecretPost {
+            client_id: "client-id".to_string(),
+            client_secret: "client-secret".to_string(),
+        };
+        assert!(matches!(
+            credentials.verify(&encrypter, method, &client).await,
+            Err(CredentialsVerificationError::AuthenticationMethodMismatch),
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_secret_bound_to_client() {
+        let encrypter = Encrypter::new(&[0x42; 32]);
+        let method = OAuthClientAuthenticationMethod::ClientSecretBasic;
+        let credentials = Credentials::ClientSecretBasic {
+            client_id: "client-id".to_string(),
+            client_secret: "client-secret".to_string(),
+        };
+
+        // The encrypted secret of another client doesn't decrypt
+        let mut client = client(&encrypter, "client-secret");
+        client.encrypted_client_secret = Some(
+            encrypter
+                .encrypt_to_string_with_ad(b"other-client", b"client-secret")
+                .unwrap(),
+        );
+        assert!(matches!(
+            credentials.verify(&encrypter, method, &client).await,
+            Err(CredentialsVerificationError::DecryptionError),
+        ));
+
+        // Secrets which are not bound to the client are rejected
+        client.encrypted_client_secret =
+            Some(encrypter.encryt_to_string(b"client-secret").unwrap());
+        assert!(matches!(
+            credentials.verify(&encrypter, method, &client).await,
+            Err(CredentialsVerificationError::DecryptionError),
+        ));
+    }
 }
@@ -12,14 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{fmt, sync::Arc};
+
 use axum_extra::extract::cookie::{Cookie, PrivateCookieJar};
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use data_encoding::{DecodeError, BASE64URL_NOPAD};
+use futures_util::FutureExt;
+use mas_config::{CsrfConfig, CsrfStrategy, Encrypter};
+use mas_iana::jose::JsonWebSignatureAlg;
+use mas_jose::{JwtHeader, SharedSecret, SigningKeystore, VerifyingKeystore};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, TimestampSeconds};
 use thiserror::Error;
 
-use crate::{cookies::CookieDecodeError, CookieExt};
+use crate::{cookies::CookieDecodeError, CookieExt, SessionInfo};
 
 /// Failed to validate CSRF token
 #[derive(Debug, Error)]
@@ -45,16 +51,64 @@ pub enum CsrfError {
     Decode(#[from] DecodeError),
 }
 
-/// A CSRF token
+/// How the CSRF tokens are issued and verified, built from the [`CsrfConfig`]
+#[derive(Clone)]
+pub enum CsrfProtection {
+    /// A random token is stored in an encrypted cookie
+    Cookie { ttl: Duration },
+
+    /// The token is signed over the session ID and the time it was issued
+    Signed { ttl: Duration, key: Arc<[u8]> },
+}
+
+impl fmt::Debug for CsrfProtection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cookie { ttl } => f.debug_struct("Cookie").field("ttl", ttl).finish(),
+            Self::Signed { ttl, .. } => f.debug_struct("Signed").field("ttl", ttl).finish(),
+        }
+    }
+}
+
+impl CsrfProtection {
+    /// Set up the CSRF protection from the configuration. The signed tokens use
+    /// a key derived from the encryption secret
+    #[must_use]
+    pub fn from_config(config: &CsrfConfig, encrypter: &Encrypter) -> Self {
+        match config.strategy {
+            CsrfStrategy::Cookie => Self::Cookie { ttl: config.ttl },
+            CsrfStrategy::Signed => Self::Signed {
+                ttl: config.ttl,
+                key: encrypter.signing_key().into(),
+            },
+        }
+    }
+}
+
+/// A CSRF token, to include in HTML forms
+#[derive(Debug)]
+pub struct CsrfToken {
+    form_value: String,
+}
+
+impl CsrfToken {
+    /// Get the value to include in HTML forms
+    #[must_use]
+    pub fn form_value(&self) -> String {
+        self.form_value.clone()
+    }
+}
+
+/// A CSRF token stored in a cookie
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug)]
-pub struct CsrfToken {
+struct CookieToken {
     #[serde_as(as = "TimestampSeconds<i64>")]
     expiration: DateTime<Utc>,
     token: [u8; 32],
 }
 
-impl CsrfToken {
+impl CookieToken {
     /// Create a new token from a defined value valid for a specified duration
     fn new(token: [u8; 32], ttl: Duration) -> Self {
         let expiration = Utc::now() + ttl;
@@ -73,13 +127,12 @@ impl CsrfToken {
     }
 
     /// Get the value to include in HTML forms
-    #[must_use]
-    pub fn form_value(&self) -> String {
+    fn form_value(&self) -> String {
         BASE64URL_NOPAD.encode(&self.token[..])
     }
 
     /// Verifies that the value got from an HTML form matches this token
-    pub fn verify_form_value(&self, form_value: &str) -> Result<(), CsrfError> {
+    fn verify_form_value(&self, form_value: &str) -> Result<(), CsrfError> {
         let form_value = BASE64URL_NOPAD.decode(form_value.as_bytes())?;
         if self.token[..] == form_value {
             Ok(())
@@ -97,6 +150,100 @@ impl CsrfToken {
     }
 }
 
+/// The cookie holding the random value the signed tokens are bound to when
+/// there is no session
+const BINDING_COOKIE: &str = "csrf-binding";
+
+/// What a signed token is bound to
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Binding {
+    /// The session of the browser
+    Session(i64),
+
+    /// A random value kept in a cookie of the browser, for the forms shown
+    /// without a session, like the login form
+    Browser(String),
+}
+
+/// The message signed in the signed tokens: what they are bound to, and when
+/// they were issued
+fn signed_message(binding: &Binding, issued_at: i64) -> String {
+    match binding {
+        Binding::Session(id) => format!("csrf:session:{}:{}", id, issued_at),
+        Binding::Browser(value) => format!("csrf:browser:{}:{}", value, issued_at),
+    }
+}
+
+/// The signed tokens are bound to the session of the cookie jar, or else to
+/// the random value of its binding cookie
+fn current_binding<K>(jar: &PrivateCookieJar<K>) -> Option<Binding> {
+    let session_id = jar
+        .get("session")
+        .and_then(|cookie| cookie.decode::<SessionInfo>().ok())
+        .and_then(|session_info| session_info.current_session_id());
+
+    if let Some(id) = session_id {
+        return Some(Binding::Session(id));
+    }
+
+    jar.get(BINDING_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+        .filter(|value| !value.is_empty())
+        .map(Binding::Browser)
+}
+
+fn header() -> JwtHeader {
+    JwtHeader::new(JsonWebSignatureAlg::Hs256)
+}
+
+/// Issue a signed token, in the form of the issuance timestamp followed by the
+/// signature
+fn sign(key: &[u8], binding: &Binding, issued_at: DateTime<Utc>) -> String {
+    let issued_at = issued_at.timestamp();
+    let message = signed_message(binding, issued_at);
+
+    // Signing with a shared secret doesn't actually wait for anything, and
+    // HMAC accepts keys of any length
+    let signature = SharedSecret::new(&key)
+        .sign(&header(), message.as_bytes())
+        .now_or_never()
+        .expect("signing with a shared secret is synchronous")
+        .expect("HMAC accepts keys of any length");
+
+    let mut token = issued_at.to_be_bytes().to_vec();
+    token.extend_from_slice(&signature);
+    BASE64URL_NOPAD.encode(&token)
+}
+
+fn verify_signed(
+    key: &[u8],
+    ttl: Duration,
+    binding: &Binding,
+    form_value: &str,
+    now: DateTime<Utc>,
+) -> Result<(), CsrfError> {
+    let token = BASE64URL_NOPAD.decode(form_value.as_bytes())?;
+    if token.len() <= 8 {
+        return Err(CsrfError::Mismatch);
+    }
+    let (issued_at, signature) = token.split_at(8);
+    let issued_at = i64::from_be_bytes(issued_at.try_into().map_err(|_| CsrfError::Mismatch)?);
+
+    let message = signed_message(binding, issued_at);
+    SharedSecret::new(&key)
+        .verify(&header(), message.as_bytes(), signature)
+        .now_or_never()
+        .expect("verifying with a shared secret is synchronous")
+        .map_err(|_| CsrfError::Mismatch)?;
+
+    // The timestamp can be trusted once the signature is verified
+    if now < Utc.timestamp(issued_at, 0) + ttl {
+        Ok(())
+    } else {
+        Err(CsrfError::Expired)
+    }
+}
+
 // A CSRF-protected form
 #[derive(Deserialize)]
 pub struct ProtectedForm<T> {
@@ -107,12 +254,36 @@ pub struct ProtectedForm<T> {
 }
 
 pub trait CsrfExt {
-    fn csrf_token(self) -> (CsrfToken, Self);
-    fn verify_form<T>(&self, form: ProtectedForm<T>) -> Result<T, CsrfError>;
+    fn csrf_token(self, protection: &CsrfProtection) -> (CsrfToken, Self);
+    fn verify_form<T>(
+        &self,
+        protection: &CsrfProtection,
+        form: ProtectedForm<T>,
+    ) -> Result<T, CsrfError>;
 }
 
 impl<K> CsrfExt for PrivateCookieJar<K> {
-    fn csrf_token(self) -> (CsrfToken, Self) {
+    fn csrf_token(self, protection: &CsrfProtection) -> (CsrfToken, Self) {
+        let ttl = match protection {
+            CsrfProtection::Cookie { ttl } => *ttl,
+            CsrfProtection::Signed { key, .. } => {
+                let (binding, jar) = match current_binding(&self) {
+                    Some(binding) => (binding, self),
+                    None => {
+                        let value: [u8; 16] = rand::random();
+                        let value = BASE64URL_NOPAD.encode(&value);
+                        let mut cookie = Cookie::new(BINDING_COOKIE, value.clone());
+                        cookie.set_path("/");
+                        cookie.set_http_only(true);
+                        (Binding::Browser(value), self.add(cookie))
+                    }
+                };
+
+                let form_value = sign(key, &binding, Utc::now());
+                return (CsrfToken { form_value }, jar);
+            }
+        };
+
         let jar = self;
         let mut cookie = jar.get("csrf").unwrap_or_else(|| Cookie::new("csrf", ""));
         cookie.set_path("/");
@@ -121,20 +292,135 @@ impl<K> CsrfExt for PrivateCookieJar<K> {
         let new_token = cookie
             .decode()
             .ok()
-            .and_then(|token: CsrfToken| token.verify_expiration().ok())
-            .unwrap_or_else(|| CsrfToken::generate(Duration::hours(1)))
-            .refresh(Duration::hours(1));
+            .and_then(|token: CookieToken| token.verify_expiration().ok())
+            .unwrap_or_else(|| CookieToken::generate(ttl))
+            .refresh(ttl);
 
         let cookie = cookie.encode(&new_token);
         let jar = jar.add(cookie);
-        (new_token, jar)
+        let token = CsrfToken {
+            form_value: new_token.form_value(),
+        };
+        (token, jar)
     }
 
-    fn verify_form<T>(&self, form: ProtectedForm<T>) -> Result<T, CsrfError> {
-        let cookie = self.get("csrf").ok_or(CsrfError::Missing)?;
-        let token: CsrfToken = cookie.decode()?;
-        let token = token.verify_expiration()?;
-        token.verify_form_value(&form.csrf)?;
+    fn verify_form<T>(
+        &self,
+        protection: &CsrfProtection,
+        form: ProtectedForm<T>,
+    ) -> Result<T, CsrfError> {
+        match protection {
+            CsrfProtection::Cookie { .. } => {
+                let cookie = self.get("csrf").ok_or(CsrfError::Missing)?;
+                let token: CookieToken = cookie.decode()?;
+                let token = token.verify_expiration()?;
+                token.verify_form_value(&form.csrf)?;
+            }
+            CsrfProtection::Signed { ttl, key } => {
+                let binding = current_binding(self).ok_or(CsrfError::Missing)?;
+                verify_signed(key, *ttl, &binding, &form.csrf, Utc::now())?;
+            }
+        }
+
         Ok(form.inner)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"a very secret key, for the tests";
+
+    #[test]
+    fn signed_tokens() {
+        let now = Utc::now();
+        let ttl = Duration::hours(1);
+        let token = sign(KEY, &Binding::Session(42), now);
+
+        assert!(verify_signed(KEY, ttl, &Binding::Session(42), &token, now).is_ok());
+        assert!(verify_signed(
+            KEY,
+            ttl,
+            &Binding::Session(42),
+            &token,
+            now + Duration::minutes(59)
+        )
+        .is_ok());
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), &token, now + ttl),
+            Err(CsrfError::Expired)
+        ));
+
+        // Tokens issued without a session are bound to the browser they were
+        // issued to, and are not valid with a session
+        let browser = Binding::Browser("abc".to_owned());
+        let token = sign(KEY, &browser, now);
+        assert!(verify_signed(KEY, ttl, &browser, &token, now).is_ok());
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), &token, now),
+            Err(CsrfError::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn forged_signed_tokens() {
+        let now = Utc::now();
+        let ttl = Duration::hours(1);
+        let token = sign(KEY, &Binding::Session(42), now);
+
+        // The token is bound to the session
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(43), &token, now),
+            Err(CsrfError::Mismatch)
+        ));
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Browser("42".to_owned()), &token, now),
+            Err(CsrfError::Mismatch)
+        ));
+
+        // Tokens issued without a session can't be used from another browser
+        let token = sign(KEY, &Binding::Browser("abc".to_owned()), now);
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Browser("def".to_owned()), &token, now),
+            Err(CsrfError::Mismatch)
+        ));
+
+        // Tokens signed with another key are rejected
+        let forged = sign(b"another key", &Binding::Session(42), now);
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), &forged, now),
+            Err(CsrfError::Mismatch)
+        ));
+
+        // The issuance time can't be moved forward to extend the token
+        let mut tampered = BASE64URL_NOPAD.decode(token.as_bytes()).unwrap();
+        let later = now.timestamp() + 3600;
+        tampered[..8].copy_from_slice(&later.to_be_bytes());
+        let tampered = BASE64URL_NOPAD.encode(&tampered);
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), &tampered, now + ttl),
+            Err(CsrfError::Mismatch)
+        ));
+
+        // Truncated or garbage tokens are rejected
+        assert!(matches!(
+            verify_signed(
+                KEY,
+                ttl,
+                &Binding::Session(42),
+                &token[..token.len() - 4],
+                now
+            ),
+            Err(CsrfError::Mismatch | CsrfError::Decode(_))
+        ));
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), "AAAA", now),
+            Err(CsrfError::Mismatch)
+        ));
+        assert!(matches!(
+            verify_signed(KEY, ttl, &Binding::Session(42), "not base64!", now),
+            Err(CsrfError::Decode(_))
+        ));
+    }
+}
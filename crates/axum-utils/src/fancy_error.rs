@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::any::Any;
+
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
@@ -19,25 +21,79 @@ use axum::{
 };
 use mas_templates::ErrorContext;
 
+use crate::csrf::CsrfError;
+
 pub struct FancyError {
+    status: StatusCode,
     context: ErrorContext,
 }
 
-impl<E: std::fmt::Display> From<E> for FancyError {
+impl FancyError {
+    /// An error caused by the request, like a malformed form field
+    pub fn bad_request(err: impl std::fmt::Display) -> Self {
+        Self::from(err.to_string()).with_status(StatusCode::BAD_REQUEST)
+    }
+
+    /// An error for something which doesn't exist, or which the user can't see
+    pub fn not_found(err: impl std::fmt::Display) -> Self {
+        Self::from(err.to_string()).with_status(StatusCode::NOT_FOUND)
+    }
+
+    /// Respond with another status than `500 Internal Server Error`
+    #[must_use]
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl<E: std::fmt::Display + 'static> From<E> for FancyError {
     fn from(err: E) -> Self {
         let context = ErrorContext::new().with_description(err.to_string());
-        FancyError { context }
+
+        // CSRF failures come from stale or forged forms, not from the server
+        if (&err as &dyn Any).is::<CsrfError>() {
+            return FancyError {
+                status: StatusCode::BAD_REQUEST,
+                context: context.with_code("csrf_failed"),
+            };
+        }
+
+        FancyError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            context,
+        }
     }
 }
 
 impl IntoResponse for FancyError {
     fn into_response(self) -> Response {
         let error = format!("{:?}", self.context);
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Extension(self.context),
-            error,
-        )
-            .into_response()
+        if self.status.is_server_error() {
+            tracing::error!(status = %self.status, "{}", error);
+        }
+
+        (self.status, Extension(self.context), error).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses() {
+        let response = FancyError::from("something went wrong").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.extensions().get::<ErrorContext>().is_some());
+
+        let response = FancyError::from(CsrfError::Mismatch).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = FancyError::bad_request("invalid session reference").into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = FancyError::not_found("session not found").into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -38,6 +38,12 @@ impl SessionInfo {
         }
     }
 
+    /// The ID of the session this cookie points to, if any
+    #[must_use]
+    pub fn current_session_id(&self) -> Option<i64> {
+        self.current
+    }
+
     /// Mark the session as ended
     #[must_use]
     pub fn mark_session_ended(mut self) -> Self {
@@ -25,7 +25,7 @@ use axum::{
 };
 use headers::{authorization::Bearer, Authorization, Header, HeaderMapExt, HeaderName};
 use http::{header::WWW_AUTHENTICATE, HeaderMap, HeaderValue, StatusCode};
-use mas_data_model::Session;
+use mas_data_model::{Session, TokenType};
 use mas_storage::{
     oauth2::access_token::{lookup_active_access_token, AccessTokenLookupError},
     PostgresqlBackend,
@@ -66,6 +66,12 @@ impl AccessToken {
             AccessToken::None => return Err(AuthorizationVerificationError::MissingToken),
         };
 
+        // Don't bother looking up tokens which are not OAuth 2.0 access tokens, or
+        // which have a typo in them
+        if !TokenType::AccessToken.validate_checksum(token) {
+            return Err(AuthorizationVerificationError::InvalidToken);
+        }
+
         let (token, session) = lookup_active_access_token(conn, token).await?;
 
         Ok((token, session))
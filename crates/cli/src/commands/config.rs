@@ -62,7 +62,7 @@ impl Options {
                 Ok(())
             }
             SC::Check => {
-                let _config: RootConfig = root.load_config()?;
+                let _config = root.load_root_config()?;
                 info!(path = ?root.config, "Configuration file looks good");
                 Ok(())
             }
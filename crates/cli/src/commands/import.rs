@@ -0,0 +1,392 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use anyhow::Context;
+use argon2::password_hash::PasswordHash;
+use clap::Parser;
+use mas_config::RootConfig;
+use mas_data_model::UsernamePolicy;
+use mas_email::EmailNormalizer;
+use mas_storage::user::{check_username_available, import_user};
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+pub(super) struct Options {
+    #[clap(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(Parser, Debug)]
+enum Subcommand {
+    /// Import users from a CSV file, with one `localpart,email,password_hash`
+    /// row per user. The emails are imported as verified, and the password
+    /// hashes have to be Argon2 hashes in the PHC string format
+    Users {
+        /// Path to the CSV file
+        #[clap(long)]
+        file: PathBuf,
+
+        /// Check the whole file against the database, without importing
+        /// anything
+        #[clap(long)]
+        dry_run: bool,
+
+        /// How many users to import in each transaction
+        #[clap(long, default_value = "100")]
+        batch_size: usize,
+    },
+}
+
+/// A user to import, from a row of the CSV file
+#[derive(Debug, PartialEq, Eq)]
+struct UserRow {
+    line: usize,
+    localpart: String,
+    email: String,
+    password_hash: String,
+}
+
+/// A row which could not be imported
+#[derive(Debug, PartialEq, Eq)]
+struct RowError {
+    line: usize,
+    message: String,
+}
+
+impl RowError {
+    fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Split a CSV record. Fields can be quoted, with `""` for a quote in a quoted
+/// field.
+///
+/// The password hash is the last field, and Argon2 hashes have commas in
+/// their parameters: when it isn't quoted, the extra fields are joined back.
+fn split_record(record: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = record.chars().peekable();
+    let mut in_quotes = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("unterminated quoted field");
+    }
+
+    fields.push(field);
+
+    if fields.len() > 3 {
+        let password_hash = fields.split_off(2).join(",");
+        fields.push(password_hash);
+    }
+
+    Ok(fields)
+}
+
+fn parse_row(line: usize, record: &str, policy: &UsernamePolicy) -> Result<UserRow, RowError> {
+    let fields = split_record(record).map_err(|e| RowError::new(line, e))?;
+
+    let (localpart, email, password_hash) = match fields.as_slice() {
+        [localpart, email, password_hash] => (
+            localpart.trim(),
+            email.trim(),
+            password_hash.trim().to_owned(),
+        ),
+        _ => {
+            return Err(RowError::new(
+                line,
+                format!("expected 3 fields, found {}", fields.len()),
+            ))
+        }
+    };
+
+    if let Err(e) = policy.check(localpart) {
+        return Err(RowError::new(
+            line,
+            format!("invalid localpart {:?}: {}", localpart, e),
+        ));
+    }
+
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty() && !domain.is_empty() && !email.contains(char::is_whitespace) => {}
+        _ => {
+            return Err(RowError::new(
+                line,
+                format!("invalid email address {:?}", email),
+            ))
+        }
+    }
+
+    let hash = PasswordHash::new(&password_hash)
+        .map_err(|e| RowError::new(line, format!("invalid password hash: {}", e)))?;
+    if !matches!(hash.algorithm.as_str(), "argon2id" | "argon2i" | "argon2d") {
+        return Err(RowError::new(
+            line,
+            format!("unsupported password hash algorithm {:?}", hash.algorithm),
+        ));
+    }
+
+    Ok(UserRow {
+        line,
+        localpart: localpart.to_owned(),
+        email: email.to_owned(),
+        password_hash,
+    })
+}
+
+/// Parse the users from a CSV file, skipping the empty lines and an optional
+/// header. The rows which can't be imported are reported without stopping
+fn parse_users(content: &str, policy: &UsernamePolicy) -> (Vec<UserRow>, Vec<RowError>) {
+    let mut users = Vec::new();
+    let mut errors = Vec::new();
+    let mut localparts = HashSet::new();
+
+    for (index, record) in content.lines().enumerate() {
+        let line = index + 1;
+        let record = record.trim();
+        if record.is_empty() || (line == 1 && record.starts_with("localpart,")) {
+            continue;
+        }
+
+        match parse_row(line, record, policy) {
+            Ok(user) if !localparts.insert(user.localpart.clone()) => errors.push(RowError::new(
+                line,
+                format!("duplicate localpart {:?}", user.localpart),
+            )),
+            Ok(user) => users.push(user),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    (users, errors)
+}
+
+impl Options {
+    pub async fn run(&self, root: &super::Options) -> anyhow::Result<()> {
+        use Subcommand as SC;
+        match &self.subcommand {
+            SC::Users {
+                file,
+                dry_run,
+                batch_size,
+            } => {
+                let content = tokio::fs::read_to_string(file)
+                    .await
+                    .with_context(|| format!("could not read {}", file.display()))?;
+
+                let config: RootConfig = root.load_config()?;
+                let policy = UsernamePolicy {
+                    min_length: config.usernames.min_length,
+                    max_length: config.usernames.max_length,
+                    reserved: config.usernames.reserved.clone(),
+                };
+                let (users, mut errors) = parse_users(&content, &policy);
+
+                let pool = config.database.connect().await?;
+                let normalizer = EmailNormalizer::from(&config.email);
+
+                let mut imported = 0;
+                for batch in users.chunks((*batch_size).max(1)) {
+                    let mut txn = pool.begin().await?;
+
+                    for user in batch {
+                        // The usernames were checked against the policy when
+                        // parsing, this reports the taken ones by name
+                        if let Err(e) =
+                            check_username_available(&mut txn, &policy, &user.localpart).await
+                        {
+                            errors.push(RowError::new(
+                                user.line,
+                                format!("{:?}: {:#}", user.localpart, anyhow::Error::from(e)),
+                            ));
+                            continue;
+                        }
+
+                        let normalized_email = normalizer.normalize(&user.email);
+                        let res = import_user(
+                            &mut txn,
+                            &user.localpart,
+                            &user.password_hash,
+                            &user.email,
+                            &normalized_email,
+                        )
+                        .await;
+
+                        match res {
+                            Ok(_) => imported += 1,
+                            Err(e) => errors.push(RowError::new(user.line, format!("{:#}", e))),
+                        }
+                    }
+
+                    // In a dry run, every user is inserted to check for
+                    // conflicts, and the transaction is rolled back
+                    if *dry_run {
+                        txn.rollback().await?;
+                    } else {
+                        txn.commit().await?;
+                    }
+                }
+
+                errors.sort_by_key(|e| e.line);
+                for error in &errors {
+                    warn!(line = error.line, "{}", error.message);
+                }
+
+                if *dry_run {
+                    info!(imported, errors = errors.len(), "Dry run, nothing imported");
+                } else {
+                    info!(imported, errors = errors.len(), "Users imported");
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::formatdoc;
+
+    use super::*;
+
+    fn policy() -> UsernamePolicy {
+        UsernamePolicy {
+            reserved: vec!["admin".to_owned()],
+            ..UsernamePolicy::default()
+        }
+    }
+
+    const HASH: &str = "$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$dGhpc2lzYWhhc2h0aGlzaXNhaGFzaA";
+
+    #[test]
+    fn split_records() {
+        assert_eq!(split_record("a,b,c").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(
+            split_record(r#""a,1","b ""2""",c"#).unwrap(),
+            vec!["a,1", r#"b "2""#, "c"]
+        );
+
+        // Unquoted hashes keep their commas
+        assert_eq!(
+            split_record(&format!("alice,alice@example.com,{}", HASH)).unwrap(),
+            vec!["alice", "alice@example.com", HASH]
+        );
+
+        assert!(split_record(r#"a,"b,c"#).is_err());
+    }
+
+    #[test]
+    fn import_csv() {
+        let content = formatdoc! {r#"
+                localpart,email,password_hash
+                alice,alice@example.com,{hash}
+
+                bob,"bob@example.com","{hash}"
+                not a valid row
+                charlie,charlie@example.com,{hash}
+            "#,
+            hash = HASH,
+        };
+
+        let (users, errors) = parse_users(&content, &policy());
+
+        // The malformed row is reported, and the next ones are still imported
+        assert_eq!(
+            users
+                .iter()
+                .map(|user| (user.line, user.localpart.as_str(), user.email.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (2, "alice", "alice@example.com"),
+                (4, "bob", "bob@example.com"),
+                (6, "charlie", "charlie@example.com"),
+            ]
+        );
+        assert!(users.iter().all(|user| user.password_hash == HASH));
+        assert_eq!(errors, vec![RowError::new(5, "expected 3 fields, found 1")]);
+    }
+
+    #[test]
+    fn invalid_rows() {
+        for (row, message) in [
+            (
+                format!("Alice,alice@example.com,{}", HASH),
+                r#"invalid localpart "Alice": username can only contain"#,
+            ),
+            (
+                format!("admin,admin@example.com,{}", HASH),
+                r#"invalid localpart "admin": username is reserved"#,
+            ),
+            (
+                format!("alice,alice,{}", HASH),
+                r#"invalid email address "alice""#,
+            ),
+            (
+                "alice,alice@example.com,hunter2".to_owned(),
+                "invalid password hash",
+            ),
+            (
+                "alice,alice@example.com,$pbkdf2-sha256$i=1000$c2FsdHNhbHQ$dGhpc2lzYWhhc2h0aGlzaXNhaGFzaA"
+                    .to_owned(),
+                "unsupported password hash algorithm",
+            ),
+        ] {
+            let error = parse_row(1, &row, &policy()).unwrap_err();
+            assert!(
+                error.message.starts_with(message),
+                "{:?} should be rejected with {:?}, got {:?}",
+                row,
+                message,
+                error.message
+            );
+        }
+
+        let (users, errors) = parse_users(
+            &format!(
+                "alice,alice@example.com,{hash}\nalice,other@example.com,{hash}",
+                hash = HASH
+            ),
+            &policy(),
+        );
+        assert_eq!(users.len(), 1);
+        assert_eq!(
+            errors,
+            vec![RowError::new(2, r#"duplicate localpart "alice""#)]
+        );
+    }
+}
@@ -12,15 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use anyhow::Context;
 use argon2::Argon2;
+use chrono::Duration;
 use clap::Parser;
-use mas_config::{DatabaseConfig, RootConfig};
+use mas_config::{DatabaseConfig, RootConfig, UsernamesConfig};
+use mas_data_model::{validate_redirect_uri, Device, TokenType, UsernamePolicy};
 use mas_storage::{
+    compat::{add_compat_access_token, start_compat_session},
     oauth2::client::{insert_client_from_config, lookup_client_by_client_id, truncate_clients},
     user::{
-        lookup_user_by_username, lookup_user_email, mark_user_email_as_verified, register_user,
+        check_username_available, lookup_user_by_username, lookup_user_email,
+        mark_user_email_as_verified, register_user, soft_delete_user, undelete_user,
+        MIN_UNDELETE_GRACE_PERIOD_DAYS,
     },
 };
+use rand::thread_rng;
 use tracing::{info, warn};
 
 #[derive(Parser, Debug)]
@@ -38,7 +45,49 @@ enum Subcommand {
     Users,
 
     /// Mark email address as verified
-    VerifyEmail { username: String, email: String },
+    VerifyEmail {
+        /// Username of the user owning the email
+        #[clap(long = "user")]
+        username: String,
+
+        /// Email address to mark as verified
+        #[clap(long)]
+        email: String,
+    },
+
+    /// Issue a compatibility access token for a user, without a password
+    IssueCompatToken {
+        /// Username of the user to issue the token for
+        #[clap(long = "user")]
+        username: String,
+
+        /// Device ID of the new session. A random one is generated if not set
+        #[clap(long)]
+        device: Option<String>,
+
+        /// Lifetime of the token in seconds. The token does not expire if not
+        /// set
+        #[clap(long)]
+        ttl: Option<u32>,
+    },
+
+    /// Delete a user and end all their sessions. The user can be restored
+    /// with `undelete-user` for a while
+    DeleteUser {
+        /// Username of the user to delete
+        username: String,
+    },
+
+    /// Restore a deleted user
+    UndeleteUser {
+        /// Username of the user to restore
+        username: String,
+
+        /// How long after the deletion the user can be restored, in days.
+        /// It has to be at least a day
+        #[clap(long, default_value = "30", validator = check_grace_period)]
+        grace_period: u32,
+    },
 
     /// Import clients from config
     ImportClients {
@@ -48,16 +97,40 @@ enum Subcommand {
     },
 }
 
+/// Reject the grace periods shorter than what [`undelete_user`] accepts, before
+/// connecting to the database
+fn check_grace_period(value: &str) -> Result<(), String> {
+    let days: u32 = value.parse().map_err(|e| format!("{}", e))?;
+    if days < MIN_UNDELETE_GRACE_PERIOD_DAYS {
+        return Err(format!(
+            "has to be at least {} day(s)",
+            MIN_UNDELETE_GRACE_PERIOD_DAYS
+        ));
+    }
+    Ok(())
+}
+
 impl Options {
+    #[allow(clippy::too_many_lines)]
     pub async fn run(&self, root: &super::Options) -> anyhow::Result<()> {
         use Subcommand as SC;
         match &self.subcommand {
             SC::Register { username, password } => {
                 let config: DatabaseConfig = root.load_config()?;
+                let usernames_config: UsernamesConfig = root.load_config()?;
                 let pool = config.connect().await?;
                 let mut txn = pool.begin().await?;
                 let hasher = Argon2::default();
 
+                let policy = UsernamePolicy {
+                    min_length: usernames_config.min_length,
+                    max_length: usernames_config.max_length,
+                    reserved: usernames_config.reserved,
+                };
+                check_username_available(&mut txn, &policy, username)
+                    .await
+                    .with_context(|| format!("Can't register {:?}", username))?;
+
                 let user = register_user(&mut txn, hasher, username, password).await?;
                 txn.commit().await?;
                 info!(?user, "User registered");
@@ -74,8 +147,25 @@ impl Options {
                 let pool = config.connect().await?;
                 let mut txn = pool.begin().await?;
 
-                let user = lookup_user_by_username(&mut txn, username).await?;
-                let email = lookup_user_email(&mut txn, &user, email).await?;
+                let user = match lookup_user_by_username(&mut txn, username).await {
+                    Ok(user) => user,
+                    Err(e) if e.not_found() => anyhow::bail!("User {:?} not found", username),
+                    Err(e) => anyhow::bail!(e),
+                };
+
+                let email = match lookup_user_email(&mut txn, &user, email).await {
+                    Ok(email) => email,
+                    Err(e) if e.not_found() => {
+                        anyhow::bail!("Email {:?} not found for user {:?}", email, username)
+                    }
+                    Err(e) => anyhow::bail!(e),
+                };
+
+                if email.confirmed_at.is_some() {
+                    warn!(?email, "Email was already verified");
+                    return Ok(());
+                }
+
                 let email = mark_user_email_as_verified(&mut txn, email).await?;
 
                 txn.commit().await?;
@@ -83,6 +173,80 @@ impl Options {
 
                 Ok(())
             }
+            SC::DeleteUser { username } => {
+                let config: DatabaseConfig = root.load_config()?;
+                let pool = config.connect().await?;
+                let mut txn = pool.begin().await?;
+
+                let user = match lookup_user_by_username(&mut txn, username).await {
+                    Ok(user) => user,
+                    Err(e) if e.not_found() => anyhow::bail!("User {:?} not found", username),
+                    Err(e) => anyhow::bail!(e),
+                };
+
+                soft_delete_user(&mut txn, &user).await?;
+
+                txn.commit().await?;
+                info!(%username, "User deleted");
+
+                Ok(())
+            }
+            SC::UndeleteUser {
+                username,
+                grace_period,
+            } => {
+                let config: DatabaseConfig = root.load_config()?;
+                let pool = config.connect().await?;
+
+                let grace_period = Duration::days((*grace_period).into());
+                undelete_user(&pool, username, grace_period).await?;
+
+                info!(%username, "User restored");
+
+                Ok(())
+            }
+            SC::IssueCompatToken {
+                username,
+                device,
+                ttl,
+            } => {
+                let config: RootConfig = root.load_config()?;
+                let pool = config.database.connect().await?;
+
+                let device = match device {
+                    Some(device) => Device::try_from(device.clone())?,
+                    None => Device::generate(&mut thread_rng()),
+                };
+                let expires_in = ttl.map(|ttl| Duration::seconds(ttl.into()));
+
+                let mut txn = pool.begin().await?;
+
+                let user = match lookup_user_by_username(&mut txn, username).await {
+                    Ok(user) => user,
+                    Err(e) if e.not_found() => anyhow::bail!("User {:?} not found", username),
+                    Err(e) => anyhow::bail!(e),
+                };
+
+                let session = start_compat_session(&mut txn, user, device).await?;
+
+                let access_token = TokenType::CompatAccessToken.generate(&mut thread_rng());
+                let access_token =
+                    add_compat_access_token(&mut txn, &session, access_token, expires_in).await?;
+
+                txn.commit().await?;
+
+                info!(
+                    user_id = %format!("@{}:{}", session.user.username, config.matrix.homeserver),
+                    device_id = session.device.as_str(),
+                    expires_at = ?access_token.expires_at,
+                    "Issued compatibility access token"
+                );
+
+                println!("access_token: {}", access_token.token);
+                println!("device_id: {}", session.device.as_str());
+
+                Ok(())
+            }
             SC::ImportClients { truncate } => {
                 let config: RootConfig = root.load_config()?;
                 let pool = config.database.connect().await?;
@@ -114,9 +278,25 @@ impl Options {
                     let jwks_uri = client.jwks_uri();
                     let redirect_uris = &client.redirect_uris;
 
+                    for redirect_uri in redirect_uris {
+                        validate_redirect_uri(redirect_uri).map_err(|e| {
+                            anyhow::anyhow!(
+                                "Invalid redirect URI {} for client {}: {}",
+                                redirect_uri,
+                                client_id,
+                                e
+                            )
+                        })?;
+                    }
+
                     // TODO: should be moved somewhere else
                     let encrypted_client_secret = client_secret
-                        .map(|client_secret| encrypter.encryt_to_string(client_secret.as_bytes()))
+                        .map(|client_secret| {
+                            encrypter.encrypt_to_string_with_ad(
+                                client_id.as_bytes(),
+                                client_secret.as_bytes(),
+                            )
+                        })
                         .transpose()?;
 
                     insert_client_from_config(
@@ -138,3 +318,20 @@ impl Options {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undelete_grace_period() {
+        let parse = |args: &[&str]| {
+            Options::try_parse_from(["manage", "undelete-user", "alice"].iter().chain(args))
+        };
+
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--grace-period", "1"]).is_ok());
+        assert!(parse(&["--grace-period", "0"]).is_err());
+        assert!(parse(&["--grace-period", "-1"]).is_err());
+    }
+}
@@ -16,11 +16,12 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use clap::Parser;
-use mas_config::ConfigurationSection;
+use mas_config::{ConfigurationSection, RootConfig};
 
 mod config;
 mod database;
 mod debug;
+mod import;
 mod manage;
 mod server;
 mod templates;
@@ -39,6 +40,9 @@ enum Subcommand {
     /// Manage the instance
     Manage(self::manage::Options),
 
+    /// Import data from another system
+    Import(self::import::Options),
+
     /// Templates-related commands
     Templates(self::templates::Options),
 
@@ -64,14 +68,16 @@ impl Options {
             Some(S::Database(c)) => c.run(self).await,
             Some(S::Server(c)) => c.run(self).await,
             Some(S::Manage(c)) => c.run(self).await,
+            Some(S::Import(c)) => c.run(self).await,
             Some(S::Templates(c)) => c.run(self).await,
             Some(S::Debug(c)) => c.run(self).await,
             None => self::server::Options::default().run(self).await,
         }
     }
 
-    pub fn load_config<'de, T: ConfigurationSection<'de>>(&self) -> anyhow::Result<T> {
-        let configs = if self.config.is_empty() {
+    /// The configuration files to load, in order
+    pub fn config_paths(&self) -> Vec<PathBuf> {
+        if self.config.is_empty() {
             // Read the MAS_CONFIG environment variable
             std::env::var("MAS_CONFIG")
                 // Default to "config.yaml"
@@ -82,8 +88,26 @@ impl Options {
                 .collect()
         } else {
             self.config.clone()
-        };
+        }
+    }
+
+    pub fn load_config<'de, T: ConfigurationSection<'de>>(&self) -> anyhow::Result<T> {
+        T::load_from_files(&self.config_paths()).context("could not load configuration")
+    }
+
+    /// Load the whole configuration, and check it before doing anything with
+    /// it
+    pub fn load_root_config(&self) -> anyhow::Result<RootConfig> {
+        let config: RootConfig = self.load_config()?;
+
+        if let Err(errors) = config.validate() {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|error| format!("  - {}", error))
+                .collect();
+            anyhow::bail!("invalid configuration:\n{}", errors.join("\n"));
+        }
 
-        T::load_from_files(&configs).context("could not load configuration")
+        Ok(config)
     }
 }
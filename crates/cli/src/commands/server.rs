@@ -14,24 +14,36 @@
 
 use std::{
     net::{SocketAddr, TcpListener},
+    path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
 use anyhow::Context;
+use axum::routing::get;
 use clap::Parser;
 use futures::stream::{StreamExt, TryStreamExt};
 use hyper::Server;
-use mas_config::RootConfig;
-use mas_email::{MailTransport, Mailer};
+use mas_config::{ConfigurationSection, TemplatesConfig};
+use mas_email::{MailQueue, MailTransport, Mailer, RateLimiter, RetryPolicy};
+use mas_handlers::{
+    bind_client_secrets, bind_totp_secrets, encrypt_plaintext_totp_secrets, ClientCache,
+    FeatureFlags, MaintenanceMode, WebhookDispatcher, Webhooks,
+};
 use mas_http::ServerLayer;
 use mas_policy::PolicyFactory;
 use mas_router::UrlBuilder;
-use mas_storage::MIGRATOR;
+use mas_storage::{
+    schema::{check_schema, SchemaError},
+    Database, MIGRATOR,
+};
 use mas_tasks::TaskQueue;
 use mas_templates::Templates;
 use tokio::io::AsyncRead;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+/// How many emails can wait to be sent before the handlers start waiting
+const MAIL_QUEUE_CAPACITY: usize = 128;
 
 #[derive(Parser, Debug, Default)]
 pub(super) struct Options {
@@ -39,6 +51,10 @@ pub(super) struct Options {
     #[clap(long)]
     migrate: bool,
 
+    /// Start even if the database schema doesn't match this version
+    #[clap(long)]
+    allow_dirty: bool,
+
     /// Watch for changes for templates on the filesystem
     #[clap(short, long)]
     watch: bool,
@@ -70,10 +86,33 @@ async fn shutdown_signal() {
     };
 }
 
-/// Watch for changes in the templates folders
+/// Toggle the maintenance mode each time the process receives `SIGUSR1`
+#[cfg(unix)]
+fn toggle_maintenance_on_signal(maintenance: MaintenanceMode) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 =
+        signal(SignalKind::user_defined1()).context("failed to install SIGUSR1 signal handler")?;
+
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            if maintenance.toggle() {
+                warn!("Got SIGUSR1, entering maintenance mode");
+            } else {
+                info!("Got SIGUSR1, leaving maintenance mode");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Watch for changes in the templates folders, and in the configuration files
+/// for the settings which can be reloaded
 async fn watch_templates(
     client: &watchman_client::Client,
     templates: &Templates,
+    config_paths: Vec<PathBuf>,
 ) -> anyhow::Result<()> {
     use watchman_client::{
         fields::NameOnly,
@@ -83,14 +122,32 @@ async fn watch_templates(
 
     let templates = templates.clone();
 
-    // Find which roots we're supposed to watch
-    let roots = templates.watch_roots().await;
+    // Find which roots we're supposed to watch: the templates folders and the
+    // folders holding the configuration files
+    let template_roots: Vec<PathBuf> = templates
+        .watch_roots()
+        .await
+        .into_iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .collect();
+    let config_files: Vec<PathBuf> = config_paths
+        .iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .collect();
+
+    let mut roots = template_roots.clone();
+    for parent in config_files.iter().filter_map(|file| file.parent()) {
+        if !roots.iter().any(|root| root == parent) {
+            roots.push(parent.to_owned());
+        }
+    }
+
     let mut streams = Vec::new();
 
     for root in roots {
         // For each root, create a subscription
         let resolved = client
-            .resolve_root(CanonicalPath::canonicalize(root)?)
+            .resolve_root(CanonicalPath::canonicalize(root.clone())?)
             .await?;
 
         // TODO: we could subscribe to less, properly filter here
@@ -102,30 +159,61 @@ async fn watch_templates(
         let stream = futures::stream::try_unfold(subscription, |mut sub| async move {
             let next = sub.next().await?;
             anyhow::Ok(Some((next, sub)))
-        });
+        })
+        .map_ok(move |event| (root.clone(), event));
 
         streams.push(Box::pin(stream));
     }
 
     let files_changed_stream =
-        futures::stream::select_all(streams).try_filter_map(|event| async move {
-            match event {
-                SubscriptionData::FilesChanged(QueryResult {
-                    files: Some(files), ..
-                }) => {
-                    let files: Vec<_> = files.into_iter().map(|f| f.name.into_inner()).collect();
-                    Ok(Some(files))
+        futures::stream::select_all(streams).try_filter_map(move |(root, event)| {
+            let template_roots = template_roots.clone();
+            let config_files = config_files.clone();
+            async move {
+                match event {
+                    SubscriptionData::FilesChanged(QueryResult {
+                        files: Some(files), ..
+                    }) => {
+                        // Ignore the other files next to the configuration files
+                        let files: Vec<_> = files
+                            .into_iter()
+                            .map(|f| root.join(f.name.into_inner()))
+                            .filter(|file| {
+                                template_roots.iter().any(|root| file.starts_with(root))
+                                    || config_files.contains(file)
+                            })
+                            .collect();
+
+                        if files.is_empty() {
+                            Ok(None)
+                        } else {
+                            Ok(Some(files))
+                        }
+                    }
+                    _ => Ok(None),
                 }
-                _ => Ok(None),
             }
         });
 
     let fut = files_changed_stream.for_each(move |files| {
         let templates = templates.clone();
+        let config_paths = config_paths.clone();
         async move {
             info!(?files, "Files changed, reloading templates");
 
-            templates.clone().reload().await.unwrap_or_else(|err| {
+            // Keys, database and the other settings still need a restart
+            let res = match TemplatesConfig::load_from_files(&config_paths) {
+                Ok(config) => templates.reload_with_config(&config).await,
+                Err(err) => {
+                    error!(
+                        ?err,
+                        "Could not load the configuration, only reloading templates"
+                    );
+                    templates.reload().await
+                }
+            };
+
+            res.unwrap_or_else(|err| {
                 error!(?err, "Error while reloading templates");
             });
         }
@@ -137,8 +225,9 @@ async fn watch_templates(
 }
 
 impl Options {
+    #[allow(clippy::too_many_lines)]
     pub async fn run(&self, root: &super::Options) -> anyhow::Result<()> {
-        let config: RootConfig = root.load_config()?;
+        let config = root.load_root_config()?;
 
         let addr: SocketAddr = config
             .http
@@ -162,9 +251,74 @@ impl Options {
                 .context("could not run migrations")?;
         }
 
+        let mut conn = pool.acquire().await?;
+        match check_schema(&mut conn).await {
+            Ok(()) => {}
+            Err(e @ SchemaError::Migrate(_)) => {
+                return Err(e).context("could not check the database schema");
+            }
+            Err(e) if self.allow_dirty => {
+                warn!("{}, starting anyway because of --allow-dirty", e);
+            }
+            Err(e) => {
+                return Err(e).context(
+                    "the database schema doesn't match this version, pass --allow-dirty to start \
+                     anyway",
+                );
+            }
+        }
+        drop(conn);
+
+        let mut database = Database::new(pool.clone());
+        if let Some(replica) = config.database.connect_read_replica().await? {
+            info!("Sending read-only queries to the read replica");
+            database = database.with_read_replica(replica);
+        }
+
         info!("Starting task scheduler");
         let queue = TaskQueue::default();
-        queue.recuring(Duration::from_secs(15), mas_tasks::cleanup_expired(&pool));
+        let token_cleanup = &config.oauth2.token_cleanup;
+        let deleted_tokens = opentelemetry::global::meter("mas-cli")
+            .u64_counter("tokens_deleted_total")
+            .with_description("Number of expired tokens and WebAuthn challenges deleted, by kind")
+            .init();
+        queue.recuring(
+            "cleanup-expired-tokens",
+            token_cleanup.interval,
+            mas_tasks::cleanup_expired(
+                &pool,
+                token_cleanup.grace_period,
+                token_cleanup.batch_size,
+                move |kind, count| {
+                    deleted_tokens.add(count, &[opentelemetry::KeyValue::new("kind", kind)]);
+                },
+            ),
+        );
+        let unverified_email_max_age = config.email.unverified_email_max_age;
+        if !unverified_email_max_age.is_zero() {
+            let purged = opentelemetry::global::meter("mas-cli")
+                .u64_counter("unverified_emails_purged_total")
+                .with_description("Number of unverified emails deleted after their max age")
+                .init();
+            queue.recuring(
+                "purge-unverified-emails",
+                Duration::from_secs(60 * 60),
+                mas_tasks::purge_unverified_emails(&pool, unverified_email_max_age, move |count| {
+                    purged.add(count, &[]);
+                }),
+            );
+        }
+        let erased = opentelemetry::global::meter("mas-cli")
+            .u64_counter("users_erased_total")
+            .with_description("Number of users erased at their request")
+            .init();
+        queue.recuring(
+            "erase-scheduled-users",
+            Duration::from_secs(60 * 60),
+            mas_tasks::erase_scheduled_users(&pool, move |count| {
+                erased.add(count, &[]);
+            }),
+        );
         queue.start();
 
         // Initialize the key store
@@ -178,6 +332,27 @@ impl Options {
 
         let encrypter = config.secrets.encrypter();
 
+        let count = encrypt_plaintext_totp_secrets(&pool, &encrypter)
+            .await
+            .context("could not encrypt the TOTP secrets")?;
+        if count > 0 {
+            info!(count, "Encrypted TOTP secrets stored in plain text");
+        }
+
+        let count = bind_totp_secrets(&pool, &encrypter)
+            .await
+            .context("could not encrypt the TOTP secrets")?;
+        if count > 0 {
+            info!(count, "Bound the TOTP secrets to their user");
+        }
+
+        let count = bind_client_secrets(&pool, &encrypter)
+            .await
+            .context("could not encrypt the client secrets")?;
+        if count > 0 {
+            info!(count, "Bound the client secrets to their client ID");
+        }
+
         // Load and compile the WASM policies (and fallback to the default embedded one)
         info!("Loading and compiling the policy module");
         let mut policy: Box<dyn AsyncRead + std::marker::Unpin> =
@@ -206,54 +381,145 @@ impl Options {
             .await
             .context("could not load templates")?;
 
+        // Fail now rather than on the first request if a template can't render
+        templates
+            .check_render()
+            .await
+            .context("some templates failed to render")?;
+
         let mailer = Mailer::new(
             &templates,
             &mail_transport,
             &config.email.from,
             &config.email.reply_to,
-        );
+        )
+        .with_monitor_bcc(config.email.monitor_bcc.clone())
+        .with_retry_policy(RetryPolicy::from(&config.email.retry))
+        .with_rate_limiter(RateLimiter::from(&config.email.rate_limit));
+
+        // Emails are sent in the background, outside of the request handlers
+        let (mail_queue, mail_worker) = MailQueue::new(mailer, MAIL_QUEUE_CAPACITY);
+        let mail_worker = tokio::spawn(mail_worker.run());
 
-        let url_builder = UrlBuilder::new(config.http.public_base.clone());
+        let url_builder = UrlBuilder::new(config.http.public_base.clone())
+            .with_trusted_forwarded_headers(config.http.trust_forwarded_headers);
 
         let static_files = mas_static_files::service(&config.http.web_root);
 
+        let email_config = config.email.clone();
         let matrix_config = config.matrix.clone();
+        let passwords_config = config.passwords.clone();
+        let usernames_config = config.usernames.clone();
+        let challenge_config = config.challenge.clone();
+        let oauth2_config = config.oauth2.clone();
+        let upstream_oauth2_config = config.upstream_oauth2.clone();
+        let admin_config = config.admin.clone();
+        let csrf_config = config.csrf.clone();
+        let sessions_config = config.sessions.clone();
+        let cors_config = config.http.cors.clone();
+        let cookies_config = config.http.cookies.clone();
+        let csp_config = config.http.csp.clone();
+        let maintenance = MaintenanceMode::new(
+            &config.http.maintenance,
+            config.http.trust_forwarded_headers,
+        );
+        let client_cache = ClientCache::from_config(&config.oauth2.client_cache);
+        if client_cache.is_enabled() && config.oauth2.client_cache.notifications {
+            tokio::spawn(client_cache.clone().listen_for_changes(pool.clone()));
+        }
+        let webhooks = Webhooks::from_config(&config.webhooks);
+        let webhook_dispatcher = WebhookDispatcher::from_config(&config.webhooks);
+        if webhook_dispatcher.is_enabled() {
+            tokio::spawn(webhook_dispatcher.run(pool.clone()));
+        }
+        let feature_flags =
+            FeatureFlags::from_config(&config.features, &config.matrix, &config.oauth2);
+        let shutdown_timeout = config.http.shutdown_timeout;
+        let watch = self.watch || config.templates.watch;
 
         // Explicitely the config to properly zeroize secret keys
         drop(config);
 
-        // Watch for changes in templates if the --watch flag is present
-        if self.watch {
+        // Watch for changes in templates if the --watch flag is present or if it
+        // is enabled in the configuration
+        if watch {
             let client = watchman_client::Connector::new()
                 .connect()
                 .await
                 .context("could not connect to watchman")?;
 
-            watch_templates(&client, &templates)
+            watch_templates(&client, &templates, root.config_paths())
                 .await
                 .context("could not watch for templates changes")?;
         }
 
-        let router = mas_handlers::router(
-            &pool,
+        if maintenance.is_enabled() {
+            warn!("Starting in maintenance mode");
+        }
+
+        #[cfg(unix)]
+        toggle_maintenance_on_signal(maintenance.clone())?;
+
+        let mut router = mas_handlers::router(
+            &database,
             &templates,
             &key_store,
             &encrypter,
-            &mailer,
+            &csrf_config,
+            &mail_queue,
+            &mail_transport,
+            &email_config,
             &url_builder,
             &matrix_config,
+            &passwords_config,
+            &usernames_config,
+            &challenge_config,
+            &oauth2_config,
+            &upstream_oauth2_config,
+            &admin_config,
+            &sessions_config,
+            &client_cache,
+            &webhooks,
+            &feature_flags,
+            &cors_config,
+            &cookies_config,
+            &csp_config,
+            &maintenance,
             &policy_factory,
-        )
-        .fallback(static_files)
-        .layer(ServerLayer::default());
+        );
 
-        info!("Listening on http://{}", listener.local_addr().unwrap());
+        // Only expose the metrics if the Prometheus exporter is enabled
+        if let Some(registry) = crate::telemetry::prometheus_registry() {
+            router = router.route(
+                "/metrics",
+                get(move || async move { crate::telemetry::prometheus_metrics(registry) }),
+            );
+        }
 
-        Server::from_tcp(listener)?
-            .serve(router.into_make_service_with_connect_info::<SocketAddr>())
-            .with_graceful_shutdown(shutdown_signal())
-            .await?;
+        let router = router.fallback(static_files).layer(ServerLayer::default());
 
-        Ok(())
+        info!("Listening on http://{}", listener.local_addr().unwrap());
+
+        crate::shutdown::serve_with_graceful_shutdown(
+            |signal| async move {
+                Server::from_tcp(listener)?
+                    .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+                    .with_graceful_shutdown(signal)
+                    .await?;
+
+                // The server dropped its handles on the queue, wait for the pending emails
+                // to be sent
+                drop(mail_queue);
+                mail_worker.await?;
+
+                // Let the running task finish, and don't start new ones
+                queue.shutdown().await;
+
+                anyhow::Ok(())
+            },
+            shutdown_signal(),
+            shutdown_timeout,
+        )
+        .await
     }
 }
@@ -61,6 +61,7 @@ impl Options {
                 let config = TemplatesConfig {
                     path: Some(path.to_string()),
                     builtin: !skip_builtin,
+                    ..TemplatesConfig::default()
                 };
                 let templates = Templates::load_from_config(&config).await?;
                 templates.check_render().await?;
@@ -28,6 +28,7 @@ use tracing_subscriber::{
 };
 
 mod commands;
+mod shutdown;
 mod telemetry;
 
 #[tokio::main]
@@ -48,12 +49,17 @@ async fn try_main() -> anyhow::Result<()> {
         // Display the error if it is something other than the .env file not existing
         .or_else(|e| if e.not_found() { Ok(None) } else { Err(e) });
 
+    // Parse the CLI arguments
+    let opts = self::commands::Options::parse();
+
+    // Telemetry config could fail to load, but that's probably OK, since the whole
+    // config will be loaded afterwards, and crash if there is a problem.
+    // Falling back to default.
+    let telemetry_config: TelemetryConfig = opts.load_config().unwrap_or_default();
+
     // Setup logging
-    // This writes logs to stderr
+    // This writes logs to stderr, in the format set in the telemetry config
     let (log_writer, _guard) = tracing_appender::non_blocking(std::io::stderr());
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_writer(log_writer)
-        .with_ansi(atty::is(atty::Stream::Stderr));
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .context("could not setup logging filter")?;
@@ -64,10 +70,13 @@ async fn try_main() -> anyhow::Result<()> {
     // We only want "INFO" level spans to go through OpenTelemetry
     let telemetry_layer = telemetry_layer.with_filter(LevelFilter::INFO);
 
-    let subscriber = Registry::default()
-        .with(telemetry_layer)
-        .with(filter_layer)
-        .with(fmt_layer);
+    let subscriber = Registry::default().with(telemetry_layer).with(filter_layer);
+    let subscriber = self::telemetry::with_log_layer(
+        subscriber,
+        telemetry_config.logging.format,
+        log_writer,
+        atty::is(atty::Stream::Stderr),
+    );
     subscriber
         .try_init()
         .context("could not initialize logging")?;
@@ -80,14 +89,6 @@ async fn try_main() -> anyhow::Result<()> {
         Err(err) => tracing::warn!(%err, "failed to load .env file"),
     }
 
-    // Parse the CLI arguments
-    let opts = self::commands::Options::parse();
-
-    // Telemetry config could fail to load, but that's probably OK, since the whole
-    // config will be loaded afterwards, and crash if there is a problem.
-    // Falling back to default.
-    let telemetry_config: TelemetryConfig = opts.load_config().unwrap_or_default();
-
     // Setup OpenTelemtry tracing and metrics
     let tracer = telemetry::setup(&telemetry_config).context("failed to setup opentelemetry")?;
     if let Some(tracer) = tracer {
@@ -0,0 +1,172 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coordinate the graceful shutdown of the server
+
+use std::{future::Future, time::Duration};
+
+use futures::future::BoxFuture;
+use tokio::sync::oneshot;
+
+/// Run the server until `signal` resolves, then give it at most `timeout` to
+/// finish.
+///
+/// `serve` gets the future to pass to
+/// [`hyper::server::Server::with_graceful_shutdown`]: once it resolves, the
+/// server stops accepting new connections and waits for the in-flight
+/// requests. The future `serve` returns should also flush any pending work,
+/// like the queued emails, after the server stopped.
+pub async fn serve_with_graceful_shutdown<F, Fut>(
+    serve: F,
+    signal: impl Future<Output = ()>,
+    timeout: Duration,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(BoxFuture<'static, ()>) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let (tx, rx) = oneshot::channel();
+    let server = serve(Box::pin(async move {
+        // The sender is only dropped once the server stopped on its own
+        let _ = rx.await;
+    }));
+    tokio::pin!(server);
+
+    tokio::select! {
+        // The server stopped without being asked to, probably because of an error
+        res = &mut server => return res,
+        () = signal => {}
+    }
+
+    tracing::info!(
+        ?timeout,
+        "Waiting for in-flight requests and queued emails before exiting"
+    );
+    let _ = tx.send(());
+
+    tokio::time::timeout(timeout, server)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for the server to shut down"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{routing::get, Extension, Router};
+    use hyper::{Client, Server, StatusCode};
+    use tokio::{
+        net::TcpStream,
+        sync::{mpsc, Notify},
+    };
+
+    use super::*;
+
+    /// Tells the test it started, then waits to be released
+    async fn slow(
+        Extension(started): Extension<mpsc::Sender<()>>,
+        Extension(release): Extension<Arc<Notify>>,
+    ) -> &'static str {
+        started.send(()).await.unwrap();
+        release.notified().await;
+        "done"
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_complete() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+
+        let (started_tx, mut started_rx) = mpsc::channel::<()>(1);
+        let release = Arc::new(Notify::new());
+        let router = Router::new()
+            .route("/slow", get(slow))
+            .layer(Extension(started_tx))
+            .layer(Extension(release.clone()));
+
+        let (signal_tx, signal_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve_with_graceful_shutdown(
+            move |signal| async move {
+                Server::from_tcp(listener)?
+                    .serve(router.into_make_service())
+                    .with_graceful_shutdown(signal)
+                    .await?;
+                anyhow::Ok(())
+            },
+            async move {
+                signal_rx.await.unwrap();
+            },
+            Duration::from_secs(5),
+        ));
+
+        // Start a request before shutting down
+        let request = tokio::spawn(async move {
+            let uri = format!("http://{}/slow", addr).parse().unwrap();
+            let response = Client::new().get(uri).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, body)
+        });
+        started_rx.recv().await.unwrap();
+
+        signal_tx.send(()).unwrap();
+
+        // New connections are refused once the shutdown started
+        let mut refused = false;
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(refused);
+
+        // The request started before still completes
+        release.notify_one();
+        let (status, body) = request.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"done");
+
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_times_out() {
+        let res = serve_with_graceful_shutdown(
+            |_signal| async {
+                // Some work which never finishes
+                std::future::pending::<()>().await;
+                anyhow::Ok(())
+            },
+            async {},
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_returned() {
+        let res = serve_with_graceful_shutdown(
+            |_signal| async { Err::<(), _>(anyhow::anyhow!("could not serve")) },
+            std::future::pending(),
+            Duration::from_secs(5),
+        )
+        .await;
+
+        assert!(res.is_err());
+    }
+}
@@ -12,11 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+};
 use futures::stream::{Stream, StreamExt};
-use mas_config::{MetricsExporterConfig, Propagator, TelemetryConfig, TracingExporterConfig};
+use mas_config::{
+    LogFormat, MetricsExporterConfig, OtlpProtocol, Propagator, TelemetryConfig, TracingConfig,
+    TracingExporterConfig,
+};
+use once_cell::sync::OnceCell;
 use opentelemetry::{
     global,
     propagation::TextMapPropagator,
@@ -32,8 +40,15 @@ use opentelemetry_jaeger::Propagator as JaegerPropagator;
 use opentelemetry_semantic_conventions as semcov;
 #[cfg(feature = "zipkin")]
 use opentelemetry_zipkin::{B3Encoding, Propagator as ZipkinPropagator};
+use prometheus::{Encoder, Registry, TextEncoder};
+use tracing::Subscriber;
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt, registry::LookupSpan};
 use url::Url;
 
+/// The registry exposed on the `/metrics` endpoint, set when the Prometheus
+/// exporter is enabled
+static PROMETHEUS_REGISTRY: OnceCell<Registry> = OnceCell::new();
+
 pub fn setup(config: &TelemetryConfig) -> anyhow::Result<Option<Tracer>> {
     global::set_error_handler(|e| tracing::error!("{}", e))?;
     let propagator = propagator(&config.tracing.propagators)?;
@@ -43,11 +58,43 @@ pub fn setup(config: &TelemetryConfig) -> anyhow::Result<Option<Tracer>> {
     mas_http::set_propagator(&propagator);
     global::set_text_map_propagator(propagator);
 
-    let tracer = tracer(&config.tracing.exporter)?;
+    let tracer = tracer(&config.tracing)?;
     meter(&config.metrics.exporter)?;
     Ok(tracer)
 }
 
+/// Add the layer writing logs in the given format to a subscriber.
+///
+/// Credentials must never end up in the logs, whatever the format: handlers
+/// dealing with them skip their arguments when instrumented
+pub fn with_log_layer<S, W>(
+    subscriber: S,
+    format: LogFormat,
+    writer: W,
+    ansi: bool,
+) -> impl Subscriber + Send + Sync
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let (human_layer, json_layer) = match format {
+        LogFormat::Human => (
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .with_ansi(ansi),
+            ),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(tracing_subscriber::fmt::layer().json().with_writer(writer)),
+        ),
+    };
+
+    subscriber.with(human_layer).with(json_layer)
+}
+
 pub fn shutdown() {
     global::shutdown_tracer_provider();
 }
@@ -86,47 +133,109 @@ fn propagator(propagators: &[Propagator]) -> anyhow::Result<impl TextMapPropagat
     Ok(TextMapCompositePropagator::new(propagators?))
 }
 
-fn stdout_tracer() -> Tracer {
+fn stdout_tracer(sampler: Sampler) -> Tracer {
     sdk::export::trace::stdout::new_pipeline()
         .with_pretty_print(true)
-        .with_trace_config(trace_config())
+        .with_trace_config(trace_config(sampler))
         .install_simple()
 }
 
-#[cfg(feature = "otlp")]
-fn otlp_tracer(endpoint: &Option<Url>) -> anyhow::Result<Tracer> {
-    use opentelemetry_otlp::WithExportConfig;
+/// Check that the OTLP metrics exporter supports the protocol
+fn check_otlp_metrics_protocol(protocol: OtlpProtocol) -> anyhow::Result<()> {
+    match protocol {
+        OtlpProtocol::Grpc => Ok(()),
+        OtlpProtocol::HttpProtobuf => bail!(
+            "OTLP metrics can only be exported over gRPC, but config uses http/protobuf. Use the grpc protocol instead."
+        ),
+    }
+}
 
-    let mut exporter = opentelemetry_otlp::new_exporter().tonic();
-    if let Some(endpoint) = endpoint {
-        exporter = exporter.with_endpoint(endpoint.to_string());
+/// Turn the configured OTLP headers into gRPC metadata
+#[cfg(feature = "otlp")]
+fn otlp_metadata(
+    headers: &HashMap<String, String>,
+) -> anyhow::Result<tonic::metadata::MetadataMap> {
+    use anyhow::Context;
+    use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+
+    let mut metadata = MetadataMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let key: MetadataKey<_> = name
+            .parse()
+            .with_context(|| format!("Invalid OTLP header name {:?}", name))?;
+        let value: MetadataValue<_> = value
+            .parse()
+            .with_context(|| format!("Invalid value for the OTLP header {:?}", name))?;
+        metadata.insert(key, value);
     }
 
+    Ok(metadata)
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_tracer(
+    endpoint: &Option<Url>,
+    protocol: OtlpProtocol,
+    headers: &HashMap<String, String>,
+    sampler: Sampler,
+) -> anyhow::Result<Tracer> {
+    use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+
+    let exporter: SpanExporterBuilder = match protocol {
+        OtlpProtocol::Grpc => {
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_metadata(otlp_metadata(headers)?);
+            if let Some(endpoint) = endpoint {
+                exporter = exporter.with_endpoint(endpoint.to_string());
+            }
+            exporter.into()
+        }
+        OtlpProtocol::HttpProtobuf => {
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .http()
+                .with_http_client(reqwest::Client::new())
+                .with_headers(headers.clone());
+            if let Some(endpoint) = endpoint {
+                exporter = exporter.with_endpoint(endpoint.to_string());
+            }
+            exporter.into()
+        }
+    };
+
     let tracer = opentelemetry_otlp::new_pipeline()
         .tracing()
         .with_exporter(exporter)
-        .with_trace_config(trace_config())
+        .with_trace_config(trace_config(sampler))
         .install_batch(opentelemetry::runtime::Tokio)?;
 
     Ok(tracer)
 }
 
 #[cfg(not(feature = "otlp"))]
-fn otlp_tracer(_endpoint: &Option<Url>) -> anyhow::Result<Tracer> {
+fn otlp_tracer(
+    _endpoint: &Option<Url>,
+    _protocol: OtlpProtocol,
+    _headers: &HashMap<String, String>,
+    _sampler: Sampler,
+) -> anyhow::Result<Tracer> {
     anyhow::bail!("The service was compiled without OTLP exporter support, but config exports traces via OTLP.")
 }
 
 #[cfg(not(feature = "jaeger"))]
-fn jaeger_tracer(_agent_endpoint: &Option<SocketAddr>) -> anyhow::Result<Tracer> {
+fn jaeger_tracer(
+    _agent_endpoint: &Option<SocketAddr>,
+    _sampler: Sampler,
+) -> anyhow::Result<Tracer> {
     anyhow::bail!("The service was compiled without Jaeger exporter support, but config exports traces via Jaeger.")
 }
 
 #[cfg(feature = "jaeger")]
-fn jaeger_tracer(agent_endpoint: &Option<SocketAddr>) -> anyhow::Result<Tracer> {
+fn jaeger_tracer(agent_endpoint: &Option<SocketAddr>, sampler: Sampler) -> anyhow::Result<Tracer> {
     // TODO: also support exporting to a Jaeger collector & skip the agent
     let mut pipeline = opentelemetry_jaeger::new_pipeline()
         .with_service_name(env!("CARGO_PKG_NAME"))
-        .with_trace_config(trace_config());
+        .with_trace_config(trace_config(sampler));
 
     if let Some(agent_endpoint) = agent_endpoint {
         pipeline = pipeline.with_agent_endpoint(agent_endpoint);
@@ -138,18 +247,18 @@ fn jaeger_tracer(agent_endpoint: &Option<SocketAddr>) -> anyhow::Result<Tracer>
 }
 
 #[cfg(not(feature = "zipkin"))]
-fn zipkin_tracer(_collector_endpoint: &Option<Url>) -> anyhow::Result<Tracer> {
+fn zipkin_tracer(_collector_endpoint: &Option<Url>, _sampler: Sampler) -> anyhow::Result<Tracer> {
     anyhow::bail!("The service was compiled without Jaeger exporter support, but config exports traces via Jaeger.")
 }
 
 #[cfg(feature = "zipkin")]
-fn zipkin_tracer(collector_endpoint: &Option<Url>) -> anyhow::Result<Tracer> {
+fn zipkin_tracer(collector_endpoint: &Option<Url>, sampler: Sampler) -> anyhow::Result<Tracer> {
     let http_client = reqwest::Client::new();
 
     let mut pipeline = opentelemetry_zipkin::new_pipeline()
         .with_http_client(http_client)
         .with_service_name(env!("CARGO_PKG_NAME"))
-        .with_trace_config(trace_config());
+        .with_trace_config(trace_config(sampler));
 
     if let Some(collector_endpoint) = collector_endpoint {
         pipeline = pipeline.with_collector_endpoint(collector_endpoint.to_string());
@@ -160,13 +269,32 @@ fn zipkin_tracer(collector_endpoint: &Option<Url>) -> anyhow::Result<Tracer> {
     Ok(tracer)
 }
 
-fn tracer(config: &TracingExporterConfig) -> anyhow::Result<Option<Tracer>> {
-    let tracer = match config {
+/// Sample a proportion of the traces, keeping the decision of the caller for
+/// the requests which are part of a trace
+fn sampler(sample_rate: f64) -> Sampler {
+    if sample_rate >= 1.0 {
+        Sampler::AlwaysOn
+    } else if sample_rate <= 0.0 {
+        Sampler::AlwaysOff
+    } else {
+        Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_rate)))
+    }
+}
+
+fn tracer(config: &TracingConfig) -> anyhow::Result<Option<Tracer>> {
+    let sampler = sampler(config.sample_rate);
+    let tracer = match &config.exporter {
         TracingExporterConfig::None => return Ok(None),
-        TracingExporterConfig::Stdout => stdout_tracer(),
-        TracingExporterConfig::Otlp { endpoint } => otlp_tracer(endpoint)?,
-        TracingExporterConfig::Jaeger { agent_endpoint } => jaeger_tracer(agent_endpoint)?,
-        TracingExporterConfig::Zipkin { collector_endpoint } => zipkin_tracer(collector_endpoint)?,
+        TracingExporterConfig::Stdout => stdout_tracer(sampler),
+        TracingExporterConfig::Otlp {
+            endpoint,
+            protocol,
+            headers,
+        } => otlp_tracer(endpoint, *protocol, headers, sampler)?,
+        TracingExporterConfig::Jaeger { agent_endpoint } => jaeger_tracer(agent_endpoint, sampler)?,
+        TracingExporterConfig::Zipkin { collector_endpoint } => {
+            zipkin_tracer(collector_endpoint, sampler)?
+        }
     };
 
     Ok(Some(tracer))
@@ -178,10 +306,18 @@ fn interval(duration: Duration) -> impl Stream<Item = tokio::time::Instant> {
 }
 
 #[cfg(feature = "otlp")]
-fn otlp_meter(endpoint: &Option<url::Url>) -> anyhow::Result<()> {
+fn otlp_meter(
+    endpoint: &Option<url::Url>,
+    protocol: OtlpProtocol,
+    headers: &HashMap<String, String>,
+) -> anyhow::Result<()> {
     use opentelemetry_otlp::WithExportConfig;
 
-    let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+    check_otlp_metrics_protocol(protocol)?;
+
+    let mut exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_metadata(otlp_metadata(headers)?);
     if let Some(endpoint) = endpoint {
         exporter = exporter.with_endpoint(endpoint.to_string());
     }
@@ -196,7 +332,12 @@ fn otlp_meter(endpoint: &Option<url::Url>) -> anyhow::Result<()> {
 }
 
 #[cfg(not(feature = "otlp"))]
-fn otlp_meter(_endpoint: &Option<url::Url>) -> anyhow::Result<()> {
+fn otlp_meter(
+    _endpoint: &Option<url::Url>,
+    protocol: OtlpProtocol,
+    _headers: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    check_otlp_metrics_protocol(protocol)?;
     anyhow::bail!("The service was compiled without OTLP exporter support, but config exports metrics via OTLP.")
 }
 
@@ -204,20 +345,56 @@ fn stdout_meter() {
     sdk::export::metrics::stdout(tokio::spawn, interval).init();
 }
 
+fn prometheus_meter() -> anyhow::Result<()> {
+    let exporter = opentelemetry_prometheus::exporter()
+        .with_resource(resource())
+        .init();
+
+    PROMETHEUS_REGISTRY
+        .set(exporter.registry().clone())
+        .map_err(|_| anyhow!("The Prometheus exporter was already set up"))?;
+
+    Ok(())
+}
+
+/// The registry holding the metrics to expose on the `/metrics` endpoint, if
+/// the Prometheus exporter is enabled
+pub fn prometheus_registry() -> Option<&'static Registry> {
+    PROMETHEUS_REGISTRY.get()
+}
+
+/// Render the metrics of a registry in the Prometheus text format
+pub fn prometheus_metrics(registry: &Registry) -> Response {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&registry.gather(), &mut buffer) {
+        Ok(()) => ([(CONTENT_TYPE, encoder.format_type())], buffer).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 fn meter(config: &MetricsExporterConfig) -> anyhow::Result<()> {
     match config {
         MetricsExporterConfig::None => {}
         MetricsExporterConfig::Stdout => stdout_meter(),
-        MetricsExporterConfig::Otlp { endpoint } => otlp_meter(endpoint)?,
+        MetricsExporterConfig::Otlp {
+            endpoint,
+            protocol,
+            headers,
+        } => otlp_meter(endpoint, *protocol, headers)?,
+        MetricsExporterConfig::Prometheus => prometheus_meter()?,
     };
 
     Ok(())
 }
 
-fn trace_config() -> sdk::trace::Config {
+fn trace_config(sampler: Sampler) -> sdk::trace::Config {
     sdk::trace::config()
         .with_resource(resource())
-        .with_sampler(Sampler::AlwaysOn)
+        .with_sampler(sampler)
 }
 
 fn resource() -> Resource {
@@ -237,3 +414,136 @@ fn resource() -> Resource {
 
     resource.merge(&detected)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing_subscriber::Registry;
+
+    use super::*;
+
+    /// Collects everything written to it
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_logs_are_parseable() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = with_log_layer(
+            Registry::default(),
+            LogFormat::Json,
+            move || writer.clone(),
+            false,
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("login", user.sub = "sub-alice");
+            let _guard = span.enter();
+            tracing::info!(session.id = 42, "User logged in");
+        });
+
+        let output = buffer.0.lock().unwrap();
+        let output = std::str::from_utf8(&output).unwrap();
+        let mut lines = output.lines();
+        let line: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert!(lines.next().is_none());
+
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "User logged in");
+        assert_eq!(line["fields"]["session.id"], 42);
+        assert_eq!(line["span"]["name"], "login");
+        assert_eq!(line["span"]["user.sub"], "sub-alice");
+    }
+
+    #[test]
+    fn sampling() {
+        assert!(matches!(sampler(1.0), Sampler::AlwaysOn));
+        assert!(matches!(sampler(0.0), Sampler::AlwaysOff));
+        match sampler(0.25) {
+            Sampler::ParentBased(root) => {
+                assert!(
+                    matches!(*root, Sampler::TraceIdRatioBased(rate) if (rate - 0.25).abs() < f64::EPSILON)
+                );
+            }
+            other => panic!("unexpected sampler {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disabled_exporters_are_a_noop() {
+        let config = TelemetryConfig::default();
+        assert!(tracer(&config.tracing).unwrap().is_none());
+        meter(&config.metrics.exporter).unwrap();
+        assert!(prometheus_registry().is_none());
+    }
+
+    // The batch exporters need another thread to flush on shutdown
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exporters_are_set_up() {
+        let config = TracingConfig {
+            exporter: TracingExporterConfig::Stdout,
+            sample_rate: 0.5,
+            ..TracingConfig::default()
+        };
+        assert!(tracer(&config).unwrap().is_some());
+
+        let headers: HashMap<String, String> =
+            [("authorization".to_owned(), "Bearer some-token".to_owned())].into();
+
+        #[cfg(feature = "otlp")]
+        for (endpoint, protocol) in [
+            ("http://localhost:4317", OtlpProtocol::Grpc),
+            (
+                "http://localhost:4318/v1/traces",
+                OtlpProtocol::HttpProtobuf,
+            ),
+        ] {
+            let config = TracingConfig {
+                exporter: TracingExporterConfig::Otlp {
+                    endpoint: Some(endpoint.parse().unwrap()),
+                    protocol,
+                    headers: headers.clone(),
+                },
+                ..TracingConfig::default()
+            };
+            assert!(tracer(&config).unwrap().is_some(), "{:?}", protocol);
+        }
+
+        // gRPC metadata can't hold any header
+        let config = TracingConfig {
+            exporter: TracingExporterConfig::Otlp {
+                endpoint: None,
+                protocol: OtlpProtocol::Grpc,
+                headers: [("not a header".to_owned(), "value".to_owned())].into(),
+            },
+            ..TracingConfig::default()
+        };
+        assert!(tracer(&config).is_err());
+
+        // Metrics are only exported over gRPC
+        let config = MetricsExporterConfig::Otlp {
+            endpoint: None,
+            protocol: OtlpProtocol::HttpProtobuf,
+            headers,
+        };
+        assert!(meter(&config).is_err());
+
+        shutdown();
+    }
+}
@@ -27,5 +27,6 @@
 pub(crate) mod schema;
 mod sections;
 pub(crate) mod util;
+mod validation;
 
-pub use self::{sections::*, util::ConfigurationSection};
+pub use self::{sections::*, util::ConfigurationSection, validation::ConfigError};
@@ -0,0 +1,81 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+/// Configuration related to the administration API
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct AdminConfig {
+    /// Usernames of the administrators. Only their access tokens with the
+    /// admin scope can use the administration API, which is disabled when
+    /// this is empty
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+impl AdminConfig {
+    /// Whether the given user is an administrator
+    #[must_use]
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.users.iter().any(|user| user == username)
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for AdminConfig {
+    fn path() -> &'static str {
+        "admin"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    admin:
+                      users:
+                        - alice
+                "#,
+            )?;
+
+            let config = AdminConfig::load_from_file("config.yaml")?;
+
+            assert!(config.is_admin("alice"));
+            assert!(!config.is_admin("bob"));
+            assert!(!AdminConfig::default().is_admin("alice"));
+
+            Ok(())
+        });
+    }
+}
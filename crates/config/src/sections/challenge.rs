@@ -0,0 +1,164 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+/// Service verifying the challenges solved by users
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum ChallengeServiceConfig {
+    /// Don't ask users to solve challenges
+    None,
+
+    /// Use hCaptcha
+    HCaptcha {
+        /// Site key, used by the widget in the pages
+        site_key: String,
+
+        /// Secret key, used to verify the responses
+        secret_key: String,
+    },
+
+    /// Use Google reCAPTCHA v2
+    ReCaptcha {
+        /// Site key, used by the widget in the pages
+        site_key: String,
+
+        /// Secret key, used to verify the responses
+        secret_key: String,
+    },
+
+    /// Use Cloudflare Turnstile
+    Turnstile {
+        /// Site key, used by the widget in the pages
+        site_key: String,
+
+        /// Secret key, used to verify the responses
+        secret_key: String,
+    },
+}
+
+impl Default for ChallengeServiceConfig {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Configuration related to the challenges (CAPTCHAs) users have to solve
+/// before logging in with a password or adding an email address
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ChallengeConfig {
+    /// Service verifying the challenges
+    #[serde(default, flatten)]
+    pub service: ChallengeServiceConfig,
+
+    /// Also require a challenge response for the password logins through the
+    /// Matrix compatibility layer. Matrix clients can't show the challenge
+    /// widget, so only enable this if all the clients send a response
+    #[serde(default)]
+    pub compat_login: bool,
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for ChallengeConfig {
+    fn path() -> &'static str {
+        "challenge"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    challenge:
+                      service: turnstile
+                      site_key: 1x00000000000000000000AA
+                      secret_key: 1x0000000000000000000000000000000AA
+                "#,
+            )?;
+
+            let config = ChallengeConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(
+                config.service,
+                ChallengeServiceConfig::Turnstile {
+                    site_key: "1x00000000000000000000AA".to_owned(),
+                    secret_key: "1x0000000000000000000000000000000AA".to_owned(),
+                }
+            );
+            assert!(!config.compat_login);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_compat_login() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    challenge:
+                      service: hcaptcha
+                      site_key: site
+                      secret_key: secret
+                      compat_login: true
+                "#,
+            )?;
+
+            let config = ChallengeConfig::load_from_file("config.yaml")?;
+            assert!(config.compat_login);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_disabled() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    challenge:
+                      service: none
+                "#,
+            )?;
+
+            let config = ChallengeConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.service, ChallengeServiceConfig::None);
+
+            Ok(())
+        });
+    }
+}
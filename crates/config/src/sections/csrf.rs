@@ -24,6 +24,26 @@ fn default_ttl() -> Duration {
     Duration::hours(1)
 }
 
+/// How the CSRF tokens are issued and verified
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CsrfStrategy {
+    /// A random token is stored in an encrypted cookie, and forms have to
+    /// submit it back
+    Cookie,
+
+    /// The token is signed with a key derived from the encryption secret,
+    /// over the session ID and the time it was issued. Without a session, it
+    /// is bound to a random value stored in a cookie instead
+    Signed,
+}
+
+impl Default for CsrfStrategy {
+    fn default() -> Self {
+        Self::Cookie
+    }
+}
+
 /// Configuration related to Cross-Site Request Forgery protections
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -33,11 +53,18 @@ pub struct CsrfConfig {
     #[serde(default = "default_ttl")]
     #[serde_as(as = "serde_with::DurationSeconds<i64>")]
     pub ttl: Duration,
+
+    /// How the CSRF tokens are issued and verified
+    #[serde(default)]
+    pub strategy: CsrfStrategy,
 }
 
 impl Default for CsrfConfig {
     fn default() -> Self {
-        Self { ttl: default_ttl() }
+        Self {
+            ttl: default_ttl(),
+            strategy: CsrfStrategy::default(),
+        }
     }
 }
 
@@ -76,6 +103,20 @@ mod tests {
             let config = CsrfConfig::load_from_file("config.yaml")?;
 
             assert_eq!(config.ttl, Duration::minutes(30));
+            assert_eq!(config.strategy, CsrfStrategy::Cookie);
+
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    csrf:
+                      strategy: signed
+                "#,
+            )?;
+
+            let config = CsrfConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.ttl, Duration::hours(1));
+            assert_eq!(config.strategy, CsrfStrategy::Signed);
 
             Ok(())
         });
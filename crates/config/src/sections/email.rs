@@ -12,20 +12,69 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::num::NonZeroU16;
+use std::{
+    num::{NonZeroU16, NonZeroU32},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use lettre::{message::Mailbox, Address};
 use schemars::{
     gen::SchemaGenerator,
-    schema::{InstanceType, Schema, SchemaObject},
+    schema::{InstanceType, Schema, SchemaObject, SubschemaValidation},
     JsonSchema,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_with::serde_as;
 
 use super::ConfigurationSection;
 
-fn mailbox_schema(_gen: &mut SchemaGenerator) -> Schema {
+/// A mailbox written as its address and its display name
+#[derive(Deserialize, JsonSchema)]
+struct MailboxParts {
+    /// The email address
+    #[schemars(schema_with = "address_schema")]
+    address: String,
+
+    /// The display name
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// A mailbox, either as a `Display Name <address@example.com>` string, or as
+/// its address and display name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MailboxConfig {
+    Raw(String),
+    Parts(MailboxParts),
+}
+
+fn deserialize_mailbox<'de, D>(deserializer: D) -> Result<Mailbox, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match MailboxConfig::deserialize(deserializer)? {
+        MailboxConfig::Raw(raw) => raw
+            .parse()
+            .map_err(|e| D::Error::custom(format!("invalid mailbox {:?}: {}", raw, e))),
+        MailboxConfig::Parts(MailboxParts { address, name }) => {
+            let address: Address = address
+                .parse()
+                .map_err(|e| D::Error::custom(format!("invalid address {:?}: {}", address, e)))?;
+            Ok(Mailbox::new(name, address))
+        }
+    }
+}
+
+fn deserialize_optional_mailbox<'de, D>(deserializer: D) -> Result<Option<Mailbox>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_mailbox(deserializer).map(Some)
+}
+
+fn address_schema(_gen: &mut SchemaGenerator) -> Schema {
     Schema::Object(SchemaObject {
         instance_type: Some(InstanceType::String.into()),
         format: Some("email".to_string()),
@@ -33,6 +82,19 @@ fn mailbox_schema(_gen: &mut SchemaGenerator) -> Schema {
     })
 }
 
+fn mailbox_schema(gen: &mut SchemaGenerator) -> Schema {
+    Schema::Object(SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![
+                address_schema(gen),
+                gen.subschema_for::<MailboxParts>(),
+            ]),
+            ..SubschemaValidation::default()
+        })),
+        ..SchemaObject::default()
+    })
+}
+
 fn hostname_schema(_gen: &mut SchemaGenerator) -> Schema {
     Schema::Object(SchemaObject {
         instance_type: Some(InstanceType::String.into()),
@@ -86,6 +148,11 @@ pub enum EmailTransportConfig {
         /// Set of credentials to use
         #[serde(flatten, default)]
         credentials: Option<Credentials>,
+
+        /// Allow the `plain` mode, which sends emails and credentials
+        /// unencrypted. Only use this with a relay on a trusted network
+        #[serde(default)]
+        allow_insecure: bool,
     },
 
     /// Send emails by calling sendmail
@@ -114,22 +181,188 @@ fn default_sendmail_command() -> String {
     "sendmail".to_string()
 }
 
+fn default_max_attempts() -> NonZeroU32 {
+    NonZeroU32::new(3).unwrap()
+}
+
+fn default_initial_backoff() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_max_elapsed() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_rate_limit_per_second() -> NonZeroU32 {
+    NonZeroU32::new(10).unwrap()
+}
+
+fn default_rate_limit_burst() -> NonZeroU32 {
+    NonZeroU32::new(5).unwrap()
+}
+
+fn default_verification_resend_cooldown() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_unverified_email_max_age() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+/// How sending an email should be retried when it fails with a transient error
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EmailRetryConfig {
+    /// Maximum number of attempts, including the first one
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: NonZeroU32,
+
+    /// Time to wait before the first retry in milliseconds. It doubles after
+    /// each attempt
+    #[schemars(with = "u64")]
+    #[serde(default = "default_initial_backoff")]
+    #[serde_as(as = "serde_with::DurationMilliSeconds<u64>")]
+    pub initial_backoff: Duration,
+
+    /// Maximum time spent trying to send one email in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_max_elapsed")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub max_elapsed: Duration,
+}
+
+impl Default for EmailRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff: default_initial_backoff(),
+            max_elapsed: default_max_elapsed(),
+        }
+    }
+}
+
+/// Limits how fast emails are sent, to avoid flooding the relay
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct EmailRateLimitConfig {
+    /// Maximum number of emails sent per second, on average
+    #[serde(default = "default_rate_limit_per_second")]
+    pub per_second: NonZeroU32,
+
+    /// Number of emails which can be sent at once before the rate applies
+    #[serde(default = "default_rate_limit_burst")]
+    pub burst: NonZeroU32,
+}
+
+impl Default for EmailRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_second: default_rate_limit_per_second(),
+            burst: default_rate_limit_burst(),
+        }
+    }
+}
+
+/// Whether an email address can be verified by more than one user
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailSharingPolicy {
+    /// Any number of users can verify the same address
+    AllowShared,
+
+    /// An address can only be verified by one user. Others can still add it,
+    /// but not once it was verified
+    UniqueVerified,
+}
+
+impl Default for EmailSharingPolicy {
+    fn default() -> Self {
+        Self::AllowShared
+    }
+}
+
+impl EmailSharingPolicy {
+    /// Whether a user may add or verify an address, given if it was already
+    /// verified by another user
+    #[must_use]
+    pub fn allows(self, verified_by_other_user: bool) -> bool {
+        match self {
+            Self::AllowShared => true,
+            Self::UniqueVerified => !verified_by_other_user,
+        }
+    }
+}
+
 /// Configuration related to sending emails
+#[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct EmailConfig {
-    /// Email address to use as From when sending emails
-    #[serde(default = "default_email")]
+    /// Email address to use as From when sending emails. Either a
+    /// `Display Name <address@example.com>` string, or an object with the
+    /// `address` and the display `name`
+    #[serde(default = "default_email", deserialize_with = "deserialize_mailbox")]
     #[schemars(schema_with = "mailbox_schema")]
     pub from: Mailbox,
 
-    /// Email address to use as Reply-To when sending emails
-    #[serde(default = "default_email")]
+    /// Email address to use as Reply-To when sending emails, in the same
+    /// format as `from`
+    #[serde(default = "default_email", deserialize_with = "deserialize_mailbox")]
     #[schemars(schema_with = "mailbox_schema")]
     pub reply_to: Mailbox,
 
+    /// Send a hidden copy of every email to this address, in the same format
+    /// as `from`. Useful to monitor the emails sent during a rollout
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_mailbox",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(schema_with = "mailbox_schema")]
+    pub monitor_bcc: Option<Mailbox>,
+
     /// What backend should be used when sending emails
     #[serde(flatten, default)]
     pub transport: EmailTransportConfig,
+
+    /// How failed sends should be retried
+    #[serde(default)]
+    pub retry: EmailRetryConfig,
+
+    /// How fast emails can be sent
+    #[serde(default)]
+    pub rate_limit: EmailRateLimitConfig,
+
+    /// Minimum time between two verification emails for the same address in
+    /// seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_verification_resend_cooldown")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub verification_resend_cooldown: Duration,
+
+    /// How long unverified email addresses are kept, in seconds. Older ones
+    /// are deleted with their verification codes, and `0` keeps them forever
+    #[schemars(with = "u64")]
+    #[serde(default = "default_unverified_email_max_age")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub unverified_email_max_age: Duration,
+
+    /// Whether an email address can be verified by more than one user
+    #[serde(default)]
+    pub sharing_policy: EmailSharingPolicy,
+
+    /// Also ignore the case and the dots in the local part of addresses from
+    /// providers known to do so when comparing them. The domain is always
+    /// compared case-insensitively
+    #[serde(default)]
+    pub normalize_known_providers: bool,
+
+    /// Also check the connection to the SMTP relay in the `/ready` endpoint
+    #[serde(default)]
+    pub readiness_check: bool,
+
+    /// Require users to verify an email address before they can log in. Until
+    /// then, their browser session can only be used to verify it
+    #[serde(default)]
+    pub require_verification: bool,
 }
 
 impl Default for EmailConfig {
@@ -137,7 +370,16 @@ impl Default for EmailConfig {
         Self {
             from: default_email(),
             reply_to: default_email(),
+            monitor_bcc: None,
             transport: EmailTransportConfig::Blackhole,
+            retry: EmailRetryConfig::default(),
+            rate_limit: EmailRateLimitConfig::default(),
+            verification_resend_cooldown: default_verification_resend_cooldown(),
+            unverified_email_max_age: default_unverified_email_max_age(),
+            sharing_policy: EmailSharingPolicy::default(),
+            normalize_known_providers: false,
+            readiness_check: false,
+            require_verification: false,
         }
     }
 }
@@ -156,3 +398,178 @@ impl ConfigurationSection<'_> for EmailConfig {
         Self::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: blackhole
+                      sharing_policy: unique_verified
+                      normalize_known_providers: true
+                      require_verification: true
+                      unverified_email_max_age: 86400
+                "#,
+            )?;
+
+            let config = EmailConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.sharing_policy, EmailSharingPolicy::UniqueVerified);
+            assert!(config.normalize_known_providers);
+            assert!(config.require_verification);
+            assert_eq!(config.unverified_email_max_age, Duration::from_secs(86400));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_smtp_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: smtp
+                      mode: plain
+                      hostname: localhost
+                      allow_insecure: true
+                "#,
+            )?;
+
+            let config = EmailConfig::load_from_file("config.yaml")?;
+
+            assert!(matches!(
+                config.transport,
+                EmailTransportConfig::Smtp {
+                    mode: EmailSmtpMode::Plain,
+                    allow_insecure: true,
+                    ..
+                }
+            ));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_from_mailbox() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: blackhole
+                      from: "Example Auth <noreply@example.com>"
+                      reply_to:
+                        address: support@example.com
+                        name: Example Support
+                "#,
+            )?;
+
+            let config = EmailConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.from.name.as_deref(), Some("Example Auth"));
+            assert_eq!(config.from.email.to_string(), "noreply@example.com");
+            assert_eq!(config.reply_to.name.as_deref(), Some("Example Support"));
+            assert_eq!(config.reply_to.email.to_string(), "support@example.com");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_from_mailbox_from_env() {
+        Jail::expect_with(|jail| {
+            jail.create_file("config.yaml", "email: { transport: blackhole }")?;
+            jail.set_env(
+                "MAS_EMAIL_FROM",
+                "Staging Auth <noreply@staging.example.com>",
+            );
+
+            let config = EmailConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.from.name.as_deref(), Some("Staging Auth"));
+            assert_eq!(config.from.email.to_string(), "noreply@staging.example.com");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_monitor_bcc() {
+        Jail::expect_with(|jail| {
+            jail.create_file("config.yaml", "email: { transport: blackhole }")?;
+            let config = EmailConfig::load_from_file("config.yaml")?;
+            assert!(config.monitor_bcc.is_none());
+
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: blackhole
+                      monitor_bcc: monitoring@example.com
+                "#,
+            )?;
+            let config = EmailConfig::load_from_file("config.yaml")?;
+            assert_eq!(
+                config.monitor_bcc.map(|m| m.email.to_string()).as_deref(),
+                Some("monitoring@example.com")
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn malformed_from_mailbox_fails_to_load() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: blackhole
+                      from: "Example Auth <not an address>"
+                "#,
+            )?;
+            assert!(EmailConfig::load_from_file("config.yaml").is_err());
+
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    email:
+                      transport: blackhole
+                      from:
+                        address: noreply
+                        name: Example Auth
+                "#,
+            )?;
+            assert!(EmailConfig::load_from_file("config.yaml").is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn addresses_are_shared_by_default() {
+        let policy = EmailConfig::default().sharing_policy;
+        assert_eq!(policy, EmailSharingPolicy::AllowShared);
+        assert!(policy.allows(false));
+        assert!(policy.allows(true));
+    }
+
+    #[test]
+    fn unique_verified_rejects_addresses_verified_elsewhere() {
+        let policy = EmailSharingPolicy::UniqueVerified;
+        assert!(policy.allows(false));
+        assert!(!policy.allows(true));
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+fn default_true() -> bool {
+    true
+}
+
+/// Features which can be turned off, here or by the administrators while the
+/// server is running
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct FeaturesConfig {
+    /// Let the users register with a username and a password
+    #[serde(default = "default_true")]
+    pub registration: bool,
+
+    /// Let the users log in and link their account through the upstream
+    /// providers
+    #[serde(default = "default_true")]
+    pub upstream_login: bool,
+}
+
+impl Default for FeaturesConfig {
+    fn default() -> Self {
+        Self {
+            registration: default_true(),
+            upstream_login: default_true(),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for FeaturesConfig {
+    fn path() -> &'static str {
+        "features"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    features:
+                      registration: false
+                "#,
+            )?;
+
+            let config = FeaturesConfig::load_from_file("config.yaml")?;
+
+            assert!(!config.registration);
+            assert!(config.upstream_login);
+
+            Ok(())
+        });
+    }
+}
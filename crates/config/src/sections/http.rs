@@ -12,11 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 
 use async_trait::async_trait;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_with::serde_as;
 use url::Url;
 
 use super::ConfigurationSection;
@@ -29,6 +30,184 @@ fn default_public_base() -> Url {
     "http://[::]:8080".parse().unwrap()
 }
 
+/// The public base has to be an absolute `http` or `https` URL, from which all
+/// the links sent to users are built
+fn deserialize_public_base<'de, D>(deserializer: D) -> Result<Url, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let url = Url::deserialize(deserializer)?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(D::Error::custom(format!(
+            "public_base {:?} must use http or https",
+            url.as_str()
+        )));
+    }
+
+    if url.host().is_none()
+        || !url.username().is_empty()
+        || url.password().is_some()
+        || url.query().is_some()
+        || url.fragment().is_some()
+    {
+        return Err(D::Error::custom(format!(
+            "public_base {:?} must only have a scheme, a host, a port and a path",
+            url.as_str()
+        )));
+    }
+
+    Ok(url)
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".to_owned(), "POST".to_owned()]
+}
+
+fn default_cors_max_age() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+fn cors_origin_example_1() -> &'static str {
+    "https://app.example.com"
+}
+fn cors_origin_example_2() -> &'static str {
+    "*"
+}
+
+/// Cross-Origin Resource Sharing policy of the API endpoints (OAuth 2.0,
+/// OIDC and Matrix compatibility layer), which browser-based clients
+/// call from their own origin. It does not apply to the HTML pages.
+///
+/// By default, no origin is allowed.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, like
+    /// `https://app.example.com`. `*` allows any origin
+    #[schemars(example = "cors_origin_example_1", example = "cors_origin_example_2")]
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+
+    /// HTTP methods allowed in cross-origin requests
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+
+    /// Request headers allowed in cross-origin requests, on top of the ones the
+    /// endpoints need, like `Authorization` and `Content-Type`
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+
+    /// Whether browsers can send credentials, like cookies, with cross-origin
+    /// requests. This can't be used when any origin is allowed
+    #[serde(default)]
+    pub allow_credentials: bool,
+
+    /// How long browsers can cache the result of a preflight request, in
+    /// seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_cors_max_age")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub max_age: Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: default_cors_allowed_methods(),
+            allowed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: default_cors_max_age(),
+        }
+    }
+}
+
+/// Maintenance mode, during which all requests are answered with a `503
+/// Service Unavailable`, except for the health checks
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct MaintenanceConfig {
+    /// Start the service in maintenance mode. On Unix, it can also be toggled
+    /// without a restart by sending `SIGUSR1` to the process
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Message shown to users while the service is in maintenance mode
+    #[serde(default)]
+    pub message: Option<String>,
+
+    /// IP addresses of the administrators, whose requests are still served
+    /// during maintenance
+    #[serde(default)]
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+/// Content Security Policy of the HTML pages, which limits where they can load
+/// resources from
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CspConfig {
+    /// Hosts from which the pages can also load scripts, styles, images and
+    /// fonts, like `https://assets.example.com`, when the templates use
+    /// assets from another host. The hosts of the challenge service are
+    /// always allowed
+    #[schemars(example = "csp_asset_host_example")]
+    #[serde(default)]
+    pub asset_hosts: Vec<String>,
+}
+
+/// The `SameSite` attribute of the cookies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieSameSite {
+    /// Cookies are sent with top-level navigations from other sites, but not
+    /// with their subrequests and form submissions
+    Lax,
+
+    /// Cookies are only sent with requests from the same site. This breaks
+    /// the logins coming back from an upstream OAuth 2.0 provider
+    Strict,
+
+    /// Cookies are sent with all requests. This requires `secure`
+    None,
+}
+
+impl Default for CookieSameSite {
+    fn default() -> Self {
+        Self::Lax
+    }
+}
+
+/// Attributes of the cookies set by the service, like the session and CSRF
+/// cookies
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CookiesConfig {
+    /// Which cross-site requests browsers send the cookies with
+    #[serde(default)]
+    pub same_site: CookieSameSite,
+
+    /// Only send the cookies over HTTPS
+    #[serde(default)]
+    pub secure: bool,
+
+    /// Domain the cookies are sent to, including its subdomains. By default,
+    /// they are only sent to the host which set them
+    #[serde(default)]
+    pub domain: Option<String>,
+
+    /// Prefix of the names of the cookies, like `__Host-`, to tell them apart
+    /// from the ones of other services on the same domain
+    #[serde(default)]
+    pub name_prefix: String,
+}
+
+fn csp_asset_host_example() -> &'static str {
+    "https://assets.example.com"
+}
+
 fn http_address_example_1() -> &'static str {
     "[::1]:8080"
 }
@@ -43,6 +222,7 @@ fn http_address_example_4() -> &'static str {
 }
 
 /// Configuration related to the web server
+#[serde_as]
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct HttpConfig {
     /// IP and port the server should listen to
@@ -61,7 +241,39 @@ pub struct HttpConfig {
     pub web_root: Option<PathBuf>,
 
     /// Public URL base from where the authentication service is reachable
+    #[serde(deserialize_with = "deserialize_public_base")]
     pub public_base: Url,
+
+    /// Trust the `X-Forwarded-Proto` and `X-Forwarded-Host` headers to build
+    /// absolute URLs, and the `X-Forwarded-For` header to find the address of
+    /// the client. Only the last value of each header, set by the reverse
+    /// proxy in front of the service, is used. The issuer and the links sent
+    /// by email always use the public base
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+
+    /// Maximum time to wait for the in-flight requests and the queued emails
+    /// when shutting down, in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_shutdown_timeout")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub shutdown_timeout: Duration,
+
+    /// Cross-Origin Resource Sharing policy of the API endpoints
+    #[serde(default)]
+    pub cors: CorsConfig,
+
+    /// Maintenance mode
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+
+    /// Attributes of the cookies
+    #[serde(default)]
+    pub cookies: CookiesConfig,
+
+    /// Content Security Policy of the HTML pages
+    #[serde(default)]
+    pub csp: CspConfig,
 }
 
 impl Default for HttpConfig {
@@ -70,6 +282,12 @@ impl Default for HttpConfig {
             address: default_http_address(),
             web_root: None,
             public_base: default_public_base(),
+            trust_forwarded_headers: false,
+            shutdown_timeout: default_shutdown_timeout(),
+            cors: CorsConfig::default(),
+            maintenance: MaintenanceConfig::default(),
+            cookies: CookiesConfig::default(),
+            csp: CspConfig::default(),
         }
     }
 }
@@ -88,3 +306,221 @@ impl ConfigurationSection<'_> for HttpConfig {
         Self::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_shutdown_timeout() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                      shutdown_timeout: 5
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.shutdown_timeout, Duration::from_secs(5));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn cors_is_locked_down_by_default() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert!(config.cors.allowed_origins.is_empty());
+            assert!(!config.cors.allow_credentials);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_cors() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                      cors:
+                        allowed_origins:
+                          - https://app.example.com
+                        allowed_headers:
+                          - X-Custom-Header
+                        allow_credentials: true
+                        max_age: 600
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.cors.allowed_origins, ["https://app.example.com"]);
+            assert_eq!(config.cors.allowed_methods, ["GET", "POST"]);
+            assert_eq!(config.cors.allowed_headers, ["X-Custom-Header"]);
+            assert!(config.cors.allow_credentials);
+            assert_eq!(config.cors.max_age, Duration::from_secs(600));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_maintenance() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                      maintenance:
+                        enabled: true
+                        message: Back in an hour
+                        allowed_ips:
+                          - 192.0.2.1
+                          - "2001:db8::1"
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert!(config.maintenance.enabled);
+            assert_eq!(
+                config.maintenance.message.as_deref(),
+                Some("Back in an hour")
+            );
+            assert_eq!(
+                config.maintenance.allowed_ips,
+                [
+                    "192.0.2.1".parse::<IpAddr>().unwrap(),
+                    "2001:db8::1".parse().unwrap()
+                ]
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_cookies() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.cookies.same_site, CookieSameSite::Lax);
+            assert!(!config.cookies.secure);
+            assert_eq!(config.cookies.domain, None);
+            assert_eq!(config.cookies.name_prefix, "");
+
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                      cookies:
+                        same_site: none
+                        secure: true
+                        domain: example.com
+                        name_prefix: __Secure-
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.cookies.same_site, CookieSameSite::None);
+            assert!(config.cookies.secure);
+            assert_eq!(config.cookies.domain.as_deref(), Some("example.com"));
+            assert_eq!(config.cookies.name_prefix, "__Secure-");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_csp() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: https://auth.example.com/
+                      csp:
+                        asset_hosts:
+                          - https://assets.example.com
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.csp.asset_hosts, ["https://assets.example.com"]);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_public_base() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    http:
+                      public_base: "https://[2001:db8::1]:8443/auth/"
+                      trust_forwarded_headers: true
+                "#,
+            )?;
+
+            let config = HttpConfig::load_from_file("config.yaml")?;
+            assert_eq!(
+                config.public_base.as_str(),
+                "https://[2001:db8::1]:8443/auth/"
+            );
+            assert!(config.trust_forwarded_headers);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn invalid_public_base() {
+        for public_base in [
+            "/relative",
+            "ftp://auth.example.com/",
+            "https://auth.example.com/?query",
+            "https://user@auth.example.com/",
+        ] {
+            Jail::expect_with(|jail| {
+                jail.create_file(
+                    "config.yaml",
+                    &format!("http:\n  public_base: {:?}\n", public_base),
+                )?;
+
+                assert!(
+                    HttpConfig::load_from_file("config.yaml").is_err(),
+                    "{} should be rejected",
+                    public_base
+                );
+
+                Ok(())
+            });
+        }
+    }
+}
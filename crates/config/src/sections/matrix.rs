@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::Duration;
+
 use async_trait::async_trait;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -23,6 +25,18 @@ fn default_homeserver() -> String {
     "localhost:8008".to_string()
 }
 
+fn default_login_database_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_max_body_size() -> usize {
+    64 * 1024
+}
+
+fn default_password_login_enabled() -> bool {
+    true
+}
+
 /// Configuration related to the Matrix homeserver
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -30,12 +44,40 @@ pub struct MatrixConfig {
     /// Time-to-live of a CSRF token in seconds
     #[serde(default = "default_homeserver")]
     pub homeserver: String,
+
+    /// Maximum time a login through the compatibility API waits for a
+    /// database connection, in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_login_database_timeout")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub login_database_timeout: Duration,
+
+    /// Maximum size in bytes of the JSON bodies accepted by the compatibility
+    /// API
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+
+    /// Advertise and accept password logins through the compatibility API.
+    /// Disable it when the users only log in through SSO
+    #[serde(default = "default_password_login_enabled")]
+    pub password_login_enabled: bool,
+
+    /// Accept the access tokens of the compatibility API from the
+    /// `access_token` query parameter, on the requests which don't change
+    /// anything, for legacy clients. Tokens in URLs tend to end up in logs, so
+    /// this is deprecated and disabled by default
+    #[serde(default)]
+    pub access_token_in_query: bool,
 }
 
 impl Default for MatrixConfig {
     fn default() -> Self {
         Self {
             homeserver: default_homeserver(),
+            login_database_timeout: default_login_database_timeout(),
+            max_body_size: default_max_body_size(),
+            password_login_enabled: default_password_login_enabled(),
+            access_token_in_query: false,
         }
     }
 }
@@ -75,6 +117,66 @@ mod tests {
             let config = MatrixConfig::load_from_file("config.yaml")?;
 
             assert_eq!(config.homeserver, "matrix.org".to_string());
+            assert_eq!(config.max_body_size, 64 * 1024);
+            assert!(config.password_login_enabled);
+            assert!(!config.access_token_in_query);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_max_body_size() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    matrix:
+                      max_body_size: 1024
+                "#,
+            )?;
+
+            let config = MatrixConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.max_body_size, 1024);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn disable_password_login() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    matrix:
+                      password_login_enabled: false
+                "#,
+            )?;
+
+            let config = MatrixConfig::load_from_file("config.yaml")?;
+
+            assert!(!config.password_login_enabled);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn enable_access_token_in_query() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    matrix:
+                      access_token_in_query: true
+                "#,
+            )?;
+
+            let config = MatrixConfig::load_from_file("config.yaml")?;
+
+            assert!(config.access_token_in_query);
 
             Ok(())
         });
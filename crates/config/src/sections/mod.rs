@@ -16,31 +16,52 @@ use async_trait::async_trait;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+mod admin;
+mod challenge;
 mod clients;
 mod csrf;
 mod database;
 mod email;
+mod features;
 mod http;
 mod matrix;
+mod oauth2;
+mod passwords;
 mod policy;
 mod secrets;
+mod sessions;
 mod telemetry;
 mod templates;
+mod upstream_oauth2;
+mod usernames;
+mod webhooks;
 
 pub use self::{
+    admin::AdminConfig,
+    challenge::{ChallengeConfig, ChallengeServiceConfig},
     clients::{ClientAuthMethodConfig, ClientConfig, ClientsConfig},
-    csrf::CsrfConfig,
+    csrf::{CsrfConfig, CsrfStrategy},
     database::DatabaseConfig,
-    email::{EmailConfig, EmailSmtpMode, EmailTransportConfig},
-    http::HttpConfig,
+    email::{
+        EmailConfig, EmailRateLimitConfig, EmailRetryConfig, EmailSharingPolicy, EmailSmtpMode,
+        EmailTransportConfig,
+    },
+    features::FeaturesConfig,
+    http::{CookieSameSite, CookiesConfig, CorsConfig, CspConfig, HttpConfig, MaintenanceConfig},
     matrix::MatrixConfig,
+    oauth2::{ClientCacheConfig, ClientRegistrationConfig, OAuth2Config, TokenCleanupConfig},
+    passwords::PasswordsConfig,
     policy::PolicyConfig,
     secrets::{Encrypter, SecretsConfig},
+    sessions::SessionsConfig,
     telemetry::{
-        MetricsConfig, MetricsExporterConfig, Propagator, TelemetryConfig, TracingConfig,
-        TracingExporterConfig,
+        LogFormat, LoggingConfig, MetricsConfig, MetricsExporterConfig, OtlpProtocol, Propagator,
+        TelemetryConfig, TracingConfig, TracingExporterConfig,
     },
-    templates::TemplatesConfig,
+    templates::{ScopeDescriptionConfig, TemplatesConfig},
+    upstream_oauth2::{UpstreamOAuth2Config, UpstreamProviderConfig, UpstreamProviderKind},
+    usernames::UsernamesConfig,
+    webhooks::{WebhookEndpointConfig, WebhooksConfig},
 };
 use crate::util::ConfigurationSection;
 
@@ -71,6 +92,10 @@ pub struct RootConfig {
     #[serde(default)]
     pub csrf: CsrfConfig,
 
+    /// Configuration related to the browser sessions
+    #[serde(default)]
+    pub sessions: SessionsConfig,
+
     /// Configuration related to sending emails
     #[serde(default)]
     pub email: EmailConfig,
@@ -82,9 +107,41 @@ pub struct RootConfig {
     #[serde(default)]
     pub matrix: MatrixConfig,
 
+    /// Requirements new passwords have to meet
+    #[serde(default)]
+    pub passwords: PasswordsConfig,
+
+    /// Requirements the usernames of new users have to meet
+    #[serde(default)]
+    pub usernames: UsernamesConfig,
+
     /// Configuration related to the OPA policies
     #[serde(default)]
     pub policy: PolicyConfig,
+
+    /// Challenges users have to solve to deter automated abuse
+    #[serde(default)]
+    pub challenge: ChallengeConfig,
+
+    /// Configuration related to the OAuth 2.0 authorization server
+    #[serde(default)]
+    pub oauth2: OAuth2Config,
+
+    /// Upstream OIDC providers users can log in with
+    #[serde(default)]
+    pub upstream_oauth2: UpstreamOAuth2Config,
+
+    /// Configuration related to the administration API
+    #[serde(default)]
+    pub admin: AdminConfig,
+
+    /// Endpoints notified of the account lifecycle events
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+
+    /// Features which can be turned off
+    #[serde(default)]
+    pub features: FeaturesConfig,
 }
 
 #[async_trait]
@@ -101,10 +158,19 @@ impl ConfigurationSection<'_> for RootConfig {
             telemetry: TelemetryConfig::generate().await?,
             templates: TemplatesConfig::generate().await?,
             csrf: CsrfConfig::generate().await?,
+            sessions: SessionsConfig::generate().await?,
             email: EmailConfig::generate().await?,
             secrets: SecretsConfig::generate().await?,
             matrix: MatrixConfig::generate().await?,
+            passwords: PasswordsConfig::generate().await?,
+            usernames: UsernamesConfig::generate().await?,
             policy: PolicyConfig::generate().await?,
+            challenge: ChallengeConfig::generate().await?,
+            oauth2: OAuth2Config::generate().await?,
+            upstream_oauth2: UpstreamOAuth2Config::generate().await?,
+            admin: AdminConfig::generate().await?,
+            webhooks: WebhooksConfig::generate().await?,
+            features: FeaturesConfig::generate().await?,
         })
     }
 
@@ -116,10 +182,19 @@ impl ConfigurationSection<'_> for RootConfig {
             telemetry: TelemetryConfig::test(),
             templates: TemplatesConfig::test(),
             csrf: CsrfConfig::test(),
+            sessions: SessionsConfig::test(),
             email: EmailConfig::test(),
             secrets: SecretsConfig::test(),
             matrix: MatrixConfig::test(),
+            passwords: PasswordsConfig::test(),
+            usernames: UsernamesConfig::test(),
             policy: PolicyConfig::test(),
+            challenge: ChallengeConfig::test(),
+            oauth2: OAuth2Config::test(),
+            upstream_oauth2: UpstreamOAuth2Config::test(),
+            admin: AdminConfig::test(),
+            webhooks: WebhooksConfig::test(),
+            features: FeaturesConfig::test(),
         }
     }
 }
@@ -0,0 +1,279 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::time::Duration;
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use super::ConfigurationSection;
+
+fn default_registration_enabled() -> bool {
+    true
+}
+
+/// Configuration of the dynamic client registration endpoint
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClientRegistrationConfig {
+    /// Whether clients can register themselves
+    #[serde(default = "default_registration_enabled")]
+    pub enabled: bool,
+
+    /// If set, clients have to send this token as a bearer token to register
+    #[serde(default)]
+    pub initial_access_token: Option<String>,
+}
+
+impl Default for ClientRegistrationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_registration_enabled(),
+            initial_access_token: None,
+        }
+    }
+}
+
+fn default_client_cache_capacity() -> usize {
+    1000
+}
+
+fn default_client_cache_ttl() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Configuration of the in-memory cache of the clients
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClientCacheConfig {
+    /// How many clients to keep in memory. `0` disables the cache
+    #[serde(default = "default_client_cache_capacity")]
+    pub capacity: usize,
+
+    /// How long a client is kept in memory, in seconds. This bounds how long
+    /// an instance can miss a change made by another one
+    #[schemars(with = "u64")]
+    #[serde(default = "default_client_cache_ttl")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub ttl: Duration,
+
+    /// Listen to the database notifications to evict the clients as soon as
+    /// they change. Disable it if the database is behind a pooler which
+    /// doesn't support `LISTEN`, like `PgBouncer` in transaction mode
+    #[serde(default = "default_true")]
+    pub notifications: bool,
+}
+
+impl Default for ClientCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: default_client_cache_capacity(),
+            ttl: default_client_cache_ttl(),
+            notifications: true,
+        }
+    }
+}
+
+fn default_token_cleanup_interval() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn default_token_cleanup_grace_period() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+fn default_token_cleanup_batch_size() -> u32 {
+    1000
+}
+
+/// Configuration of the periodic deletion of the expired and revoked tokens
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TokenCleanupConfig {
+    /// How often the tokens are deleted, in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_token_cleanup_interval")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub interval: Duration,
+
+    /// How long the tokens are kept after they expired, were revoked or were
+    /// replaced, in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_token_cleanup_grace_period")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub grace_period: Duration,
+
+    /// How many tokens of each kind are deleted at most on each run, to keep
+    /// the database locks short
+    #[serde(default = "default_token_cleanup_batch_size")]
+    pub batch_size: u32,
+}
+
+impl Default for TokenCleanupConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_token_cleanup_interval(),
+            grace_period: default_token_cleanup_grace_period(),
+            batch_size: default_token_cleanup_batch_size(),
+        }
+    }
+}
+
+/// Configuration related to the OAuth 2.0 authorization server
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct OAuth2Config {
+    /// Require all the clients asking for an authorization code to use PKCE
+    /// with the `S256` challenge method. By default, only public clients
+    /// have to use PKCE, and the `plain` method is allowed
+    #[serde(default)]
+    pub require_pkce_s256: bool,
+
+    /// Dynamic client registration
+    #[serde(default)]
+    pub registration: ClientRegistrationConfig,
+
+    /// In-memory cache of the clients
+    #[serde(default)]
+    pub client_cache: ClientCacheConfig,
+
+    /// Deletion of the expired and revoked tokens
+    #[serde(default)]
+    pub token_cleanup: TokenCleanupConfig,
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for OAuth2Config {
+    fn path() -> &'static str {
+        "oauth2"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    oauth2:
+                      require_pkce_s256: true
+                "#,
+            )?;
+
+            let config = OAuth2Config::load_from_file("config.yaml")?;
+
+            assert!(config.require_pkce_s256);
+            assert!(config.registration.enabled);
+            assert_eq!(config.registration.initial_access_token, None);
+            assert_eq!(config.client_cache.capacity, 1000);
+            assert_eq!(config.client_cache.ttl, Duration::from_secs(300));
+            assert!(config.client_cache.notifications);
+            assert_eq!(config.token_cleanup.interval, Duration::from_secs(15));
+            assert_eq!(config.token_cleanup.grace_period, Duration::from_secs(900));
+            assert_eq!(config.token_cleanup.batch_size, 1000);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_registration_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    oauth2:
+                      registration:
+                        initial_access_token: s3cr3t
+                "#,
+            )?;
+
+            let config = OAuth2Config::load_from_file("config.yaml")?;
+
+            assert!(!config.require_pkce_s256);
+            assert!(config.registration.enabled);
+            assert_eq!(
+                config.registration.initial_access_token.as_deref(),
+                Some("s3cr3t")
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_client_cache_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    oauth2:
+                      client_cache:
+                        capacity: 50
+                        ttl: 30
+                        notifications: false
+                "#,
+            )?;
+
+            let config = OAuth2Config::load_from_file("config.yaml")?;
+
+            assert_eq!(config.client_cache.capacity, 50);
+            assert_eq!(config.client_cache.ttl, Duration::from_secs(30));
+            assert!(!config.client_cache.notifications);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_token_cleanup_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    oauth2:
+                      token_cleanup:
+                        interval: 60
+                        grace_period: 3600
+                        batch_size: 100
+                "#,
+            )?;
+
+            let config = OAuth2Config::load_from_file("config.yaml")?;
+
+            assert_eq!(config.token_cleanup.interval, Duration::from_secs(60));
+            assert_eq!(config.token_cleanup.grace_period, Duration::from_secs(3600));
+            assert_eq!(config.token_cleanup.batch_size, 100);
+
+            Ok(())
+        });
+    }
+}
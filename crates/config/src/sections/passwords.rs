@@ -0,0 +1,91 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+fn default_min_length() -> usize {
+    8
+}
+
+fn default_min_complexity() -> u8 {
+    0
+}
+
+/// Requirements new passwords have to meet
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PasswordsConfig {
+    /// Minimum number of characters of a password
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+
+    /// Minimum number of character classes (lowercase, uppercase, digits and
+    /// symbols) a password has to mix. `0` disables the check
+    #[serde(default = "default_min_complexity")]
+    pub min_complexity: u8,
+}
+
+impl Default for PasswordsConfig {
+    fn default() -> Self {
+        Self {
+            min_length: default_min_length(),
+            min_complexity: default_min_complexity(),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for PasswordsConfig {
+    fn path() -> &'static str {
+        "passwords"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    passwords:
+                      min_complexity: 3
+                "#,
+            )?;
+
+            let config = PasswordsConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.min_length, 8);
+            assert_eq!(config.min_complexity, 3);
+
+            Ok(())
+        });
+    }
+}
@@ -17,10 +17,10 @@ use std::{path::PathBuf, sync::Arc};
 use anyhow::Context;
 use async_trait::async_trait;
 use chacha20poly1305::{
-    aead::{generic_array::GenericArray, Aead, NewAead},
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
     ChaCha20Poly1305,
 };
-use cookie::Key;
+use cookie::{Cookie, CookieJar, Key};
 use data_encoding::BASE64;
 use mas_jose::StaticKeystore;
 use pkcs8::DecodePrivateKey;
@@ -36,11 +36,29 @@ use tracing::info;
 
 use super::ConfigurationSection;
 
-/// Helps encrypting and decrypting data
+/// A key used to encrypt cookies and payloads
+struct EncryptionKey {
+    cookie_key: Key,
+    aead: ChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    fn new(key: &[u8; 32]) -> Self {
+        let cookie_key = Key::derive_from(&key[..]);
+        let aead = ChaCha20Poly1305::new(GenericArray::from_slice(key));
+        Self { cookie_key, aead }
+    }
+}
+
+/// Helps encrypting and decrypting data.
+///
+/// Everything is encrypted with the current key. The retired keys are only
+/// used to decrypt what was encrypted before the key was rotated.
 #[derive(Clone)]
 pub struct Encrypter {
     cookie_key: Arc<Key>,
     aead: Arc<ChaCha20Poly1305>,
+    retired: Arc<[EncryptionKey]>,
 }
 
 // TODO: move this somewhere else
@@ -48,12 +66,60 @@ impl Encrypter {
     /// Creates an [`Encrypter`] out of an encryption key
     #[must_use]
     pub fn new(key: &[u8; 32]) -> Self {
-        let cookie_key = Key::derive_from(&key[..]);
-        let cookie_key = Arc::new(cookie_key);
-        let key = GenericArray::from_slice(key);
-        let aead = ChaCha20Poly1305::new(key);
-        let aead = Arc::new(aead);
-        Self { cookie_key, aead }
+        let EncryptionKey { cookie_key, aead } = EncryptionKey::new(key);
+        Self {
+            cookie_key: Arc::new(cookie_key),
+            aead: Arc::new(aead),
+            retired: Vec::new().into(),
+        }
+    }
+
+    /// Also decrypt what was encrypted with the given retired keys
+    #[must_use]
+    pub fn with_retired_keys<'a>(mut self, keys: impl IntoIterator<Item = &'a [u8; 32]>) -> Self {
+        self.retired = keys.into_iter().map(EncryptionKey::new).collect();
+        self
+    }
+
+    /// Whether there are retired keys to decrypt with
+    #[must_use]
+    pub fn has_retired_keys(&self) -> bool {
+        !self.retired.is_empty()
+    }
+
+    /// Encrypt again with the current key a private cookie which was encrypted
+    /// with a retired key.
+    ///
+    /// Returns `None` if the cookie doesn't need it, because it is encrypted
+    /// with the current key, or can't be decrypted with any key.
+    #[must_use]
+    pub fn upgrade_cookie(&self, cookie: &Cookie<'static>) -> Option<Cookie<'static>> {
+        if !self.has_retired_keys() {
+            return None;
+        }
+
+        let mut jar = CookieJar::new();
+        if jar
+            .private(&self.cookie_key)
+            .decrypt(cookie.clone())
+            .is_some()
+        {
+            return None;
+        }
+
+        let decrypted = self
+            .retired
+            .iter()
+            .find_map(|retired| jar.private(&retired.cookie_key).decrypt(cookie.clone()))?;
+
+        jar.private_mut(&self.cookie_key).add(decrypted);
+        jar.get(cookie.name()).cloned()
+    }
+
+    /// Key to sign payloads with, derived from the encryption key
+    #[must_use]
+    pub fn signing_key(&self) -> &[u8] {
+        self.cookie_key.signing()
     }
 
     /// Encrypt a payload
@@ -62,20 +128,63 @@ impl Encrypter {
     ///
     /// Will return `Err` when the payload failed to encrypt
     pub fn encrypt(&self, nonce: &[u8; 12], decrypted: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.encrypt_with_ad(nonce, &[], decrypted)
+    }
+
+    /// Encrypt a payload, bound to some associated data
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when the payload failed to encrypt
+    pub fn encrypt_with_ad(
+        &self,
+        nonce: &[u8; 12],
+        associated_data: &[u8],
+        decrypted: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
         let nonce = GenericArray::from_slice(&nonce[..]);
-        let encrypted = self.aead.encrypt(nonce, decrypted)?;
+        let payload = Payload {
+            msg: decrypted,
+            aad: associated_data,
+        };
+        let encrypted = self.aead.encrypt(nonce, payload)?;
         Ok(encrypted)
     }
 
-    /// Decrypts a payload
+    /// Decrypts a payload, with the current key or else with the retired ones
     ///
     /// # Errors
     ///
     /// Will return `Err` when the payload failed to decrypt
     pub fn decrypt(&self, nonce: &[u8; 12], encrypted: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_with_ad(nonce, &[], encrypted)
+    }
+
+    /// Decrypts a payload bound to some associated data, with the current key
+    /// or else with the retired ones
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when the payload failed to decrypt, including when
+    /// it was bound to other associated data
+    pub fn decrypt_with_ad(
+        &self,
+        nonce: &[u8; 12],
+        associated_data: &[u8],
+        encrypted: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
         let nonce = GenericArray::from_slice(&nonce[..]);
-        let encrypted = self.aead.decrypt(nonce, encrypted)?;
-        Ok(encrypted)
+        let payload = || Payload {
+            msg: encrypted,
+            aad: associated_data,
+        };
+        let decrypted = self.aead.decrypt(nonce, payload()).or_else(|e| {
+            self.retired
+                .iter()
+                .find_map(|retired| retired.aead.decrypt(nonce, payload()).ok())
+                .ok_or(e)
+        })?;
+        Ok(decrypted)
     }
 
     /// Encrypt a payload to a self-contained base64-encoded string
@@ -84,8 +193,24 @@ impl Encrypter {
     ///
     /// Will return `Err` when the payload failed to encrypt
     pub fn encryt_to_string(&self, decrypted: &[u8]) -> anyhow::Result<String> {
+        self.encrypt_to_string_with_ad(&[], decrypted)
+    }
+
+    /// Encrypt a payload to a self-contained base64-encoded string, bound to
+    /// some associated data, like the ID of what it belongs to. It then only
+    /// decrypts with the same associated data, so that the encrypted values of
+    /// two rows can't be swapped
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when the payload failed to encrypt
+    pub fn encrypt_to_string_with_ad(
+        &self,
+        associated_data: &[u8],
+        decrypted: &[u8],
+    ) -> anyhow::Result<String> {
         let nonce = rand::random();
-        let encrypted = self.encrypt(&nonce, decrypted)?;
+        let encrypted = self.encrypt_with_ad(&nonce, associated_data, decrypted)?;
         let encrypted = [&nonce[..], &encrypted].concat();
         let encrypted = BASE64.encode(&encrypted);
         Ok(encrypted)
@@ -97,6 +222,21 @@ impl Encrypter {
     ///
     /// Will return `Err` when the payload failed to decrypt
     pub fn decrypt_string(&self, encrypted: &str) -> anyhow::Result<Vec<u8>> {
+        self.decrypt_string_with_ad(&[], encrypted)
+    }
+
+    /// Decrypt a payload from a self-contained base64-encoded string, bound to
+    /// some associated data
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when the payload failed to decrypt, including when
+    /// it was bound to other associated data
+    pub fn decrypt_string_with_ad(
+        &self,
+        associated_data: &[u8],
+        encrypted: &str,
+    ) -> anyhow::Result<Vec<u8>> {
         let encrypted = BASE64.decode(encrypted.as_bytes())?;
 
         let nonce: &[u8; 12] = encrypted
@@ -108,9 +248,9 @@ impl Encrypter {
             .get(12..)
             .ok_or_else(|| anyhow::anyhow!("invalid payload serialization"))?;
 
-        let decrypted_client_secret = self.decrypt(nonce, payload)?;
+        let decrypted = self.decrypt_with_ad(nonce, associated_data, payload)?;
 
-        Ok(decrypted_client_secret)
+        Ok(decrypted)
     }
 }
 
@@ -158,6 +298,13 @@ pub struct SecretsConfig {
     #[serde_as(as = "serde_with::hex::Hex")]
     encryption: [u8; 32],
 
+    /// Previous encryption keys, only used to decrypt the cookies and payloads
+    /// encrypted before the key was rotated
+    #[schemars(with = "Vec<String>")]
+    #[serde(default)]
+    #[serde_as(as = "Vec<serde_with::hex::Hex>")]
+    retired_encryption: Vec<[u8; 32]>,
+
     /// List of private keys to use for signing and encrypting payloads
     #[serde(default)]
     keys: Vec<KeyConfig>,
@@ -223,10 +370,15 @@ impl SecretsConfig {
         Ok(store)
     }
 
+    /// Whether there is at least one key to sign payloads with
+    pub(crate) fn has_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
     /// Derive an [`Encrypter`] out of the config
     #[must_use]
     pub fn encrypter(&self) -> Encrypter {
-        Encrypter::new(&self.encryption)
+        Encrypter::new(&self.encryption).with_retired_keys(&self.retired_encryption)
     }
 }
 
@@ -273,6 +425,7 @@ impl ConfigurationSection<'_> for SecretsConfig {
 
         Ok(Self {
             encryption: rand::random(),
+            retired_encryption: Vec::new(),
             keys: vec![rsa_key, ecdsa_key],
         })
     }
@@ -312,7 +465,151 @@ impl ConfigurationSection<'_> for SecretsConfig {
 
         Self {
             encryption: [0xEA; 32],
+            retired_encryption: Vec::new(),
             keys: vec![rsa_key, ecdsa_key],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    /// Encrypt a private cookie like the handlers do
+    fn encrypt_cookie(encrypter: &Encrypter, value: &str) -> Cookie<'static> {
+        let key: Key = encrypter.clone().into();
+        let mut jar = CookieJar::new();
+        jar.private_mut(&key)
+            .add(Cookie::new("session", value.to_owned()));
+        jar.get("session").unwrap().clone()
+    }
+
+    fn decrypt_cookie(encrypter: &Encrypter, cookie: &Cookie<'static>) -> Option<String> {
+        let key: Key = encrypter.clone().into();
+        CookieJar::new()
+            .private(&key)
+            .decrypt(cookie.clone())
+            .map(|cookie| cookie.value().to_owned())
+    }
+
+    #[test]
+    fn retired_cookie_keys() {
+        let old = Encrypter::new(&[0x01; 32]);
+        let current = Encrypter::new(&[0x02; 32]);
+        let rotated = Encrypter::new(&[0x02; 32]).with_retired_keys(&[[0x01; 32]]);
+
+        // A cookie encrypted with the retired key is encrypted again with the
+        // current one
+        let cookie = encrypt_cookie(&old, "hello");
+        assert_eq!(decrypt_cookie(&current, &cookie), None);
+        let upgraded = rotated.upgrade_cookie(&cookie).unwrap();
+        assert_eq!(
+            decrypt_cookie(&current, &upgraded).as_deref(),
+            Some("hello")
+        );
+        assert_eq!(decrypt_cookie(&old, &upgraded), None);
+
+        // New cookies use the current key, and don't need it
+        let cookie = encrypt_cookie(&rotated, "hello");
+        assert_eq!(decrypt_cookie(&current, &cookie).as_deref(), Some("hello"));
+        assert!(rotated.upgrade_cookie(&cookie).is_none());
+
+        // Without retired keys, nothing is decrypted with the old key
+        assert!(current
+            .upgrade_cookie(&encrypt_cookie(&old, "hello"))
+            .is_none());
+
+        // Cookies encrypted with an unknown key are left as they are
+        let unknown = Encrypter::new(&[0x03; 32]);
+        assert!(rotated
+            .upgrade_cookie(&encrypt_cookie(&unknown, "hello"))
+            .is_none());
+    }
+
+    #[test]
+    fn retired_payload_keys() {
+        let old = Encrypter::new(&[0x01; 32]);
+        let rotated = Encrypter::new(&[0x02; 32]).with_retired_keys(&[[0x01; 32]]);
+
+        let encrypted = old.encryt_to_string(b"secret").unwrap();
+        assert_eq!(rotated.decrypt_string(&encrypted).unwrap(), b"secret");
+
+        // New payloads can't be decrypted with the retired key
+        let encrypted = rotated.encryt_to_string(b"secret").unwrap();
+        assert!(old.decrypt_string(&encrypted).is_err());
+        assert!(Encrypter::new(&[0x03; 32])
+            .decrypt_string(&encrypted)
+            .is_err());
+    }
+
+    #[test]
+    fn associated_data() {
+        let encrypter = Encrypter::new(&[0x01; 32]);
+
+        let ciphertext = encrypter
+            .encrypt_to_string_with_ad(b"client-a", b"secret")
+            .unwrap();
+        assert_eq!(
+            encrypter
+                .decrypt_string_with_ad(b"client-a", &ciphertext)
+                .unwrap(),
+            b"secret"
+        );
+
+        // It can't be moved to something else
+        assert!(encrypter
+            .decrypt_string_with_ad(b"client-b", &ciphertext)
+            .is_err());
+        assert!(encrypter.decrypt_string(&ciphertext).is_err());
+
+        // Values ciphertext without associated data don't decrypt with some
+        let ciphertext = encrypter.encryt_to_string(b"secret").unwrap();
+        assert!(encrypter
+            .decrypt_string_with_ad(b"client-a", &ciphertext)
+            .is_err());
+        assert_eq!(encrypter.decrypt_string(&ciphertext).unwrap(), b"secret");
+
+        // The retired keys are bound the same way
+        let rotated = Encrypter::new(&[0x02; 32]).with_retired_keys(&[[0x01; 32]]);
+        let ciphertext = encrypter
+            .encrypt_to_string_with_ad(b"client-a", b"secret")
+            .unwrap();
+        assert_eq!(
+            rotated
+                .decrypt_string_with_ad(b"client-a", &ciphertext)
+                .unwrap(),
+            b"secret"
+        );
+        assert!(rotated
+            .decrypt_string_with_ad(b"client-b", &ciphertext)
+            .is_err());
+    }
+
+    #[test]
+    fn load_retired_keys() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    secrets:
+                      encryption: 0202020202020202020202020202020202020202020202020202020202020202
+                      retired_encryption:
+                        - 0101010101010101010101010101010101010101010101010101010101010101
+                "#,
+            )?;
+
+            let config = SecretsConfig::load_from_file("config.yaml")?;
+            let encrypted = Encrypter::new(&[0x01; 32])
+                .encryt_to_string(b"secret")
+                .unwrap();
+            assert_eq!(
+                config.encrypter().decrypt_string(&encrypted).unwrap(),
+                b"secret"
+            );
+
+            Ok(())
+        });
+    }
+}
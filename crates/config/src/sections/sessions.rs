@@ -0,0 +1,142 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use chrono::Duration;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use super::ConfigurationSection;
+
+fn default_sensitive_action_max_age() -> Duration {
+    Duration::hours(1)
+}
+
+fn default_erasure_delay() -> Duration {
+    Duration::days(7)
+}
+
+/// Configuration related to the browser sessions
+///
+/// By default, the sessions last until the user logs out
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionsConfig {
+    /// How long a session lasts after the user logged in, in seconds
+    #[schemars(with = "Option<u64>", range(min = 60))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<serde_with::DurationSeconds<i64>>")]
+    pub absolute_lifetime: Option<Duration>,
+
+    /// How long a session lasts without being used, in seconds
+    #[schemars(with = "Option<u64>", range(min = 60))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<serde_with::DurationSeconds<i64>>")]
+    pub idle_timeout: Option<Duration>,
+
+    /// How recently the user has to have authenticated to do sensitive
+    /// actions, like changing their password, in seconds. They are asked for
+    /// their password again otherwise
+    #[schemars(with = "u64", range(min = 60))]
+    #[serde(default = "default_sensitive_action_max_age")]
+    #[serde_as(as = "serde_with::DurationSeconds<i64>")]
+    pub sensitive_action_max_age: Duration,
+
+    /// How long after asking for it the account of a user and all their data
+    /// are erased, in seconds. They can cancel the erasure until then
+    #[schemars(with = "u64")]
+    #[serde(default = "default_erasure_delay")]
+    #[serde_as(as = "serde_with::DurationSeconds<i64>")]
+    pub erasure_delay: Duration,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            absolute_lifetime: None,
+            idle_timeout: None,
+            sensitive_action_max_age: default_sensitive_action_max_age(),
+            erasure_delay: default_erasure_delay(),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for SessionsConfig {
+    fn path() -> &'static str {
+        "sessions"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    sessions:
+                      absolute_lifetime: 604800
+                      idle_timeout: 3600
+                      sensitive_action_max_age: 300
+                      erasure_delay: 86400
+                "#,
+            )?;
+
+            let config = SessionsConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.absolute_lifetime, Some(Duration::weeks(1)));
+            assert_eq!(config.idle_timeout, Some(Duration::hours(1)));
+            assert_eq!(config.sensitive_action_max_age, Duration::minutes(5));
+            assert_eq!(config.erasure_delay, Duration::days(1));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn sessions_last_by_default() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    sessions: {}
+                "#,
+            )?;
+
+            let config = SessionsConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.absolute_lifetime, None);
+            assert_eq!(config.idle_timeout, None);
+            assert_eq!(config.sensitive_action_max_age, Duration::hours(1));
+            assert_eq!(config.erasure_delay, Duration::days(7));
+
+            Ok(())
+        });
+    }
+}
@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr};
 
 use async_trait::async_trait;
 use schemars::JsonSchema;
@@ -45,6 +45,24 @@ pub enum Propagator {
     B3Multi,
 }
 
+/// Transport of the OpenTelemetry protocol
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub enum OtlpProtocol {
+    /// gRPC
+    #[serde(rename = "grpc")]
+    Grpc,
+
+    /// Protocol buffers over HTTP
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+impl Default for OtlpProtocol {
+    fn default() -> Self {
+        Self::Grpc
+    }
+}
+
 fn otlp_endpoint_example() -> &'static str {
     "https://localhost:4317"
 }
@@ -70,10 +88,20 @@ pub enum TracingExporterConfig {
 
     /// Export traces to an OpenTelemetry protocol compatible endpoint
     Otlp {
-        /// OTLP compatible endpoint
+        /// OTLP compatible endpoint. With `http/protobuf`, the full URL of the
+        /// traces endpoint, usually ending with `/v1/traces`
         #[schemars(url, example = "otlp_endpoint_example")]
         #[serde(default)]
         endpoint: Option<Url>,
+
+        /// Transport to use to reach the endpoint
+        #[serde(default)]
+        protocol: OtlpProtocol,
+
+        /// Headers to send with each export, for example to authenticate
+        /// with the collector
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
 
     /// Export traces to a Jaeger agent
@@ -99,8 +127,12 @@ impl Default for TracingExporterConfig {
     }
 }
 
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
 /// Configuration related to exporting traces
-#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct TracingConfig {
     /// Exporter to use when exporting traces
     #[serde(default, flatten)]
@@ -108,6 +140,21 @@ pub struct TracingConfig {
 
     /// List of propagation formats to use for incoming and outgoing requests
     pub propagators: Vec<Propagator>,
+
+    /// Proportion of the traces to export, between 0 and 1. The decision of
+    /// the caller is kept for the requests which are part of a trace
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TracingExporterConfig::default(),
+            propagators: Vec::new(),
+            sample_rate: default_sample_rate(),
+        }
+    }
 }
 
 /// Exporter to use when exporting metrics
@@ -127,7 +174,20 @@ pub enum MetricsExporterConfig {
         #[schemars(url, example = "otlp_endpoint_example")]
         #[serde(default)]
         endpoint: Option<Url>,
+
+        /// Transport to use to reach the endpoint. Only `grpc` is supported
+        /// for metrics
+        #[serde(default)]
+        protocol: OtlpProtocol,
+
+        /// Headers to send with each export, for example to authenticate
+        /// with the collector
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
+
+    /// Expose metrics in the Prometheus format on the `/metrics` endpoint
+    Prometheus,
 }
 
 impl Default for MetricsExporterConfig {
@@ -144,9 +204,38 @@ pub struct MetricsConfig {
     pub exporter: MetricsExporterConfig,
 }
 
+/// Format of the logs written to the standard error
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines, colored when writing to a terminal
+    Human,
+
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        Self::Human
+    }
+}
+
+/// Configuration related to logging
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct LoggingConfig {
+    /// Format of the logs
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
 /// Configuration related to sending monitoring data
 #[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
 pub struct TelemetryConfig {
+    /// Configuration related to logging
+    #[serde(default)]
+    pub logging: LoggingConfig,
+
     /// Configuration related to exporting traces
     #[serde(default)]
     pub tracing: TracingConfig,
@@ -170,3 +259,93 @@ impl ConfigurationSection<'_> for TelemetryConfig {
         Self::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_log_format() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    telemetry:
+                      logging:
+                        format: json
+                "#,
+            )?;
+
+            let config = TelemetryConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.logging.format, LogFormat::Json);
+
+            // Logs are human-readable by default
+            let config = TelemetryConfig::default();
+            assert_eq!(config.logging.format, LogFormat::Human);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn load_otlp_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    telemetry:
+                      tracing:
+                        exporter: otlp
+                        endpoint: https://collector.example.com:4317
+                        propagators: [tracecontext]
+                        sample_rate: 0.1
+                        headers:
+                          authorization: Bearer some-token
+                      metrics:
+                        exporter: otlp
+                        protocol: http/protobuf
+                "#,
+            )?;
+
+            let config = TelemetryConfig::load_from_file("config.yaml")?;
+            match &config.tracing.exporter {
+                TracingExporterConfig::Otlp {
+                    endpoint: Some(_),
+                    protocol: OtlpProtocol::Grpc,
+                    headers,
+                } => {
+                    assert_eq!(
+                        headers.get("authorization").map(String::as_str),
+                        Some("Bearer some-token")
+                    );
+                }
+                other => panic!("unexpected exporter {:?}", other),
+            }
+            assert!((config.tracing.sample_rate - 0.1).abs() < f64::EPSILON);
+            assert!(matches!(
+                config.metrics.exporter,
+                MetricsExporterConfig::Otlp {
+                    endpoint: None,
+                    protocol: OtlpProtocol::HttpProtobuf,
+                    headers,
+                } if headers.is_empty()
+            ));
+
+            // Nothing is exported by default, and everything is sampled
+            let config = TelemetryConfig::default();
+            assert!(matches!(
+                config.tracing.exporter,
+                TracingExporterConfig::None
+            ));
+            assert!(matches!(
+                config.metrics.exporter,
+                MetricsExporterConfig::None
+            ));
+            assert!((config.tracing.sample_rate - 1.0).abs() < f64::EPSILON);
+
+            Ok(())
+        });
+    }
+}
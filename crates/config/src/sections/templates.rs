@@ -12,9 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeMap;
+
 use async_trait::async_trait;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 use super::ConfigurationSection;
 
@@ -22,6 +25,21 @@ fn default_builtin() -> bool {
     true
 }
 
+fn default_service_name() -> String {
+    "matrix-authentication-service".to_owned()
+}
+
+/// Describes custom scope tokens to the users consenting to them
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+pub struct ScopeDescriptionConfig {
+    /// A scope token, or a prefix followed by `*` to match all the tokens
+    /// starting with it
+    pub scope: String,
+
+    /// The description, keyed by language tag, like `en` or `fr-CA`
+    pub description: BTreeMap<String, String>,
+}
+
 /// Configuration related to templates
 #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
 pub struct TemplatesConfig {
@@ -32,6 +50,25 @@ pub struct TemplatesConfig {
     /// Load the templates embedded in the binary
     #[serde(default = "default_builtin")]
     pub builtin: bool,
+
+    /// Name of the service, displayed in the pages
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+
+    /// Link to a page where users can get help
+    #[serde(default)]
+    pub support_url: Option<Url>,
+
+    /// Descriptions of scope tokens shown on the consent page, which take
+    /// precedence over the builtin ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scope_descriptions: Vec<ScopeDescriptionConfig>,
+
+    /// Reload the templates when they change on disk, and the service name,
+    /// the support link and the scope descriptions when the configuration
+    /// files change. Other settings still need a restart. This needs watchman
+    #[serde(default)]
+    pub watch: bool,
 }
 
 impl Default for TemplatesConfig {
@@ -39,6 +76,10 @@ impl Default for TemplatesConfig {
         Self {
             path: None,
             builtin: default_builtin(),
+            service_name: default_service_name(),
+            support_url: None,
+            scope_descriptions: Vec::new(),
+            watch: false,
         }
     }
 }
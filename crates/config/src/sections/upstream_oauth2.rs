@@ -0,0 +1,194 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::ConfigurationSection;
+
+fn default_scope() -> String {
+    "openid".to_owned()
+}
+
+fn default_subject_claim() -> String {
+    "sub".to_owned()
+}
+
+fn default_username_template() -> String {
+    "{preferred_username}".to_owned()
+}
+
+fn default_provision_users() -> bool {
+    true
+}
+
+fn default_email_claim() -> String {
+    "email".to_owned()
+}
+
+/// How to talk to an upstream provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProviderKind {
+    /// A generic OIDC provider, with the authorization code flow and
+    /// the userinfo endpoint
+    Oidc,
+}
+
+impl Default for UpstreamProviderKind {
+    fn default() -> Self {
+        Self::Oidc
+    }
+}
+
+/// An upstream OIDC provider users can log in with
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UpstreamProviderConfig {
+    /// Identifier of the provider, in the URLs and in the list of identity
+    /// providers advertised to the Matrix clients
+    pub id: String,
+
+    /// Name of the provider shown to the users
+    pub name: String,
+
+    /// How to talk to the provider
+    #[serde(default, rename = "type")]
+    pub kind: UpstreamProviderKind,
+
+    /// Client ID registered on the provider
+    pub client_id: String,
+
+    /// Client secret registered on the provider, sent with HTTP Basic
+    /// authentication
+    pub client_secret: String,
+
+    /// URL the users are sent to, to log in on the provider
+    pub authorization_endpoint: Url,
+
+    /// URL where the authorization codes are exchanged for access tokens
+    pub token_endpoint: Url,
+
+    /// URL where the claims about the user are fetched with the access token
+    pub userinfo_endpoint: Url,
+
+    /// Space-separated scopes to request
+    #[serde(default = "default_scope")]
+    pub scope: String,
+
+    /// Claim identifying the user on the provider, which has to be stable
+    #[serde(default = "default_subject_claim")]
+    pub subject_claim: String,
+
+    /// Register the users logging in for the first time. When disabled, only
+    /// the users already linked to the provider can log in
+    #[serde(default = "default_provision_users")]
+    pub provision_users: bool,
+
+    /// Link the users logging in for the first time to the local user who
+    /// verified the same email, if the provider verified it too. Only enable
+    /// it for providers trusted to verify the emails
+    #[serde(default)]
+    pub link_by_email: bool,
+
+    /// Template of the username of the users logging in for the first time,
+    /// with the claims between braces, like `{preferred_username}`. A number
+    /// is appended if the username is taken
+    #[serde(default = "default_username_template")]
+    pub username_template: String,
+
+    /// Claim with the email of the user, given to the users logging in for
+    /// the first time if the provider verified it
+    #[serde(default = "default_email_claim")]
+    pub email_claim: String,
+}
+
+/// Upstream OIDC providers users can log in with
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct UpstreamOAuth2Config {
+    /// List of the providers
+    #[serde(default)]
+    pub providers: Vec<UpstreamProviderConfig>,
+}
+
+impl UpstreamOAuth2Config {
+    /// Find a provider by its ID
+    #[must_use]
+    pub fn provider(&self, id: &str) -> Option<&UpstreamProviderConfig> {
+        self.providers.iter().find(|provider| provider.id == id)
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for UpstreamOAuth2Config {
+    fn path() -> &'static str {
+        "upstream_oauth2"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    upstream_oauth2:
+                      providers:
+                        - id: example
+                          name: Example
+                          client_id: mas
+                          client_secret: secret
+                          authorization_endpoint: https://sso.example.com/authorize
+                          token_endpoint: https://sso.example.com/token
+                          userinfo_endpoint: https://sso.example.com/userinfo
+                          scope: openid profile
+                "#,
+            )?;
+
+            let config = UpstreamOAuth2Config::load_from_file("config.yaml")?;
+
+            let provider = config.provider("example").unwrap();
+            assert_eq!(provider.name, "Example");
+            assert_eq!(
+                provider.authorization_endpoint.as_str(),
+                "https://sso.example.com/authorize"
+            );
+            assert_eq!(provider.scope, "openid profile");
+            assert_eq!(provider.subject_claim, "sub");
+            assert!(provider.provision_users);
+            assert!(!provider.link_by_email);
+            assert_eq!(provider.username_template, "{preferred_username}");
+            assert_eq!(provider.email_claim, "email");
+            assert_eq!(provider.kind, UpstreamProviderKind::Oidc);
+            assert!(config.provider("other").is_none());
+
+            Ok(())
+        });
+    }
+}
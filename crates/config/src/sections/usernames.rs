@@ -0,0 +1,113 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::ConfigurationSection;
+
+fn default_min_length() -> usize {
+    1
+}
+
+fn default_max_length() -> usize {
+    64
+}
+
+fn default_reserved() -> Vec<String> {
+    [
+        "admin",
+        "administrator",
+        "root",
+        "support",
+        "abuse",
+        "postmaster",
+    ]
+    .into_iter()
+    .map(ToOwned::to_owned)
+    .collect()
+}
+
+/// Requirements the usernames of new users have to meet
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UsernamesConfig {
+    /// Minimum number of characters of a username
+    #[serde(default = "default_min_length")]
+    pub min_length: usize,
+
+    /// Maximum number of characters of a username
+    #[serde(default = "default_max_length")]
+    pub max_length: usize,
+
+    /// Usernames which can't be registered, compared case-insensitively
+    #[serde(default = "default_reserved")]
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamesConfig {
+    fn default() -> Self {
+        Self {
+            min_length: default_min_length(),
+            max_length: default_max_length(),
+            reserved: default_reserved(),
+        }
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for UsernamesConfig {
+    fn path() -> &'static str {
+        "usernames"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    usernames:
+                      max_length: 32
+                      reserved:
+                        - admin
+                        - helpdesk
+                "#,
+            )?;
+
+            let config = UsernamesConfig::load_from_file("config.yaml")?;
+
+            assert_eq!(config.min_length, 1);
+            assert_eq!(config.max_length, 32);
+            assert_eq!(config.reserved, vec!["admin", "helpdesk"]);
+
+            Ok(())
+        });
+    }
+}
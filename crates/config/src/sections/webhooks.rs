@@ -0,0 +1,136 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use url::Url;
+
+use super::ConfigurationSection;
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_poll_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// An endpoint notified of the account lifecycle events
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookEndpointConfig {
+    /// Identifier of the endpoint, in the delivery log. Changing it drops the
+    /// pending deliveries to the endpoint
+    pub id: String,
+
+    /// URL the events are posted to
+    pub url: Url,
+
+    /// Secret the payloads are signed with, with HMAC-SHA256
+    pub secret: String,
+}
+
+/// Webhooks notifying other systems, like the homeserver, of the account
+/// lifecycle events
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WebhooksConfig {
+    /// List of the endpoints, each of them getting all the events
+    #[serde(default)]
+    pub endpoints: Vec<WebhookEndpointConfig>,
+
+    /// How many times the delivery of an event is attempted before giving up
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    /// How often the pending deliveries are looked up, in seconds
+    #[schemars(with = "u64")]
+    #[serde(default = "default_poll_interval")]
+    #[serde_as(as = "serde_with::DurationSeconds<u64>")]
+    pub poll_interval: Duration,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            max_attempts: default_max_attempts(),
+            poll_interval: default_poll_interval(),
+        }
+    }
+}
+
+impl WebhooksConfig {
+    /// Find an endpoint by its ID
+    #[must_use]
+    pub fn endpoint(&self, id: &str) -> Option<&WebhookEndpointConfig> {
+        self.endpoints.iter().find(|endpoint| endpoint.id == id)
+    }
+}
+
+#[async_trait]
+impl ConfigurationSection<'_> for WebhooksConfig {
+    fn path() -> &'static str {
+        "webhooks"
+    }
+
+    async fn generate() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn test() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+
+    #[test]
+    fn load_config() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    webhooks:
+                      endpoints:
+                        - id: synapse
+                          url: https://synapse.example.com/_mas/events
+                          secret: hunter2
+                      max_attempts: 3
+                "#,
+            )?;
+
+            let config = WebhooksConfig::load_from_file("config.yaml")?;
+
+            let endpoint = config.endpoint("synapse").unwrap();
+            assert_eq!(
+                endpoint.url.as_str(),
+                "https://synapse.example.com/_mas/events"
+            );
+            assert_eq!(endpoint.secret, "hunter2");
+            assert!(config.endpoint("other").is_none());
+            assert_eq!(config.max_attempts, 3);
+            assert_eq!(config.poll_interval, Duration::from_secs(5));
+
+            Ok(())
+        });
+    }
+}
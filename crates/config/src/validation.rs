@@ -0,0 +1,538 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks of the invariants spanning several fields or sections, which can't
+//! be expressed when deserializing the configuration
+
+use std::collections::HashSet;
+
+use thiserror::Error;
+
+use crate::{
+    ChallengeServiceConfig, CookieSameSite, EmailSmtpMode, EmailTransportConfig, RootConfig,
+};
+
+/// A problem with the configuration, reported by [`RootConfig::validate`]
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Emails would be sent from the default address, which can't receive
+    /// replies or bounces
+    #[error("email.from: {0} is not a real address, set one to send emails")]
+    UndeliverableFromAddress(String),
+
+    /// The plain SMTP mode was used without explicitly allowing it
+    #[error(
+        "email: the plain SMTP mode sends emails and credentials unencrypted, set allow_insecure \
+         to use it anyway"
+    )]
+    InsecureSmtp,
+
+    /// There is no key to sign the ID tokens with
+    #[error("secrets.keys: at least one signing key is needed to sign ID tokens")]
+    NoSigningKey,
+
+    /// Credentials can't be sent to any origin
+    #[error("http.cors: allow_credentials can't be used when any origin is allowed")]
+    CorsCredentialsWithAnyOrigin,
+
+    /// Browsers reject the cookies with `SameSite=None` which are not secure
+    #[error("http.cookies: same_site can only be none when secure is set")]
+    SameSiteNoneWithoutSecure,
+
+    /// Browsers reject the cookies which don't meet the requirements of their
+    /// name prefix
+    #[error(
+        "http.cookies: the {0:?} name prefix requires secure, and no domain for the __Host- \
+         prefix"
+    )]
+    InvalidCookiePrefix(String),
+
+    /// Two clients have the same ID
+    #[error("clients: the client {0:?} is defined more than once")]
+    DuplicateClient(String),
+
+    /// A client has an empty secret
+    #[error("clients: the client {0:?} has an empty client_secret")]
+    EmptyClientSecret(String),
+
+    /// Passwords can't mix more character classes than there are
+    #[error("passwords.min_complexity: {0} is more than the 4 character classes")]
+    ImpossiblePasswordComplexity(u8),
+
+    /// No username can be both long and short enough
+    #[error("usernames: min_length ({0}) is more than max_length ({1})")]
+    ImpossibleUsernameLength(usize, usize),
+
+    /// The keys of the challenge service are missing
+    #[error("challenge: the site_key and the secret_key can't be empty")]
+    EmptyChallengeKeys,
+
+    /// The username template of an upstream provider can't be rendered
+    #[error(
+        "upstream_oauth2.providers: the username template of {0:?} has unbalanced braces or \
+         empty claim names"
+    )]
+    InvalidUsernameTemplate(String),
+
+    /// Two upstream providers have the same ID
+    #[error("upstream_oauth2.providers: the provider {0:?} is defined more than once")]
+    DuplicateUpstreamProvider(String),
+
+    /// The initial access token would be trivially guessed
+    #[error("oauth2.registration: the initial_access_token can't be empty")]
+    EmptyInitialAccessToken,
+
+    /// The sample rate is not a proportion
+    #[error("telemetry.tracing: the sample_rate has to be between 0 and 1")]
+    InvalidSampleRate,
+
+    /// The tokens would never be deleted
+    #[error("oauth2.token_cleanup: the interval and the batch_size can't be 0")]
+    NoTokenCleanup,
+
+    /// The users would be erased before asking for it
+    #[error("sessions.erasure_delay: can't be negative")]
+    NegativeErasureDelay,
+
+    /// Two webhook endpoints have the same ID
+    #[error("webhooks.endpoints: the endpoint {0:?} is defined more than once")]
+    DuplicateWebhookEndpoint(String),
+
+    /// The payloads sent to a webhook endpoint would be trivially forged
+    #[error("webhooks.endpoints: the endpoint {0:?} has an empty secret")]
+    EmptyWebhookSecret(String),
+
+    /// The events would never be delivered
+    #[error("webhooks.max_attempts: can't be 0")]
+    NoWebhookAttempts,
+}
+
+/// Check that the claims in a template are between balanced braces, and have
+/// a name
+fn is_valid_template(template: &str) -> bool {
+    let mut rest = template;
+    while let Some(index) = rest.find(['{', '}']) {
+        if rest[index..].starts_with('}') {
+            return false;
+        }
+
+        let after = &rest[index + 1..];
+        match after.find(['{', '}']) {
+            Some(end) if end > 0 && after[end..].starts_with('}') => rest = &after[end + 1..],
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+impl RootConfig {
+    /// Check the invariants the configuration has to meet, which would
+    /// otherwise only fail when the misconfigured feature is first used.
+    ///
+    /// # Errors
+    ///
+    /// Returns all the problems found, not only the first one
+    #[allow(clippy::too_many_lines)]
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        // Emails are discarded by the blackhole transport
+        if !matches!(self.email.transport, EmailTransportConfig::Blackhole) {
+            if self.email.from.email.domain() == "localhost" {
+                errors.push(ConfigError::UndeliverableFromAddress(
+                    self.email.from.email.to_string(),
+                ));
+            }
+
+            if let EmailTransportConfig::Smtp {
+                mode: EmailSmtpMode::Plain,
+                allow_insecure: false,
+                ..
+            } = self.email.transport
+            {
+                errors.push(ConfigError::InsecureSmtp);
+            }
+        }
+
+        if !self.secrets.has_keys() {
+            errors.push(ConfigError::NoSigningKey);
+        }
+
+        let cors = &self.http.cors;
+        if cors.allow_credentials && cors.allowed_origins.iter().any(|origin| origin == "*") {
+            errors.push(ConfigError::CorsCredentialsWithAnyOrigin);
+        }
+
+        let cookies = &self.http.cookies;
+        if cookies.same_site == CookieSameSite::None && !cookies.secure {
+            errors.push(ConfigError::SameSiteNoneWithoutSecure);
+        }
+
+        // Browsers enforce those prefixes, and would silently drop the cookies
+        let prefix = cookies.name_prefix.as_str();
+        if (prefix.starts_with("__Secure-") && !cookies.secure)
+            || (prefix.starts_with("__Host-") && (!cookies.secure || cookies.domain.is_some()))
+        {
+            errors.push(ConfigError::InvalidCookiePrefix(prefix.to_owned()));
+        }
+
+        let mut client_ids = HashSet::new();
+        for client in self.clients.iter() {
+            if !client_ids.insert(client.client_id.as_str()) {
+                errors.push(ConfigError::DuplicateClient(client.client_id.clone()));
+            }
+
+            if client.client_secret() == Some("") {
+                errors.push(ConfigError::EmptyClientSecret(client.client_id.clone()));
+            }
+        }
+
+        if self.passwords.min_complexity > 4 {
+            errors.push(ConfigError::ImpossiblePasswordComplexity(
+                self.passwords.min_complexity,
+            ));
+        }
+
+        if self.usernames.min_length > self.usernames.max_length {
+            errors.push(ConfigError::ImpossibleUsernameLength(
+                self.usernames.min_length,
+                self.usernames.max_length,
+            ));
+        }
+
+        let challenge_keys = match &self.challenge.service {
+            ChallengeServiceConfig::None => None,
+            ChallengeServiceConfig::HCaptcha {
+                site_key,
+                secret_key,
+            }
+            | ChallengeServiceConfig::ReCaptcha {
+                site_key,
+                secret_key,
+            }
+            | ChallengeServiceConfig::Turnstile {
+                site_key,
+                secret_key,
+            } => Some((site_key, secret_key)),
+        };
+        if let Some((site_key, secret_key)) = challenge_keys {
+            if site_key.is_empty() || secret_key.is_empty() {
+                errors.push(ConfigError::EmptyChallengeKeys);
+            }
+        }
+
+        if self.oauth2.registration.initial_access_token.as_deref() == Some("") {
+            errors.push(ConfigError::EmptyInitialAccessToken);
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.tracing.sample_rate) {
+            errors.push(ConfigError::InvalidSampleRate);
+        }
+
+        let token_cleanup = &self.oauth2.token_cleanup;
+        if token_cleanup.interval.is_zero() || token_cleanup.batch_size == 0 {
+            errors.push(ConfigError::NoTokenCleanup);
+        }
+
+        if self.sessions.erasure_delay < chrono::Duration::zero() {
+            errors.push(ConfigError::NegativeErasureDelay);
+        }
+
+        let mut provider_ids = HashSet::new();
+        for provider in &self.upstream_oauth2.providers {
+            if !provider_ids.insert(provider.id.as_str()) {
+                errors.push(ConfigError::DuplicateUpstreamProvider(provider.id.clone()));
+            }
+
+            if !is_valid_template(&provider.username_template) {
+                errors.push(ConfigError::InvalidUsernameTemplate(provider.id.clone()));
+            }
+        }
+
+        let mut endpoint_ids = HashSet::new();
+        for endpoint in &self.webhooks.endpoints {
+            if !endpoint_ids.insert(endpoint.id.as_str()) {
+                errors.push(ConfigError::DuplicateWebhookEndpoint(endpoint.id.clone()));
+            }
+
+            if endpoint.secret.is_empty() {
+                errors.push(ConfigError::EmptyWebhookSecret(endpoint.id.clone()));
+            }
+        }
+
+        if self.webhooks.max_attempts == 0 {
+            errors.push(ConfigError::NoWebhookAttempts);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::*;
+    use crate::{
+        ClientAuthMethodConfig, ClientConfig, ConfigurationSection, UpstreamProviderConfig,
+        UpstreamProviderKind, WebhookEndpointConfig,
+    };
+
+    fn client(client_id: &str, client_secret: &str) -> ClientConfig {
+        ClientConfig {
+            client_id: client_id.to_owned(),
+            client_auth_method: ClientAuthMethodConfig::ClientSecretBasic {
+                client_secret: client_secret.to_owned(),
+            },
+            redirect_uris: Vec::new(),
+        }
+    }
+
+    fn provider(id: &str) -> UpstreamProviderConfig {
+        UpstreamProviderConfig {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            kind: UpstreamProviderKind::Oidc,
+            client_id: "mas".to_owned(),
+            client_secret: "secret".to_owned(),
+            authorization_endpoint: "https://sso.example.com/authorize".parse().unwrap(),
+            token_endpoint: "https://sso.example.com/token".parse().unwrap(),
+            userinfo_endpoint: "https://sso.example.com/userinfo".parse().unwrap(),
+            scope: "openid".to_owned(),
+            subject_claim: "sub".to_owned(),
+            provision_users: true,
+            link_by_email: false,
+            username_template: "{preferred_username}".to_owned(),
+            email_claim: "email".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_config_is_valid() {
+        assert_eq!(RootConfig::test().validate(), Ok(()));
+    }
+
+    #[test]
+    fn insecure_email() {
+        let mut config = RootConfig::test();
+        config.email.transport = EmailTransportConfig::Smtp {
+            mode: EmailSmtpMode::Plain,
+            hostname: "localhost".to_owned(),
+            port: None,
+            credentials: None,
+            allow_insecure: false,
+        };
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigError::UndeliverableFromAddress("root@localhost".to_owned()),
+                ConfigError::InsecureSmtp,
+            ])
+        );
+
+        // Both can be fixed
+        config.email.from = "Auth <auth@example.com>".parse().unwrap();
+        config.email.transport = EmailTransportConfig::Smtp {
+            mode: EmailSmtpMode::Plain,
+            hostname: "localhost".to_owned(),
+            port: None,
+            credentials: None,
+            allow_insecure: true,
+        };
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn contradictory_settings() {
+        let mut config = RootConfig::test();
+        config.http.cors.allowed_origins = vec!["*".to_owned()];
+        config.http.cors.allow_credentials = true;
+        config.clients.push(client("first", "secret"));
+        config.clients.push(client("second", ""));
+        config.clients.push(client("first", "other-secret"));
+        config.passwords.min_complexity = 5;
+        config.usernames.min_length = 10;
+        config.usernames.max_length = 8;
+        config.challenge.service = ChallengeServiceConfig::Turnstile {
+            site_key: "site-key".to_owned(),
+            secret_key: String::new(),
+        };
+        config.oauth2.registration.initial_access_token = Some(String::new());
+        config.upstream_oauth2.providers = vec![provider("example"), provider("example")];
+        config.upstream_oauth2.providers[1].username_template = "{sub".to_owned();
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigError::CorsCredentialsWithAnyOrigin,
+                ConfigError::EmptyClientSecret("second".to_owned()),
+                ConfigError::DuplicateClient("first".to_owned()),
+                ConfigError::ImpossiblePasswordComplexity(5),
+                ConfigError::ImpossibleUsernameLength(10, 8),
+                ConfigError::EmptyChallengeKeys,
+                ConfigError::EmptyInitialAccessToken,
+                ConfigError::DuplicateUpstreamProvider("example".to_owned()),
+                ConfigError::InvalidUsernameTemplate("example".to_owned()),
+            ])
+        );
+    }
+
+    #[test]
+    fn sample_rate() {
+        let mut config = RootConfig::test();
+        config.telemetry.tracing.sample_rate = 0.0;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.telemetry.tracing.sample_rate = 1.5;
+        assert_eq!(config.validate(), Err(vec![ConfigError::InvalidSampleRate]));
+
+        config.telemetry.tracing.sample_rate = f64::NAN;
+        assert_eq!(config.validate(), Err(vec![ConfigError::InvalidSampleRate]));
+    }
+
+    #[test]
+    fn token_cleanup() {
+        let mut config = RootConfig::test();
+        config.oauth2.token_cleanup.batch_size = 0;
+        assert_eq!(config.validate(), Err(vec![ConfigError::NoTokenCleanup]));
+
+        config.oauth2.token_cleanup.batch_size = 1;
+        config.oauth2.token_cleanup.interval = std::time::Duration::ZERO;
+        assert_eq!(config.validate(), Err(vec![ConfigError::NoTokenCleanup]));
+    }
+
+    #[test]
+    fn erasure_delay() {
+        let mut config = RootConfig::test();
+        config.sessions.erasure_delay = chrono::Duration::zero();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.sessions.erasure_delay = chrono::Duration::seconds(-1);
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::NegativeErasureDelay])
+        );
+    }
+
+    #[test]
+    fn webhooks() {
+        fn endpoint(id: &str, secret: &str) -> WebhookEndpointConfig {
+            WebhookEndpointConfig {
+                id: id.to_owned(),
+                url: "https://synapse.example.com/_mas/events".parse().unwrap(),
+                secret: secret.to_owned(),
+            }
+        }
+
+        let mut config = RootConfig::test();
+        config.webhooks.endpoints = vec![endpoint("synapse", "secret"), endpoint("other", "")];
+        config
+            .webhooks
+            .endpoints
+            .push(endpoint("synapse", "secret"));
+        config.webhooks.max_attempts = 0;
+
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigError::EmptyWebhookSecret("other".to_owned()),
+                ConfigError::DuplicateWebhookEndpoint("synapse".to_owned()),
+                ConfigError::NoWebhookAttempts,
+            ])
+        );
+    }
+
+    #[test]
+    fn cookie_attributes() {
+        let mut config = RootConfig::test();
+        config.http.cookies.same_site = CookieSameSite::None;
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::SameSiteNoneWithoutSecure])
+        );
+
+        config.http.cookies.secure = true;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.http.cookies.name_prefix = "__Host-".to_owned();
+        config.http.cookies.domain = Some("example.com".to_owned());
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::InvalidCookiePrefix("__Host-".to_owned())])
+        );
+
+        config.http.cookies.name_prefix = "__Secure-".to_owned();
+        assert_eq!(config.validate(), Ok(()));
+
+        config.http.cookies.same_site = CookieSameSite::Strict;
+        config.http.cookies.secure = false;
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::InvalidCookiePrefix(
+                "__Secure-".to_owned()
+            )])
+        );
+    }
+
+    #[test]
+    fn username_templates() {
+        for template in ["{preferred_username}", "{sub}", "gh-{login}_{id}", "static"] {
+            assert!(
+                is_valid_template(template),
+                "{:?} should be valid",
+                template
+            );
+        }
+
+        for template in ["{sub", "sub}", "{}", "{{sub}}", "{a{b}}", "{a}}"] {
+            assert!(
+                !is_valid_template(template),
+                "{:?} should be invalid",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn missing_signing_key() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "config.yaml",
+                r#"
+                    secrets:
+                      encryption: 0000111122223333444455556666777788889999aaaabbbbccccddddeeeeffff
+                "#,
+            )?;
+
+            let config = RootConfig::load_from_file("config.yaml")?;
+            assert_eq!(config.validate(), Err(vec![ConfigError::NoSigningKey]));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn errors_are_readable() {
+        assert_eq!(
+            ConfigError::DuplicateClient("first".to_owned()).to_string(),
+            r#"clients: the client "first" is defined more than once"#
+        );
+    }
+}
@@ -102,7 +102,7 @@ impl<S: StorageBackendMarker> From<CompatSession<S>> for CompatSession<()> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct CompatAccessToken<T: StorageBackend> {
     pub data: T::CompatAccessTokenData,
     pub token: String,
@@ -110,6 +110,16 @@ pub struct CompatAccessToken<T: StorageBackend> {
     pub expires_at: Option<DateTime<Utc>>,
 }
 
+impl<T: StorageBackend> CompatAccessToken<T> {
+    /// Whether the token expired at the given time. Tokens without an
+    /// expiration never expire
+    #[must_use]
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
 impl<S: StorageBackendMarker> From<CompatAccessToken<S>> for CompatAccessToken<()> {
     fn from(t: CompatAccessToken<S>) -> Self {
         Self {
@@ -121,7 +131,19 @@ impl<S: StorageBackendMarker> From<CompatAccessToken<S>> for CompatAccessToken<(
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl<T: StorageBackend> std::fmt::Debug for CompatAccessToken<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Like OAuth 2.0 tokens, never print the token itself
+        f.debug_struct("CompatAccessToken")
+            .field("data", &self.data)
+            .field("token", &format_args!("[redacted]"))
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct CompatRefreshToken<T: StorageBackend> {
     pub data: T::RefreshTokenData,
     pub token: String,
@@ -138,6 +160,16 @@ impl<S: StorageBackendMarker> From<CompatRefreshToken<S>> for CompatRefreshToken
     }
 }
 
+impl<T: StorageBackend> std::fmt::Debug for CompatRefreshToken<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompatRefreshToken")
+            .field("data", &self.data)
+            .field("token", &format_args!("[redacted]"))
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
 pub enum CompatSsoLoginState<T: StorageBackend> {
@@ -199,3 +231,35 @@ impl<S: StorageBackendMarker> From<CompatSsoLogin<S>> for CompatSsoLogin<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::TokenType;
+
+    #[test]
+    fn tokens_debug_is_redacted() {
+        let token = TokenType::CompatAccessToken.generate(thread_rng());
+        let access_token = CompatAccessToken::<()> {
+            data: (),
+            token: token.clone(),
+            created_at: Utc::now(),
+            expires_at: None,
+        };
+        let debug = format!("{:?}", access_token);
+        assert!(!debug.contains(&token));
+        assert!(debug.contains("token: [redacted]"));
+
+        let token = TokenType::CompatRefreshToken.generate(thread_rng());
+        let refresh_token = CompatRefreshToken::<()> {
+            data: (),
+            token: token.clone(),
+            created_at: Utc::now(),
+        };
+        let debug = format!("{:?}", refresh_token);
+        assert!(!debug.contains(&token));
+        assert!(debug.contains("token: [redacted]"));
+    }
+}
@@ -24,9 +24,12 @@
 
 pub(crate) mod compat;
 pub(crate) mod oauth2;
+pub(crate) mod pagination;
 pub(crate) mod tokens;
+pub(crate) mod totp;
 pub(crate) mod traits;
 pub(crate) mod users;
+pub(crate) mod webauthn;
 
 pub use self::{
     compat::{
@@ -34,13 +37,18 @@ pub use self::{
         Device,
     },
     oauth2::{
-        AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, Client,
-        InvalidRedirectUriError, JwksOrJwksUri, Pkce, Session,
+        validate_redirect_uri, AuthorizationCode, AuthorizationCodeError, AuthorizationGrant,
+        AuthorizationGrantStage, Client, ClientType, InvalidRedirectUriError, JwksOrJwksUri, Pkce,
+        RedirectUriValidationError, Session,
     },
+    pagination::{Cursor, CursorError},
     tokens::{AccessToken, RefreshToken, TokenFormatError, TokenType},
+    totp::{InvalidTotpSecret, TotpSecret, UserTotp},
     traits::{StorageBackend, StorageBackendMarker},
     users::{
-        Authentication, BrowserSession, User, UserEmail, UserEmailVerification,
-        UserEmailVerificationState,
+        Authentication, BrowserSession, LoginAttempts, PasswordPolicy, PasswordPolicyError,
+        PasswordReset, User, UserEmail, UserEmailVerification, UserEmailVerificationState,
+        UsernamePolicy, UsernamePolicyError,
     },
+    webauthn::{ReplayedSignCount, WebauthnCredential},
 };
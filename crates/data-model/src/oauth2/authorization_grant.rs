@@ -16,7 +16,7 @@ use std::num::NonZeroU32;
 
 use chrono::{DateTime, Duration, Utc};
 use mas_iana::oauth::PkceCodeChallengeMethod;
-use oauth2_types::{pkce::CodeChallengeMethodExt, requests::ResponseMode};
+use oauth2_types::{pkce::CodeChallengeMethodExt, requests::ResponseMode, scope::Scope};
 use serde::Serialize;
 use thiserror::Error;
 use url::Url;
@@ -49,6 +49,55 @@ impl Pkce {
 pub struct AuthorizationCode {
     pub code: String,
     pub pkce: Option<Pkce>,
+    /// When the code was issued to the client
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub exchanged_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AuthorizationCodeError {
+    #[error("authorization code was already exchanged")]
+    AlreadyExchanged,
+
+    #[error("authorization code has expired")]
+    Expired,
+
+    #[error("authorization code was issued to another client")]
+    ClientMismatch,
+
+    #[error("redirect_uri does not match the one the code was issued for")]
+    RedirectUriMismatch,
+}
+
+impl AuthorizationCode {
+    /// How long a code can be exchanged for after it was issued
+    #[must_use]
+    pub fn ttl() -> Duration {
+        Duration::minutes(10)
+    }
+
+    #[must_use]
+    pub fn new(code: String, pkce: Option<Pkce>, created_at: DateTime<Utc>) -> Self {
+        Self {
+            code,
+            pkce,
+            created_at,
+            expires_at: created_at + Self::ttl(),
+            exchanged_at: None,
+        }
+    }
+
+    /// Check that the code can still be exchanged at the given time
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), AuthorizationCodeError> {
+        if self.exchanged_at.is_some() {
+            Err(AuthorizationCodeError::AlreadyExchanged)
+        } else if now > self.expires_at {
+            Err(AuthorizationCodeError::Expired)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -157,6 +206,7 @@ impl<S: StorageBackendMarker> From<AuthorizationGrantStage<S>> for Authorization
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
+#[allow(clippy::struct_excessive_bools)]
 pub struct AuthorizationGrant<T: StorageBackend> {
     #[serde(skip_serializing)]
     pub data: T::AuthorizationGrantData,
@@ -175,6 +225,42 @@ pub struct AuthorizationGrant<T: StorageBackend> {
     pub response_type_id_token: bool,
     pub created_at: DateTime<Utc>,
     pub requires_consent: bool,
+    /// The user consented to this grant, whether or not they chose to remember
+    /// their consent for the next grants of the client
+    pub consent_given: bool,
+}
+
+impl<T: StorageBackend> AuthorizationGrant<T>
+where
+    T::AuthorizationGrantData: Default,
+    T::ClientData: Default,
+{
+    /// A pending grant waiting for the consent of the user, for each of the
+    /// sample clients
+    #[must_use]
+    pub fn samples() -> Vec<Self> {
+        Client::<T>::samples()
+            .into_iter()
+            .map(|client| AuthorizationGrant {
+                data: Default::default(),
+                stage: AuthorizationGrantStage::Pending,
+                code: None,
+                redirect_uri: client.redirect_uris[0].clone(),
+                client,
+                scope: "openid profile email".parse().unwrap(),
+                state: Some("state".to_string()),
+                nonce: None,
+                max_age: None,
+                acr_values: None,
+                response_mode: ResponseMode::Query,
+                response_type_token: false,
+                response_type_id_token: false,
+                created_at: Utc::now(),
+                requires_consent: true,
+                consent_given: false,
+            })
+            .collect()
+    }
 }
 
 impl<S: StorageBackendMarker> From<AuthorizationGrant<S>> for AuthorizationGrant<()> {
@@ -195,6 +281,7 @@ impl<S: StorageBackendMarker> From<AuthorizationGrant<S>> for AuthorizationGrant
             response_type_id_token: g.response_type_id_token,
             created_at: g.created_at,
             requires_consent: g.requires_consent,
+            consent_given: g.consent_given,
         }
     }
 }
@@ -204,4 +291,174 @@ impl<T: StorageBackend> AuthorizationGrant<T> {
         let max_age: Option<i64> = self.max_age.map(|x| x.get().into());
         self.created_at - Duration::seconds(max_age.unwrap_or(3600 * 24 * 365))
     }
+
+    /// Whether the user has to be asked for consent before completing this
+    /// grant, given the scope they previously allowed the client to get.
+    ///
+    /// The `urn:matrix:device:*` scope tokens are never remembered, since each
+    /// grant asks for a new device.
+    #[must_use]
+    pub fn needs_consent(&self, remembered: &Scope) -> bool {
+        if self.consent_given {
+            return false;
+        }
+
+        self.requires_consent
+            || self
+                .scope
+                .difference(remembered)
+                .any(|token| !token.starts_with("urn:matrix:device:"))
+    }
+
+    /// Check that the code of this grant is presented by the client it was
+    /// issued to, along with the `redirect_uri` it was issued for
+    pub fn check_code_binding(
+        &self,
+        client_id: &str,
+        redirect_uri: Option<&Url>,
+    ) -> Result<(), AuthorizationCodeError> {
+        if self.client.client_id != client_id {
+            return Err(AuthorizationCodeError::ClientMismatch);
+        }
+
+        match redirect_uri {
+            Some(redirect_uri) if redirect_uri != &self.redirect_uri => {
+                Err(AuthorizationCodeError::RedirectUriMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ClientType;
+
+    fn client(client_id: &str) -> Client<()> {
+        Client {
+            data: (),
+            client_id: client_id.to_string(),
+            client_type: ClientType::Confidential,
+            encrypted_client_secret: None,
+            redirect_uris: vec!["https://example.com/callback".parse().unwrap()],
+            response_types: Vec::new(),
+            grant_types: Vec::new(),
+            contacts: Vec::new(),
+            client_name: None,
+            logo_uri: None,
+            client_uri: None,
+            policy_uri: None,
+            tos_uri: None,
+            jwks: None,
+            id_token_signed_response_alg: None,
+            userinfo_signed_response_alg: None,
+            token_endpoint_auth_method: None,
+            token_endpoint_auth_signing_alg: None,
+            initiate_login_uri: None,
+        }
+    }
+
+    fn grant(client_id: &str) -> AuthorizationGrant<()> {
+        let now = Utc::now();
+        AuthorizationGrant {
+            data: (),
+            stage: AuthorizationGrantStage::Pending,
+            code: Some(AuthorizationCode::new("code".to_string(), None, now)),
+            client: client(client_id),
+            redirect_uri: "https://example.com/callback".parse().unwrap(),
+            scope: "openid".parse::<Scope>().unwrap(),
+            state: None,
+            nonce: None,
+            max_age: None,
+            acr_values: None,
+            response_mode: ResponseMode::Query,
+            response_type_token: false,
+            response_type_id_token: false,
+            created_at: now,
+            requires_consent: false,
+            consent_given: false,
+        }
+    }
+
+    #[test]
+    fn fresh_code_can_be_exchanged() {
+        let now = Utc::now();
+        let code = AuthorizationCode::new("code".to_string(), None, now);
+        assert_eq!(code.check(now + Duration::minutes(1)), Ok(()));
+    }
+
+    #[test]
+    fn exchanged_code_cannot_be_reused() {
+        let now = Utc::now();
+        let mut code = AuthorizationCode::new("code".to_string(), None, now);
+        code.exchanged_at = Some(now + Duration::seconds(5));
+        assert_eq!(
+            code.check(now + Duration::seconds(10)),
+            Err(AuthorizationCodeError::AlreadyExchanged)
+        );
+    }
+
+    #[test]
+    fn expired_code_cannot_be_exchanged() {
+        let now = Utc::now();
+        let code = AuthorizationCode::new("code".to_string(), None, now);
+        assert_eq!(
+            code.check(now + AuthorizationCode::ttl() + Duration::seconds(1)),
+            Err(AuthorizationCodeError::Expired)
+        );
+    }
+
+    #[test]
+    fn code_is_bound_to_client() {
+        let grant = grant("client-a");
+        assert_eq!(grant.check_code_binding("client-a", None), Ok(()));
+        assert_eq!(
+            grant.check_code_binding("client-b", None),
+            Err(AuthorizationCodeError::ClientMismatch)
+        );
+    }
+
+    #[test]
+    fn code_is_bound_to_redirect_uri() {
+        let grant = grant("client-a");
+        let same: Url = "https://example.com/callback".parse().unwrap();
+        let other: Url = "https://attacker.example.com/callback".parse().unwrap();
+        assert_eq!(grant.check_code_binding("client-a", Some(&same)), Ok(()));
+        assert_eq!(
+            grant.check_code_binding("client-a", Some(&other)),
+            Err(AuthorizationCodeError::RedirectUriMismatch)
+        );
+    }
+
+    #[test]
+    fn first_grant_needs_consent() {
+        let grant = grant("client-a");
+        assert!(grant.needs_consent(&std::iter::empty().collect()));
+    }
+
+    #[test]
+    fn remembered_consent_is_reused() {
+        let mut grant = grant("client-a");
+        grant.scope = "openid urn:matrix:device:ABCDEF".parse().unwrap();
+
+        let remembered: Scope = "openid email".parse().unwrap();
+        assert!(!grant.needs_consent(&remembered));
+
+        // Asking for more than what was remembered needs a new consent
+        grant.scope = "openid profile".parse().unwrap();
+        assert!(grant.needs_consent(&remembered));
+    }
+
+    #[test]
+    fn explicit_prompt_needs_consent() {
+        let mut grant = grant("client-a");
+        grant.requires_consent = true;
+        assert!(grant.needs_consent(&"openid".parse().unwrap()));
+
+        // Until the user consents to this grant
+        grant.requires_consent = false;
+        grant.consent_given = true;
+        assert!(!grant.needs_consent(&std::iter::empty().collect()));
+    }
 }
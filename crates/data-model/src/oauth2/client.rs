@@ -20,7 +20,7 @@ use mas_jose::JsonWebKeySet;
 use oauth2_types::requests::GrantType;
 use serde::Serialize;
 use thiserror::Error;
-use url::Url;
+use url::{Host, Url};
 
 use crate::traits::{StorageBackend, StorageBackendMarker};
 
@@ -34,6 +34,31 @@ pub enum JwksOrJwksUri {
     JwksUri(Url),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientType {
+    Public,
+    Confidential,
+}
+
+impl ClientType {
+    /// Public clients are the ones which can't authenticate at the token
+    /// endpoint, either because they use the `none` method or have none set
+    #[must_use]
+    pub fn from_auth_method(method: Option<&OAuthClientAuthenticationMethod>) -> Self {
+        match method {
+            None | Some(OAuthClientAuthenticationMethod::None) => Self::Public,
+            Some(_) => Self::Confidential,
+        }
+    }
+
+    /// Whether this kind of client must use PKCE to get an authorization code
+    #[must_use]
+    pub fn requires_pkce(self) -> bool {
+        matches!(self, Self::Public)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
 pub struct Client<T: StorageBackend> {
@@ -43,6 +68,8 @@ pub struct Client<T: StorageBackend> {
     /// Client identifier
     pub client_id: String,
 
+    pub client_type: ClientType,
+
     pub encrypted_client_secret: Option<String>,
 
     /// Array of Redirection URI values used by the Client
@@ -103,6 +130,7 @@ impl<S: StorageBackendMarker> From<Client<S>> for Client<()> {
         Client {
             data: (),
             client_id: c.client_id,
+            client_type: c.client_type,
             encrypted_client_secret: c.encrypted_client_secret,
             redirect_uris: c.redirect_uris,
             response_types: c.response_types,
@@ -125,7 +153,7 @@ impl<S: StorageBackendMarker> From<Client<S>> for Client<()> {
 
 #[derive(Debug, Error)]
 pub enum InvalidRedirectUriError {
-    #[error("redirect_uri is not allowed for this client")]
+    #[error("redirect_uri does not exactly match any of the URIs registered for this client")]
     NotAllowed,
 
     #[error("multiple redirect_uris registered for this client")]
@@ -135,17 +163,286 @@ pub enum InvalidRedirectUriError {
     NoneRegistered,
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RedirectUriValidationError {
+    #[error("redirect URIs can't have a fragment")]
+    Fragment,
+
+    #[error("redirect URIs can only use http on loopback addresses")]
+    InsecureHttp,
+
+    #[error("the {0:?} scheme is not allowed in redirect URIs")]
+    DisallowedScheme(String),
+}
+
+/// Schemes which could run code or read local data in the browser
+const DISALLOWED_SCHEMES: [&str; 6] = ["javascript", "data", "vbscript", "file", "blob", "about"];
+
+fn is_loopback(uri: &Url) -> bool {
+    match uri.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+/// Check that a URI can be registered as a redirect URI: it can't have a
+/// fragment, and can only use `http` on loopback addresses.
+///
+/// # Errors
+///
+/// Returns an error if the URI is not acceptable
+pub fn validate_redirect_uri(uri: &Url) -> Result<(), RedirectUriValidationError> {
+    if uri.fragment().is_some() {
+        return Err(RedirectUriValidationError::Fragment);
+    }
+
+    match uri.scheme() {
+        "http" if !is_loopback(uri) => Err(RedirectUriValidationError::InsecureHttp),
+        scheme if DISALLOWED_SCHEMES.contains(&scheme) => Err(
+            RedirectUriValidationError::DisallowedScheme(scheme.to_owned()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+impl<T: StorageBackend> Client<T>
+where
+    T::ClientData: Default,
+{
+    /// A public client with all its metadata, and a confidential client
+    /// without any
+    #[must_use]
+    pub fn samples() -> Vec<Self> {
+        vec![
+            Client {
+                data: Default::default(),
+                client_id: "client1".to_string(),
+                client_type: ClientType::Public,
+                encrypted_client_secret: None,
+                redirect_uris: vec!["https://client1.example.com/callback".parse().unwrap()],
+                response_types: vec![OAuthAuthorizationEndpointResponseType::Code],
+                grant_types: vec![GrantType::AuthorizationCode, GrantType::RefreshToken],
+                contacts: vec!["admin@client1.example.com".to_string()],
+                client_name: Some("Client 1".to_string()),
+                logo_uri: Some("https://client1.example.com/logo.png".parse().unwrap()),
+                client_uri: Some("https://client1.example.com/".parse().unwrap()),
+                policy_uri: Some("https://client1.example.com/privacy".parse().unwrap()),
+                tos_uri: Some("https://client1.example.com/tos".parse().unwrap()),
+                jwks: None,
+                id_token_signed_response_alg: None,
+                userinfo_signed_response_alg: None,
+                token_endpoint_auth_method: Some(OAuthClientAuthenticationMethod::None),
+                token_endpoint_auth_signing_alg: None,
+                initiate_login_uri: None,
+            },
+            Client {
+                data: Default::default(),
+                client_id: "client2".to_string(),
+                client_type: ClientType::Confidential,
+                encrypted_client_secret: None,
+                redirect_uris: vec!["https://client2.example.com/callback".parse().unwrap()],
+                response_types: vec![OAuthAuthorizationEndpointResponseType::Code],
+                grant_types: vec![GrantType::AuthorizationCode],
+                contacts: Vec::new(),
+                client_name: None,
+                logo_uri: None,
+                client_uri: None,
+                policy_uri: None,
+                tos_uri: None,
+                jwks: None,
+                id_token_signed_response_alg: None,
+                userinfo_signed_response_alg: None,
+                token_endpoint_auth_method: Some(
+                    OAuthClientAuthenticationMethod::ClientSecretBasic,
+                ),
+                token_endpoint_auth_signing_alg: None,
+                initiate_login_uri: None,
+            },
+        ]
+    }
+}
+
 impl<S: StorageBackend> Client<S> {
+    /// Find the registered redirect URI matching the one of an authorization
+    /// request, which may be omitted if the client registered only one.
+    ///
+    /// URIs are compared as strings, after the canonicalization done when
+    /// parsing them: there are no wildcards, and `/callback` doesn't match
+    /// `/callback/`.
     pub fn resolve_redirect_uri<'a>(
         &'a self,
-        redirect_uri: &'a Option<Url>,
+        redirect_uri: &Option<Url>,
     ) -> Result<&'a Url, InvalidRedirectUriError> {
         match (&self.redirect_uris[..], redirect_uri) {
             ([], _) => Err(InvalidRedirectUriError::NoneRegistered),
             ([one], None) => Ok(one),
             (_, None) => Err(InvalidRedirectUriError::MultipleRegistered),
-            (uris, Some(uri)) if uris.contains(uri) => Ok(uri),
-            _ => Err(InvalidRedirectUriError::NotAllowed),
+            (uris, Some(uri)) => uris
+                .iter()
+                .find(|registered| registered.as_str() == uri.as_str())
+                .ok_or(InvalidRedirectUriError::NotAllowed),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_client_requires_pkce() {
+        let client_type =
+            ClientType::from_auth_method(Some(&OAuthClientAuthenticationMethod::None));
+        assert_eq!(client_type, ClientType::Public);
+        assert!(client_type.requires_pkce());
+
+        let client_type = ClientType::from_auth_method(None);
+        assert_eq!(client_type, ClientType::Public);
+        assert!(client_type.requires_pkce());
+    }
+
+    #[test]
+    fn confidential_client_may_skip_pkce() {
+        let client_type =
+            ClientType::from_auth_method(Some(&OAuthClientAuthenticationMethod::ClientSecretBasic));
+        assert_eq!(client_type, ClientType::Confidential);
+        assert!(!client_type.requires_pkce());
+    }
+
+    fn client_with_redirect_uris(redirect_uris: &[&str]) -> Client<()> {
+        Client {
+            data: (),
+            client_id: "client".to_owned(),
+            client_type: ClientType::Public,
+            encrypted_client_secret: None,
+            redirect_uris: redirect_uris
+                .iter()
+                .map(|uri| uri.parse().unwrap())
+                .collect(),
+            response_types: Vec::new(),
+            grant_types: Vec::new(),
+            contacts: Vec::new(),
+            client_name: None,
+            logo_uri: None,
+            client_uri: None,
+            policy_uri: None,
+            tos_uri: None,
+            jwks: None,
+            id_token_signed_response_alg: None,
+            userinfo_signed_response_alg: None,
+            token_endpoint_auth_method: None,
+            token_endpoint_auth_signing_alg: None,
+            initiate_login_uri: None,
+        }
+    }
+
+    #[test]
+    fn redirect_uri_exact_match() {
+        let client = client_with_redirect_uris(&[
+            "https://example.com/callback",
+            "https://example.com/other?foo=bar",
+            "com.example.app:/callback",
+        ]);
+
+        for uri in [
+            "https://example.com/callback",
+            "https://example.com/other?foo=bar",
+            "com.example.app:/callback",
+            // Those are equivalent once parsed
+            "HTTPS://EXAMPLE.COM/callback",
+            "https://example.com:443/callback",
+        ] {
+            let uri: Url = uri.parse().unwrap();
+            let resolved = client.resolve_redirect_uri(&Some(uri.clone())).unwrap();
+            assert_eq!(resolved.as_str(), uri.as_str());
+        }
+
+        // The only URI can be omitted
+        let client = client_with_redirect_uris(&["https://example.com/callback"]);
+        assert_eq!(
+            client.resolve_redirect_uri(&None).unwrap().as_str(),
+            "https://example.com/callback"
+        );
+    }
+
+    #[test]
+    fn redirect_uri_near_miss() {
+        let client = client_with_redirect_uris(&[
+            "https://example.com/callback",
+            "https://example.com/other?foo=bar",
+        ]);
+
+        for uri in [
+            "https://example.com/callback/",
+            "https://example.com/Callback",
+            "https://example.com/callback/extra",
+            "https://example.com/callback?foo=bar",
+            "https://example.com/other",
+            "https://example.com/other?foo=baz",
+            "https://example.com:8443/callback",
+            "https://sub.example.com/callback",
+            "https://example.com.evil.com/callback",
+            "http://example.com/callback",
+        ] {
+            let uri: Url = uri.parse().unwrap();
+            assert!(
+                matches!(
+                    client.resolve_redirect_uri(&Some(uri.clone())),
+                    Err(InvalidRedirectUriError::NotAllowed)
+                ),
+                "{} should not match",
+                uri
+            );
+        }
+
+        assert!(matches!(
+            client.resolve_redirect_uri(&None),
+            Err(InvalidRedirectUriError::MultipleRegistered)
+        ));
+        assert!(matches!(
+            client_with_redirect_uris(&[])
+                .resolve_redirect_uri(&Some("https://example.com/callback".parse().unwrap())),
+            Err(InvalidRedirectUriError::NoneRegistered)
+        ));
+    }
+
+    #[test]
+    fn redirect_uri_validation() {
+        for uri in [
+            "https://example.com/callback",
+            "http://localhost/callback",
+            "http://127.0.0.1:1234/callback",
+            "http://[::1]/callback",
+            "com.example.app:/callback",
+        ] {
+            assert_eq!(
+                validate_redirect_uri(&uri.parse().unwrap()),
+                Ok(()),
+                "{}",
+                uri
+            );
+        }
+
+        let validate = |uri: &str| validate_redirect_uri(&uri.parse().unwrap());
+        assert_eq!(
+            validate("http://example.com/callback"),
+            Err(RedirectUriValidationError::InsecureHttp)
+        );
+        assert_eq!(
+            validate("http://localhost.example.com/callback"),
+            Err(RedirectUriValidationError::InsecureHttp)
+        );
+        assert_eq!(
+            validate("https://example.com/callback#fragment"),
+            Err(RedirectUriValidationError::Fragment)
+        );
+        assert_eq!(
+            validate("javascript:alert(1)"),
+            Err(RedirectUriValidationError::DisallowedScheme(
+                "javascript".to_owned()
+            ))
+        );
+    }
+}
@@ -17,7 +17,13 @@ pub(self) mod client;
 pub(self) mod session;
 
 pub use self::{
-    authorization_grant::{AuthorizationCode, AuthorizationGrant, AuthorizationGrantStage, Pkce},
-    client::{Client, InvalidRedirectUriError, JwksOrJwksUri},
+    authorization_grant::{
+        AuthorizationCode, AuthorizationCodeError, AuthorizationGrant, AuthorizationGrantStage,
+        Pkce,
+    },
+    client::{
+        validate_redirect_uri, Client, ClientType, InvalidRedirectUriError, JwksOrJwksUri,
+        RedirectUriValidationError,
+    },
     session::Session,
 };
@@ -41,3 +41,33 @@ impl<S: StorageBackendMarker> From<Session<S>> for Session<()> {
         }
     }
 }
+
+impl<T: StorageBackend> Session<T> {
+    /// Check if the scope granted to this session allows the given scope token
+    #[must_use]
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scope.allows(scope)
+    }
+}
+
+impl<T: StorageBackend> Session<T>
+where
+    T::SessionData: Default,
+    T::BrowserSessionData: Default,
+    T::UserData: Default,
+    T::ClientData: Default,
+{
+    #[must_use]
+    pub fn samples() -> Vec<Self> {
+        BrowserSession::<T>::samples()
+            .into_iter()
+            .zip(Client::<T>::samples())
+            .map(|(browser_session, client)| Session {
+                data: Default::default(),
+                browser_session,
+                client,
+                scope: "openid profile email".parse().unwrap(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, TimeZone, Utc};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use data_encoding::BASE64URL_NOPAD;
+use thiserror::Error;
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// An opaque position in a list of items ordered by creation time, used for
+/// pagination
+///
+/// It encodes the creation time and ID of the last item of a page, followed
+/// by a CRC checksum to catch cursors which were tampered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: DateTime<Utc>,
+    pub id: i64,
+}
+
+impl Cursor {
+    #[must_use]
+    pub fn new(created_at: DateTime<Utc>, id: i64) -> Self {
+        Self { created_at, id }
+    }
+
+    /// Encode the cursor as a base64url string
+    #[must_use]
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(20);
+        let timestamp = self.created_at.timestamp() * 1_000_000
+            + i64::from(self.created_at.timestamp_subsec_micros());
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        let crc = CRC.checksum(&bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        BASE64URL_NOPAD.encode(&bytes)
+    }
+
+    /// Decode a cursor previously encoded with [`Cursor::encode`]
+    pub fn decode(encoded: &str) -> Result<Self, CursorError> {
+        let bytes = BASE64URL_NOPAD
+            .decode(encoded.as_bytes())
+            .map_err(|_| CursorError::InvalidFormat)?;
+
+        let bytes: [u8; 20] = bytes.try_into().map_err(|_| CursorError::InvalidFormat)?;
+
+        let (payload, crc) = bytes.split_at(16);
+        let crc = u32::from_be_bytes(crc.try_into().map_err(|_| CursorError::InvalidFormat)?);
+        if crc != CRC.checksum(payload) {
+            return Err(CursorError::InvalidCrc);
+        }
+
+        let (timestamp, id) = payload.split_at(8);
+        let timestamp = i64::from_be_bytes(
+            timestamp
+                .try_into()
+                .map_err(|_| CursorError::InvalidFormat)?,
+        );
+        let id = i64::from_be_bytes(id.try_into().map_err(|_| CursorError::InvalidFormat)?);
+
+        let micros = u32::try_from(timestamp.rem_euclid(1_000_000))
+            .map_err(|_| CursorError::InvalidFormat)?;
+        let created_at = Utc
+            .timestamp_opt(timestamp.div_euclid(1_000_000), micros * 1000)
+            .single()
+            .ok_or(CursorError::InvalidFormat)?;
+
+        Ok(Self { created_at, id })
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.encode())
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = CursorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::decode(s)
+    }
+}
+
+/// Invalid pagination cursor
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CursorError {
+    /// The cursor is not valid base64url or has the wrong length
+    #[error("invalid cursor format")]
+    InvalidFormat,
+
+    /// The CRC checksum in the cursor does not match its content
+    #[error("invalid cursor checksum")]
+    InvalidCrc,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        let created_at = Utc.ymd(2022, 6, 15).and_hms_micro(12, 34, 56, 789_012);
+        let cursor = Cursor::new(created_at, 42);
+
+        let encoded = cursor.to_string();
+        assert_eq!(Cursor::decode(&encoded), Ok(cursor));
+        assert_eq!(encoded.parse::<Cursor>(), Ok(cursor));
+    }
+
+    #[test]
+    fn tampered_cursor_is_rejected() {
+        let created_at = Utc.ymd(2022, 6, 15).and_hms(12, 34, 56);
+        let encoded = Cursor::new(created_at, 42).encode();
+
+        // Flip one byte of the ID
+        let mut bytes = BASE64URL_NOPAD.decode(encoded.as_bytes()).unwrap();
+        bytes[15] ^= 0x01;
+        let tampered = BASE64URL_NOPAD.encode(&bytes);
+        assert_eq!(Cursor::decode(&tampered), Err(CursorError::InvalidCrc));
+
+        // Truncated or garbage cursors
+        assert_eq!(
+            Cursor::decode(&encoded[..10]),
+            Err(CursorError::InvalidFormat)
+        );
+        assert_eq!(
+            Cursor::decode("not a cursor"),
+            Err(CursorError::InvalidFormat)
+        );
+    }
+}
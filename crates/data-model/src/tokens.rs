@@ -20,7 +20,7 @@ use thiserror::Error;
 
 use crate::traits::{StorageBackend, StorageBackendMarker};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct AccessToken<T: StorageBackend> {
     pub data: T::AccessTokenData,
     pub jti: String,
@@ -41,13 +41,29 @@ impl<S: StorageBackendMarker> From<AccessToken<S>> for AccessToken<()> {
     }
 }
 
+impl<T: StorageBackend> std::fmt::Debug for AccessToken<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the token itself, it would be enough to impersonate the
+        // session if it ended up in the logs
+        f.debug_struct("AccessToken")
+            .field("data", &self.data)
+            .field("jti", &self.jti)
+            .field("token", &format_args!("[redacted]"))
+            .field("expires_after", &self.expires_after)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
 impl<T: StorageBackend> AccessToken<T> {
-    pub fn exp(&self) -> DateTime<Utc> {
+    /// When the token stops being valid
+    #[must_use]
+    pub fn expires_at(&self) -> DateTime<Utc> {
         self.created_at + self.expires_after
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct RefreshToken<T: StorageBackend> {
     pub data: T::RefreshTokenData,
     pub token: String,
@@ -66,6 +82,17 @@ impl<S: StorageBackendMarker> From<RefreshToken<S>> for RefreshToken<()> {
     }
 }
 
+impl<T: StorageBackend> std::fmt::Debug for RefreshToken<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RefreshToken")
+            .field("data", &self.data)
+            .field("token", &format_args!("[redacted]"))
+            .field("created_at", &self.created_at)
+            .field("access_token", &self.access_token)
+            .finish()
+    }
+}
+
 /// Type of token to generate or validate
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
@@ -82,24 +109,46 @@ pub enum TokenType {
     CompatRefreshToken,
 }
 
+/// Prefix of the tokens of each type, used both to generate and to parse them
+const PREFIXES: [(TokenType, &str); 4] = [
+    (TokenType::AccessToken, "mat"),
+    (TokenType::RefreshToken, "mar"),
+    (TokenType::CompatAccessToken, "mct"),
+    (TokenType::CompatRefreshToken, "mcr"),
+];
+
 impl TokenType {
     fn prefix(self) -> &'static str {
-        match self {
-            TokenType::AccessToken => "mat",
-            TokenType::RefreshToken => "mar",
-            TokenType::CompatAccessToken => "mct",
-            TokenType::CompatRefreshToken => "mcr",
-        }
+        PREFIXES
+            .iter()
+            .find_map(|&(token_type, prefix)| (token_type == self).then(|| prefix))
+            .expect("all token types have a prefix")
     }
 
     fn match_prefix(prefix: &str) -> Option<Self> {
-        match prefix {
-            "mat" => Some(TokenType::AccessToken),
-            "mar" => Some(TokenType::RefreshToken),
-            "mct" => Some(TokenType::CompatAccessToken),
-            "mcr" => Some(TokenType::CompatRefreshToken),
-            _ => None,
-        }
+        PREFIXES
+            .iter()
+            .find_map(|&(token_type, p)| (p == prefix).then(|| token_type))
+    }
+
+    /// Guess the type of a token from its prefix, without checking the rest
+    /// of its format. Useful to reject tokens of the wrong type early, before
+    /// looking them up.
+    ///
+    /// ```rust
+    /// use mas_data_model::TokenType;
+    ///
+    /// assert_eq!(
+    ///     TokenType::from_token("mct_kkLSacJDpek22jKWw4AcXG68b7U3W6_0Lg9yb"),
+    ///     Some(TokenType::CompatAccessToken)
+    /// );
+    ///
+    /// assert_eq!(TokenType::from_token("syt_foo_bar"), None);
+    /// ```
+    #[must_use]
+    pub fn from_token(token: &str) -> Option<Self> {
+        let (prefix, _) = token.split_once('_')?;
+        Self::match_prefix(prefix)
     }
 
     /// Generate a token for the given type
@@ -168,6 +217,26 @@ impl TokenType {
 
         Ok(token_type)
     }
+
+    /// Check that a token is of this type and that its checksum matches,
+    /// without any lookup. Tokens with a typo in them fail this check, so it
+    /// should be done before looking them up in the database.
+    ///
+    /// ```rust
+    /// use mas_data_model::TokenType;
+    ///
+    /// let token = "mat_kkLSacJDpek22jKWw4AcXG68b7U3W6_0Lg9yb";
+    /// assert!(TokenType::AccessToken.validate_checksum(token));
+    /// assert!(!TokenType::RefreshToken.validate_checksum(token));
+    ///
+    /// // One character changed
+    /// let token = "mat_kkLSacJDpek22jKWw4AcXG68b7U3W7_0Lg9yb";
+    /// assert!(!TokenType::AccessToken.validate_checksum(token));
+    /// ```
+    #[must_use]
+    pub fn validate_checksum(self, token: &str) -> bool {
+        Self::check(token) == Ok(self)
+    }
 }
 
 impl PartialEq<OAuthTokenTypeHint> for TokenType {
@@ -259,6 +328,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_from_token() {
+        let mut rng = thread_rng();
+
+        for t in [
+            TokenType::CompatAccessToken,
+            TokenType::CompatRefreshToken,
+            TokenType::AccessToken,
+            TokenType::RefreshToken,
+        ] {
+            let token = t.generate(&mut rng);
+            assert_eq!(TokenType::from_token(&token), Some(t));
+        }
+
+        // Tokens from other services, or without any prefix
+        assert_eq!(
+            TokenType::from_token("syt_YWxpY2U_oLcOpKrHWHJtSbGpEwrb_2NqLzU"),
+            None
+        );
+        assert_eq!(TokenType::from_token("mat"), None);
+        assert_eq!(
+            TokenType::from_token("matkkLSacJDpek22jKWw4AcXG68b7U3W6"),
+            None
+        );
+        assert_eq!(TokenType::from_token(""), None);
+    }
+
+    #[test]
+    fn test_validate_checksum() {
+        let mut rng = thread_rng();
+        let token = TokenType::CompatAccessToken.generate(&mut rng);
+        assert!(TokenType::CompatAccessToken.validate_checksum(&token));
+
+        // Changing any single character of the random part or the checksum
+        // makes the check fail
+        for (i, c) in token.char_indices().skip(4) {
+            if c == '_' {
+                continue;
+            }
+
+            let replacement = if c == 'a' { 'b' } else { 'a' };
+            let mut mutated = token.clone();
+            mutated.replace_range(i..=i, &replacement.to_string());
+            assert!(
+                !TokenType::CompatAccessToken.validate_checksum(&mutated),
+                "{} should not pass the check",
+                mutated
+            );
+        }
+    }
+
     #[test]
     fn test_generate_and_check() {
         const COUNT: usize = 500; // Generate 500 of each token type
@@ -282,4 +402,58 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn access_token_debug_is_redacted() {
+        let token = TokenType::AccessToken.generate(thread_rng());
+        let access_token = AccessToken::<()> {
+            data: (),
+            jti: "some-jti".to_owned(),
+            token: token.clone(),
+            expires_after: Duration::minutes(5),
+            created_at: Utc::now(),
+        };
+
+        let debug = format!("{:?}", access_token);
+        assert!(!debug.contains(&token));
+        assert!(debug.contains("token: [redacted]"));
+        assert!(debug.contains("some-jti"));
+    }
+
+    #[test]
+    fn refresh_token_debug_is_redacted() {
+        let access_token = TokenType::AccessToken.generate(thread_rng());
+        let token = TokenType::RefreshToken.generate(thread_rng());
+        let refresh_token = RefreshToken::<()> {
+            data: (),
+            token: token.clone(),
+            created_at: Utc::now(),
+            access_token: Some(AccessToken {
+                data: (),
+                jti: "some-jti".to_owned(),
+                token: access_token.clone(),
+                expires_after: Duration::minutes(5),
+                created_at: Utc::now(),
+            }),
+        };
+
+        let debug = format!("{:?}", refresh_token);
+        assert!(!debug.contains(&token));
+        assert!(!debug.contains(&access_token));
+        assert!(debug.contains("token: [redacted]"));
+        assert!(debug.contains("some-jti"));
+    }
+
+    #[test]
+    fn access_token_expires_at() {
+        let created_at = Utc::now();
+        let token = AccessToken::<()> {
+            data: (),
+            jti: "1".to_owned(),
+            token: TokenType::AccessToken.generate(&mut thread_rng()),
+            expires_after: Duration::minutes(5),
+            created_at,
+        };
+        assert_eq!(token.expires_at(), created_at + Duration::minutes(5));
+    }
 }
@@ -0,0 +1,331 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Time-based one-time passwords ([RFC 6238]), used as a second factor
+//!
+//! [RFC 6238]: https://datatracker.ietf.org/doc/html/rfc6238
+
+use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use thiserror::Error;
+use url::Url;
+
+use crate::traits::StorageBackend;
+
+/// How many bytes of randomness go in a secret, as recommended by RFC 4226
+const SECRET_LENGTH: usize = 20;
+
+/// How long each code is valid, in seconds
+const PERIOD: u64 = 30;
+
+/// How many digits the codes have
+const DIGITS: usize = 6;
+
+/// Codes are the truncated hash modulo 10^[`DIGITS`]
+const MODULUS: u32 = 1_000_000;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid TOTP secret")]
+pub struct InvalidTotpSecret;
+
+/// The secret shared with the authenticator app of the user
+#[derive(Clone, PartialEq, Eq)]
+pub struct TotpSecret(Vec<u8>);
+
+impl std::fmt::Debug for TotpSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Anyone with the secret can generate valid codes
+        f.write_str("TotpSecret([redacted])")
+    }
+}
+
+impl TotpSecret {
+    /// Generate a new random secret
+    #[must_use]
+    pub fn generate<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let mut secret = vec![0; SECRET_LENGTH];
+        rng.fill(&mut secret[..]);
+        Self(secret)
+    }
+
+    /// Parse a secret from its base32 representation
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the string is not valid unpadded base32
+    pub fn from_base32(encoded: &str) -> Result<Self, InvalidTotpSecret> {
+        let secret = BASE32_NOPAD
+            .decode(encoded.as_bytes())
+            .map_err(|_| InvalidTotpSecret)?;
+
+        if secret.is_empty() {
+            return Err(InvalidTotpSecret);
+        }
+
+        Ok(Self(secret))
+    }
+
+    /// Get back a secret from its raw bytes
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no bytes
+    pub fn from_bytes(secret: Vec<u8>) -> Result<Self, InvalidTotpSecret> {
+        if secret.is_empty() {
+            return Err(InvalidTotpSecret);
+        }
+
+        Ok(Self(secret))
+    }
+
+    /// The raw bytes of the secret
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The base32 representation of the secret, which is how authenticator
+    /// apps expect it
+    #[must_use]
+    pub fn to_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.0)
+    }
+
+    /// The `otpauth://` URI used to add this secret to an authenticator app,
+    /// usually by scanning it as a QR code
+    #[must_use]
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> Url {
+        let mut uri = Url::parse("otpauth://totp/").expect("static URL is valid");
+        uri.path_segments_mut()
+            .expect("URL has a path")
+            .pop_if_empty()
+            .push(&format!("{}:{}", issuer, account));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.to_base32())
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &PERIOD.to_string());
+        uri
+    }
+
+    fn code_at_step(&self, step: u64) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation, as per RFC 4226 section 5.3
+        let offset = usize::from(hash[hash.len() - 1] & 0xf);
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        let code = binary % MODULUS;
+        format!("{:0width$}", code, width = DIGITS)
+    }
+
+    /// The code valid at the given time
+    #[must_use]
+    pub fn code_at(&self, time: DateTime<Utc>) -> String {
+        self.code_at_step(step(time))
+    }
+
+    /// Check a code entered by the user. Codes from the previous and the next
+    /// periods are also accepted, to allow for clock drift and slow typing
+    #[must_use]
+    pub fn verify(&self, code: &str, now: DateTime<Utc>) -> bool {
+        self.matching_step(code, now).is_some()
+    }
+
+    /// Like [`TotpSecret::verify`], but returns the time step the code is for
+    #[must_use]
+    pub fn matching_step(&self, code: &str, now: DateTime<Utc>) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let step = step(now);
+        [step.saturating_sub(1), step, step + 1]
+            .into_iter()
+            .find(|step| self.code_at_step(*step) == code)
+    }
+}
+
+fn step(time: DateTime<Utc>) -> u64 {
+    u64::try_from(time.timestamp()).unwrap_or(0) / PERIOD
+}
+
+/// A TOTP second factor of a user
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserTotp<T: StorageBackend> {
+    pub data: T::UserTotpData,
+    /// The [`TotpSecret`], encrypted with the key from the configuration
+    pub encrypted_secret: String,
+    pub created_at: DateTime<Utc>,
+    /// When the user proved their app was set up by entering a valid code.
+    /// Logins only require a code after that
+    pub verified_at: Option<DateTime<Utc>>,
+    /// The time step of the last code accepted
+    pub last_used_step: Option<u64>,
+}
+
+impl<T: StorageBackend> UserTotp<T> {
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    /// Check a code against the decrypted secret of this factor, and return
+    /// the time step it is for. A code is only accepted once: codes for the
+    /// step of the last accepted one, or an earlier one, are rejected
+    #[must_use]
+    pub fn check_code(&self, secret: &TotpSecret, code: &str, now: DateTime<Utc>) -> Option<u64> {
+        secret
+            .matching_step(code, now)
+            .filter(|step| self.last_used_step.map_or(true, |last| *step > last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use rand::thread_rng;
+
+    use super::*;
+
+    /// The SHA-1 secret from the test vectors of RFC 6238 appendix B
+    fn rfc_secret() -> TotpSecret {
+        TotpSecret(b"12345678901234567890".to_vec())
+    }
+
+    #[test]
+    fn rfc6238_test_vectors() {
+        let secret = rfc_secret();
+        // The RFC uses 8 digits, these are the last 6
+        for (time, code) in [
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_111_111_111, "050471"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+        ] {
+            assert_eq!(secret.code_at(Utc.timestamp(time, 0)), code);
+        }
+    }
+
+    #[test]
+    fn accepts_valid_codes() {
+        let secret = rfc_secret();
+        let now = Utc.timestamp(1_111_111_109, 0);
+        assert!(secret.verify("081804", now));
+        assert!(secret.verify(" 081804 ", now));
+
+        // The code from the previous period is still accepted
+        assert!(secret.verify("081804", now + chrono::Duration::seconds(30)));
+    }
+
+    #[test]
+    fn rejects_wrong_codes() {
+        let secret = rfc_secret();
+        let now = Utc.timestamp(1_111_111_109, 0);
+        assert!(!secret.verify("081805", now));
+        assert!(!secret.verify("81804", now));
+        assert!(!secret.verify("0818040", now));
+        assert!(!secret.verify("08180a", now));
+        assert!(!secret.verify("", now));
+
+        // Codes are only valid for a short time
+        assert!(!secret.verify("081804", now + chrono::Duration::minutes(5)));
+    }
+
+    #[test]
+    fn enrollment() {
+        let secret = TotpSecret::generate(&mut thread_rng());
+        assert_eq!(
+            TotpSecret::from_base32(&secret.to_base32()),
+            Ok(secret.clone())
+        );
+        assert_eq!(
+            TotpSecret::from_base32("not base32!"),
+            Err(InvalidTotpSecret)
+        );
+
+        let uri = secret.provisioning_uri("example.com", "alice");
+        assert_eq!(uri.scheme(), "otpauth");
+        assert_eq!(uri.host_str(), Some("totp"));
+        assert_eq!(uri.path(), "/example.com:alice");
+        let encoded = uri
+            .query_pairs()
+            .find(|(key, _)| key == "secret")
+            .map(|(_, value)| value.into_owned())
+            .unwrap();
+        assert_eq!(TotpSecret::from_base32(&encoded), Ok(secret.clone()));
+
+        // What the app would show right now is accepted
+        let now = Utc::now();
+        assert!(secret.verify(&secret.code_at(now), now));
+    }
+
+    #[test]
+    fn codes_are_only_accepted_once() {
+        let secret = rfc_secret();
+        let now = Utc.timestamp(1_111_111_109, 0);
+        let mut totp = UserTotp::<()> {
+            data: (),
+            encrypted_secret: String::new(),
+            created_at: now,
+            verified_at: Some(now),
+            last_used_step: None,
+        };
+
+        let step = totp.check_code(&secret, "081804", now).unwrap();
+        assert_eq!(Some(step), secret.matching_step("081804", now));
+
+        // Once used, neither that code nor an older one is accepted
+        totp.last_used_step = Some(step);
+        assert_eq!(totp.check_code(&secret, "081804", now), None);
+        let older = secret.code_at(now - chrono::Duration::seconds(30));
+        assert_eq!(totp.check_code(&secret, &older, now), None);
+
+        // The next one is
+        let later = now + chrono::Duration::seconds(30);
+        let next = secret.code_at(later);
+        assert_eq!(totp.check_code(&secret, &next, later), Some(step + 1));
+    }
+
+    #[test]
+    fn secret_bytes_roundtrip() {
+        let secret = TotpSecret::generate(&mut thread_rng());
+        assert_eq!(
+            TotpSecret::from_bytes(secret.as_bytes().to_vec()),
+            Ok(secret)
+        );
+        assert_eq!(TotpSecret::from_bytes(Vec::new()), Err(InvalidTotpSecret));
+    }
+
+    #[test]
+    fn secret_debug_is_redacted() {
+        let secret = rfc_secret();
+        let debug = format!("{:?}", secret);
+        assert!(!debug.contains(&secret.to_base32()));
+        assert!(debug.contains("[redacted]"));
+    }
+}
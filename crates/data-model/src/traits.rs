@@ -27,6 +27,7 @@ pub trait StorageBackend {
         + Serialize
         + DeserializeOwned
         + Default;
+    type PasswordResetData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type AuthenticationData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type BrowserSessionData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type ClientData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
@@ -38,6 +39,8 @@ pub trait StorageBackend {
     type CompatRefreshTokenData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type CompatSessionData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
     type CompatSsoLoginData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
+    type UserTotpData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
+    type WebauthnCredentialData: Clone + Debug + PartialEq + Serialize + DeserializeOwned + Default;
 }
 
 impl StorageBackend for () {
@@ -50,9 +53,12 @@ impl StorageBackend for () {
     type CompatRefreshTokenData = ();
     type CompatSessionData = ();
     type CompatSsoLoginData = ();
+    type PasswordResetData = ();
     type RefreshTokenData = ();
     type SessionData = ();
     type UserData = ();
     type UserEmailData = ();
     type UserEmailVerificationData = ();
+    type UserTotpData = ();
+    type WebauthnCredentialData = ();
 }
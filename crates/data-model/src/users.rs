@@ -14,6 +14,7 @@
 
 use chrono::{DateTime, Duration, Utc};
 use serde::Serialize;
+use thiserror::Error;
 
 use crate::traits::{StorageBackend, StorageBackendMarker};
 
@@ -26,6 +27,16 @@ pub struct User<T: StorageBackend> {
     pub primary_email: Option<UserEmail<T>>,
 }
 
+impl<T: StorageBackend> User<T> {
+    /// Whether the user verified their primary email address
+    #[must_use]
+    pub fn has_verified_email(&self) -> bool {
+        self.primary_email
+            .as_ref()
+            .map_or(false, |email| email.confirmed_at.is_some())
+    }
+}
+
 impl<T: StorageBackend> User<T>
 where
     T::UserData: Default,
@@ -52,6 +63,208 @@ impl<S: StorageBackendMarker> From<User<S>> for User<()> {
     }
 }
 
+/// Failed password logins of a user, used to temporarily lock the account
+/// after too many failures in a row
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoginAttempts {
+    pub failed_count: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+impl LoginAttempts {
+    /// How many failed logins in a row lock the account
+    pub const MAX_FAILURES: u32 = 5;
+
+    /// How long the account stays locked
+    #[must_use]
+    pub fn lockout_duration() -> Duration {
+        Duration::minutes(15)
+    }
+
+    #[must_use]
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.locked_until.map_or(false, |until| until > now)
+    }
+
+    /// Record a failed login, locking the account if it was the last one
+    /// allowed. The count starts over once the account is locked
+    #[must_use]
+    pub fn record_failure(self, now: DateTime<Utc>) -> Self {
+        let failed_count = self.failed_count + 1;
+        if failed_count >= Self::MAX_FAILURES {
+            Self {
+                failed_count: 0,
+                locked_until: Some(now + Self::lockout_duration()),
+            }
+        } else {
+            Self {
+                failed_count,
+                ..self
+            }
+        }
+    }
+
+    /// Record a successful login, which resets the count
+    #[must_use]
+    pub fn record_success(self) -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PasswordPolicyError {
+    #[error("password must be at least {0} characters long")]
+    TooShort(usize),
+
+    #[error("password is too weak")]
+    TooWeak,
+}
+
+/// Requirements a new password has to meet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    /// Minimum number of characters
+    pub min_length: usize,
+
+    /// Minimum number of character classes (lowercase, uppercase, digits and
+    /// symbols) the password has to mix. `0` disables the check
+    pub min_complexity: u8,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_complexity: 0,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Number of character classes used in the password, from 0 to 4
+    #[must_use]
+    pub fn complexity(password: &str) -> u8 {
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(char::is_numeric),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+
+        classes.into_iter().map(u8::from).sum()
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), PasswordPolicyError> {
+        if password.chars().count() < self.min_length {
+            return Err(PasswordPolicyError::TooShort(self.min_length));
+        }
+
+        if Self::complexity(password) < self.min_complexity {
+            return Err(PasswordPolicyError::TooWeak);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UsernamePolicyError {
+    #[error("username must be at least {0} characters long")]
+    TooShort(usize),
+
+    #[error("username must be at most {0} characters long")]
+    TooLong(usize),
+
+    #[error("username can only contain lowercase letters, digits and `._=-/`")]
+    InvalidCharacters,
+
+    #[error("username is reserved")]
+    Reserved,
+}
+
+/// Requirements the username of a new user has to meet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsernamePolicy {
+    /// Minimum number of characters
+    pub min_length: usize,
+
+    /// Maximum number of characters
+    pub max_length: usize,
+
+    /// Usernames which can't be registered
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 1,
+            max_length: 64,
+            reserved: Vec::new(),
+        }
+    }
+}
+
+impl UsernamePolicy {
+    /// Check the username against the grammar of Matrix user ID localparts,
+    /// its length, and the reserved usernames
+    pub fn check(&self, username: &str) -> Result<(), UsernamePolicyError> {
+        // Only ASCII characters are allowed, so the length in bytes is the
+        // number of characters
+        let valid = username.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '=' | '-' | '/')
+        });
+        if !valid {
+            return Err(UsernamePolicyError::InvalidCharacters);
+        }
+
+        if username.len() < self.min_length {
+            return Err(UsernamePolicyError::TooShort(self.min_length));
+        }
+
+        if username.len() > self.max_length {
+            return Err(UsernamePolicyError::TooLong(self.max_length));
+        }
+
+        if self
+            .reserved
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(username))
+        {
+            return Err(UsernamePolicyError::Reserved);
+        }
+
+        Ok(())
+    }
+}
+
+/// A password reset requested by email, which lets the user set a new
+/// password without knowing the current one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(bound = "T: StorageBackend")]
+pub struct PasswordReset<T: StorageBackend> {
+    #[serde(skip_serializing)]
+    pub data: T::PasswordResetData,
+    pub token: String,
+    pub user: User<T>,
+    pub created_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+}
+
+impl<T: StorageBackend> PasswordReset<T> {
+    /// How long the reset link sent by email can be used
+    #[must_use]
+    pub fn ttl() -> Duration {
+        Duration::hours(1)
+    }
+
+    /// Check if the reset can still be used to set a new password
+    #[must_use]
+    pub fn is_valid(&self, now: DateTime<Utc>) -> bool {
+        self.consumed_at.is_none() && now < self.created_at + Self::ttl()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(bound = "T: StorageBackend")]
 pub struct Authentication<T: StorageBackend> {
@@ -218,3 +431,148 @@ where
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_locks_after_too_many_failures() {
+        let now = Utc::now();
+        let mut attempts = LoginAttempts::default();
+
+        for _ in 1..LoginAttempts::MAX_FAILURES {
+            attempts = attempts.record_failure(now);
+            assert!(!attempts.is_locked(now));
+        }
+
+        attempts = attempts.record_failure(now);
+        assert!(attempts.is_locked(now));
+        assert!(attempts.is_locked(now + Duration::minutes(1)));
+    }
+
+    #[test]
+    fn account_unlocks_after_the_lockout() {
+        let now = Utc::now();
+        let mut attempts = LoginAttempts::default();
+        for _ in 0..LoginAttempts::MAX_FAILURES {
+            attempts = attempts.record_failure(now);
+        }
+
+        let later = now + LoginAttempts::lockout_duration() + Duration::seconds(1);
+        assert!(!attempts.is_locked(later));
+
+        // A single failure after the lockout does not lock the account again
+        let attempts = attempts.record_failure(later);
+        assert!(!attempts.is_locked(later));
+    }
+
+    #[test]
+    fn successful_login_resets_the_count() {
+        let now = Utc::now();
+        let attempts = LoginAttempts::default()
+            .record_failure(now)
+            .record_failure(now)
+            .record_success();
+        assert_eq!(attempts, LoginAttempts::default());
+    }
+
+    #[test]
+    fn password_reset_validity() {
+        let now = Utc::now();
+        let reset = PasswordReset::<()> {
+            data: (),
+            token: "abc123".to_owned(),
+            user: User::samples().remove(0),
+            created_at: now,
+            consumed_at: None,
+        };
+        assert!(reset.is_valid(now));
+        assert!(reset.is_valid(now + Duration::minutes(59)));
+        assert!(!reset.is_valid(now + PasswordReset::<()>::ttl()));
+
+        let consumed = PasswordReset {
+            consumed_at: Some(now),
+            ..reset
+        };
+        assert!(!consumed.is_valid(now));
+    }
+
+    #[test]
+    fn password_policy_length() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check("hunter2"),
+            Err(PasswordPolicyError::TooShort(8))
+        );
+        assert_eq!(policy.check("hunter22"), Ok(()));
+        // Length is counted in characters, not bytes
+        assert_eq!(
+            policy.check("éééééé"),
+            Err(PasswordPolicyError::TooShort(8))
+        );
+    }
+
+    #[test]
+    fn password_policy_complexity() {
+        assert_eq!(PasswordPolicy::complexity(""), 0);
+        assert_eq!(PasswordPolicy::complexity("password"), 1);
+        assert_eq!(PasswordPolicy::complexity("Password"), 2);
+        assert_eq!(PasswordPolicy::complexity("Passw0rd"), 3);
+        assert_eq!(PasswordPolicy::complexity("Passw0rd!"), 4);
+
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_complexity: 3,
+        };
+        assert_eq!(policy.check("password"), Err(PasswordPolicyError::TooWeak));
+        assert_eq!(policy.check("Password"), Err(PasswordPolicyError::TooWeak));
+        assert_eq!(policy.check("Passw0rd"), Ok(()));
+    }
+
+    #[test]
+    fn verified_email() {
+        let mut user = User::<()>::samples().remove(0);
+        assert!(!user.has_verified_email());
+
+        let mut email = UserEmail::<()>::samples().remove(0);
+        email.confirmed_at = None;
+        user.primary_email = Some(email.clone());
+        assert!(!user.has_verified_email());
+
+        email.confirmed_at = Some(Utc::now());
+        user.primary_email = Some(email);
+        assert!(user.has_verified_email());
+    }
+
+    #[test]
+    fn username_policy() {
+        let policy = UsernamePolicy {
+            min_length: 3,
+            max_length: 8,
+            reserved: vec!["admin".to_owned(), "Support".to_owned()],
+        };
+
+        assert_eq!(policy.check("alice"), Ok(()));
+        assert_eq!(
+            policy.check("a.l_i=c-e/"),
+            Err(UsernamePolicyError::TooLong(8))
+        );
+        assert_eq!(policy.check("a.l_i=c/"), Ok(()));
+        assert_eq!(policy.check("al"), Err(UsernamePolicyError::TooShort(3)));
+        assert_eq!(policy.check(""), Err(UsernamePolicyError::TooShort(3)));
+
+        for invalid in ["Alice", "al ice", "alice@example", "élise"] {
+            assert_eq!(
+                policy.check(invalid),
+                Err(UsernamePolicyError::InvalidCharacters),
+                "{:?}",
+                invalid
+            );
+        }
+
+        assert_eq!(policy.check("admin"), Err(UsernamePolicyError::Reserved));
+        assert_eq!(policy.check("support"), Err(UsernamePolicyError::Reserved));
+        assert_eq!(policy.check("admins"), Ok(()));
+    }
+}
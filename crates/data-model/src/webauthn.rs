@@ -0,0 +1,100 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use crate::traits::StorageBackend;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("the signature counter did not increase, the authenticator might have been cloned")]
+pub struct ReplayedSignCount;
+
+/// A Web Authentication credential (passkey) a user registered to log in
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebauthnCredential<T: StorageBackend> {
+    pub data: T::WebauthnCredentialData,
+
+    /// The ID the authenticator gave to the credential, base64url-encoded
+    pub credential_id: String,
+
+    /// The opaque ID the authenticator knows the user by
+    pub user_handle: String,
+
+    /// The credential as serialized by the `webauthn-rs` library, which holds
+    /// the public key used to verify the assertions
+    pub passkey: String,
+
+    /// The signature counter of the last assertion
+    pub sign_count: u32,
+
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl<T: StorageBackend> WebauthnCredential<T> {
+    /// Check the signature counter of a new assertion, as per step 21 of the
+    /// [assertion verification].
+    ///
+    /// The counter has to increase with each assertion: an assertion with a
+    /// counter we already saw was either replayed or made by a clone of the
+    /// authenticator. Authenticators which don't implement the counter always
+    /// send zero, so the check is skipped when both the stored and the new
+    /// counters are zero.
+    ///
+    /// [assertion verification]: https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion
+    pub fn check_sign_count(&self, sign_count: u32) -> Result<(), ReplayedSignCount> {
+        if sign_count > self.sign_count || (sign_count == 0 && self.sign_count == 0) {
+            Ok(())
+        } else {
+            Err(ReplayedSignCount)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential(sign_count: u32) -> WebauthnCredential<()> {
+        WebauthnCredential {
+            data: (),
+            credential_id: "credential".to_owned(),
+            user_handle: "user".to_owned(),
+            passkey: "{}".to_owned(),
+            sign_count,
+            created_at: Utc::now(),
+            last_used_at: None,
+        }
+    }
+
+    #[test]
+    fn increasing_sign_count_is_accepted() {
+        assert_eq!(credential(5).check_sign_count(6), Ok(()));
+        assert_eq!(credential(5).check_sign_count(100), Ok(()));
+        assert_eq!(credential(0).check_sign_count(1), Ok(()));
+    }
+
+    #[test]
+    fn replayed_sign_count_is_rejected() {
+        assert_eq!(credential(5).check_sign_count(5), Err(ReplayedSignCount));
+        assert_eq!(credential(5).check_sign_count(4), Err(ReplayedSignCount));
+        assert_eq!(credential(5).check_sign_count(0), Err(ReplayedSignCount));
+    }
+
+    #[test]
+    fn authenticators_without_counter_are_accepted() {
+        assert_eq!(credential(0).check_sign_count(0), Ok(()));
+    }
+}
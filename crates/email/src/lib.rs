@@ -19,9 +19,17 @@
 #![warn(clippy::pedantic)]
 
 mod mailer;
+mod normalize;
+mod queue;
+mod rate_limit;
+mod retry;
 mod transport;
 
 pub use self::{
     mailer::Mailer,
+    normalize::EmailNormalizer,
+    queue::{MailJob, MailQueue, MailQueueWorker},
+    rate_limit::RateLimiter,
+    retry::RetryPolicy,
     transport::{aws_ses::Transport as AwsSesTransport, Transport as MailTransport},
 };
@@ -18,9 +18,26 @@ use lettre::{
     message::{Mailbox, MessageBuilder, MultiPart},
     AsyncTransport, Message,
 };
-use mas_templates::{EmailVerificationContext, Templates};
+use mas_templates::{EmailVerificationContext, PasswordResetEmailContext, Templates};
+use once_cell::sync::Lazy;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 
-use crate::MailTransport;
+use crate::{MailTransport, RateLimiter, RetryPolicy};
+
+static VERIFICATION_EMAIL_SENT_TOTAL: Lazy<Counter<u64>> = Lazy::new(|| {
+    global::meter("mas-email")
+        .u64_counter("verification_email_sent_total")
+        .with_description("Number of verification emails sent, by result")
+        .init()
+});
+
+static PASSWORD_RESET_EMAIL_SENT_TOTAL: Lazy<Counter<u64>> = Lazy::new(|| {
+    global::meter("mas-email")
+        .u64_counter("password_reset_email_sent_total")
+        .with_description("Number of password reset emails sent, by result")
+        .init()
+});
 
 /// Helps sending mails to users
 #[derive(Clone)]
@@ -29,6 +46,9 @@ pub struct Mailer {
     transport: MailTransport,
     from: Mailbox,
     reply_to: Mailbox,
+    monitor_bcc: Option<Mailbox>,
+    retry_policy: RetryPolicy,
+    rate_limiter: RateLimiter,
 }
 
 impl Mailer {
@@ -45,13 +65,75 @@ impl Mailer {
             transport: transport.clone(),
             from: from.clone(),
             reply_to: reply_to.clone(),
+            monitor_bcc: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: RateLimiter::default(),
         }
     }
 
+    /// Set the policy used to retry sending emails on transient failures
+    #[must_use]
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send a hidden copy of every email to this mailbox. It is not visible
+    /// to the recipients
+    #[must_use]
+    pub fn with_monitor_bcc(mut self, monitor_bcc: Option<Mailbox>) -> Self {
+        self.monitor_bcc = monitor_bcc;
+        self
+    }
+
+    /// Set the limiter used to throttle sends. It is shared with all the
+    /// clones of this [`Mailer`]
+    #[must_use]
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Send a message through the transport, retrying on transient failures
+    async fn send(&self, message: Message) -> anyhow::Result<()> {
+        let envelope = message.envelope();
+        let raw = message.formatted();
+        self.retry_policy
+            .run(
+                || async {
+                    // Every attempt counts towards the rate limit
+                    self.rate_limiter.acquire().await;
+                    self.transport.send_raw(envelope, &raw).await
+                },
+                MailTransport::is_transient,
+            )
+            .await
+    }
+
     fn base_message(&self) -> MessageBuilder {
-        Message::builder()
+        let builder = Message::builder()
             .from(self.from.clone())
             .reply_to(self.reply_to.clone())
+            .message_id(Some(self.message_id()))
+            .date_now();
+
+        // The Bcc header is dropped when the message is built, so only the
+        // envelope has the monitoring address
+        match &self.monitor_bcc {
+            Some(monitor_bcc) => builder.bcc(monitor_bcc.clone()),
+            None => builder,
+        }
+    }
+
+    /// Generate a unique Message-ID on the domain of the From address, so
+    /// that it aligns with the domain signing the emails
+    fn message_id(&self) -> String {
+        let id: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        format!("<{}@{}>", id, self.from.email.domain())
     }
 
     async fn prepare_verification_email(
@@ -95,8 +177,228 @@ impl Mailer {
         to: Mailbox,
         context: &EmailVerificationContext,
     ) -> anyhow::Result<()> {
-        let message = self.prepare_verification_email(to, context).await?;
-        self.transport.send(message).await?;
-        Ok(())
+        let result = async {
+            let message = self.prepare_verification_email(to, context).await?;
+            self.send(message).await
+        }
+        .await;
+
+        let label = if result.is_ok() { "success" } else { "failure" };
+        VERIFICATION_EMAIL_SENT_TOTAL.add(1, &[KeyValue::new("result", label)]);
+
+        result
+    }
+
+    async fn prepare_password_reset_email(
+        &self,
+        to: Mailbox,
+        context: &PasswordResetEmailContext,
+    ) -> anyhow::Result<Message> {
+        let plain = self
+            .templates
+            .render_email_password_reset_txt(context)
+            .await?;
+
+        let html = self
+            .templates
+            .render_email_password_reset_html(context)
+            .await?;
+
+        let multipart = MultiPart::alternative_plain_html(plain, html);
+
+        let subject = self
+            .templates
+            .render_email_password_reset_subject(context)
+            .await?;
+
+        let message = self
+            .base_message()
+            .subject(subject.trim())
+            .to(to)
+            .multipart(multipart)?;
+
+        Ok(message)
+    }
+
+    /// Send a password reset link to a user
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the email failed rendering or failed sending
+    pub async fn send_password_reset_email(
+        &self,
+        to: Mailbox,
+        context: &PasswordResetEmailContext,
+    ) -> anyhow::Result<()> {
+        let result = async {
+            let message = self.prepare_password_reset_email(to, context).await?;
+            self.send(message).await
+        }
+        .await;
+
+        let label = if result.is_ok() { "success" } else { "failure" };
+        PASSWORD_RESET_EMAIL_SENT_TOTAL.add(1, &[KeyValue::new("result", label)]);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_config::{EmailConfig, TemplatesConfig};
+    use mas_templates::TemplateContext;
+
+    use super::*;
+
+    fn header<'a>(raw: &'a str, name: &str) -> Vec<&'a str> {
+        let prefix = format!("{}: ", name);
+        raw.lines()
+            .filter_map(|line| line.strip_prefix(&prefix))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn verification_email_headers() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let mailer = Mailer::new(
+            &templates,
+            &MailTransport::default(),
+            &"Example <noreply@example.com>".parse().unwrap(),
+            &"Support <support@example.com>".parse().unwrap(),
+        );
+        let context = EmailVerificationContext::sample().remove(0);
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let message = mailer
+                .prepare_verification_email("alice@example.org".parse().unwrap(), &context)
+                .await
+                .unwrap();
+            let raw = String::from_utf8(message.formatted()).unwrap();
+
+            let message_id = header(&raw, "Message-ID");
+            assert_eq!(message_id.len(), 1);
+            assert!(message_id[0].starts_with('<'));
+            assert!(message_id[0].ends_with("@example.com>"));
+            ids.push(message_id[0].to_owned());
+
+            let date = header(&raw, "Date");
+            assert_eq!(date.len(), 1);
+            assert!(chrono::DateTime::parse_from_rfc2822(date[0]).is_ok());
+
+            assert_eq!(
+                header(&raw, "Reply-To"),
+                vec!["Support <support@example.com>"]
+            );
+        }
+
+        // Every email gets its own Message-ID
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn from_uses_the_configured_display_name() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let config = EmailConfig {
+            from: Mailbox::new(
+                Some("Example Auth".to_owned()),
+                "noreply@example.com".parse().unwrap(),
+            ),
+            ..EmailConfig::default()
+        };
+        let mailer = Mailer::new(
+            &templates,
+            &MailTransport::default(),
+            &config.from,
+            &config.reply_to,
+        );
+        let context = EmailVerificationContext::sample().remove(0);
+
+        let message = mailer
+            .prepare_verification_email("alice@example.org".parse().unwrap(), &context)
+            .await
+            .unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+
+        let from = header(&raw, "From");
+        assert_eq!(from.len(), 1);
+        assert!(from[0].contains("Example Auth"));
+        assert!(from[0].ends_with("<noreply@example.com>"));
+    }
+
+    #[tokio::test]
+    async fn monitor_bcc_is_a_hidden_recipient() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let mailer = Mailer::new(
+            &templates,
+            &MailTransport::default(),
+            &"Example <noreply@example.com>".parse().unwrap(),
+            &"Support <support@example.com>".parse().unwrap(),
+        );
+        let context = EmailVerificationContext::sample().remove(0);
+        let to: Mailbox = "alice@example.org".parse().unwrap();
+
+        // Without the option, only the recipient gets the email
+        let message = mailer
+            .prepare_verification_email(to.clone(), &context)
+            .await
+            .unwrap();
+        let recipients: Vec<String> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(recipients, vec!["alice@example.org"]);
+
+        let mailer = mailer.with_monitor_bcc(Some("monitoring@example.com".parse().unwrap()));
+        let message = mailer
+            .prepare_verification_email(to, &context)
+            .await
+            .unwrap();
+        let recipients: Vec<String> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            recipients,
+            vec!["alice@example.org", "monitoring@example.com"]
+        );
+
+        // The recipients can't see the copy
+        let raw = String::from_utf8(message.formatted()).unwrap();
+        assert!(header(&raw, "Bcc").is_empty());
+        assert!(!raw.contains("monitoring@example.com"));
+    }
+
+    #[tokio::test]
+    async fn password_reset_email_contains_the_link() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+        let mailer = Mailer::new(
+            &templates,
+            &MailTransport::default(),
+            &"Example <noreply@example.com>".parse().unwrap(),
+            &"Support <support@example.com>".parse().unwrap(),
+        );
+        let context = PasswordResetEmailContext::sample().remove(0);
+
+        let message = mailer
+            .prepare_password_reset_email("alice@example.org".parse().unwrap(), &context)
+            .await
+            .unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+
+        assert_eq!(header(&raw, "To"), vec!["alice@example.org"]);
+        assert!(raw.contains("https://example.com/account/password/reset/complete?token=abc123"));
     }
 }
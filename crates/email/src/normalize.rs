@@ -0,0 +1,121 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Normalize email addresses to detect duplicates
+
+use mas_config::EmailConfig;
+
+/// Providers which ignore the case and the dots in the local part, and the
+/// domain their addresses are normalized to
+const KNOWN_PROVIDERS: &[(&str, &str)] =
+    &[("gmail.com", "gmail.com"), ("googlemail.com", "gmail.com")];
+
+/// Computes the form of an email address used to compare it with others
+///
+/// The domain is always lowercased. The local part is only changed for known
+/// providers, and only if enabled, as other servers are free to treat it as
+/// case sensitive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EmailNormalizer {
+    known_providers: bool,
+}
+
+impl From<&EmailConfig> for EmailNormalizer {
+    fn from(config: &EmailConfig) -> Self {
+        Self::new(config.normalize_known_providers)
+    }
+}
+
+impl EmailNormalizer {
+    /// Constructs a new [`EmailNormalizer`]
+    #[must_use]
+    pub fn new(known_providers: bool) -> Self {
+        Self { known_providers }
+    }
+
+    /// Normalize an email address. The address as entered by the user should
+    /// still be used when displaying it or sending emails to it
+    #[must_use]
+    pub fn normalize(&self, email: &str) -> String {
+        let (local_part, domain) = match email.rsplit_once('@') {
+            Some(parts) => parts,
+            None => return email.to_owned(),
+        };
+
+        let domain = domain.to_lowercase();
+
+        if self.known_providers {
+            let provider = KNOWN_PROVIDERS
+                .iter()
+                .find(|(provider, _)| *provider == domain);
+
+            if let Some((_, canonical_domain)) = provider {
+                let local_part = local_part.replace('.', "").to_lowercase();
+                return format!("{}@{}", local_part, canonical_domain);
+            }
+        }
+
+        format!("{}@{}", local_part, domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_is_always_lowercased() {
+        let normalizer = EmailNormalizer::new(false);
+        assert_eq!(normalizer.normalize("John@Example.COM"), "John@example.com");
+        assert_eq!(
+            normalizer.normalize("John@Example.COM"),
+            normalizer.normalize("John@example.com"),
+        );
+
+        // Without the known providers rules, the local part is left alone
+        assert_ne!(
+            normalizer.normalize("John@example.com"),
+            normalizer.normalize("john@example.com"),
+        );
+        assert_ne!(
+            normalizer.normalize("j.ohn@gmail.com"),
+            normalizer.normalize("john@gmail.com"),
+        );
+    }
+
+    #[test]
+    fn known_providers_ignore_case_and_dots() {
+        let normalizer = EmailNormalizer::new(true);
+        assert_eq!(
+            normalizer.normalize("J.Ohn.Doe@GMail.com"),
+            "johndoe@gmail.com"
+        );
+        assert_eq!(
+            normalizer.normalize("john.doe@googlemail.com"),
+            "johndoe@gmail.com"
+        );
+
+        // Other domains are only lowercased
+        assert_eq!(
+            normalizer.normalize("J.Ohn@Example.com"),
+            "J.Ohn@example.com"
+        );
+    }
+
+    #[test]
+    fn invalid_addresses_are_left_alone() {
+        let normalizer = EmailNormalizer::new(true);
+        assert_eq!(normalizer.normalize("not an email"), "not an email");
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Queue emails to be sent in the background
+
+use std::future::Future;
+
+use anyhow::Context;
+use lettre::message::Mailbox;
+use mas_templates::{EmailVerificationContext, PasswordResetEmailContext};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::Mailer;
+
+/// An email waiting to be sent by the [`MailQueueWorker`]
+pub enum MailJob {
+    /// Send a verification email
+    Verification {
+        /// Who to send the email to
+        to: Mailbox,
+
+        /// The context used to render the email
+        context: EmailVerificationContext,
+    },
+
+    /// Send a password reset link
+    PasswordReset {
+        /// Who to send the email to
+        to: Mailbox,
+
+        /// The context used to render the email
+        context: PasswordResetEmailContext,
+    },
+}
+
+/// Handle used to queue emails to be sent in the background
+#[derive(Clone)]
+pub struct MailQueue {
+    sender: mpsc::Sender<MailJob>,
+}
+
+impl MailQueue {
+    /// Create a new queue which can hold up to `capacity` pending emails,
+    /// along with the worker which sends them
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero
+    #[must_use]
+    pub fn new(mailer: Mailer, capacity: usize) -> (Self, MailQueueWorker) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = Self { sender };
+        let worker = MailQueueWorker { receiver, mailer };
+        (queue, worker)
+    }
+
+    async fn push(&self, job: MailJob) -> anyhow::Result<()> {
+        self.sender
+            .send(job)
+            .await
+            .ok()
+            .context("the mail queue worker is not running")
+    }
+
+    /// Queue a verification email to be sent in the background
+    ///
+    /// This only waits if the queue is full.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the worker is not running anymore
+    pub async fn queue_verification_email(
+        &self,
+        to: Mailbox,
+        context: EmailVerificationContext,
+    ) -> anyhow::Result<()> {
+        self.push(MailJob::Verification { to, context }).await
+    }
+
+    /// Queue a password reset email to be sent in the background
+    ///
+    /// This only waits if the queue is full.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the worker is not running anymore
+    pub async fn queue_password_reset_email(
+        &self,
+        to: Mailbox,
+        context: PasswordResetEmailContext,
+    ) -> anyhow::Result<()> {
+        self.push(MailJob::PasswordReset { to, context }).await
+    }
+}
+
+/// Sends the emails pushed to a [`MailQueue`]
+pub struct MailQueueWorker {
+    receiver: mpsc::Receiver<MailJob>,
+    mailer: Mailer,
+}
+
+impl MailQueueWorker {
+    /// Send emails as they are queued, until all the [`MailQueue`] handles
+    /// are dropped and the remaining emails are sent
+    pub async fn run(self) {
+        let mailer = self.mailer;
+        drain(self.receiver, |job| {
+            let mailer = mailer.clone();
+            async move {
+                match job {
+                    MailJob::Verification { to, context } => {
+                        mailer.send_verification_email(to, &context).await
+                    }
+                    MailJob::PasswordReset { to, context } => {
+                        mailer.send_password_reset_email(to, &context).await
+                    }
+                }
+            }
+        })
+        .await;
+    }
+}
+
+async fn drain<F, Fut>(mut receiver: mpsc::Receiver<MailJob>, mut handle: F)
+where
+    F: FnMut(MailJob) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    while let Some(job) = receiver.recv().await {
+        if let Err(e) = handle(job).await {
+            error!(error = %e, "Failed to send queued email");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use mas_templates::TemplateContext;
+
+    use super::*;
+
+    fn job() -> MailJob {
+        MailJob::Verification {
+            to: "alice@example.com".parse().unwrap(),
+            context: EmailVerificationContext::sample().remove(0),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queueing_does_not_wait_for_the_send() {
+        let (sender, receiver) = mpsc::channel(4);
+        let queue = MailQueue { sender };
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+
+        let worker = tokio::spawn(drain(receiver, move |job| {
+            let done_tx = done_tx.clone();
+            async move {
+                // Simulate a very slow SMTP server
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                let (MailJob::Verification { to, .. } | MailJob::PasswordReset { to, .. }) = job;
+                done_tx.send(to).unwrap();
+                Ok(())
+            }
+        }));
+
+        let start = tokio::time::Instant::now();
+        queue.push(job()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // The worker eventually picks up the job
+        let to = done_rx.recv().await.unwrap();
+        assert_eq!(to.email.to_string(), "alice@example.com");
+
+        // Dropping the queue stops the worker
+        drop(queue);
+        worker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_do_not_stop_the_worker() {
+        let (sender, receiver) = mpsc::channel(4);
+        let queue = MailQueue { sender };
+
+        queue.push(job()).await.unwrap();
+        queue.push(job()).await.unwrap();
+        drop(queue);
+
+        let mut processed = 0;
+        drain(receiver, |_job| {
+            processed += 1;
+            async { Err(anyhow::anyhow!("failed")) }
+        })
+        .await;
+
+        assert_eq!(processed, 2);
+    }
+
+    #[tokio::test]
+    async fn queueing_fails_without_a_worker() {
+        let (sender, receiver) = mpsc::channel(4);
+        let queue = MailQueue { sender };
+        drop(receiver);
+
+        assert!(queue.push(job()).await.is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limit the rate at which emails are sent
+
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use mas_config::EmailRateLimitConfig;
+use tokio::time::Instant;
+
+/// A token bucket limiting how many emails are sent per second, shared by all
+/// the clones of a [`RateLimiter`]
+///
+/// Up to `burst` emails can be sent at once, after which sends are delayed to
+/// match the configured rate.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_second: NonZeroU32,
+    burst: NonZeroU32,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Available tokens. This goes below zero when sends are waiting for a
+    /// token, so that they are spaced out in the order they arrived
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::from(&EmailRateLimitConfig::default())
+    }
+}
+
+impl From<&EmailRateLimitConfig> for RateLimiter {
+    fn from(config: &EmailRateLimitConfig) -> Self {
+        Self::new(config.per_second, config.burst)
+    }
+}
+
+impl RateLimiter {
+    /// Constructs a new [`RateLimiter`]
+    #[must_use]
+    pub fn new(per_second: NonZeroU32, burst: NonZeroU32) -> Self {
+        let bucket = Bucket {
+            tokens: f64::from(burst.get()),
+            last_refill: Instant::now(),
+        };
+
+        Self {
+            per_second,
+            burst,
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Wait until an email can be sent
+    pub(crate) async fn acquire(&self) {
+        let wait = self.reserve();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token from the bucket, and return how long to wait before it is
+    /// actually available
+    fn reserve(&self) -> Duration {
+        let rate = f64::from(self.per_second.get());
+        let burst = f64::from(self.burst.get());
+
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last_refill = now;
+
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_second: u32, burst: u32) -> RateLimiter {
+        RateLimiter::new(
+            NonZeroU32::new(per_second).unwrap(),
+            NonZeroU32::new(burst).unwrap(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_are_smoothed() {
+        let limiter = limiter(2, 3);
+        let start = Instant::now();
+
+        let mut sent_at = Vec::new();
+        for _ in 0..7 {
+            limiter.acquire().await;
+            sent_at.push(start.elapsed());
+        }
+
+        // The first 3 go through immediately, then one every 500ms
+        let expected: Vec<_> = [0, 0, 0, 500, 1000, 1500, 2000]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+
+        for (sent_at, expected) in sent_at.into_iter().zip(expected) {
+            let diff = sent_at.max(expected).saturating_sub(sent_at.min(expected));
+            assert!(
+                diff < Duration::from_millis(5),
+                "{:?} != {:?}",
+                sent_at,
+                expected
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_share_the_limit() {
+        let limiter = limiter(1, 1);
+        let clone = limiter.clone();
+        let start = Instant::now();
+
+        limiter.acquire().await;
+        clone.acquire().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(995));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_over_time() {
+        let limiter = limiter(10, 2);
+        limiter.acquire().await;
+        limiter.acquire().await;
+
+        // The bucket is empty, wait long enough for it to be full again
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() < Duration::from_millis(5));
+
+        // It never holds more than the burst
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(95));
+    }
+}
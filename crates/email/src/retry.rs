@@ -0,0 +1,235 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Retry sending emails with an exponential backoff
+
+use std::{future::Future, num::NonZeroU32, time::Duration};
+
+use mas_config::EmailRetryConfig;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Defines how many times and for how long sending an email should be retried
+/// when it fails with a transient error
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: NonZeroU32,
+    initial_backoff: Duration,
+    max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::from(&EmailRetryConfig::default())
+    }
+}
+
+impl From<&EmailRetryConfig> for RetryPolicy {
+    fn from(config: &EmailRetryConfig) -> Self {
+        Self::new(
+            config.max_attempts,
+            config.initial_backoff,
+            config.max_elapsed,
+        )
+    }
+}
+
+impl RetryPolicy {
+    /// Constructs a new [`RetryPolicy`]
+    #[must_use]
+    pub fn new(max_attempts: NonZeroU32, initial_backoff: Duration, max_elapsed: Duration) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff,
+            max_elapsed,
+        }
+    }
+
+    /// Run the `operation` until it succeeds, fails with an error for which
+    /// `is_transient` returns `false`, or the policy limits are reached.
+    ///
+    /// The delay between two attempts starts at the initial backoff and
+    /// doubles each time.
+    pub(crate) async fn run<F, Fut, T, E>(
+        &self,
+        mut operation: F,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let start = Instant::now();
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+
+        loop {
+            let error = match operation().await {
+                Ok(ret) => return Ok(ret),
+                Err(e) => e,
+            };
+
+            let out_of_attempts = attempt >= self.max_attempts.get();
+            let out_of_time = start.elapsed() + backoff > self.max_elapsed;
+            if !is_transient(&error) || out_of_attempts || out_of_time {
+                return Err(error);
+            }
+
+            warn!(
+                %error,
+                attempt,
+                ?backoff,
+                "Failed to send email, retrying"
+            );
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use async_trait::async_trait;
+    use lettre::{address::Envelope, AsyncTransport, Message};
+
+    use super::*;
+
+    #[derive(Debug)]
+    enum MockError {
+        Transient,
+        Permanent,
+    }
+
+    impl std::fmt::Display for MockError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Transient => write!(f, "transient failure"),
+                Self::Permanent => write!(f, "permanent failure"),
+            }
+        }
+    }
+
+    impl MockError {
+        fn is_transient(&self) -> bool {
+            matches!(self, Self::Transient)
+        }
+    }
+
+    /// A transport which fails a given number of times before succeeding
+    struct MockTransport {
+        failures: u32,
+        error: fn() -> MockError,
+        attempts: AtomicU32,
+    }
+
+    impl MockTransport {
+        fn new(failures: u32, error: fn() -> MockError) -> Self {
+            Self {
+                failures,
+                error,
+                attempts: AtomicU32::new(0),
+            }
+        }
+
+        fn attempts(&self) -> u32 {
+            self.attempts.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl AsyncTransport for MockTransport {
+        type Ok = ();
+        type Error = MockError;
+
+        async fn send_raw(&self, _envelope: &Envelope, _email: &[u8]) -> Result<(), MockError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            if attempt < self.failures {
+                Err((self.error)())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .subject("Hello")
+            .body(String::from("Hello world"))
+            .unwrap()
+    }
+
+    async fn send(policy: &RetryPolicy, transport: &MockTransport) -> Result<(), MockError> {
+        let message = message();
+        let envelope = message.envelope();
+        let raw = message.formatted();
+        policy
+            .run(
+                || transport.send_raw(envelope, &raw),
+                MockError::is_transient,
+            )
+            .await
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(
+            NonZeroU32::new(max_attempts).unwrap(),
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_errors() {
+        let transport = MockTransport::new(2, || MockError::Transient);
+        send(&policy(5), &transport).await.unwrap();
+        assert_eq!(transport.attempts(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_errors_fail_fast() {
+        let transport = MockTransport::new(2, || MockError::Permanent);
+        let res = send(&policy(5), &transport).await;
+        assert!(matches!(res, Err(MockError::Permanent)));
+        assert_eq!(transport.attempts(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_are_capped() {
+        let transport = MockTransport::new(10, || MockError::Transient);
+        let res = send(&policy(3), &transport).await;
+        assert!(matches!(res, Err(MockError::Transient)));
+        assert_eq!(transport.attempts(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn elapsed_time_is_capped() {
+        // 100ms + 200ms + 400ms = 700ms, the next 800ms backoff would go over 1s
+        let policy = RetryPolicy::new(
+            NonZeroU32::new(10).unwrap(),
+            Duration::from_millis(100),
+            Duration::from_secs(1),
+        );
+        let transport = MockTransport::new(10, || MockError::Transient);
+        let res = send(&policy, &transport).await;
+        assert!(matches!(res, Err(MockError::Transient)));
+        assert_eq!(transport.attempts(), 4);
+    }
+}
@@ -14,14 +14,15 @@
 
 //! Email transport backends
 
-use std::sync::Arc;
+use std::{num::NonZeroU16, sync::Arc};
 
+use anyhow::bail;
 use async_trait::async_trait;
 use lettre::{
     address::Envelope,
     transport::{
         sendmail::AsyncSendmailTransport,
-        smtp::{authentication::Credentials, AsyncSmtpTransport},
+        smtp::{authentication::Credentials, AsyncSmtpTransport, Error as SmtpError},
     },
     AsyncTransport, Tokio1Executor,
 };
@@ -56,29 +57,19 @@ impl Transport {
                 hostname,
                 credentials,
                 port,
+                allow_insecure,
             } => {
-                let mut t = match mode {
-                    EmailSmtpMode::Plain => {
-                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(hostname)
-                    }
-                    EmailSmtpMode::StartTls => {
-                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(hostname)?
-                    }
-                    EmailSmtpMode::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(hostname)?,
-                };
-
-                if let Some(credentials) = credentials {
-                    t = t.credentials(Credentials::new(
-                        credentials.username.clone(),
-                        credentials.password.clone(),
-                    ));
-                }
-
-                if let Some(port) = port {
-                    t = t.port((*port).into());
-                }
-
-                TransportInner::Smtp(t.build())
+                let credentials = credentials.as_ref().map(|credentials| {
+                    Credentials::new(credentials.username.clone(), credentials.password.clone())
+                });
+
+                TransportInner::Smtp(smtp_transport(
+                    mode,
+                    hostname,
+                    *port,
+                    credentials,
+                    *allow_insecure,
+                )?)
             }
             EmailTransportConfig::Sendmail { command } => {
                 TransportInner::Sendmail(AsyncSendmailTransport::new_with_command(command))
@@ -92,6 +83,38 @@ impl Transport {
     }
 }
 
+/// Build an SMTP transport, refusing to send in plain text unless explicitly
+/// allowed
+fn smtp_transport(
+    mode: &EmailSmtpMode,
+    hostname: &str,
+    port: Option<NonZeroU16>,
+    credentials: Option<Credentials>,
+    allow_insecure: bool,
+) -> anyhow::Result<AsyncSmtpTransport<Tokio1Executor>> {
+    let mut t = match mode {
+        EmailSmtpMode::Plain => {
+            if !allow_insecure {
+                bail!("the plain SMTP mode is insecure, set allow_insecure to use it anyway");
+            }
+
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(hostname)
+        }
+        EmailSmtpMode::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(hostname)?,
+        EmailSmtpMode::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(hostname)?,
+    };
+
+    if let Some(credentials) = credentials {
+        t = t.credentials(credentials);
+    }
+
+    if let Some(port) = port {
+        t = t.port(port.into());
+    }
+
+    Ok(t.build())
+}
+
 impl Transport {
     /// Test the connection to the underlying transport. Only works with the
     /// SMTP backend for now
@@ -110,6 +133,23 @@ impl Transport {
 
         Ok(())
     }
+
+    /// Check if an error returned while sending an email is worth retrying
+    ///
+    /// Only SMTP errors are considered: transient (4xx) replies, timeouts and
+    /// connection failures are retryable, permanent (5xx) rejections are not.
+    pub(crate) fn is_transient(error: &anyhow::Error) -> bool {
+        if let Some(error) = error.downcast_ref::<SmtpError>() {
+            error.is_transient()
+                || error.is_timeout()
+                || !(error.is_permanent()
+                    || error.is_client()
+                    || error.is_response()
+                    || error.is_tls())
+        } else {
+            false
+        }
+    }
 }
 
 impl Default for TransportInner {
@@ -145,3 +185,40 @@ impl AsyncTransport for Transport {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smtp(mode: EmailSmtpMode, allow_insecure: bool) -> EmailTransportConfig {
+        EmailTransportConfig::Smtp {
+            mode,
+            hostname: "smtp.example.com".to_owned(),
+            port: None,
+            credentials: None,
+            allow_insecure,
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_mode_is_rejected_by_default() {
+        let res = Transport::from_config(&smtp(EmailSmtpMode::Plain, false)).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn plain_mode_can_be_allowed() {
+        let transport = Transport::from_config(&smtp(EmailSmtpMode::Plain, true))
+            .await
+            .unwrap();
+        assert!(matches!(transport.inner.as_ref(), TransportInner::Smtp(_)));
+    }
+
+    #[tokio::test]
+    async fn tls_modes_are_always_allowed() {
+        for mode in [EmailSmtpMode::StartTls, EmailSmtpMode::Tls] {
+            let transport = Transport::from_config(&smtp(mode, false)).await.unwrap();
+            assert!(matches!(transport.inner.as_ref(), TransportInner::Smtp(_)));
+        }
+    }
+}
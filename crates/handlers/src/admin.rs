@@ -0,0 +1,488 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Administration API, for the operators to manage the users
+//!
+//! It is authorized with an access token with the [`SCOPE`] scope, issued to
+//! one of the administrators listed in the [`AdminConfig`].
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{FromRequest, Path, Query, RequestParts},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{body::HttpBody, StatusCode};
+use mas_axum_utils::user_authorization::{AuthorizationVerificationError, UserAuthorization};
+use mas_config::AdminConfig;
+use mas_data_model::{Cursor, CursorError, Session, StorageBackend};
+use mas_storage::{
+    user::{
+        get_primary_emails_for_users, lookup_user_by_sub, reactivate_user, search_users,
+        soft_delete_user, user_id_from_sub, UserListItem,
+    },
+    Database, PostgresqlBackend,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    features::{Feature, FeatureFlags, FeatureState},
+    webhooks::{WebhookEvent, Webhooks},
+};
+
+/// The scope an access token needs to use the administration API
+pub(crate) const SCOPE: &str = "urn:mas:admin";
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("missing or invalid access token")]
+    Unauthorized,
+
+    #[error("access token is not allowed to use the administration API")]
+    Forbidden,
+
+    #[error("unknown user")]
+    UnknownUser,
+
+    #[error("unknown feature")]
+    UnknownFeature,
+
+    #[error(transparent)]
+    InvalidCursor(#[from] CursorError),
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<AuthorizationVerificationError> for RouteError {
+    fn from(e: AuthorizationVerificationError) -> Self {
+        match e {
+            AuthorizationVerificationError::MissingToken
+            | AuthorizationVerificationError::MissingForm
+            | AuthorizationVerificationError::InvalidToken => Self::Unauthorized,
+            AuthorizationVerificationError::InternalError(e) => Self::Internal(e),
+        }
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::UnknownUser | Self::UnknownFeature => StatusCode::NOT_FOUND,
+            Self::InvalidCursor(_) => StatusCode::BAD_REQUEST,
+            // The details of internal errors are only logged
+            Self::Internal(_) | Self::Anyhow(_) => {
+                error!(error = %self, "Administration API request failed");
+                return (StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+                    .into_response();
+            }
+        };
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+/// Check that the session has the admin scope, and belongs to an
+/// administrator
+fn authorize<T: StorageBackend>(
+    config: &AdminConfig,
+    session: &Session<T>,
+) -> Result<(), RouteError> {
+    if session.allows(SCOPE) && config.is_admin(&session.browser_session.user.username) {
+        Ok(())
+    } else {
+        Err(RouteError::Forbidden)
+    }
+}
+
+/// The OAuth 2.0 session of an administrator, authorized to use the
+/// administration API
+pub(crate) struct AdminSession(pub Session<PostgresqlBackend>);
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for AdminSession
+where
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Rejection = RouteError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(config) = Extension::<AdminConfig>::from_request(req)
+            .await
+            .map_err(|e| RouteError::Internal(Box::new(e)))?;
+
+        let Extension(pool) = Extension::<PgPool>::from_request(req)
+            .await
+            .map_err(|e| RouteError::Internal(Box::new(e)))?;
+
+        // The query parameters of GET requests are parsed as a form, and can
+        // hold anything besides the access token
+        let authorization = UserAuthorization::<HashMap<String, String>>::from_request(req)
+            .await
+            .map_err(|_| RouteError::Unauthorized)?;
+
+        let session = authorization.protected(&pool).await?;
+
+        authorize(&config, &session)?;
+
+        Ok(Self(session))
+    }
+}
+
+/// Deactivate a user: it can't log in anymore, and all its sessions end,
+/// which revokes the tokens issued to it
+pub(crate) async fn deactivate(
+    Extension(pool): Extension<PgPool>,
+    Extension(webhooks): Extension<Webhooks>,
+    AdminSession(admin): AdminSession,
+    Path(sub): Path<String>,
+) -> Result<StatusCode, RouteError> {
+    let mut txn = pool.begin().await?;
+
+    let user = lookup_user_by_sub(&mut txn, &sub).await.map_err(|e| {
+        if e.not_found() {
+            RouteError::UnknownUser
+        } else {
+            RouteError::Internal(Box::new(e))
+        }
+    })?;
+
+    soft_delete_user(&mut txn, &user).await?;
+    webhooks
+        .notify(&mut txn, &WebhookEvent::user_deactivated(&user))
+        .await?;
+
+    txn.commit().await?;
+    info!(admin = %admin.browser_session.user.username, %user.username, "User deactivated");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reactivate a deactivated user, so that it can log in again. The sessions
+/// ended by the deactivation stay ended
+pub(crate) async fn reactivate(
+    Extension(pool): Extension<PgPool>,
+    AdminSession(admin): AdminSession,
+    Path(sub): Path<String>,
+) -> Result<StatusCode, RouteError> {
+    let id = user_id_from_sub(&sub).ok_or(RouteError::UnknownUser)?;
+
+    if !reactivate_user(&pool, id).await? {
+        return Err(RouteError::UnknownUser);
+    }
+
+    info!(admin = %admin.browser_session.user.username, user.id = id, "User reactivated");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// How many users are listed when the request doesn't say
+const DEFAULT_LIMIT: usize = 50;
+
+/// How many users can be listed at once
+const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ListParams {
+    /// Only list the users whose username or email contains this
+    #[serde(default)]
+    query: Option<String>,
+
+    /// The cursor of the last user of the previous page
+    #[serde(default)]
+    after: Option<String>,
+
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+impl ListParams {
+    fn query(&self) -> Option<&str> {
+        self.query
+            .as_deref()
+            .map(str::trim)
+            .filter(|query| !query.is_empty())
+    }
+
+    fn after(&self) -> Result<Option<Cursor>, CursorError> {
+        self.after.as_deref().map(Cursor::decode).transpose()
+    }
+
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct UserResponse {
+    sub: String,
+    username: String,
+    primary_email: Option<String>,
+    deactivated: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListResponse {
+    users: Vec<UserResponse>,
+
+    /// The cursor to get the next page with, if there is one
+    next: Option<String>,
+}
+
+/// Split the users fetched for a page, one more than its `limit` to know
+/// whether there is a next one, into the page and the cursor to the next page
+fn paginate(mut users: Vec<UserListItem>, limit: usize) -> (Vec<UserListItem>, Option<Cursor>) {
+    if users.len() > limit {
+        users.truncate(limit);
+        let next = users.last().map(UserListItem::cursor);
+        (users, next)
+    } else {
+        (users, None)
+    }
+}
+
+/// List the users in the order they were created, deactivated ones included,
+/// optionally searching them by username or email
+pub(crate) async fn list(
+    Extension(database): Extension<Database>,
+    AdminSession(_admin): AdminSession,
+    Query(params): Query<ListParams>,
+) -> Result<Json<ListResponse>, RouteError> {
+    let after = params.after()?;
+    let limit = params.limit();
+
+    // Fetch one more user, to know whether there is a next page
+    let fetch = i64::try_from(limit + 1).unwrap_or(i64::MAX);
+    let fetched = search_users(database.read(), params.query(), after, fetch).await?;
+    let (page, next) = paginate(fetched, limit);
+
+    let users: Vec<_> = page.iter().map(|item| item.user.clone()).collect();
+    let emails = get_primary_emails_for_users(database.read(), &users).await?;
+
+    let users = page
+        .into_iter()
+        .zip(emails)
+        .map(|(item, email)| UserResponse {
+            sub: item.user.sub,
+            username: item.user.username,
+            primary_email: email.map(|email| email.email),
+            deactivated: item.deleted_at.is_some(),
+        })
+        .collect();
+
+    Ok(Json(ListResponse {
+        users,
+        next: next.map(|cursor| cursor.encode()),
+    }))
+}
+
+/// List the features, with whether they are enabled
+pub(crate) async fn list_features(
+    Extension(feature_flags): Extension<FeatureFlags>,
+    AdminSession(_admin): AdminSession,
+) -> Json<Vec<FeatureState>> {
+    Json(
+        Feature::ALL
+            .into_iter()
+            .map(|feature| feature_flags.state(feature))
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SetFeature {
+    /// Whether the feature is enabled, or `null` to go back to the
+    /// configuration
+    enabled: Option<bool>,
+}
+
+/// Enable or disable a feature until the server restarts
+pub(crate) async fn set_feature(
+    Extension(feature_flags): Extension<FeatureFlags>,
+    AdminSession(admin): AdminSession,
+    Path(name): Path<String>,
+    Json(body): Json<SetFeature>,
+) -> Result<Json<FeatureState>, RouteError> {
+    let feature: Feature = name.parse().map_err(|_| RouteError::UnknownFeature)?;
+
+    feature_flags.set_override(feature, body.enabled);
+    info!(
+        admin = %admin.browser_session.user.username,
+        feature = feature.name(),
+        enabled = ?body.enabled,
+        "Feature overridden"
+    );
+
+    Ok(Json(feature_flags.state(feature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use hyper::{Body, Request};
+    use mas_data_model::User;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    use super::*;
+
+    fn session(scope: &str) -> Session<()> {
+        let mut session = Session::<()>::samples().remove(0);
+        session.scope = scope.parse().unwrap();
+        session
+    }
+
+    fn config(users: &[&str]) -> AdminConfig {
+        AdminConfig {
+            users: users.iter().map(|&user| user.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn admins_with_the_scope_are_authorized() {
+        let session = session("openid urn:mas:admin");
+        assert_eq!(session.browser_session.user.username, "john");
+
+        assert!(authorize(&config(&["john"]), &session).is_ok());
+    }
+
+    #[test]
+    fn other_users_are_forbidden() {
+        let session = session("openid urn:mas:admin");
+        let err = authorize(&config(&["alice"]), &session).unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+
+        // Without any administrator, the API is disabled
+        assert!(authorize(&config(&[]), &session).is_err());
+    }
+
+    #[test]
+    fn tokens_without_the_scope_are_forbidden() {
+        let err = authorize(&config(&["john"]), &session("openid email")).unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_token_are_unauthorized() {
+        // The token is checked before querying the database, so this pool
+        // never connects
+        let pool = PgPoolOptions::new().connect_lazy_with(PgConnectOptions::new().host("nowhere"));
+        let request = Request::builder()
+            .uri("/admin/users?query=alice")
+            .extension(config(&["john"]))
+            .extension(pool)
+            .body(Body::empty())
+            .unwrap();
+
+        let err = AdminSession::from_request(&mut RequestParts::new(request))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn item(id: i64) -> UserListItem {
+        UserListItem {
+            user: User {
+                data: id,
+                username: format!("user{}", id),
+                sub: format!("fake-sub-{}", id),
+                primary_email: None,
+            },
+            created_at: Utc.timestamp(1_600_000_000 + id, 0),
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn pagination() {
+        let (page, next) = paginate((1..=3).map(item).collect(), 2);
+        assert_eq!(page.len(), 2);
+        assert_eq!(next, Some(Cursor::new(Utc.timestamp(1_600_000_002, 0), 2)));
+
+        // The last page has no cursor
+        let (page, next) = paginate((1..=2).map(item).collect(), 2);
+        assert_eq!(page.len(), 2);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn list_params() {
+        let params = ListParams::default();
+        assert_eq!(params.query(), None);
+        assert_eq!(params.after(), Ok(None));
+        assert_eq!(params.limit(), DEFAULT_LIMIT);
+
+        let cursor = Cursor::new(Utc.timestamp(1_600_000_000, 0), 42);
+        let params = ListParams {
+            query: Some(" alice ".to_owned()),
+            after: Some(cursor.encode()),
+            limit: Some(1000),
+        };
+        assert_eq!(params.query(), Some("alice"));
+        assert_eq!(params.after(), Ok(Some(cursor)));
+        assert_eq!(params.limit(), MAX_LIMIT);
+
+        // Empty searches list everyone, and broken cursors are rejected
+        let params = ListParams {
+            query: Some(String::new()),
+            after: Some("not a cursor".to_owned()),
+            limit: Some(0),
+        };
+        assert_eq!(params.query(), None);
+        assert!(params.after().is_err());
+        assert_eq!(params.limit(), 1);
+    }
+
+    #[test]
+    fn invalid_tokens_are_unauthorized() {
+        for e in [
+            AuthorizationVerificationError::MissingToken,
+            AuthorizationVerificationError::InvalidToken,
+        ] {
+            let err = RouteError::from(e);
+            assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_not_leaked() {
+        let err = RouteError::from(anyhow::anyhow!("connection to 10.0.0.3 refused"));
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"internal server error");
+
+        // The client errors keep their message
+        let response = RouteError::UnknownUser.into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"unknown user");
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Challenges (CAPTCHAs) users have to solve to deter automated abuse
+
+use std::{net::IpAddr, sync::Arc};
+
+use async_trait::async_trait;
+use mas_config::{ChallengeConfig, ChallengeServiceConfig};
+use mas_http::HttpServiceExt;
+use mas_templates::ChallengeWidget;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tower::ServiceExt;
+
+/// The challenge provider configured, if any
+pub(crate) type Challenge = Option<Arc<dyn ChallengeProvider>>;
+
+#[derive(Debug, Error)]
+pub enum ChallengeError {
+    #[error("no challenge response was provided")]
+    Missing,
+
+    #[error("the challenge response was rejected")]
+    Rejected,
+
+    #[error("could not verify the challenge response")]
+    Verification(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+/// A service verifying that users solved a challenge
+#[async_trait]
+pub trait ChallengeProvider: Send + Sync {
+    /// The widget to show in forms for users to solve the challenge
+    fn widget(&self) -> ChallengeWidget;
+
+    /// Sources the widget loads scripts, styles and frames from, to allow in
+    /// the Content Security Policy of the pages
+    fn content_sources(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Verify the response to a challenge, sent by the user from the given
+    /// address
+    async fn verify(&self, response: &str, remote_ip: Option<IpAddr>)
+        -> Result<(), ChallengeError>;
+}
+
+/// The response to a challenge in a form. Each widget sends it under its own
+/// name, and some send it under several names for compatibility
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChallengeForm {
+    #[serde(default, rename = "h-captcha-response", skip_serializing)]
+    hcaptcha: Option<String>,
+
+    #[serde(default, rename = "cf-turnstile-response", skip_serializing)]
+    turnstile: Option<String>,
+
+    #[serde(default, rename = "g-recaptcha-response", skip_serializing)]
+    recaptcha: Option<String>,
+}
+
+impl ChallengeForm {
+    pub(crate) fn response(&self) -> Option<&str> {
+        self.hcaptcha
+            .as_deref()
+            .or(self.turnstile.as_deref())
+            .or(self.recaptcha.as_deref())
+    }
+}
+
+/// Verify the response to a challenge, if challenges are enabled
+pub(crate) async fn verify_challenge(
+    challenge: &Challenge,
+    response: Option<&str>,
+    remote_ip: Option<IpAddr>,
+) -> Result<(), ChallengeError> {
+    let provider = match challenge {
+        Some(provider) => provider,
+        None => return Ok(()),
+    };
+
+    let response = response
+        .filter(|response| !response.is_empty())
+        .ok_or(ChallengeError::Missing)?;
+
+    provider.verify(response, remote_ip).await
+}
+
+/// Build the challenge provider for the password logins through the Matrix
+/// compatibility layer. Matrix clients can't show the widget, so this is only
+/// enabled if the config asks for it
+pub(crate) fn compat_from_config(config: &ChallengeConfig) -> Challenge {
+    if config.compat_login {
+        from_config(config)
+    } else {
+        None
+    }
+}
+
+/// The widget to show in forms, if challenges are enabled
+pub(crate) fn challenge_widget(challenge: &Challenge) -> Option<ChallengeWidget> {
+    challenge.as_ref().map(|provider| provider.widget())
+}
+
+/// Build the challenge provider from the config
+pub(crate) fn from_config(config: &ChallengeConfig) -> Challenge {
+    let provider = match &config.service {
+        ChallengeServiceConfig::None => return None,
+        ChallengeServiceConfig::HCaptcha {
+            site_key,
+            secret_key,
+        } => SiteVerify {
+            verify_url: "https://api.hcaptcha.com/siteverify",
+            script: "https://js.hcaptcha.com/1/api.js",
+            class: "h-captcha",
+            sources: &["https://hcaptcha.com", "https://*.hcaptcha.com"],
+            site_key: site_key.clone(),
+            secret_key: secret_key.clone(),
+        },
+        ChallengeServiceConfig::ReCaptcha {
+            site_key,
+            secret_key,
+        } => SiteVerify {
+            verify_url: "https://www.google.com/recaptcha/api/siteverify",
+            script: "https://www.google.com/recaptcha/api.js",
+            class: "g-recaptcha",
+            sources: &[
+                "https://www.google.com/recaptcha/",
+                "https://www.gstatic.com/recaptcha/",
+            ],
+            site_key: site_key.clone(),
+            secret_key: secret_key.clone(),
+        },
+        ChallengeServiceConfig::Turnstile {
+            site_key,
+            secret_key,
+        } => SiteVerify {
+            verify_url: "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            script: "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            class: "cf-turnstile",
+            sources: &["https://challenges.cloudflare.com"],
+            site_key: site_key.clone(),
+            secret_key: secret_key.clone(),
+        },
+    };
+
+    Some(Arc::new(provider))
+}
+
+/// Verifies challenges with a `siteverify` endpoint, which hCaptcha, reCAPTCHA
+/// and Turnstile all implement the same way
+struct SiteVerify {
+    verify_url: &'static str,
+    script: &'static str,
+    class: &'static str,
+    sources: &'static [&'static str],
+    site_key: String,
+    secret_key: String,
+}
+
+#[derive(Serialize)]
+struct SiteVerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remoteip: Option<IpAddr>,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+
+    #[serde(default, rename = "error-codes")]
+    error_codes: Vec<String>,
+}
+
+impl SiteVerifyResponse {
+    fn into_result(self) -> Result<(), ChallengeError> {
+        if self.success {
+            Ok(())
+        } else {
+            tracing::info!(error_codes = ?self.error_codes, "Challenge response rejected");
+            Err(ChallengeError::Rejected)
+        }
+    }
+}
+
+#[async_trait]
+impl ChallengeProvider for SiteVerify {
+    fn widget(&self) -> ChallengeWidget {
+        ChallengeWidget::new(self.script, self.class, self.site_key.clone())
+    }
+
+    fn content_sources(&self) -> &'static [&'static str] {
+        self.sources
+    }
+
+    async fn verify(
+        &self,
+        response: &str,
+        remote_ip: Option<IpAddr>,
+    ) -> Result<(), ChallengeError> {
+        let body = serde_urlencoded::to_string(SiteVerifyRequest {
+            secret: &self.secret_key,
+            response,
+            remoteip: remote_ip,
+        })
+        .map_err(|e| ChallengeError::Verification(Box::new(e)))?;
+
+        let request = hyper::Request::builder()
+            .method("POST")
+            .uri(self.verify_url)
+            .header(
+                hyper::header::CONTENT_TYPE,
+                mime::APPLICATION_WWW_FORM_URLENCODED.as_ref(),
+            )
+            .body(hyper::Body::from(body))
+            .map_err(|e| ChallengeError::Verification(Box::new(e)))?;
+
+        let response = mas_http::client("challenge-verify")
+            .json::<SiteVerifyResponse>()
+            .oneshot(request)
+            .await
+            .map_err(|e| ChallengeError::Verification(Box::new(e)))?;
+
+        response.into_body().into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts a single response
+    struct MockProvider {
+        valid_response: &'static str,
+    }
+
+    #[async_trait]
+    impl ChallengeProvider for MockProvider {
+        fn widget(&self) -> ChallengeWidget {
+            ChallengeWidget::new("https://example.com/api.js", "mock-challenge", "site-key")
+        }
+
+        async fn verify(
+            &self,
+            response: &str,
+            _remote_ip: Option<IpAddr>,
+        ) -> Result<(), ChallengeError> {
+            if response == self.valid_response {
+                Ok(())
+            } else {
+                Err(ChallengeError::Rejected)
+            }
+        }
+    }
+
+    #[allow(clippy::unnecessary_wraps)]
+    fn mock() -> Challenge {
+        Some(Arc::new(MockProvider {
+            valid_response: "solved",
+        }))
+    }
+
+    #[tokio::test]
+    async fn valid_response_passes() {
+        verify_challenge(&mock(), Some("solved"), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_response_fails() {
+        let res = verify_challenge(&mock(), Some("wrong"), None).await;
+        assert!(matches!(res, Err(ChallengeError::Rejected)));
+
+        let res = verify_challenge(&mock(), Some(""), None).await;
+        assert!(matches!(res, Err(ChallengeError::Missing)));
+
+        let res = verify_challenge(&mock(), None, None).await;
+        assert!(matches!(res, Err(ChallengeError::Missing)));
+    }
+
+    #[tokio::test]
+    async fn disabled_challenge_always_passes() {
+        verify_challenge(&None, None, None).await.unwrap();
+        assert!(challenge_widget(&None).is_none());
+        assert!(from_config(&ChallengeConfig::default()).is_none());
+    }
+
+    #[test]
+    fn compat_login_challenge_is_opt_in() {
+        let mut config = ChallengeConfig {
+            service: ChallengeServiceConfig::HCaptcha {
+                site_key: "site-key".to_owned(),
+                secret_key: "secret-key".to_owned(),
+            },
+            compat_login: false,
+        };
+        assert!(from_config(&config).is_some());
+        assert!(compat_from_config(&config).is_none());
+
+        config.compat_login = true;
+        assert!(compat_from_config(&config).is_some());
+    }
+
+    #[test]
+    fn challenge_form_response() {
+        let form: ChallengeForm = serde_urlencoded::from_str(
+            "h-captcha-response=from-hcaptcha&g-recaptcha-response=from-hcaptcha",
+        )
+        .unwrap();
+        assert_eq!(form.response(), Some("from-hcaptcha"));
+
+        let form: ChallengeForm =
+            serde_urlencoded::from_str("cf-turnstile-response=from-turnstile").unwrap();
+        assert_eq!(form.response(), Some("from-turnstile"));
+
+        let form: ChallengeForm = serde_urlencoded::from_str("").unwrap();
+        assert_eq!(form.response(), None);
+    }
+
+    #[test]
+    fn siteverify_response() {
+        let response: SiteVerifyResponse =
+            serde_json::from_str(r#"{"success": true, "hostname": "example.com"}"#).unwrap();
+        assert!(response.into_result().is_ok());
+
+        let response: SiteVerifyResponse = serde_json::from_str(
+            r#"{"success": false, "error-codes": ["invalid-input-response"]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            response.into_result(),
+            Err(ChallengeError::Rejected)
+        ));
+    }
+}
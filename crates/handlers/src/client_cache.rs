@@ -0,0 +1,363 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory cache of the clients, in front of their lookups by client ID
+//!
+//! The database notifies the changes to the clients on the
+//! [`CLIENTS_CHANNEL`], so that every instance evicts them. Entries also
+//! expire after a while, in case a notification is missed.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mas_config::ClientCacheConfig;
+use mas_data_model::Client;
+use mas_storage::{
+    oauth2::client::{lookup_client_by_client_id, ClientFetchError, CLIENTS_CHANNEL},
+    PostgresqlBackend,
+};
+use sqlx::{postgres::PgListener, PgExecutor, PgPool};
+use tracing::{debug, error, warn};
+
+/// How long to wait before listening again after an error
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Entry {
+    client: Client<PostgresqlBackend>,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+
+    /// Incremented on each use of an entry, to find the least recently used
+    tick: u64,
+}
+
+/// A bounded LRU cache of the clients, by client ID.
+///
+/// Clones share the same entries.
+#[derive(Debug, Clone)]
+pub struct ClientCache {
+    entries: Arc<Mutex<Entries>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl ClientCache {
+    /// Create a cache holding at most `capacity` clients, each for `ttl`. A
+    /// capacity of `0` disables the cache
+    #[must_use]
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Arc::default(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Create the cache from the configuration
+    #[must_use]
+    pub fn from_config(config: &ClientCacheConfig) -> Self {
+        Self::new(config.capacity, config.ttl)
+    }
+
+    /// Whether clients are cached at all
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Lookup a client by its client ID, from the cache or else from the
+    /// database
+    pub(crate) async fn lookup(
+        &self,
+        executor: impl PgExecutor<'_>,
+        client_id: &str,
+    ) -> Result<Client<PostgresqlBackend>, ClientFetchError> {
+        if let Some(client) = self.get(client_id, Instant::now()) {
+            crate::metrics::client_cache("hit");
+            return Ok(client);
+        }
+
+        crate::metrics::client_cache("miss");
+        let client = lookup_client_by_client_id(executor, client_id).await?;
+        self.insert(client.clone(), Instant::now());
+        Ok(client)
+    }
+
+    fn get(&self, client_id: &str, now: Instant) -> Option<Client<PostgresqlBackend>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = &mut *entries;
+
+        let expired = match entries.entries.get_mut(client_id) {
+            Some(entry) if now.duration_since(entry.inserted_at) < self.ttl => {
+                entries.tick += 1;
+                entry.last_used = entries.tick;
+                return Some(entry.client.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        if expired {
+            entries.entries.remove(client_id);
+        }
+
+        None
+    }
+
+    fn insert(&self, client: Client<PostgresqlBackend>, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.entries.len() >= self.capacity
+            && !entries.entries.contains_key(&client.client_id)
+        {
+            let least_recently_used = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(client_id, _)| client_id.clone());
+            if let Some(client_id) = least_recently_used {
+                entries.entries.remove(&client_id);
+            }
+        }
+
+        entries.tick += 1;
+        let entry = Entry {
+            inserted_at: now,
+            last_used: entries.tick,
+            client,
+        };
+        entries
+            .entries
+            .insert(entry.client.client_id.clone(), entry);
+    }
+
+    /// Remove a client from the cache
+    fn evict(&self, client_id: &str) {
+        self.entries.lock().unwrap().entries.remove(client_id);
+    }
+
+    /// Remove all the clients from the cache
+    fn clear(&self) {
+        self.entries.lock().unwrap().entries.clear();
+    }
+
+    /// Handle a notification from the [`CLIENTS_CHANNEL`]
+    fn notified(&self, payload: &str) {
+        if payload.is_empty() {
+            debug!("All clients changed, clearing the cache");
+            self.clear();
+        } else {
+            debug!(client.id = payload, "Client changed, evicting it");
+            self.evict(payload);
+        }
+    }
+
+    /// Evict the clients as the database notifies their changes. This runs
+    /// forever, and should be spawned in the background.
+    ///
+    /// The whole cache is cleared whenever the connection is lost, as
+    /// notifications could have been missed.
+    pub async fn listen_for_changes(self, pool: PgPool) {
+        loop {
+            let mut listener = match PgListener::connect_with(&pool).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(error = %e, "Could not connect to listen for client changes");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            if let Err(e) = listener.listen(CLIENTS_CHANNEL).await {
+                error!(error = %e, "Could not listen for client changes");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+
+            // Changes made before listening could have been missed
+            self.clear();
+
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => self.notified(notification.payload()),
+                    Ok(None) => {
+                        // The listener connects again on the next call
+                        warn!("Lost the connection listening for client changes");
+                        self.clear();
+                    }
+                    Err(e) => {
+                        error!(error = %e, "Could not receive client changes");
+                        self.clear();
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(client_id: &str) -> Client<PostgresqlBackend> {
+        let mut client = Client::<PostgresqlBackend>::samples()[0].clone();
+        client.client_id = client_id.to_owned();
+        client
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = ClientCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(cache.get("first", now), None);
+
+        cache.insert(client("first"), now);
+        assert_eq!(cache.get("first", now), Some(client("first")));
+        assert_eq!(cache.get("second", now), None);
+
+        cache.evict("first");
+        assert_eq!(cache.get("first", now), None);
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = ClientCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert(client("first"), now);
+
+        // Using an entry doesn't extend its lifetime
+        let later = now + Duration::from_secs(59);
+        assert!(cache.get("first", later).is_some());
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(cache.get("first", later), None);
+        assert!(cache.entries.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = ClientCache::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert(client("first"), now);
+        cache.insert(client("second"), now);
+
+        // Use the first one, so that the second one is the least recently used
+        assert!(cache.get("first", now).is_some());
+        cache.insert(client("third"), now);
+
+        assert!(cache.get("first", now).is_some());
+        assert_eq!(cache.get("second", now), None);
+        assert!(cache.get("third", now).is_some());
+
+        // Replacing an entry doesn't evict another one
+        cache.insert(client("third"), now);
+        assert!(cache.get("first", now).is_some());
+    }
+
+    #[test]
+    fn disabled_cache() {
+        let cache = ClientCache::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert(client("first"), now);
+        assert_eq!(cache.get("first", now), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let cache = ClientCache::new(10, Duration::from_secs(60));
+        let now = Instant::now();
+        cache.insert(client("first"), now);
+        cache.insert(client("second"), now);
+
+        cache.notified("first");
+        assert_eq!(cache.get("first", now), None);
+        assert!(cache.get("second", now).is_some());
+
+        // An empty payload means all the clients changed
+        cache.insert(client("first"), now);
+        cache.notified("");
+        assert_eq!(cache.get("first", now), None);
+        assert_eq!(cache.get("second", now), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn writes_evict_the_client() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPool::connect(&url).await.unwrap();
+        let client_id = "client-cache-test";
+
+        sqlx::query(
+            r#"
+                INSERT INTO oauth2_clients
+                    (client_id, response_types, grant_type_authorization_code,
+                     grant_type_refresh_token, contacts)
+                VALUES ($1, '{}', FALSE, FALSE, '{}')
+            "#,
+        )
+        .bind(client_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let cache = ClientCache::new(10, Duration::from_secs(60));
+        cache.insert(client("unrelated"), Instant::now());
+        tokio::spawn(cache.clone().listen_for_changes(pool.clone()));
+
+        // The cache is cleared once the listener is ready
+        let wait = |client_id: &'static str| {
+            let cache = cache.clone();
+            tokio::time::timeout(Duration::from_secs(5), async move {
+                while cache.get(client_id, Instant::now()).is_some() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        wait("unrelated").await.unwrap();
+
+        cache.insert(client(client_id), Instant::now());
+        cache.insert(client("unrelated"), Instant::now());
+        sqlx::query("UPDATE oauth2_clients SET client_name = 'Cached' WHERE client_id = $1")
+            .bind(client_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let evicted = wait(client_id).await;
+
+        sqlx::query("DELETE FROM oauth2_clients WHERE client_id = $1")
+            .bind(client_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(evicted.is_ok());
+        assert!(cache.get("unrelated", Instant::now()).is_some());
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Replay the response of requests retried with the same `Idempotency-Key`
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use headers::HeaderName;
+use sha2::{Digest, Sha256};
+use tokio::{sync::OnceCell, time::Instant};
+
+/// Header clients set to make retrying a request safe
+pub(crate) static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// How long a response is replayed to retried requests
+const TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct IdempotencyKey {
+    key: String,
+    username: String,
+    fingerprint: [u8; 32],
+}
+
+impl IdempotencyKey {
+    /// Build the key of a request. The fingerprint of the request body makes
+    /// sure a retry sends the same credentials as the first request before it
+    /// gets its response
+    pub(crate) fn new(key: String, username: String, body: &[u8]) -> Self {
+        Self {
+            key,
+            username,
+            fingerprint: Sha256::digest(body).into(),
+        }
+    }
+}
+
+struct Entry<T> {
+    created_at: Instant,
+    response: Arc<OnceCell<T>>,
+}
+
+/// Remembers successful responses by [`IdempotencyKey`] for a short time, so
+/// that a client retrying a request gets the same response instead of doing
+/// the work twice
+pub(crate) struct IdempotencyCache<T> {
+    entries: Arc<Mutex<HashMap<IdempotencyKey, Entry<T>>>>,
+}
+
+impl<T> Clone for IdempotencyCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<T> Default for IdempotencyCache<T> {
+    fn default() -> Self {
+        Self {
+            entries: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+    /// Get the response of a previous request with the same key, or compute
+    /// it. Concurrent requests with the same key wait for the first one.
+    /// Errors are not remembered, so a failed request can be retried
+    pub(crate) async fn get_or_try_init<E, F, Fut>(
+        &self,
+        key: IdempotencyKey,
+        init: F,
+    ) -> Result<T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let response = {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            entries.retain(|_, entry| now.duration_since(entry.created_at) < TTL);
+            entries
+                .entry(key)
+                .or_insert_with(|| Entry {
+                    created_at: now,
+                    response: Arc::default(),
+                })
+                .response
+                .clone()
+        };
+
+        response.get_or_try_init(init).await.cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    /// Stands for a login, starting a new session each time it is called
+    async fn login(sessions: &AtomicU32) -> Result<u32, ()> {
+        Ok(sessions.fetch_add(1, Ordering::SeqCst))
+    }
+
+    fn key(key: &str) -> IdempotencyKey {
+        IdempotencyKey::new(
+            key.to_owned(),
+            "alice".to_owned(),
+            b"{\"password\":\"hunter2\"}",
+        )
+    }
+
+    #[tokio::test]
+    async fn same_key_replays_the_response() {
+        let cache = IdempotencyCache::default();
+        let sessions = AtomicU32::new(0);
+
+        let first = cache.get_or_try_init(key("abc"), || login(&sessions)).await;
+        let second = cache.get_or_try_init(key("abc"), || login(&sessions)).await;
+
+        assert_eq!(first, second);
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn different_keys_start_distinct_sessions() {
+        let cache = IdempotencyCache::default();
+        let sessions = AtomicU32::new(0);
+
+        let first = cache.get_or_try_init(key("abc"), || login(&sessions)).await;
+        let second = cache.get_or_try_init(key("def"), || login(&sessions)).await;
+        assert_ne!(first, second);
+
+        // The same key for another user or with other credentials is a
+        // different request
+        let other_user = IdempotencyKey::new(
+            "abc".to_owned(),
+            "bob".to_owned(),
+            b"{\"password\":\"hunter2\"}",
+        );
+        let other_password = IdempotencyKey::new(
+            "abc".to_owned(),
+            "alice".to_owned(),
+            b"{\"password\":\"hunter3\"}",
+        );
+        let third = cache.get_or_try_init(other_user, || login(&sessions)).await;
+        let fourth = cache
+            .get_or_try_init(other_password, || login(&sessions))
+            .await;
+        assert_ne!(first, third);
+        assert_ne!(first, fourth);
+
+        assert_eq!(sessions.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn errors_are_not_remembered() {
+        let cache = IdempotencyCache::default();
+
+        let first: Result<u32, ()> = cache
+            .get_or_try_init(key("abc"), || async { Err(()) })
+            .await;
+        let second: Result<u32, ()> = cache.get_or_try_init(key("abc"), || async { Ok(1) }).await;
+
+        assert_eq!(first, Err(()));
+        assert_eq!(second, Ok(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn responses_expire() {
+        let cache = IdempotencyCache::default();
+        let sessions = AtomicU32::new(0);
+
+        let first = cache.get_or_try_init(key("abc"), || login(&sessions)).await;
+        tokio::time::advance(TTL).await;
+        let second = cache.get_or_try_init(key("abc"), || login(&sessions)).await;
+
+        assert_ne!(first, second);
+    }
+}
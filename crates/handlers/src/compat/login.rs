@@ -12,16 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{response::IntoResponse, Extension, Json};
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{extract::ConnectInfo, response::IntoResponse, Extension, Json};
 use chrono::{Duration, Utc};
-use hyper::StatusCode;
-use mas_config::MatrixConfig;
+use hyper::{header::RETRY_AFTER, HeaderMap, StatusCode};
+use mas_config::{EmailConfig, Encrypter, MatrixConfig};
 use mas_data_model::{CompatSession, CompatSsoLoginState, Device, TokenType};
 use mas_storage::{
     compat::{
         add_compat_access_token, add_compat_refresh_token, compat_login,
         get_compat_sso_login_by_token, mark_compat_sso_login_as_exchanged,
-        CompatSsoLoginLookupError,
+        CompatSsoLoginLookupError, SecondFactorError,
     },
     PostgresqlBackend,
 };
@@ -31,7 +36,16 @@ use serde_with::{serde_as, skip_serializing_none, DurationMilliSeconds};
 use sqlx::{PgPool, Postgres, Transaction};
 use thiserror::Error;
 
-use super::MatrixError;
+use super::{
+    idempotency::{IdempotencyCache, IdempotencyKey, IDEMPOTENCY_KEY},
+    LimitedJson, MatrixError,
+};
+use crate::{
+    challenge::{verify_challenge, Challenge, ChallengeError},
+    features::FeatureFlags,
+    upstream_oauth2::UpstreamProviders,
+    views::account::totp::decrypt_totp_secret,
+};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -61,8 +75,8 @@ enum LoginType {
 
 #[derive(Debug, Serialize)]
 struct SsoIdentityProvider {
-    id: &'static str,
-    name: &'static str,
+    id: String,
+    name: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,21 +84,40 @@ struct LoginTypes {
     flows: Vec<LoginType>,
 }
 
-pub(crate) async fn get() -> impl IntoResponse {
-    let res = LoginTypes {
-        flows: vec![
-            LoginType::Password {
-                actions: vec![Action::Login],
-            },
-            LoginType::Sso {
-                identity_providers: vec![],
-                actions: vec![Action::Login, Action::Register],
-            },
-            LoginType::Token,
-        ],
-    };
+/// The login flows advertised to the clients, with the upstream providers
+/// they can redirect the users to
+fn login_types(feature_flags: &FeatureFlags, upstream: &UpstreamProviders) -> LoginTypes {
+    let mut flows = Vec::new();
 
-    Json(res)
+    if feature_flags.password_login() {
+        flows.push(LoginType::Password {
+            actions: vec![Action::Login],
+        });
+    }
+
+    let identity_providers = upstream
+        .iter()
+        .filter(|_| feature_flags.upstream_login())
+        .map(|provider| SsoIdentityProvider {
+            id: provider.id().to_owned(),
+            name: provider.name().to_owned(),
+        })
+        .collect();
+
+    flows.push(LoginType::Sso {
+        identity_providers,
+        actions: vec![Action::Login, Action::Register],
+    });
+    flows.push(LoginType::Token);
+
+    LoginTypes { flows }
+}
+
+pub(crate) async fn get(
+    Extension(feature_flags): Extension<FeatureFlags>,
+    Extension(upstream): Extension<UpstreamProviders>,
+) -> impl IntoResponse {
+    Json(login_types(&feature_flags, &upstream))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -94,15 +127,25 @@ pub struct RequestBody {
 
     #[serde(default)]
     refresh_token: bool,
+
+    /// Response to the challenge, required for password logins when
+    /// challenges are enabled for the compatibility layer
+    #[serde(default)]
+    challenge_response: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Credentials {
     #[serde(rename = "m.login.password")]
     Password {
         identifier: Identifier,
         password: String,
+
+        /// Code from the authenticator app, for users who enabled TOTP. Clients
+        /// first try without it, and retry with it if the server asks for one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        totp_code: Option<String>,
     },
 
     #[serde(rename = "m.login.token")]
@@ -112,6 +155,29 @@ pub enum Credentials {
     Unsupported,
 }
 
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The password and the login token must never end up in the logs
+        match self {
+            Self::Password {
+                identifier,
+                totp_code,
+                ..
+            } => f
+                .debug_struct("Password")
+                .field("identifier", identifier)
+                .field("password", &format_args!("[redacted]"))
+                .field("totp_code", &totp_code.as_ref().map(|_| "[redacted]"))
+                .finish(),
+            Self::Token { .. } => f
+                .debug_struct("Token")
+                .field("token", &format_args!("[redacted]"))
+                .finish(),
+            Self::Unsupported => f.write_str("Unsupported"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Identifier {
@@ -124,7 +190,7 @@ pub enum Identifier {
 
 #[skip_serializing_none]
 #[serde_as]
-#[derive(Debug, Serialize)]
+#[derive(Clone, Serialize)]
 pub struct ResponseBody {
     access_token: String,
     device_id: Device,
@@ -134,6 +200,24 @@ pub struct ResponseBody {
     expires_in_ms: Option<Duration>,
 }
 
+impl std::fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseBody")
+            .field("access_token", &format_args!("[redacted]"))
+            .field("device_id", &self.device_id)
+            .field("user_id", &self.user_id)
+            .field(
+                "refresh_token",
+                &self
+                    .refresh_token
+                    .as_ref()
+                    .map(|_| format_args!("[redacted]")),
+            )
+            .field("expires_in_ms", &self.expires_in_ms)
+            .finish()
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum RouteError {
     #[error(transparent)]
@@ -153,6 +237,21 @@ pub enum RouteError {
 
     #[error("invalid login token")]
     InvalidLoginToken,
+
+    #[error("no database connection available")]
+    Unavailable,
+
+    #[error("challenge failed")]
+    ChallengeFailed,
+
+    #[error("a TOTP code is required")]
+    TotpRequired,
+
+    #[error("the user has to verify an email address first")]
+    EmailNotVerified,
+
+    #[error("password logins are disabled")]
+    PasswordLoginDisabled,
 }
 
 impl From<sqlx::Error> for RouteError {
@@ -161,6 +260,15 @@ impl From<sqlx::Error> for RouteError {
     }
 }
 
+impl From<ChallengeError> for RouteError {
+    fn from(e: ChallengeError) -> Self {
+        match e {
+            ChallengeError::Missing | ChallengeError::Rejected => Self::ChallengeFailed,
+            ChallengeError::Verification(e) => Self::Internal(e),
+        }
+    }
+}
+
 impl From<CompatSsoLoginLookupError> for RouteError {
     fn from(e: CompatSsoLoginLookupError) -> Self {
         if e.not_found() {
@@ -171,51 +279,226 @@ impl From<CompatSsoLoginLookupError> for RouteError {
     }
 }
 
+/// How long clients should wait before retrying when the service is
+/// overloaded, in seconds
+const RETRY_AFTER_SECONDS: &str = "5";
+
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         match self {
+            Self::Unavailable => {
+                let error = MatrixError {
+                    errcode: "M_UNKNOWN",
+                    error: "Service temporarily unavailable",
+                    status: StatusCode::SERVICE_UNAVAILABLE,
+                    soft_logout: false,
+                };
+                return ([(RETRY_AFTER, RETRY_AFTER_SECONDS)], error).into_response();
+            }
             Self::Internal(_) | Self::Anyhow(_) => MatrixError {
                 errcode: "M_UNKNOWN",
                 error: "Internal server error",
                 status: StatusCode::INTERNAL_SERVER_ERROR,
+                soft_logout: false,
             },
             Self::Unsupported => MatrixError {
                 errcode: "M_UNRECOGNIZED",
                 error: "Invalid login type",
                 status: StatusCode::BAD_REQUEST,
+                soft_logout: false,
             },
             Self::LoginFailed => MatrixError {
                 errcode: "M_UNAUTHORIZED",
                 error: "Invalid username/password",
                 status: StatusCode::FORBIDDEN,
+                soft_logout: false,
             },
             Self::LoginTookTooLong => MatrixError {
                 errcode: "M_UNAUTHORIZED",
                 error: "Login token expired",
                 status: StatusCode::FORBIDDEN,
+                soft_logout: false,
             },
             Self::InvalidLoginToken => MatrixError {
                 errcode: "M_UNAUTHORIZED",
                 error: "Invalid login token",
                 status: StatusCode::FORBIDDEN,
+                soft_logout: false,
+            },
+            Self::ChallengeFailed => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "A valid challenge response is required to log in with a password",
+                status: StatusCode::FORBIDDEN,
+                soft_logout: false,
+            },
+            Self::TotpRequired => MatrixError {
+                errcode: "ORG.MATRIX.MAS_TOTP_REQUIRED",
+                error: "A code from the authenticator app is required, send it as totp_code",
+                status: StatusCode::UNAUTHORIZED,
+                soft_logout: false,
+            },
+            Self::EmailNotVerified => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "An email address has to be verified before logging in",
+                status: StatusCode::FORBIDDEN,
+                soft_logout: false,
+            },
+            Self::PasswordLoginDisabled => MatrixError {
+                errcode: "M_FORBIDDEN",
+                error: "Password logins are disabled, log in with SSO instead",
+                status: StatusCode::FORBIDDEN,
+                soft_logout: false,
             },
         }
         .into_response()
     }
 }
 
-#[tracing::instrument(skip_all, err)]
+impl RouteError {
+    /// The `result` label of the login metric for this error
+    fn metric_result(&self) -> &'static str {
+        match self {
+            Self::Internal(_) | Self::Anyhow(_) => "error",
+            Self::Unsupported | Self::PasswordLoginDisabled => "unsupported",
+            Self::Unavailable => "unavailable",
+            Self::TotpRequired => "totp_required",
+            Self::LoginFailed
+            | Self::LoginTookTooLong
+            | Self::InvalidLoginToken
+            | Self::ChallengeFailed
+            | Self::EmailNotVerified => "failure",
+        }
+    }
+}
+
+fn record_login<T>(result: &Result<T, RouteError>) {
+    let result = match result {
+        Ok(_) => "success",
+        Err(e) => e.metric_result(),
+    };
+    crate::metrics::compat_login(result);
+}
+
+/// Attach the user and the session to the current span. This must not record
+/// any credentials
+fn record_session(session: &CompatSession<PostgresqlBackend>) {
+    let span = tracing::Span::current();
+    span.record("user.sub", &session.user.sub.as_str());
+    span.record("session.id", &session.data);
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(user.sub, session.id), err)]
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(config): Extension<MatrixConfig>,
-    Json(input): Json<RequestBody>,
+    Extension(feature_flags): Extension<FeatureFlags>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(cache): Extension<IdempotencyCache<ResponseBody>>,
+    Extension(challenge): Extension<Challenge>,
+    Extension(encrypter): Extension<Encrypter>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    LimitedJson(input): LimitedJson<RequestBody>,
 ) -> Result<impl IntoResponse, RouteError> {
-    let mut txn = pool.begin().await?;
+    let remote_ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let key = idempotency_key(&headers, &input);
+    let fut = login(
+        &pool,
+        &config,
+        &feature_flags,
+        &email_config,
+        &challenge,
+        &encrypter,
+        remote_ip,
+        input,
+    );
+    let result = match key {
+        // A client retrying a password login with the same key gets the same
+        // session back instead of starting a new one
+        Some(key) => cache.get_or_try_init(key, || fut).await,
+        None => fut.await,
+    };
+    record_login(&result);
+    result.map(Json)
+}
+
+/// The key under which the response to a password login is remembered, if the
+/// client sent an `Idempotency-Key` header
+fn idempotency_key(headers: &HeaderMap, input: &RequestBody) -> Option<IdempotencyKey> {
+    let user = match &input.credentials {
+        Credentials::Password {
+            identifier: Identifier::User { user },
+            ..
+        } => user,
+        _ => return None,
+    };
+
+    let key = headers.get(&IDEMPOTENCY_KEY)?.to_str().ok()?;
+    let body = serde_json::to_vec(input).ok()?;
+    Some(IdempotencyKey::new(key.to_owned(), user.clone(), &body))
+}
+
+/// Wait for a database connection, giving up after the given timeout so that
+/// the client can retry later instead of hanging when the pool is exhausted
+async fn with_acquire_timeout<T>(
+    timeout: std::time::Duration,
+    acquire: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, RouteError> {
+    match tokio::time::timeout(timeout, acquire).await {
+        Ok(Ok(conn)) => Ok(conn),
+        Ok(Err(sqlx::Error::PoolTimedOut)) | Err(_) => {
+            tracing::warn!(
+                ?timeout,
+                "Timed out waiting for a database connection, the pool might be exhausted"
+            );
+            Err(RouteError::Unavailable)
+        }
+        Ok(Err(e)) => Err(e.into()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn login(
+    pool: &PgPool,
+    config: &MatrixConfig,
+    feature_flags: &FeatureFlags,
+    email_config: &EmailConfig,
+    challenge: &Challenge,
+    encrypter: &Encrypter,
+    remote_ip: Option<IpAddr>,
+    input: RequestBody,
+) -> Result<ResponseBody, RouteError> {
+    if matches!(input.credentials, Credentials::Password { .. }) {
+        if !feature_flags.password_login() {
+            return Err(RouteError::PasswordLoginDisabled);
+        }
+
+        // Password logins are the ones worth automating, make sure a human is
+        // behind them before checking the credentials
+        verify_challenge(challenge, input.challenge_response.as_deref(), remote_ip).await?;
+    }
+
+    let mut txn = with_acquire_timeout(config.login_database_timeout, pool.begin()).await?;
     let session = match input.credentials {
         Credentials::Password {
             identifier: Identifier::User { user },
             password,
-        } => user_password_login(&mut txn, user, password).await?,
+            totp_code,
+        } => match user_password_login(&mut txn, encrypter, user, password, totp_code).await {
+            // Dropping the transaction rolls the new session back
+            Ok(session)
+                if email_config.require_verification && !session.user.has_verified_email() =>
+            {
+                return Err(RouteError::EmailNotVerified);
+            }
+            Ok(session) => session,
+            Err(e) => {
+                // Keep track of the failed attempt
+                txn.commit().await?;
+                return Err(e);
+            }
+        },
 
         Credentials::Token { token } => token_login(&mut txn, &token).await?,
 
@@ -224,6 +507,8 @@ pub(crate) async fn post(
         }
     };
 
+    record_session(&session);
+
     let user_id = format!("@{}:{}", session.user.username, config.homeserver);
 
     // If the client asked for a refreshable token, make it expire
@@ -249,13 +534,13 @@ pub(crate) async fn post(
 
     txn.commit().await?;
 
-    Ok(Json(ResponseBody {
+    Ok(ResponseBody {
         access_token: access_token.token,
         device_id: session.device,
         user_id,
         refresh_token,
         expires_in_ms: expires_in,
-    }))
+    })
 }
 
 async fn token_login(
@@ -301,13 +586,379 @@ async fn token_login(
 
 async fn user_password_login(
     txn: &mut Transaction<'_, Postgres>,
+    encrypter: &Encrypter,
     username: String,
     password: String,
+    totp_code: Option<String>,
 ) -> Result<CompatSession<PostgresqlBackend>, RouteError> {
     let device = Device::generate(&mut thread_rng());
-    let session = compat_login(txn, &username, &password, device)
-        .await
-        .map_err(|_| RouteError::LoginFailed)?;
+    let session = compat_login(
+        txn,
+        &username,
+        &password,
+        totp_code.as_deref(),
+        |user, totp| decrypt_totp_secret(encrypter, user, totp),
+        device,
+    )
+    .await
+    .map_err(|e| match e.downcast_ref::<SecondFactorError>() {
+        // The password was right, let the client ask for the code
+        Some(SecondFactorError::Required) => RouteError::TotpRequired,
+        _ => RouteError::LoginFailed,
+    })?;
 
     Ok(session)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use mas_config::UpstreamOAuth2Config;
+    use mas_data_model::User;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, Layer};
+
+    use super::*;
+    use crate::features::Feature;
+
+    /// Collects the fields recorded on spans, after they were created
+    #[derive(Clone, Default)]
+    struct RecordedFields(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl Visit for RecordedFields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let value = format!("{:?}", value);
+            self.0
+                .lock()
+                .unwrap()
+                .push((field.name().to_owned(), value));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for RecordedFields {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+            attrs.record(&mut self.clone());
+        }
+
+        fn on_record(&self, _span: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+            values.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn session_is_recorded_on_the_span() {
+        let recorded = RecordedFields::default();
+        let subscriber = tracing_subscriber::registry().with(recorded.clone());
+
+        let session = CompatSession {
+            data: 42,
+            user: User {
+                data: 1,
+                username: "alice".to_owned(),
+                sub: "sub-alice".to_owned(),
+                primary_email: None,
+            },
+            device: Device::generate(&mut thread_rng()),
+            created_at: Utc::now(),
+            deleted_at: None,
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "login",
+                user.sub = tracing::field::Empty,
+                session.id = tracing::field::Empty,
+            );
+            let _guard = span.enter();
+            record_session(&session);
+        });
+
+        let recorded = recorded.0.lock().unwrap();
+        assert!(recorded.contains(&("user.sub".to_owned(), "\"sub-alice\"".to_owned())));
+        assert!(recorded.contains(&("session.id".to_owned(), "42".to_owned())));
+
+        // Nothing else, and especially no credentials, was recorded
+        assert_eq!(recorded.len(), 2);
+    }
+
+    fn counter_value(exporter: &opentelemetry_prometheus::PrometheusExporter, result: &str) -> f64 {
+        exporter
+            .registry()
+            .gather()
+            .iter()
+            .filter(|family| family.get_name() == "compat_login_total")
+            .flat_map(|family| family.get_metric().iter())
+            .filter(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == "result" && label.get_value() == result)
+            })
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exhausted_pool_is_unavailable() {
+        // An exhausted pool never hands out a connection
+        let acquire = std::future::pending::<Result<(), sqlx::Error>>();
+        let error = with_acquire_timeout(std::time::Duration::from_secs(5), acquire)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RouteError::Unavailable));
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], RETRY_AFTER_SECONDS);
+
+        // sqlx giving up on its own is handled the same way
+        let acquire = async { Err::<(), _>(sqlx::Error::PoolTimedOut) };
+        let error = with_acquire_timeout(std::time::Duration::from_secs(5), acquire)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, RouteError::Unavailable));
+    }
+
+    #[test]
+    fn credentials_debug_is_redacted() {
+        let password: RequestBody = serde_json::from_value(serde_json::json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": "alice" },
+            "password": "hunter2",
+        }))
+        .unwrap();
+        let debug = format!("{:?}", password);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("password: [redacted]"));
+        assert!(debug.contains("alice"));
+
+        let token: RequestBody = serde_json::from_value(serde_json::json!({
+            "type": "m.login.token",
+            "token": "some-login-token",
+        }))
+        .unwrap();
+        let debug = format!("{:?}", token);
+        assert!(!debug.contains("some-login-token"));
+        assert!(debug.contains("token: [redacted]"));
+
+        let with_totp: RequestBody = serde_json::from_value(serde_json::json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": "alice" },
+            "password": "hunter2",
+            "totp_code": "123456",
+        }))
+        .unwrap();
+        let debug = format!("{:?}", with_totp);
+        assert!(!debug.contains("123456"));
+        assert!(debug.contains(r#"totp_code: Some("[redacted]")"#));
+    }
+
+    #[test]
+    fn totp_required_response() {
+        let error = RouteError::TotpRequired;
+        assert_eq!(error.metric_result(), "totp_required");
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn email_not_verified_response() {
+        let error = RouteError::EmailNotVerified;
+        assert_eq!(error.metric_result(), "failure");
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["errcode"], "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn password_flow_follows_the_flag() {
+        let flows = |feature_flags: &FeatureFlags| -> Vec<String> {
+            let types =
+                serde_json::to_value(login_types(feature_flags, &UpstreamProviders::default()))
+                    .unwrap();
+            types["flows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|flow| flow["type"].as_str().unwrap().to_owned())
+                .collect()
+        };
+
+        let feature_flags = FeatureFlags::default();
+        assert_eq!(
+            flows(&feature_flags),
+            vec!["m.login.password", "m.login.sso", "m.login.token"]
+        );
+
+        feature_flags.set_override(Feature::PasswordLogin, Some(false));
+        assert_eq!(flows(&feature_flags), vec!["m.login.sso", "m.login.token"]);
+    }
+
+    #[test]
+    fn upstream_providers_are_advertised() {
+        let feature_flags = FeatureFlags::default();
+        let sso_flow = |upstream: &UpstreamOAuth2Config| {
+            let providers = crate::upstream_oauth2::from_config(upstream);
+            let types = serde_json::to_value(login_types(&feature_flags, &providers)).unwrap();
+            types["flows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|flow| flow["type"] == "m.login.sso")
+                .unwrap()
+                .clone()
+        };
+
+        // Without providers, the field is left out
+        let flow = sso_flow(&UpstreamOAuth2Config::default());
+        assert!(flow.get("identity_providers").is_none());
+
+        let upstream: UpstreamOAuth2Config = serde_json::from_value(serde_json::json!({
+            "providers": [{
+                "id": "example",
+                "name": "Example",
+                "client_id": "mas",
+                "client_secret": "secret",
+                "authorization_endpoint": "https://sso.example.com/authorize",
+                "token_endpoint": "https://sso.example.com/token",
+                "userinfo_endpoint": "https://sso.example.com/userinfo",
+            }],
+        }))
+        .unwrap();
+        let flow = sso_flow(&upstream);
+        assert_eq!(
+            flow["identity_providers"],
+            serde_json::json!([{ "id": "example", "name": "Example" }])
+        );
+
+        // They are hidden while the upstream logins are disabled
+        feature_flags.set_override(Feature::UpstreamLogin, Some(false));
+        let flow = sso_flow(&upstream);
+        assert!(flow.get("identity_providers").is_none());
+    }
+
+    #[tokio::test]
+    async fn password_login_can_be_disabled() {
+        // Disabled password logins are rejected before using the database, and
+        // this pool can't connect, so the enabled ones fail on the database
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy_with(sqlx::postgres::PgConnectOptions::new().host("nowhere"));
+        let config = MatrixConfig {
+            login_database_timeout: std::time::Duration::from_millis(100),
+            ..MatrixConfig::default()
+        };
+        let feature_flags = FeatureFlags::default();
+        feature_flags.set_override(Feature::PasswordLogin, Some(false));
+        let challenge = crate::challenge::from_config(&mas_config::ChallengeConfig::default());
+        let input = || -> RequestBody {
+            serde_json::from_value(serde_json::json!({
+                "type": "m.login.password",
+                "identifier": { "type": "m.id.user", "user": "alice" },
+                "password": "hunter2",
+            }))
+            .unwrap()
+        };
+
+        let error = login(
+            &pool,
+            &config,
+            &feature_flags,
+            &EmailConfig::default(),
+            &challenge,
+            &Encrypter::new(&[0x42; 32]),
+            None,
+            input(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, RouteError::PasswordLoginDisabled));
+
+        // Once enabled again, the login goes on to the database
+        feature_flags.set_override(Feature::PasswordLogin, None);
+        let enabled = login(
+            &pool,
+            &config,
+            &feature_flags,
+            &EmailConfig::default(),
+            &challenge,
+            &Encrypter::new(&[0x42; 32]),
+            None,
+            input(),
+        )
+        .await
+        .unwrap_err();
+        assert!(!matches!(enabled, RouteError::PasswordLoginDisabled));
+
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["errcode"], "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn idempotency_key_is_only_used_for_password_logins() {
+        let password: RequestBody = serde_json::from_value(serde_json::json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": "alice" },
+            "password": "hunter2",
+        }))
+        .unwrap();
+        let token: RequestBody = serde_json::from_value(serde_json::json!({
+            "type": "m.login.token",
+            "token": "abc",
+        }))
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert!(idempotency_key(&headers, &password).is_none());
+
+        headers.insert(&IDEMPOTENCY_KEY, "retry-1".parse().unwrap());
+        let key = idempotency_key(&headers, &password).unwrap();
+        assert_eq!(
+            key,
+            IdempotencyKey::new(
+                "retry-1".to_owned(),
+                "alice".to_owned(),
+                &serde_json::to_vec(&password).unwrap()
+            )
+        );
+        assert!(idempotency_key(&headers, &token).is_none());
+    }
+
+    #[test]
+    fn successful_login_is_counted() {
+        let exporter = opentelemetry_prometheus::exporter().init();
+
+        record_login::<()>(&Ok(()));
+        record_login::<()>(&Err(RouteError::LoginFailed));
+        record_login::<()>(&Ok(()));
+
+        assert!((counter_value(&exporter, "success") - 2.0).abs() < f64::EPSILON);
+        assert!((counter_value(&exporter, "failure") - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn response_body_debug_is_redacted() {
+        let body = ResponseBody {
+            access_token: "mct_secret-access".to_owned(),
+            device_id: Device::generate(&mut thread_rng()),
+            user_id: "@alice:example.com".to_owned(),
+            refresh_token: Some("mcr_secret-refresh".to_owned()),
+            expires_in_ms: None,
+        };
+
+        let debug = format!("{:?}", body);
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("@alice:example.com"));
+    }
+}
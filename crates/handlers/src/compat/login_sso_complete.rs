@@ -23,17 +23,21 @@ use axum::{
 use axum_extra::extract::PrivateCookieJar;
 use chrono::{Duration, Utc};
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     FancyError, SessionInfoExt,
 };
 use mas_config::Encrypter;
-use mas_data_model::Device;
+use mas_data_model::{CompatSsoLogin, Device};
 use mas_router::{PostAuthAction, Route};
-use mas_storage::compat::{fullfill_compat_sso_login, get_compat_sso_login_by_id};
+use mas_storage::{
+    compat::{fullfill_compat_sso_login, get_compat_sso_login_by_id},
+    PostgresqlBackend,
+};
 use mas_templates::{CompatSsoContext, ErrorContext, TemplateContext, Templates};
 use rand::thread_rng;
 use serde::Serialize;
 use sqlx::PgPool;
+use url::Url;
 
 #[derive(Serialize)]
 struct AllParams<'s> {
@@ -44,16 +48,36 @@ struct AllParams<'s> {
     login_token: &'s str,
 }
 
+/// Where to send the user back to the client, with the login token to
+/// exchange
+fn redirect_uri_with_token(login: &CompatSsoLogin<PostgresqlBackend>) -> anyhow::Result<Url> {
+    let mut redirect_uri = login.redirect_uri.clone();
+    let existing_params = redirect_uri
+        .query()
+        .map(serde_urlencoded::from_str)
+        .transpose()?
+        .unwrap_or_default();
+
+    let params = AllParams {
+        existing_params,
+        login_token: &login.token,
+    };
+    let query = serde_urlencoded::to_string(&params)?;
+    redirect_uri.set_query(Some(&query));
+    Ok(redirect_uri)
+}
+
 pub async fn get(
     Extension(pool): Extension<PgPool>,
     Extension(templates): Extension<Templates>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Path(id): Path<i64>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
 
     let maybe_session = session_info.load_session(&mut conn).await?;
 
@@ -65,14 +89,7 @@ pub async fn get(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    // TODO: make that more generic
-    if session
-        .user
-        .primary_email
-        .as_ref()
-        .and_then(|e| e.confirmed_at)
-        .is_none()
-    {
+    if !session.user.has_verified_email() {
         let destination = mas_router::AccountAddEmail::default()
             .and_then(PostAuthAction::ContinueCompatSsoLogin { data: id });
         return Ok((cookie_jar, destination.go()).into_response());
@@ -102,6 +119,7 @@ pub async fn get(
 pub async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(templates): Extension<Templates>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Path(id): Path<i64>,
     Form(form): Form<ProtectedForm<()>>,
@@ -109,7 +127,7 @@ pub async fn post(
     let mut txn = pool.begin().await?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
-    cookie_jar.verify_form(form)?;
+    cookie_jar.verify_form(&csrf_protection, form)?;
 
     let maybe_session = session_info.load_session(&mut txn).await?;
 
@@ -121,14 +139,7 @@ pub async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    // TODO: make that more generic
-    if session
-        .user
-        .primary_email
-        .as_ref()
-        .and_then(|e| e.confirmed_at)
-        .is_none()
-    {
+    if !session.user.has_verified_email() {
         let destination = mas_router::AccountAddEmail::default()
             .and_then(PostAuthAction::ContinueCompatSsoLogin { data: id });
         return Ok((cookie_jar, destination.go()).into_response());
@@ -146,22 +157,7 @@ pub async fn post(
         return Ok((cookie_jar, Html(content)).into_response());
     }
 
-    let redirect_uri = {
-        let mut redirect_uri = login.redirect_uri.clone();
-        let existing_params = redirect_uri
-            .query()
-            .map(serde_urlencoded::from_str)
-            .transpose()?
-            .unwrap_or_default();
-
-        let params = AllParams {
-            existing_params,
-            login_token: &login.token,
-        };
-        let query = serde_urlencoded::to_string(&params)?;
-        redirect_uri.set_query(Some(&query));
-        redirect_uri
-    };
+    let redirect_uri = redirect_uri_with_token(&login)?;
 
     let device = Device::generate(&mut thread_rng());
     let _login = fullfill_compat_sso_login(&mut txn, session.user, login, device).await?;
@@ -13,8 +13,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Redirect},
+    Extension,
+};
+use axum_extra::extract::PrivateCookieJar;
 use hyper::StatusCode;
+use mas_config::Encrypter;
 use mas_router::{CompatLoginSsoComplete, UrlBuilder};
 use mas_storage::compat::insert_compat_sso_login;
 use rand::{
@@ -26,6 +32,8 @@ use sqlx::PgPool;
 use thiserror::Error;
 use url::Url;
 
+use crate::upstream_oauth2::{self, UpstreamAction, UpstreamProviders};
+
 #[derive(Debug, Deserialize)]
 pub struct Params {
     #[serde(rename = "redirectUrl")]
@@ -45,6 +53,9 @@ pub enum RouteError {
 
     #[error("invalid redirect_url")]
     InvalidRedirectUrl,
+
+    #[error("unknown identity provider")]
+    UnknownIdentityProvider,
 }
 
 impl From<sqlx::Error> for RouteError {
@@ -55,18 +66,17 @@ impl From<sqlx::Error> for RouteError {
 
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self)).into_response()
+        let status = match self {
+            Self::UnknownIdentityProvider => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, format!("{}", self)).into_response()
     }
 }
 
-#[tracing::instrument(skip(pool, url_builder), err)]
-pub async fn get(
-    Extension(pool): Extension<PgPool>,
-    Extension(url_builder): Extension<UrlBuilder>,
-    Query(params): Query<Params>,
-) -> Result<impl IntoResponse, RouteError> {
-    // Check the redirectUrl parameter
-    let redirect_url = params.redirect_url.ok_or(RouteError::MissingRedirectUrl)?;
+/// Check the `redirectUrl` parameter the client gave
+fn validate_redirect_url(redirect_url: Option<String>) -> Result<Url, RouteError> {
+    let redirect_url = redirect_url.ok_or(RouteError::MissingRedirectUrl)?;
     let redirect_url = Url::parse(&redirect_url).map_err(|_| RouteError::InvalidRedirectUrl)?;
 
     // Do not allow URLs with username or passwords in them
@@ -79,9 +89,52 @@ pub async fn get(
         return Err(RouteError::InvalidRedirectUrl);
     }
 
+    Ok(redirect_url)
+}
+
+#[tracing::instrument(skip(pool, url_builder), err)]
+pub async fn get(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Query(params): Query<Params>,
+) -> Result<impl IntoResponse, RouteError> {
+    let redirect_url = validate_redirect_url(params.redirect_url)?;
+
     let token = Alphanumeric.sample_string(&mut thread_rng(), 32);
     let mut conn = pool.acquire().await?;
     let login = insert_compat_sso_login(&mut conn, token, redirect_url).await?;
 
     Ok(url_builder.absolute_redirect(&CompatLoginSsoComplete(login.data)))
 }
+
+/// Start the login on the upstream provider the client picked, instead of
+/// asking the user to log in here
+#[tracing::instrument(skip(pool, url_builder, providers, cookie_jar), err)]
+pub async fn get_idp(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(providers): Extension<UpstreamProviders>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Path((_, idp)): Path<(String, String)>,
+    Query(params): Query<Params>,
+) -> Result<impl IntoResponse, RouteError> {
+    let provider = providers
+        .get(&idp)
+        .ok_or(RouteError::UnknownIdentityProvider)?;
+    let redirect_url = validate_redirect_url(params.redirect_url)?;
+
+    let token = Alphanumeric.sample_string(&mut thread_rng(), 32);
+    let mut conn = pool.acquire().await?;
+    let login = insert_compat_sso_login(&mut conn, token, redirect_url).await?;
+
+    let (authorization_url, cookie_jar) = upstream_oauth2::start(
+        cookie_jar,
+        &url_builder,
+        provider,
+        UpstreamAction::Login {
+            login_id: login.data,
+        },
+    );
+
+    Ok((cookie_jar, Redirect::to(authorization_url.as_str())))
+}
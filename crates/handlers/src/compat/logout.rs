@@ -12,75 +12,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{response::IntoResponse, Extension, Json, TypedHeader};
-use headers::{authorization::Bearer, Authorization};
-use hyper::StatusCode;
-use mas_data_model::{TokenFormatError, TokenType};
-use mas_storage::compat::compat_logout;
+use axum::{response::IntoResponse, Extension, Json};
+use mas_storage::user::revoke_compat_session;
 use sqlx::PgPool;
 
-use super::MatrixError;
-
-pub enum RouteError {
-    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
-    MissingAuthorization,
-    InvalidAuthorization,
-    LogoutFailed,
-}
-
-impl IntoResponse for RouteError {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            Self::Internal(_) => MatrixError {
-                errcode: "M_UNKNOWN",
-                error: "Internal error",
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-            },
-            Self::MissingAuthorization => MatrixError {
-                errcode: "M_MISSING_TOKEN",
-                error: "Missing access token",
-                status: StatusCode::UNAUTHORIZED,
-            },
-            Self::InvalidAuthorization | Self::LogoutFailed => MatrixError {
-                errcode: "M_UNKNOWN_TOKEN",
-                error: "Invalid access token",
-                status: StatusCode::UNAUTHORIZED,
-            },
-        }
-        .into_response()
-    }
-}
-
-impl From<sqlx::Error> for RouteError {
-    fn from(e: sqlx::Error) -> Self {
-        Self::Internal(Box::new(e))
-    }
-}
-
-impl From<TokenFormatError> for RouteError {
-    fn from(_e: TokenFormatError) -> Self {
-        Self::InvalidAuthorization
-    }
-}
+use super::{CompatAuth, MatrixError};
 
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
-    maybe_authorization: Option<TypedHeader<Authorization<Bearer>>>,
-) -> Result<impl IntoResponse, RouteError> {
-    let mut conn = pool.acquire().await?;
-
-    let TypedHeader(authorization) = maybe_authorization.ok_or(RouteError::MissingAuthorization)?;
-
-    let token = authorization.token();
-    let token_type = TokenType::check(token)?;
-
-    if token_type != TokenType::CompatAccessToken {
-        return Err(RouteError::InvalidAuthorization);
-    }
-
-    compat_logout(&mut conn, token)
+    auth: CompatAuth,
+) -> Result<impl IntoResponse, MatrixError> {
+    // Only the sessions started through the compatibility API can be ended
+    // here
+    let session = match auth {
+        CompatAuth::Compat(session) => session,
+        CompatAuth::OAuth2(_) => return Err(MatrixError::UNKNOWN_TOKEN),
+    };
+
+    let ended = revoke_compat_session(&pool, &session.user, session.data)
         .await
-        .map_err(|_| RouteError::LogoutFailed)?;
+        .map_err(|_| MatrixError::UNKNOWN)?;
+    if !ended {
+        return Err(MatrixError::UNKNOWN_TOKEN);
+    }
 
     Ok(Json(serde_json::json!({})))
 }
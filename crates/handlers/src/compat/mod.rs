@@ -12,22 +12,50 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{response::IntoResponse, Json};
-use hyper::StatusCode;
-use serde::Serialize;
+use axum::{
+    extract::{rejection::TypedHeaderRejectionReason, FromRequest, RequestParts},
+    response::IntoResponse,
+    Extension, Json, TypedHeader,
+};
+use chrono::{DateTime, Utc};
+use headers::{authorization::Bearer, Authorization};
+use hyper::{
+    body::{Buf, HttpBody},
+    header::CONTENT_LENGTH,
+    Method, StatusCode,
+};
+use mas_axum_utils::user_authorization::AuthorizationVerificationError;
+use mas_config::MatrixConfig;
+use mas_data_model::{CompatAccessToken, CompatSession, Session, StorageBackend, TokenType, User};
+use mas_storage::{
+    compat::{lookup_compat_access_token, CompatAccessTokenLookupError},
+    oauth2::access_token::lookup_active_access_token,
+    PostgresqlBackend,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+use url::form_urlencoded;
 
+pub(crate) mod idempotency;
 pub(crate) mod login;
 pub(crate) mod login_sso_complete;
 pub(crate) mod login_sso_redirect;
 pub(crate) mod logout;
 pub(crate) mod refresh;
+pub(crate) mod whoami;
 
 #[derive(Debug, Serialize)]
-struct MatrixError {
+pub(crate) struct MatrixError {
     errcode: &'static str,
     error: &'static str,
     #[serde(skip)]
     status: StatusCode,
+
+    /// Tells the client its device is still there, and that it only has to
+    /// refresh its access token or log in again
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    soft_logout: bool,
 }
 
 impl IntoResponse for MatrixError {
@@ -35,3 +63,695 @@ impl IntoResponse for MatrixError {
         (self.status, Json(self)).into_response()
     }
 }
+
+impl MatrixError {
+    const TOO_LARGE: Self = Self {
+        errcode: "M_TOO_LARGE",
+        error: "Request body is too large",
+        status: StatusCode::PAYLOAD_TOO_LARGE,
+        soft_logout: false,
+    };
+
+    const NOT_JSON: Self = Self {
+        errcode: "M_NOT_JSON",
+        error: "Request body is not valid JSON",
+        status: StatusCode::BAD_REQUEST,
+        soft_logout: false,
+    };
+
+    const BAD_JSON: Self = Self {
+        errcode: "M_BAD_JSON",
+        error: "Request body is not of the expected shape",
+        status: StatusCode::BAD_REQUEST,
+        soft_logout: false,
+    };
+
+    const UNKNOWN: Self = Self {
+        errcode: "M_UNKNOWN",
+        error: "Internal error",
+        status: StatusCode::INTERNAL_SERVER_ERROR,
+        soft_logout: false,
+    };
+
+    const MISSING_TOKEN: Self = Self {
+        errcode: "M_MISSING_TOKEN",
+        error: "Missing access token",
+        status: StatusCode::UNAUTHORIZED,
+        soft_logout: false,
+    };
+
+    const UNKNOWN_TOKEN: Self = Self {
+        errcode: "M_UNKNOWN_TOKEN",
+        error: "Invalid access token",
+        status: StatusCode::UNAUTHORIZED,
+        soft_logout: false,
+    };
+
+    const EXPIRED_TOKEN: Self = Self {
+        errcode: "M_UNKNOWN_TOKEN",
+        error: "Access token has expired",
+        status: StatusCode::UNAUTHORIZED,
+        soft_logout: true,
+    };
+
+    const FORBIDDEN: Self = Self {
+        errcode: "M_FORBIDDEN",
+        error: "Access token does not have the required scope",
+        status: StatusCode::FORBIDDEN,
+        soft_logout: false,
+    };
+}
+
+impl From<AuthorizationVerificationError> for MatrixError {
+    fn from(e: AuthorizationVerificationError) -> Self {
+        match e {
+            AuthorizationVerificationError::MissingToken
+            | AuthorizationVerificationError::MissingForm => Self::MISSING_TOKEN,
+            // Revoked and expired tokens are not found
+            AuthorizationVerificationError::InvalidToken => Self::UNKNOWN_TOKEN,
+            AuthorizationVerificationError::InternalError(_) => Self::UNKNOWN,
+        }
+    }
+}
+
+/// The scope token a route requires, set on the route with an [`Extension`]
+/// layer and enforced by the [`CompatAuth`] extractor
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequireScope(pub &'static str);
+
+impl RequireScope {
+    /// Check that the given session was granted this scope
+    fn check<T: StorageBackend>(self, session: &Session<T>) -> Result<(), MatrixError> {
+        if session.allows(self.0) {
+            Ok(())
+        } else {
+            Err(MatrixError::FORBIDDEN)
+        }
+    }
+}
+
+impl From<CompatAccessTokenLookupError> for MatrixError {
+    fn from(e: CompatAccessTokenLookupError) -> Self {
+        // Tokens of ended sessions are not found
+        if e.not_found() {
+            Self::UNKNOWN_TOKEN
+        } else {
+            Self::UNKNOWN
+        }
+    }
+}
+
+/// The access token from the `access_token` query parameter, if it is
+/// `enabled` and the request doesn't change anything
+fn token_from_query<B>(req: &RequestParts<B>, enabled: bool) -> Option<String> {
+    if !enabled || !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return None;
+    }
+
+    let token = form_urlencoded::parse(req.uri().query()?.as_bytes())
+        .find(|(key, _)| key == "access_token")
+        .map(|(_, value)| value.into_owned())?;
+
+    warn!(
+        path = req.uri().path(),
+        "Access token passed in the query string, which is deprecated"
+    );
+
+    Some(token)
+}
+
+/// The session authenticated by the `Authorization: Bearer` header of a
+/// request to the compatibility API.
+///
+/// When the `access_token_in_query` option of the [`MatrixConfig`] is
+/// enabled, the token can also come from the `access_token` query parameter,
+/// but only for `GET` and `HEAD` requests.
+///
+/// Compat access tokens, from the logins through the compatibility API, are
+/// accepted everywhere. OAuth 2.0 access tokens are only accepted on the
+/// routes with a [`RequireScope`], and have to be granted that scope.
+pub(crate) enum CompatAuth {
+    Compat(CompatSession<PostgresqlBackend>),
+    OAuth2(Box<Session<PostgresqlBackend>>),
+}
+
+impl CompatAuth {
+    /// The user the token was issued to
+    #[must_use]
+    pub fn user(&self) -> &User<PostgresqlBackend> {
+        match self {
+            Self::Compat(session) => &session.user,
+            Self::OAuth2(session) => &session.browser_session.user,
+        }
+    }
+
+    /// Accept a compat access token found in the database, unless it expired
+    fn compat(
+        token: &CompatAccessToken<PostgresqlBackend>,
+        session: CompatSession<PostgresqlBackend>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, MatrixError> {
+        if token.is_expired(now) {
+            return Err(MatrixError::EXPIRED_TOKEN);
+        }
+
+        Ok(Self::Compat(session))
+    }
+}
+
+#[axum::async_trait]
+impl<B> FromRequest<B> for CompatAuth
+where
+    B: Send,
+{
+    type Rejection = MatrixError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let access_token_in_query = Extension::<MatrixConfig>::from_request(req)
+            .await
+            .map_or(false, |Extension(config)| config.access_token_in_query);
+
+        let token = match TypedHeader::<Authorization<Bearer>>::from_request(req).await {
+            Ok(TypedHeader(authorization)) => authorization.token().to_owned(),
+            Err(e) if matches!(e.reason(), TypedHeaderRejectionReason::Missing) => {
+                token_from_query(req, access_token_in_query).ok_or(MatrixError::MISSING_TOKEN)?
+            }
+            Err(_) => return Err(MatrixError::UNKNOWN_TOKEN),
+        };
+
+        // Don't bother looking up tokens with a typo in them, or OAuth 2.0
+        // tokens on routes which don't accept them
+        let required = Extension::<RequireScope>::from_request(req)
+            .await
+            .ok()
+            .map(|Extension(required)| required);
+        let required = match (TokenType::check(&token), required) {
+            (Ok(TokenType::CompatAccessToken), _) => None,
+            (Ok(TokenType::AccessToken), Some(required)) => Some(required),
+            _ => return Err(MatrixError::UNKNOWN_TOKEN),
+        };
+
+        let Extension(pool) = Extension::<PgPool>::from_request(req)
+            .await
+            .map_err(|_| MatrixError::UNKNOWN)?;
+        let mut conn = pool.acquire().await.map_err(|_| MatrixError::UNKNOWN)?;
+
+        if let Some(required) = required {
+            let (_token, session) = lookup_active_access_token(&mut conn, &token)
+                .await
+                .map_err(AuthorizationVerificationError::from)?;
+            required.check(&session)?;
+            Ok(Self::OAuth2(Box::new(session)))
+        } else {
+            let (token, session) = lookup_compat_access_token(&mut conn, &token).await?;
+            Self::compat(&token, session, Utc::now())
+        }
+    }
+}
+
+/// A JSON body, rejected with `M_TOO_LARGE` if it is bigger than the
+/// `max_body_size` set in the [`MatrixConfig`] extension.
+///
+/// Bodies which are not JSON are rejected with `M_NOT_JSON`, and JSON which
+/// doesn't have the expected fields with `M_BAD_JSON`
+pub(crate) struct LimitedJson<T>(pub T);
+
+#[axum::async_trait]
+impl<T, B> FromRequest<B> for LimitedJson<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send,
+    B::Data: Send,
+{
+    type Rejection = MatrixError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let limit = match Extension::<MatrixConfig>::from_request(req).await {
+            Ok(Extension(config)) => config.max_body_size,
+            Err(_) => MatrixConfig::default().max_body_size,
+        };
+
+        // Reject early if the client tells us the body is too big
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if content_length.map_or(false, |length| length > limit) {
+            return Err(MatrixError::TOO_LARGE);
+        }
+
+        let mut body = Box::pin(req.take_body().ok_or(MatrixError::NOT_JSON)?);
+
+        // Don't trust the content length, and stop reading as soon as the limit
+        // is reached
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let mut chunk = chunk.map_err(|_| MatrixError::NOT_JSON)?;
+            if buf.len() + chunk.remaining() > limit {
+                return Err(MatrixError::TOO_LARGE);
+            }
+
+            while chunk.has_remaining() {
+                let bytes = chunk.chunk();
+                let len = bytes.len();
+                buf.extend_from_slice(bytes);
+                chunk.advance(len);
+            }
+        }
+
+        let value = serde_json::from_slice(&buf).map_err(|e| match e.classify() {
+            serde_json::error::Category::Data => MatrixError::BAD_JSON,
+            _ => MatrixError::NOT_JSON,
+        })?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::Argon2;
+    use chrono::Duration;
+    use hyper::{header::AUTHORIZATION, Body, Request};
+    use mas_data_model::{BrowserSession, Client, ClientType, Device};
+    use mas_storage::{
+        compat::{add_compat_access_token, start_compat_session},
+        oauth2::access_token::AccessTokenLookupError,
+        user::{register_user, revoke_all_sessions, start_session},
+    };
+    use oauth2_types::scope::Scope;
+    use rand::{thread_rng, Rng};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Input {
+        #[allow(dead_code)]
+        value: String,
+    }
+
+    fn request(body: Body, content_length: Option<usize>) -> RequestParts<Body> {
+        let config = MatrixConfig {
+            max_body_size: 1024,
+            ..MatrixConfig::default()
+        };
+
+        let mut request = Request::builder().method("POST").extension(config);
+        if let Some(length) = content_length {
+            request = request.header(CONTENT_LENGTH, length);
+        }
+
+        RequestParts::new(request.body(body).unwrap())
+    }
+
+    async fn status(req: &mut RequestParts<Body>) -> Result<(), StatusCode> {
+        LimitedJson::<Input>::from_request(req)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into_response().status())
+    }
+
+    async fn errcode(req: &mut RequestParts<Body>) -> Option<&'static str> {
+        LimitedJson::<Input>::from_request(req)
+            .await
+            .err()
+            .map(|e| e.errcode)
+    }
+
+    #[tokio::test]
+    async fn small_body_is_accepted() {
+        let body = br#"{"value": "hello"}"#.to_vec();
+        let length = body.len();
+        let mut req = request(Body::from(body), Some(length));
+        assert_eq!(status(&mut req).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected() {
+        let body = format!(r#"{{"value": "{}"}}"#, "a".repeat(2048));
+
+        // With a content length
+        let length = body.len();
+        let mut req = request(Body::from(body.clone()), Some(length));
+        assert_eq!(status(&mut req).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+
+        // Streamed without a content length
+        let (mut sender, streamed) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in body.into_bytes().chunks(100) {
+                if sender.send_data(chunk.to_vec().into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut req = request(streamed, None);
+        assert_eq!(status(&mut req).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[tokio::test]
+    async fn invalid_json_is_rejected() {
+        let mut req = request(Body::from("not json"), None);
+        assert_eq!(status(&mut req).await, Err(StatusCode::BAD_REQUEST));
+
+        for body in ["not json", r#"{"value": "hello""#, ""] {
+            let mut req = request(Body::from(body), None);
+            assert_eq!(errcode(&mut req).await, Some("M_NOT_JSON"), "{:?}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_shape_is_rejected() {
+        for body in [r#"{"other": "hello"}"#, r#"{"value": 42}"#, "[]"] {
+            let mut req = request(Body::from(body), None);
+            assert_eq!(status(&mut req).await, Err(StatusCode::BAD_REQUEST));
+
+            let mut req = request(Body::from(body), None);
+            assert_eq!(errcode(&mut req).await, Some("M_BAD_JSON"), "{:?}", body);
+        }
+    }
+
+    fn session(scope: &str) -> Session<()> {
+        let client = Client {
+            data: (),
+            client_id: "client".to_string(),
+            client_type: ClientType::Confidential,
+            encrypted_client_secret: None,
+            redirect_uris: Vec::new(),
+            response_types: Vec::new(),
+            grant_types: Vec::new(),
+            contacts: Vec::new(),
+            client_name: None,
+            logo_uri: None,
+            client_uri: None,
+            policy_uri: None,
+            tos_uri: None,
+            jwks: None,
+            id_token_signed_response_alg: None,
+            userinfo_signed_response_alg: None,
+            token_endpoint_auth_method: None,
+            token_endpoint_auth_signing_alg: None,
+            initiate_login_uri: None,
+        };
+
+        Session {
+            data: (),
+            browser_session: BrowserSession::samples().remove(0),
+            client,
+            scope: scope.parse::<Scope>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn required_scope_is_allowed() {
+        let required = RequireScope("urn:matrix:client:api:*");
+        assert!(required
+            .check(&session("openid urn:matrix:client:api:*"))
+            .is_ok());
+    }
+
+    #[test]
+    fn revoked_token_is_unknown() {
+        // The lookup of a revoked access token finds nothing
+        let e = AccessTokenLookupError::Database(sqlx::Error::RowNotFound);
+        let err = MatrixError::from(AuthorizationVerificationError::from(e));
+        assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    async fn auth_error(
+        authorization: Option<&str>,
+        required: Option<RequireScope>,
+    ) -> MatrixError {
+        let mut request = Request::builder();
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        if let Some(required) = required {
+            request = request.extension(required);
+        }
+
+        // Those requests are rejected before needing a database
+        let mut req = RequestParts::new(request.body(Body::empty()).unwrap());
+        match CompatAuth::from_request(&mut req).await {
+            Ok(_) => panic!("the request should be rejected"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_token() {
+        let err = auth_error(None, None).await;
+        assert_eq!(err.errcode, "M_MISSING_TOKEN");
+        assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn malformed_token_is_unknown() {
+        for authorization in ["Bearer not-a-token", "Basic dXNlcjpwYXNz"] {
+            let err = auth_error(Some(authorization), None).await;
+            assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+            assert_eq!(err.into_response().status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn oauth2_token_needs_a_scoped_route() {
+        let token = TokenType::AccessToken.generate(thread_rng());
+        let err = auth_error(Some(&format!("Bearer {}", token)), None).await;
+        assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+
+        // Refresh tokens are never accepted
+        let token = TokenType::CompatRefreshToken.generate(thread_rng());
+        let required = RequireScope("urn:matrix:client:api:*");
+        let err = auth_error(Some(&format!("Bearer {}", token)), Some(required)).await;
+        assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+    }
+
+    fn query_request(method: Method, uri: &str, enabled: bool) -> RequestParts<Body> {
+        let config = MatrixConfig {
+            access_token_in_query: enabled,
+            ..MatrixConfig::default()
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .extension(config)
+            .body(Body::empty())
+            .unwrap();
+        RequestParts::new(request)
+    }
+
+    #[test]
+    fn access_token_from_query() {
+        let uri = "/_matrix/media/v3/download/example.com/abc?access_token=mct_token&x=1";
+
+        let req = query_request(Method::GET, uri, true);
+        assert_eq!(token_from_query(&req, true), Some("mct_token".to_owned()));
+        assert_eq!(token_from_query(&req, false), None);
+
+        // Never on requests which change something
+        let req = query_request(Method::POST, uri, true);
+        assert_eq!(token_from_query(&req, true), None);
+
+        let req = query_request(Method::GET, "/_matrix/client/v3/account/whoami", true);
+        assert_eq!(token_from_query(&req, true), None);
+    }
+
+    #[tokio::test]
+    async fn access_token_in_query_is_config_gated() {
+        let uri = "/_matrix/client/v3/account/whoami?access_token=not-a-token";
+
+        // Disabled by default: the token is ignored
+        let mut req = query_request(Method::GET, uri, false);
+        let err = match CompatAuth::from_request(&mut req).await {
+            Ok(_) => panic!("the request should be rejected"),
+            Err(e) => e,
+        };
+        assert_eq!(err.errcode, "M_MISSING_TOKEN");
+
+        // Enabled: the token is used, and this one is invalid
+        let mut req = query_request(Method::GET, uri, true);
+        let err = match CompatAuth::from_request(&mut req).await {
+            Ok(_) => panic!("the request should be rejected"),
+            Err(e) => e,
+        };
+        assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+
+        let mut req = query_request(Method::POST, uri, true);
+        let err = match CompatAuth::from_request(&mut req).await {
+            Ok(_) => panic!("the request should be rejected"),
+            Err(e) => e,
+        };
+        assert_eq!(err.errcode, "M_MISSING_TOKEN");
+    }
+
+    #[test]
+    fn unknown_compat_token() {
+        let err = MatrixError::from(CompatAccessTokenLookupError::Database(
+            sqlx::Error::RowNotFound,
+        ));
+        assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+        assert!(!err.soft_logout);
+
+        let err = MatrixError::from(CompatAccessTokenLookupError::Database(
+            sqlx::Error::PoolTimedOut,
+        ));
+        assert_eq!(err.errcode, "M_UNKNOWN");
+    }
+
+    fn compat_session() -> CompatSession<PostgresqlBackend> {
+        CompatSession {
+            data: 1,
+            user: User::samples().remove(0),
+            device: Device::generate(&mut thread_rng()),
+            created_at: Utc::now(),
+            deleted_at: None,
+        }
+    }
+
+    fn compat_token(expires_at: Option<DateTime<Utc>>) -> CompatAccessToken<PostgresqlBackend> {
+        CompatAccessToken {
+            data: 1,
+            token: TokenType::CompatAccessToken.generate(thread_rng()),
+            created_at: Utc::now(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn valid_compat_token() {
+        let now = Utc::now();
+
+        for expires_at in [None, Some(now + Duration::minutes(5))] {
+            let auth =
+                CompatAuth::compat(&compat_token(expires_at), compat_session(), now).unwrap();
+            assert_eq!(auth.user().username, "john");
+            assert!(matches!(auth, CompatAuth::Compat(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_compat_token_is_a_soft_logout() {
+        let now = Utc::now();
+        let err = match CompatAuth::compat(&compat_token(Some(now)), compat_session(), now) {
+            Ok(_) => panic!("the token should be expired"),
+            Err(e) => e,
+        };
+
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["errcode"], "M_UNKNOWN_TOKEN");
+        assert_eq!(body["soft_logout"], true);
+    }
+
+    #[test]
+    fn insufficient_scope_is_forbidden() {
+        let required = RequireScope("urn:matrix:client:api:*");
+        let err = required.check(&session("openid email")).unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    async fn authenticate(pool: &PgPool, token: &str) -> Result<CompatAuth, MatrixError> {
+        let request = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .extension(pool.clone())
+            .extension(RequireScope("urn:matrix:client:api:*"))
+            .body(Body::empty())
+            .unwrap();
+        CompatAuth::from_request(&mut RequestParts::new(request)).await
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn tokens_are_rejected_after_revoking_all_sessions() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPool::connect(&url).await.unwrap();
+
+        // The extractor looks the tokens up on its own connections, so they are
+        // committed, under a name unique to this run
+        let mut txn = pool.begin().await.unwrap();
+        let username = format!("revoked-{:08x}", thread_rng().gen::<u32>());
+        let user = register_user(&mut txn, Argon2::default(), &username, "hunter2")
+            .await
+            .unwrap();
+        let browser_session = start_session(&mut txn, user.clone()).await.unwrap();
+
+        let compat_session =
+            start_compat_session(&mut txn, user.clone(), Device::generate(&mut thread_rng()))
+                .await
+                .unwrap();
+        let compat_token = TokenType::CompatAccessToken.generate(thread_rng());
+        add_compat_access_token(&mut txn, &compat_session, compat_token.clone(), None)
+            .await
+            .unwrap();
+
+        let oauth2_session: i64 = sqlx::query_scalar(
+            r#"
+                WITH client AS (
+                    INSERT INTO oauth2_clients
+                        (client_id, response_types, grant_type_authorization_code,
+                         grant_type_refresh_token, contacts)
+                    VALUES ($1, '{}', TRUE, TRUE, '{}')
+                    RETURNING id
+                )
+                INSERT INTO oauth2_sessions (user_session_id, oauth2_client_id, scope)
+                SELECT $2, id, 'openid urn:matrix:client:api:*' FROM client
+                RETURNING id
+            "#,
+        )
+        .bind(&username)
+        .bind(browser_session.data)
+        .fetch_one(&mut txn)
+        .await
+        .unwrap();
+        let access_token = TokenType::AccessToken.generate(thread_rng());
+        sqlx::query(
+            r#"
+                INSERT INTO oauth2_access_tokens (oauth2_session_id, token, expires_after)
+                VALUES ($1, $2, 3600)
+            "#,
+        )
+        .bind(oauth2_session)
+        .bind(&access_token)
+        .execute(&mut txn)
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
+
+        assert!(matches!(
+            authenticate(&pool, &compat_token).await,
+            Ok(CompatAuth::Compat(_))
+        ));
+        assert!(matches!(
+            authenticate(&pool, &access_token).await,
+            Ok(CompatAuth::OAuth2(_))
+        ));
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(revoke_all_sessions(&mut conn, &user).await.unwrap(), 2);
+
+        for token in [&compat_token, &access_token] {
+            let err = match authenticate(&pool, token).await {
+                Ok(_) => panic!("the token should be rejected"),
+                Err(e) => e,
+            };
+            assert_eq!(err.errcode, "M_UNKNOWN_TOKEN");
+        }
+
+        // Don't leave the tokens around for the other tests, their sessions go
+        // away with the user
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user.data)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM oauth2_clients WHERE client_id = $1")
+            .bind(&username)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+}
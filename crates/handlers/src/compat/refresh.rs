@@ -27,7 +27,7 @@ use serde_with::{serde_as, DurationMilliSeconds};
 use sqlx::PgPool;
 use thiserror::Error;
 
-use super::MatrixError;
+use super::{LimitedJson, MatrixError};
 
 #[derive(Debug, Deserialize)]
 pub struct RequestBody {
@@ -53,11 +53,13 @@ impl IntoResponse for RouteError {
                 errcode: "M_UNKNOWN",
                 error: "Internal error",
                 status: StatusCode::INTERNAL_SERVER_ERROR,
+                soft_logout: false,
             },
             Self::InvalidToken => MatrixError {
                 errcode: "M_UNKNOWN_TOKEN",
                 error: "Invalid refresh token",
                 status: StatusCode::UNAUTHORIZED,
+                soft_logout: false,
             },
         }
         .into_response()
@@ -97,7 +99,7 @@ pub struct ResponseBody {
 
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
-    Json(input): Json<RequestBody>,
+    LimitedJson(input): LimitedJson<RequestBody>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut txn = pool.begin().await?;
 
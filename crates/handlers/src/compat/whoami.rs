@@ -0,0 +1,44 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{response::IntoResponse, Extension, Json};
+use mas_config::MatrixConfig;
+use serde::Serialize;
+
+use super::{CompatAuth, RequireScope};
+
+/// The scope an OAuth 2.0 access token needs to use this endpoint
+pub(crate) const SCOPE: RequireScope = RequireScope("urn:matrix:client:api:*");
+
+#[derive(Debug, Serialize)]
+struct ResponseBody {
+    user_id: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+}
+
+pub(crate) async fn get(
+    Extension(config): Extension<MatrixConfig>,
+    auth: CompatAuth,
+) -> impl IntoResponse {
+    let user_id = format!("@{}:{}", auth.user().username, config.homeserver);
+
+    let device_id = match &auth {
+        CompatAuth::Compat(session) => Some(session.device.as_str().to_owned()),
+        CompatAuth::OAuth2(_) => None,
+    };
+
+    Json(ResponseBody { user_id, device_id })
+}
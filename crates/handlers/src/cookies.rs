@@ -0,0 +1,300 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Attributes of the cookies, applied to all the cookies set by the handlers
+//!
+//! The handlers set their cookies, like the session and CSRF ones, without
+//! caring about the deployment: the `SameSite`, `Secure` and `Domain`
+//! attributes and the name prefix from the configuration are added to the
+//! responses, and the prefix is removed from the requests.
+//!
+//! The private cookies encrypted with a retired key are also encrypted again
+//! with the current key when they come in, so that the handlers can read them.
+//! They are set again with the current key the next time the handlers update
+//! them.
+
+use axum_extra::extract::cookie::{Cookie, SameSite};
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
+use mas_config::{CookieSameSite, CookiesConfig, Encrypter};
+
+#[derive(Debug, Clone)]
+pub(crate) struct CookiePolicy {
+    same_site: SameSite,
+    secure: bool,
+    domain: Option<String>,
+    name_prefix: String,
+}
+
+impl CookiePolicy {
+    pub(crate) fn from_config(config: &CookiesConfig) -> Self {
+        let same_site = match config.same_site {
+            CookieSameSite::Lax => SameSite::Lax,
+            CookieSameSite::Strict => SameSite::Strict,
+            CookieSameSite::None => SameSite::None,
+        };
+
+        Self {
+            same_site,
+            secure: config.secure,
+            domain: config.domain.clone(),
+            name_prefix: config.name_prefix.clone(),
+        }
+    }
+
+    /// Remove the prefix from the names of the cookies of a request, so that
+    /// the handlers find them under the names they set. The cookies without
+    /// the prefix belong to other services, and are dropped
+    pub(crate) fn strip_prefix(&self, headers: &mut HeaderMap) {
+        if self.name_prefix.is_empty() {
+            return;
+        }
+
+        let cookies: Vec<&str> = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().strip_prefix(self.name_prefix.as_str()))
+            .collect();
+
+        // The remaining pairs come from valid header values
+        let value = (!cookies.is_empty())
+            .then(|| HeaderValue::from_str(&cookies.join("; ")).ok())
+            .flatten();
+
+        headers.remove(COOKIE);
+        if let Some(value) = value {
+            headers.insert(COOKIE, value);
+        }
+    }
+
+    /// Set the attributes of the cookies of a response
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        let values: Vec<HeaderValue> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| self.rewrite(value).unwrap_or_else(|| value.clone()))
+            .collect();
+
+        headers.remove(SET_COOKIE);
+        for value in values {
+            headers.append(SET_COOKIE, value);
+        }
+    }
+
+    fn rewrite(&self, value: &HeaderValue) -> Option<HeaderValue> {
+        let mut cookie = Cookie::parse(value.to_str().ok()?).ok()?;
+
+        let name = format!("{}{}", self.name_prefix, cookie.name());
+        cookie.set_name(name);
+
+        // Handlers can ask for stricter cookies, like the ones only used by the
+        // scripts of the service
+        if cookie.same_site() != Some(SameSite::Strict) {
+            cookie.set_same_site(self.same_site);
+        }
+
+        if self.secure {
+            cookie.set_secure(true);
+        }
+
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        // The `__Host-` prefix requires it, and the cookies are meant for the
+        // whole service anyway
+        if cookie.path().is_none() {
+            cookie.set_path("/");
+        }
+
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+}
+
+/// Encrypt again with the current key the cookies of a request which were
+/// encrypted with a retired key
+pub(crate) fn upgrade_encryption(encrypter: &Encrypter, headers: &mut HeaderMap) {
+    if !encrypter.has_retired_keys() {
+        return;
+    }
+
+    let mut upgraded = false;
+    let cookies: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(|pair| {
+            let pair = pair.trim();
+            Cookie::parse(pair.to_owned())
+                .ok()
+                .and_then(|cookie| encrypter.upgrade_cookie(&cookie))
+                .map_or_else(
+                    || pair.to_owned(),
+                    |cookie| {
+                        upgraded = true;
+                        format!("{}={}", cookie.name(), cookie.value())
+                    },
+                )
+        })
+        .collect();
+
+    if upgraded {
+        if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::Request, response::Response, routing::get, Router};
+    use hyper::Body;
+    use tower::{
+        util::{MapRequestLayer, MapResponseLayer},
+        ServiceExt,
+    };
+
+    use super::*;
+
+    /// A router which sets a cookie, and answers with the cookies it got
+    fn router(policy: &CookiePolicy) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|headers: HeaderMap| async move {
+                    let cookies = headers
+                        .get(COOKIE)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .to_owned();
+                    ([(SET_COOKIE, "session=abc; Path=/; HttpOnly")], cookies)
+                }),
+            )
+            .layer(MapRequestLayer::new({
+                let policy = policy.clone();
+                move |mut request: Request<Body>| {
+                    policy.strip_prefix(request.headers_mut());
+                    request
+                }
+            }))
+            .layer(MapResponseLayer::new({
+                let policy = policy.clone();
+                move |mut response: Response| {
+                    policy.apply(response.headers_mut());
+                    response
+                }
+            }))
+    }
+
+    async fn call(policy: &CookiePolicy, cookies: &str) -> (String, String) {
+        let request = Request::builder()
+            .uri("/")
+            .header(COOKIE, cookies)
+            .body(Body::empty())
+            .unwrap();
+        let response = router(policy).oneshot(request).await.unwrap();
+
+        let set_cookie = response.headers()[SET_COOKIE].to_str().unwrap().to_owned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (set_cookie, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn default_attributes() {
+        let policy = CookiePolicy::from_config(&CookiesConfig::default());
+        let (set_cookie, cookies) = call(&policy, "session=abc; other=def").await;
+
+        let cookie = Cookie::parse(set_cookie).unwrap();
+        assert_eq!(cookie.name(), "session");
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.secure(), None);
+        assert_eq!(cookie.domain(), None);
+        assert_eq!(cookie.http_only(), Some(true));
+
+        // Without a prefix, the cookies are left as they are
+        assert_eq!(cookies, "session=abc; other=def");
+    }
+
+    #[tokio::test]
+    async fn configured_attributes() {
+        let policy = CookiePolicy::from_config(&CookiesConfig {
+            same_site: CookieSameSite::None,
+            secure: true,
+            domain: Some("example.com".to_owned()),
+            name_prefix: "__Secure-".to_owned(),
+        });
+        let (set_cookie, cookies) = call(&policy, "__Secure-session=abc; session=def").await;
+
+        let cookie = Cookie::parse(set_cookie).unwrap();
+        assert_eq!(cookie.name(), "__Secure-session");
+        assert_eq!(cookie.value(), "abc");
+        assert_eq!(cookie.same_site(), Some(SameSite::None));
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert_eq!(cookie.path(), Some("/"));
+
+        // The handlers see their cookies without the prefix, and not the ones
+        // of other services
+        assert_eq!(cookies, "session=abc");
+    }
+
+    #[test]
+    fn missing_path() {
+        let policy = CookiePolicy::from_config(&CookiesConfig {
+            same_site: CookieSameSite::Strict,
+            ..CookiesConfig::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, HeaderValue::from_static("session=abc"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("csrf=def; Path=/"));
+        policy.apply(&mut headers);
+
+        let cookies: Vec<Cookie> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap()).unwrap())
+            .collect();
+        assert_eq!(cookies.len(), 2);
+        for cookie in cookies {
+            assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+            assert_eq!(cookie.path(), Some("/"));
+        }
+    }
+
+    #[test]
+    fn stricter_same_site_is_kept() {
+        let policy = CookiePolicy::from_config(&CookiesConfig::default());
+        let mut headers = HeaderMap::new();
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("ceremony=abc; Secure; SameSite=Strict"),
+        );
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("session=def; SameSite=None"),
+        );
+        policy.apply(&mut headers);
+
+        let cookies: Vec<Cookie> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| Cookie::parse(value.to_str().unwrap()).unwrap())
+            .collect();
+        assert_eq!(cookies[0].same_site(), Some(SameSite::Strict));
+        assert_eq!(cookies[0].secure(), Some(true));
+        assert_eq!(cookies[1].same_site(), Some(SameSite::Lax));
+    }
+}
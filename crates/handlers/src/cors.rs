@@ -0,0 +1,210 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use headers::{HeaderName, HeaderValue};
+use hyper::Method;
+use mas_config::CorsConfig;
+use mas_http::CorsLayerExt;
+use tower_http::cors::{AllowMethods, AllowOrigin, CorsLayer};
+use tracing::warn;
+
+/// Build the CORS layer of a group of API endpoints from the configuration.
+///
+/// `headers` are the request headers those endpoints need, which are allowed
+/// on top of the ones from the configuration. Invalid values in the
+/// configuration are skipped with a warning.
+pub(crate) fn layer<H>(config: &CorsConfig, headers: H) -> CorsLayer
+where
+    H: IntoIterator<Item = HeaderName>,
+{
+    let any_origin = config.allowed_origins.iter().any(|origin| origin == "*");
+
+    let allow_origin = if any_origin {
+        AllowOrigin::any()
+    } else {
+        // Browsers send the origin without a trailing slash
+        let origins: Vec<HeaderValue> = config
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .map_err(|_| warn!(%origin, "Ignoring invalid CORS origin"))
+                    .ok()
+            })
+            .collect();
+        AllowOrigin::list(origins)
+    };
+
+    // Credentials can't be sent to any origin, and tower-http refuses this
+    // combination
+    let allow_credentials = if any_origin && config.allow_credentials {
+        warn!("CORS credentials can't be allowed when any origin is, ignoring");
+        false
+    } else {
+        config.allow_credentials
+    };
+
+    let methods: Vec<Method> = config
+        .allowed_methods
+        .iter()
+        .filter_map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| warn!(%method, "Ignoring invalid CORS method"))
+                .ok()
+        })
+        .collect();
+
+    let extra_headers: Vec<HeaderName> = config
+        .allowed_headers
+        .iter()
+        .filter_map(|header| {
+            HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| warn!(%header, "Ignoring invalid CORS header"))
+                .ok()
+        })
+        .collect();
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(AllowMethods::list(methods))
+        .allow_otel_headers(headers.into_iter().chain(extra_headers))
+        .allow_credentials(allow_credentials)
+        .max_age(config.max_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use hyper::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION,
+            CONTENT_TYPE, ORIGIN,
+        },
+        Body, Request, Response, StatusCode,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn preflight(config: &CorsConfig, origin: &str) -> Response<axum::body::BoxBody> {
+        let router: Router = Router::new()
+            .route("/token", post(|| async {}))
+            .layer(layer(config, [AUTHORIZATION, CONTENT_TYPE]));
+
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/token")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap();
+
+        router.oneshot(request).await.unwrap()
+    }
+
+    fn allowing(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|&origin| origin.to_owned()).collect(),
+            ..CorsConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn preflight_from_allowed_origin() {
+        let config = allowing(&["https://app.example.com/", "https://other.example.com"]);
+        let response = preflight(&config, "https://app.example.com").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "https://app.example.com"
+        );
+        let methods = headers
+            .get(ACCESS_CONTROL_ALLOW_METHODS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(methods.contains("POST"));
+        let allowed_headers = headers
+            .get(ACCESS_CONTROL_ALLOW_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(allowed_headers.contains("authorization"));
+        assert!(headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+
+    #[tokio::test]
+    async fn preflight_from_disallowed_origin() {
+        // Browsers reject the response if it doesn't allow their origin
+        let config = allowing(&["https://app.example.com"]);
+        for origin in [
+            "https://evil.example.com",
+            "http://app.example.com",
+            "https://app.example.com.evil.com",
+        ] {
+            let response = preflight(&config, origin).await;
+            assert!(
+                response
+                    .headers()
+                    .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+                    .is_none(),
+                "{} should not be allowed",
+                origin
+            );
+        }
+
+        // Nothing is allowed by default
+        let response = preflight(&CorsConfig::default(), "https://app.example.com").await;
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn credentials() {
+        let config = CorsConfig {
+            allow_credentials: true,
+            ..allowing(&["https://app.example.com"])
+        };
+        let response = preflight(&config, "https://app.example.com").await;
+        assert_eq!(
+            response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .unwrap(),
+            "true"
+        );
+
+        // Credentials are never allowed for any origin
+        let config = CorsConfig {
+            allow_credentials: true,
+            ..allowing(&["*"])
+        };
+        let response = preflight(&config, "https://app.example.com").await;
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "*"
+        );
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_CREDENTIALS)
+            .is_none());
+    }
+}
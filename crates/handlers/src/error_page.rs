@@ -0,0 +1,241 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Render the HTML error page of the human-facing routes
+
+use axum::{
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use hyper::header::CONTENT_TYPE;
+use mas_templates::{ErrorContext, Templates};
+
+/// Static page used when the error template itself fails to render
+const FALLBACK_ERROR_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8">
+    <title>Internal server error</title>
+  </head>
+  <body>
+    <h1>Internal server error</h1>
+    <p>Something went wrong while displaying this page. Please try again later.</p>
+  </body>
+</html>
+"#;
+
+/// Build an [`ErrorContext`] for the plain text error responses, like the
+/// rejections of the axum extractors
+///
+/// Their message is shown for client errors. Server errors are not expected
+/// there, so they are logged and their message is kept out of the page.
+async fn plain_error_context(response: Response) -> (Response, Option<ErrorContext>) {
+    let is_plain = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(true, |value| value.starts_with("text/plain"));
+    if !is_plain {
+        return (response, None);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(err) => {
+            tracing::error!(%err, "Failed to read the error response");
+            String::new()
+        }
+    };
+
+    // Forms which don't deserialize are rejected as unprocessable entities,
+    // this is a bad request as far as the user is concerned
+    if parts.status == StatusCode::UNPROCESSABLE_ENTITY {
+        parts.status = StatusCode::BAD_REQUEST;
+    }
+
+    let code = match parts.status {
+        StatusCode::BAD_REQUEST => Some("bad_request"),
+        StatusCode::NOT_FOUND => Some("not_found"),
+        StatusCode::METHOD_NOT_ALLOWED => Some("method_not_allowed"),
+        _ => None,
+    };
+
+    let mut context = ErrorContext::new();
+    if let Some(code) = code {
+        context = context.with_code(code);
+    }
+    if let Some(reason) = parts.status.canonical_reason() {
+        context = context.with_description(reason.to_owned());
+    }
+
+    if parts.status.is_server_error() {
+        tracing::error!(status = %parts.status, %message, "Unexpected error response");
+    } else if !message.is_empty() {
+        context = context.with_details(message);
+    }
+
+    (
+        Response::from_parts(parts, axum::body::boxed(axum::body::Empty::new())),
+        Some(context),
+    )
+}
+
+/// Replace the body of error responses with the rendered error page
+///
+/// The responses with an [`ErrorContext`] attached to them are rendered with
+/// it, and the plain text ones get a generic context from their status. If
+/// rendering the error template fails, a static page is used instead.
+pub(crate) async fn render(templates: &Templates, response: Response) -> Response {
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (response, rendered) = if let Some(ctx) = response.extensions().get::<ErrorContext>() {
+        let rendered = templates.render_error(ctx).await;
+        (response, rendered)
+    } else {
+        match plain_error_context(response).await {
+            (response, Some(ctx)) => {
+                let rendered = templates.render_error(&ctx).await;
+                (response, rendered)
+            }
+            (response, None) => return response,
+        }
+    };
+
+    let (mut parts, _original_body) = response.into_parts();
+    parts.headers.remove(CONTENT_TYPE);
+
+    match rendered {
+        Ok(content) => (parts, Html(content)).into_response(),
+        Err(err) => {
+            tracing::error!(%err, "Failed to render the error page");
+            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            (parts, Html(FALLBACK_ERROR_PAGE)).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_axum_utils::FancyError;
+    use mas_config::TemplatesConfig;
+
+    use super::*;
+
+    async fn body(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn renders_the_error_template() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+
+        let response = FancyError::from("something went wrong").into_response();
+        let response = render(&templates, response).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = body(response).await;
+        assert!(body.contains("something went wrong"));
+        assert_ne!(body, FALLBACK_ERROR_PAGE);
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_the_error_template_is_broken() {
+        // Custom templates take precedence over the builtin ones
+        let path =
+            std::env::temp_dir().join(format!("mas-broken-templates-{}", std::process::id()));
+        tokio::fs::create_dir_all(path.join("pages")).await.unwrap();
+        tokio::fs::write(path.join("pages/error.html"), "{{ this_does_not_exist }}")
+            .await
+            .unwrap();
+
+        let config = TemplatesConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..TemplatesConfig::default()
+        };
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        tokio::fs::remove_dir_all(&path).await.unwrap();
+
+        let response =
+            (StatusCode::NOT_FOUND, axum::Extension(ErrorContext::new())).into_response();
+        let response = render(&templates, response).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body(response).await, FALLBACK_ERROR_PAGE);
+    }
+
+    #[tokio::test]
+    async fn renders_plain_rejections() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+
+        // This is how the form extractor rejects a form with missing fields
+        let response = (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Failed to deserialize form: missing field `csrf`",
+        )
+            .into_response();
+        let response = render(&templates, response).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let content = body(response).await;
+        assert!(content.contains("missing field `csrf`"));
+        assert_ne!(content, FALLBACK_ERROR_PAGE);
+
+        let response = StatusCode::NOT_FOUND.into_response();
+        let response = render(&templates, response).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(body(response).await.contains("Not Found"));
+
+        // Unexpected errors don't leak their message
+        let response = (StatusCode::INTERNAL_SERVER_ERROR, "secret database error").into_response();
+        let response = render(&templates, response).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body(response).await.contains("secret database error"));
+    }
+
+    #[tokio::test]
+    async fn other_responses_are_left_untouched() {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+
+        let response = (StatusCode::OK, "hello").into_response();
+        let response = render(&templates, response).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "hello");
+
+        // JSON errors are meant for the API clients
+        let response = (
+            StatusCode::BAD_REQUEST,
+            axum::Json(serde_json::json!({ "error": "invalid_request" })),
+        )
+            .into_response();
+        let response = render(&templates, response).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(response).await, r#"{"error":"invalid_request"}"#);
+    }
+}
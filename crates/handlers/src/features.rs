@@ -0,0 +1,362 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Feature flags, which the administrators can override while the server is
+//! running
+//!
+//! The flags start with the value from the configuration. The overrides are
+//! only kept in memory: they are lost on restart, and each instance has its
+//! own.
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::{Request, StatusCode};
+use mas_config::{FeaturesConfig, MatrixConfig, OAuth2Config};
+use serde::Serialize;
+
+/// A feature which can be turned off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Password logins through the compatibility API, configured with
+    /// `matrix.password_login_enabled`
+    PasswordLogin,
+
+    /// Logging in and linking accounts through the upstream providers
+    UpstreamLogin,
+
+    /// Registration with a username and a password
+    Registration,
+
+    /// Dynamic client registration, configured with
+    /// `oauth2.registration.enabled`
+    ClientRegistration,
+}
+
+impl Feature {
+    pub const ALL: [Self; 4] = [
+        Self::PasswordLogin,
+        Self::UpstreamLogin,
+        Self::Registration,
+        Self::ClientRegistration,
+    ];
+
+    /// The name of the feature, in the administration API
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PasswordLogin => "password_login",
+            Self::UpstreamLogin => "upstream_login",
+            Self::Registration => "registration",
+            Self::ClientRegistration => "client_registration",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::PasswordLogin => 0,
+            Self::UpstreamLogin => 1,
+            Self::Registration => 2,
+            Self::ClientRegistration => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownFeature;
+
+impl FromStr for Feature {
+    type Err = UnknownFeature;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.name() == s)
+            .ok_or(UnknownFeature)
+    }
+}
+
+/// No override, the configured value is used
+const UNSET: u8 = 0;
+const DISABLED: u8 = 1;
+const ENABLED: u8 = 2;
+
+#[derive(Debug)]
+struct Inner {
+    configured: [bool; 4],
+    overrides: [AtomicU8; 4],
+}
+
+/// The current state of a feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FeatureState {
+    pub feature: Feature,
+
+    /// Whether the feature is enabled, with the override if any
+    pub enabled: bool,
+
+    /// Whether the configuration enables the feature
+    pub configured: bool,
+
+    /// Whether the feature is enabled or disabled regardless of the
+    /// configuration
+    pub overridden: bool,
+}
+
+/// The state of the feature flags.
+///
+/// Clones share the same overrides, so that they can be changed while the
+/// server is running.
+#[derive(Debug, Clone)]
+pub struct FeatureFlags {
+    inner: Arc<Inner>,
+}
+
+impl Default for FeatureFlags {
+    /// All the features enabled
+    fn default() -> Self {
+        Self::new([true; 4])
+    }
+}
+
+impl FeatureFlags {
+    fn new(configured: [bool; 4]) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                configured,
+                overrides: Default::default(),
+            }),
+        }
+    }
+
+    /// Set up the flags from the configuration
+    #[must_use]
+    pub fn from_config(
+        features_config: &FeaturesConfig,
+        matrix_config: &MatrixConfig,
+        oauth2_config: &OAuth2Config,
+    ) -> Self {
+        let mut configured = [true; 4];
+        configured[Feature::PasswordLogin.index()] = matrix_config.password_login_enabled;
+        configured[Feature::UpstreamLogin.index()] = features_config.upstream_login;
+        configured[Feature::Registration.index()] = features_config.registration;
+        configured[Feature::ClientRegistration.index()] = oauth2_config.registration.enabled;
+        Self::new(configured)
+    }
+
+    /// Whether a feature is currently enabled
+    #[must_use]
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        match self.inner.overrides[feature.index()].load(Ordering::Relaxed) {
+            DISABLED => false,
+            ENABLED => true,
+            _ => self.inner.configured[feature.index()],
+        }
+    }
+
+    /// Enable or disable a feature regardless of the configuration, or go back
+    /// to the configured value with `None`
+    pub fn set_override(&self, feature: Feature, enabled: Option<bool>) {
+        let value = match enabled {
+            None => UNSET,
+            Some(false) => DISABLED,
+            Some(true) => ENABLED,
+        };
+        self.inner.overrides[feature.index()].store(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn state(&self, feature: Feature) -> FeatureState {
+        FeatureState {
+            feature,
+            enabled: self.is_enabled(feature),
+            configured: self.inner.configured[feature.index()],
+            overridden: self.inner.overrides[feature.index()].load(Ordering::Relaxed) != UNSET,
+        }
+    }
+
+    #[must_use]
+    pub fn password_login(&self) -> bool {
+        self.is_enabled(Feature::PasswordLogin)
+    }
+
+    #[must_use]
+    pub fn upstream_login(&self) -> bool {
+        self.is_enabled(Feature::UpstreamLogin)
+    }
+
+    #[must_use]
+    pub fn registration(&self) -> bool {
+        self.is_enabled(Feature::Registration)
+    }
+
+    #[must_use]
+    pub fn client_registration(&self) -> bool {
+        self.is_enabled(Feature::ClientRegistration)
+    }
+}
+
+/// Middleware of the routes of a feature, answering with a `404 Not Found`
+/// while it is disabled.
+///
+/// The feature is set with an [`Extension`](axum::Extension) layered around
+/// the middleware.
+pub(crate) async fn require<B>(request: Request<B>, next: Next<B>) -> Response {
+    let flags = request.extensions().get::<FeatureFlags>();
+    let feature = request.extensions().get::<Feature>();
+
+    // A route without its feature set is treated as disabled, to notice it
+    let enabled = match (flags, feature) {
+        (Some(flags), Some(feature)) => flags.is_enabled(*feature),
+        _ => false,
+    };
+
+    if enabled {
+        next.run(request).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Extension, middleware::from_fn, routing::get, Router};
+    use hyper::Body;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn call(flags: &FeatureFlags) -> StatusCode {
+        let router: Router = Router::new()
+            .route(
+                "/register",
+                get(|| async { "registration form" })
+                    .layer(from_fn(require))
+                    .layer(Extension(Feature::Registration)),
+            )
+            .layer(Extension(flags.clone()));
+
+        let request = Request::builder()
+            .uri("/register")
+            .body(Body::empty())
+            .unwrap();
+        router.oneshot(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn names() {
+        for feature in Feature::ALL {
+            assert_eq!(feature.name().parse(), Ok(feature));
+            assert_eq!(
+                serde_json::to_value(feature).unwrap(),
+                serde_json::json!(feature.name())
+            );
+        }
+        assert_eq!("unknown".parse::<Feature>(), Err(UnknownFeature));
+    }
+
+    #[test]
+    fn configured_values() {
+        let features_config = FeaturesConfig {
+            registration: false,
+            ..FeaturesConfig::default()
+        };
+        let matrix_config = MatrixConfig {
+            password_login_enabled: false,
+            ..MatrixConfig::default()
+        };
+        let flags =
+            FeatureFlags::from_config(&features_config, &matrix_config, &OAuth2Config::default());
+
+        assert!(!flags.password_login());
+        assert!(flags.upstream_login());
+        assert!(!flags.registration());
+        assert!(flags.client_registration());
+    }
+
+    #[test]
+    fn overrides() {
+        let flags = FeatureFlags::default();
+        let clone = flags.clone();
+
+        flags.set_override(Feature::Registration, Some(false));
+        assert!(!clone.registration());
+        assert_eq!(
+            clone.state(Feature::Registration),
+            FeatureState {
+                feature: Feature::Registration,
+                enabled: false,
+                configured: true,
+                overridden: true,
+            }
+        );
+
+        // The other features are left alone
+        assert!(clone.password_login());
+        assert!(!clone.state(Feature::PasswordLogin).overridden);
+
+        flags.set_override(Feature::Registration, None);
+        assert!(clone.registration());
+        assert!(!clone.state(Feature::Registration).overridden);
+
+        // A feature disabled in the configuration can be enabled
+        let flags = FeatureFlags::new([false; 4]);
+        flags.set_override(Feature::ClientRegistration, Some(true));
+        assert!(flags.client_registration());
+    }
+
+    #[tokio::test]
+    async fn disabled_features_are_not_found() {
+        let flags = FeatureFlags::default();
+        assert_eq!(call(&flags).await, StatusCode::OK);
+
+        flags.set_override(Feature::Registration, Some(false));
+        assert_eq!(call(&flags).await, StatusCode::NOT_FOUND);
+
+        // Enabling it again restores the route
+        flags.set_override(Feature::Registration, Some(true));
+        assert_eq!(call(&flags).await, StatusCode::OK);
+
+        flags.set_override(Feature::Registration, None);
+        assert_eq!(call(&flags).await, StatusCode::OK);
+
+        // Other features don't matter
+        flags.set_override(Feature::PasswordLogin, Some(false));
+        assert_eq!(call(&flags).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn routes_without_flags_are_not_found() {
+        let router: Router = Router::new().route(
+            "/register",
+            get(|| async { "registration form" }).layer(from_fn(require)),
+        );
+        let request = Request::builder()
+            .uri("/register")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
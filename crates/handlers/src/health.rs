@@ -12,12 +12,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use axum::{extract::Extension, response::IntoResponse};
-use mas_axum_utils::FancyError;
+use axum::{extract::Extension, http::StatusCode, response::IntoResponse};
+use mas_config::EmailConfig;
+use mas_email::MailTransport;
 use sqlx::PgPool;
-use tracing::{info_span, Instrument};
+use tracing::{info_span, warn, Instrument};
 
-pub async fn get(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse, FancyError> {
+/// Liveness check: succeeds as long as the process is able to answer
+pub async fn get() -> impl IntoResponse {
+    "ok"
+}
+
+/// Readiness check: fails if the database, or the mail server if configured
+/// so, is unavailable
+pub async fn ready(
+    Extension(pool): Extension<PgPool>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(mail_transport): Extension<MailTransport>,
+) -> impl IntoResponse {
+    if let Err(e) = check_database(&pool).await {
+        warn!(error = %e, "Database is not ready");
+        return (StatusCode::SERVICE_UNAVAILABLE, "database unavailable");
+    }
+
+    if email_config.readiness_check {
+        if let Err(e) = mail_transport
+            .test_connection()
+            .instrument(info_span!("SMTP health"))
+            .await
+        {
+            warn!(error = %e, "Mail server is not ready");
+            return (StatusCode::SERVICE_UNAVAILABLE, "mail server unavailable");
+        }
+    }
+
+    (StatusCode::OK, "ok")
+}
+
+async fn check_database(pool: &PgPool) -> Result<(), sqlx::Error> {
     let mut conn = pool.acquire().await?;
 
     sqlx::query("SELECT $1")
@@ -26,5 +58,39 @@ pub async fn get(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse
         .instrument(info_span!("DB health"))
         .await?;
 
-    Ok("ok")
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::Response;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    async fn closed_pool() -> PgPool {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/mas")
+            .unwrap();
+        pool.close().await;
+        pool
+    }
+
+    #[tokio::test]
+    async fn health_does_not_need_the_database() {
+        let response: Response = get().await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn ready_fails_without_the_database() {
+        let response = ready(
+            Extension(closed_pool().await),
+            Extension(EmailConfig::default()),
+            Extension(MailTransport::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
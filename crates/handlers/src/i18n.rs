@@ -0,0 +1,73 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Negotiation of the language of the pages with the browser
+
+use hyper::{header::ACCEPT_LANGUAGE, HeaderMap};
+use language_tags::LanguageTag;
+
+/// The languages from the `Accept-Language` header, most preferred first.
+/// Wildcards and invalid tags are skipped
+pub(crate) fn accepted_languages(headers: &HeaderMap) -> Vec<LanguageTag> {
+    let mut languages: Vec<(LanguageTag, f32)> = headers
+        .get_all(ACCEPT_LANGUAGE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let tag = parts.next()?.parse().ok()?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+
+    // The sort is stable, so languages with the same quality keep their order
+    languages.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    languages.into_iter().map(|(tag, _)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn accept_language_parsing() {
+        let mut headers = HeaderMap::new();
+        assert!(accepted_languages(&headers).is_empty());
+
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            HeaderValue::from_static("fr-CH, fr;q=0.9, en;q=0.8, de;q=0.7, *;q=0.5, it;q=0"),
+        );
+        let languages: Vec<_> = accepted_languages(&headers)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(languages, vec!["fr-CH", "fr", "en", "de"]);
+
+        headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static("en;q=0.5, de"));
+        let languages: Vec<_> = accepted_languages(&headers)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(languages, vec!["de", "en"]);
+    }
+}
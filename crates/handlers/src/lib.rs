@@ -19,32 +19,62 @@
     clippy::unused_async // Some axum handlers need that
 )]
 
-use std::{convert::Infallible, sync::Arc, time::Duration};
+use std::{convert::Infallible, sync::Arc};
 
 use axum::{
     body::HttpBody,
     extract::Extension,
-    response::{Html, IntoResponse},
-    routing::{get, on, post, MethodFilter},
+    middleware::from_fn,
+    routing::{get, on, post, put, MethodFilter},
     Router,
 };
 use headers::HeaderName;
-use hyper::header::{ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, CONTENT_TYPE};
-use mas_config::{Encrypter, MatrixConfig};
-use mas_email::Mailer;
-use mas_http::CorsLayerExt;
+use hyper::{
+    header::{ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_LANGUAGE, CONTENT_TYPE},
+    Request,
+};
+use mas_axum_utils::csrf::CsrfProtection;
+use mas_config::{
+    AdminConfig, ChallengeConfig, CookiesConfig, CorsConfig, CspConfig, CsrfConfig, EmailConfig,
+    Encrypter, MatrixConfig, OAuth2Config, PasswordsConfig, SessionsConfig, UpstreamOAuth2Config,
+    UsernamesConfig,
+};
+use mas_email::{MailQueue, MailTransport};
 use mas_jose::StaticKeystore;
 use mas_policy::PolicyFactory;
 use mas_router::{Route, UrlBuilder};
-use mas_templates::{ErrorContext, Templates};
-use sqlx::PgPool;
-use tower::util::ThenLayer;
-use tower_http::cors::{Any, CorsLayer};
+use mas_storage::Database;
+use mas_templates::Templates;
+use tower::util::{MapRequestLayer, MapResponseLayer, ThenLayer};
 
+mod admin;
+mod challenge;
+mod client_cache;
 mod compat;
+mod cookies;
+mod cors;
+mod error_page;
+mod features;
 mod health;
+mod i18n;
+mod maintenance;
+mod metrics;
 mod oauth2;
+mod request_id;
+mod security_headers;
+mod session_expiration;
+mod upstream_oauth2;
 mod views;
+mod webhooks;
+
+pub use self::{
+    client_cache::ClientCache,
+    features::{Feature, FeatureFlags, FeatureState, UnknownFeature},
+    maintenance::MaintenanceMode,
+    oauth2::registration::bind_client_secrets,
+    views::account::totp::{bind_totp_secrets, encrypt_plaintext_totp_secrets},
+    webhooks::{WebhookDispatcher, WebhookEvent, Webhooks},
+};
 
 #[must_use]
 #[allow(
@@ -53,13 +83,30 @@ mod views;
     clippy::too_many_arguments
 )]
 pub fn router<B>(
-    pool: &PgPool,
+    database: &Database,
     templates: &Templates,
     key_store: &Arc<StaticKeystore>,
     encrypter: &Encrypter,
-    mailer: &Mailer,
+    csrf_config: &CsrfConfig,
+    mail_queue: &MailQueue,
+    mail_transport: &MailTransport,
+    email_config: &EmailConfig,
     url_builder: &UrlBuilder,
     matrix_config: &MatrixConfig,
+    passwords_config: &PasswordsConfig,
+    usernames_config: &UsernamesConfig,
+    challenge_config: &ChallengeConfig,
+    oauth2_config: &OAuth2Config,
+    upstream_oauth2_config: &UpstreamOAuth2Config,
+    admin_config: &AdminConfig,
+    sessions_config: &SessionsConfig,
+    client_cache: &ClientCache,
+    webhooks: &Webhooks,
+    feature_flags: &FeatureFlags,
+    cors_config: &CorsConfig,
+    cookies_config: &CookiesConfig,
+    csp_config: &CspConfig,
+    maintenance: &MaintenanceMode,
     policy_factory: &Arc<PolicyFactory>,
 ) -> Router<B>
 where
@@ -85,6 +132,10 @@ where
             mas_router::OAuth2Keys::route(),
             get(self::oauth2::keys::get),
         )
+        .route(
+            mas_router::WellKnownJwks::route(),
+            get(self::oauth2::keys::get),
+        )
         .route(
             mas_router::OidcUserinfo::route(),
             on(
@@ -100,28 +151,37 @@ where
             mas_router::OAuth2TokenEndpoint::route(),
             post(self::oauth2::token::post),
         )
+        .route(
+            mas_router::OAuth2Revocation::route(),
+            post(self::oauth2::revocation::post),
+        )
         .route(
             mas_router::OAuth2RegistrationEndpoint::route(),
             post(self::oauth2::registration::post),
         )
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_otel_headers([
-                    AUTHORIZATION,
-                    ACCEPT,
-                    ACCEPT_LANGUAGE,
-                    CONTENT_LANGUAGE,
-                    CONTENT_TYPE,
-                ])
-                .max_age(Duration::from_secs(60 * 60)),
-        );
+        .layer(from_fn(self::maintenance::api))
+        .layer(self::cors::layer(
+            cors_config,
+            [
+                AUTHORIZATION,
+                ACCEPT,
+                ACCEPT_LANGUAGE,
+                CONTENT_LANGUAGE,
+                CONTENT_TYPE,
+            ],
+        ));
 
     let compat_router = Router::new()
         .route(
             mas_router::CompatLogin::route(),
-            get(self::compat::login::get).post(self::compat::login::post),
+            get(self::compat::login::get)
+                .post(self::compat::login::post)
+                .layer(Extension(self::compat::idempotency::IdempotencyCache::<
+                    self::compat::login::ResponseBody,
+                >::default()))
+                .layer(Extension(self::challenge::compat_from_config(
+                    challenge_config,
+                ))),
         )
         .route(
             mas_router::CompatLogout::route(),
@@ -131,30 +191,61 @@ where
             mas_router::CompatRefresh::route(),
             post(self::compat::refresh::post),
         )
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_otel_headers([
-                    AUTHORIZATION,
-                    ACCEPT,
-                    ACCEPT_LANGUAGE,
-                    CONTENT_LANGUAGE,
-                    CONTENT_TYPE,
-                    HeaderName::from_static("x-requested-with"),
-                ])
-                .max_age(Duration::from_secs(60 * 60)),
-        );
+        .route(
+            mas_router::CompatWhoami::route(),
+            get(self::compat::whoami::get).layer(Extension(self::compat::whoami::SCOPE)),
+        )
+        .layer(from_fn(self::maintenance::api))
+        .layer(self::cors::layer(
+            cors_config,
+            [
+                AUTHORIZATION,
+                ACCEPT,
+                ACCEPT_LANGUAGE,
+                CONTENT_LANGUAGE,
+                CONTENT_TYPE,
+                HeaderName::from_static("x-requested-with"),
+                self::compat::idempotency::IDEMPOTENCY_KEY.clone(),
+            ],
+        ));
+
+    let admin_router = Router::new()
+        .route(mas_router::AdminUsers::route(), get(self::admin::list))
+        .route(
+            mas_router::AdminDeactivateUser::route(),
+            post(self::admin::deactivate),
+        )
+        .route(
+            mas_router::AdminReactivateUser::route(),
+            post(self::admin::reactivate),
+        )
+        .route(
+            mas_router::AdminFeatures::route(),
+            get(self::admin::list_features),
+        )
+        .route(
+            mas_router::AdminFeature::route(),
+            put(self::admin::set_feature),
+        )
+        .layer(from_fn(self::maintenance::api));
+
+    // The health checks are still served during maintenance
+    let health_router = Router::new()
+        .route(mas_router::Healthcheck::route(), get(self::health::get))
+        .route(mas_router::Readiness::route(), get(self::health::ready));
 
     let human_router = {
         let templates = templates.clone();
         Router::new()
             .route(mas_router::Index::route(), get(self::views::index::get))
-            .route(mas_router::Healthcheck::route(), get(self::health::get))
             .route(
                 mas_router::Login::route(),
                 get(self::views::login::get).post(self::views::login::post),
             )
+            .route(
+                mas_router::WebauthnLogin::route(),
+                get(self::views::webauthn::login_get).post(self::views::webauthn::login_post),
+            )
             .route(mas_router::Logout::route(), post(self::views::logout::post))
             .route(
                 mas_router::Reauth::route(),
@@ -162,17 +253,53 @@ where
             )
             .route(
                 mas_router::Register::route(),
-                get(self::views::register::get).post(self::views::register::post),
+                get(self::views::register::get)
+                    .post(self::views::register::post)
+                    .layer(from_fn(self::features::require))
+                    .layer(Extension(Feature::Registration)),
             )
             .route(mas_router::Account::route(), get(self::views::account::get))
             .route(
                 mas_router::AccountPassword::route(),
                 get(self::views::account::password::get).post(self::views::account::password::post),
             )
+            .route(
+                mas_router::AccountPasswordReset::route(),
+                get(self::views::password_reset::get).post(self::views::password_reset::post),
+            )
+            .route(
+                mas_router::AccountPasswordResetComplete::route(),
+                get(self::views::password_reset::complete_get)
+                    .post(self::views::password_reset::complete_post),
+            )
+            .route(
+                mas_router::AccountSessions::route(),
+                get(self::views::account::sessions::get).post(self::views::account::sessions::post),
+            )
+            .route(
+                mas_router::AccountUpstream::route(),
+                get(self::views::account::upstream::get).post(self::views::account::upstream::post),
+            )
+            .route(
+                mas_router::AccountTotp::route(),
+                get(self::views::account::totp::get).post(self::views::account::totp::post),
+            )
+            .route(
+                mas_router::AccountWebauthnRegister::route(),
+                get(self::views::webauthn::register_get).post(self::views::webauthn::register_post),
+            )
             .route(
                 mas_router::AccountEmails::route(),
                 get(self::views::account::emails::get).post(self::views::account::emails::post),
             )
+            .route(
+                mas_router::AccountDelete::route(),
+                get(self::views::account::delete::get).post(self::views::account::delete::post),
+            )
+            .route(
+                mas_router::AccountExport::route(),
+                get(self::views::account::export::get),
+            )
             .route(
                 mas_router::AccountVerifyEmail::route(),
                 get(self::views::account::emails::verify::get)
@@ -201,43 +328,101 @@ where
             )
             .route(
                 mas_router::CompatLoginSsoRedirectIdp::route(),
-                get(self::compat::login_sso_redirect::get),
+                get(self::compat::login_sso_redirect::get_idp)
+                    .layer(from_fn(self::features::require))
+                    .layer(Extension(Feature::UpstreamLogin)),
             )
             .route(
                 mas_router::CompatLoginSsoComplete::route(),
                 get(self::compat::login_sso_complete::get)
                     .post(self::compat::login_sso_complete::post),
             )
+            .route(
+                mas_router::UpstreamOAuth2Callback::route(),
+                get(self::upstream_oauth2::callback)
+                    .layer(from_fn(self::features::require))
+                    .layer(Extension(Feature::UpstreamLogin)),
+            )
+            .layer(from_fn(self::session_expiration::html))
+            .layer(from_fn(self::maintenance::html))
             .layer(ThenLayer::new(
                 move |result: Result<axum::response::Response, Infallible>| async move {
-                    let response = result.unwrap();
-
-                    if response.status().is_server_error() {
-                        // Error responses should have an ErrorContext attached to them
-                        let ext = response.extensions().get::<ErrorContext>();
-                        if let Some(ctx) = ext {
-                            if let Ok(res) = templates.render_error(ctx).await {
-                                let (mut parts, _original_body) = response.into_parts();
-                                parts.headers.remove(CONTENT_TYPE);
-                                return Ok((parts, Html(res)).into_response());
-                            }
-                        }
-                    }
-
-                    Ok(response)
+                    let response = match result {
+                        Ok(response) => response,
+                        Err(e) => match e {},
+                    };
+                    Ok(error_page::render(&templates, response).await)
                 },
             ))
+            .layer(from_fn(self::security_headers::html))
     };
 
     human_router
         .merge(api_router)
         .merge(compat_router)
-        .layer(Extension(pool.clone()))
+        .merge(admin_router)
+        .merge(health_router)
+        .layer(Extension(maintenance.clone()))
+        .layer(Extension(database.primary().clone()))
+        .layer(Extension(database.clone()))
+        .layer(Extension(client_cache.clone()))
+        .layer(Extension(webhooks.clone()))
+        .layer(Extension(feature_flags.clone()))
         .layer(Extension(templates.clone()))
         .layer(Extension(key_store.clone()))
         .layer(Extension(encrypter.clone()))
-        .layer(Extension(url_builder.clone()))
-        .layer(Extension(mailer.clone()))
+        .layer(Extension(CsrfProtection::from_config(
+            csrf_config,
+            encrypter,
+        )))
+        .layer(MapRequestLayer::new({
+            let url_builder = url_builder.clone();
+            move |mut request: Request<B>| {
+                // Build URLs from the forwarded headers, if they are trusted
+                let url_builder = url_builder.for_request(request.headers());
+                request.extensions_mut().insert(url_builder);
+                request
+            }
+        }))
+        .layer(MapRequestLayer::new({
+            let cookie_policy = self::cookies::CookiePolicy::from_config(cookies_config);
+            let encrypter = encrypter.clone();
+            move |mut request: Request<B>| {
+                cookie_policy.strip_prefix(request.headers_mut());
+                self::cookies::upgrade_encryption(&encrypter, request.headers_mut());
+                request
+            }
+        }))
+        .layer(MapResponseLayer::new({
+            let cookie_policy = self::cookies::CookiePolicy::from_config(cookies_config);
+            move |mut response: axum::response::Response| {
+                cookie_policy.apply(response.headers_mut());
+                response
+            }
+        }))
+        .layer(Extension(mail_queue.clone()))
+        .layer(Extension(mail_transport.clone()))
+        .layer(Extension(email_config.clone()))
         .layer(Extension(matrix_config.clone()))
+        .layer(Extension(passwords_config.clone()))
+        .layer(Extension(usernames_config.clone()))
+        .layer(Extension(self::challenge::from_config(challenge_config)))
+        .layer(Extension(self::security_headers::SecurityHeaders::new(
+            csp_config,
+            &self::challenge::from_config(challenge_config),
+        )))
+        .layer(Extension(oauth2_config.clone()))
+        .layer(Extension(self::upstream_oauth2::from_config(
+            upstream_oauth2_config,
+        )))
+        .layer(Extension(admin_config.clone()))
+        .layer(Extension(sessions_config.clone()))
+        .layer(Extension(
+            self::views::account::export::ExportLimiter::default(),
+        ))
+        .layer(Extension(
+            self::session_expiration::SessionExpiration::from_config(sessions_config),
+        ))
         .layer(Extension(policy_factory.clone()))
+        .layer(from_fn(self::request_id::middleware))
 }
@@ -0,0 +1,338 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Maintenance mode, during which only the health checks and the requests of
+//! the administrators are served
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::ConnectInfo,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::{Request, StatusCode};
+use mas_config::MaintenanceConfig;
+use mas_templates::ErrorContext;
+use serde::Serialize;
+
+/// Shown when no message is configured
+const DEFAULT_MESSAGE: &str = "The service is down for maintenance, please try again later";
+
+#[derive(Debug)]
+struct Inner {
+    enabled: AtomicBool,
+    message: String,
+    allowed_ips: Vec<IpAddr>,
+    trust_forwarded_headers: bool,
+}
+
+/// Whether the service is in maintenance mode.
+///
+/// Clones share the same flag, so that it can be toggled while the server is
+/// running.
+#[derive(Debug, Clone)]
+pub struct MaintenanceMode {
+    inner: Arc<Inner>,
+}
+
+impl MaintenanceMode {
+    /// Set up the maintenance mode from the configuration.
+    ///
+    /// If `trust_forwarded_headers` is set, the address of the client is taken
+    /// from the `X-Forwarded-For` header to check the allowed IPs.
+    #[must_use]
+    pub fn new(config: &MaintenanceConfig, trust_forwarded_headers: bool) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                enabled: AtomicBool::new(config.enabled),
+                message: config
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_MESSAGE.to_owned()),
+                allowed_ips: config.allowed_ips.iter().copied().map(canonical).collect(),
+                trust_forwarded_headers,
+            }),
+        }
+    }
+
+    /// Whether the service is currently in maintenance mode
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enable or disable the maintenance mode
+    pub fn set_enabled(&self, enabled: bool) {
+        self.inner.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Toggle the maintenance mode, returning whether it is now enabled
+    #[must_use]
+    pub fn toggle(&self) -> bool {
+        !self.inner.enabled.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    fn client_ip<B>(&self, request: &Request<B>) -> Option<IpAddr> {
+        let forwarded = if self.inner.trust_forwarded_headers {
+            // Each proxy appends the address it got the request from. Only the
+            // last one, added by the trusted proxy, can't be forged by the
+            // client
+            request
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+        } else {
+            None
+        };
+
+        forwarded
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(address)| address.ip())
+            })
+            .map(canonical)
+    }
+
+    /// Whether a request should be turned away
+    fn blocks<B>(&self, request: &Request<B>) -> bool {
+        self.is_enabled()
+            && !self
+                .client_ip(request)
+                .map_or(false, |ip| self.inner.allowed_ips.contains(&ip))
+    }
+}
+
+/// IPv4 clients of a server listening on IPv6 show up as IPv4-mapped
+/// addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn blocked<B>(request: &Request<B>) -> Option<String> {
+    request
+        .extensions()
+        .get::<MaintenanceMode>()
+        .filter(|maintenance| maintenance.blocks(request))
+        .map(|maintenance| maintenance.inner.message.clone())
+}
+
+/// Middleware of the HTML pages, answering with the error page while in
+/// maintenance mode
+pub(crate) async fn html<B: Send>(request: Request<B>, next: Next<B>) -> Response {
+    if let Some(message) = blocked(&request) {
+        let context = ErrorContext::new()
+            .with_code("maintenance")
+            .with_description(message);
+        (StatusCode::SERVICE_UNAVAILABLE, Extension(context)).into_response()
+    } else {
+        next.run(request).await
+    }
+}
+
+#[derive(Serialize)]
+struct MaintenanceError {
+    errcode: &'static str,
+    error: String,
+}
+
+/// Middleware of the API endpoints, answering with a Matrix-style error while
+/// in maintenance mode
+pub(crate) async fn api<B: Send>(request: Request<B>, next: Next<B>) -> Response {
+    if let Some(message) = blocked(&request) {
+        let error = MaintenanceError {
+            errcode: "M_UNKNOWN",
+            error: message,
+        };
+        (StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response()
+    } else {
+        next.run(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use axum::{
+        middleware::from_fn,
+        routing::{get, post},
+        Router,
+    };
+    use hyper::{header::CONTENT_TYPE, Body};
+    use mas_config::TemplatesConfig;
+    use mas_templates::Templates;
+    use tower::{util::ThenLayer, ServiceExt};
+
+    use super::*;
+
+    async fn app(maintenance: &MaintenanceMode) -> Router {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+
+        let human_router = Router::new()
+            .route("/account", get(|| async { "account" }))
+            .layer(from_fn(html))
+            .layer(ThenLayer::new(
+                move |result: Result<Response, Infallible>| async move {
+                    Ok(crate::error_page::render(&templates, result.unwrap()).await)
+                },
+            ));
+
+        let api_router = Router::new()
+            .route("/oauth2/token", post(|| async { "token" }))
+            .layer(from_fn(api));
+
+        Router::new()
+            .route("/health", get(crate::health::get))
+            .merge(human_router)
+            .merge(api_router)
+            .layer(Extension(maintenance.clone()))
+    }
+
+    async fn call(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn from_ip(mut request: Request<Body>, ip: &str) -> Request<Body> {
+        let address = SocketAddr::new(ip.parse().unwrap(), 54321);
+        request.extensions_mut().insert(ConnectInfo(address));
+        request
+    }
+
+    #[tokio::test]
+    async fn only_health_is_served_during_maintenance() {
+        let config = MaintenanceConfig {
+            enabled: true,
+            message: Some("Back in an hour".to_owned()),
+            allowed_ips: Vec::new(),
+        };
+        let maintenance = MaintenanceMode::new(&config, false);
+        let app = app(&maintenance).await;
+
+        let (status, body) = call(&app, request("GET", "/account")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("Back in an hour"));
+        assert!(body.contains("<html"));
+
+        let response = app
+            .clone()
+            .oneshot(request("POST", "/oauth2/token"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "errcode": "M_UNKNOWN", "error": "Back in an hour" })
+        );
+
+        assert_eq!(
+            call(&app, request("GET", "/health")).await,
+            (StatusCode::OK, "ok".to_owned())
+        );
+
+        // Everything is back once the maintenance mode is disabled
+        assert!(!maintenance.toggle());
+        assert_eq!(
+            call(&app, request("GET", "/account")).await,
+            (StatusCode::OK, "account".to_owned())
+        );
+        assert_eq!(
+            call(&app, request("POST", "/oauth2/token")).await,
+            (StatusCode::OK, "token".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn administrators_bypass_maintenance() {
+        let config = MaintenanceConfig {
+            enabled: true,
+            message: None,
+            allowed_ips: vec!["192.0.2.1".parse().unwrap()],
+        };
+        let maintenance = MaintenanceMode::new(&config, false);
+        let app = app(&maintenance).await;
+
+        let (status, _) = call(&app, from_ip(request("GET", "/account"), "192.0.2.1")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // IPv4 clients of a server listening on IPv6
+        let (status, _) = call(
+            &app,
+            from_ip(request("POST", "/oauth2/token"), "::ffff:192.0.2.1"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = call(&app, from_ip(request("GET", "/account"), "192.0.2.2")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains(DEFAULT_MESSAGE));
+
+        // The forwarded address is only used when trusted
+        let forwarded = |header: &str| {
+            let mut req = from_ip(request("GET", "/account"), "203.0.113.1");
+            req.headers_mut()
+                .insert("x-forwarded-for", header.parse().unwrap());
+            req
+        };
+        let (status, _) = call(&app, forwarded("192.0.2.1")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let app = self::app(&MaintenanceMode::new(&config, true)).await;
+        let (status, _) = call(&app, forwarded("192.0.2.1")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Only the address added by the proxy is used, not the ones sent by
+        // the client
+        let (status, _) = call(&app, forwarded("192.0.2.1, 198.51.100.1")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics recorded by the handlers
+
+use once_cell::sync::Lazy;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+
+static COMPAT_LOGIN_TOTAL: Lazy<Counter<u64>> = Lazy::new(|| {
+    global::meter("mas-handlers")
+        .u64_counter("compat_login_total")
+        .with_description("Number of logins through the Matrix compatibility API")
+        .init()
+});
+
+/// Count a login through the Matrix compatibility API, by result
+pub(crate) fn compat_login(result: &'static str) {
+    COMPAT_LOGIN_TOTAL.add(1, &[KeyValue::new("result", result)]);
+}
+
+static CLIENT_CACHE_TOTAL: Lazy<Counter<u64>> = Lazy::new(|| {
+    global::meter("mas-handlers")
+        .u64_counter("client_cache_total")
+        .with_description("Number of client lookups through the cache")
+        .init()
+});
+
+/// Count a client lookup through the cache, by result: `hit` or `miss`
+pub(crate) fn client_cache(result: &'static str) {
+    CLIENT_CACHE_TOTAL.add(1, &[KeyValue::new("result", result)]);
+}
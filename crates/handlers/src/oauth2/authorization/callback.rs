@@ -24,6 +24,8 @@ use serde::Serialize;
 use thiserror::Error;
 use url::Url;
 
+use crate::security_headers::CspNonce;
+
 #[derive(Debug, Clone)]
 enum CallbackDestinationMode {
     Query {
@@ -107,6 +109,7 @@ impl CallbackDestination {
     pub async fn go<T: Serialize>(
         self,
         templates: &Templates,
+        csp_nonce: &CspNonce,
         params: T,
     ) -> Result<Response, CallbackDestinationError> {
         #[derive(Serialize)]
@@ -159,7 +162,8 @@ impl CallbackDestination {
                     state,
                     params,
                 };
-                let ctx = FormPostContext::new(redirect_uri, merged);
+                // The form is submitted by an inline script
+                let ctx = FormPostContext::new(redirect_uri, merged).with_csp_nonce(csp_nonce);
                 let rendered = templates.render_form_post(&ctx).await?;
                 Ok(Html(rendered).into_response())
             }
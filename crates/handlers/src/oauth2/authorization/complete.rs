@@ -22,7 +22,7 @@ use axum_extra::extract::PrivateCookieJar;
 use chrono::Duration;
 use hyper::StatusCode;
 use mas_axum_utils::SessionInfoExt;
-use mas_config::Encrypter;
+use mas_config::{EmailConfig, Encrypter};
 use mas_data_model::{AuthorizationGrant, BrowserSession, TokenType};
 use mas_router::{PostAuthAction, Route};
 use mas_storage::{
@@ -42,6 +42,7 @@ use sqlx::{PgPool, Postgres, Transaction};
 use thiserror::Error;
 
 use super::callback::{CallbackDestination, CallbackDestinationError, InvalidRedirectUriError};
+use crate::security_headers::CspNonce;
 
 #[derive(Debug, Error)]
 pub enum RouteError {
@@ -107,6 +108,8 @@ impl From<CallbackDestinationError> for RouteError {
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(csp_nonce): Extension<CspNonce>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Path(grant_id): Path<i64>,
 ) -> Result<Response, RouteError> {
@@ -129,9 +132,11 @@ pub(crate) async fn get(
         return Ok((cookie_jar, mas_router::Login::and_then(continue_grant).go()).into_response());
     };
 
-    match complete(grant, session, txn).await {
+    match complete(grant, session, &email_config, txn).await {
         Ok(params) => {
-            let res = callback_destination.go(&templates, params).await?;
+            let res = callback_destination
+                .go(&templates, &csp_nonce, params)
+                .await?;
             Ok((cookie_jar, res).into_response())
         }
         Err(GrantCompletionError::RequiresReauth) => Ok((
@@ -143,6 +148,9 @@ pub(crate) async fn get(
             let next = mas_router::Consent(grant_id);
             Ok((cookie_jar, next.go()).into_response())
         }
+        Err(GrantCompletionError::RequiresEmailVerification) => {
+            Ok((cookie_jar, mas_router::AccountEmails.go()).into_response())
+        }
         Err(GrantCompletionError::NotPending) => Err(RouteError::NotPending),
         Err(GrantCompletionError::Internal(e)) => Err(RouteError::Internal(e)),
         Err(GrantCompletionError::Anyhow(e)) => Err(RouteError::Anyhow(e)),
@@ -165,6 +173,9 @@ pub enum GrantCompletionError {
 
     #[error("client lacks consent")]
     RequiresConsent,
+
+    #[error("user needs to verify an email address")]
+    RequiresEmailVerification,
 }
 
 impl From<sqlx::Error> for GrantCompletionError {
@@ -182,6 +193,7 @@ impl From<InvalidRedirectUriError> for GrantCompletionError {
 pub(crate) async fn complete(
     grant: AuthorizationGrant<PostgresqlBackend>,
     browser_session: BrowserSession<PostgresqlBackend>,
+    email_config: &EmailConfig,
     mut txn: Transaction<'_, Postgres>,
 ) -> Result<AuthorizationResponse<Option<AccessTokenResponse>>, GrantCompletionError> {
     // Verify that the grant is in a pending stage
@@ -195,16 +207,16 @@ pub(crate) async fn complete(
         return Err(GrantCompletionError::RequiresReauth);
     }
 
+    if email_config.require_verification && !browser_session.user.has_verified_email() {
+        txn.commit().await?;
+        return Err(GrantCompletionError::RequiresEmailVerification);
+    }
+
     let current_consent =
         fetch_client_consent(&mut txn, &browser_session.user, &grant.client).await?;
 
-    let lacks_consent = grant
-        .scope
-        .difference(&current_consent)
-        .any(|scope| !scope.starts_with("urn:matrix:device:"));
-
     // Check if the client lacks consent *or* if consent was explicitely asked
-    if lacks_consent || grant.requires_consent {
+    if grant.needs_consent(&current_consent) {
         txn.commit().await?;
         return Err(GrantCompletionError::RequiresConsent);
     }
@@ -18,16 +18,14 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
 use hyper::StatusCode;
 use mas_axum_utils::SessionInfoExt;
-use mas_config::Encrypter;
+use mas_config::{EmailConfig, Encrypter, OAuth2Config};
 use mas_data_model::{AuthorizationCode, Device, Pkce};
-use mas_iana::oauth::OAuthAuthorizationEndpointResponseType;
+use mas_iana::oauth::{OAuthAuthorizationEndpointResponseType, PkceCodeChallengeMethod};
 use mas_router::{PostAuthAction, Route};
-use mas_storage::oauth2::{
-    authorization_grant::new_authorization_grant,
-    client::{lookup_client_by_client_id, ClientFetchError},
-};
+use mas_storage::oauth2::{authorization_grant::new_authorization_grant, client::ClientFetchError};
 use mas_templates::Templates;
 use oauth2_types::{
     errors::{
@@ -45,6 +43,7 @@ use sqlx::PgPool;
 use thiserror::Error;
 
 use self::{callback::CallbackDestination, complete::GrantCompletionError};
+use crate::{security_headers::CspNonce, ClientCache};
 
 mod callback;
 pub mod complete;
@@ -155,17 +154,40 @@ fn resolve_response_mode(
     }
 }
 
-#[allow(clippy::too_many_lines)]
+/// Check the PKCE parameters of a request asking for an authorization code.
+///
+/// Clients which can't authenticate must use PKCE, and the server can be
+/// configured to require it from all the clients, with the `S256` method.
+fn is_pkce_acceptable(
+    pkce: Option<&pkce::AuthorizationRequest>,
+    client_requires_pkce: bool,
+    config: &OAuth2Config,
+) -> bool {
+    match pkce {
+        None => !(client_requires_pkce || config.require_pkce_s256),
+        Some(pkce) => {
+            !config.require_pkce_s256 || pkce.code_challenge_method == PkceCodeChallengeMethod::S256
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(oauth2_config): Extension<OAuth2Config>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(client_cache): Extension<ClientCache>,
+    Extension(csp_nonce): Extension<CspNonce>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(params): Form<Params>,
 ) -> Result<Response, RouteError> {
     let mut txn = pool.begin().await?;
 
     // First, figure out what client it is
-    let client = lookup_client_by_client_id(&mut txn, &params.auth.client_id).await?;
+    let client = client_cache
+        .lookup(&mut txn, &params.auth.client_id)
+        .await?;
 
     // And resolve the redirect_uri and response_mode
     let redirect_uri = client
@@ -187,6 +209,7 @@ pub(crate) async fn get(
     // One day, we will have try blocks
     let res: Result<Response, RouteError> = ({
         let templates = templates.clone();
+        let csp_nonce = csp_nonce.clone();
         let callback_destination = callback_destination.clone();
         async move {
             let maybe_session = session_info
@@ -198,35 +221,47 @@ pub(crate) async fn get(
             // with the right error since we don't support them.
             if params.auth.request.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REQUEST_NOT_SUPPORTED)
+                    .go(&templates, &csp_nonce, REQUEST_NOT_SUPPORTED)
                     .await?);
             }
 
             if params.auth.request_uri.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REQUEST_URI_NOT_SUPPORTED)
+                    .go(&templates, &csp_nonce, REQUEST_URI_NOT_SUPPORTED)
                     .await?);
             }
 
             if params.auth.registration.is_some() {
                 return Ok(callback_destination
-                    .go(&templates, REGISTRATION_NOT_SUPPORTED)
+                    .go(&templates, &csp_nonce, REGISTRATION_NOT_SUPPORTED)
                     .await?);
             }
 
             // Check if it is allowed to use this grant type
             if !client.grant_types.contains(&GrantType::AuthorizationCode) {
                 return Ok(callback_destination
-                    .go(&templates, UNAUTHORIZED_CLIENT)
+                    .go(&templates, &csp_nonce, UNAUTHORIZED_CLIENT)
                     .await?);
             }
 
             // Fail early if prompt=none and there is no active session
             if params.auth.prompt == Some(Prompt::None) && maybe_session.is_none() {
-                return Ok(callback_destination.go(&templates, LOGIN_REQUIRED).await?);
+                return Ok(callback_destination
+                    .go(&templates, &csp_nonce, LOGIN_REQUIRED)
+                    .await?);
             }
 
             let code: Option<AuthorizationCode> = if response_type.has_code() {
+                if !is_pkce_acceptable(
+                    params.pkce.as_ref(),
+                    client.client_type.requires_pkce(),
+                    &oauth2_config,
+                ) {
+                    return Ok(callback_destination
+                        .go(&templates, &csp_nonce, INVALID_REQUEST)
+                        .await?);
+                }
+
                 // 32 random alphanumeric characters, about 190bit of entropy
                 let code: String = thread_rng()
                     .sample_iter(&Alphanumeric)
@@ -239,12 +274,14 @@ pub(crate) async fn get(
                     challenge_method: p.code_challenge_method,
                 });
 
-                Some(AuthorizationCode { code, pkce })
+                Some(AuthorizationCode::new(code, pkce, Utc::now()))
             } else {
                 // If the request had PKCE params but no code asked, it should get back with an
                 // error
                 if params.pkce.is_some() {
-                    return Ok(callback_destination.go(&templates, INVALID_REQUEST).await?);
+                    return Ok(callback_destination
+                        .go(&templates, &csp_nonce, INVALID_REQUEST)
+                        .await?);
                 }
 
                 None
@@ -316,16 +353,23 @@ pub(crate) async fn get(
                 // Else, we immediately try to complete the authorization grant
                 (Some(user_session), Some(Prompt::None)) => {
                     // With prompt=none, we should get back to the client immediately
-                    match self::complete::complete(grant, user_session, txn).await {
-                        Ok(params) => callback_destination.go(&templates, params).await?,
+                    match self::complete::complete(grant, user_session, &email_config, txn).await {
+                        Ok(params) => {
+                            callback_destination
+                                .go(&templates, &csp_nonce, params)
+                                .await?
+                        }
                         Err(GrantCompletionError::RequiresConsent) => {
                             callback_destination
-                                .go(&templates, CONSENT_REQUIRED)
+                                .go(&templates, &csp_nonce, CONSENT_REQUIRED)
                                 .await?
                         }
-                        Err(GrantCompletionError::RequiresReauth) => {
+                        Err(
+                            GrantCompletionError::RequiresReauth
+                            | GrantCompletionError::RequiresEmailVerification,
+                        ) => {
                             callback_destination
-                                .go(&templates, INTERACTION_REQUIRED)
+                                .go(&templates, &csp_nonce, INTERACTION_REQUIRED)
                                 .await?
                         }
                         Err(GrantCompletionError::Anyhow(a)) => return Err(RouteError::Anyhow(a)),
@@ -341,8 +385,12 @@ pub(crate) async fn get(
                 (Some(user_session), _) => {
                     let grant_id = grant.data;
                     // Else, we show the relevant reauth/consent page if necessary
-                    match self::complete::complete(grant, user_session, txn).await {
-                        Ok(params) => callback_destination.go(&templates, params).await?,
+                    match self::complete::complete(grant, user_session, &email_config, txn).await {
+                        Ok(params) => {
+                            callback_destination
+                                .go(&templates, &csp_nonce, params)
+                                .await?
+                        }
                         Err(GrantCompletionError::RequiresConsent) => {
                             mas_router::Consent(grant_id).go().into_response()
                         }
@@ -351,6 +399,9 @@ pub(crate) async fn get(
                                 .go()
                                 .into_response()
                         }
+                        Err(GrantCompletionError::RequiresEmailVerification) => {
+                            mas_router::AccountEmails.go().into_response()
+                        }
                         Err(GrantCompletionError::Anyhow(a)) => return Err(RouteError::Anyhow(a)),
                         Err(GrantCompletionError::Internal(e)) => {
                             return Err(RouteError::Internal(e))
@@ -372,9 +423,61 @@ pub(crate) async fn get(
         Ok(r) => r,
         Err(err) => {
             tracing::error!(%err);
-            callback_destination.go(&templates, SERVER_ERROR).await?
+            callback_destination
+                .go(&templates, &csp_nonce, SERVER_ERROR)
+                .await?
         }
     };
 
     Ok((cookie_jar, response).into_response())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkce(method: PkceCodeChallengeMethod) -> pkce::AuthorizationRequest {
+        pkce::AuthorizationRequest {
+            code_challenge_method: method,
+            code_challenge: "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM".to_owned(),
+        }
+    }
+
+    #[test]
+    fn pkce_is_optional_for_confidential_clients() {
+        let config = OAuth2Config::default();
+        assert!(is_pkce_acceptable(None, false, &config));
+        assert!(is_pkce_acceptable(
+            Some(&pkce(PkceCodeChallengeMethod::Plain)),
+            false,
+            &config
+        ));
+        assert!(!is_pkce_acceptable(None, true, &config));
+        assert!(is_pkce_acceptable(
+            Some(&pkce(PkceCodeChallengeMethod::Plain)),
+            true,
+            &config
+        ));
+    }
+
+    #[test]
+    fn pkce_s256_can_be_required() {
+        let config = OAuth2Config {
+            require_pkce_s256: true,
+            ..OAuth2Config::default()
+        };
+        for client_requires_pkce in [false, true] {
+            assert!(!is_pkce_acceptable(None, client_requires_pkce, &config));
+            assert!(!is_pkce_acceptable(
+                Some(&pkce(PkceCodeChallengeMethod::Plain)),
+                client_requires_pkce,
+                &config
+            ));
+            assert!(is_pkce_acceptable(
+                Some(&pkce(PkceCodeChallengeMethod::S256)),
+                client_requires_pkce,
+                &config
+            ));
+        }
+    }
+}
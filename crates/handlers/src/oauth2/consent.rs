@@ -18,9 +18,9 @@ use axum::{
     response::{Html, IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
-use hyper::StatusCode;
+use hyper::{HeaderMap, StatusCode};
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     SessionInfoExt,
 };
 use mas_config::Encrypter;
@@ -31,15 +31,26 @@ use mas_storage::oauth2::{
     consent::insert_client_consent,
 };
 use mas_templates::{ConsentContext, TemplateContext, Templates};
+use serde::Deserialize;
 use sqlx::PgPool;
 use thiserror::Error;
 
+use crate::i18n::accepted_languages;
+
 #[derive(Debug, Error)]
 pub enum RouteError {
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
 
+#[derive(Deserialize)]
+pub(crate) struct ConsentForm {
+    /// Set by the checkbox to skip the consent page on the next grants of the
+    /// client asking for the same scope
+    #[serde(default)]
+    remember: Option<String>,
+}
+
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -49,7 +60,9 @@ impl IntoResponse for RouteError {
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
+    headers: HeaderMap,
     Path(grant_id): Path<i64>,
 ) -> Result<Response, RouteError> {
     let mut conn = pool
@@ -71,9 +84,13 @@ pub(crate) async fn get(
     }
 
     if let Some(session) = maybe_session {
-        let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+
+        let scope_descriptions =
+            templates.describe_scope(&grant.scope, &accepted_languages(&headers));
 
         let ctx = ConsentContext::new(grant, PostAuthAction::continue_grant(grant_id))
+            .with_scope_descriptions(scope_descriptions)
             .with_session(session)
             .with_csrf(csrf_token.form_value());
 
@@ -91,17 +108,18 @@ pub(crate) async fn get(
 
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Path(grant_id): Path<i64>,
-    Form(form): Form<ProtectedForm<()>>,
+    Form(form): Form<ProtectedForm<ConsentForm>>,
 ) -> Result<Response, RouteError> {
     let mut txn = pool
         .begin()
         .await
         .context("failed to begin db transaction")?;
 
-    cookie_jar
-        .verify_form(form)
+    let form = cookie_jar
+        .verify_form(&csrf_protection, form)
         .context("csrf verification failed")?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
@@ -121,20 +139,22 @@ pub(crate) async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    // Do not consent for the "urn:matrix:device:*" scope
-    let scope_without_device = grant
-        .scope
-        .iter()
-        .filter(|s| !s.starts_with("urn:matrix:device:"))
-        .cloned()
-        .collect();
-    insert_client_consent(
-        &mut txn,
-        &session.user,
-        &grant.client,
-        &scope_without_device,
-    )
-    .await?;
+    if form.remember.is_some() {
+        // Do not consent for the "urn:matrix:device:*" scope
+        let scope_without_device = grant
+            .scope
+            .iter()
+            .filter(|s| !s.starts_with("urn:matrix:device:"))
+            .cloned()
+            .collect();
+        insert_client_consent(
+            &mut txn,
+            &session.user,
+            &grant.client,
+            &scope_without_device,
+        )
+        .await?;
+    }
 
     let _grant = give_consent_to_grant(&mut txn, grant)
         .await
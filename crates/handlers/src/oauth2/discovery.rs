@@ -15,6 +15,7 @@
 use std::sync::Arc;
 
 use axum::{extract::Extension, response::IntoResponse, Json};
+use mas_config::OAuth2Config;
 use mas_iana::{
     jose::JsonWebSignatureAlg,
     oauth::{
@@ -30,11 +31,31 @@ use oauth2_types::{
     scope,
 };
 
-#[allow(clippy::too_many_lines)]
+use crate::features::FeatureFlags;
+
 pub(crate) async fn get(
     Extension(key_store): Extension<Arc<StaticKeystore>>,
     Extension(url_builder): Extension<UrlBuilder>,
+    Extension(oauth2_config): Extension<OAuth2Config>,
+    Extension(feature_flags): Extension<FeatureFlags>,
 ) -> impl IntoResponse {
+    Json(metadata(
+        &key_store,
+        &url_builder,
+        &oauth2_config,
+        &feature_flags,
+    ))
+}
+
+/// The discovery document, describing what the endpoints support with the
+/// current configuration
+#[allow(clippy::too_many_lines)]
+fn metadata(
+    key_store: &StaticKeystore,
+    url_builder: &UrlBuilder,
+    oauth2_config: &OAuth2Config,
+    feature_flags: &FeatureFlags,
+) -> Metadata {
     // This is how clients can authenticate
     let client_auth_methods_supported = Some(vec![
         OAuthClientAuthenticationMethod::ClientSecretBasic,
@@ -67,8 +88,11 @@ pub(crate) async fn get(
     let token_endpoint = Some(url_builder.oauth_token_endpoint());
     let jwks_uri = Some(url_builder.jwks_uri());
     let introspection_endpoint = Some(url_builder.oauth_introspection_endpoint());
+    let revocation_endpoint = Some(url_builder.oauth_revocation_endpoint());
     let userinfo_endpoint = Some(url_builder.oidc_userinfo_endpoint());
-    let registration_endpoint = Some(url_builder.oauth_registration_endpoint());
+    let registration_endpoint = feature_flags
+        .client_registration()
+        .then(|| url_builder.oauth_registration_endpoint());
 
     let scopes_supported = Some(vec![scope::OPENID.to_string(), scope::EMAIL.to_string()]);
 
@@ -79,7 +103,7 @@ pub(crate) async fn get(
         OAuthAuthorizationEndpointResponseType::CodeToken,
         OAuthAuthorizationEndpointResponseType::CodeIdToken,
         OAuthAuthorizationEndpointResponseType::IdTokenToken,
-        OAuthAuthorizationEndpointResponseType::CodeIdToken,
+        OAuthAuthorizationEndpointResponseType::CodeIdTokenToken,
     ]);
 
     let response_modes_supported = Some(vec![
@@ -98,14 +122,32 @@ pub(crate) async fn get(
     let token_endpoint_auth_signing_alg_values_supported =
         client_auth_signing_alg_values_supported.clone();
 
-    let introspection_endpoint_auth_methods_supported = client_auth_methods_supported;
+    // Like introspection, revocation is not available to public clients
+    let confidential_client_auth_methods_supported = client_auth_methods_supported.map(|methods| {
+        methods
+            .into_iter()
+            .filter(|method| *method != OAuthClientAuthenticationMethod::None)
+            .collect::<Vec<_>>()
+    });
+
+    let introspection_endpoint_auth_methods_supported =
+        confidential_client_auth_methods_supported.clone();
     let introspection_endpoint_auth_signing_alg_values_supported =
+        client_auth_signing_alg_values_supported.clone();
+
+    let revocation_endpoint_auth_methods_supported = confidential_client_auth_methods_supported;
+    let revocation_endpoint_auth_signing_alg_values_supported =
         client_auth_signing_alg_values_supported;
 
-    let code_challenge_methods_supported = Some(vec![
-        PkceCodeChallengeMethod::Plain,
-        PkceCodeChallengeMethod::S256,
-    ]);
+    // The authorization endpoint rejects the `plain` method if S256 is required
+    let code_challenge_methods_supported = Some(if oauth2_config.require_pkce_s256 {
+        vec![PkceCodeChallengeMethod::S256]
+    } else {
+        vec![
+            PkceCodeChallengeMethod::Plain,
+            PkceCodeChallengeMethod::S256,
+        ]
+    });
 
     let subject_types_supported = Some(vec![SubjectType::Public]);
 
@@ -126,6 +168,9 @@ pub(crate) async fn get(
         "auth_time".to_string(),
         "at_hash".to_string(),
         "c_hash".to_string(),
+        "preferred_username".to_string(),
+        "email".to_string(),
+        "email_verified".to_string(),
     ]);
 
     let claims_parameter_supported = Some(false);
@@ -134,7 +179,7 @@ pub(crate) async fn get(
 
     let prompt_values_supported = Some(vec![Prompt::None, Prompt::Login, Prompt::Create]);
 
-    let metadata = Metadata {
+    Metadata {
         issuer,
         authorization_endpoint,
         token_endpoint,
@@ -149,6 +194,9 @@ pub(crate) async fn get(
         introspection_endpoint,
         introspection_endpoint_auth_methods_supported,
         introspection_endpoint_auth_signing_alg_values_supported,
+        revocation_endpoint,
+        revocation_endpoint_auth_methods_supported,
+        revocation_endpoint_auth_signing_alg_values_supported,
         code_challenge_methods_supported,
         userinfo_endpoint,
         subject_types_supported,
@@ -162,7 +210,117 @@ pub(crate) async fn get(
         request_uri_parameter_supported,
         prompt_values_supported,
         ..Metadata::default()
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use url::Url;
+
+    use super::*;
+    use crate::features::Feature;
+
+    fn document_with_flags(oauth2_config: &OAuth2Config, feature_flags: &FeatureFlags) -> Value {
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_ecdsa_key().unwrap();
+        let url_builder = UrlBuilder::new(Url::parse("https://auth.example.com/").unwrap());
+        serde_json::to_value(metadata(
+            &key_store,
+            &url_builder,
+            oauth2_config,
+            feature_flags,
+        ))
+        .unwrap()
+    }
+
+    fn document(oauth2_config: &OAuth2Config) -> Value {
+        document_with_flags(oauth2_config, &FeatureFlags::default())
+    }
+
+    #[test]
+    fn required_fields_are_present() {
+        let document = document(&OAuth2Config::default());
+
+        // Required by OpenID Connect Discovery 1.0, section 3
+        for field in [
+            "issuer",
+            "authorization_endpoint",
+            "token_endpoint",
+            "jwks_uri",
+            "response_types_supported",
+            "subject_types_supported",
+            "id_token_signing_alg_values_supported",
+        ] {
+            assert!(
+                !document[field].is_null(),
+                "missing required field {}",
+                field
+            );
+        }
+
+        assert_eq!(document["issuer"], "https://auth.example.com/");
+        assert!(document["scopes_supported"]
+            .as_array()
+            .unwrap()
+            .contains(&"openid".into()));
+        assert!(document["id_token_signing_alg_values_supported"]
+            .as_array()
+            .unwrap()
+            .contains(&"ES256".into()));
+    }
+
+    #[test]
+    fn urls_are_absolute() {
+        let document = document(&OAuth2Config::default());
+
+        for field in [
+            "issuer",
+            "authorization_endpoint",
+            "token_endpoint",
+            "jwks_uri",
+            "userinfo_endpoint",
+            "registration_endpoint",
+            "introspection_endpoint",
+            "revocation_endpoint",
+        ] {
+            let url = document[field].as_str().unwrap();
+            let url =
+                Url::parse(url).unwrap_or_else(|e| panic!("{} is not absolute: {}", field, e));
+            assert_eq!(url.scheme(), "https", "{}", field);
+            assert_eq!(url.host_str(), Some("auth.example.com"), "{}", field);
+        }
+    }
+
+    #[test]
+    fn registration_endpoint_follows_the_flag() {
+        let default = document(&OAuth2Config::default());
+        assert_eq!(
+            default["registration_endpoint"],
+            "https://auth.example.com/oauth2/register"
+        );
+
+        let feature_flags = FeatureFlags::default();
+        feature_flags.set_override(Feature::ClientRegistration, Some(false));
+        let disabled = document_with_flags(&OAuth2Config::default(), &feature_flags);
+        assert!(disabled["registration_endpoint"].is_null());
+    }
+
+    #[test]
+    fn pkce_methods_follow_the_config() {
+        let default = document(&OAuth2Config::default());
+        assert_eq!(
+            default["code_challenge_methods_supported"],
+            serde_json::json!(["plain", "S256"])
+        );
 
-    Json(metadata)
+        let strict = document(&OAuth2Config {
+            require_pkce_s256: true,
+            ..OAuth2Config::default()
+        });
+        assert_eq!(
+            strict["code_challenge_methods_supported"],
+            serde_json::json!(["S256"])
+        );
+    }
 }
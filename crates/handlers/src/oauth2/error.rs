@@ -0,0 +1,180 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Error responses of the endpoints called by OAuth 2.0 clients, as per [RFC
+//! 6749 section 5.2]
+//!
+//! [RFC 6749 section 5.2]: https://www.rfc-editor.org/rfc/rfc6749#section-5.2
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{
+    header::{HeaderValue, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use oauth2_types::errors::{
+    ClientError, INVALID_CLIENT, INVALID_GRANT, INVALID_REQUEST, SERVER_ERROR, UNAUTHORIZED_CLIENT,
+    UNSUPPORTED_GRANT_TYPE,
+};
+
+/// The challenge sent with `invalid_client` errors, for clients
+/// authenticating with HTTP Basic
+const BASIC_CHALLENGE: &str = r#"Basic realm="OAuth 2.0 client""#;
+
+/// An error returned to an OAuth 2.0 client, with a JSON body like
+/// `{"error": "invalid_grant", "error_description": "..."}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OAuthError {
+    /// The request is malformed
+    InvalidRequest,
+
+    /// Client authentication failed. Answered with a `401` and a
+    /// `WWW-Authenticate` challenge
+    InvalidClient,
+
+    /// The authorization code or the refresh token is invalid, expired or
+    /// revoked, or was issued to another client
+    InvalidGrant,
+
+    /// The client is not allowed to use this grant type or endpoint
+    UnauthorizedClient,
+
+    /// The grant type is not supported by this server
+    UnsupportedGrantType,
+
+    /// Something went wrong on the server side
+    ServerError,
+}
+
+impl OAuthError {
+    /// The status code of the response
+    pub(crate) const fn status(self) -> StatusCode {
+        match self {
+            Self::InvalidClient => StatusCode::UNAUTHORIZED,
+            Self::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidRequest
+            | Self::InvalidGrant
+            | Self::UnauthorizedClient
+            | Self::UnsupportedGrantType => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// The body of the response
+    pub(crate) const fn body(self) -> ClientError {
+        match self {
+            Self::InvalidRequest => INVALID_REQUEST,
+            Self::InvalidClient => INVALID_CLIENT,
+            Self::InvalidGrant => INVALID_GRANT,
+            Self::UnauthorizedClient => UNAUTHORIZED_CLIENT,
+            Self::UnsupportedGrantType => UNSUPPORTED_GRANT_TYPE,
+            Self::ServerError => SERVER_ERROR,
+        }
+    }
+}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+
+        // Clients using HTTP authentication expect a challenge with a 401, as
+        // per RFC 6749 section 5.2 and RFC 7235 section 3.1
+        if self == Self::InvalidClient {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static(BASIC_CHALLENGE));
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::CONTENT_TYPE;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    async fn respond(error: OAuthError) -> (StatusCode, Option<HeaderValue>, Value) {
+        let response = error.into_response();
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+
+        let status = response.status();
+        let challenge = response.headers().get(WWW_AUTHENTICATE).cloned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, challenge, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        for (error, status, code) in [
+            (
+                OAuthError::InvalidRequest,
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                OAuthError::InvalidGrant,
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+            ),
+            (
+                OAuthError::UnauthorizedClient,
+                StatusCode::BAD_REQUEST,
+                "unauthorized_client",
+            ),
+            (
+                OAuthError::UnsupportedGrantType,
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+            ),
+            (
+                OAuthError::ServerError,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+            ),
+        ] {
+            let (response_status, challenge, body) = respond(error).await;
+            assert_eq!(response_status, status, "{:?}", error);
+            assert_eq!(challenge, None, "{:?}", error);
+            assert_eq!(body["error"], code, "{:?}", error);
+            assert!(
+                body["error_description"]
+                    .as_str()
+                    .map_or(false, |description| !description.is_empty()),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_client_has_a_challenge() {
+        let (status, challenge, body) = respond(OAuthError::InvalidClient).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(challenge.unwrap(), BASIC_CHALLENGE);
+        assert_eq!(
+            body,
+            json!({
+                "error": "invalid_client",
+                "error_description": "Client authentication failed.",
+            })
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Minting of the ID tokens handed to the clients alongside the access tokens
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use mas_data_model::{BrowserSession, StorageBackend};
+use mas_iana::jose::JsonWebSignatureAlg;
+use mas_jose::{claims, DecodedJsonWebToken, SigningKeystore};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// The claims of an ID token, before they get signed
+#[derive(Debug, Clone)]
+pub(crate) struct IdToken<'a> {
+    issuer: &'a Url,
+    subject: &'a str,
+    audience: &'a str,
+    auth_time: Option<DateTime<Utc>>,
+    nonce: Option<&'a str>,
+    access_token: Option<&'a str>,
+    code: Option<&'a str>,
+}
+
+impl<'a> IdToken<'a> {
+    /// An ID token for the user of a browser session, issued to a client
+    #[must_use]
+    pub fn new<T: StorageBackend>(
+        issuer: &'a Url,
+        client_id: &'a str,
+        browser_session: &'a BrowserSession<T>,
+    ) -> Self {
+        Self {
+            issuer,
+            subject: &browser_session.user.sub,
+            audience: client_id,
+            auth_time: browser_session
+                .last_authentication
+                .as_ref()
+                .map(|authentication| authentication.created_at),
+            nonce: None,
+            access_token: None,
+            code: None,
+        }
+    }
+
+    /// Echo the nonce the client sent in the authorization request
+    #[must_use]
+    pub fn with_nonce(mut self, nonce: Option<&'a str>) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Bind the token to the access token issued alongside it (`at_hash`)
+    #[must_use]
+    pub fn with_access_token(mut self, access_token: &'a str) -> Self {
+        self.access_token = Some(access_token);
+        self
+    }
+
+    /// Bind the token to the authorization code it was exchanged for
+    /// (`c_hash`)
+    #[must_use]
+    pub fn with_code(mut self, code: &'a str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Sign the token with the key of the store for the given algorithm.
+    ///
+    /// The header carries the `kid` of the key, so that clients can pick
+    /// the right one from the published JWKS after a key rotation.
+    pub async fn sign<S: SigningKeystore>(
+        &self,
+        key_store: &S,
+        alg: JsonWebSignatureAlg,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<String> {
+        let mut claims = HashMap::new();
+        claims::ISS.insert(&mut claims, self.issuer.to_string())?;
+        claims::SUB.insert(&mut claims, self.subject)?;
+        claims::AUD.insert(&mut claims, self.audience.to_owned())?;
+        claims::IAT.insert(&mut claims, now)?;
+        claims::EXP.insert(&mut claims, now + Duration::hours(1))?;
+
+        if let Some(nonce) = self.nonce {
+            claims::NONCE.insert(&mut claims, nonce)?;
+        }
+        if let Some(auth_time) = self.auth_time {
+            claims::AUTH_TIME.insert(&mut claims, auth_time)?;
+        }
+        if let Some(access_token) = self.access_token {
+            claims::AT_HASH.insert(&mut claims, hash(Sha256::new(), access_token)?)?;
+        }
+        if let Some(code) = self.code {
+            claims::C_HASH.insert(&mut claims, hash(Sha256::new(), code)?)?;
+        }
+
+        let header = key_store.prepare_header(alg).await?;
+        let id_token = DecodedJsonWebToken::new(header, claims);
+        let id_token = id_token.sign(key_store).await?;
+
+        Ok(id_token.serialize())
+    }
+}
+
+fn hash<H: Digest>(mut hasher: H, token: &str) -> anyhow::Result<String> {
+    hasher.update(token);
+    let hash = hasher.finalize();
+    // Left-most 128bit
+    let bits = hash
+        .get(..16)
+        .context("failed to get first 128 bits of hash")?;
+    Ok(BASE64URL_NOPAD.encode(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mas_data_model::Authentication;
+    use mas_jose::{JsonWebTokenParts, StaticJwksStore, StaticKeystore};
+    use serde_json::{json, Value};
+    use tower::{Service, ServiceExt};
+
+    use super::*;
+
+    fn browser_session() -> BrowserSession<()> {
+        let mut session = BrowserSession::<()>::samples().remove(0);
+        session.last_authentication = Some(Authentication {
+            data: (),
+            created_at: Utc::now() - Duration::minutes(5),
+        });
+        session
+    }
+
+    async fn published_keys(key_store: &StaticKeystore) -> StaticJwksStore {
+        let mut key_store = key_store;
+        let jwks = key_store.ready().await.unwrap().call(()).await.unwrap();
+        // Go through the wire format, like a client fetching the JWKS would
+        let jwks = serde_json::from_value(serde_json::to_value(jwks).unwrap()).unwrap();
+        StaticJwksStore::new(jwks)
+    }
+
+    async fn verify(token: &str, jwks: &StaticJwksStore) -> HashMap<String, Value> {
+        let token = JsonWebTokenParts::from_str(token).unwrap();
+        let decoded = token
+            .decode_and_verify::<HashMap<String, Value>, _>(jwks)
+            .await
+            .unwrap();
+        decoded.claims().clone()
+    }
+
+    #[tokio::test]
+    async fn id_token_validates_against_the_published_keys() {
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_ecdsa_key().unwrap();
+
+        let issuer = Url::parse("https://example.com/").unwrap();
+        let session = browser_session();
+        let now = Utc::now();
+        let token = IdToken::new(&issuer, "client", &session)
+            .with_nonce(Some("n-0S6_WzA2Mj"))
+            .with_access_token("access-token")
+            .with_code("code")
+            .sign(&key_store, JsonWebSignatureAlg::Es256, now)
+            .await
+            .unwrap();
+
+        let jwks = published_keys(&key_store).await;
+        let claims = verify(&token, &jwks).await;
+
+        assert_eq!(claims["iss"], json!("https://example.com/"));
+        assert_eq!(claims["sub"], json!(session.user.sub));
+        assert_eq!(claims["aud"], json!("client"));
+        assert_eq!(claims["nonce"], json!("n-0S6_WzA2Mj"));
+        assert_eq!(claims["iat"], json!(now.timestamp()));
+        assert_eq!(claims["exp"], json!((now + Duration::hours(1)).timestamp()));
+        assert_eq!(
+            claims["auth_time"],
+            json!(session
+                .last_authentication
+                .as_ref()
+                .unwrap()
+                .created_at
+                .timestamp())
+        );
+        assert_eq!(
+            claims["at_hash"],
+            json!(hash(Sha256::new(), "access-token").unwrap())
+        );
+        assert_eq!(
+            claims["c_hash"],
+            json!(hash(Sha256::new(), "code").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn optional_claims_are_omitted() {
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_ecdsa_key().unwrap();
+
+        let issuer = Url::parse("https://example.com/").unwrap();
+        let mut session = browser_session();
+        session.last_authentication = None;
+        let token = IdToken::new(&issuer, "client", &session)
+            .with_nonce(None)
+            .sign(&key_store, JsonWebSignatureAlg::Es256, Utc::now())
+            .await
+            .unwrap();
+
+        let claims = verify(&token, &published_keys(&key_store).await).await;
+        for claim in ["nonce", "auth_time", "at_hash", "c_hash"] {
+            assert!(!claims.contains_key(claim), "unexpected {} claim", claim);
+        }
+    }
+
+    #[tokio::test]
+    async fn tampered_id_token_is_rejected() {
+        let mut key_store = StaticKeystore::new();
+        key_store.add_test_ecdsa_key().unwrap();
+
+        let issuer = Url::parse("https://example.com/").unwrap();
+        let session = browser_session();
+        let token = IdToken::new(&issuer, "client", &session)
+            .with_nonce(Some("nonce"))
+            .sign(&key_store, JsonWebSignatureAlg::Es256, Utc::now())
+            .await
+            .unwrap();
+
+        // Swap the payload for one with another nonce
+        let other = IdToken::new(&issuer, "client", &session)
+            .with_nonce(Some("other"))
+            .sign(&key_store, JsonWebSignatureAlg::Es256, Utc::now())
+            .await
+            .unwrap();
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[1] = other.split('.').nth(1).unwrap();
+        let tampered = parts.join(".");
+
+        let jwks = published_keys(&key_store).await;
+        let tampered = JsonWebTokenParts::from_str(&tampered).unwrap();
+        assert!(tampered
+            .decode_and_verify::<HashMap<String, Value>, _>(&jwks)
+            .await
+            .is_err());
+    }
+}
@@ -16,7 +16,7 @@ use axum::{extract::Extension, response::IntoResponse, Json};
 use hyper::StatusCode;
 use mas_axum_utils::client_authorization::{ClientAuthorization, CredentialsVerificationError};
 use mas_config::Encrypter;
-use mas_data_model::{TokenFormatError, TokenType};
+use mas_data_model::{AccessToken, Session, StorageBackend, TokenFormatError, TokenType};
 use mas_iana::oauth::{OAuthClientAuthenticationMethod, OAuthTokenTypeHint};
 use mas_storage::{
     compat::{
@@ -33,6 +33,8 @@ use oauth2_types::requests::{IntrospectionRequest, IntrospectionResponse};
 use sqlx::PgPool;
 use thiserror::Error;
 
+use crate::ClientCache;
+
 #[derive(Debug, Error)]
 pub enum RouteError {
     #[error(transparent)]
@@ -152,15 +154,39 @@ const INACTIVE: IntrospectionResponse = IntrospectionResponse {
     jti: None,
 };
 
+/// What resource servers get to know about an active access token
+fn access_token_introspection<T: StorageBackend>(
+    token: &AccessToken<T>,
+    session: Session<T>,
+) -> IntrospectionResponse {
+    IntrospectionResponse {
+        active: true,
+        scope: Some(session.scope),
+        client_id: Some(session.client.client_id),
+        username: Some(session.browser_session.user.username),
+        token_type: Some(OAuthTokenTypeHint::AccessToken),
+        exp: Some(token.expires_at()),
+        iat: Some(token.created_at),
+        nbf: Some(token.created_at),
+        sub: Some(session.browser_session.user.sub),
+        aud: None,
+        iss: None,
+        jti: None,
+    }
+}
+
 #[tracing::instrument(skip_all, err)]
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
     Extension(encrypter): Extension<Encrypter>,
+    Extension(client_cache): Extension<ClientCache>,
     client_authorization: ClientAuthorization<IntrospectionRequest>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut conn = pool.acquire().await?;
 
-    let client = client_authorization.credentials.fetch(&mut conn).await?;
+    let client = client_cache
+        .lookup(&mut conn, client_authorization.credentials.client_id())
+        .await?;
 
     let method = match client.token_endpoint_auth_method {
         None | Some(OAuthClientAuthenticationMethod::None) => {
@@ -191,22 +217,7 @@ pub(crate) async fn post(
     let reply = match token_type {
         TokenType::AccessToken => {
             let (token, session) = lookup_active_access_token(&mut conn, token).await?;
-            let exp = token.exp();
-
-            IntrospectionResponse {
-                active: true,
-                scope: Some(session.scope),
-                client_id: Some(session.client.client_id),
-                username: Some(session.browser_session.user.username),
-                token_type: Some(OAuthTokenTypeHint::AccessToken),
-                exp: Some(exp),
-                iat: Some(token.created_at),
-                nbf: Some(token.created_at),
-                sub: Some(session.browser_session.user.sub),
-                aud: None,
-                iss: None,
-                jti: None,
-            }
+            access_token_introspection(&token, session)
         }
         TokenType::RefreshToken => {
             let (token, session) = lookup_active_refresh_token(&mut conn, token).await?;
@@ -273,3 +284,97 @@ pub(crate) async fn post(
 
     Ok(Json(reply))
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, SubsecRound, Utc};
+    use mas_data_model::{BrowserSession, Client, ClientType};
+    use oauth2_types::scope::Scope;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn session() -> Session<()> {
+        let client = Client {
+            data: (),
+            client_id: "client".to_string(),
+            client_type: ClientType::Confidential,
+            encrypted_client_secret: None,
+            redirect_uris: Vec::new(),
+            response_types: Vec::new(),
+            grant_types: Vec::new(),
+            contacts: Vec::new(),
+            client_name: None,
+            logo_uri: None,
+            client_uri: None,
+            policy_uri: None,
+            tos_uri: None,
+            jwks: None,
+            id_token_signed_response_alg: None,
+            userinfo_signed_response_alg: None,
+            token_endpoint_auth_method: None,
+            token_endpoint_auth_signing_alg: None,
+            initiate_login_uri: None,
+        };
+
+        Session {
+            data: (),
+            browser_session: BrowserSession::samples().remove(0),
+            client,
+            scope: "openid email".parse::<Scope>().unwrap(),
+        }
+    }
+
+    async fn body(error: RouteError) -> (StatusCode, Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn active_access_token() {
+        // The expiration is serialized in whole seconds
+        let created_at = Utc::now().trunc_subsecs(0);
+        let token = AccessToken {
+            data: (),
+            jti: "1".to_owned(),
+            token: TokenType::AccessToken.generate(&mut rand::thread_rng()),
+            expires_after: Duration::minutes(5),
+            created_at,
+        };
+        let session = session();
+        let sub = session.browser_session.user.sub.clone();
+
+        let response = serde_json::to_value(access_token_introspection(&token, session)).unwrap();
+        assert_eq!(response["active"], json!(true));
+        assert_eq!(response["scope"], json!("email openid"));
+        assert_eq!(response["sub"], json!(sub));
+        assert_eq!(response["client_id"], json!("client"));
+        assert_eq!(response["token_type"], json!("access_token"));
+        assert_eq!(response["exp"], json!(token.expires_at().timestamp()));
+    }
+
+    #[tokio::test]
+    async fn revoked_token_is_inactive() {
+        // Revoked and expired tokens are not found by the lookup
+        let error = AccessTokenLookupError::Database(sqlx::Error::RowNotFound);
+        let (status, body) = body(error.into()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "active": false }));
+    }
+
+    #[tokio::test]
+    async fn unknown_token_is_inactive() {
+        let error = TokenType::check("not-a-token").unwrap_err();
+        let (status, response) = body(error.into()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, json!({ "active": false }));
+
+        // A well-formed token which doesn't exist gets the same answer
+        let error = RefreshTokenLookupError::Fetch(sqlx::Error::RowNotFound);
+        let (status, response) = body(error.into()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response, json!({ "active": false }));
+    }
+}
@@ -15,9 +15,12 @@
 pub mod authorization;
 pub mod consent;
 pub mod discovery;
+pub mod error;
+pub mod id_token;
 pub mod introspection;
 pub mod keys;
 pub mod registration;
+pub mod revocation;
 pub mod token;
 pub mod userinfo;
 pub mod webfinger;
@@ -14,20 +14,28 @@
 
 use std::sync::Arc;
 
-use axum::{response::IntoResponse, Extension, Json};
-use hyper::StatusCode;
+use axum::{response::IntoResponse, Extension, Json, TypedHeader};
+use chrono::{TimeZone, Utc};
+use headers::{authorization::Bearer, Authorization, HeaderValue};
+use hyper::{header::WWW_AUTHENTICATE, HeaderMap, StatusCode};
+use mas_config::{ClientRegistrationConfig, Encrypter, OAuth2Config};
 use mas_iana::oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod};
 use mas_policy::PolicyFactory;
-use mas_storage::oauth2::client::insert_client;
+use mas_storage::oauth2::client::{insert_client, reencrypt_client_secrets};
 use oauth2_types::{
-    errors::{INVALID_CLIENT_METADATA, INVALID_REDIRECT_URI, SERVER_ERROR},
+    errors::{INVALID_CLIENT_METADATA, INVALID_REDIRECT_URI, INVALID_TOKEN, SERVER_ERROR},
+    oidc::ApplicationType,
     registration::{ClientMetadata, ClientRegistrationResponse},
     requests::GrantType,
 };
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 use thiserror::Error;
-use tracing::info;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::features::FeatureFlags;
 
 #[derive(Debug, Error)]
 pub(crate) enum RouteError {
@@ -37,6 +45,12 @@ pub(crate) enum RouteError {
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 
+    #[error("client registration is disabled")]
+    Disabled,
+
+    #[error("invalid initial access token")]
+    InvalidInitialAccessToken,
+
     #[error("invalid redirect uri")]
     InvalidRedirectUri,
 
@@ -57,31 +71,80 @@ impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         match self {
             Self::Internal(_) | Self::Anyhow(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(SERVER_ERROR))
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(SERVER_ERROR)).into_response()
+            }
+            Self::Disabled => StatusCode::NOT_FOUND.into_response(),
+            Self::InvalidInitialAccessToken => {
+                // As per RFC 6750, section 3
+                let mut headers = HeaderMap::new();
+                headers.insert(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Bearer error="invalid_token""#),
+                );
+                (StatusCode::UNAUTHORIZED, headers, Json(INVALID_TOKEN)).into_response()
+            }
+            Self::InvalidRedirectUri => {
+                (StatusCode::BAD_REQUEST, Json(INVALID_REDIRECT_URI)).into_response()
+            }
+            Self::InvalidClientMetadata => {
+                (StatusCode::BAD_REQUEST, Json(INVALID_CLIENT_METADATA)).into_response()
+            }
+            Self::PolicyDenied => {
+                (StatusCode::UNAUTHORIZED, Json(INVALID_CLIENT_METADATA)).into_response()
             }
-            Self::InvalidRedirectUri => (StatusCode::BAD_REQUEST, Json(INVALID_REDIRECT_URI)),
-            Self::InvalidClientMetadata => (StatusCode::BAD_REQUEST, Json(INVALID_CLIENT_METADATA)),
-            Self::PolicyDenied => (StatusCode::UNAUTHORIZED, Json(INVALID_CLIENT_METADATA)),
         }
-        .into_response()
     }
 }
 
-#[tracing::instrument(skip_all, err)]
-pub(crate) async fn post(
-    Extension(pool): Extension<PgPool>,
-    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
-    Json(body): Json<ClientMetadata>,
-) -> Result<impl IntoResponse, RouteError> {
-    info!(?body, "Client registration");
+/// Check that registration is enabled, and that the client sent the initial
+/// access token if one is required
+fn check_access(
+    feature_flags: &FeatureFlags,
+    config: &ClientRegistrationConfig,
+    bearer: Option<&Bearer>,
+) -> Result<(), RouteError> {
+    if !feature_flags.client_registration() {
+        return Err(RouteError::Disabled);
+    }
 
-    // Let's validate a bunch of things on the client body first
-    for uri in &body.redirect_uris {
-        if uri.fragment().is_some() {
-            return Err(RouteError::InvalidRedirectUri);
+    if let Some(expected) = &config.initial_access_token {
+        // Compare digests, so that the comparison doesn't leak how much of the
+        // token is right
+        let matches = bearer.map_or(false, |bearer| {
+            Sha256::digest(bearer.token()) == Sha256::digest(expected)
+        });
+        if !matches {
+            return Err(RouteError::InvalidInitialAccessToken);
         }
     }
 
+    Ok(())
+}
+
+/// Check a redirect URI, following the rules of [OpenID Connect Dynamic Client
+/// Registration] and [RFC 8252] for native apps.
+///
+/// On top of the rules applying to all clients, web apps have to use `https`,
+/// whereas native apps can also use a private-use scheme.
+///
+/// [OpenID Connect Dynamic Client Registration]: https://openid.net/specs/openid-connect-registration-1_0.html#ClientMetadata
+/// [RFC 8252]: https://datatracker.ietf.org/doc/html/rfc8252#section-7
+fn validate_redirect_uri(uri: &Url, application_type: ApplicationType) -> Result<(), RouteError> {
+    mas_data_model::validate_redirect_uri(uri).map_err(|_| RouteError::InvalidRedirectUri)?;
+
+    match uri.scheme() {
+        "https" | "http" => Ok(()),
+        _ if application_type == ApplicationType::Native => Ok(()),
+        _ => Err(RouteError::InvalidRedirectUri),
+    }
+}
+
+/// Validate the metadata sent by the client
+fn validate(body: &ClientMetadata) -> Result<(), RouteError> {
+    for uri in &body.redirect_uris {
+        validate_redirect_uri(uri, body.application_type)?;
+    }
+
     // Check that the client did not send both a jwks and a jwks_uri
     if body.jwks_uri.is_some() && body.jwks.is_some() {
         return Err(RouteError::InvalidClientMetadata);
@@ -92,6 +155,11 @@ pub(crate) async fn post(
     let has_authorization_code = body.grant_types.contains(&GrantType::AuthorizationCode);
     let has_both = has_implicit && has_authorization_code;
 
+    // Both grants end up redirecting to the client
+    if (has_implicit || has_authorization_code) && body.redirect_uris.is_empty() {
+        return Err(RouteError::InvalidRedirectUri);
+    }
+
     for response_type in &body.response_types {
         let is_ok = match response_type {
             OAuthAuthorizationEndpointResponseType::Code => has_authorization_code,
@@ -118,6 +186,79 @@ pub(crate) async fn post(
         return Err(RouteError::InvalidClientMetadata);
     }
 
+    Ok(())
+}
+
+/// Generate a secret for the clients authenticating with one
+fn generate_client_secret<R: Rng + ?Sized>(
+    rng: &mut R,
+    method: OAuthClientAuthenticationMethod,
+) -> Option<String> {
+    match method {
+        OAuthClientAuthenticationMethod::ClientSecretBasic
+        | OAuthClientAuthenticationMethod::ClientSecretPost
+        | OAuthClientAuthenticationMethod::ClientSecretJwt => Some(
+            rng.sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Encrypt again the client secrets stored before they were bound to their
+/// client ID, so that they can only be decrypted with it. Returns how many
+/// were encrypted again
+///
+/// # Errors
+///
+/// Returns an error if the database failed
+pub async fn bind_client_secrets(pool: &PgPool, encrypter: &Encrypter) -> anyhow::Result<u64> {
+    let mut conn = pool.acquire().await?;
+    reencrypt_client_secrets(&mut conn, |client_id, encrypted_client_secret| {
+        let associated_data = client_id.as_bytes();
+        if encrypter
+            .decrypt_string_with_ad(associated_data, encrypted_client_secret)
+            .is_ok()
+        {
+            return Ok(None);
+        }
+
+        if let Ok(client_secret) = encrypter.decrypt_string(encrypted_client_secret) {
+            let encrypted_client_secret =
+                encrypter.encrypt_to_string_with_ad(associated_data, &client_secret)?;
+            Ok(Some(encrypted_client_secret))
+        } else {
+            warn!(%client_id, "Could not decrypt the client secret");
+            Ok(None)
+        }
+    })
+    .await
+}
+
+#[tracing::instrument(skip_all, err)]
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(policy_factory): Extension<Arc<PolicyFactory>>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(oauth2_config): Extension<OAuth2Config>,
+    Extension(feature_flags): Extension<FeatureFlags>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(body): Json<ClientMetadata>,
+) -> Result<impl IntoResponse, RouteError> {
+    check_access(
+        &feature_flags,
+        &oauth2_config.registration,
+        authorization
+            .as_ref()
+            .map(|TypedHeader(Authorization(bearer))| bearer),
+    )?;
+
+    info!(?body, "Client registration");
+
+    validate(&body)?;
+
     let mut policy = policy_factory.instantiate().await?;
     let res = policy.evaluate_client_registration(&body).await?;
     if !res.valid() {
@@ -134,11 +275,22 @@ pub(crate) async fn post(
         .map(char::from)
         .collect();
 
+    // Clients which don't say how they authenticate use a client secret, as per
+    // RFC 7591, section 2
+    let token_endpoint_auth_method = body
+        .token_endpoint_auth_method
+        .unwrap_or(OAuthClientAuthenticationMethod::ClientSecretBasic);
+    let client_secret = generate_client_secret(&mut thread_rng(), token_endpoint_auth_method);
+    let encrypted_client_secret = client_secret
+        .as_deref()
+        .map(|secret| encrypter.encrypt_to_string_with_ad(client_id.as_bytes(), secret.as_bytes()))
+        .transpose()?;
+
     insert_client(
         &mut txn,
         &client_id,
         &body.redirect_uris,
-        None,
+        encrypted_client_secret.as_deref(),
         &body.response_types,
         &body.grant_types,
         &body.contacts,
@@ -151,7 +303,7 @@ pub(crate) async fn post(
         body.jwks.as_ref(),
         body.id_token_signed_response_alg,
         body.userinfo_signed_response_alg,
-        body.token_endpoint_auth_method,
+        Some(token_endpoint_auth_method),
         body.token_endpoint_auth_signing_alg,
         body.initiate_login_uri.as_ref(),
     )
@@ -159,12 +311,201 @@ pub(crate) async fn post(
 
     txn.commit().await?;
 
+    // The secret never expires, which is signaled with a zero timestamp
+    let client_secret_expires_at = client_secret.as_ref().map(|_| Utc.timestamp(0, 0));
+
     let response = ClientRegistrationResponse {
         client_id,
-        client_secret: None,
-        client_id_issued_at: None,
-        client_secret_expires_at: None,
+        client_secret,
+        client_id_issued_at: Some(Utc::now()),
+        client_secret_expires_at,
     };
 
     Ok((StatusCode::CREATED, Json(response)))
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::features::Feature;
+
+    fn metadata(value: serde_json::Value) -> ClientMetadata {
+        serde_json::from_value(value).unwrap()
+    }
+
+    async fn error_code(error: RouteError) -> (StatusCode, String) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["error"].as_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn successful_registration() {
+        let body = metadata(json!({
+            "redirect_uris": ["https://client.example.com/callback"],
+            "client_name": "Example",
+        }));
+        assert!(validate(&body).is_ok());
+
+        let body = metadata(json!({
+            "redirect_uris": [
+                "com.example.app:/callback",
+                "http://127.0.0.1:1234/callback",
+                "http://localhost/callback",
+            ],
+            "application_type": "native",
+            "token_endpoint_auth_method": "none",
+        }));
+        assert!(validate(&body).is_ok());
+    }
+
+    #[test]
+    fn confidential_clients_get_a_secret() {
+        let mut rng = thread_rng();
+        let encrypter = Encrypter::new(&[0x42; 32]);
+
+        for method in [
+            OAuthClientAuthenticationMethod::ClientSecretBasic,
+            OAuthClientAuthenticationMethod::ClientSecretPost,
+            OAuthClientAuthenticationMethod::ClientSecretJwt,
+        ] {
+            let secret = generate_client_secret(&mut rng, method).unwrap();
+            assert_eq!(secret.len(), 32);
+
+            // What gets stored is what the client authenticates with
+            let ciphertext = encrypter
+                .encrypt_to_string_with_ad(b"client-id", secret.as_bytes())
+                .unwrap();
+            assert_eq!(
+                encrypter
+                    .decrypt_string_with_ad(b"client-id", &ciphertext)
+                    .unwrap(),
+                secret.as_bytes()
+            );
+        }
+
+        for method in [
+            OAuthClientAuthenticationMethod::None,
+            OAuthClientAuthenticationMethod::PrivateKeyJwt,
+        ] {
+            assert_eq!(generate_client_secret(&mut rng, method), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_redirect_uri_is_rejected() {
+        for (uri, application_type) in [
+            ("javascript:alert(1)", "native"),
+            ("data:text/html,hello", "native"),
+            ("file:///etc/passwd", "native"),
+            ("https://client.example.com/callback#fragment", "web"),
+            ("http://client.example.com/callback", "web"),
+            ("http://client.example.com/callback", "native"),
+            ("com.example.app:/callback", "web"),
+        ] {
+            let body = metadata(json!({
+                "redirect_uris": [uri],
+                "application_type": application_type,
+            }));
+            let error = validate(&body).unwrap_err();
+            assert!(
+                matches!(error, RouteError::InvalidRedirectUri),
+                "{} was accepted for a {} app",
+                uri,
+                application_type
+            );
+            assert_eq!(
+                error_code(error).await,
+                (StatusCode::BAD_REQUEST, "invalid_redirect_uri".to_owned())
+            );
+        }
+
+        // Authorization code clients need somewhere to be redirected to
+        let body = metadata(json!({ "redirect_uris": [] }));
+        assert!(matches!(
+            validate(&body),
+            Err(RouteError::InvalidRedirectUri)
+        ));
+    }
+
+    #[tokio::test]
+    async fn registration_can_be_restricted() {
+        let feature_flags = FeatureFlags::default();
+        let open = ClientRegistrationConfig::default();
+        assert!(check_access(&feature_flags, &open, None).is_ok());
+
+        feature_flags.set_override(Feature::ClientRegistration, Some(false));
+        let error = check_access(&feature_flags, &open, None).unwrap_err();
+        assert_eq!(error.into_response().status(), StatusCode::NOT_FOUND);
+
+        // Enabling it again restores the registration
+        feature_flags.set_override(Feature::ClientRegistration, Some(true));
+        assert!(check_access(&feature_flags, &open, None).is_ok());
+
+        let restricted = ClientRegistrationConfig {
+            enabled: true,
+            initial_access_token: Some("s3cr3t".to_owned()),
+        };
+        let Authorization(right) = Authorization::bearer("s3cr3t").unwrap();
+        let Authorization(wrong) = Authorization::bearer("s3cr3tt").unwrap();
+        assert!(check_access(&feature_flags, &restricted, Some(&right)).is_ok());
+
+        for bearer in [None, Some(&wrong)] {
+            let error = check_access(&feature_flags, &restricted, bearer).unwrap_err();
+            let response = error.into_response();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn legacy_client_secrets_are_bound() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPool::connect(&url).await.unwrap();
+        let encrypter = Encrypter::new(&[0x42; 32]);
+
+        // The secrets are encrypted again on other connections, so the clients
+        // are committed, under a name unique to this run
+        let client_id = format!("legacy-{:08x}", thread_rng().gen::<u32>());
+        let legacy = encrypter.encryt_to_string(b"client-secret").unwrap();
+        let mut txn = pool.begin().await.unwrap();
+        mas_storage::oauth2::client::insert_client_from_config(
+            &mut txn,
+            &client_id,
+            OAuthClientAuthenticationMethod::ClientSecretBasic,
+            Some(&legacy),
+            None,
+            None,
+            &[],
+        )
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
+
+        assert!(bind_client_secrets(&pool, &encrypter).await.unwrap() >= 1);
+
+        let mut conn = pool.acquire().await.unwrap();
+        let client = mas_storage::oauth2::client::lookup_client_by_client_id(&mut conn, &client_id)
+            .await
+            .unwrap();
+        let bound = client.encrypted_client_secret.unwrap();
+        assert_eq!(
+            encrypter
+                .decrypt_string_with_ad(client_id.as_bytes(), &bound)
+                .unwrap(),
+            b"client-secret"
+        );
+
+        // Once bound, they are left as they are
+        bind_client_secrets(&pool, &encrypter).await.unwrap();
+        let client = mas_storage::oauth2::client::lookup_client_by_client_id(&mut conn, &client_id)
+            .await
+            .unwrap();
+        assert_eq!(client.encrypted_client_secret, Some(bound));
+    }
+}
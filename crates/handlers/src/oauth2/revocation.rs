@@ -0,0 +1,170 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use axum::{extract::Extension, response::IntoResponse};
+use hyper::StatusCode;
+use mas_axum_utils::client_authorization::{ClientAuthorization, CredentialsVerificationError};
+use mas_config::Encrypter;
+use mas_data_model::TokenType;
+use mas_iana::oauth::OAuthClientAuthenticationMethod;
+use mas_storage::oauth2::{
+    access_token::revoke_client_access_token, client::ClientFetchError,
+    refresh_token::revoke_client_refresh_token,
+};
+use oauth2_types::requests::RevocationRequest;
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::debug;
+
+use super::error::OAuthError;
+use crate::ClientCache;
+
+#[derive(Debug, Error)]
+pub(crate) enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error("could not find client")]
+    ClientNotFound,
+
+    #[error("client is not allowed to revoke tokens")]
+    NotAllowed,
+
+    #[error("bad request")]
+    BadRequest,
+
+    #[error(transparent)]
+    ClientCredentialsVerification(#[from] CredentialsVerificationError),
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            Self::Internal(_) => OAuthError::ServerError,
+            Self::ClientNotFound | Self::ClientCredentialsVerification(_) => {
+                OAuthError::InvalidClient
+            }
+            Self::NotAllowed => OAuthError::UnauthorizedClient,
+            Self::BadRequest => OAuthError::InvalidRequest,
+        }
+        .into_response()
+    }
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<ClientFetchError> for RouteError {
+    fn from(e: ClientFetchError) -> Self {
+        if e.not_found() {
+            Self::ClientNotFound
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
+/// The type of the token to revoke, if it is one this endpoint can revoke.
+///
+/// The hint sent by the client is ignored, since we can tell the type of the
+/// token from its prefix.
+fn revocable_token_type(token: &str) -> Option<TokenType> {
+    match TokenType::check(token) {
+        Ok(token_type @ (TokenType::AccessToken | TokenType::RefreshToken)) => Some(token_type),
+        _ => None,
+    }
+}
+
+#[tracing::instrument(skip_all, err)]
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(encrypter): Extension<Encrypter>,
+    Extension(client_cache): Extension<ClientCache>,
+    client_authorization: ClientAuthorization<RevocationRequest>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut txn = pool.begin().await?;
+
+    let client = client_cache
+        .lookup(&mut txn, client_authorization.credentials.client_id())
+        .await?;
+
+    let method = match client.token_endpoint_auth_method {
+        None | Some(OAuthClientAuthenticationMethod::None) => {
+            return Err(RouteError::NotAllowed);
+        }
+        Some(c) => c,
+    };
+
+    client_authorization
+        .credentials
+        .verify(&encrypter, method, &client)
+        .await?;
+
+    let form = client_authorization.form.ok_or(RouteError::BadRequest)?;
+
+    // Unknown tokens, and tokens issued to other clients are silently ignored,
+    // so that clients can't use this endpoint to find out which tokens exist
+    let revoked = match revocable_token_type(&form.token) {
+        Some(TokenType::AccessToken) => {
+            revoke_client_access_token(&mut txn, &client, &form.token).await?
+        }
+        Some(TokenType::RefreshToken) => {
+            revoke_client_refresh_token(&mut txn, &client, &form.token).await?
+        }
+        _ => false,
+    };
+
+    if !revoked {
+        debug!("No token revoked");
+    }
+
+    txn.commit().await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use super::*;
+
+    #[test]
+    fn oauth2_tokens_are_revocable() {
+        let mut rng = thread_rng();
+        for token_type in [TokenType::AccessToken, TokenType::RefreshToken] {
+            let token = token_type.generate(&mut rng);
+            assert_eq!(revocable_token_type(&token), Some(token_type));
+        }
+    }
+
+    #[test]
+    fn other_tokens_are_ignored() {
+        let mut rng = thread_rng();
+        for token_type in [TokenType::CompatAccessToken, TokenType::CompatRefreshToken] {
+            let token = token_type.generate(&mut rng);
+            assert_eq!(revocable_token_type(&token), None);
+        }
+
+        assert_eq!(revocable_token_type("not-a-token"), None);
+
+        // A token with a typo in it
+        let mut token = TokenType::AccessToken.generate(&mut rng);
+        token.pop();
+        token.push('?');
+        assert_eq!(revocable_token_type(&token), None);
+    }
+}
@@ -12,22 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, sync::Arc};
+use std::sync::Arc;
 
-use anyhow::Context;
 use axum::{extract::Extension, response::IntoResponse, Json};
 use chrono::{DateTime, Duration, Utc};
-use data_encoding::BASE64URL_NOPAD;
 use headers::{CacheControl, HeaderMap, HeaderMapExt, Pragma};
-use hyper::StatusCode;
 use mas_axum_utils::client_authorization::{ClientAuthorization, CredentialsVerificationError};
 use mas_config::Encrypter;
 use mas_data_model::{AuthorizationGrantStage, Client, TokenType};
 use mas_iana::jose::JsonWebSignatureAlg;
-use mas_jose::{
-    claims::{self, ClaimError},
-    DecodedJsonWebToken, SigningKeystore, StaticKeystore,
-};
+use mas_jose::StaticKeystore;
 use mas_router::UrlBuilder;
 use mas_storage::{
     oauth2::{
@@ -43,7 +37,6 @@ use mas_storage::{
     DatabaseInconsistencyError, PostgresqlBackend,
 };
 use oauth2_types::{
-    errors::{INVALID_CLIENT, INVALID_GRANT, INVALID_REQUEST, SERVER_ERROR, UNAUTHORIZED_CLIENT},
     requests::{
         AccessTokenRequest, AccessTokenResponse, AuthorizationCodeGrant, RefreshTokenGrant,
     },
@@ -52,12 +45,14 @@ use oauth2_types::{
 use rand::thread_rng;
 use serde::Serialize;
 use serde_with::{serde_as, skip_serializing_none};
-use sha2::{Digest, Sha256};
 use sqlx::{PgPool, Postgres, Transaction};
 use thiserror::Error;
 use tracing::debug;
 use url::Url;
 
+use super::{error::OAuthError, id_token::IdToken};
+use crate::ClientCache;
+
 #[serde_as]
 #[skip_serializing_none]
 #[derive(Serialize, Debug)]
@@ -98,8 +93,8 @@ pub(crate) enum RouteError {
     #[error("invalid grant")]
     InvalidGrant,
 
-    #[error("unauthorized client")]
-    UnauthorizedClient,
+    #[error("unsupported grant type")]
+    UnsupportedGrantType,
 }
 
 impl From<ClientFetchError> for RouteError {
@@ -125,17 +120,14 @@ impl From<RefreshTokenLookupError> for RouteError {
 impl IntoResponse for RouteError {
     fn into_response(self) -> axum::response::Response {
         match self {
-            Self::Internal(_) | Self::Anyhow(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(SERVER_ERROR))
-            }
-            Self::BadRequest => (StatusCode::BAD_REQUEST, Json(INVALID_REQUEST)),
+            Self::Internal(_) | Self::Anyhow(_) => OAuthError::ServerError,
+            Self::BadRequest => OAuthError::InvalidRequest,
             Self::ClientNotFound | Self::ClientCredentialsVerification(_) => {
-                (StatusCode::UNAUTHORIZED, Json(INVALID_CLIENT))
+                OAuthError::InvalidClient
             }
-            Self::ClientNotAllowed | Self::UnauthorizedClient => {
-                (StatusCode::UNAUTHORIZED, Json(UNAUTHORIZED_CLIENT))
-            }
-            Self::InvalidGrant => (StatusCode::BAD_REQUEST, Json(INVALID_GRANT)),
+            Self::ClientNotAllowed => OAuthError::UnauthorizedClient,
+            Self::InvalidGrant => OAuthError::InvalidGrant,
+            Self::UnsupportedGrantType => OAuthError::UnsupportedGrantType,
         }
         .into_response()
     }
@@ -147,12 +139,6 @@ impl From<sqlx::Error> for RouteError {
     }
 }
 
-impl From<ClaimError> for RouteError {
-    fn from(e: ClaimError) -> Self {
-        Self::Internal(Box::new(e))
-    }
-}
-
 #[tracing::instrument(skip_all, err)]
 pub(crate) async fn post(
     client_authorization: ClientAuthorization<AccessTokenRequest>,
@@ -160,10 +146,13 @@ pub(crate) async fn post(
     Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
     Extension(encrypter): Extension<Encrypter>,
+    Extension(client_cache): Extension<ClientCache>,
 ) -> Result<impl IntoResponse, RouteError> {
     let mut txn = pool.begin().await?;
 
-    let client = client_authorization.credentials.fetch(&mut txn).await?;
+    let client = client_cache
+        .lookup(&mut txn, client_authorization.credentials.client_id())
+        .await?;
 
     let method = client
         .token_endpoint_auth_method
@@ -184,7 +173,7 @@ pub(crate) async fn post(
             refresh_token_grant(&grant, &client, txn).await?
         }
         _ => {
-            return Err(RouteError::InvalidGrant);
+            return Err(RouteError::UnsupportedGrantType);
         }
     };
 
@@ -195,16 +184,6 @@ pub(crate) async fn post(
     Ok((headers, Json(reply)))
 }
 
-fn hash<H: Digest>(mut hasher: H, token: &str) -> anyhow::Result<String> {
-    hasher.update(token);
-    let hash = hasher.finalize();
-    // Left-most 128bit
-    let bits = hash
-        .get(..16)
-        .context("failed to get first 128 bits of hash")?;
-    Ok(BASE64URL_NOPAD.encode(bits))
-}
-
 #[allow(clippy::too_many_lines)]
 async fn authorization_code_grant(
     grant: &AuthorizationCodeGrant,
@@ -220,6 +199,12 @@ async fn authorization_code_grant(
     // TODO: that's not a timestamp from the DB. Let's assume they are in sync
     let now = Utc::now();
 
+    // This should never happen, since we looked up in the database using the code
+    let code = authz_grant
+        .code
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!(DatabaseInconsistencyError))?;
+
     let session = match authz_grant.stage {
         AuthorizationGrantStage::Cancelled { cancelled_at } => {
             debug!(%cancelled_at, "Authorization grant was cancelled");
@@ -245,12 +230,9 @@ async fn authorization_code_grant(
             debug!("Authorization grant has not been fulfilled yet");
             return Err(RouteError::InvalidGrant);
         }
-        AuthorizationGrantStage::Fulfilled {
-            ref session,
-            fulfilled_at,
-        } => {
-            if now - fulfilled_at > Duration::minutes(10) {
-                debug!("Code exchange took more than 10 minutes");
+        AuthorizationGrantStage::Fulfilled { ref session, .. } => {
+            if let Err(e) = code.check(now) {
+                debug!(%e, "Authorization code can't be exchanged");
                 return Err(RouteError::InvalidGrant);
             }
 
@@ -258,14 +240,9 @@ async fn authorization_code_grant(
         }
     };
 
-    // This should never happen, since we looked up in the database using the code
-    let code = authz_grant
-        .code
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!(DatabaseInconsistencyError))?;
-
-    if client.client_id != session.client.client_id {
-        return Err(RouteError::UnauthorizedClient);
+    if let Err(e) = authz_grant.check_code_binding(&client.client_id, grant.redirect_uri.as_ref()) {
+        debug!(%e, "Authorization code can't be exchanged by this client");
+        return Err(RouteError::InvalidGrant);
     }
 
     match (code.pkce.as_ref(), grant.code_verifier.as_ref()) {
@@ -297,35 +274,21 @@ async fn authorization_code_grant(
         add_refresh_token(&mut txn, session, access_token, &refresh_token_str).await?;
 
     let id_token = if session.scope.contains(&scope::OPENID) {
-        let mut claims = HashMap::new();
-        let now = Utc::now();
-        claims::ISS.insert(&mut claims, url_builder.oidc_issuer().to_string())?;
-        claims::SUB.insert(&mut claims, &browser_session.user.sub)?;
-        claims::AUD.insert(&mut claims, client.client_id.clone())?;
-        claims::IAT.insert(&mut claims, now)?;
-        claims::EXP.insert(&mut claims, now + Duration::hours(1))?;
-
-        if let Some(ref nonce) = authz_grant.nonce {
-            claims::NONCE.insert(&mut claims, nonce.clone())?;
-        }
-        if let Some(ref last_authentication) = browser_session.last_authentication {
-            claims::AUTH_TIME.insert(&mut claims, last_authentication.created_at)?;
-        }
-
-        claims::AT_HASH.insert(&mut claims, hash(Sha256::new(), &access_token_str)?)?;
-        claims::C_HASH.insert(&mut claims, hash(Sha256::new(), &grant.code)?)?;
-
-        let header = key_store
-            .prepare_header(
+        let issuer = url_builder.oidc_issuer();
+        let id_token = IdToken::new(&issuer, &client.client_id, browser_session)
+            .with_nonce(authz_grant.nonce.as_deref())
+            .with_access_token(&access_token_str)
+            .with_code(&grant.code)
+            .sign(
+                key_store,
                 client
                     .id_token_signed_response_alg
                     .unwrap_or(JsonWebSignatureAlg::Rs256),
+                Utc::now(),
             )
             .await?;
-        let id_token = DecodedJsonWebToken::new(header, claims);
-        let id_token = id_token.sign(key_store).await?;
 
-        Some(id_token.serialize())
+        Some(id_token)
     } else {
         None
     };
@@ -339,7 +302,11 @@ async fn authorization_code_grant(
         params = params.with_id_token(id_token);
     }
 
-    exchange_grant(&mut txn, authz_grant).await?;
+    // The code was exchanged by a concurrent request, dropping the transaction
+    // rolls back the tokens created for this one
+    if exchange_grant(&mut txn, authz_grant).await?.is_none() {
+        return Err(RouteError::InvalidGrant);
+    }
 
     txn.commit().await?;
 
@@ -20,24 +20,48 @@ use axum::{
     Json, TypedHeader,
 };
 use headers::ContentType;
-use mas_axum_utils::{user_authorization::UserAuthorization, FancyError};
+use hyper::StatusCode;
+use mas_axum_utils::user_authorization::{AuthorizationVerificationError, UserAuthorization};
+use mas_data_model::{StorageBackend, User};
 use mas_jose::{DecodedJsonWebToken, SigningKeystore, StaticKeystore};
 use mas_router::UrlBuilder;
 use mime::Mime;
-use oauth2_types::scope;
+use oauth2_types::scope::{self, Scope};
 use serde::Serialize;
 use serde_with::skip_serializing_none;
 use sqlx::PgPool;
+use thiserror::Error;
 
 #[skip_serializing_none]
-#[derive(Serialize)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 struct UserInfo {
     sub: String,
-    username: String,
+    preferred_username: String,
     email: Option<String>,
     email_verified: Option<bool>,
 }
 
+impl UserInfo {
+    /// The claims about the user the given scope allows the client to see
+    fn new<T: StorageBackend>(user: User<T>, scope: &Scope) -> Self {
+        let mut user_info = Self {
+            sub: user.sub,
+            preferred_username: user.username,
+            email: None,
+            email_verified: None,
+        };
+
+        if scope.contains(&scope::EMAIL) {
+            if let Some(email) = user.primary_email {
+                user_info.email_verified = Some(email.confirmed_at.is_some());
+                user_info.email = Some(email.email);
+            }
+        }
+
+        user_info
+    }
+}
+
 #[derive(Serialize)]
 struct SignedUserInfo {
     iss: String,
@@ -46,31 +70,47 @@ struct SignedUserInfo {
     user_info: UserInfo,
 }
 
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error(transparent)]
+    Unauthorized(#[from] AuthorizationVerificationError),
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        match self {
+            // This sets the `WWW-Authenticate` header the bearer token clients expect
+            Self::Unauthorized(e) => e.into_response(),
+            Self::Internal(_) | Self::Anyhow(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
 pub async fn get(
     Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
     Extension(key_store): Extension<Arc<StaticKeystore>>,
     user_authorization: UserAuthorization,
-) -> Result<Response, FancyError> {
-    // TODO: error handling
+) -> Result<Response, RouteError> {
     let mut conn = pool.acquire().await?;
 
     let session = user_authorization.protected(&mut conn).await?;
 
-    let user = session.browser_session.user;
-    let mut user_info = UserInfo {
-        sub: user.sub,
-        username: user.username,
-        email: None,
-        email_verified: None,
-    };
-
-    if session.scope.contains(&scope::EMAIL) {
-        if let Some(email) = user.primary_email {
-            user_info.email_verified = Some(email.confirmed_at.is_some());
-            user_info.email = Some(email.email);
-        }
-    }
+    let user_info = UserInfo::new(session.browser_session.user, &session.scope);
 
     if let Some(alg) = session.client.userinfo_signed_response_alg {
         let header = key_store.prepare_header(alg).await?;
@@ -92,3 +132,60 @@ pub async fn get(
         Ok(Json(user_info).into_response())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::UserEmail;
+
+    use super::*;
+
+    fn user(email: Option<UserEmail<()>>) -> User<()> {
+        User {
+            data: (),
+            username: "alice".to_owned(),
+            sub: "123-456".to_owned(),
+            primary_email: email,
+        }
+    }
+
+    #[test]
+    fn email_claims_need_the_email_scope() {
+        let email = UserEmail::<()>::samples().remove(0);
+        let openid: Scope = "openid".parse().unwrap();
+
+        let user_info = UserInfo::new(user(Some(email.clone())), &openid);
+        assert_eq!(
+            user_info,
+            UserInfo {
+                sub: "123-456".to_owned(),
+                preferred_username: "alice".to_owned(),
+                email: None,
+                email_verified: None,
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&user_info).unwrap(),
+            serde_json::json!({ "sub": "123-456", "preferred_username": "alice" })
+        );
+
+        let with_email: Scope = "openid email".parse().unwrap();
+        let user_info = UserInfo::new(user(Some(email)), &with_email);
+        assert_eq!(user_info.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(user_info.email_verified, Some(true));
+    }
+
+    #[test]
+    fn email_claims_of_unverified_emails() {
+        let email = UserEmail::<()>::samples().remove(1);
+        let scope: Scope = "openid email".parse().unwrap();
+
+        let user_info = UserInfo::new(user(Some(email)), &scope);
+        assert_eq!(user_info.email.as_deref(), Some("bob@example.com"));
+        assert_eq!(user_info.email_verified, Some(false));
+
+        // Users without an email don't get email claims, even with the scope
+        let user_info = UserInfo::new(user(None), &scope);
+        assert_eq!(user_info.email, None);
+        assert_eq!(user_info.email_verified, None);
+    }
+}
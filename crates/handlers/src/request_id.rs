@@ -0,0 +1,161 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Identify each request with an `X-Request-Id`
+//!
+//! The ID sent by the client, or by a reverse proxy in front of the service,
+//! is kept if it looks sane, else one is generated. It is recorded on the
+//! request span, so that it shows up in the logs and traces, and echoed in the
+//! response, so that reports from the users can be matched with them.
+
+use std::fmt;
+
+use axum::{middleware::Next, response::Response};
+use data_encoding::HEXLOWER;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Request,
+};
+use opentelemetry::{trace::TraceContextExt, Context, KeyValue};
+use rand::{thread_rng, Rng};
+use tracing::{info_span, Instrument};
+
+/// The header carrying the ID of the request
+pub(crate) static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longer IDs sent by the clients are replaced
+const MAX_LENGTH: usize = 128;
+
+/// The ID of a request, available to the handlers as an extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestId(String);
+
+impl RequestId {
+    fn generate(rng: &mut impl Rng) -> Self {
+        let bytes: [u8; 16] = rng.gen();
+        Self(HEXLOWER.encode(&bytes))
+    }
+
+    /// The ID sent by the client, if it is made of visible ASCII characters
+    /// and isn't too long
+    fn from_header(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_LENGTH
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| Self(value.to_owned()))
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        // Both the generated and the accepted IDs are visible ASCII
+        HeaderValue::from_str(&self.0).unwrap_or_else(|_| HeaderValue::from_static("invalid"))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Middleware of all the routes, giving an ID to the request and echoing it in
+/// the response
+pub(crate) async fn middleware<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(RequestId::from_header)
+        .unwrap_or_else(|| RequestId::generate(&mut thread_rng()));
+    let header_value = request_id.header_value();
+
+    request
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), header_value.clone());
+    request.extensions_mut().insert(request_id.clone());
+
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("http.request_id", request_id.0.clone()));
+    let span = info_span!("request", request.id = %request_id);
+
+    let mut response = next.run(request).instrument(span).await;
+    response
+        .headers_mut()
+        .insert(X_REQUEST_ID.clone(), header_value);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Extension, middleware::from_fn, routing::get, Router};
+    use hyper::{Body, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Call a route which replies with the ID it got
+    async fn call(request_id: Option<&str>) -> (Response, String) {
+        let router: Router =
+            Router::new()
+                .route(
+                    "/",
+                    get(|Extension(request_id): Extension<RequestId>| async move {
+                        request_id.to_string()
+                    }),
+                )
+                .layer(from_fn(middleware));
+
+        let mut request = Request::builder().uri("/");
+        if let Some(request_id) = request_id {
+            request = request.header(&X_REQUEST_ID, request_id);
+        }
+        let request = request.body(Body::empty()).unwrap();
+
+        let mut response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(std::mem::take(response.body_mut()))
+            .await
+            .unwrap();
+        (response, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn client_id_is_echoed() {
+        let (response, seen) = call(Some("abc-123")).await;
+        assert_eq!(response.headers()[&X_REQUEST_ID], "abc-123");
+        assert_eq!(seen, "abc-123");
+    }
+
+    #[tokio::test]
+    async fn id_is_generated() {
+        let (response, seen) = call(None).await;
+        let request_id = response.headers()[&X_REQUEST_ID].to_str().unwrap();
+        assert_eq!(request_id.len(), 32);
+        assert_eq!(request_id, seen);
+
+        // Each request gets its own ID
+        let (other, _) = call(None).await;
+        assert_ne!(other.headers()[&X_REQUEST_ID], request_id);
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_replaced() {
+        let long = "a".repeat(MAX_LENGTH + 1);
+        for invalid in ["", "has spaces", long.as_str()] {
+            let (response, seen) = call(Some(invalid)).await;
+            assert_ne!(response.headers()[&X_REQUEST_ID], invalid);
+            assert_eq!(seen.len(), 32);
+        }
+    }
+}
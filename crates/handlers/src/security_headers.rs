@@ -0,0 +1,369 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Security headers of the HTML pages
+//!
+//! The pages get a Content Security Policy, and are kept from being framed or
+//! sniffed. The API responses are left alone, as those headers only matter
+//! to documents rendered by browsers.
+//!
+//! Each request gets a [`CspNonce`], which the policy of its response allows.
+//! Templates with inline scripts get it with
+//! [`mas_templates::TemplateContext::with_csp_nonce`], so that only their
+//! scripts run.
+
+use std::fmt;
+
+use axum::{middleware::Next, response::Response};
+use data_encoding::BASE64URL_NOPAD;
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE, REFERRER_POLICY,
+        X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    Request,
+};
+use mas_config::CspConfig;
+use rand::{thread_rng, Rng};
+use tracing::warn;
+
+use crate::challenge::Challenge;
+
+/// A random value, generated for each request, which the inline scripts of the
+/// page need to have to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CspNonce(String);
+
+impl CspNonce {
+    fn generate(rng: &mut impl Rng) -> Self {
+        let bytes: [u8; 16] = rng.gen();
+        // The templates escape the `/` of the standard alphabet
+        Self(BASE64URL_NOPAD.encode(&bytes))
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SecurityHeaders {
+    /// The `script-src` directive, to which the nonce is added
+    script_src: String,
+
+    /// The other directives
+    policy: String,
+}
+
+/// A directive of the policy, from its sources
+fn directive<'a>(name: &str, sources: impl IntoIterator<Item = &'a str>) -> String {
+    let sources: Vec<&str> = sources.into_iter().collect();
+    if sources.is_empty() {
+        format!("{} 'none'", name)
+    } else {
+        format!("{} {}", name, sources.join(" "))
+    }
+}
+
+impl SecurityHeaders {
+    /// Build the headers from the configuration. The sources of the challenge
+    /// widget are allowed, and invalid asset hosts are skipped with a warning
+    pub(crate) fn new(config: &CspConfig, challenge: &Challenge) -> Self {
+        let challenge_sources = challenge
+            .as_ref()
+            .map(|provider| provider.content_sources())
+            .unwrap_or_default();
+
+        // Sources can't contain separators of the directives and sources
+        let asset_hosts: Vec<&str> = config
+            .asset_hosts
+            .iter()
+            .map(String::as_str)
+            .filter(|host| {
+                let valid = !host.is_empty()
+                    && !host.contains(|c: char| c == ';' || c == ',' || c.is_whitespace());
+                if !valid {
+                    warn!(%host, "Ignoring invalid CSP asset host");
+                }
+                valid
+            })
+            .collect();
+
+        let this = std::iter::once("'self'");
+        let script_src = directive(
+            "script-src",
+            this.clone()
+                .chain(challenge_sources.iter().copied())
+                .chain(asset_hosts.iter().copied()),
+        );
+        let policy = [
+            directive("default-src", this.clone()),
+            directive(
+                "style-src",
+                this.clone()
+                    .chain(challenge_sources.iter().copied())
+                    .chain(asset_hosts.iter().copied()),
+            ),
+            directive(
+                "img-src",
+                this.clone()
+                    .chain(std::iter::once("data:"))
+                    .chain(asset_hosts.iter().copied()),
+            ),
+            directive("font-src", this.clone().chain(asset_hosts.iter().copied())),
+            directive("connect-src", this.chain(challenge_sources.iter().copied())),
+            directive("frame-src", challenge_sources.iter().copied()),
+            directive("object-src", []),
+            directive("base-uri", []),
+            directive("frame-ancestors", []),
+        ]
+        .join("; ");
+
+        Self { script_src, policy }
+    }
+
+    /// The policy of a response, allowing the scripts with its nonce
+    fn content_security_policy(&self, nonce: &CspNonce) -> HeaderValue {
+        let policy = format!("{}; {} 'nonce-{}'", self.policy, self.script_src, nonce);
+
+        // The sources were checked when building the policy
+        HeaderValue::from_str(&policy)
+            .unwrap_or_else(|_| HeaderValue::from_static("default-src 'self'"))
+    }
+
+    /// Add the headers to an HTML response. Those already set by the handler
+    /// are kept
+    fn apply(&self, response: &mut Response, nonce: &CspNonce) {
+        let is_html = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, |value| value.starts_with("text/html"));
+        if !is_html {
+            return;
+        }
+
+        let headers: [(HeaderName, HeaderValue); 4] = [
+            (CONTENT_SECURITY_POLICY, self.content_security_policy(nonce)),
+            (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+            (REFERRER_POLICY, HeaderValue::from_static("same-origin")),
+            (X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+        ];
+
+        for (name, value) in headers {
+            response.headers_mut().entry(name).or_insert(value);
+        }
+    }
+}
+
+/// Middleware of the HTML pages, giving a nonce to the request and adding the
+/// security headers to its response
+pub(crate) async fn html<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let security_headers = request.extensions().get::<SecurityHeaders>().cloned();
+    let nonce = CspNonce::generate(&mut thread_rng());
+    request.extensions_mut().insert(nonce.clone());
+
+    let mut response = next.run(request).await;
+    if let Some(security_headers) = security_headers {
+        security_headers.apply(&mut response, &nonce);
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, sync::Arc};
+
+    use async_trait::async_trait;
+    use axum::{
+        extract::Extension,
+        middleware::from_fn,
+        response::{Html, IntoResponse},
+        routing::get,
+        Json, Router,
+    };
+    use hyper::{Body, StatusCode};
+    use mas_config::TemplatesConfig;
+    use mas_templates::{ChallengeWidget, EmptyContext, FormPostContext, Templates};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::challenge::{ChallengeError, ChallengeProvider};
+
+    struct MockProvider;
+
+    #[async_trait]
+    impl ChallengeProvider for MockProvider {
+        fn widget(&self) -> ChallengeWidget {
+            ChallengeWidget::new("https://challenge.example.com/api.js", "mock", "site-key")
+        }
+
+        fn content_sources(&self) -> &'static [&'static str] {
+            &["https://challenge.example.com"]
+        }
+
+        async fn verify(
+            &self,
+            _response: &str,
+            _remote_ip: Option<IpAddr>,
+        ) -> Result<(), ChallengeError> {
+            Ok(())
+        }
+    }
+
+    /// A router with an HTML page, like the emails page, a page with an inline
+    /// script, like the `form_post` one, and a JSON endpoint, like the
+    /// compatibility layer ones
+    async fn call(security_headers: &SecurityHeaders, uri: &str) -> Response {
+        let templates = Templates::load_from_config(&TemplatesConfig::default())
+            .await
+            .unwrap();
+
+        let router: Router = Router::new()
+            .route(
+                "/account/emails",
+                get(|| async { Html("<!DOCTYPE html><title>Emails</title>") }),
+            )
+            .route(
+                "/form_post",
+                get(
+                    |Extension(templates): Extension<Templates>,
+                     Extension(nonce): Extension<CspNonce>| async move {
+                        let ctx = FormPostContext::new(
+                            "https://client.example.com/callback".parse().unwrap(),
+                            EmptyContext,
+                        )
+                        .with_csp_nonce(nonce);
+                        Html(templates.render_form_post(&ctx).await.unwrap())
+                    },
+                ),
+            )
+            .route(
+                "/_matrix/client/v3/login",
+                get(|| async { Json(serde_json::json!({ "flows": [] })) }),
+            )
+            .layer(from_fn(html))
+            .layer(Extension(security_headers.clone()))
+            .layer(Extension(templates));
+
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        router.oneshot(request).await.unwrap()
+    }
+
+    fn policy(response: &Response) -> &str {
+        response.headers()[CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn html_pages_get_the_headers() {
+        let security_headers = SecurityHeaders::new(&CspConfig::default(), &None);
+        let response = call(&security_headers, "/account/emails").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let policy = policy(&response);
+        assert!(policy.starts_with("default-src 'self';"));
+        assert!(policy.contains("; script-src 'self' 'nonce-"));
+        assert!(policy.contains("frame-src 'none'"));
+        assert!(policy.contains("frame-ancestors 'none'"));
+
+        let headers = response.headers();
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[REFERRER_POLICY], "same-origin");
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[tokio::test]
+    async fn inline_scripts_have_the_nonce() {
+        let security_headers = SecurityHeaders::new(&CspConfig::default(), &None);
+        let response = call(&security_headers, "/form_post").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let nonce = policy(&response)
+            .split_once("'nonce-")
+            .and_then(|(_, rest)| rest.split_once('\''))
+            .map(|(nonce, _)| nonce.to_owned())
+            .unwrap();
+        assert!(!nonce.is_empty());
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(&format!(r#"<script nonce="{}">"#, nonce)));
+
+        // Each response gets its own nonce
+        let response = call(&security_headers, "/form_post").await;
+        assert!(!policy(&response).contains(&nonce));
+    }
+
+    #[tokio::test]
+    async fn api_responses_are_left_alone() {
+        let security_headers = SecurityHeaders::new(&CspConfig::default(), &None);
+        let response = call(&security_headers, "/_matrix/client/v3/login").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        for name in [
+            CONTENT_SECURITY_POLICY,
+            X_CONTENT_TYPE_OPTIONS,
+            REFERRER_POLICY,
+            X_FRAME_OPTIONS,
+        ] {
+            assert!(headers.get(&name).is_none(), "{} should not be set", name);
+        }
+    }
+
+    #[test]
+    fn allowed_sources() {
+        let config = CspConfig {
+            asset_hosts: vec![
+                "https://assets.example.com".to_owned(),
+                "https://evil.example.com; script-src *".to_owned(),
+            ],
+        };
+        let challenge: Challenge = Some(Arc::new(MockProvider));
+        let security_headers = SecurityHeaders::new(&config, &challenge);
+        let nonce = CspNonce("abc".to_owned());
+        let policy = security_headers.content_security_policy(&nonce);
+        let policy = policy.to_str().unwrap();
+
+        assert!(policy.ends_with(
+            "; script-src 'self' https://challenge.example.com https://assets.example.com \
+             'nonce-abc'"
+        ));
+        assert!(policy.contains("img-src 'self' data: https://assets.example.com;"));
+        assert!(policy.contains("frame-src https://challenge.example.com;"));
+        assert!(!policy.contains("evil"));
+    }
+
+    #[test]
+    fn handler_headers_are_kept() {
+        let security_headers = SecurityHeaders::new(&CspConfig::default(), &None);
+        let mut response = Html("<!DOCTYPE html>").into_response();
+        response.headers_mut().insert(
+            CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("default-src 'none'"),
+        );
+        security_headers.apply(&mut response, &CspNonce("abc".to_owned()));
+
+        assert_eq!(
+            response.headers()[CONTENT_SECURITY_POLICY],
+            "default-src 'none'"
+        );
+        assert_eq!(response.headers()[X_FRAME_OPTIONS], "DENY");
+    }
+}
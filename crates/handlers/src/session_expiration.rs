@@ -0,0 +1,177 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End the browser sessions which are too old, or which were not used for too
+//! long
+
+use axum::{
+    extract::{Extension, FromRequest, RequestParts},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{DateTime, Duration, Utc};
+use hyper::{Method, Request};
+use mas_axum_utils::{FancyError, SessionInfoExt};
+use mas_config::{Encrypter, SessionsConfig};
+use mas_router::Route;
+use mas_storage::user::{
+    expire_session, lookup_session_activity, record_session_activity, SessionActivity,
+};
+use sqlx::PgPool;
+use tracing::info;
+
+/// How long the browser sessions last
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SessionExpiration {
+    absolute_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl SessionExpiration {
+    pub(crate) fn from_config(config: &SessionsConfig) -> Self {
+        Self {
+            absolute_lifetime: config.absolute_lifetime,
+            idle_timeout: config.idle_timeout,
+        }
+    }
+
+    /// Whether a session is past its absolute lifetime, or was idle for too
+    /// long
+    fn is_expired(&self, activity: &SessionActivity, now: DateTime<Utc>) -> bool {
+        let too_old = self
+            .absolute_lifetime
+            .map_or(false, |lifetime| now - activity.created_at >= lifetime);
+        let idle = self
+            .idle_timeout
+            .map_or(false, |timeout| now - activity.last_active_at >= timeout);
+        too_old || idle
+    }
+}
+
+/// Check the session of the request, recording its activity.
+///
+/// If it expired, it is ended, and the cookie jar clearing it is returned
+async fn check<B: Send>(
+    req: &mut RequestParts<B>,
+) -> Result<Option<PrivateCookieJar<Encrypter>>, FancyError> {
+    let cookie_jar = PrivateCookieJar::<Encrypter>::from_request(req).await?;
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+    let id = match session_info.current_session_id() {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let Extension(expiration) = Extension::<SessionExpiration>::from_request(req).await?;
+    let Extension(pool) = Extension::<PgPool>::from_request(req).await?;
+    let mut conn = pool.acquire().await?;
+
+    // Sessions which already ended are handled by the pages themselves
+    let activity = match lookup_session_activity(&mut conn, id).await? {
+        Some(activity) => activity,
+        None => return Ok(None),
+    };
+
+    if expiration.is_expired(&activity, Utc::now()) {
+        expire_session(&mut conn, id).await?;
+        info!(session.id = id, "Browser session expired");
+        let cookie_jar = cookie_jar.update_session_info(&session_info.mark_session_ended());
+        return Ok(Some(cookie_jar));
+    }
+
+    record_session_activity(&mut conn, id).await?;
+    Ok(None)
+}
+
+/// Middleware of the HTML pages, ending the expired sessions.
+///
+/// The session cookie is then cleared, and the user sent back to the same page
+/// if it can be loaded again, or else to the login page
+pub(crate) async fn html<B: Send>(request: Request<B>, next: Next<B>) -> Response {
+    let mut req = RequestParts::new(request);
+
+    match check(&mut req).await {
+        Ok(None) => {}
+        Ok(Some(cookie_jar)) => {
+            let destination = if matches!(*req.method(), Method::GET | Method::HEAD) {
+                Redirect::to(&req.uri().to_string())
+            } else {
+                mas_router::Login::default().go()
+            };
+            return (cookie_jar, destination).into_response();
+        }
+        Err(e) => return e.into_response(),
+    }
+
+    match req.try_into_request() {
+        Ok(request) => next.run(request).await,
+        Err(e) => FancyError::from(e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expiration() -> SessionExpiration {
+        SessionExpiration {
+            absolute_lifetime: Some(Duration::days(7)),
+            idle_timeout: Some(Duration::hours(1)),
+        }
+    }
+
+    #[test]
+    fn active_session() {
+        let now = Utc::now();
+        let activity = SessionActivity {
+            created_at: now - Duration::days(6),
+            last_active_at: now - Duration::minutes(59),
+        };
+
+        assert!(!expiration().is_expired(&activity, now));
+    }
+
+    #[test]
+    fn idle_session() {
+        let now = Utc::now();
+        let activity = SessionActivity {
+            created_at: now - Duration::days(1),
+            last_active_at: now - Duration::hours(1),
+        };
+
+        assert!(expiration().is_expired(&activity, now));
+    }
+
+    #[test]
+    fn old_session() {
+        let now = Utc::now();
+        let activity = SessionActivity {
+            created_at: now - Duration::days(7),
+            last_active_at: now - Duration::seconds(5),
+        };
+
+        assert!(expiration().is_expired(&activity, now));
+    }
+
+    #[test]
+    fn sessions_last_by_default() {
+        let now = Utc::now();
+        let activity = SessionActivity {
+            created_at: now - Duration::days(365),
+            last_active_at: now - Duration::days(30),
+        };
+
+        assert!(!SessionExpiration::default().is_expired(&activity, now));
+    }
+}
@@ -0,0 +1,576 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logins on upstream providers, for the Matrix clients which asked for one
+//! through the `m.login.sso` flow
+//!
+//! The users are sent to the provider with an authorization code request, and
+//! come back on the callback. The code is exchanged for an access token, and
+//! the claims about the user are fetched with it. Users logging in for the
+//! first time are registered, and linked to their subject on the provider.
+//! The users are then logged in here, and confirm the login of the client.
+
+use axum::{
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::extract::{cookie::Cookie, PrivateCookieJar};
+use chrono::{Duration, Utc};
+use hyper::StatusCode;
+use mas_axum_utils::{CookieExt, SessionInfoExt};
+use mas_config::{EmailConfig, Encrypter, UsernamesConfig};
+use mas_data_model::{User, UsernamePolicy, UsernamePolicyError};
+use mas_email::EmailNormalizer;
+use mas_router::{Route, UrlBuilder};
+use mas_storage::{
+    compat::get_compat_sso_login_by_id,
+    upstream_oauth2::{add_upstream_link, lookup_user_by_upstream_subject, register_upstream_user},
+    user::{
+        check_username_available, lookup_user_by_verified_email, record_session_authentication,
+        start_session, UsernameError,
+    },
+    PostgresqlBackend,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use thiserror::Error;
+use tracing::info;
+use url::Url;
+
+pub(crate) use self::provider::{from_config, UpstreamProvider, UpstreamProviders};
+use self::provider::{UpstreamError, UpstreamUser};
+use crate::webhooks::{WebhookEvent, Webhooks};
+
+mod provider;
+
+/// Name of the cookie holding the pending upstream login
+const COOKIE_NAME: &str = "upstream-oauth2";
+
+/// What to do once the user comes back from the provider
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum UpstreamAction {
+    /// Complete a compatibility SSO login
+    Login { login_id: i64 },
+
+    /// Link the account on the provider to the logged in user
+    Link { user_id: i64 },
+}
+
+/// An upstream login in progress, kept in a private cookie until the user
+/// comes back from the provider
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct UpstreamSession {
+    provider: String,
+    state: String,
+    code_verifier: String,
+    action: UpstreamAction,
+}
+
+impl UpstreamSession {
+    fn generate(provider: &str, action: UpstreamAction) -> Self {
+        let mut rng = thread_rng();
+        Self {
+            provider: provider.to_owned(),
+            state: Alphanumeric.sample_string(&mut rng, 32),
+            code_verifier: Alphanumeric.sample_string(&mut rng, 64),
+            action,
+        }
+    }
+
+    /// Check that the user came back from the provider this login was started
+    /// on, with the same state
+    fn verify(&self, provider: &str, state: Option<&str>) -> Result<(), RouteError> {
+        if self.provider == provider && state == Some(self.state.as_str()) {
+            Ok(())
+        } else {
+            Err(RouteError::StateMismatch)
+        }
+    }
+}
+
+/// Start a login on an upstream provider, to complete the given action once
+/// the user comes back. Returns the URL to redirect the user to.
+pub(crate) fn start(
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    url_builder: &UrlBuilder,
+    provider: &dyn UpstreamProvider,
+    action: UpstreamAction,
+) -> (Url, PrivateCookieJar<Encrypter>) {
+    let session = UpstreamSession::generate(provider.id(), action);
+    let redirect_uri = url_builder.upstream_oauth2_callback(provider.id());
+    let url = provider.authorization_url(&redirect_uri, &session.state, &session.code_verifier);
+
+    let mut cookie = Cookie::new(COOKIE_NAME, "");
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    let cookie_jar = cookie_jar.add(cookie.encode(&session));
+
+    (url, cookie_jar)
+}
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("unknown upstream provider")]
+    UnknownProvider,
+
+    #[error("no login in progress on this upstream provider")]
+    MissingSession,
+
+    #[error("the state does not match the login in progress")]
+    StateMismatch,
+
+    #[error("the upstream provider returned an error: {0}")]
+    Upstream(String),
+
+    #[error("the upstream provider did not return an authorization code")]
+    MissingCode,
+
+    #[error(transparent)]
+    Provider(#[from] UpstreamError),
+
+    #[error("the upstream provider did not give a username for the new user")]
+    MissingUsername,
+
+    #[error("new users can't log in with this upstream provider")]
+    ProvisioningDisabled,
+
+    #[error("this login session expired")]
+    LoginExpired,
+
+    #[error("the account to link to is not logged in anymore")]
+    NotLoggedIn,
+
+    #[error("this upstream account is already linked to another user")]
+    AlreadyLinked,
+
+    #[error(transparent)]
+    Username(#[from] UsernameError),
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::UnknownProvider => StatusCode::NOT_FOUND,
+            Self::ProvisioningDisabled => StatusCode::FORBIDDEN,
+            Self::MissingSession
+            | Self::StateMismatch
+            | Self::Upstream(_)
+            | Self::MissingCode
+            | Self::LoginExpired
+            | Self::NotLoggedIn => StatusCode::BAD_REQUEST,
+            Self::AlreadyLinked
+            | Self::Username(UsernameError::Policy(_) | UsernameError::Taken) => {
+                StatusCode::CONFLICT
+            }
+            Self::Internal(_)
+            | Self::Anyhow(_)
+            | Self::Provider(_)
+            | Self::MissingUsername
+            | Self::Username(UsernameError::Fetch(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, format!("{}", self)).into_response()
+    }
+}
+
+/// How many numbered usernames to try when the rendered one is taken
+const MAX_USERNAME_SUFFIX: usize = 100;
+
+/// The username to register a user logging in for the first time with, before
+/// handling the collisions
+fn new_username<'a>(
+    provider: &dyn UpstreamProvider,
+    upstream_user: &'a UpstreamUser,
+) -> Result<&'a str, RouteError> {
+    if !provider.provision_users() {
+        return Err(RouteError::ProvisioningDisabled);
+    }
+
+    upstream_user
+        .username
+        .as_deref()
+        .ok_or(RouteError::MissingUsername)
+}
+
+/// The usernames to try for a new user: the rendered one, then with a number
+/// appended
+fn candidate_usernames(base: &str) -> impl Iterator<Item = String> + '_ {
+    std::iter::once(base.to_owned())
+        .chain((1..MAX_USERNAME_SUFFIX).map(move |suffix| format!("{}{}", base, suffix)))
+}
+
+/// The normalized email to find the local user to link to with, if the
+/// provider links the users by email and verified the email of this one
+fn email_to_link(
+    provider: &dyn UpstreamProvider,
+    upstream_user: &UpstreamUser,
+    normalizer: EmailNormalizer,
+) -> Option<String> {
+    if !provider.link_by_email() {
+        return None;
+    }
+
+    upstream_user
+        .email
+        .as_deref()
+        .map(|email| normalizer.normalize(email))
+}
+
+/// Find the user linked to the upstream user, or link them to the local user
+/// with the same verified email, or register them if they log in for the first
+/// time and the provider allows it
+async fn find_or_provision_user(
+    txn: &mut Transaction<'_, Postgres>,
+    provider: &dyn UpstreamProvider,
+    upstream_user: &UpstreamUser,
+    policy: &UsernamePolicy,
+    normalizer: EmailNormalizer,
+    webhooks: &Webhooks,
+) -> Result<User<PostgresqlBackend>, RouteError> {
+    let existing =
+        lookup_user_by_upstream_subject(&mut *txn, provider.id(), &upstream_user.subject)
+            .await
+            .map_err(anyhow::Error::from)?;
+    if let Some(user) = existing {
+        return Ok(user);
+    }
+
+    if let Some(normalized_email) = email_to_link(provider, upstream_user, normalizer) {
+        let local = lookup_user_by_verified_email(&mut *txn, &normalized_email)
+            .await
+            .map_err(anyhow::Error::from)?;
+        if let Some(user) = local {
+            add_upstream_link(&mut *txn, provider.id(), &upstream_user.subject, &user).await?;
+            info!(user.id = user.data, "Linked upstream account by email");
+            return Ok(user);
+        }
+    }
+
+    let base = new_username(provider, upstream_user)?;
+
+    // Reserved usernames are handled like the taken ones, and get a number
+    let mut username = None;
+    for candidate in candidate_usernames(base) {
+        match check_username_available(&mut *txn, policy, &candidate).await {
+            Ok(()) => {
+                username = Some(candidate);
+                break;
+            }
+            Err(UsernameError::Taken | UsernameError::Policy(UsernamePolicyError::Reserved)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let username = username.ok_or(RouteError::Username(UsernameError::Taken))?;
+
+    let normalized_email = upstream_user
+        .email
+        .as_deref()
+        .map(|email| normalizer.normalize(email));
+    let verified_email = upstream_user
+        .email
+        .as_deref()
+        .zip(normalized_email.as_deref());
+
+    let user = register_upstream_user(
+        &mut *txn,
+        provider.id(),
+        &upstream_user.subject,
+        &username,
+        verified_email,
+    )
+    .await?;
+
+    webhooks
+        .notify(&mut *txn, &WebhookEvent::user_created(&user))
+        .await?;
+
+    Ok(user)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[tracing::instrument(skip_all, err)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn callback(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(providers): Extension<UpstreamProviders>,
+    Extension(usernames_config): Extension<UsernamesConfig>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(webhooks): Extension<Webhooks>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Path(provider_id): Path<String>,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, RouteError> {
+    let provider = providers
+        .get(&provider_id)
+        .ok_or(RouteError::UnknownProvider)?;
+
+    let session: UpstreamSession = cookie_jar
+        .get(COOKIE_NAME)
+        .and_then(|cookie| cookie.decode().ok())
+        .ok_or(RouteError::MissingSession)?;
+    session.verify(provider.id(), params.state.as_deref())?;
+
+    // The login can only be attempted once
+    let cookie_jar = cookie_jar.remove(Cookie::named(COOKIE_NAME));
+
+    if let Some(error) = params.error {
+        return Err(RouteError::Upstream(error));
+    }
+    let code = params.code.ok_or(RouteError::MissingCode)?;
+
+    let redirect_uri = url_builder.upstream_oauth2_callback(provider.id());
+    let access_token = provider
+        .exchange_code(&redirect_uri, &code, &session.code_verifier)
+        .await?;
+    let claims = provider.fetch_claims(&access_token).await?;
+    let upstream_user = provider.map_claims(&claims)?;
+
+    let mut txn = pool.begin().await?;
+
+    match session.action {
+        UpstreamAction::Login { login_id } => {
+            let login = get_compat_sso_login_by_id(&mut txn, login_id)
+                .await
+                .map_err(anyhow::Error::from)?;
+
+            // Same limit as the logins completed here
+            if Utc::now() > login.created_at + Duration::minutes(30) {
+                return Err(RouteError::LoginExpired);
+            }
+
+            let policy = UsernamePolicy {
+                min_length: usernames_config.min_length,
+                max_length: usernames_config.max_length,
+                reserved: usernames_config.reserved,
+            };
+            let normalizer = EmailNormalizer::from(&email_config);
+            let user = find_or_provision_user(
+                &mut txn,
+                provider,
+                &upstream_user,
+                &policy,
+                normalizer,
+                &webhooks,
+            )
+            .await?;
+
+            // Log the user in here, and let them confirm the login of the client
+            // like the users who logged in with a password
+            let mut session = start_session(&mut txn, user).await?;
+            record_session_authentication(&mut txn, &mut session).await?;
+
+            txn.commit().await?;
+
+            info!(
+                user.id = session.user.data,
+                user_session.id = session.data,
+                "Logged in with an upstream provider"
+            );
+
+            let cookie_jar = cookie_jar.set_session(&session);
+            let destination = mas_router::CompatLoginSsoComplete(login.data);
+            Ok((cookie_jar, destination.go()).into_response())
+        }
+
+        UpstreamAction::Link { user_id } => {
+            // The user who started linking has to still be logged in
+            let (session_info, cookie_jar) = cookie_jar.session_info();
+            let user = session_info
+                .load_session(&mut txn)
+                .await
+                .map_err(|e| RouteError::Internal(Box::new(e)))?
+                .map(|session| session.user)
+                .filter(|user| user.data == user_id)
+                .ok_or(RouteError::NotLoggedIn)?;
+
+            let linked =
+                lookup_user_by_upstream_subject(&mut txn, provider.id(), &upstream_user.subject)
+                    .await
+                    .map_err(anyhow::Error::from)?;
+            match linked {
+                Some(linked) if linked.data == user.data => {}
+                Some(_) => return Err(RouteError::AlreadyLinked),
+                None => {
+                    add_upstream_link(&mut txn, provider.id(), &upstream_user.subject, &user)
+                        .await?;
+                    info!(user.id = user.data, "Linked upstream account");
+                }
+            }
+
+            txn.commit().await?;
+
+            Ok((cookie_jar, mas_router::AccountUpstream.go()).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_config::UpstreamOAuth2Config;
+
+    use super::*;
+
+    fn providers(provision_users: bool) -> UpstreamProviders {
+        providers_with(provision_users, false)
+    }
+
+    fn providers_with(provision_users: bool, link_by_email: bool) -> UpstreamProviders {
+        let config: UpstreamOAuth2Config = serde_json::from_value(serde_json::json!({
+            "providers": [{
+                "id": "example",
+                "name": "Example",
+                "client_id": "mas",
+                "client_secret": "secret",
+                "authorization_endpoint": "https://sso.example.com/authorize",
+                "token_endpoint": "https://sso.example.com/token",
+                "userinfo_endpoint": "https://sso.example.com/userinfo",
+                "provision_users": provision_users,
+                "link_by_email": link_by_email,
+            }],
+        }))
+        .unwrap();
+        from_config(&config)
+    }
+
+    fn upstream_user(username: Option<&str>) -> UpstreamUser {
+        UpstreamUser {
+            subject: "abc".to_owned(),
+            username: username.map(ToOwned::to_owned),
+            email: None,
+        }
+    }
+
+    #[test]
+    fn users_are_linked_by_email_if_enabled() {
+        let normalizer = EmailNormalizer::default();
+        let user = UpstreamUser {
+            email: Some("Alice@Example.com".to_owned()),
+            ..upstream_user(Some("alice"))
+        };
+
+        // Off by default
+        let providers = providers(true);
+        let provider = providers.get("example").unwrap();
+        assert_eq!(email_to_link(provider, &user, normalizer), None);
+
+        let providers = providers_with(true, true);
+        let provider = providers.get("example").unwrap();
+        assert_eq!(
+            email_to_link(provider, &user, normalizer).as_deref(),
+            Some("Alice@example.com")
+        );
+
+        // The provider did not verify the email
+        assert_eq!(
+            email_to_link(provider, &upstream_user(Some("alice")), normalizer),
+            None
+        );
+    }
+
+    #[test]
+    fn actions_survive_the_cookie() {
+        for action in [
+            UpstreamAction::Login { login_id: 42 },
+            UpstreamAction::Link { user_id: 42 },
+        ] {
+            let session = UpstreamSession::generate("example", action);
+            let cookie = Cookie::new(COOKIE_NAME, "").encode(&session);
+            let decoded: UpstreamSession = cookie.decode().unwrap();
+            assert_eq!(decoded, session);
+        }
+    }
+
+    #[test]
+    fn new_users_are_provisioned() {
+        let providers = providers(true);
+        let provider = providers.get("example").unwrap();
+
+        assert_eq!(
+            new_username(provider, &upstream_user(Some("alice"))).unwrap(),
+            "alice"
+        );
+        assert!(matches!(
+            new_username(provider, &upstream_user(None)),
+            Err(RouteError::MissingUsername)
+        ));
+    }
+
+    #[test]
+    fn provisioning_can_be_disabled() {
+        let providers = providers(false);
+        let provider = providers.get("example").unwrap();
+
+        let error = new_username(provider, &upstream_user(Some("alice"))).unwrap_err();
+        assert!(matches!(error, RouteError::ProvisioningDisabled));
+        assert_eq!(error.into_response().status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn colliding_usernames_get_a_suffix() {
+        let candidates: Vec<String> = candidate_usernames("alice").collect();
+        assert_eq!(candidates.len(), MAX_USERNAME_SUFFIX);
+        assert_eq!(candidates[..3], ["alice", "alice1", "alice2"]);
+        assert_eq!(candidates.last().unwrap(), "alice99");
+    }
+
+    #[test]
+    fn state_is_checked() {
+        let session = UpstreamSession::generate("example", UpstreamAction::Login { login_id: 42 });
+        assert!(session.verify("example", Some(&session.state)).is_ok());
+        assert!(matches!(
+            session.verify("example", Some("other")),
+            Err(RouteError::StateMismatch)
+        ));
+        assert!(matches!(
+            session.verify("example", None),
+            Err(RouteError::StateMismatch)
+        ));
+        assert!(matches!(
+            session.verify("other", Some(&session.state)),
+            Err(RouteError::StateMismatch)
+        ));
+    }
+
+    #[test]
+    fn callback_url() {
+        let url_builder = UrlBuilder::new("https://auth.example.com/".parse().unwrap());
+        assert_eq!(
+            url_builder.upstream_oauth2_callback("example").as_str(),
+            "https://auth.example.com/upstream/callback/example"
+        );
+    }
+}
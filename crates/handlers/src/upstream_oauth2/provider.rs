@@ -0,0 +1,431 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The upstream providers users can log in with, and how to talk to them
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use headers::{Authorization, HeaderMapExt};
+use mas_config::{UpstreamOAuth2Config, UpstreamProviderConfig, UpstreamProviderKind};
+use mas_http::HttpServiceExt;
+use mas_iana::oauth::PkceCodeChallengeMethod;
+use oauth2_types::pkce::CodeChallengeMethodExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tower::ServiceExt;
+use url::Url;
+
+/// The claims about a user, as returned by the provider
+pub(crate) type Claims = HashMap<String, serde_json::Value>;
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error("the upstream provider did not return the {0:?} claim")]
+    MissingClaim(String),
+
+    #[error("could not talk to the upstream provider")]
+    Http(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+impl UpstreamError {
+    fn http(e: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Self::Http(Box::new(e))
+    }
+}
+
+/// A user as the upstream provider knows them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UpstreamUser {
+    /// Stable identifier of the user on the provider
+    pub subject: String,
+
+    /// Username to give to the user, if they log in for the first time,
+    /// rendered from the template of the provider
+    pub username: Option<String>,
+
+    /// Email to give to the user, if they log in for the first time. Only set
+    /// if the provider verified it
+    pub email: Option<String>,
+}
+
+/// A provider users can log in with, through the authorization code flow
+#[async_trait]
+pub trait UpstreamProvider: Send + Sync {
+    /// Identifier of the provider, in the URLs
+    fn id(&self) -> &str;
+
+    /// Name of the provider shown to the users
+    fn name(&self) -> &str;
+
+    /// Whether the users logging in for the first time are registered
+    fn provision_users(&self) -> bool;
+
+    /// Whether the users logging in for the first time are linked to the
+    /// local user who verified the same email
+    fn link_by_email(&self) -> bool;
+
+    /// The URL to send the users to, to log in on the provider
+    fn authorization_url(&self, redirect_uri: &Url, state: &str, code_verifier: &str) -> Url;
+
+    /// Exchange an authorization code for an access token
+    async fn exchange_code(
+        &self,
+        redirect_uri: &Url,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, UpstreamError>;
+
+    /// Fetch the claims about the user with the access token
+    async fn fetch_claims(&self, access_token: &str) -> Result<Claims, UpstreamError>;
+
+    /// Find out who the user is from their claims
+    fn map_claims(&self, claims: &Claims) -> Result<UpstreamUser, UpstreamError>;
+}
+
+/// The providers configured, by ID
+#[derive(Clone, Default)]
+pub(crate) struct UpstreamProviders(Arc<Vec<Arc<dyn UpstreamProvider>>>);
+
+impl UpstreamProviders {
+    /// Find a provider by its ID
+    pub(crate) fn get(&self, id: &str) -> Option<&dyn UpstreamProvider> {
+        self.0
+            .iter()
+            .find(|provider| provider.id() == id)
+            .map(|provider| &**provider)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &dyn UpstreamProvider> {
+        self.0.iter().map(|provider| &**provider)
+    }
+}
+
+/// Build the providers from the config
+pub(crate) fn from_config(config: &UpstreamOAuth2Config) -> UpstreamProviders {
+    let providers = config
+        .providers
+        .iter()
+        .map(|provider| -> Arc<dyn UpstreamProvider> {
+            match provider.kind {
+                UpstreamProviderKind::Oidc => Arc::new(OidcProvider {
+                    config: provider.clone(),
+                }),
+            }
+        })
+        .collect();
+
+    UpstreamProviders(Arc::new(providers))
+}
+
+/// A generic OIDC provider
+struct OidcProvider {
+    config: UpstreamProviderConfig,
+}
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'static str,
+    code: &'a str,
+    redirect_uri: &'a str,
+    code_verifier: &'a str,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// A claim as a string. Some providers use numbers for the subjects
+fn string_claim(claims: &Claims, name: &str) -> Option<String> {
+    match claims.get(name) {
+        Some(serde_json::Value::String(value)) if !value.is_empty() => Some(value.clone()),
+        Some(serde_json::Value::Number(value)) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Render a template, with the claims between braces. Returns `None` if a
+/// claim is missing or if the result is empty.
+///
+/// The result is lowercased, as the usernames can't have uppercase letters.
+fn render_template(template: &str, claims: &Claims) -> Option<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let (name, after) = rest[start + 1..].split_once('}')?;
+        rendered.push_str(&string_claim(claims, name)?);
+        rest = after;
+    }
+    rendered.push_str(rest);
+
+    if rendered.is_empty() {
+        None
+    } else {
+        Some(rendered.to_lowercase())
+    }
+}
+
+#[async_trait]
+impl UpstreamProvider for OidcProvider {
+    fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn provision_users(&self) -> bool {
+        self.config.provision_users
+    }
+
+    fn link_by_email(&self) -> bool {
+        self.config.link_by_email
+    }
+
+    fn authorization_url(&self, redirect_uri: &Url, state: &str, code_verifier: &str) -> Url {
+        let code_challenge = PkceCodeChallengeMethod::S256.compute_challenge(code_verifier);
+
+        let mut url = self.config.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", redirect_uri.as_str())
+            .append_pair("scope", &self.config.scope)
+            .append_pair("state", state)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        url
+    }
+
+    async fn exchange_code(
+        &self,
+        redirect_uri: &Url,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<String, UpstreamError> {
+        let body = serde_urlencoded::to_string(TokenRequest {
+            grant_type: "authorization_code",
+            code,
+            redirect_uri: redirect_uri.as_str(),
+            code_verifier,
+        })
+        .map_err(UpstreamError::http)?;
+
+        let mut request = hyper::Request::builder()
+            .method("POST")
+            .uri(self.config.token_endpoint.as_str())
+            .header(
+                hyper::header::CONTENT_TYPE,
+                mime::APPLICATION_WWW_FORM_URLENCODED.as_ref(),
+            )
+            .body(hyper::Body::from(body))
+            .map_err(UpstreamError::http)?;
+        request.headers_mut().typed_insert(Authorization::basic(
+            &self.config.client_id,
+            &self.config.client_secret,
+        ));
+
+        let response = mas_http::client("upstream-oauth2-token")
+            .json::<TokenResponse>()
+            .oneshot(request)
+            .await
+            .map_err(UpstreamError::http)?;
+
+        Ok(response.into_body().access_token)
+    }
+
+    async fn fetch_claims(&self, access_token: &str) -> Result<Claims, UpstreamError> {
+        let mut request = hyper::Request::builder()
+            .method("GET")
+            .uri(self.config.userinfo_endpoint.as_str())
+            .body(hyper::Body::empty())
+            .map_err(UpstreamError::http)?;
+        let authorization = Authorization::bearer(access_token).map_err(UpstreamError::http)?;
+        request.headers_mut().typed_insert(authorization);
+
+        let response = mas_http::client("upstream-oauth2-userinfo")
+            .json::<Claims>()
+            .oneshot(request)
+            .await
+            .map_err(UpstreamError::http)?;
+
+        Ok(response.into_body())
+    }
+
+    fn map_claims(&self, claims: &Claims) -> Result<UpstreamUser, UpstreamError> {
+        let subject = string_claim(claims, &self.config.subject_claim)
+            .ok_or_else(|| UpstreamError::MissingClaim(self.config.subject_claim.clone()))?;
+
+        let email_verified = claims.get("email_verified") == Some(&serde_json::Value::Bool(true));
+        let email = string_claim(claims, &self.config.email_claim).filter(|_| email_verified);
+
+        Ok(UpstreamUser {
+            subject,
+            username: render_template(&self.config.username_template, claims),
+            email,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn providers() -> UpstreamProviders {
+        let config: UpstreamOAuth2Config = serde_json::from_value(serde_json::json!({
+            "providers": [{
+                "id": "example",
+                "name": "Example",
+                "client_id": "mas",
+                "client_secret": "secret",
+                "authorization_endpoint": "https://sso.example.com/authorize?tenant=1",
+                "token_endpoint": "https://sso.example.com/token",
+                "userinfo_endpoint": "https://sso.example.com/userinfo",
+                "username_template": "{nickname}",
+            }],
+        }))
+        .unwrap();
+        from_config(&config)
+    }
+
+    fn claims(value: serde_json::Value) -> Claims {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn providers_by_id() {
+        let providers = providers();
+        assert_eq!(providers.get("example").unwrap().name(), "Example");
+        assert!(providers.get("other").is_none());
+        assert_eq!(
+            providers
+                .iter()
+                .map(UpstreamProvider::id)
+                .collect::<Vec<_>>(),
+            vec!["example"]
+        );
+    }
+
+    #[test]
+    fn authorization_url() {
+        let providers = providers();
+        let provider = providers.get("example").unwrap();
+        let redirect_uri: Url = "https://auth.example.com/upstream/callback/example"
+            .parse()
+            .unwrap();
+
+        let url = provider.authorization_url(&redirect_uri, "state", "verifier");
+        assert_eq!(url.host_str(), Some("sso.example.com"));
+        assert_eq!(url.path(), "/authorize");
+
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["tenant"], "1");
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "mas");
+        assert_eq!(params["redirect_uri"], redirect_uri.as_str());
+        assert_eq!(params["scope"], "openid");
+        assert_eq!(params["state"], "state");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(
+            params["code_challenge"],
+            PkceCodeChallengeMethod::S256.compute_challenge("verifier")
+        );
+    }
+
+    #[test]
+    fn username_templates() {
+        let claims = claims(serde_json::json!({
+            "sub": 1234,
+            "preferred_username": "Alice",
+            "login": "alice",
+            "empty": "",
+        }));
+
+        assert_eq!(
+            render_template("{preferred_username}", &claims).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(render_template("{sub}", &claims).as_deref(), Some("1234"));
+        assert_eq!(
+            render_template("gh-{login}.{sub}", &claims).as_deref(),
+            Some("gh-alice.1234")
+        );
+        assert_eq!(
+            render_template("static", &claims).as_deref(),
+            Some("static")
+        );
+
+        // Missing or empty claims can't be rendered
+        assert_eq!(render_template("{nickname}", &claims), None);
+        assert_eq!(render_template("x-{empty}", &claims), None);
+        assert_eq!(render_template("", &claims), None);
+        assert_eq!(render_template("{sub", &claims), None);
+    }
+
+    #[test]
+    fn claims_of_a_new_user() {
+        let providers = providers();
+        let provider = providers.get("example").unwrap();
+
+        let user = provider
+            .map_claims(&claims(serde_json::json!({
+                "sub": "abc",
+                "nickname": "alice",
+                "preferred_username": "not-alice",
+                "email": "alice@example.com",
+                "email_verified": true,
+            })))
+            .unwrap();
+        assert_eq!(
+            user,
+            UpstreamUser {
+                subject: "abc".to_owned(),
+                username: Some("alice".to_owned()),
+                email: Some("alice@example.com".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn partial_claims() {
+        let providers = providers();
+        let provider = providers.get("example").unwrap();
+
+        // Numeric subjects are accepted, unverified emails are not
+        let user = provider
+            .map_claims(&claims(serde_json::json!({
+                "sub": 1234,
+                "email": "alice@example.com",
+                "email_verified": false,
+            })))
+            .unwrap();
+        assert_eq!(
+            user,
+            UpstreamUser {
+                subject: "1234".to_owned(),
+                username: None,
+                email: None,
+            }
+        );
+
+        assert!(matches!(
+            provider.map_claims(&claims(serde_json::json!({ "nickname": "alice" }))),
+            Err(UpstreamError::MissingClaim(claim)) if claim == "sub"
+        ));
+    }
+}
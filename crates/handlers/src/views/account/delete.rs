@@ -0,0 +1,219 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{DateTime, Utc};
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::{Encrypter, SessionsConfig};
+use mas_data_model::BrowserSession;
+use mas_router::{PostAuthAction, Route};
+use mas_storage::{
+    user::{cancel_user_erasure, lookup_user_erasure, schedule_user_erasure},
+    PostgresqlBackend,
+};
+use mas_templates::{
+    AccountDeleteContext, AccountDeleteFormField, FieldError, FormState, TemplateContext, Templates,
+};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::info;
+
+use crate::views::shared::require_recent_authentication;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    Schedule { confirmation: String },
+    Cancel,
+}
+
+/// The user has to type their username again to confirm the deletion
+fn is_confirmed(username: &str, confirmation: &str) -> bool {
+    confirmation.trim() == username
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::and_then(PostAuthAction::DeleteAccount);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    if let Some(reauth) = require_recent_authentication(
+        &session,
+        sessions_config.sensitive_action_max_age,
+        PostAuthAction::DeleteAccount,
+        Utc::now(),
+    ) {
+        return Ok((cookie_jar, reauth).into_response());
+    }
+
+    let erase_after = lookup_user_erasure(&mut conn, &session.user).await?;
+
+    render(
+        templates,
+        session,
+        erase_after,
+        FormState::default(),
+        cookie_jar,
+        &csrf_protection,
+    )
+    .await
+}
+
+async fn render(
+    templates: Templates,
+    session: BrowserSession<PostgresqlBackend>,
+    erase_after: Option<DateTime<Utc>>,
+    form_state: FormState<AccountDeleteFormField>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    csrf_protection: &CsrfProtection,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(csrf_protection);
+
+    let ctx = AccountDeleteContext::new(erase_after)
+        .with_form_state(form_state)
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_delete(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::and_then(PostAuthAction::DeleteAccount);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    match form {
+        ManagementForm::Schedule { confirmation } => {
+            if let Some(reauth) = require_recent_authentication(
+                &session,
+                sessions_config.sensitive_action_max_age,
+                PostAuthAction::DeleteAccount,
+                Utc::now(),
+            ) {
+                return Ok((cookie_jar, reauth).into_response());
+            }
+
+            let erase_after = lookup_user_erasure(&mut txn, &session.user).await?;
+
+            if !is_confirmed(&session.user.username, &confirmation) {
+                let mut form_state = FormState::default();
+                form_state
+                    .add_error_on_field(AccountDeleteFormField::Confirmation, FieldError::Invalid);
+                return render(
+                    templates,
+                    session,
+                    erase_after,
+                    form_state,
+                    cookie_jar,
+                    &csrf_protection,
+                )
+                .await;
+            }
+
+            // Submitting the form twice doesn't push the erasure back
+            if erase_after.is_none() {
+                let erase_after =
+                    schedule_user_erasure(&mut txn, &session.user, sessions_config.erasure_delay)
+                        .await?;
+                info!(
+                    user.id = session.user.data,
+                    %erase_after,
+                    "Scheduled user erasure"
+                );
+            }
+        }
+
+        ManagementForm::Cancel => {
+            if cancel_user_erasure(&mut txn, &session.user).await? {
+                info!(user.id = session.user.data, "Cancelled user erasure");
+            }
+        }
+    }
+
+    txn.commit().await?;
+
+    Ok((cookie_jar, mas_router::AccountDelete.go()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmation() {
+        assert!(is_confirmed("alice", "alice"));
+        assert!(is_confirmed("alice", " alice\n"));
+        assert!(!is_confirmed("alice", "Alice"));
+        assert!(!is_confirmed("alice", ""));
+        assert!(!is_confirmed("alice", "bob"));
+    }
+
+    #[test]
+    fn deletion_form() {
+        let form: ManagementForm =
+            serde_urlencoded::from_str("action=schedule&confirmation=alice").unwrap();
+        assert!(matches!(
+            form,
+            ManagementForm::Schedule { confirmation } if confirmation == "alice"
+        ));
+
+        let form: ManagementForm = serde_urlencoded::from_str("action=cancel").unwrap();
+        assert!(matches!(form, ManagementForm::Cancel));
+
+        // The confirmation is required
+        assert!(serde_urlencoded::from_str::<ManagementForm>("action=schedule").is_err());
+    }
+}
@@ -12,39 +12,56 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::net::SocketAddr;
+
 use axum::{
-    extract::{Extension, Form, Query},
+    extract::{ConnectInfo, Extension, Form, Query},
     response::{Html, IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     FancyError, SessionInfoExt,
 };
-use mas_config::Encrypter;
-use mas_email::Mailer;
+use mas_config::{EmailConfig, Encrypter};
+use mas_email::{EmailNormalizer, MailQueue};
 use mas_router::Route;
 use mas_storage::user::add_user_email;
-use mas_templates::{EmailAddContext, TemplateContext, Templates};
-use serde::Deserialize;
+use mas_templates::{
+    EmailAddContext, EmailAddFormField, FieldError, FormError, TemplateContext, Templates,
+    ToFormState,
+};
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
-use super::start_email_verification;
-use crate::views::shared::OptionalPostAuthAction;
+use super::{can_add_email, start_email_verification};
+use crate::{
+    challenge::{challenge_widget, verify_challenge, Challenge, ChallengeError, ChallengeForm},
+    views::shared::OptionalPostAuthAction,
+};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct EmailForm {
     email: String,
+
+    #[serde(flatten)]
+    challenge: ChallengeForm,
+}
+
+impl ToFormState for EmailForm {
+    type Field = EmailAddFormField;
 }
 
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(challenge): Extension<Challenge>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.begin().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
@@ -57,6 +74,7 @@ pub(crate) async fn get(
     };
 
     let ctx = EmailAddContext::new()
+        .with_challenge(challenge_widget(&challenge))
         .with_session(session)
         .with_csrf(csrf_token.form_value());
 
@@ -65,16 +83,22 @@ pub(crate) async fn get(
     Ok((cookie_jar, Html(content)).into_response())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
-    Extension(mailer): Extension<Mailer>,
+    Extension(mail_queue): Extension<MailQueue>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(challenge): Extension<Challenge>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Query(query): Query<OptionalPostAuthAction>,
     Form(form): Form<ProtectedForm<EmailForm>>,
 ) -> Result<Response, FancyError> {
     let mut txn = pool.begin().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut txn).await?;
@@ -86,14 +110,55 @@ pub(crate) async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    let user_email = add_user_email(&mut txn, &session.user, &form.email).await?;
+    // Make sure a human is adding this address before sending it an email
+    let remote_ip = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    match verify_challenge(&challenge, form.challenge.response(), remote_ip).await {
+        Ok(()) => {}
+        Err(ChallengeError::Missing | ChallengeError::Rejected) => {
+            let state = form
+                .to_form_state()
+                .with_error_on_form(FormError::ChallengeFailed);
+
+            let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+            let ctx = EmailAddContext::with_form_state(state)
+                .with_challenge(challenge_widget(&challenge))
+                .with_session(session)
+                .with_csrf(csrf_token.form_value());
+
+            let content = templates.render_account_add_email(&ctx).await?;
+
+            return Ok((cookie_jar, Html(content)).into_response());
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let normalized_email = EmailNormalizer::from(&email_config).normalize(&form.email);
+    if !can_add_email(&mut txn, &email_config, &session.user, &normalized_email).await? {
+        // The user already has this address, or another user verified it
+        let state = form
+            .to_form_state()
+            .with_error_on_field(EmailAddFormField::Email, FieldError::Exists);
+
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+        let ctx = EmailAddContext::with_form_state(state)
+            .with_challenge(challenge_widget(&challenge))
+            .with_session(session)
+            .with_csrf(csrf_token.form_value());
+
+        let content = templates.render_account_add_email(&ctx).await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let user_email =
+        add_user_email(&mut txn, &session.user, &form.email, &normalized_email).await?;
     let next = mas_router::AccountVerifyEmail::new(user_email.data);
     let next = if let Some(action) = query.post_auth_action {
         next.and_then(action)
     } else {
         next
     };
-    start_email_verification(&mailer, &mut txn, &session.user, user_email).await?;
+    start_email_verification(&mail_queue, &mut txn, &session.user, user_email).await?;
 
     txn.commit().await?;
 
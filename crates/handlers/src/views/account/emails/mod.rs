@@ -12,33 +12,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::num::ParseIntError;
+
 use axum::{
     extract::{Extension, Form},
+    http::{Method, StatusCode},
     response::{Html, IntoResponse, Response},
+    TypedHeader,
 };
 use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use headers::{CacheControl, ContentType, ETag, HeaderMapExt, IfNoneMatch};
 use lettre::{message::Mailbox, Address};
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
-    FancyError, SessionInfoExt,
+    csrf::{CsrfError, CsrfExt, CsrfProtection, CsrfToken, ProtectedForm},
+    SessionInfoExt,
 };
-use mas_config::Encrypter;
+use mas_config::{EmailConfig, EmailSharingPolicy, Encrypter, SessionsConfig};
 use mas_data_model::{BrowserSession, User, UserEmail};
-use mas_email::Mailer;
-use mas_router::Route;
+use mas_email::{EmailNormalizer, MailQueue};
+use mas_router::{PostAuthAction, Route};
 use mas_storage::{
     user::{
         add_user_email, add_user_email_verification_code, get_user_email, get_user_emails,
-        remove_user_email, set_user_email_as_primary,
+        is_email_verified_by_other_user, mark_user_email_verification_sent, remove_user_email,
+        set_user_email_as_primary, user_email_exists, ActiveSessionLookupError,
+        UserEmailLookupError,
     },
-    PostgresqlBackend,
+    Database, PostgresqlBackend,
+};
+use mas_templates::{
+    AccountEmailsContext, EmailAddFormField, EmailVerificationContext, ErrorContext, FieldError,
+    FormError, FormState, TemplateContext, TemplateError, Templates, WithCsrf, WithSession,
 };
-use mas_templates::{AccountEmailsContext, EmailVerificationContext, TemplateContext, Templates};
 use rand::{distributions::Uniform, thread_rng, Rng};
-use serde::Deserialize;
-use sqlx::{PgExecutor, PgPool};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use thiserror::Error;
 use tracing::info;
 
+use crate::{
+    views::shared::require_recent_authentication,
+    webhooks::{WebhookEvent, Webhooks},
+};
+
 pub mod add;
 pub mod verify;
 
@@ -51,46 +70,268 @@ pub enum ManagementForm {
     Remove { data: String },
 }
 
+#[derive(Debug, Error)]
+pub(crate) enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("email not found")]
+    NotFound,
+
+    #[error("invalid request")]
+    BadRequest(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<ActiveSessionLookupError> for RouteError {
+    fn from(e: ActiveSessionLookupError) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<TemplateError> for RouteError {
+    fn from(e: TemplateError) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<UserEmailLookupError> for RouteError {
+    fn from(e: UserEmailLookupError) -> Self {
+        if e.not_found() {
+            Self::NotFound
+        } else {
+            Self::Internal(Box::new(e))
+        }
+    }
+}
+
+impl From<ParseIntError> for RouteError {
+    fn from(e: ParseIntError) -> Self {
+        Self::BadRequest(Box::new(e))
+    }
+}
+
+impl From<CsrfError> for RouteError {
+    fn from(e: CsrfError) -> Self {
+        Self::BadRequest(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let (status, context) = match self {
+            Self::Internal(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorContext::new().with_description(e.to_string()),
+            ),
+            Self::Anyhow(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorContext::new().with_description(e.to_string()),
+            ),
+            Self::NotFound => (
+                StatusCode::NOT_FOUND,
+                ErrorContext::new()
+                    .with_code("not_found")
+                    .with_description("This email address could not be found".to_owned()),
+            ),
+            Self::BadRequest(e) => (
+                StatusCode::BAD_REQUEST,
+                ErrorContext::new()
+                    .with_code("bad_request")
+                    .with_description(e.to_string()),
+            ),
+        };
+
+        let error = format!("{:?}", context);
+        (status, Extension(context), error).into_response()
+    }
+}
+
+/// Attach the user and the session to the current span
+fn record_session(session: &BrowserSession<PostgresqlBackend>) {
+    let span = tracing::Span::current();
+    span.record("user.sub", &session.user.sub.as_str());
+    span.record("session.id", &session.data);
+}
+
+#[tracing::instrument(skip_all, fields(user.sub, session.id))]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
-    Extension(pool): Extension<PgPool>,
+    Extension(database): Extension<Database>,
+    method: Method,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
-) -> Result<Response, FancyError> {
-    let mut conn = pool.acquire().await?;
+) -> Result<Response, RouteError> {
+    // The session is loaded from the primary, as it may have just been created
+    let mut conn = database.primary().acquire().await?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
 
-    if let Some(session) = maybe_session {
-        render(templates, session, cookie_jar, &mut conn).await
+    let session = if let Some(session) = maybe_session {
+        session
     } else {
         let login = mas_router::Login::default();
-        Ok((cookie_jar, login.go()).into_response())
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    record_session(&session);
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+    // The emails are listed from the replica. The form handlers render the
+    // page within their transaction, so that they see their own changes
+    let mut conn = database.read().acquire().await?;
+    let ctx = context(session, FormState::default(), &csrf_token, &mut conn).await?;
+
+    let etag = etag(&ctx)?;
+    if is_unmodified(if_none_match.as_ref().map(|TypedHeader(h)| h), &etag) {
+        return Ok((cookie_jar, not_modified(etag)).into_response());
+    }
+
+    if method == Method::HEAD {
+        // No need to render the page, the body would be stripped anyway
+        return Ok((cookie_jar, page(Some(etag), None)).into_response());
     }
+
+    let content = templates.render_account_emails(&ctx).await?;
+
+    Ok((cookie_jar, page(Some(etag), Some(content))).into_response())
 }
 
-async fn render(
-    templates: Templates,
+async fn context(
     session: BrowserSession<PostgresqlBackend>,
-    cookie_jar: PrivateCookieJar<Encrypter>,
+    form_state: FormState<EmailAddFormField>,
+    csrf_token: &CsrfToken,
     executor: impl PgExecutor<'_>,
-) -> Result<Response, FancyError> {
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
-
+) -> Result<WithCsrf<WithSession<AccountEmailsContext<PostgresqlBackend>>>, RouteError> {
     let emails = get_user_emails(executor, &session.user).await?;
 
     let ctx = AccountEmailsContext::new(emails)
+        .with_form_state(form_state)
         .with_session(session)
         .with_csrf(csrf_token.form_value());
 
+    Ok(ctx)
+}
+
+async fn render(
+    templates: Templates,
+    session: BrowserSession<PostgresqlBackend>,
+    form_state: FormState<EmailAddFormField>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    csrf_protection: &CsrfProtection,
+    executor: impl PgExecutor<'_>,
+) -> Result<Response, RouteError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(csrf_protection);
+    let ctx = context(session, form_state, &csrf_token, executor).await?;
+
     let content = templates.render_account_emails(&ctx).await?;
 
-    Ok((cookie_jar, Html(content)).into_response())
+    Ok((cookie_jar, page(None, Some(content))).into_response())
+}
+
+/// Build the response for the email management page. The list of emails is
+/// sensitive, so it should never be stored by caches
+fn page(etag: Option<ETag>, content: Option<String>) -> Response {
+    let cache_control = TypedHeader(CacheControl::new().with_no_store());
+    let mut response = match content {
+        Some(content) => (cache_control, Html(content)).into_response(),
+        None => (
+            cache_control,
+            TypedHeader(ContentType::from(mime::TEXT_HTML_UTF_8)),
+        )
+            .into_response(),
+    };
+
+    if let Some(etag) = etag {
+        response.headers_mut().typed_insert(etag);
+    }
+
+    response
+}
+
+/// Compute an entity tag for a page from the context used to render it
+fn etag<T: Serialize>(ctx: &T) -> Result<ETag, RouteError> {
+    let serialized = serde_json::to_vec(ctx).map_err(|e| RouteError::Internal(Box::new(e)))?;
+    let hash = Sha256::digest(&serialized);
+    let etag = format!("\"{}\"", BASE64URL_NOPAD.encode(&hash))
+        .parse()
+        .map_err(|e| RouteError::Internal(Box::new(e)))?;
+    Ok(etag)
+}
+
+/// Check if the client already has the current version of the page
+fn is_unmodified(if_none_match: Option<&IfNoneMatch>, etag: &ETag) -> bool {
+    if_none_match.map_or(false, |if_none_match| {
+        !if_none_match.precondition_passes(etag)
+    })
+}
+
+fn not_modified(etag: ETag) -> Response {
+    let cache_control = TypedHeader(CacheControl::new().with_no_store());
+    (StatusCode::NOT_MODIFIED, cache_control, TypedHeader(etag)).into_response()
+}
+
+/// Load one of the user's emails from the ID sent in a form
+///
+/// All the actions on an existing email must go through this: the lookup is
+/// scoped to the user, so an ID belonging to someone else is not found.
+async fn lookup_owned_email(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    data: &str,
+) -> Result<UserEmail<PostgresqlBackend>, RouteError> {
+    let id = data.parse()?;
+    let email = get_user_email(executor, user, id).await?;
+    Ok(email)
+}
+
+/// Check if the user may add or verify an email address under the configured
+/// sharing policy, given its normalized form
+async fn is_email_allowed(
+    executor: impl PgExecutor<'_>,
+    policy: EmailSharingPolicy,
+    user: &User<PostgresqlBackend>,
+    normalized_email: &str,
+) -> Result<bool, sqlx::Error> {
+    if policy == EmailSharingPolicy::AllowShared {
+        // No need to look at who else verified this address
+        return Ok(true);
+    }
+
+    let verified_by_other_user =
+        is_email_verified_by_other_user(executor, user, normalized_email).await?;
+    Ok(policy.allows(verified_by_other_user))
+}
+
+/// Check if the user may add an email address, given its normalized form
+///
+/// This rejects addresses the user already has, even if written differently.
+async fn can_add_email(
+    conn: &mut PgConnection,
+    email_config: &EmailConfig,
+    user: &User<PostgresqlBackend>,
+    normalized_email: &str,
+) -> Result<bool, sqlx::Error> {
+    if user_email_exists(&mut *conn, user, normalized_email).await? {
+        return Ok(false);
+    }
+
+    is_email_allowed(conn, email_config.sharing_policy, user, normalized_email).await
 }
 
 async fn start_email_verification(
-    mailer: &Mailer,
+    mail_queue: &MailQueue,
     executor: impl PgExecutor<'_>,
     user: &User<PostgresqlBackend>,
     user_email: UserEmail<PostgresqlBackend>,
@@ -103,27 +344,35 @@ async fn start_email_verification(
 
     let verification = add_user_email_verification_code(executor, user_email, code).await?;
 
-    // And send the verification email
+    // And queue the verification email
     let mailbox = Mailbox::new(Some(user.username.clone()), address);
 
     let context = EmailVerificationContext::new(user.clone().into(), verification.clone().into());
 
-    mailer.send_verification_email(mailbox, &context).await?;
+    mail_queue
+        .queue_verification_email(mailbox, context)
+        .await?;
 
     info!(
         email.id = verification.email.data,
-        "Verification email sent"
+        "Verification email queued"
     );
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(user.sub, session.id))]
 pub(crate) async fn post(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
-    Extension(mailer): Extension<Mailer>,
+    Extension(mail_queue): Extension<MailQueue>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    Extension(webhooks): Extension<Webhooks>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<ManagementForm>>,
-) -> Result<Response, FancyError> {
+) -> Result<Response, RouteError> {
     let mut txn = pool.begin().await?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
@@ -137,42 +386,185 @@ pub(crate) async fn post(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    let form = cookie_jar.verify_form(form)?;
+    record_session(&session);
+
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+    let mut form_state = FormState::default();
 
     match form {
         ManagementForm::Add { email } => {
-            let user_email = add_user_email(&mut txn, &session.user, &email).await?;
-            let next = mas_router::AccountVerifyEmail::new(user_email.data);
-            start_email_verification(&mailer, &mut txn, &session.user, user_email).await?;
-            txn.commit().await?;
-            return Ok((cookie_jar, next.go()).into_response());
+            let normalized_email = EmailNormalizer::from(&email_config).normalize(&email);
+            if can_add_email(&mut txn, &email_config, &session.user, &normalized_email).await? {
+                let user_email =
+                    add_user_email(&mut txn, &session.user, &email, &normalized_email).await?;
+                let next = mas_router::AccountVerifyEmail::new(user_email.data);
+                start_email_verification(&mail_queue, &mut txn, &session.user, user_email).await?;
+                txn.commit().await?;
+                return Ok((cookie_jar, next.go()).into_response());
+            }
+
+            // The user already has this address, or another user verified it
+            form_state.add_error_on_field(EmailAddFormField::Email, FieldError::Exists);
         }
         ManagementForm::ResendConfirmation { data } => {
-            let id = data.parse()?;
+            let user_email = lookup_owned_email(&mut txn, &session.user, &data).await?;
+            let cooldown = chrono::Duration::from_std(email_config.verification_resend_cooldown)
+                .map_err(|e| RouteError::Internal(Box::new(e)))?;
+
+            if mark_user_email_verification_sent(&mut txn, &user_email, cooldown).await? {
+                let next = mas_router::AccountVerifyEmail::new(user_email.data);
+                start_email_verification(&mail_queue, &mut txn, &session.user, user_email).await?;
+                txn.commit().await?;
+                return Ok((cookie_jar, next.go()).into_response());
+            }
 
-            let user_email = get_user_email(&mut txn, &session.user, id).await?;
-            let next = mas_router::AccountVerifyEmail::new(user_email.data);
-            start_email_verification(&mailer, &mut txn, &session.user, user_email).await?;
-            txn.commit().await?;
-            return Ok((cookie_jar, next.go()).into_response());
+            // A verification email was sent recently, ask the user to wait
+            form_state.add_error_on_form(FormError::RateLimited);
         }
         ManagementForm::Remove { data } => {
-            let id = data.parse()?;
+            // The user has to confirm the removal once authenticated again
+            if let Some(reauth) = require_recent_authentication(
+                &session,
+                sessions_config.sensitive_action_max_age,
+                PostAuthAction::ManageEmails,
+                Utc::now(),
+            ) {
+                return Ok((cookie_jar, reauth).into_response());
+            }
 
-            let email = get_user_email(&mut txn, &session.user, id).await?;
-            remove_user_email(&mut txn, email).await?;
+            let email = lookup_owned_email(&mut txn, &session.user, &data).await?;
+            let was_primary = session
+                .user
+                .primary_email
+                .as_ref()
+                .map(|primary| primary.data)
+                == Some(email.data);
+            remove_user_email(&mut txn, &session.user, email).await?;
+            if was_primary {
+                session.user.primary_email = None;
+            }
+            webhooks
+                .notify(&mut txn, &WebhookEvent::user_email_changed(&session.user))
+                .await?;
         }
         ManagementForm::SetPrimary { data } => {
-            let id = data.parse()?;
-            let email = get_user_email(&mut txn, &session.user, id).await?;
-            set_user_email_as_primary(&mut txn, &email).await?;
+            let email = lookup_owned_email(&mut txn, &session.user, &data).await?;
+            set_user_email_as_primary(&mut txn, &session.user, &email).await?;
             session.user.primary_email = Some(email);
+            webhooks
+                .notify(&mut txn, &WebhookEvent::user_email_changed(&session.user))
+                .await?;
         }
     };
 
-    let reply = render(templates.clone(), session, cookie_jar, &mut txn).await?;
+    let reply = render(
+        templates.clone(),
+        session,
+        form_state,
+        cookie_jar,
+        &csrf_protection,
+        &mut txn,
+    )
+    .await?;
 
     txn.commit().await?;
 
     Ok(reply)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::HttpBody, http::header};
+
+    use super::*;
+
+    #[test]
+    fn page_is_not_cached() {
+        let response = page(None, Some("<html></html>".to_owned()));
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+    }
+
+    fn error_status(error: impl Into<RouteError>) -> StatusCode {
+        let response = error.into().into_response();
+        // The error page is rendered from this context
+        assert!(response.extensions().get::<ErrorContext>().is_some());
+        response.status()
+    }
+
+    #[test]
+    fn email_of_another_user_is_not_found() {
+        // `get_user_email` is scoped to the current user, so an email owned by
+        // someone else is not found
+        let error = UserEmailLookupError::Database(sqlx::Error::RowNotFound);
+        assert_eq!(error_status(error), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn invalid_email_id_is_bad_request() {
+        let error = "not-a-number".parse::<i64>().unwrap_err();
+        assert_eq!(error_status(error), StatusCode::BAD_REQUEST);
+        assert_eq!(error_status(CsrfError::Mismatch), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn other_errors_are_internal() {
+        let error = UserEmailLookupError::Database(sqlx::Error::PoolTimedOut);
+        assert_eq!(error_status(error), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            error_status(anyhow::anyhow!("failed")),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn head_response_has_no_body() {
+        let response = page(None, None);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.body().size_hint().exact(), Some(0));
+    }
+
+    #[test]
+    fn fresh_page_has_an_etag() {
+        let etag = etag(&serde_json::json!({ "emails": ["alice@example.com"] })).unwrap();
+        let response = page(Some(etag.clone()), Some("<html></html>".to_owned()));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().typed_get::<ETag>(), Some(etag));
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let ctx = serde_json::json!({ "emails": ["alice@example.com"] });
+        let etag = etag(&ctx).unwrap();
+
+        // The tag only depends on the context
+        assert_eq!(super::etag(&ctx).unwrap(), etag);
+
+        let if_none_match = IfNoneMatch::from(etag.clone());
+        assert!(is_unmodified(Some(&if_none_match), &etag));
+
+        let response = not_modified(etag.clone());
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().typed_get::<ETag>(), Some(etag));
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+    }
+
+    #[test]
+    fn changed_context_is_modified() {
+        let old = etag(&serde_json::json!({ "emails": ["alice@example.com"] })).unwrap();
+        let new = etag(&serde_json::json!({ "emails": ["bob@example.com"] })).unwrap();
+        assert_ne!(old, new);
+
+        let if_none_match = IfNoneMatch::from(old);
+        assert!(!is_unmodified(Some(&if_none_match), &new));
+        assert!(!is_unmodified(None, &new));
+    }
+}
@@ -19,20 +19,27 @@ use axum::{
 use axum_extra::extract::PrivateCookieJar;
 use chrono::Duration;
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     FancyError, SessionInfoExt,
 };
-use mas_config::Encrypter;
+use mas_config::{EmailConfig, Encrypter};
+use mas_email::EmailNormalizer;
 use mas_router::Route;
 use mas_storage::user::{
     consume_email_verification, lookup_user_email_by_id, lookup_user_email_verification_code,
     mark_user_email_as_verified, set_user_email_as_primary,
 };
-use mas_templates::{EmailVerificationPageContext, TemplateContext, Templates};
+use mas_templates::{
+    EmailVerificationPageContext, FormError, FormState, TemplateContext, Templates,
+};
 use serde::Deserialize;
 use sqlx::PgPool;
 
-use crate::views::shared::OptionalPostAuthAction;
+use super::is_email_allowed;
+use crate::{
+    views::shared::OptionalPostAuthAction,
+    webhooks::{WebhookEvent, Webhooks},
+};
 
 #[derive(Deserialize, Debug)]
 pub struct CodeForm {
@@ -44,11 +51,12 @@ pub(crate) async fn get(
     Extension(pool): Extension<PgPool>,
     Query(query): Query<OptionalPostAuthAction>,
     Path(id): Path<i64>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
@@ -77,8 +85,13 @@ pub(crate) async fn get(
     Ok((cookie_jar, Html(content)).into_response())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    Extension(webhooks): Extension<Webhooks>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Query(query): Query<OptionalPostAuthAction>,
     Path(id): Path<i64>,
@@ -86,12 +99,12 @@ pub(crate) async fn post(
 ) -> Result<Response, FancyError> {
     let mut txn = pool.begin().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut txn).await?;
 
-    let session = if let Some(session) = maybe_session {
+    let mut session = if let Some(session) = maybe_session {
         session
     } else {
         let login = mas_router::Login::default();
@@ -100,8 +113,26 @@ pub(crate) async fn post(
 
     let email = lookup_user_email_by_id(&mut txn, &session.user, id).await?;
 
+    // Another user might have verified this address since it was added
+    let policy = email_config.sharing_policy;
+    let normalized_email = EmailNormalizer::from(&email_config).normalize(&email.email);
+    if !is_email_allowed(&mut txn, policy, &session.user, &normalized_email).await? {
+        let state = FormState::default().with_error_on_form(FormError::EmailInUse);
+
+        let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+        let ctx = EmailVerificationPageContext::new(email)
+            .with_form_state(state)
+            .with_session(session)
+            .with_csrf(csrf_token.form_value());
+
+        let content = templates.render_account_verify_email(&ctx).await?;
+
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
     if session.user.primary_email.is_none() {
-        set_user_email_as_primary(&mut txn, &email).await?;
+        set_user_email_as_primary(&mut txn, &session.user, &email).await?;
+        session.user.primary_email = Some(email.clone());
     }
 
     // TODO: make those 8 hours configurable
@@ -114,6 +145,10 @@ pub(crate) async fn post(
 
     let _email = mark_user_email_as_verified(&mut txn, verification.email).await?;
 
+    webhooks
+        .notify(&mut txn, &WebhookEvent::user_email_changed(&session.user))
+        .await?;
+
     txn.commit().await?;
 
     let destination = query.go_next_or_default(&mas_router::AccountEmails);
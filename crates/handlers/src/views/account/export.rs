@@ -0,0 +1,458 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of the data of an account, as a JSON document
+//!
+//! The document is streamed one section at a time, so that only one section
+//! is held in memory. An error in the middle of the export aborts the
+//! response, so that a truncated document is never mistaken for a complete
+//! one.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::boxed,
+    extract::Extension,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{DateTime, Utc};
+use hyper::{
+    body::{Body, Bytes},
+    header::{CONTENT_DISPOSITION, CONTENT_TYPE, RETRY_AFTER},
+    StatusCode,
+};
+use mas_axum_utils::{FancyError, SessionInfoExt};
+use mas_config::{Encrypter, SessionsConfig};
+use mas_data_model::{User, UserEmail};
+use mas_router::{PostAuthAction, Route};
+use mas_storage::{
+    upstream_oauth2::{get_upstream_links, UpstreamLink},
+    user::{get_active_sessions, get_user_emails, ClientSession},
+    Database, PostgresqlBackend,
+};
+use serde::Serialize;
+use sqlx::PgPool;
+use tracing::{error, info};
+
+use crate::views::shared::require_recent_authentication;
+
+/// How long a user has to wait between two exports
+const EXPORT_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Limits how often each user can export their data, as the export is costly.
+///
+/// Clones share the same state. The state is local to each instance.
+#[derive(Debug, Clone)]
+pub(crate) struct ExportLimiter {
+    last_exports: Arc<Mutex<HashMap<i64, Instant>>>,
+    cooldown: Duration,
+}
+
+impl Default for ExportLimiter {
+    fn default() -> Self {
+        Self::new(EXPORT_COOLDOWN)
+    }
+}
+
+impl ExportLimiter {
+    fn new(cooldown: Duration) -> Self {
+        Self {
+            last_exports: Arc::default(),
+            cooldown,
+        }
+    }
+
+    /// Record an export by the user, or return how long they have to wait
+    /// before the next one
+    fn try_acquire(&self, user_id: i64, now: Instant) -> Result<(), Duration> {
+        let mut last_exports = self.last_exports.lock().unwrap();
+
+        // Forget about the users who can export again
+        last_exports.retain(|_, at| now.duration_since(*at) < self.cooldown);
+
+        if let Some(at) = last_exports.get(&user_id) {
+            return Err(self.cooldown.saturating_sub(now.duration_since(*at)));
+        }
+
+        last_exports.insert(user_id, now);
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedProfile<'a> {
+    username: &'a str,
+    sub: &'a str,
+    primary_email: Option<&'a str>,
+}
+
+impl<'a> From<&'a User<PostgresqlBackend>> for ExportedProfile<'a> {
+    fn from(user: &'a User<PostgresqlBackend>) -> Self {
+        Self {
+            username: &user.username,
+            sub: &user.sub,
+            primary_email: user.primary_email.as_ref().map(|e| e.email.as_str()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedEmail {
+    email: String,
+    verified: bool,
+    created_at: DateTime<Utc>,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl From<UserEmail<PostgresqlBackend>> for ExportedEmail {
+    fn from(email: UserEmail<PostgresqlBackend>) -> Self {
+        Self {
+            verified: email.confirmed_at.is_some(),
+            email: email.email,
+            created_at: email.created_at,
+            confirmed_at: email.confirmed_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ExportedSession {
+    #[serde(rename = "oauth2")]
+    OAuth2 {
+        client_id: String,
+        client_name: Option<String>,
+        scope: String,
+        created_at: DateTime<Utc>,
+        last_active_at: Option<DateTime<Utc>>,
+    },
+    Device {
+        device_id: String,
+        device_display_name: Option<String>,
+        created_at: DateTime<Utc>,
+        last_active_at: Option<DateTime<Utc>>,
+    },
+}
+
+impl From<ClientSession> for ExportedSession {
+    fn from(session: ClientSession) -> Self {
+        match session {
+            ClientSession::OAuth2 {
+                client_id,
+                client_name,
+                scope,
+                created_at,
+                last_active_at,
+                ..
+            } => Self::OAuth2 {
+                client_id,
+                client_name,
+                scope,
+                created_at,
+                last_active_at,
+            },
+            ClientSession::Compat {
+                device_id,
+                device_display_name,
+                created_at,
+                last_active_at,
+                ..
+            } => Self::Device {
+                device_id,
+                device_display_name,
+                created_at,
+                last_active_at,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedUpstreamLink {
+    provider: String,
+    subject: String,
+    linked_at: DateTime<Utc>,
+}
+
+impl From<UpstreamLink> for ExportedUpstreamLink {
+    fn from(link: UpstreamLink) -> Self {
+        Self {
+            provider: link.provider,
+            subject: link.subject,
+            linked_at: link.created_at,
+        }
+    }
+}
+
+/// Writes the sections of the exported JSON object, one chunk at a time
+#[derive(Debug, Default)]
+struct ExportWriter {
+    started: bool,
+}
+
+impl ExportWriter {
+    /// Serialize a section of the document
+    fn section(&mut self, name: &str, value: &impl Serialize) -> serde_json::Result<Bytes> {
+        let mut chunk = if self.started {
+            b",".to_vec()
+        } else {
+            b"{".to_vec()
+        };
+        self.started = true;
+
+        serde_json::to_writer(&mut chunk, name)?;
+        chunk.push(b':');
+        serde_json::to_writer(&mut chunk, value)?;
+        Ok(chunk.into())
+    }
+
+    /// Close the document
+    fn finish(self) -> Bytes {
+        if self.started {
+            Bytes::from_static(b"}")
+        } else {
+            Bytes::from_static(b"{}")
+        }
+    }
+}
+
+/// Fetch and write the sections of the export in order
+async fn export(
+    pool: &PgPool,
+    user: &User<PostgresqlBackend>,
+    sender: &mut hyper::body::Sender,
+) -> anyhow::Result<()> {
+    let mut conn = pool.acquire().await?;
+    let mut writer = ExportWriter::default();
+
+    let profile = writer.section("profile", &ExportedProfile::from(user))?;
+    sender.send_data(profile).await?;
+
+    let emails: Vec<ExportedEmail> = get_user_emails(&mut conn, user)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    sender.send_data(writer.section("emails", &emails)?).await?;
+
+    let sessions: Vec<ExportedSession> = get_active_sessions(&mut conn, user)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    sender
+        .send_data(writer.section("sessions", &sessions)?)
+        .await?;
+
+    let links: Vec<ExportedUpstreamLink> = get_upstream_links(&mut conn, user)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    sender
+        .send_data(writer.section("upstream_links", &links)?)
+        .await?;
+
+    sender.send_data(writer.finish()).await?;
+    Ok(())
+}
+
+pub(crate) async fn get(
+    Extension(database): Extension<Database>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(limiter): Extension<ExportLimiter>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = database.primary().acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::and_then(PostAuthAction::ExportAccount);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    if let Some(reauth) = require_recent_authentication(
+        &session,
+        sessions_config.sensitive_action_max_age,
+        PostAuthAction::ExportAccount,
+        Utc::now(),
+    ) {
+        return Ok((cookie_jar, reauth).into_response());
+    }
+
+    if let Err(wait) = limiter.try_acquire(session.user.data, Instant::now()) {
+        let retry_after = wait.as_secs() + 1;
+        let error = FancyError::from("the account data was exported recently, try again later")
+            .with_status(StatusCode::TOO_MANY_REQUESTS);
+        return Ok((cookie_jar, [(RETRY_AFTER, retry_after.to_string())], error).into_response());
+    }
+
+    info!(user.id = session.user.data, "Exporting account data");
+
+    let (mut sender, body) = Body::channel();
+    let pool = database.read().clone();
+    let user = session.user;
+    tokio::spawn(async move {
+        if let Err(e) = export(&pool, &user, &mut sender).await {
+            error!(user.id = user.data, error = %e, "Could not export account data");
+            sender.abort();
+        }
+    });
+
+    let headers = [
+        (CONTENT_TYPE, "application/json"),
+        (
+            CONTENT_DISPOSITION,
+            r#"attachment; filename="account-export.json""#,
+        ),
+    ];
+    Ok((cookie_jar, headers, boxed(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture_user() -> User<PostgresqlBackend> {
+        let email = UserEmail {
+            data: 1,
+            email: "alice@example.com".to_owned(),
+            created_at: Utc::now(),
+            confirmed_at: Some(Utc::now()),
+        };
+
+        User {
+            data: 1,
+            username: "alice".to_owned(),
+            sub: "sub-alice".to_owned(),
+            primary_email: Some(email),
+        }
+    }
+
+    fn write_export(user: &User<PostgresqlBackend>) -> serde_json::Value {
+        let mut writer = ExportWriter::default();
+
+        let emails: Vec<ExportedEmail> = [
+            user.primary_email.clone().unwrap(),
+            UserEmail {
+                data: 2,
+                email: "unverified@example.com".to_owned(),
+                created_at: Utc::now(),
+                confirmed_at: None,
+            },
+        ]
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+        let sessions: Vec<ExportedSession> = vec![
+            ClientSession::OAuth2 {
+                id: 1,
+                client_id: "client".to_owned(),
+                client_name: Some("Client".to_owned()),
+                scope: "openid".to_owned(),
+                created_at: Utc::now(),
+                last_active_at: None,
+            }
+            .into(),
+            ClientSession::Compat {
+                id: 2,
+                device_id: "ABCDEF".to_owned(),
+                device_display_name: None,
+                created_at: Utc::now(),
+                last_active_at: Some(Utc::now()),
+            }
+            .into(),
+        ];
+
+        let links: Vec<ExportedUpstreamLink> = vec![UpstreamLink {
+            id: 1,
+            provider: "google".to_owned(),
+            subject: "1234".to_owned(),
+            created_at: Utc::now(),
+        }
+        .into()];
+
+        let chunks = [
+            writer
+                .section("profile", &ExportedProfile::from(user))
+                .unwrap(),
+            writer.section("emails", &emails).unwrap(),
+            writer.section("sessions", &sessions).unwrap(),
+            writer.section("upstream_links", &links).unwrap(),
+            writer.finish(),
+        ];
+
+        serde_json::from_slice(&chunks.concat()).unwrap()
+    }
+
+    #[test]
+    fn export_has_all_sections() {
+        let export = write_export(&fixture_user());
+
+        assert_eq!(
+            export.as_object().unwrap().keys().collect::<Vec<_>>(),
+            vec!["emails", "profile", "sessions", "upstream_links"]
+        );
+
+        assert_eq!(export["profile"]["username"], "alice");
+        assert_eq!(export["profile"]["primary_email"], "alice@example.com");
+
+        assert_eq!(export["emails"][0]["verified"], true);
+        assert_eq!(export["emails"][1]["email"], "unverified@example.com");
+        assert_eq!(export["emails"][1]["verified"], false);
+
+        assert_eq!(export["sessions"][0]["kind"], "oauth2");
+        assert_eq!(export["sessions"][0]["client_id"], "client");
+        assert_eq!(export["sessions"][1]["kind"], "device");
+        assert_eq!(export["sessions"][1]["device_id"], "ABCDEF");
+
+        assert_eq!(export["upstream_links"][0]["provider"], "google");
+        assert_eq!(export["upstream_links"][0]["subject"], "1234");
+    }
+
+    #[test]
+    fn empty_export_is_valid_json() {
+        let writer = ExportWriter::default();
+        let export: serde_json::Value = serde_json::from_slice(&writer.finish()).unwrap();
+        assert!(export.as_object().unwrap().is_empty());
+    }
+
+    #[test]
+    fn exports_are_rate_limited() {
+        let limiter = ExportLimiter::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert_eq!(limiter.try_acquire(1, now), Ok(()));
+
+        // Other users are not affected
+        assert_eq!(limiter.try_acquire(2, now), Ok(()));
+
+        let later = now + Duration::from_secs(20);
+        assert_eq!(limiter.try_acquire(1, later), Err(Duration::from_secs(40)));
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.try_acquire(1, later), Ok(()));
+    }
+}
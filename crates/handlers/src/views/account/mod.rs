@@ -12,29 +12,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod delete;
 pub mod emails;
+pub mod export;
 pub mod password;
+pub mod sessions;
+pub mod totp;
+pub mod upstream;
 
 use axum::{
     extract::Extension,
     response::{Html, IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
-use mas_axum_utils::{csrf::CsrfExt, FancyError, SessionInfoExt};
+use hyper::HeaderMap;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection},
+    FancyError, SessionInfoExt,
+};
 use mas_config::Encrypter;
 use mas_router::Route;
-use mas_storage::user::{count_active_sessions, get_user_emails};
-use mas_templates::{AccountContext, TemplateContext, Templates};
-use sqlx::PgPool;
+use mas_storage::{
+    user::{count_active_sessions, get_active_sessions, get_user_emails},
+    Database,
+};
+use mas_templates::{AccountOverviewContext, TemplateContext, Templates};
+
+use crate::i18n::accepted_languages;
 
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
-    Extension(pool): Extension<PgPool>,
+    Extension(database): Extension<Database>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
+    headers: HeaderMap,
 ) -> Result<Response, FancyError> {
-    let mut conn = pool.acquire().await?;
+    // The session is loaded from the primary, as it may have just been created
+    let mut conn = database.primary().acquire().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
@@ -46,15 +62,23 @@ pub(crate) async fn get(
         return Ok((cookie_jar, login.go()).into_response());
     };
 
+    let mut conn = database.read().acquire().await?;
+
     let active_sessions = count_active_sessions(&mut conn, &session.user).await?;
 
+    let client_sessions = get_active_sessions(&mut conn, &session.user).await?;
+
     let emails = get_user_emails(&mut conn, &session.user).await?;
 
-    let ctx = AccountContext::new(active_sessions, emails)
+    let ctx = AccountOverviewContext::new(active_sessions, emails)
+        .with_primary_email(session.user.primary_email.clone())
+        .with_last_authentication(session.last_authentication.clone())
+        .with_client_sessions(client_sessions.len())
         .with_session(session)
-        .with_csrf(csrf_token.form_value());
+        .with_csrf(csrf_token.form_value())
+        .with_locale(templates.negotiate_locale(&accepted_languages(&headers)));
 
-    let content = templates.render_account_index(&ctx).await?;
+    let content = templates.render_account_overview(&ctx).await?;
 
     Ok((cookie_jar, Html(content)).into_response())
 }
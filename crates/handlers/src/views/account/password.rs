@@ -18,21 +18,21 @@ use axum::{
     response::{Html, IntoResponse, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     FancyError, SessionInfoExt,
 };
-use mas_config::Encrypter;
-use mas_data_model::BrowserSession;
-use mas_router::Route;
-use mas_storage::{
-    user::{authenticate_session, set_password},
-    PostgresqlBackend,
-};
+use mas_config::{Encrypter, PasswordsConfig, SessionsConfig};
+use mas_data_model::{BrowserSession, PasswordPolicy};
+use mas_router::{PostAuthAction, Route};
+use mas_storage::{user::change_password, PostgresqlBackend};
 use mas_templates::{EmptyContext, TemplateContext, Templates};
 use serde::Deserialize;
 use sqlx::PgPool;
 
+use crate::views::shared::require_recent_authentication;
+
 #[derive(Deserialize)]
 pub struct ChangeForm {
     current_password: String,
@@ -43,6 +43,8 @@ pub struct ChangeForm {
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
@@ -51,20 +53,32 @@ pub(crate) async fn get(
 
     let maybe_session = session_info.load_session(&mut conn).await?;
 
-    if let Some(session) = maybe_session {
-        render(templates, session, cookie_jar).await
+    let session = if let Some(session) = maybe_session {
+        session
     } else {
-        let login = mas_router::Login::and_then(mas_router::PostAuthAction::ChangePassword);
-        Ok((cookie_jar, login.go()).into_response())
+        let login = mas_router::Login::and_then(PostAuthAction::ChangePassword);
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    if let Some(reauth) = require_recent_authentication(
+        &session,
+        sessions_config.sensitive_action_max_age,
+        PostAuthAction::ChangePassword,
+        Utc::now(),
+    ) {
+        return Ok((cookie_jar, reauth).into_response());
     }
+
+    render(templates, session, cookie_jar, &csrf_protection).await
 }
 
 async fn render(
     templates: Templates,
     session: BrowserSession<PostgresqlBackend>,
     cookie_jar: PrivateCookieJar<Encrypter>,
+    csrf_protection: &CsrfProtection,
 ) -> Result<Response, FancyError> {
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(csrf_protection);
 
     let ctx = EmptyContext
         .with_session(session)
@@ -78,35 +92,57 @@ async fn render(
 pub(crate) async fn post(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(passwords_config): Extension<PasswordsConfig>,
+    Extension(sessions_config): Extension<SessionsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<ChangeForm>>,
 ) -> Result<Response, FancyError> {
     let mut txn = pool.begin().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
 
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut txn).await?;
 
-    let mut session = if let Some(session) = maybe_session {
+    let session = if let Some(session) = maybe_session {
         session
     } else {
-        let login = mas_router::Login::and_then(mas_router::PostAuthAction::ChangePassword);
+        let login = mas_router::Login::and_then(PostAuthAction::ChangePassword);
         return Ok((cookie_jar, login.go()).into_response());
     };
 
-    authenticate_session(&mut txn, &mut session, &form.current_password).await?;
+    if let Some(reauth) = require_recent_authentication(
+        &session,
+        sessions_config.sensitive_action_max_age,
+        PostAuthAction::ChangePassword,
+        Utc::now(),
+    ) {
+        return Ok((cookie_jar, reauth).into_response());
+    }
 
     // TODO: display nice form errors
     if form.new_password != form.new_password_confirm {
         return Err(anyhow::anyhow!("password mismatch").into());
     }
 
+    let policy = PasswordPolicy {
+        min_length: passwords_config.min_length,
+        min_complexity: passwords_config.min_complexity,
+    };
     let phf = Argon2::default();
-    set_password(&mut txn, phf, &session.user, &form.new_password).await?;
-
-    let reply = render(templates.clone(), session, cookie_jar).await?;
+    change_password(
+        &mut txn,
+        phf,
+        &policy,
+        &session,
+        &form.current_password,
+        &form.new_password,
+    )
+    .await?;
+
+    let reply = render(templates.clone(), session, cookie_jar, &csrf_protection).await?;
 
     txn.commit().await?;
 
@@ -0,0 +1,272 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Display, str::FromStr};
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_router::Route;
+use mas_storage::user::{
+    get_active_sessions, revoke_all_sessions, revoke_compat_session, revoke_oauth2_session,
+    set_device_display_name, ClientSession,
+};
+use mas_templates::{AccountSession, AccountSessionsContext, TemplateContext, Templates};
+use serde::Deserialize;
+use sqlx::PgPool;
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    RevokeAll,
+    Revoke { data: String },
+    Rename { data: String, name: String },
+}
+
+/// Identifies one of the sessions listed on the page in the forms, since the
+/// OAuth 2.0 and compat sessions IDs overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionRef {
+    OAuth2(i64),
+    Compat(i64),
+}
+
+#[derive(Debug, Error)]
+#[error("invalid session reference")]
+struct InvalidSessionRef;
+
+impl Display for SessionRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OAuth2(id) => write!(f, "oauth2:{}", id),
+            Self::Compat(id) => write!(f, "compat:{}", id),
+        }
+    }
+}
+
+impl FromStr for SessionRef {
+    type Err = InvalidSessionRef;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, id) = s.split_once(':').ok_or(InvalidSessionRef)?;
+        let id = id.parse().map_err(|_| InvalidSessionRef)?;
+        match kind {
+            "oauth2" => Ok(Self::OAuth2(id)),
+            "compat" => Ok(Self::Compat(id)),
+            _ => Err(InvalidSessionRef),
+        }
+    }
+}
+
+/// Describe a client session with names the user can recognize
+fn describe_session(session: ClientSession) -> AccountSession {
+    match session {
+        ClientSession::OAuth2 {
+            id,
+            client_id,
+            client_name,
+            scope,
+            created_at,
+            last_active_at,
+        } => AccountSession::new(
+            SessionRef::OAuth2(id).to_string(),
+            client_name.unwrap_or(client_id),
+            scope_summary(&scope),
+            created_at,
+        )
+        .with_last_active_at(last_active_at),
+
+        ClientSession::Compat {
+            id,
+            device_id,
+            device_display_name,
+            created_at,
+            last_active_at,
+        } => AccountSession::new(
+            SessionRef::Compat(id).to_string(),
+            "Matrix client".to_owned(),
+            "Full account access".to_owned(),
+            created_at,
+        )
+        .with_device_name(Some(device_display_name.unwrap_or(device_id)))
+        .with_last_active_at(last_active_at)
+        .with_renamable(true),
+    }
+}
+
+/// List the scope tokens granted to a session
+fn scope_summary(scope: &str) -> String {
+    scope.split(' ').collect::<Vec<_>>().join(", ")
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let sessions = get_active_sessions(&mut conn, &session.user)
+        .await?
+        .into_iter()
+        .map(describe_session)
+        .collect();
+
+    let ctx = AccountSessionsContext::new(sessions)
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_sessions(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    match form {
+        ManagementForm::RevokeAll => {
+            let revoked = revoke_all_sessions(&mut txn, &session.user).await?;
+            info!(
+                user.id = session.user.data,
+                revoked, "Revoked all client sessions"
+            );
+
+            txn.commit().await?;
+
+            return Ok((cookie_jar, mas_router::Account.go()).into_response());
+        }
+
+        ManagementForm::Revoke { data } => {
+            // Both lookups are scoped to the user, so a session belonging to
+            // someone else is not found
+            let revoked = match data
+                .parse::<SessionRef>()
+                .map_err(FancyError::bad_request)?
+            {
+                SessionRef::OAuth2(id) => {
+                    revoke_oauth2_session(&mut txn, &session.user, id).await?
+                }
+                SessionRef::Compat(id) => {
+                    revoke_compat_session(&mut txn, &session.user, id).await?
+                }
+            };
+
+            if !revoked {
+                return Err(FancyError::not_found("session not found"));
+            }
+
+            info!(user.id = session.user.data, session = %data, "Revoked client session");
+        }
+
+        ManagementForm::Rename { data, name } => {
+            // Only the compat sessions are tied to a device
+            let id = match data
+                .parse::<SessionRef>()
+                .map_err(FancyError::bad_request)?
+            {
+                SessionRef::Compat(id) => id,
+                SessionRef::OAuth2(_) => {
+                    return Err(anyhow::anyhow!("this session has no device to rename").into())
+                }
+            };
+
+            // The lookup is scoped to the user, so a device belonging to someone
+            // else is not found
+            if !set_device_display_name(&mut txn, &session.user, id, &name).await? {
+                return Err(FancyError::not_found("session not found"));
+            }
+
+            info!(user.id = session.user.data, session = %data, "Renamed device");
+        }
+    }
+
+    txn.commit().await?;
+
+    Ok((cookie_jar, mas_router::AccountSessions.go()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_ref_roundtrip() {
+        for session in [SessionRef::OAuth2(42), SessionRef::Compat(42)] {
+            assert_eq!(session.to_string().parse::<SessionRef>().unwrap(), session);
+        }
+
+        assert!("oauth2:".parse::<SessionRef>().is_err());
+        assert!("compat:abc".parse::<SessionRef>().is_err());
+        assert!("browser:42".parse::<SessionRef>().is_err());
+        assert!("42".parse::<SessionRef>().is_err());
+    }
+
+    #[test]
+    fn session_form() {
+        let form: ManagementForm = serde_urlencoded::from_str("action=revoke_all").unwrap();
+        assert!(matches!(form, ManagementForm::RevokeAll));
+
+        let form: ManagementForm =
+            serde_urlencoded::from_str("action=revoke&data=compat%3A3").unwrap();
+        assert!(matches!(form, ManagementForm::Revoke { data } if data == "compat:3"));
+
+        let form: ManagementForm =
+            serde_urlencoded::from_str("action=rename&data=compat%3A3&name=Work+laptop").unwrap();
+        assert!(matches!(
+            form,
+            ManagementForm::Rename { data, name } if data == "compat:3" && name == "Work laptop"
+        ));
+    }
+}
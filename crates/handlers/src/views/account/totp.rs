@@ -0,0 +1,257 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::Utc;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::{Encrypter, MatrixConfig};
+use mas_data_model::{BrowserSession, TotpSecret, User, UserTotp};
+use mas_router::Route;
+use mas_storage::{
+    user::{lookup_user_totp, mark_user_totp_as_verified, start_user_totp_enrollment},
+    PostgresqlBackend,
+};
+use mas_templates::{
+    AccountTotpContext, FieldError, FormState, TemplateContext, Templates, TotpFormField,
+};
+use rand::thread_rng;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+#[derive(Deserialize)]
+pub struct TotpForm {
+    code: String,
+}
+
+/// The secrets are bound to their user, so that they can't be swapped in the
+/// database
+fn secret_associated_data(user_id: i64) -> Vec<u8> {
+    format!("user_totp:{}", user_id).into_bytes()
+}
+
+fn encrypt_totp_secret(
+    encrypter: &Encrypter,
+    user_id: i64,
+    secret: &TotpSecret,
+) -> anyhow::Result<String> {
+    encrypter.encrypt_to_string_with_ad(&secret_associated_data(user_id), secret.as_bytes())
+}
+
+/// Decrypt the TOTP secret of the user
+pub(crate) fn decrypt_totp_secret(
+    encrypter: &Encrypter,
+    user: &User<PostgresqlBackend>,
+    totp: &UserTotp<PostgresqlBackend>,
+) -> anyhow::Result<TotpSecret> {
+    let secret = encrypter
+        .decrypt_string_with_ad(&secret_associated_data(user.data), &totp.encrypted_secret)
+        .context("could not decrypt TOTP secret")?;
+    Ok(TotpSecret::from_bytes(secret)?)
+}
+
+/// Encrypt the TOTP secrets which were stored in plain text. Returns how many
+/// were encrypted
+///
+/// # Errors
+///
+/// Returns an error if the database failed
+pub async fn encrypt_plaintext_totp_secrets(
+    pool: &PgPool,
+    encrypter: &Encrypter,
+) -> anyhow::Result<u64> {
+    let mut conn = pool.acquire().await?;
+    mas_storage::user::encrypt_plaintext_totp_secrets(&mut conn, |user_id, secret| {
+        encrypt_totp_secret(encrypter, user_id, secret)
+    })
+    .await
+}
+
+/// Encrypt again the TOTP secrets encrypted before they were bound to their
+/// user, so that they can only be decrypted for it. Returns how many were
+/// encrypted again
+///
+/// # Errors
+///
+/// Returns an error if the database failed
+pub async fn bind_totp_secrets(pool: &PgPool, encrypter: &Encrypter) -> anyhow::Result<u64> {
+    let mut conn = pool.acquire().await?;
+    mas_storage::user::reencrypt_totp_secrets(&mut conn, |user_id, encrypted_secret| {
+        let associated_data = secret_associated_data(user_id);
+        if encrypter
+            .decrypt_string_with_ad(&associated_data, encrypted_secret)
+            .is_ok()
+        {
+            return Ok(None);
+        }
+
+        if let Ok(secret) = encrypter.decrypt_string(encrypted_secret) {
+            let encrypted_secret =
+                encrypter.encrypt_to_string_with_ad(&associated_data, &secret)?;
+            Ok(Some(encrypted_secret))
+        } else {
+            warn!(user.id = user_id, "Could not decrypt the TOTP secret");
+            Ok(None)
+        }
+    })
+    .await
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(matrix_config): Extension<MatrixConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    Extension(encrypter): Extension<Encrypter>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    // Keep showing the same secret until the user enters a valid code, in case
+    // they already added it to their app
+    let totp = if let Some(totp) = lookup_user_totp(&mut conn, &session.user).await? {
+        totp
+    } else {
+        let secret = TotpSecret::generate(&mut thread_rng());
+        let encrypted_secret = encrypt_totp_secret(&encrypter, session.user.data, &secret)?;
+        start_user_totp_enrollment(&mut conn, &session.user, encrypted_secret).await?
+    };
+
+    render(
+        templates,
+        &matrix_config,
+        &encrypter,
+        session,
+        &totp,
+        FormState::default(),
+        cookie_jar,
+        &csrf_protection,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn render(
+    templates: Templates,
+    matrix_config: &MatrixConfig,
+    encrypter: &Encrypter,
+    session: BrowserSession<PostgresqlBackend>,
+    totp: &UserTotp<PostgresqlBackend>,
+    form_state: FormState<TotpFormField>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    csrf_protection: &CsrfProtection,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(csrf_protection);
+
+    let ctx = if totp.is_enabled() {
+        AccountTotpContext::enabled()
+    } else {
+        let secret = decrypt_totp_secret(encrypter, &session.user, totp)?;
+        let provisioning_uri =
+            secret.provisioning_uri(&matrix_config.homeserver, &session.user.username);
+        AccountTotpContext::enrolling(provisioning_uri, secret.to_base32())
+    };
+
+    let ctx = ctx
+        .with_form_state(form_state)
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_totp(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(matrix_config): Extension<MatrixConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    Extension(encrypter): Extension<Encrypter>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<TotpForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let totp = lookup_user_totp(&mut txn, &session.user)
+        .await?
+        .context("TOTP setup was not started")?;
+
+    if totp.is_enabled() {
+        return Ok((cookie_jar, mas_router::AccountTotp.go()).into_response());
+    }
+
+    // Only enable TOTP once we know the app of the user generates valid codes
+    let secret = decrypt_totp_secret(&encrypter, &session.user, &totp)?;
+    let step = if let Some(step) = totp.check_code(&secret, &form.code, Utc::now()) {
+        step
+    } else {
+        let mut form_state = FormState::default();
+        form_state.add_error_on_field(TotpFormField::Code, FieldError::Invalid);
+        return render(
+            templates,
+            &matrix_config,
+            &encrypter,
+            session,
+            &totp,
+            form_state,
+            cookie_jar,
+            &csrf_protection,
+        )
+        .await;
+    };
+
+    let totp = mark_user_totp_as_verified(&mut txn, totp, step).await?;
+    txn.commit().await?;
+
+    info!(
+        user.id = session.user.data,
+        user_totp.id = totp.data,
+        "Enabled TOTP"
+    );
+
+    Ok((cookie_jar, mas_router::Account.go()).into_response())
+}
@@ -0,0 +1,248 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axum::{
+    extract::{Extension, Form},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
+    FancyError, SessionInfoExt,
+};
+use mas_config::Encrypter;
+use mas_router::{Route, UrlBuilder};
+use mas_storage::{
+    upstream_oauth2::{get_upstream_links, remove_upstream_link, UpstreamLink},
+    user::user_has_password,
+};
+use mas_templates::{AccountUpstreamContext, AccountUpstreamProvider, TemplateContext, Templates};
+use serde::Deserialize;
+use sqlx::PgPool;
+use tracing::info;
+
+use crate::{
+    features::FeatureFlags,
+    upstream_oauth2::{self, UpstreamAction, UpstreamProviders},
+};
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ManagementForm {
+    Link { provider: String },
+    Unlink { provider: String },
+}
+
+/// List the configured providers, and the ones the user is still linked to
+/// which were removed from the config
+fn describe_providers(
+    providers: &UpstreamProviders,
+    links: &[UpstreamLink],
+) -> Vec<AccountUpstreamProvider> {
+    let linked_at = |id: &str| {
+        links
+            .iter()
+            .find(|link| link.provider == id)
+            .map(|link| link.created_at)
+    };
+
+    let configured = providers.iter().map(|provider| {
+        AccountUpstreamProvider::new(provider.id().to_owned(), provider.name().to_owned())
+            .with_linked_at(linked_at(provider.id()))
+    });
+
+    let removed = links
+        .iter()
+        .filter(|link| providers.get(&link.provider).is_none())
+        .map(|link| {
+            AccountUpstreamProvider::new(link.provider.clone(), link.provider.clone())
+                .with_linked_at(Some(link.created_at))
+        });
+
+    configured.chain(removed).collect()
+}
+
+/// Users without a password need to keep one upstream account to log in with
+fn can_unlink(has_password: bool, links: usize) -> bool {
+    has_password || links > 1
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(providers): Extension<UpstreamProviders>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let mut conn = pool.acquire().await?;
+
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut conn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    let links = get_upstream_links(&mut conn, &session.user).await?;
+
+    let ctx = AccountUpstreamContext::new(describe_providers(&providers, &links))
+        .with_session(session)
+        .with_csrf(csrf_token.form_value());
+
+    let content = templates.render_account_upstream(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn post(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(providers): Extension<UpstreamProviders>,
+    Extension(feature_flags): Extension<FeatureFlags>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ManagementForm>>,
+) -> Result<Response, FancyError> {
+    let mut txn = pool.begin().await?;
+
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+
+    let maybe_session = session_info.load_session(&mut txn).await?;
+
+    let session = if let Some(session) = maybe_session {
+        session
+    } else {
+        let login = mas_router::Login::default();
+        return Ok((cookie_jar, login.go()).into_response());
+    };
+
+    match form {
+        ManagementForm::Link { provider } => {
+            if !feature_flags.upstream_login() {
+                return Err(anyhow::anyhow!("linking upstream accounts is disabled").into());
+            }
+
+            let provider = providers
+                .get(&provider)
+                .ok_or_else(|| anyhow::anyhow!("unknown upstream provider"))?;
+
+            // The link is added when the user comes back from the provider
+            let action = UpstreamAction::Link {
+                user_id: session.user.data,
+            };
+            let (url, cookie_jar) =
+                upstream_oauth2::start(cookie_jar, &url_builder, provider, action);
+
+            return Ok((cookie_jar, Redirect::to(url.as_str())).into_response());
+        }
+
+        ManagementForm::Unlink { provider } => {
+            let links = get_upstream_links(&mut txn, &session.user).await?;
+            let has_password = user_has_password(&mut txn, &session.user).await?;
+            if !can_unlink(has_password, links.len()) {
+                return Err(anyhow::anyhow!(
+                    "this is the only way to log in to this account, set a password first"
+                )
+                .into());
+            }
+
+            if !remove_upstream_link(&mut txn, &session.user, &provider).await? {
+                return Err(anyhow::anyhow!("this account is not linked to {}", provider).into());
+            }
+
+            info!(user.id = session.user.data, upstream.provider = %provider, "Unlinked upstream account");
+        }
+    }
+
+    txn.commit().await?;
+
+    Ok((cookie_jar, mas_router::AccountUpstream.go()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use mas_config::UpstreamOAuth2Config;
+
+    use super::*;
+
+    fn providers() -> UpstreamProviders {
+        let config: UpstreamOAuth2Config = serde_json::from_value(serde_json::json!({
+            "providers": [{
+                "id": "example",
+                "name": "Example",
+                "client_id": "mas",
+                "client_secret": "secret",
+                "authorization_endpoint": "https://sso.example.com/authorize",
+                "token_endpoint": "https://sso.example.com/token",
+                "userinfo_endpoint": "https://sso.example.com/userinfo",
+            }],
+        }))
+        .unwrap();
+        upstream_oauth2::from_config(&config)
+    }
+
+    fn link(provider: &str) -> UpstreamLink {
+        UpstreamLink {
+            id: 1,
+            provider: provider.to_owned(),
+            subject: "abc".to_owned(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn management_form() {
+        let form: ManagementForm =
+            serde_urlencoded::from_str("action=link&provider=example").unwrap();
+        assert!(matches!(form, ManagementForm::Link { provider } if provider == "example"));
+
+        let form: ManagementForm =
+            serde_urlencoded::from_str("action=unlink&provider=example").unwrap();
+        assert!(matches!(form, ManagementForm::Unlink { provider } if provider == "example"));
+    }
+
+    #[test]
+    fn linked_providers() {
+        let providers = providers();
+
+        let listed = serde_json::to_value(describe_providers(&providers, &[])).unwrap();
+        assert_eq!(listed[0]["id"], "example");
+        assert!(listed[0]["linked_at"].is_null());
+
+        // Links to providers removed from the config can still be removed
+        let listed = serde_json::to_value(describe_providers(
+            &providers,
+            &[link("example"), link("removed")],
+        ))
+        .unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 2);
+        assert!(!listed[0]["linked_at"].is_null());
+        assert_eq!(listed[1]["id"], "removed");
+    }
+
+    #[test]
+    fn unlink_keeps_a_way_to_log_in() {
+        assert!(can_unlink(true, 1));
+        assert!(can_unlink(false, 2));
+        assert!(!can_unlink(false, 1));
+    }
+}
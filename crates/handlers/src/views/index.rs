@@ -17,7 +17,10 @@ use axum::{
     response::{Html, IntoResponse},
 };
 use axum_extra::extract::PrivateCookieJar;
-use mas_axum_utils::{csrf::CsrfExt, FancyError, SessionInfoExt};
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection},
+    FancyError, SessionInfoExt,
+};
 use mas_config::Encrypter;
 use mas_router::UrlBuilder;
 use mas_templates::{IndexContext, TemplateContext, Templates};
@@ -27,11 +30,12 @@ pub async fn get(
     Extension(templates): Extension<Templates>,
     Extension(url_builder): Extension<UrlBuilder>,
     Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<impl IntoResponse, FancyError> {
     let mut conn = pool.acquire().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
     let session = session_info.load_session(&mut conn).await?;
 
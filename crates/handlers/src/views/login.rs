@@ -18,10 +18,10 @@ use axum::{
 };
 use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{
-    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, CsrfToken, ProtectedForm},
     FancyError, SessionInfoExt,
 };
-use mas_config::Encrypter;
+use mas_config::{EmailConfig, Encrypter};
 use mas_router::Route;
 use mas_storage::user::{login, LoginError};
 use mas_templates::{
@@ -31,6 +31,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::{PgConnection, PgPool};
 
 use super::shared::OptionalPostAuthAction;
+use crate::features::FeatureFlags;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct LoginForm {
@@ -42,16 +43,18 @@ impl ToFormState for LoginForm {
     type Field = LoginFormField;
 }
 
-#[tracing::instrument(skip(templates, pool, cookie_jar))]
+#[tracing::instrument(skip(templates, pool, feature_flags, cookie_jar))]
 pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(feature_flags): Extension<FeatureFlags>,
     Query(query): Query<OptionalPostAuthAction>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
@@ -66,6 +69,7 @@ pub(crate) async fn get(
             csrf_token,
             &mut conn,
             &templates,
+            &feature_flags,
         )
         .await?;
 
@@ -73,18 +77,22 @@ pub(crate) async fn get(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(feature_flags): Extension<FeatureFlags>,
     Query(query): Query<OptionalPostAuthAction>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<LoginForm>>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
 
     // Validate the form
     let state = {
@@ -108,6 +116,7 @@ pub(crate) async fn post(
             csrf_token,
             &mut conn,
             &templates,
+            &feature_flags,
         )
         .await?;
 
@@ -117,6 +126,13 @@ pub(crate) async fn post(
     match login(&mut conn, &form.username, &form.password).await {
         Ok(session_info) => {
             let cookie_jar = cookie_jar.set_session(&session_info);
+
+            // The session can only be used to verify an email address until
+            // the user did so
+            if email_config.require_verification && !session_info.user.has_verified_email() {
+                return Ok((cookie_jar, mas_router::AccountEmails.go()).into_response());
+            }
+
             let reply = query.go_next();
             Ok((cookie_jar, reply).into_response())
         }
@@ -134,6 +150,7 @@ pub(crate) async fn post(
                 csrf_token,
                 &mut conn,
                 &templates,
+                &feature_flags,
             )
             .await?;
 
@@ -148,6 +165,7 @@ async fn render(
     csrf_token: CsrfToken,
     conn: &mut PgConnection,
     templates: &Templates,
+    feature_flags: &FeatureFlags,
 ) -> Result<String, FancyError> {
     let next = action.load_context(conn).await?;
     let ctx = if let Some(next) = next {
@@ -155,10 +173,13 @@ async fn render(
     } else {
         ctx
     };
-    let register_link = mas_router::Register::from(action.post_auth_action).relative_url();
-    let ctx = ctx
-        .with_register_link(register_link.to_string())
-        .with_csrf(csrf_token.form_value());
+    let ctx = if feature_flags.registration() {
+        let register_link = mas_router::Register::from(action.post_auth_action).relative_url();
+        ctx.with_register_link(register_link.to_string())
+    } else {
+        ctx
+    };
+    let ctx = ctx.with_csrf(csrf_token.form_value());
 
     let content = templates.render_login(&ctx).await?;
     Ok(content)
@@ -18,7 +18,7 @@ use axum::{
 };
 use axum_extra::extract::PrivateCookieJar;
 use mas_axum_utils::{
-    csrf::{CsrfExt, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
     FancyError, SessionInfoExt,
 };
 use mas_config::Encrypter;
@@ -28,12 +28,13 @@ use sqlx::PgPool;
 
 pub(crate) async fn post(
     Extension(pool): Extension<PgPool>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<Option<PostAuthAction>>>,
 ) -> Result<impl IntoResponse, FancyError> {
     let mut txn = pool.begin().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
 
     let (session_info, mut cookie_jar) = cookie_jar.session_info();
 
@@ -16,6 +16,8 @@ pub mod account;
 pub mod index;
 pub mod login;
 pub mod logout;
+pub mod password_reset;
 pub mod reauth;
 pub mod register;
 pub mod shared;
+pub mod webauthn;
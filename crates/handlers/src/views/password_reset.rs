@@ -0,0 +1,231 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::str::FromStr;
+
+use argon2::Argon2;
+use axum::{
+    extract::{Extension, Form, Query},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use lettre::{message::Mailbox, Address};
+use mas_axum_utils::{
+    csrf::{CsrfExt, CsrfProtection, ProtectedForm},
+    FancyError,
+};
+use mas_config::{EmailConfig, Encrypter, PasswordsConfig};
+use mas_data_model::{PasswordPolicy, User};
+use mas_email::{EmailNormalizer, MailQueue};
+use mas_router::{PasswordResetToken, Route, UrlBuilder};
+use mas_storage::{
+    user::{
+        add_password_reset, lookup_users_by_verified_email, reset_password, PasswordResetError,
+    },
+    PostgresqlBackend,
+};
+use mas_templates::{
+    FieldError, FormError, PasswordResetCompleteContext, PasswordResetCompleteFormField,
+    PasswordResetContext, PasswordResetEmailContext, PasswordResetFormField, TemplateContext,
+    Templates, ToFormState,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, PgPool};
+use tracing::info;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ResetForm {
+    email: String,
+}
+
+impl ToFormState for ResetForm {
+    type Field = PasswordResetFormField;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CompleteForm {
+    token: String,
+    new_password: String,
+    new_password_confirm: String,
+}
+
+impl ToFormState for CompleteForm {
+    type Field = PasswordResetCompleteFormField;
+}
+
+pub(crate) async fn get(
+    Extension(templates): Extension<Templates>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+
+    let ctx = PasswordResetContext::default().with_csrf(csrf_token.form_value());
+    let content = templates.render_password_reset(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+/// Create a password reset for the user and queue the email with the link
+async fn start_password_reset(
+    mail_queue: &MailQueue,
+    url_builder: &UrlBuilder,
+    executor: impl PgExecutor<'_>,
+    user: User<PostgresqlBackend>,
+    email: &str,
+) -> anyhow::Result<()> {
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let address: Address = email.parse()?;
+
+    let reset = add_password_reset(executor, user, token).await?;
+
+    let mailbox = Mailbox::new(Some(reset.user.username.clone()), address);
+    let link = url_builder.password_reset_link(reset.token);
+    let context = PasswordResetEmailContext::new(reset.user.into(), link);
+
+    mail_queue
+        .queue_password_reset_email(mailbox, context)
+        .await?;
+
+    info!(
+        password_reset.id = reset.data,
+        "Password reset email queued"
+    );
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(mail_queue): Extension<MailQueue>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<ResetForm>>,
+) -> Result<Response, FancyError> {
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+
+    if Address::from_str(&form.email).is_err() {
+        let state = form
+            .to_form_state()
+            .with_error_on_field(PasswordResetFormField::Email, FieldError::Invalid);
+        let ctx = PasswordResetContext::default()
+            .with_form_state(state)
+            .with_csrf(csrf_token.form_value());
+        let content = templates.render_password_reset(&ctx).await?;
+        return Ok((cookie_jar, Html(content)).into_response());
+    }
+
+    let mut txn = pool.begin().await?;
+
+    let normalized_email = EmailNormalizer::from(&email_config).normalize(&form.email);
+    let owners = lookup_users_by_verified_email(&mut txn, &normalized_email).await?;
+    for (user, email) in owners {
+        start_password_reset(&mail_queue, &url_builder, &mut txn, user, &email).await?;
+    }
+
+    txn.commit().await?;
+
+    // Reply the same way whether the address is known or not, so that this
+    // form can't be used to find out which addresses have an account
+    let ctx = PasswordResetContext::default()
+        .sent()
+        .with_csrf(csrf_token.form_value());
+    let content = templates.render_password_reset(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn complete_get(
+    Extension(templates): Extension<Templates>,
+    Query(query): Query<PasswordResetToken>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<Response, FancyError> {
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+
+    let ctx = PasswordResetCompleteContext::new(query.token).with_csrf(csrf_token.form_value());
+    let content = templates.render_password_reset_complete(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
+
+pub(crate) async fn complete_post(
+    Extension(templates): Extension<Templates>,
+    Extension(pool): Extension<PgPool>,
+    Extension(passwords_config): Extension<PasswordsConfig>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Form(form): Form<ProtectedForm<CompleteForm>>,
+) -> Result<Response, FancyError> {
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
+
+    let mut state = form.to_form_state();
+
+    if form.new_password == form.new_password_confirm {
+        let mut txn = pool.begin().await?;
+
+        let policy = PasswordPolicy {
+            min_length: passwords_config.min_length,
+            min_complexity: passwords_config.min_complexity,
+        };
+        let phf = Argon2::default();
+        match reset_password(&mut txn, phf, &policy, &form.token, &form.new_password).await {
+            Ok(user) => {
+                txn.commit().await?;
+                info!(user.id = user.data, "Password reset");
+                return Ok((cookie_jar, mas_router::Login::default().go()).into_response());
+            }
+            Err(PasswordResetError::InvalidToken) => {
+                state.add_error_on_form(FormError::InvalidLink);
+            }
+            Err(PasswordResetError::Policy(e)) => {
+                state.add_error_on_field(
+                    PasswordResetCompleteFormField::NewPassword,
+                    FieldError::Policy {
+                        message: e.to_string(),
+                    },
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    } else {
+        state.add_error_on_form(FormError::PasswordMismatch);
+        state.add_error_on_field(
+            PasswordResetCompleteFormField::NewPassword,
+            FieldError::Unspecified,
+        );
+        state.add_error_on_field(
+            PasswordResetCompleteFormField::NewPasswordConfirm,
+            FieldError::Unspecified,
+        );
+    }
+
+    let ctx = PasswordResetCompleteContext::new(form.token)
+        .with_form_state(state)
+        .with_csrf(csrf_token.form_value());
+    let content = templates.render_password_reset_complete(&ctx).await?;
+
+    Ok((cookie_jar, Html(content)).into_response())
+}
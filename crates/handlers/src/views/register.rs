@@ -24,18 +24,20 @@ use axum::{
 use axum_extra::extract::PrivateCookieJar;
 use lettre::{message::Mailbox, Address};
 use mas_axum_utils::{
-    csrf::{CsrfExt, CsrfToken, ProtectedForm},
+    csrf::{CsrfExt, CsrfProtection, CsrfToken, ProtectedForm},
     FancyError, SessionInfoExt,
 };
-use mas_config::Encrypter;
-use mas_email::Mailer;
+use mas_config::{EmailConfig, Encrypter, PasswordsConfig, UsernamesConfig};
+use mas_data_model::{PasswordPolicy, UsernamePolicy};
+use mas_email::{EmailNormalizer, MailQueue};
 use mas_policy::PolicyFactory;
 use mas_router::Route;
 use mas_storage::user::{
-    add_user_email, add_user_email_verification_code, register_user, start_session, username_exists,
+    add_user_email, add_user_email_verification_code, check_username_available, register_user,
+    start_session, UsernameError,
 };
 use mas_templates::{
-    EmailVerificationContext, FieldError, FormError, RegisterContext, RegisterFormField,
+    EmailVerificationContext, FieldError, FormError, FormState, RegisterContext, RegisterFormField,
     TemplateContext, Templates, ToFormState,
 };
 use rand::{distributions::Uniform, thread_rng, Rng};
@@ -43,6 +45,7 @@ use serde::{Deserialize, Serialize};
 use sqlx::{PgConnection, PgPool};
 
 use super::shared::OptionalPostAuthAction;
+use crate::webhooks::{WebhookEvent, Webhooks};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct RegisterForm {
@@ -60,11 +63,12 @@ pub(crate) async fn get(
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
     Query(query): Query<OptionalPostAuthAction>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
 ) -> Result<Response, FancyError> {
     let mut conn = pool.acquire().await?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
     let (session_info, cookie_jar) = cookie_jar.session_info();
 
     let maybe_session = session_info.load_session(&mut conn).await?;
@@ -86,49 +90,55 @@ pub(crate) async fn get(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn post(
-    Extension(mailer): Extension<Mailer>,
+    Extension(mail_queue): Extension<MailQueue>,
+    Extension(email_config): Extension<EmailConfig>,
+    Extension(passwords_config): Extension<PasswordsConfig>,
+    Extension(usernames_config): Extension<UsernamesConfig>,
     Extension(policy_factory): Extension<Arc<PolicyFactory>>,
     Extension(templates): Extension<Templates>,
     Extension(pool): Extension<PgPool>,
+    Extension(webhooks): Extension<Webhooks>,
     Query(query): Query<OptionalPostAuthAction>,
+    Extension(csrf_protection): Extension<CsrfProtection>,
     cookie_jar: PrivateCookieJar<Encrypter>,
     Form(form): Form<ProtectedForm<RegisterForm>>,
 ) -> Result<Response, FancyError> {
     let mut txn = pool.begin().await?;
 
-    let form = cookie_jar.verify_form(form)?;
+    let form = cookie_jar.verify_form(&csrf_protection, form)?;
 
-    let (csrf_token, cookie_jar) = cookie_jar.csrf_token();
+    let (csrf_token, cookie_jar) = cookie_jar.csrf_token(&csrf_protection);
 
     // Validate the form
     let state = {
-        let mut state = form.to_form_state();
-
-        if form.username.is_empty() {
-            state.add_error_on_field(RegisterFormField::Username, FieldError::Required);
-        } else if username_exists(&mut txn, &form.username).await? {
-            state.add_error_on_field(RegisterFormField::Username, FieldError::Exists);
-        }
-
-        if form.email.is_empty() {
-            state.add_error_on_field(RegisterFormField::Email, FieldError::Required);
-        } else if Address::from_str(&form.email).is_err() {
-            state.add_error_on_field(RegisterFormField::Email, FieldError::Invalid);
-        }
-
-        if form.password.is_empty() {
-            state.add_error_on_field(RegisterFormField::Password, FieldError::Required);
-        }
-
-        if form.password_confirm.is_empty() {
-            state.add_error_on_field(RegisterFormField::PasswordConfirm, FieldError::Required);
-        }
-
-        if form.password != form.password_confirm {
-            state.add_error_on_form(FormError::PasswordMismatch);
-            state.add_error_on_field(RegisterFormField::Password, FieldError::Unspecified);
-            state.add_error_on_field(RegisterFormField::PasswordConfirm, FieldError::Unspecified);
+        let password_policy = PasswordPolicy {
+            min_length: passwords_config.min_length,
+            min_complexity: passwords_config.min_complexity,
+        };
+        let mut state = check_form(&form, &password_policy);
+
+        if !form.username.is_empty() {
+            let username_policy = UsernamePolicy {
+                min_length: usernames_config.min_length,
+                max_length: usernames_config.max_length,
+                reserved: usernames_config.reserved,
+            };
+
+            match check_username_available(&mut txn, &username_policy, &form.username).await {
+                Ok(()) => {}
+                Err(UsernameError::Taken) => {
+                    state.add_error_on_field(RegisterFormField::Username, FieldError::Exists);
+                }
+                Err(UsernameError::Policy(e)) => state.add_error_on_field(
+                    RegisterFormField::Username,
+                    FieldError::Policy {
+                        message: e.to_string(),
+                    },
+                ),
+                Err(e) => return Err(e.into()),
+            }
         }
 
         let mut policy = policy_factory.instantiate().await?;
@@ -181,7 +191,12 @@ pub(crate) async fn post(
     let pfh = Argon2::default();
     let user = register_user(&mut txn, pfh, &form.username, &form.password).await?;
 
-    let user_email = add_user_email(&mut txn, &user, &form.email).await?;
+    let normalized_email = EmailNormalizer::from(&email_config).normalize(&form.email);
+    let user_email = add_user_email(&mut txn, &user, &form.email, &normalized_email).await?;
+
+    webhooks
+        .notify(&mut txn, &WebhookEvent::user_created(&user))
+        .await?;
 
     // First, generate a code
     let range = Uniform::<u32>::from(0..1_000_000);
@@ -191,12 +206,14 @@ pub(crate) async fn post(
 
     let verification = add_user_email_verification_code(&mut txn, user_email, code).await?;
 
-    // And send the verification email
+    // And queue the verification email
     let mailbox = Mailbox::new(Some(user.username.clone()), address);
 
     let context = EmailVerificationContext::new(user.clone().into(), verification.clone().into());
 
-    mailer.send_verification_email(mailbox, &context).await?;
+    mail_queue
+        .queue_verification_email(mailbox, context)
+        .await?;
 
     let next =
         mas_router::AccountVerifyEmail::new(verification.data).and_maybe(query.post_auth_action);
@@ -209,6 +226,47 @@ pub(crate) async fn post(
     Ok((cookie_jar, next.go()).into_response())
 }
 
+/// Check the fields of the form which don't need the database
+fn check_form(
+    form: &RegisterForm,
+    password_policy: &PasswordPolicy,
+) -> FormState<RegisterFormField> {
+    let mut state = form.to_form_state();
+
+    if form.username.is_empty() {
+        state.add_error_on_field(RegisterFormField::Username, FieldError::Required);
+    }
+
+    if form.email.is_empty() {
+        state.add_error_on_field(RegisterFormField::Email, FieldError::Required);
+    } else if Address::from_str(&form.email).is_err() {
+        state.add_error_on_field(RegisterFormField::Email, FieldError::Invalid);
+    }
+
+    if form.password.is_empty() {
+        state.add_error_on_field(RegisterFormField::Password, FieldError::Required);
+    } else if let Err(e) = password_policy.check(&form.password) {
+        state.add_error_on_field(
+            RegisterFormField::Password,
+            FieldError::Policy {
+                message: e.to_string(),
+            },
+        );
+    }
+
+    if form.password_confirm.is_empty() {
+        state.add_error_on_field(RegisterFormField::PasswordConfirm, FieldError::Required);
+    }
+
+    if form.password != form.password_confirm {
+        state.add_error_on_form(FormError::PasswordMismatch);
+        state.add_error_on_field(RegisterFormField::Password, FieldError::Unspecified);
+        state.add_error_on_field(RegisterFormField::PasswordConfirm, FieldError::Unspecified);
+    }
+
+    state
+}
+
 async fn render(
     ctx: RegisterContext,
     action: OptionalPostAuthAction,
@@ -230,3 +288,76 @@ async fn render(
     let content = templates.render_register(&ctx).await?;
     Ok(content)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn form(username: &str, password: &str, password_confirm: &str) -> RegisterForm {
+        RegisterForm {
+            username: username.to_owned(),
+            email: "alice@example.com".to_owned(),
+            password: password.to_owned(),
+            password_confirm: password_confirm.to_owned(),
+        }
+    }
+
+    fn field_errors(state: &FormState<RegisterFormField>, field: &str) -> Value {
+        serde_json::to_value(state).unwrap()["fields"][field]["errors"].clone()
+    }
+
+    #[test]
+    fn valid_registration() {
+        let state = check_form(
+            &form("alice", "hunter22", "hunter22"),
+            &PasswordPolicy::default(),
+        );
+        assert!(state.is_valid());
+    }
+
+    #[test]
+    fn password_against_the_policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_complexity: 3,
+        };
+
+        let state = check_form(&form("alice", "hunter2", "hunter2"), &policy);
+        assert!(!state.is_valid());
+        assert_eq!(
+            field_errors(&state, "password"),
+            json!([{
+                "kind": "policy",
+                "message": "password must be at least 8 characters long",
+            }])
+        );
+
+        let state = check_form(&form("alice", "hunter22", "hunter22"), &policy);
+        assert_eq!(
+            field_errors(&state, "password"),
+            json!([{ "kind": "policy", "message": "password is too weak" }])
+        );
+
+        let state = check_form(&form("alice", "Hunter22", "Hunter22"), &policy);
+        assert!(state.is_valid());
+    }
+
+    #[test]
+    fn missing_and_mismatched_fields() {
+        let state = check_form(
+            &form("", "hunter22", "hunter23"),
+            &PasswordPolicy::default(),
+        );
+        assert!(!state.is_valid());
+        assert_eq!(
+            field_errors(&state, "username"),
+            json!([{ "kind": "required" }])
+        );
+        assert_eq!(
+            field_errors(&state, "password_confirm"),
+            json!([{ "kind": "unspecified" }])
+        );
+    }
+}
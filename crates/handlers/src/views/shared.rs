@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use axum::response::Redirect;
+use chrono::{DateTime, Duration, Utc};
+use mas_data_model::{BrowserSession, StorageBackend};
 use mas_router::{PostAuthAction, Route};
 use mas_storage::{
     compat::get_compat_sso_login_by_id, oauth2::authorization_grant::get_grant_by_id,
@@ -53,7 +56,80 @@ impl OptionalPostAuthAction {
                 Ok(Some(PostAuthContext::ContinueCompatSsoLogin { login }))
             }
             Some(PostAuthAction::ChangePassword) => Ok(Some(PostAuthContext::ChangePassword)),
+            Some(PostAuthAction::ManageEmails) => Ok(Some(PostAuthContext::ManageEmails)),
+            Some(PostAuthAction::ExportAccount) => Ok(Some(PostAuthContext::ExportAccount)),
+            Some(PostAuthAction::DeleteAccount) => Ok(Some(PostAuthContext::DeleteAccount)),
             None => Ok(None),
         }
     }
 }
+
+/// Send the user to the re-authentication prompt before a sensitive action, if
+/// they didn't authenticate in the last `max_age`. They are sent to `action`
+/// once authenticated again
+pub(crate) fn require_recent_authentication<T: StorageBackend>(
+    session: &BrowserSession<T>,
+    max_age: Duration,
+    action: PostAuthAction,
+    now: DateTime<Utc>,
+) -> Option<Redirect> {
+    if session.was_authenticated_after(now - max_age) {
+        None
+    } else {
+        Some(mas_router::Reauth::and_then(action).go())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use hyper::header::LOCATION;
+    use mas_data_model::Authentication;
+
+    use super::*;
+
+    fn session(authenticated_at: Option<DateTime<Utc>>) -> BrowserSession<()> {
+        let mut session = BrowserSession::<()>::samples().remove(0);
+        session.last_authentication = authenticated_at.map(|created_at| Authentication {
+            data: (),
+            created_at,
+        });
+        session
+    }
+
+    #[test]
+    fn stale_sessions_have_to_reauthenticate() {
+        let now = Utc::now();
+
+        for session in [session(Some(now - Duration::hours(2))), session(None)] {
+            let redirect = require_recent_authentication(
+                &session,
+                Duration::hours(1),
+                PostAuthAction::ChangePassword,
+                now,
+            )
+            .expect("the user should authenticate again");
+
+            // The action is resumed after the re-authentication
+            let response = redirect.into_response();
+            assert_eq!(
+                response.headers()[LOCATION],
+                &*mas_router::Reauth::and_then(PostAuthAction::ChangePassword).relative_url()
+            );
+        }
+    }
+
+    #[test]
+    fn fresh_sessions_proceed() {
+        let now = Utc::now();
+        let session = session(Some(now - Duration::minutes(5)));
+
+        assert!(require_recent_authentication(
+            &session,
+            Duration::hours(1),
+            PostAuthAction::ManageEmails,
+            now
+        )
+        .is_none());
+    }
+}
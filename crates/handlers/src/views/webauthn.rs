@@ -0,0 +1,528 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passkey registration and login with Web Authentication.
+//!
+//! Both ceremonies are two requests: the `GET` returns the challenge the
+//! browser passes to `navigator.credentials`, and the `POST` gets the response
+//! of the authenticator. The state of the ceremony is kept in the database in
+//! between, and an encrypted cookie points the browser to it. The challenge can
+//! only be answered once, within a few minutes.
+
+use anyhow::Context;
+use axum::{
+    extract::{Extension, Query},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    PrivateCookieJar,
+};
+use chrono::{Duration, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use hyper::StatusCode;
+use mas_axum_utils::SessionInfoExt;
+use mas_config::Encrypter;
+use mas_data_model::{ReplayedSignCount, StorageBackend, User, WebauthnCredential};
+use mas_router::UrlBuilder;
+use mas_storage::{
+    user::{
+        lookup_user_by_username, record_session_authentication, start_session,
+        ActiveSessionLookupError,
+    },
+    webauthn::{
+        add_webauthn_challenge, add_webauthn_credential, consume_webauthn_challenge,
+        get_user_webauthn_credentials, update_webauthn_credential,
+    },
+    PostgresqlBackend,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{PgExecutor, PgPool};
+use thiserror::Error;
+use tracing::info;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, CredentialID, Passkey, PasskeyAuthentication, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid,
+    Webauthn, WebauthnBuilder, WebauthnError,
+};
+
+const REGISTRATION_COOKIE: &str = "webauthn-registration";
+const AUTHENTICATION_COOKIE: &str = "webauthn-authentication";
+
+/// How long the user has to answer a challenge
+const CHALLENGE_TTL_SECONDS: i64 = 5 * 60;
+
+#[derive(Debug, Error)]
+pub(crate) enum RouteError {
+    #[error(transparent)]
+    Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error(transparent)]
+    Anyhow(#[from] anyhow::Error),
+
+    #[error("not logged in")]
+    NotLoggedIn,
+
+    #[error("no WebAuthn ceremony in progress")]
+    NoCeremony,
+
+    #[error("no passkey registered for this user")]
+    NoCredentials,
+
+    #[error("the authenticator response was rejected")]
+    Rejected(#[source] WebauthnError),
+
+    #[error(transparent)]
+    ReplayedSignCount(#[from] ReplayedSignCount),
+}
+
+impl From<sqlx::Error> for RouteError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<ActiveSessionLookupError> for RouteError {
+    fn from(e: ActiveSessionLookupError) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl From<serde_json::Error> for RouteError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Internal(Box::new(e))
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self {
+            Self::Internal(_) | Self::Anyhow(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
+            }
+            Self::NotLoggedIn => (StatusCode::UNAUTHORIZED, "not logged in"),
+            Self::NoCeremony => (StatusCode::BAD_REQUEST, "no WebAuthn ceremony in progress"),
+            // Same response whether the user exists or not
+            Self::NoCredentials => (StatusCode::BAD_REQUEST, "no passkey available"),
+            Self::Rejected(_) | Self::ReplayedSignCount(_) => {
+                (StatusCode::UNAUTHORIZED, "passkey verification failed")
+            }
+        };
+
+        (status, Json(json!({ "error": error }))).into_response()
+    }
+}
+
+/// The state of a registration, kept between the challenge and the response
+#[derive(Serialize, Deserialize)]
+struct RegistrationState {
+    user_id: i64,
+    user_handle: Uuid,
+    state: PasskeyRegistration,
+}
+
+/// The state of a login, kept between the challenge and the response
+#[derive(Serialize, Deserialize)]
+struct AuthenticationState {
+    username: String,
+    state: PasskeyAuthentication,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginParams {
+    username: String,
+}
+
+/// The relying party, which is the service as seen from the browser
+fn relying_party(url_builder: &UrlBuilder) -> anyhow::Result<Webauthn> {
+    let base = url_builder.oidc_issuer();
+    let rp_id = base.host_str().context("public base URL has no host")?;
+    let origin = Url::parse(&base.origin().ascii_serialization())?;
+
+    let webauthn = WebauthnBuilder::new(rp_id, &origin)?
+        .rp_name(rp_id)
+        .build()?;
+
+    Ok(webauthn)
+}
+
+/// Save the state of a ceremony, and point the browser to it.
+///
+/// The cookie is only used by the scripts of the service, which need a secure
+/// context for Web Authentication anyway, so it is never sent over plain HTTP
+/// nor with cross-site requests.
+async fn set_state<T: Serialize>(
+    executor: impl PgExecutor<'_>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    name: &'static str,
+    user: &User<PostgresqlBackend>,
+    state: &T,
+) -> Result<PrivateCookieJar<Encrypter>, RouteError> {
+    let state = serde_json::to_string(state)?;
+    let expires_at = Utc::now() + Duration::seconds(CHALLENGE_TTL_SECONDS);
+    let id = add_webauthn_challenge(executor, user, &state, expires_at).await?;
+
+    let mut cookie = Cookie::new(name, id.to_string());
+    cookie.set_path("/");
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_same_site(SameSite::Strict);
+    Ok(cookie_jar.add(cookie))
+}
+
+/// Get the state of a ceremony, which can only be used once.
+///
+/// It is consumed outside of the transaction of the handler, so that a
+/// rejected response also uses the challenge up.
+async fn take_state<T: DeserializeOwned>(
+    pool: &PgPool,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    name: &'static str,
+) -> Result<(T, PrivateCookieJar<Encrypter>), RouteError> {
+    let id = cookie_jar
+        .get(name)
+        .and_then(|cookie| cookie.value().parse().ok())
+        .ok_or(RouteError::NoCeremony)?;
+
+    let state = consume_webauthn_challenge(pool, id)
+        .await?
+        .ok_or(RouteError::NoCeremony)?;
+    let state = serde_json::from_str(&state).map_err(|_| RouteError::NoCeremony)?;
+
+    let mut cookie = Cookie::named(name);
+    cookie.set_path("/");
+    Ok((state, cookie_jar.remove(cookie)))
+}
+
+/// Authenticators store one user handle per account, so all the passkeys of a
+/// user share the handle of the first one
+fn user_handle<T: StorageBackend>(
+    credentials: &[WebauthnCredential<T>],
+) -> Result<Uuid, RouteError> {
+    match credentials.first() {
+        Some(credential) => Uuid::parse_str(&credential.user_handle)
+            .context("invalid user handle in database")
+            .map_err(RouteError::from),
+        None => Ok(Uuid::new_v4()),
+    }
+}
+
+fn credential_id(credential: &WebauthnCredential<impl StorageBackend>) -> Option<CredentialID> {
+    let id = BASE64URL_NOPAD
+        .decode(credential.credential_id.as_bytes())
+        .ok()?;
+    Some(id.into())
+}
+
+/// Check the response of the authenticator to a registration challenge, and
+/// get the ID and the serialized passkey to store
+fn finish_registration(
+    webauthn: &Webauthn,
+    state: &PasskeyRegistration,
+    response: &RegisterPublicKeyCredential,
+) -> Result<(String, String), RouteError> {
+    let passkey = webauthn
+        .finish_passkey_registration(response, state)
+        .map_err(RouteError::Rejected)?;
+
+    let credential_id = BASE64URL_NOPAD.encode(&passkey.cred_id().0);
+    let passkey = serde_json::to_string(&passkey)?;
+    Ok((credential_id, passkey))
+}
+
+/// Check the response of the authenticator to a login challenge against the
+/// stored credentials. Returns which credential was used, with its updated
+/// passkey and signature counter.
+///
+/// The library checks the counter against the credentials as they were when
+/// the challenge was made, so this checks it again against their current
+/// state.
+fn verify_assertion<T: StorageBackend>(
+    webauthn: &Webauthn,
+    state: &PasskeyAuthentication,
+    response: &PublicKeyCredential,
+    credentials: &[WebauthnCredential<T>],
+) -> Result<(usize, Passkey, u32), RouteError> {
+    let result = webauthn
+        .finish_passkey_authentication(response, state)
+        .map_err(RouteError::Rejected)?;
+
+    let credential_id = BASE64URL_NOPAD.encode(&result.cred_id().0);
+    let index = credentials
+        .iter()
+        .position(|credential| credential.credential_id == credential_id)
+        .ok_or(RouteError::NoCredentials)?;
+    let credential = &credentials[index];
+
+    credential.check_sign_count(result.counter())?;
+
+    let mut passkey: Passkey = serde_json::from_str(&credential.passkey)?;
+    passkey.update_credential(&result);
+
+    Ok((index, passkey, result.counter()))
+}
+
+pub(crate) async fn register_get(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+    let session = session_info
+        .load_session(&mut conn)
+        .await?
+        .ok_or(RouteError::NotLoggedIn)?;
+
+    let credentials = get_user_webauthn_credentials(&mut conn, &session.user).await?;
+    let user_handle = user_handle(&credentials)?;
+    let exclude = credentials.iter().filter_map(credential_id).collect();
+
+    let webauthn = relying_party(&url_builder)?;
+    let (challenge, state): (CreationChallengeResponse, _) = webauthn
+        .start_passkey_registration(
+            user_handle,
+            &session.user.username,
+            &session.user.username,
+            Some(exclude),
+        )
+        .context("could not start passkey registration")?;
+
+    let state = RegistrationState {
+        user_id: session.user.data,
+        user_handle,
+        state,
+    };
+    let cookie_jar = set_state(
+        &mut conn,
+        cookie_jar,
+        REGISTRATION_COOKIE,
+        &session.user,
+        &state,
+    )
+    .await?;
+
+    Ok((cookie_jar, Json(challenge)))
+}
+
+pub(crate) async fn register_post(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Json(response): Json<RegisterPublicKeyCredential>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut txn = pool.begin().await?;
+
+    let (session_info, cookie_jar) = cookie_jar.session_info();
+    let session = session_info
+        .load_session(&mut txn)
+        .await?
+        .ok_or(RouteError::NotLoggedIn)?;
+
+    let (state, cookie_jar): (RegistrationState, _) =
+        take_state(&pool, cookie_jar, REGISTRATION_COOKIE).await?;
+
+    // The challenge was made for another user
+    if state.user_id != session.user.data {
+        return Err(RouteError::NoCeremony);
+    }
+
+    let webauthn = relying_party(&url_builder)?;
+    let (credential_id, passkey) = finish_registration(&webauthn, &state.state, &response)?;
+
+    let credential = add_webauthn_credential(
+        &mut txn,
+        &session.user,
+        credential_id,
+        state.user_handle.to_string(),
+        passkey,
+        0,
+    )
+    .await?;
+
+    txn.commit().await?;
+
+    info!(
+        user.id = session.user.data,
+        user_webauthn_credential.id = credential.data,
+        "Registered passkey"
+    );
+
+    Ok((cookie_jar, StatusCode::CREATED))
+}
+
+pub(crate) async fn login_get(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    Query(params): Query<LoginParams>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+) -> Result<impl IntoResponse, RouteError> {
+    let mut conn = pool.acquire().await?;
+
+    let user = match lookup_user_by_username(&mut conn, &params.username).await {
+        Ok(user) => user,
+        Err(e) if e.not_found() => return Err(RouteError::NoCredentials),
+        Err(e) => return Err(RouteError::Internal(Box::new(e))),
+    };
+
+    let credentials = get_user_webauthn_credentials(&mut conn, &user).await?;
+    let passkeys = credentials
+        .iter()
+        .map(|credential| serde_json::from_str(&credential.passkey))
+        .collect::<Result<Vec<Passkey>, _>>()?;
+
+    if passkeys.is_empty() {
+        return Err(RouteError::NoCredentials);
+    }
+
+    let webauthn = relying_party(&url_builder)?;
+    let (challenge, state): (RequestChallengeResponse, _) = webauthn
+        .start_passkey_authentication(&passkeys)
+        .context("could not start passkey authentication")?;
+
+    let state = AuthenticationState {
+        username: user.username.clone(),
+        state,
+    };
+    let cookie_jar = set_state(&mut conn, cookie_jar, AUTHENTICATION_COOKIE, &user, &state).await?;
+
+    Ok((cookie_jar, Json(challenge)))
+}
+
+pub(crate) async fn login_post(
+    Extension(pool): Extension<PgPool>,
+    Extension(url_builder): Extension<UrlBuilder>,
+    cookie_jar: PrivateCookieJar<Encrypter>,
+    Json(response): Json<PublicKeyCredential>,
+) -> Result<impl IntoResponse, RouteError> {
+    let (state, cookie_jar): (AuthenticationState, _) =
+        take_state(&pool, cookie_jar, AUTHENTICATION_COOKIE).await?;
+
+    let mut txn = pool.begin().await?;
+
+    let user = match lookup_user_by_username(&mut txn, &state.username).await {
+        Ok(user) => user,
+        Err(e) if e.not_found() => return Err(RouteError::NoCredentials),
+        Err(e) => return Err(RouteError::Internal(Box::new(e))),
+    };
+
+    let mut credentials = get_user_webauthn_credentials(&mut txn, &user).await?;
+
+    let webauthn = relying_party(&url_builder)?;
+    let (index, passkey, sign_count) =
+        verify_assertion(&webauthn, &state.state, &response, &credentials)?;
+
+    let credential = &mut credentials[index];
+    let passkey = serde_json::to_string(&passkey)?;
+    // Another login with the same counter might have happened in the meantime
+    if !update_webauthn_credential(&mut txn, credential, passkey, sign_count).await? {
+        return Err(RouteError::ReplayedSignCount(ReplayedSignCount));
+    }
+    let credential_id = credential.data;
+
+    let mut session = start_session(&mut txn, user).await?;
+    record_session_authentication(&mut txn, &mut session).await?;
+
+    txn.commit().await?;
+
+    info!(
+        user.id = session.user.data,
+        user_webauthn_credential.id = credential_id,
+        "Logged in with a passkey"
+    );
+
+    let cookie_jar = cookie_jar.set_session(&session);
+    Ok((cookie_jar, StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    use super::*;
+
+    fn origin() -> Url {
+        Url::parse("https://example.com").unwrap()
+    }
+
+    fn webauthn() -> Webauthn {
+        relying_party(&UrlBuilder::new(origin())).unwrap()
+    }
+
+    #[test]
+    fn registration_and_assertion() {
+        let webauthn = webauthn();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let handle = user_handle::<()>(&[]).unwrap();
+        let (challenge, state) = webauthn
+            .start_passkey_registration(handle, "alice", "alice", None)
+            .unwrap();
+        let response = authenticator.do_registration(origin(), challenge).unwrap();
+        let (id, passkey) = finish_registration(&webauthn, &state, &response).unwrap();
+
+        let mut credential = WebauthnCredential::<()> {
+            data: (),
+            credential_id: id,
+            user_handle: handle.to_string(),
+            passkey,
+            sign_count: 0,
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+
+        // The next passkey of the user gets the same handle and excludes this one
+        let credentials = [credential.clone()];
+        assert_eq!(user_handle::<()>(&credentials).unwrap(), handle);
+        assert!(credential_id(&credential).is_some());
+
+        let passkeys: Vec<Passkey> = vec![serde_json::from_str(&credential.passkey).unwrap()];
+        let (challenge, state) = webauthn.start_passkey_authentication(&passkeys).unwrap();
+        let assertion = authenticator
+            .do_authentication(origin(), challenge)
+            .unwrap();
+
+        let (index, passkey, sign_count) =
+            verify_assertion(&webauthn, &state, &assertion, &credentials).unwrap();
+        assert_eq!(index, 0);
+
+        // Once the credential was used again, the same assertion is rejected
+        credential.passkey = serde_json::to_string(&passkey).unwrap();
+        credential.sign_count = sign_count + 1;
+        let res = verify_assertion(&webauthn, &state, &assertion, &[credential]);
+        assert!(matches!(res, Err(RouteError::ReplayedSignCount(_))));
+    }
+
+    #[test]
+    fn registration_with_wrong_challenge_is_rejected() {
+        let webauthn = webauthn();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new());
+
+        let (_, state) = webauthn
+            .start_passkey_registration(Uuid::new_v4(), "alice", "alice", None)
+            .unwrap();
+        let (other_challenge, _) = webauthn
+            .start_passkey_registration(Uuid::new_v4(), "alice", "alice", None)
+            .unwrap();
+        let response = authenticator
+            .do_registration(origin(), other_challenge)
+            .unwrap();
+
+        let res = finish_registration(&webauthn, &state, &response);
+        assert!(matches!(res, Err(RouteError::Rejected(_))));
+    }
+}
@@ -0,0 +1,642 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Webhooks notifying other systems, like the homeserver, of the account
+//! lifecycle events
+//!
+//! The events are logged in the database with the change they describe, and
+//! the [`WebhookDispatcher`] delivers them in the background, at least once,
+//! retrying with an exponential backoff. Each request is signed with the
+//! secret of the endpoint: the `X-Webhook-Signature` header is the hex-encoded
+//! HMAC-SHA256 of `{id}.{timestamp}.{body}`, prefixed with `sha256=`.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use hyper::{
+    header::{HeaderName, CONTENT_TYPE},
+    Body, Request, StatusCode,
+};
+use mas_config::{WebhookEndpointConfig, WebhooksConfig};
+use mas_data_model::User;
+use mas_iana::jose::JsonWebSignatureAlg;
+use mas_jose::{JwtHeader, SharedSecret, SigningKeystore};
+use mas_storage::{
+    webhook::{
+        claim_pending_webhooks, enqueue_webhook, mark_webhook_delivered, mark_webhook_failed,
+        WebhookDelivery,
+    },
+    PostgresqlBackend,
+};
+use serde::Serialize;
+use sqlx::{PgExecutor, PgPool};
+use thiserror::Error;
+use tower::ServiceExt;
+use tracing::{error, info, warn};
+
+/// The header with the ID of the delivery, the same across its attempts
+static X_WEBHOOK_ID: HeaderName = HeaderName::from_static("x-webhook-id");
+
+/// The header with the time of the attempt, as a UNIX timestamp
+static X_WEBHOOK_TIMESTAMP: HeaderName = HeaderName::from_static("x-webhook-timestamp");
+
+/// The header with the signature of the payload
+static X_WEBHOOK_SIGNATURE: HeaderName = HeaderName::from_static("x-webhook-signature");
+
+/// How long an endpoint has to reply
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many deliveries are attempted on each run
+const BATCH_SIZE: u16 = 100;
+
+/// How long the deliveries of a run are claimed for. This is longer than
+/// attempting a whole batch can take, after which they are attempted again
+const CLAIM_LEASE: i64 = 30 * 60;
+
+/// The delay before the first retry, doubled after each failed attempt
+const FIRST_RETRY_DELAY: i64 = 30;
+
+/// The longest delay between two attempts
+const MAX_RETRY_DELAY: i64 = 6 * 60 * 60;
+
+/// The user an event is about
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WebhookUser {
+    sub: String,
+    username: String,
+    primary_email: Option<String>,
+}
+
+impl From<&User<PostgresqlBackend>> for WebhookUser {
+    fn from(user: &User<PostgresqlBackend>) -> Self {
+        Self {
+            sub: user.sub.clone(),
+            username: user.username.clone(),
+            primary_email: user.primary_email.as_ref().map(|email| email.email.clone()),
+        }
+    }
+}
+
+/// An account lifecycle event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type")]
+pub enum WebhookEvent {
+    /// A user registered, or was provisioned by an upstream provider
+    #[serde(rename = "user.created")]
+    UserCreated(WebhookUser),
+
+    /// A user was deactivated by an administrator
+    #[serde(rename = "user.deactivated")]
+    UserDeactivated(WebhookUser),
+
+    /// A user verified, removed or changed their primary email. The event
+    /// carries the primary email after the change
+    #[serde(rename = "user.email_changed")]
+    UserEmailChanged(WebhookUser),
+}
+
+impl WebhookEvent {
+    #[must_use]
+    pub fn user_created(user: &User<PostgresqlBackend>) -> Self {
+        Self::UserCreated(user.into())
+    }
+
+    #[must_use]
+    pub fn user_deactivated(user: &User<PostgresqlBackend>) -> Self {
+        Self::UserDeactivated(user.into())
+    }
+
+    #[must_use]
+    pub fn user_email_changed(user: &User<PostgresqlBackend>) -> Self {
+        Self::UserEmailChanged(user.into())
+    }
+
+    /// The type of the event, in the payload and in the delivery log
+    #[must_use]
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Self::UserCreated(_) => "user.created",
+            Self::UserDeactivated(_) => "user.deactivated",
+            Self::UserEmailChanged(_) => "user.email_changed",
+        }
+    }
+}
+
+/// The endpoints to notify, available to the handlers as an extension
+#[derive(Debug, Clone, Default)]
+pub struct Webhooks {
+    endpoints: Arc<Vec<String>>,
+}
+
+impl Webhooks {
+    #[must_use]
+    pub fn from_config(config: &WebhooksConfig) -> Self {
+        let endpoints = config
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.id.clone())
+            .collect();
+        Self {
+            endpoints: Arc::new(endpoints),
+        }
+    }
+
+    /// Enqueue the delivery of an event to all the endpoints. This should be
+    /// called in the transaction making the change, so that the event is
+    /// delivered if and only if it is committed
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the event could not be logged
+    pub async fn notify(
+        &self,
+        executor: impl PgExecutor<'_>,
+        event: &WebhookEvent,
+    ) -> anyhow::Result<()> {
+        if self.endpoints.is_empty() {
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(event)?;
+        enqueue_webhook(executor, &self.endpoints, event.event_type(), &payload).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("the endpoint replied with {0}")]
+    Status(StatusCode),
+
+    #[error("the endpoint did not reply in time")]
+    Timeout,
+
+    #[error("could not reach the endpoint")]
+    Request(#[source] Box<dyn std::error::Error + Send + Sync + 'static>),
+
+    #[error("could not sign the payload")]
+    Signature(#[source] anyhow::Error),
+}
+
+/// Sends the signed requests to the endpoints
+#[async_trait]
+pub trait WebhookTransport: Send + Sync {
+    async fn send(&self, request: Request<Body>) -> Result<(), WebhookError>;
+}
+
+/// Sends the requests over HTTP. Any reply outside of the 2xx range is a
+/// failure
+struct HttpTransport;
+
+#[async_trait]
+impl WebhookTransport for HttpTransport {
+    async fn send(&self, request: Request<Body>) -> Result<(), WebhookError> {
+        let response = tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            mas_http::client("webhook").oneshot(request),
+        )
+        .await
+        .map_err(|_| WebhookError::Timeout)?
+        .map_err(|e| WebhookError::Request(Box::new(e)))?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(WebhookError::Status(response.status()))
+        }
+    }
+}
+
+/// The hex-encoded HMAC-SHA256 of the ID, the timestamp and the body
+async fn signature(
+    secret: &str,
+    id: i64,
+    timestamp: i64,
+    body: &str,
+) -> Result<String, WebhookError> {
+    let message = format!("{}.{}.{}", id, timestamp, body);
+    let signature = SharedSecret::new(&secret)
+        .sign(
+            &JwtHeader::new(JsonWebSignatureAlg::Hs256),
+            message.as_bytes(),
+        )
+        .await
+        .map_err(WebhookError::Signature)?;
+    Ok(format!("sha256={}", HEXLOWER.encode(&signature)))
+}
+
+/// How long to wait before attempting a delivery again, after it failed
+/// `attempts` times
+fn retry_delay(attempts: i32) -> chrono::Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1))
+        .unwrap_or_default()
+        .min(20);
+    let seconds = FIRST_RETRY_DELAY
+        .saturating_mul(2_i64.pow(exponent))
+        .min(MAX_RETRY_DELAY);
+    chrono::Duration::seconds(seconds)
+}
+
+/// What to record after attempting a delivery
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Delivered,
+    Retry {
+        error: String,
+        delay: chrono::Duration,
+    },
+    GiveUp {
+        error: String,
+    },
+}
+
+/// Delivers the events logged by [`Webhooks::notify`] in the background
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    endpoints: Arc<Vec<WebhookEndpointConfig>>,
+    max_attempts: u32,
+    poll_interval: Duration,
+    transport: Arc<dyn WebhookTransport>,
+}
+
+impl WebhookDispatcher {
+    #[must_use]
+    pub fn from_config(config: &WebhooksConfig) -> Self {
+        Self {
+            endpoints: Arc::new(config.endpoints.clone()),
+            max_attempts: config.max_attempts,
+            poll_interval: config.poll_interval,
+            transport: Arc::new(HttpTransport),
+        }
+    }
+
+    /// Whether there is any endpoint to deliver the events to
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.endpoints.is_empty()
+    }
+
+    /// Deliver the pending events, polling the database for them. This runs
+    /// forever, and should be spawned in the background.
+    pub async fn run(self, pool: PgPool) {
+        loop {
+            match self.deliver_pending(&pool).await {
+                // There might be more of them
+                Ok(count) if count >= usize::from(BATCH_SIZE) => continue,
+                Ok(_) => {}
+                Err(e) => error!(error = %e, "Could not deliver the webhooks"),
+            }
+
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Attempt the deliveries which are due. They are claimed first, so that
+    /// other instances skip them, and no transaction is held while the
+    /// endpoints are called
+    ///
+    /// Returns how many deliveries were attempted
+    async fn deliver_pending(&self, pool: &PgPool) -> anyhow::Result<usize> {
+        let deliveries = claim_pending_webhooks(
+            pool,
+            i64::from(BATCH_SIZE),
+            chrono::Duration::seconds(CLAIM_LEASE),
+        )
+        .await?;
+
+        for delivery in &deliveries {
+            match self.attempt(delivery, Utc::now()).await {
+                Outcome::Delivered => {
+                    mark_webhook_delivered(pool, delivery.id).await?;
+                }
+                Outcome::Retry { error, delay } => {
+                    warn!(
+                        delivery.id = delivery.id,
+                        %delivery.endpoint,
+                        %error,
+                        retry_in = delay.num_seconds(),
+                        "Webhook delivery failed"
+                    );
+                    mark_webhook_failed(pool, delivery.id, &error, Some(delay)).await?;
+                }
+                Outcome::GiveUp { error } => {
+                    error!(
+                        delivery.id = delivery.id,
+                        %delivery.endpoint,
+                        %error,
+                        "Giving up on webhook delivery"
+                    );
+                    mark_webhook_failed(pool, delivery.id, &error, None).await?;
+                }
+            }
+        }
+
+        if !deliveries.is_empty() {
+            info!(count = deliveries.len(), "Attempted webhook deliveries");
+        }
+
+        Ok(deliveries.len())
+    }
+
+    /// Send a delivery to its endpoint, and decide what to do next
+    async fn attempt(&self, delivery: &WebhookDelivery, now: DateTime<Utc>) -> Outcome {
+        let endpoint = match self
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.id == delivery.endpoint)
+        {
+            Some(endpoint) => endpoint,
+            None => {
+                return Outcome::GiveUp {
+                    error: "the endpoint is not configured anymore".to_owned(),
+                }
+            }
+        };
+
+        let error = match self.send(endpoint, delivery, now).await {
+            Ok(()) => return Outcome::Delivered,
+            Err(e) => e.to_string(),
+        };
+
+        let attempts = delivery.attempts.saturating_add(1);
+        if u32::try_from(attempts).unwrap_or(u32::MAX) >= self.max_attempts {
+            Outcome::GiveUp { error }
+        } else {
+            Outcome::Retry {
+                error,
+                delay: retry_delay(attempts),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        endpoint: &WebhookEndpointConfig,
+        delivery: &WebhookDelivery,
+        now: DateTime<Utc>,
+    ) -> Result<(), WebhookError> {
+        let timestamp = now.timestamp();
+        let signature =
+            signature(&endpoint.secret, delivery.id, timestamp, &delivery.payload).await?;
+
+        let request = Request::builder()
+            .method("POST")
+            .uri(endpoint.url.as_str())
+            .header(CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(&X_WEBHOOK_ID, delivery.id)
+            .header(&X_WEBHOOK_TIMESTAMP, timestamp)
+            .header(&X_WEBHOOK_SIGNATURE, signature)
+            .body(Body::from(delivery.payload.clone()))
+            .map_err(|e| WebhookError::Request(Box::new(e)))?;
+
+        self.transport.send(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use argon2::Argon2;
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+
+    /// Fails a number of times, then accepts the requests, keeping them
+    #[derive(Default)]
+    struct MockTransport {
+        failures: Mutex<usize>,
+        received: Mutex<Vec<Request<Body>>>,
+    }
+
+    #[async_trait]
+    impl WebhookTransport for MockTransport {
+        async fn send(&self, request: Request<Body>) -> Result<(), WebhookError> {
+            self.received.lock().unwrap().push(request);
+
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                Err(WebhookError::Status(StatusCode::SERVICE_UNAVAILABLE))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn dispatcher(transport: Arc<MockTransport>) -> WebhookDispatcher {
+        WebhookDispatcher {
+            endpoints: Arc::new(vec![WebhookEndpointConfig {
+                id: "synapse".to_owned(),
+                url: "https://synapse.example.com/_mas/events".parse().unwrap(),
+                secret: "hunter2".to_owned(),
+            }]),
+            max_attempts: 3,
+            poll_interval: Duration::from_secs(5),
+            transport,
+        }
+    }
+
+    fn delivery(attempts: i32) -> WebhookDelivery {
+        WebhookDelivery {
+            id: 42,
+            endpoint: "synapse".to_owned(),
+            event: "user.created".to_owned(),
+            payload: r#"{"type":"user.created"}"#.to_owned(),
+            created_at: Utc::now(),
+            attempts,
+        }
+    }
+
+    fn user() -> User<PostgresqlBackend> {
+        User {
+            data: 1,
+            username: "alice".to_owned(),
+            sub: "01FSHN9AG0MZAA6S4AF7CTV32E".to_owned(),
+            primary_email: None,
+        }
+    }
+
+    #[test]
+    fn event_payloads() {
+        let payload = serde_json::to_value(WebhookEvent::user_created(&user())).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "type": "user.created",
+                "sub": "01FSHN9AG0MZAA6S4AF7CTV32E",
+                "username": "alice",
+                "primary_email": null,
+            })
+        );
+
+        for event in [
+            WebhookEvent::user_created(&user()),
+            WebhookEvent::user_deactivated(&user()),
+            WebhookEvent::user_email_changed(&user()),
+        ] {
+            let payload = serde_json::to_value(&event).unwrap();
+            assert_eq!(payload["type"], event.event_type());
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let delays: Vec<_> = (1..=12).map(|n| retry_delay(n).num_seconds()).collect();
+        assert_eq!(
+            delays,
+            vec![30, 60, 120, 240, 480, 960, 1920, 3840, 7680, 15360, 21600, 21600]
+        );
+
+        // It doesn't overflow
+        assert_eq!(retry_delay(i32::MAX).num_seconds(), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(0).num_seconds(), FIRST_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn requests_are_signed() {
+        let transport = Arc::new(MockTransport::default());
+        let now = DateTime::parse_from_rfc3339("2022-06-24T09:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let outcome = dispatcher(transport.clone())
+            .attempt(&delivery(0), now)
+            .await;
+        assert_eq!(outcome, Outcome::Delivered);
+
+        let expected = signature("hunter2", 42, 1_656_061_200, r#"{"type":"user.created"}"#)
+            .await
+            .unwrap();
+        assert_eq!(expected.len(), "sha256=".len() + 64);
+
+        {
+            let received = transport.received.lock().unwrap();
+            let headers = received[0].headers();
+            assert_eq!(received[0].uri(), "https://synapse.example.com/_mas/events");
+            assert_eq!(headers[&X_WEBHOOK_ID], "42");
+            assert_eq!(headers[&X_WEBHOOK_TIMESTAMP], "1656061200");
+            assert_eq!(headers[&X_WEBHOOK_SIGNATURE], expected.as_str());
+        }
+
+        // Another secret, or another payload, gives another signature
+        let other = signature("hunter3", 42, 1_656_061_200, r#"{"type":"user.created"}"#)
+            .await
+            .unwrap();
+        assert_ne!(other, expected);
+        let other = signature("hunter2", 42, 1_656_061_200, r#"{"type":"user.deleted"}"#)
+            .await
+            .unwrap();
+        assert_ne!(other, expected);
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let transport = Arc::new(MockTransport {
+            failures: Mutex::new(2),
+            ..MockTransport::default()
+        });
+        let dispatcher = dispatcher(transport.clone());
+
+        // The first failure is retried after the first delay
+        let outcome = dispatcher.attempt(&delivery(0), Utc::now()).await;
+        assert_eq!(
+            outcome,
+            Outcome::Retry {
+                error: "the endpoint replied with 503 Service Unavailable".to_owned(),
+                delay: chrono::Duration::seconds(30),
+            }
+        );
+
+        // Then after twice as long
+        let outcome = dispatcher.attempt(&delivery(1), Utc::now()).await;
+        assert!(matches!(
+            outcome,
+            Outcome::Retry { delay, .. } if delay == chrono::Duration::seconds(60)
+        ));
+
+        // Until it succeeds
+        let outcome = dispatcher.attempt(&delivery(2), Utc::now()).await;
+        assert_eq!(outcome, Outcome::Delivered);
+
+        // Every attempt has the same ID
+        let received = transport.received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received
+            .iter()
+            .all(|request| request.headers()[&X_WEBHOOK_ID] == "42"));
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_given_up_on() {
+        let transport = Arc::new(MockTransport {
+            failures: Mutex::new(usize::MAX),
+            ..MockTransport::default()
+        });
+        let dispatcher = dispatcher(transport);
+
+        // The third attempt is the last one
+        let outcome = dispatcher.attempt(&delivery(2), Utc::now()).await;
+        assert!(matches!(outcome, Outcome::GiveUp { .. }));
+
+        // The deliveries to endpoints removed from the config are dropped
+        let mut unknown = delivery(0);
+        unknown.endpoint = "removed".to_owned();
+        let outcome = dispatcher.attempt(&unknown, Utc::now()).await;
+        assert!(matches!(outcome, Outcome::GiveUp { .. }));
+    }
+
+    #[test]
+    fn no_endpoints_is_a_noop() {
+        let webhooks = Webhooks::from_config(&WebhooksConfig::default());
+        assert!(webhooks.endpoints.is_empty());
+        assert!(!WebhookDispatcher::from_config(&WebhooksConfig::default()).is_enabled());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn creating_a_user_enqueues_a_webhook() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        let webhooks = Webhooks {
+            endpoints: Arc::new(vec!["synapse".to_owned()]),
+        };
+
+        let user =
+            mas_storage::user::register_user(&mut txn, Argon2::default(), "webhook-bob", "hunter2")
+                .await
+                .unwrap();
+        webhooks
+            .notify(&mut txn, &WebhookEvent::user_created(&user))
+            .await
+            .unwrap();
+
+        let pending = claim_pending_webhooks(&mut txn, 1000, chrono::Duration::minutes(30))
+            .await
+            .unwrap();
+        let delivery = pending
+            .iter()
+            .find(|delivery| delivery.payload.contains(&user.sub))
+            .unwrap();
+        assert_eq!(delivery.endpoint, "synapse");
+        assert_eq!(delivery.event, "user.created");
+        assert_eq!(delivery.attempts, 0);
+
+        txn.rollback().await.unwrap();
+    }
+}
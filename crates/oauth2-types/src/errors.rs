@@ -134,6 +134,16 @@ pub mod oidc_core {
     );
 }
 
+mod rfc6750 {
+    use super::ClientError;
+
+    pub const INVALID_TOKEN: ClientError = ClientError::new(
+        "invalid_token",
+        "The access token provided is expired, revoked, malformed, \
+         or invalid for other reasons.",
+    );
+}
+
 mod rfc7591 {
     use super::ClientError;
 
@@ -150,4 +160,5 @@ mod rfc7591 {
 
 pub use oidc_core::*;
 pub use rfc6749::*;
+pub use rfc6750::*;
 pub use rfc7591::*;
@@ -53,6 +53,7 @@ pub mod pkce;
 pub mod registration;
 pub mod requests;
 pub mod scope;
+pub mod scope_description;
 pub mod webfinger;
 
 pub mod prelude {
@@ -271,6 +271,15 @@ pub struct IntrospectionRequest {
     pub token_type_hint: Option<OAuthTokenTypeHint>,
 }
 
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RevocationRequest {
+    pub token: String,
+
+    #[serde(default)]
+    pub token_type_hint: Option<OAuthTokenTypeHint>,
+}
+
 #[serde_as]
 #[skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
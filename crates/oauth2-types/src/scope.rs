@@ -14,9 +14,8 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use std::{borrow::Cow, collections::HashSet, iter::FromIterator, ops::Deref, str::FromStr};
+use std::{borrow::Cow, collections::BTreeSet, iter::FromIterator, ops::Deref, str::FromStr};
 
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -48,7 +47,7 @@ pub const OFFLINE_ACCESS: ScopeToken = ScopeToken::from_static("offline_access")
 //
 //    NQCHAR     = %x21 / %x23-5B / %x5D-7E
 fn nqchar(c: char) -> bool {
-    '\x21' == c || ('\x23'..'\x5B').contains(&c) || ('\x5D'..'\x7E').contains(&c)
+    '\x21' == c || ('\x23'..='\x5B').contains(&c) || ('\x5D'..='\x7E').contains(&c)
 }
 
 impl FromStr for ScopeToken {
@@ -75,17 +74,22 @@ impl Deref for ScopeToken {
     }
 }
 
-impl ToString for ScopeToken {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for ScopeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
+/// A set of scope tokens
+///
+/// Tokens are kept sorted and without duplicates, so that two scopes with the
+/// same tokens always have the same string representation, whatever order they
+/// were requested in.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Scope(HashSet<ScopeToken>);
+pub struct Scope(BTreeSet<ScopeToken>);
 
 impl std::ops::Deref for Scope {
-    type Target = HashSet<ScopeToken>;
+    type Target = BTreeSet<ScopeToken>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -100,7 +104,7 @@ impl FromStr for Scope {
         // https://datatracker.ietf.org/doc/html/rfc6749#appendix-A.4
         //
         //    scope       = scope-token *( SP scope-token )
-        let scopes: Result<HashSet<ScopeToken>, InvalidScope> =
+        let scopes: Result<BTreeSet<ScopeToken>, InvalidScope> =
             s.split(' ').map(ScopeToken::from_str).collect();
 
         Ok(Self(scopes?))
@@ -126,15 +130,31 @@ impl Scope {
             .unwrap_or(false)
     }
 
+    /// Check if this scope grants the `required` scope token. A granted token
+    /// ending with `*` grants every token starting with the same prefix
+    #[must_use]
+    pub fn allows(&self, required: &str) -> bool {
+        self.contains(required)
+            || self.0.iter().any(|token| match token.strip_suffix('*') {
+                Some(prefix) => required.starts_with(prefix),
+                None => false,
+            })
+    }
+
     pub fn insert(&mut self, value: ScopeToken) -> bool {
         self.0.insert(value)
     }
 }
 
-impl ToString for Scope {
-    fn to_string(&self) -> String {
-        let it = self.0.iter().map(ScopeToken::to_string);
-        Itertools::intersperse(it, ' '.to_string()).collect()
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, token) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(token)?;
+        }
+        Ok(())
     }
 }
 
@@ -160,7 +180,7 @@ impl<'de> Deserialize<'de> for Scope {
 
 impl FromIterator<ScopeToken> for Scope {
     fn from_iter<T: IntoIterator<Item = ScopeToken>>(iter: T) -> Self {
-        Self(HashSet::from_iter(iter))
+        Self(BTreeSet::from_iter(iter))
     }
 }
 
@@ -208,4 +228,90 @@ mod tests {
         assert!(Scope::from_str("http://example.com").is_ok());
         assert!(Scope::from_str("urn:matrix:*").is_ok());
     }
+
+    #[test]
+    fn parse_unusual_scope_tokens() {
+        // The bounds of the NQCHAR ranges are valid
+        assert!(ScopeToken::from_str("[").is_ok());
+        assert!(ScopeToken::from_str("]").is_ok());
+        assert!(ScopeToken::from_str("~").is_ok());
+        assert!(ScopeToken::from_str("#!$").is_ok());
+
+        assert!(ScopeToken::from_str("\"").is_err());
+        assert!(ScopeToken::from_str("\\").is_err());
+        assert!(ScopeToken::from_str("\x7F").is_err());
+        assert!(ScopeToken::from_str("é").is_err());
+    }
+
+    #[test]
+    fn scope_is_normalized() {
+        let scope = Scope::from_str("profile openid profile email openid").unwrap();
+        assert_eq!(scope.len(), 3);
+        assert_eq!(scope.to_string(), "email openid profile");
+
+        assert_eq!(
+            Scope::from_str("b a c").unwrap().to_string(),
+            Scope::from_str("c b a").unwrap().to_string(),
+        );
+    }
+
+    #[test]
+    fn scope_allows() {
+        let scope = Scope::from_str("openid urn:matrix:client:api:*").unwrap();
+        assert!(scope.allows("openid"));
+        assert!(scope.allows("urn:matrix:client:api:*"));
+        assert!(scope.allows("urn:matrix:client:api:guest"));
+        assert!(!scope.allows("urn:matrix:client:device:ABCDEF"));
+        assert!(!scope.allows("profile"));
+
+        let scope = Scope::from_str("openid profile").unwrap();
+        assert!(!scope.allows("urn:matrix:client:api:*"));
+    }
+
+    #[test]
+    fn scope_round_trips() {
+        let scopes = [
+            "openid",
+            "openid profile address",
+            "address openid profile",
+            "openid openid",
+            "urn:matrix:client:api:* urn:matrix:client:device:ABCDEF",
+            "http://example.com/scope?a=b&c=d openid",
+            "[weird]~ !#$%&'()*+,-./ ^_`{|}",
+        ];
+
+        for s in scopes {
+            let scope = Scope::from_str(s).unwrap();
+
+            // Display and FromStr agree
+            let displayed = scope.to_string();
+            assert_eq!(Scope::from_str(&displayed).unwrap(), scope, "{}", s);
+
+            // The displayed form is already normalized
+            assert_eq!(
+                Scope::from_str(&displayed).unwrap().to_string(),
+                displayed,
+                "{}",
+                s
+            );
+
+            // Every token of the original string is kept
+            for token in s.split(' ') {
+                assert!(scope.contains(token), "{} in {}", token, s);
+            }
+
+            // Serde uses the same representation
+            let json = serde_json::to_string(&scope).unwrap();
+            assert_eq!(json, serde_json::to_string(&displayed).unwrap());
+            let deserialized: Scope = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized, scope, "{}", s);
+        }
+    }
+
+    #[test]
+    fn invalid_scope_fails_to_deserialize() {
+        assert!(serde_json::from_str::<Scope>(r#""""#).is_err());
+        assert!(serde_json::from_str::<Scope>(r#""openid  profile""#).is_err());
+        assert!(serde_json::from_str::<Scope>(r#""invalid\\scope""#).is_err());
+    }
 }
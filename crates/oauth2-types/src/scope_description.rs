@@ -0,0 +1,277 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Human-readable descriptions of scope tokens, shown to users when they are
+//! asked to consent to a grant
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use language_tags::LanguageTag;
+use serde::{Deserialize, Serialize};
+
+use crate::scope::Scope;
+
+/// The language of the descriptions used when none of the languages of the
+/// user is available
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Describes the scope tokens matching a pattern, in one or more languages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScopeDescriptionRule {
+    /// Either a whole scope token, or a prefix followed by `*`, which matches
+    /// all the tokens starting with that prefix
+    pub pattern: String,
+
+    /// The description, keyed by language tag
+    pub descriptions: BTreeMap<String, String>,
+}
+
+impl ScopeDescriptionRule {
+    /// Create a rule with an English description
+    #[must_use]
+    pub fn new(pattern: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            descriptions: BTreeMap::new(),
+        }
+        .with_description(DEFAULT_LANGUAGE, description)
+    }
+
+    /// Add a description in another language
+    #[must_use]
+    pub fn with_description(
+        mut self,
+        language: impl Into<String>,
+        description: impl Into<String>,
+    ) -> Self {
+        self.descriptions
+            .insert(language.into(), description.into());
+        self
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => token.starts_with(prefix),
+            None => token == self.pattern,
+        }
+    }
+
+    fn description(&self, languages: &[LanguageTag]) -> Option<&str> {
+        let find = |language: &str| {
+            self.descriptions
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(language))
+                .map(|(_, description)| description.as_str())
+        };
+
+        // Look for `fr-CA`, then `fr`, before moving to the next language
+        languages
+            .iter()
+            .find_map(|tag| find(tag.as_str()).or_else(|| find(tag.primary_language())))
+            .or_else(|| find(DEFAULT_LANGUAGE))
+            .or_else(|| self.descriptions.values().next().map(String::as_str))
+    }
+}
+
+/// Maps scope tokens to human-readable descriptions.
+///
+/// The first rule matching a token is used, and tokens without any matching
+/// rule are shown as-is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeDescriptions {
+    rules: Vec<ScopeDescriptionRule>,
+}
+
+impl Default for ScopeDescriptions {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl ScopeDescriptions {
+    /// The descriptions of the scope tokens this service knows about
+    #[must_use]
+    pub fn builtin() -> Self {
+        Self {
+            rules: vec![
+                ScopeDescriptionRule::new("openid", "See your profile info"),
+                ScopeDescriptionRule::new("profile", "See your username"),
+                ScopeDescriptionRule::new("email", "See your email address"),
+                ScopeDescriptionRule::new(
+                    "urn:matrix:client:api:*",
+                    "View your existing messages and data, and send new messages on your behalf",
+                ),
+                ScopeDescriptionRule::new(
+                    "urn:matrix:device:*",
+                    "Log in a new device to your Matrix account",
+                ),
+            ],
+        }
+    }
+
+    /// Add rules, which take precedence over the existing ones
+    #[must_use]
+    pub fn with_rules(mut self, rules: impl IntoIterator<Item = ScopeDescriptionRule>) -> Self {
+        let mut rules: Vec<_> = rules.into_iter().collect();
+        rules.append(&mut self.rules);
+        self.rules = rules;
+        self
+    }
+
+    /// Describe a scope token, in the first of the given languages for which
+    /// there is a description
+    #[must_use]
+    pub fn describe<'a>(&'a self, token: &'a str, languages: &[LanguageTag]) -> Cow<'a, str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(token))
+            .and_then(|rule| rule.description(languages))
+            .map_or(Cow::Borrowed(token), Cow::Borrowed)
+    }
+
+    /// Describe all the tokens of a scope. Tokens with the same description
+    /// are only described once
+    #[must_use]
+    pub fn describe_scope(&self, scope: &Scope, languages: &[LanguageTag]) -> Vec<String> {
+        let mut descriptions: Vec<String> = Vec::with_capacity(scope.len());
+        for token in scope.iter() {
+            let description = self.describe(token, languages);
+            if !descriptions.iter().any(|d| d == description.as_ref()) {
+                descriptions.push(description.into_owned());
+            }
+        }
+        descriptions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages(tags: &[&str]) -> Vec<LanguageTag> {
+        tags.iter().map(|tag| tag.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn known_scopes_are_described() {
+        let descriptions = ScopeDescriptions::builtin();
+        assert_eq!(
+            descriptions.describe("openid", &[]),
+            "See your profile info"
+        );
+        assert_eq!(
+            descriptions.describe("email", &languages(&["en-GB"])),
+            "See your email address"
+        );
+        assert_eq!(
+            descriptions.describe("urn:matrix:device:ABCDEF", &[]),
+            "Log in a new device to your Matrix account"
+        );
+        assert_eq!(
+            descriptions.describe("urn:matrix:client:api:*", &[]),
+            "View your existing messages and data, and send new messages on your behalf"
+        );
+    }
+
+    #[test]
+    fn unknown_scopes_fall_back_to_the_token() {
+        let descriptions = ScopeDescriptions::builtin();
+        assert_eq!(
+            descriptions.describe("https://example.com/scope", &[]),
+            "https://example.com/scope"
+        );
+        // Prefix patterns only match with the `*`
+        assert_eq!(descriptions.describe("openid2", &[]), "openid2");
+
+        // A rule without any description also falls back to the token
+        let descriptions = ScopeDescriptions::builtin().with_rules([ScopeDescriptionRule {
+            pattern: "custom".to_owned(),
+            descriptions: BTreeMap::new(),
+        }]);
+        assert_eq!(descriptions.describe("custom", &[]), "custom");
+    }
+
+    #[test]
+    fn descriptions_are_localized() {
+        let descriptions = ScopeDescriptions::builtin().with_rules([ScopeDescriptionRule::new(
+            "email",
+            "See your email address",
+        )
+        .with_description("fr", "Voir votre adresse e-mail")
+        .with_description("fr-CA", "Voir votre adresse courriel")]);
+
+        assert_eq!(
+            descriptions.describe("email", &languages(&["fr-CA"])),
+            "Voir votre adresse courriel"
+        );
+        assert_eq!(
+            descriptions.describe("email", &languages(&["fr-BE"])),
+            "Voir votre adresse e-mail"
+        );
+        assert_eq!(
+            descriptions.describe("email", &languages(&["de", "fr"])),
+            "Voir votre adresse e-mail"
+        );
+        assert_eq!(
+            descriptions.describe("email", &languages(&["de"])),
+            "See your email address"
+        );
+
+        // Without a description in English, any description is better than none
+        let descriptions = ScopeDescriptions::builtin().with_rules([ScopeDescriptionRule {
+            pattern: "custom".to_owned(),
+            descriptions: [("de".to_owned(), "Etwas".to_owned())].into(),
+        }]);
+        assert_eq!(descriptions.describe("custom", &[]), "Etwas");
+    }
+
+    #[test]
+    fn operator_rules_take_precedence() {
+        let descriptions = ScopeDescriptions::builtin().with_rules([
+            ScopeDescriptionRule::new("urn:example:*", "Use the example API"),
+            ScopeDescriptionRule::new("openid", "Know who you are"),
+        ]);
+
+        assert_eq!(descriptions.describe("openid", &[]), "Know who you are");
+        assert_eq!(
+            descriptions.describe("urn:example:read", &[]),
+            "Use the example API"
+        );
+        assert_eq!(
+            descriptions.describe("email", &[]),
+            "See your email address"
+        );
+    }
+
+    #[test]
+    fn describe_whole_scope() {
+        let descriptions = ScopeDescriptions::builtin().with_rules([ScopeDescriptionRule::new(
+            "profile",
+            "See your profile info",
+        )]);
+        let scope: Scope = "openid profile unknown urn:matrix:device:ABCDEF"
+            .parse()
+            .unwrap();
+
+        // Scopes are sorted, and duplicate descriptions are merged
+        assert_eq!(
+            descriptions.describe_scope(&scope, &[]),
+            vec![
+                "See your profile info".to_owned(),
+                "unknown".to_owned(),
+                "Log in a new device to your Matrix account".to_owned(),
+            ]
+        );
+    }
+}
@@ -28,6 +28,9 @@ pub enum PostAuthAction {
         data: i64,
     },
     ChangePassword,
+    ManageEmails,
+    ExportAccount,
+    DeleteAccount,
 }
 
 impl PostAuthAction {
@@ -47,6 +50,9 @@ impl PostAuthAction {
             Self::ContinueAuthorizationGrant { data } => ContinueAuthorizationGrant(*data).go(),
             Self::ContinueCompatSsoLogin { data } => CompatLoginSsoComplete(*data).go(),
             Self::ChangePassword => AccountPassword.go(),
+            Self::ManageEmails => AccountEmails.go(),
+            Self::ExportAccount => AccountExport.go(),
+            Self::DeleteAccount => AccountDelete.go(),
         }
     }
 }
@@ -74,6 +80,14 @@ impl SimpleRoute for ChangePasswordDiscovery {
     const PATH: &'static str = "/.well-known/change-password";
 }
 
+/// `GET /.well-known/jwks.json`
+#[derive(Default, Debug, Clone)]
+pub struct WellKnownJwks;
+
+impl SimpleRoute for WellKnownJwks {
+    const PATH: &'static str = "/.well-known/jwks.json";
+}
+
 /// `GET /oauth2/keys.json`
 #[derive(Default, Debug, Clone)]
 pub struct OAuth2Keys;
@@ -98,6 +112,14 @@ impl SimpleRoute for OAuth2Introspection {
     const PATH: &'static str = "/oauth2/introspect";
 }
 
+/// `POST /oauth2/revoke`
+#[derive(Default, Debug, Clone)]
+pub struct OAuth2Revocation;
+
+impl SimpleRoute for OAuth2Revocation {
+    const PATH: &'static str = "/oauth2/revoke";
+}
+
 /// `POST /oauth2/token`
 #[derive(Default, Debug, Clone)]
 pub struct OAuth2TokenEndpoint;
@@ -106,12 +128,12 @@ impl SimpleRoute for OAuth2TokenEndpoint {
     const PATH: &'static str = "/oauth2/token";
 }
 
-/// `POST /oauth2/registration`
+/// `POST /oauth2/register`
 #[derive(Default, Debug, Clone)]
 pub struct OAuth2RegistrationEndpoint;
 
 impl SimpleRoute for OAuth2RegistrationEndpoint {
-    const PATH: &'static str = "/oauth2/registration";
+    const PATH: &'static str = "/oauth2/register";
 }
 
 /// `GET /authorize`
@@ -138,6 +160,14 @@ impl SimpleRoute for Healthcheck {
     const PATH: &'static str = "/health";
 }
 
+/// `GET /ready`
+#[derive(Default, Debug, Clone)]
+pub struct Readiness;
+
+impl SimpleRoute for Readiness {
+    const PATH: &'static str = "/ready";
+}
+
 /// `GET|POST /login`
 #[derive(Default, Debug, Clone)]
 pub struct Login {
@@ -199,6 +229,14 @@ impl From<Option<PostAuthAction>> for Login {
     }
 }
 
+/// `GET|POST /login/webauthn`
+#[derive(Default, Debug, Clone)]
+pub struct WebauthnLogin;
+
+impl SimpleRoute for WebauthnLogin {
+    const PATH: &'static str = "/login/webauthn";
+}
+
 /// `POST /logout`
 #[derive(Default, Debug, Clone)]
 pub struct Logout;
@@ -400,6 +438,69 @@ impl SimpleRoute for AccountPassword {
     const PATH: &'static str = "/account/password";
 }
 
+/// `GET|POST /account/password/reset`
+#[derive(Default, Debug, Clone)]
+pub struct AccountPasswordReset;
+
+impl SimpleRoute for AccountPasswordReset {
+    const PATH: &'static str = "/account/password/reset";
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PasswordResetToken {
+    pub token: String,
+}
+
+/// `GET|POST /account/password/reset/complete`
+#[derive(Debug, Clone)]
+pub struct AccountPasswordResetComplete {
+    token: PasswordResetToken,
+}
+
+impl AccountPasswordResetComplete {
+    #[must_use]
+    pub fn new(token: String) -> Self {
+        Self {
+            token: PasswordResetToken { token },
+        }
+    }
+}
+
+impl Route for AccountPasswordResetComplete {
+    type Query = PasswordResetToken;
+    fn route() -> &'static str {
+        "/account/password/reset/complete"
+    }
+
+    fn query(&self) -> Option<&Self::Query> {
+        Some(&self.token)
+    }
+}
+
+/// `GET|POST /account/sessions`
+#[derive(Default, Debug, Clone)]
+pub struct AccountSessions;
+
+impl SimpleRoute for AccountSessions {
+    const PATH: &'static str = "/account/sessions";
+}
+
+/// `GET|POST /account/totp`
+#[derive(Default, Debug, Clone)]
+pub struct AccountTotp;
+
+impl SimpleRoute for AccountTotp {
+    const PATH: &'static str = "/account/totp";
+}
+
+/// `GET|POST /account/webauthn/register`
+#[derive(Default, Debug, Clone)]
+pub struct AccountWebauthnRegister;
+
+impl SimpleRoute for AccountWebauthnRegister {
+    const PATH: &'static str = "/account/webauthn/register";
+}
+
 /// `GET|POST /account/emails`
 #[derive(Default, Debug, Clone)]
 pub struct AccountEmails;
@@ -408,6 +509,30 @@ impl SimpleRoute for AccountEmails {
     const PATH: &'static str = "/account/emails";
 }
 
+/// `GET|POST /account/upstream`
+#[derive(Default, Debug, Clone)]
+pub struct AccountUpstream;
+
+impl SimpleRoute for AccountUpstream {
+    const PATH: &'static str = "/account/upstream";
+}
+
+/// `GET /account/export`
+#[derive(Default, Debug, Clone)]
+pub struct AccountExport;
+
+impl SimpleRoute for AccountExport {
+    const PATH: &'static str = "/account/export";
+}
+
+/// `GET|POST /account/delete`
+#[derive(Default, Debug, Clone)]
+pub struct AccountDelete;
+
+impl SimpleRoute for AccountDelete {
+    const PATH: &'static str = "/account/delete";
+}
+
 /// `GET /authorize/:grant_id`
 #[derive(Debug, Clone)]
 pub struct ContinueAuthorizationGrant(pub i64);
@@ -459,6 +584,13 @@ impl SimpleRoute for CompatRefresh {
     const PATH: &'static str = "/_matrix/client/:version/refresh";
 }
 
+/// `GET /_matrix/client/v3/account/whoami`
+pub struct CompatWhoami;
+
+impl SimpleRoute for CompatWhoami {
+    const PATH: &'static str = "/_matrix/client/:version/account/whoami";
+}
+
 /// `POST /_matrix/client/v3/login/sso/redirect`
 pub struct CompatLoginSsoRedirect;
 
@@ -486,3 +618,75 @@ impl Route for CompatLoginSsoComplete {
         format!("/complete-compat-sso/{}", self.0).into()
     }
 }
+
+/// `GET /upstream/callback/:provider`
+pub struct UpstreamOAuth2Callback(pub String);
+
+impl Route for UpstreamOAuth2Callback {
+    type Query = ();
+    fn route() -> &'static str {
+        "/upstream/callback/:provider"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/upstream/callback/{}", self.0).into()
+    }
+}
+
+/// `GET /admin/users`
+#[derive(Default, Debug, Clone)]
+pub struct AdminUsers;
+
+impl SimpleRoute for AdminUsers {
+    const PATH: &'static str = "/admin/users";
+}
+
+/// `POST /admin/users/:sub/deactivate`
+pub struct AdminDeactivateUser(pub String);
+
+impl Route for AdminDeactivateUser {
+    type Query = ();
+    fn route() -> &'static str {
+        "/admin/users/:sub/deactivate"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/admin/users/{}/deactivate", self.0).into()
+    }
+}
+
+/// `POST /admin/users/:sub/reactivate`
+pub struct AdminReactivateUser(pub String);
+
+impl Route for AdminReactivateUser {
+    type Query = ();
+    fn route() -> &'static str {
+        "/admin/users/:sub/reactivate"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/admin/users/{}/reactivate", self.0).into()
+    }
+}
+
+/// `GET /admin/features`
+#[derive(Default, Debug, Clone)]
+pub struct AdminFeatures;
+
+impl SimpleRoute for AdminFeatures {
+    const PATH: &'static str = "/admin/features";
+}
+
+/// `PUT /admin/features/:feature`
+pub struct AdminFeature(pub String);
+
+impl Route for AdminFeature {
+    type Query = ();
+    fn route() -> &'static str {
+        "/admin/features/:feature"
+    }
+
+    fn path(&self) -> std::borrow::Cow<'static, str> {
+        format!("/admin/features/{}", self.0).into()
+    }
+}
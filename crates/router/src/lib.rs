@@ -49,5 +49,11 @@ mod tests {
             OidcConfiguration.absolute_url(&base).as_str(),
             "https://example.com/.well-known/openid-configuration"
         );
+        assert_eq!(
+            UrlBuilder::new(base)
+                .password_reset_link("abc123".to_owned())
+                .as_str(),
+            "https://example.com/account/password/reset/complete?token=abc123"
+        );
     }
 }
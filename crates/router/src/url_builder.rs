@@ -14,13 +14,35 @@
 
 //! Utility to build URLs
 
+use axum::http::{uri::Authority, HeaderMap};
 use url::Url;
 
 use crate::traits::Route;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct UrlBuilder {
+    /// Base of the links shown to the client, from the forwarded headers if
+    /// they are trusted
     base: Url,
+
+    /// The configured public base, for the URLs which can't depend on the
+    /// request, like the issuer and the links sent by email
+    public_base: Url,
+
+    trust_forwarded_headers: bool,
+}
+
+/// The last value of a header set by a reverse proxy. Proxies append their
+/// value to the ones they got, so only the last one can't be chosen by the
+/// client
+fn forwarded_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let last = value.rsplit(',').next()?.trim();
+    if last.is_empty() {
+        None
+    } else {
+        Some(last)
+    }
 }
 
 impl UrlBuilder {
@@ -41,13 +63,68 @@ impl UrlBuilder {
     /// Create a new [`UrlBuilder`] from a base URL
     #[must_use]
     pub fn new(base: Url) -> Self {
-        Self { base }
+        Self {
+            public_base: base.clone(),
+            base,
+            trust_forwarded_headers: false,
+        }
+    }
+
+    /// Trust the `X-Forwarded-Proto` and `X-Forwarded-Host` headers of the
+    /// requests, when running behind a reverse proxy which sets them
+    #[must_use]
+    pub fn with_trusted_forwarded_headers(mut self, trust: bool) -> Self {
+        self.trust_forwarded_headers = trust;
+        self
+    }
+
+    /// The [`UrlBuilder`] to use for a request.
+    ///
+    /// If the forwarded headers are trusted, URLs use the scheme and host the
+    /// client used to reach the reverse proxy. Otherwise, or if those headers
+    /// are missing or invalid, they use the configured base URL. The issuer
+    /// and the links sent by email always use the configured base URL.
+    #[must_use]
+    pub fn for_request(&self, headers: &HeaderMap) -> Self {
+        if !self.trust_forwarded_headers {
+            return self.clone();
+        }
+
+        let mut base = self.base.clone();
+
+        if let Some(proto) = forwarded_header(headers, "x-forwarded-proto") {
+            let proto = proto.to_ascii_lowercase();
+            if proto == "http" || proto == "https" {
+                // This can't fail when switching between those two schemes
+                let _ = base.set_scheme(&proto);
+            }
+        }
+
+        // `Authority` handles IPv6 addresses like `[::1]:8080`, and doesn't
+        // accept paths
+        let authority = forwarded_header(headers, "x-forwarded-host")
+            .and_then(|host| host.parse::<Authority>().ok())
+            .filter(|authority| !authority.as_str().contains('@'));
+        if let Some(authority) = authority {
+            let mut with_host = base.clone();
+            if with_host.set_host(Some(authority.host())).is_ok()
+                && with_host.set_port(authority.port_u16()).is_ok()
+            {
+                base = with_host;
+            }
+        }
+
+        Self {
+            base,
+            public_base: self.public_base.clone(),
+            trust_forwarded_headers: true,
+        }
     }
 
     /// OIDC issuer
     #[must_use]
     pub fn oidc_issuer(&self) -> Url {
-        self.base.clone()
+        self.public_base.clone()
     }
 
     /// OIDC dicovery document URL
@@ -74,6 +151,12 @@ impl UrlBuilder {
         self.url_for(&crate::endpoints::OAuth2Introspection)
     }
 
+    /// OAuth 2.0 revocation endpoint
+    #[must_use]
+    pub fn oauth_revocation_endpoint(&self) -> Url {
+        self.url_for(&crate::endpoints::OAuth2Revocation)
+    }
+
     /// OAuth 2.0 client registration endpoint
     #[must_use]
     pub fn oauth_registration_endpoint(&self) -> Url {
@@ -91,4 +174,138 @@ impl UrlBuilder {
     pub fn jwks_uri(&self) -> Url {
         self.url_for(&crate::endpoints::OAuth2Keys)
     }
+
+    /// Link sent by email to complete a password reset
+    #[must_use]
+    pub fn password_reset_link(&self, token: String) -> Url {
+        crate::endpoints::AccountPasswordResetComplete::new(token).absolute_url(&self.public_base)
+    }
+
+    /// Where an upstream provider sends the users back after they logged in
+    #[must_use]
+    pub fn upstream_oauth2_callback(&self, provider: &str) -> Url {
+        self.url_for(&crate::endpoints::UpstreamOAuth2Callback(
+            provider.to_owned(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn builder() -> UrlBuilder {
+        UrlBuilder::new("https://auth.example.com/".parse().unwrap())
+    }
+
+    #[test]
+    fn links_use_configured_base() {
+        let forwarded = headers(&[
+            ("x-forwarded-proto", "http"),
+            ("x-forwarded-host", "evil.example.com"),
+        ]);
+
+        // The forwarded headers are ignored unless trusted
+        let url_builder = builder().for_request(&forwarded);
+        assert_eq!(
+            url_builder
+                .password_reset_link("abc123".to_owned())
+                .as_str(),
+            "https://auth.example.com/account/password/reset/complete?token=abc123"
+        );
+        assert_eq!(
+            url_builder.oidc_issuer().as_str(),
+            "https://auth.example.com/"
+        );
+
+        let url_builder =
+            UrlBuilder::new("http://[::1]:8080/".parse().unwrap()).for_request(&forwarded);
+        assert_eq!(
+            url_builder
+                .password_reset_link("abc123".to_owned())
+                .as_str(),
+            "http://[::1]:8080/account/password/reset/complete?token=abc123"
+        );
+    }
+
+    #[test]
+    fn trusted_forwarded_headers() {
+        let url_builder = UrlBuilder::new("http://localhost:8080/".parse().unwrap())
+            .with_trusted_forwarded_headers(true);
+
+        let forwarded = headers(&[
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "auth.example.com"),
+        ]);
+        let request_builder = url_builder.for_request(&forwarded);
+        assert_eq!(
+            request_builder.oauth_token_endpoint().as_str(),
+            "https://auth.example.com/oauth2/token"
+        );
+
+        // The issuer and the links sent by email don't depend on the request
+        assert_eq!(
+            request_builder.oidc_issuer().as_str(),
+            "http://localhost:8080/"
+        );
+        assert_eq!(
+            request_builder
+                .password_reset_link("abc123".to_owned())
+                .as_str(),
+            "http://localhost:8080/account/password/reset/complete?token=abc123"
+        );
+
+        // IPv6 addresses, ports and lists of proxies, of which only the last
+        // value is used
+        let forwarded = headers(&[
+            ("x-forwarded-proto", "http, HTTPS"),
+            ("x-forwarded-host", "evil.example.com, [2001:db8::1]:8443"),
+        ]);
+        assert_eq!(
+            url_builder.for_request(&forwarded).jwks_uri().as_str(),
+            "https://[2001:db8::1]:8443/oauth2/keys.json"
+        );
+
+        // Without the headers, the configured base is used
+        assert_eq!(
+            url_builder
+                .for_request(&HeaderMap::new())
+                .jwks_uri()
+                .as_str(),
+            "http://localhost:8080/oauth2/keys.json"
+        );
+    }
+
+    #[test]
+    fn invalid_forwarded_headers_are_ignored() {
+        let url_builder = builder().with_trusted_forwarded_headers(true);
+
+        for (name, value) in [
+            ("x-forwarded-proto", "javascript"),
+            ("x-forwarded-host", "evil.example.com/path"),
+            ("x-forwarded-host", "user@evil.example.com"),
+            ("x-forwarded-host", "::1"),
+            ("x-forwarded-host", ""),
+        ] {
+            assert_eq!(
+                url_builder
+                    .for_request(&headers(&[(name, value)]))
+                    .jwks_uri()
+                    .as_str(),
+                "https://auth.example.com/oauth2/keys.json",
+                "{}: {}",
+                name,
+                value
+            );
+        }
+    }
 }
@@ -0,0 +1,29 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Existing addresses only get their domain lowercased
+ALTER TABLE user_emails
+  ADD COLUMN "normalized_email" TEXT;
+
+UPDATE user_emails
+  SET normalized_email = COALESCE(
+    substring(email from '^(.*)@[^@]*$') || '@' || lower(substring(email from '@([^@]*)$')),
+    email
+  );
+
+ALTER TABLE user_emails
+  ALTER COLUMN "normalized_email" SET NOT NULL;
+
+CREATE INDEX user_emails_normalized_email_idx
+  ON user_emails (normalized_email);
@@ -0,0 +1,45 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Tell the instances caching the clients which one changed, by its client_id.
+-- An empty payload means that all of them changed
+CREATE OR REPLACE FUNCTION trigger_notify_oauth2_client()
+RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'TRUNCATE' THEN
+    PERFORM pg_notify('oauth2_clients', '');
+    RETURN NULL;
+  END IF;
+
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    PERFORM pg_notify('oauth2_clients', OLD.client_id);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    PERFORM pg_notify('oauth2_clients', NEW.client_id);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_oauth2_client
+  AFTER INSERT OR UPDATE OR DELETE ON oauth2_clients
+  FOR EACH ROW
+  EXECUTE PROCEDURE trigger_notify_oauth2_client();
+
+CREATE TRIGGER notify_oauth2_clients_truncated
+  AFTER TRUNCATE ON oauth2_clients
+  FOR EACH STATEMENT
+  EXECUTE PROCEDURE trigger_notify_oauth2_client();
@@ -0,0 +1,28 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Users logging in through an upstream OpenID Connect provider, by their
+-- subject on that provider
+CREATE TABLE upstream_oauth_links (
+  "id" BIGSERIAL PRIMARY KEY,
+  "provider" TEXT NOT NULL,
+  "subject" TEXT NOT NULL,
+  "user_id" BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+  UNIQUE ("provider", "subject")
+);
+
+CREATE INDEX upstream_oauth_links_user_id_idx
+  ON upstream_oauth_links ("user_id");
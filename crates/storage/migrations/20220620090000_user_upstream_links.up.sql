@@ -0,0 +1,24 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- The links can now also be added by the users themselves, to log in to an
+-- existing account through an upstream provider
+ALTER TABLE upstream_oauth_links
+  RENAME TO user_upstream_links;
+
+ALTER SEQUENCE upstream_oauth_links_id_seq
+  RENAME TO user_upstream_links_id_seq;
+
+ALTER INDEX upstream_oauth_links_user_id_idx
+  RENAME TO user_upstream_links_user_id_idx;
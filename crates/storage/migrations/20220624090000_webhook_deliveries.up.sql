@@ -0,0 +1,35 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- Events sent to the webhook endpoints, kept as a log of their deliveries.
+-- Each event is delivered at least once to each endpoint, until it succeeds or
+-- the attempts run out
+CREATE TABLE webhook_deliveries (
+  "id" BIGSERIAL PRIMARY KEY,
+  "endpoint" TEXT NOT NULL,
+  "event" TEXT NOT NULL,
+  "payload" TEXT NOT NULL,
+  "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+  "attempts" INTEGER NOT NULL DEFAULT 0,
+  "last_error" TEXT,
+
+  -- NULL once the event was delivered, or given up on
+  "next_attempt_at" TIMESTAMP WITH TIME ZONE DEFAULT now(),
+  "delivered_at" TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX webhook_deliveries_next_attempt_at_idx
+  ON webhook_deliveries ("next_attempt_at")
+  WHERE "next_attempt_at" IS NOT NULL;
@@ -0,0 +1,26 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- The secrets are now encrypted by the server, which can't be done here. The
+-- ones stored until now are flagged, and get encrypted when the server starts
+ALTER TABLE user_totp
+  RENAME COLUMN "secret" TO "encrypted_secret";
+ALTER TABLE user_totp
+  ADD COLUMN "plaintext" BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE user_totp
+  ALTER COLUMN "plaintext" SET DEFAULT FALSE;
+
+-- The time step of the last code accepted, so that it can't be used again
+ALTER TABLE user_totp
+  ADD COLUMN "last_used_step" BIGINT;
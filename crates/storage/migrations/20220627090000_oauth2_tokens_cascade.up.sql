@@ -0,0 +1,24 @@
+-- Copyright 2022 The Matrix.org Foundation C.I.C.
+--
+-- Licensed under the Apache License, Version 2.0 (the "License");
+-- you may not use this file except in compliance with the License.
+-- You may obtain a copy of the License at
+--
+--     http://www.apache.org/licenses/LICENSE-2.0
+--
+-- Unless required by applicable law or agreed to in writing, software
+-- distributed under the License is distributed on an "AS IS" BASIS,
+-- WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+-- See the License for the specific language governing permissions and
+-- limitations under the License.
+
+-- The tokens go away with their session, like the compat ones, so that
+-- deleting a user deletes their OAuth 2.0 sessions and tokens too
+ALTER TABLE oauth2_access_tokens
+  DROP CONSTRAINT oauth2_access_tokens_oauth2_session_id_fkey,
+  ADD CONSTRAINT oauth2_access_tokens_oauth2_session_id_fkey
+    FOREIGN KEY (oauth2_session_id) REFERENCES oauth2_sessions (id) ON DELETE CASCADE;
+ALTER TABLE oauth2_refresh_tokens
+  DROP CONSTRAINT oauth2_refresh_tokens_oauth2_session_id_fkey,
+  ADD CONSTRAINT oauth2_refresh_tokens_oauth2_session_id_fkey
+    FOREIGN KEY (oauth2_session_id) REFERENCES oauth2_sessions (id) ON DELETE CASCADE;
@@ -17,7 +17,7 @@ use argon2::{Argon2, PasswordHash};
 use chrono::{DateTime, Duration, Utc};
 use mas_data_model::{
     CompatAccessToken, CompatRefreshToken, CompatSession, CompatSsoLogin, CompatSsoLoginState,
-    Device, User, UserEmail,
+    Device, LoginAttempts, TotpSecret, User, UserEmail, UserTotp,
 };
 use sqlx::{postgres::types::PgInterval, Acquire, PgExecutor, Postgres};
 use thiserror::Error;
@@ -26,7 +26,11 @@ use tracing::{info_span, Instrument};
 use url::Url;
 
 use crate::{
-    user::lookup_user_by_username, DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend,
+    user::{
+        get_login_attempts, lookup_user_by_username, lookup_user_totp, record_user_totp_step,
+        set_login_attempts,
+    },
+    DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend,
 };
 
 struct CompatAccessTokenLookup {
@@ -70,6 +74,36 @@ pub async fn lookup_active_compat_access_token(
         CompatSession<PostgresqlBackend>,
     ),
     CompatAccessTokenLookupError,
+> {
+    fetch_compat_access_token(executor, token, false).await
+}
+
+/// Lookup a compat access token of an active session, even if it expired, so
+/// that the caller can tell expired tokens from unknown ones
+#[tracing::instrument(skip_all, err)]
+pub async fn lookup_compat_access_token(
+    executor: impl PgExecutor<'_>,
+    token: &str,
+) -> Result<
+    (
+        CompatAccessToken<PostgresqlBackend>,
+        CompatSession<PostgresqlBackend>,
+    ),
+    CompatAccessTokenLookupError,
+> {
+    fetch_compat_access_token(executor, token, true).await
+}
+
+async fn fetch_compat_access_token(
+    executor: impl PgExecutor<'_>,
+    token: &str,
+    include_expired: bool,
+) -> Result<
+    (
+        CompatAccessToken<PostgresqlBackend>,
+        CompatSession<PostgresqlBackend>,
+    ),
+    CompatAccessTokenLookupError,
 > {
     let res = sqlx::query_as!(
         CompatAccessTokenLookup,
@@ -99,10 +133,11 @@ pub async fn lookup_active_compat_access_token(
               ON ue.id = u.primary_email_id
 
             WHERE ct.token = $1
-              AND (ct.expires_at IS NULL OR ct.expires_at > NOW())
+              AND ($2 OR ct.expires_at IS NULL OR ct.expires_at > NOW())
             AND cs.deleted_at IS NULL
             "#,
         token,
+        include_expired,
     )
     .fetch_one(executor)
     .instrument(info_span!("Fetch compat access token"))
@@ -288,11 +323,28 @@ pub async fn lookup_active_compat_refresh_token(
     Ok((refresh_token, access_token, session))
 }
 
-#[tracing::instrument(skip(conn, password), err)]
+/// The user enabled TOTP, and the login did not come with a valid code
+#[derive(Debug, Error)]
+pub enum SecondFactorError {
+    #[error("a TOTP code is required")]
+    Required,
+
+    #[error("invalid TOTP code")]
+    Invalid,
+}
+
+/// Check the password of the user and start a compat session. Users who
+/// enabled TOTP also have to give a valid `totp_code`, otherwise this fails
+/// with a [`SecondFactorError`]. Their secret is decrypted with
+/// `decrypt_totp_secret`
+#[tracing::instrument(skip(conn, password, totp_code, decrypt_totp_secret), err)]
 pub async fn compat_login(
     conn: impl Acquire<'_, Database = Postgres>,
     username: &str,
     password: &str,
+    totp_code: Option<&str>,
+    decrypt_totp_secret: impl FnOnce(&User<PostgresqlBackend>, &UserTotp<PostgresqlBackend>) -> anyhow::Result<TotpSecret>
+        + Send,
     device: Device,
 ) -> Result<CompatSession<PostgresqlBackend>, anyhow::Error> {
     let mut txn = conn.begin().await.context("could not start transaction")?;
@@ -300,6 +352,14 @@ pub async fn compat_login(
     // First, lookup the user
     let user = lookup_user_by_username(&mut txn, username).await?;
 
+    // Don't even check the password if the account is locked
+    let attempts = get_login_attempts(&mut txn, &user).await?;
+    let now = Utc::now();
+    if attempts.is_locked(now) {
+        tracing::warn!(locked_until = ?attempts.locked_until, "Account is locked");
+        bail!("account is locked");
+    }
+
     // Now, fetch the hashed password from the user associated with that session
     let hashed_password: String = sqlx::query_scalar!(
         r#"
@@ -318,14 +378,73 @@ pub async fn compat_login(
     // TODO: pass verifiers list as parameter
     // Verify the password in a blocking thread to avoid blocking the async executor
     let password = password.to_string();
-    task::spawn_blocking(move || {
+    let verification = task::spawn_blocking(move || {
         let context = Argon2::default();
         let hasher = PasswordHash::new(&hashed_password)?;
         hasher.verify_password(&[&context], &password)
     })
     .instrument(tracing::info_span!("Verify hashed password"))
-    .await??;
+    .await?;
 
+    if let Err(e) = verification {
+        // Save the failed attempt even though the login fails
+        record_failed_login(&mut txn, &user, attempts, now).await?;
+        txn.commit().await.context("could not commit transaction")?;
+        return Err(e.into());
+    }
+
+    // The password is right, but users who enabled TOTP need a code on top
+    if let Some(totp) = lookup_user_totp(&mut txn, &user).await? {
+        if totp.is_enabled() {
+            let code = totp_code.ok_or(SecondFactorError::Required)?;
+            let secret = decrypt_totp_secret(&user, &totp)?;
+
+            // Saving the step of the code also makes sure a concurrent login
+            // did not use it in the meantime
+            let accepted = match totp.check_code(&secret, code, now) {
+                Some(step) => record_user_totp_step(&mut txn, &totp, step).await?,
+                None => false,
+            };
+
+            if !accepted {
+                // Wrong codes count towards the lockout, so they can't be guessed
+                record_failed_login(&mut txn, &user, attempts, now).await?;
+                txn.commit().await.context("could not commit transaction")?;
+                return Err(SecondFactorError::Invalid.into());
+            }
+        }
+    }
+
+    if attempts != LoginAttempts::default() {
+        set_login_attempts(&mut txn, &user, attempts.record_success()).await?;
+    }
+
+    let session = start_compat_session(&mut txn, user, device).await?;
+
+    txn.commit().await.context("could not commit transaction")?;
+    Ok(session)
+}
+
+async fn record_failed_login(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    attempts: LoginAttempts,
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let attempts = attempts.record_failure(now);
+    if attempts.is_locked(now) {
+        tracing::warn!(locked_until = ?attempts.locked_until, "Locking account");
+    }
+    set_login_attempts(executor, user, attempts).await
+}
+
+/// Start a compat session for a user, without checking any credentials
+#[tracing::instrument(skip_all, fields(user.id = user.data, %user.username, device.id = device.as_str()), err)]
+pub async fn start_compat_session(
+    executor: impl PgExecutor<'_>,
+    user: User<PostgresqlBackend>,
+    device: Device,
+) -> Result<CompatSession<PostgresqlBackend>, anyhow::Error> {
     let res = sqlx::query_as!(
         IdAndCreationTime,
         r#"
@@ -336,21 +455,18 @@ pub async fn compat_login(
         user.data,
         device.as_str(),
     )
-    .fetch_one(&mut txn)
+    .fetch_one(executor)
     .instrument(tracing::info_span!("Insert compat session"))
     .await
     .context("could not insert compat session")?;
 
-    let session = CompatSession {
+    Ok(CompatSession {
         data: res.id,
         user,
         device,
         created_at: res.created_at,
         deleted_at: None,
-    };
-
-    txn.commit().await.context("could not commit transaction")?;
-    Ok(session)
+    })
 }
 
 #[tracing::instrument(skip(executor, token), err)]
@@ -713,7 +829,7 @@ pub async fn get_compat_sso_login_by_id(
 }
 
 #[allow(clippy::too_many_lines)]
-#[tracing::instrument(skip(executor), err)]
+#[tracing::instrument(skip(executor, token), err)]
 pub async fn get_compat_sso_login_by_token(
     executor: impl PgExecutor<'_>,
     token: &str,
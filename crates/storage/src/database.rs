@@ -0,0 +1,100 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Routing of the queries between the primary database and a read replica
+
+use sqlx::PgPool;
+
+/// The connection pools to the primary database and to its optional read
+/// replica.
+///
+/// Writes, and reads which have to see them, go to the [`primary`] pool,
+/// usually through a transaction. Read-only queries which can tolerate the
+/// replication lag, like listing the emails of a user, use the [`read`] pool.
+///
+/// [`primary`]: Database::primary
+/// [`read`]: Database::read
+#[derive(Debug, Clone)]
+pub struct Database {
+    primary: PgPool,
+    replica: Option<PgPool>,
+}
+
+impl Database {
+    /// Use a single database for everything
+    #[must_use]
+    pub fn new(primary: PgPool) -> Self {
+        Self {
+            primary,
+            replica: None,
+        }
+    }
+
+    /// Send the read-only queries to a replica
+    #[must_use]
+    pub fn with_read_replica(mut self, replica: PgPool) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// The pool of the primary database, for writes and transactions
+    #[must_use]
+    pub fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// The pool for read-only queries: the replica if there is one, else the
+    /// primary
+    #[must_use]
+    pub fn read(&self) -> &PgPool {
+        self.replica.as_ref().unwrap_or(&self.primary)
+    }
+}
+
+impl From<PgPool> for Database {
+    fn from(primary: PgPool) -> Self {
+        Self::new(primary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    use super::*;
+
+    /// A pool which never connects, unless it is used
+    fn pool(host: &str) -> PgPool {
+        PgPoolOptions::new().connect_lazy_with(PgConnectOptions::new().host(host))
+    }
+
+    #[tokio::test]
+    async fn reads_go_to_the_replica() {
+        let database = Database::new(pool("primary")).with_read_replica(pool("replica"));
+
+        assert!(std::ptr::eq(
+            database.read(),
+            database.replica.as_ref().unwrap()
+        ));
+        assert!(std::ptr::eq(database.primary(), &database.primary));
+    }
+
+    #[tokio::test]
+    async fn reads_go_to_the_primary_without_replica() {
+        let database = Database::new(pool("primary"));
+
+        assert!(std::ptr::eq(database.read(), &database.primary));
+        assert!(std::ptr::eq(database.primary(), &database.primary));
+    }
+}
@@ -46,11 +46,14 @@ impl StorageBackend for PostgresqlBackend {
     type CompatRefreshTokenData = i64;
     type CompatSessionData = i64;
     type CompatSsoLoginData = i64;
+    type PasswordResetData = i64;
     type RefreshTokenData = i64;
     type SessionData = i64;
     type UserData = i64;
     type UserEmailData = i64;
     type UserEmailVerificationData = i64;
+    type UserTotpData = i64;
+    type WebauthnCredentialData = i64;
 }
 
 impl StorageBackendMarker for PostgresqlBackend {}
@@ -61,8 +64,15 @@ struct IdAndCreationTime {
 }
 
 pub mod compat;
+mod database;
 pub mod oauth2;
+pub mod schema;
+pub mod upstream_oauth2;
 pub mod user;
+pub mod webauthn;
+pub mod webhook;
+
+pub use self::database::Database;
 
 /// Embedded migrations, allowing them to run on startup
 pub static MIGRATOR: Migrator = sqlx::migrate!();
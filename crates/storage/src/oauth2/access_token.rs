@@ -14,8 +14,10 @@
 
 use anyhow::Context;
 use chrono::{DateTime, Duration, Utc};
-use mas_data_model::{AccessToken, Authentication, BrowserSession, Session, User, UserEmail};
-use sqlx::{Acquire, PgExecutor, Postgres};
+use mas_data_model::{
+    AccessToken, Authentication, BrowserSession, Client, Session, User, UserEmail,
+};
+use sqlx::{postgres::types::PgInterval, Acquire, PgExecutor, Postgres};
 use thiserror::Error;
 
 use super::client::{lookup_client, ClientFetchError};
@@ -145,6 +147,7 @@ where
               ON ue.id = u.primary_email_id
 
             WHERE at.token = $1
+              AND at.revoked_at IS NULL
               AND at.created_at + (at.expires_after * INTERVAL '1 second') >= now()
               AND us.active
               AND os.ended_at IS NULL
@@ -228,8 +231,10 @@ pub async fn revoke_access_token(
 ) -> anyhow::Result<()> {
     let res = sqlx::query!(
         r#"
-            DELETE FROM oauth2_access_tokens
+            UPDATE oauth2_access_tokens
+            SET revoked_at = NOW()
             WHERE id = $1
+              AND revoked_at IS NULL
         "#,
         access_token.data,
     )
@@ -244,12 +249,57 @@ pub async fn revoke_access_token(
     }
 }
 
-pub async fn cleanup_expired(executor: impl PgExecutor<'_>) -> anyhow::Result<u64> {
+/// Revoke an access token, if it was issued to the given client. Returns
+/// `false` if the client has no such active token
+pub async fn revoke_client_access_token(
+    executor: impl PgExecutor<'_>,
+    client: &Client<PostgresqlBackend>,
+    token: &str,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE oauth2_access_tokens at
+            SET revoked_at = NOW()
+            FROM oauth2_sessions os
+            WHERE os.id = at.oauth2_session_id
+              AND os.oauth2_client_id = $1
+              AND at.token = $2
+              AND at.revoked_at IS NULL
+        "#,
+        client.data,
+        token,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Delete at most `batch_size` access tokens which expired or were revoked
+/// more than `grace_period` ago
+///
+/// Returns how many tokens were deleted
+pub async fn cleanup_expired(
+    executor: impl PgExecutor<'_>,
+    grace_period: std::time::Duration,
+    batch_size: u32,
+) -> anyhow::Result<u64> {
+    let grace_period = PgInterval::try_from(grace_period)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
     let res = sqlx::query!(
         r#"
             DELETE FROM oauth2_access_tokens
-            WHERE created_at + (expires_after * INTERVAL '1 second') + INTERVAL '15 minutes' < now()
+            WHERE id IN (
+                SELECT id
+                FROM oauth2_access_tokens
+                WHERE created_at + (expires_after * INTERVAL '1 second') + $1 < now()
+                   OR revoked_at + $1 < now()
+                LIMIT $2
+            )
         "#,
+        grace_period,
+        i64::from(batch_size),
     )
     .execute(executor)
     .await
@@ -257,3 +307,81 @@ pub async fn cleanup_expired(executor: impl PgExecutor<'_>) -> anyhow::Result<u6
 
     Ok(res.rows_affected())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+    use crate::oauth2::insert_test_session;
+
+    /// Insert an access token created `age` seconds ago, valid for
+    /// `expires_after` seconds, and revoked `revoked` seconds ago
+    async fn insert_token(
+        conn: &mut PgConnection,
+        session_id: i64,
+        age: i32,
+        expires_after: i32,
+        revoked: Option<i32>,
+    ) -> i64 {
+        sqlx::query_scalar(
+            r#"
+                INSERT INTO oauth2_access_tokens
+                    (oauth2_session_id, token, expires_after, created_at, revoked_at)
+                VALUES
+                    ($1, md5(random()::text), $3,
+                     now() - $2 * INTERVAL '1 second',
+                     now() - $4 * INTERVAL '1 second')
+                RETURNING id
+            "#,
+        )
+        .bind(session_id)
+        .bind(f64::from(age))
+        .bind(expires_after)
+        .bind(revoked.map(f64::from))
+        .fetch_one(conn)
+        .await
+        .unwrap()
+    }
+
+    async fn remaining(conn: &mut PgConnection, session_id: i64) -> Vec<i64> {
+        sqlx::query_scalar(
+            "SELECT id FROM oauth2_access_tokens WHERE oauth2_session_id = $1 ORDER BY id",
+        )
+        .bind(session_id)
+        .fetch_all(conn)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn expired_tokens_are_deleted_after_the_grace_period() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+        let session_id = insert_test_session(&mut txn, "cleanup-access-tokens").await;
+        let grace_period = std::time::Duration::from_secs(15 * 60);
+
+        // Expired 55 minutes ago
+        insert_token(&mut txn, session_id, 3600, 300, None).await;
+        // Revoked 30 minutes ago
+        insert_token(&mut txn, session_id, 3600, 7200, Some(1800)).await;
+        // Expired 5 minutes ago, still in the grace period
+        let recently_expired = insert_token(&mut txn, session_id, 600, 300, None).await;
+        // Still valid
+        let valid = insert_token(&mut txn, session_id, 0, 300, None).await;
+
+        // The batch size bounds how many are deleted at once
+        cleanup_expired(&mut txn, grace_period, 1).await.unwrap();
+        assert_eq!(remaining(&mut txn, session_id).await.len(), 3);
+
+        cleanup_expired(&mut txn, grace_period, 1000).await.unwrap();
+        assert_eq!(
+            remaining(&mut txn, session_id).await,
+            vec![recently_expired, valid]
+        );
+
+        txn.rollback().await.unwrap();
+    }
+}
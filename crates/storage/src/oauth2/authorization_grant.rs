@@ -104,6 +104,7 @@ pub async fn new_authorization_grant(
         response_type_token,
         response_type_id_token,
         requires_consent,
+        consent_given: false,
     })
 }
 
@@ -128,6 +129,7 @@ struct GrantLookup {
     grant_code_challenge: Option<String>,
     grant_code_challenge_method: Option<String>,
     grant_requires_consent: bool,
+    grant_consent_given: bool,
     oauth2_client_id: i64,
     session_id: Option<i64>,
     user_session_id: Option<i64>,
@@ -277,10 +279,16 @@ impl GrantLookup {
             }
         };
 
+        // The code is only given to the client once the grant is fulfilled
+        let code_created_at = self.grant_fulfilled_at.unwrap_or(self.grant_created_at);
+
         let code: Option<AuthorizationCode> =
             match (self.grant_response_type_code, self.grant_code, pkce) {
                 (false, None, None) => None,
-                (true, Some(code), pkce) => Some(AuthorizationCode { code, pkce }),
+                (true, Some(code), pkce) => Some(AuthorizationCode {
+                    exchanged_at: self.grant_exchanged_at,
+                    ..AuthorizationCode::new(code, pkce, code_created_at)
+                }),
                 _ => {
                     return Err(DatabaseInconsistencyError);
                 }
@@ -321,6 +329,7 @@ impl GrantLookup {
             response_type_token: self.grant_response_type_token,
             response_type_id_token: self.grant_response_type_id_token,
             requires_consent: self.grant_requires_consent,
+            consent_given: self.grant_consent_given,
         })
     }
 }
@@ -354,6 +363,7 @@ pub async fn get_grant_by_id(
                 og.code_challenge         AS grant_code_challenge,
                 og.code_challenge_method  AS grant_code_challenge_method,
                 og.requires_consent       AS grant_requires_consent,
+                og.consent_given          AS grant_consent_given,
                 os.id              AS "session_id?",
                 us.id              AS "user_session_id?",
                 us.created_at      AS "user_session_created_at?",
@@ -423,6 +433,7 @@ pub async fn lookup_grant_by_code(
                 og.code_challenge         AS grant_code_challenge,
                 og.code_challenge_method  AS grant_code_challenge_method,
                 og.requires_consent       AS grant_requires_consent,
+                og.consent_given          AS grant_consent_given,
                 os.id              AS "session_id?",
                 us.id              AS "user_session_id?",
                 us.created_at      AS "user_session_created_at?",
@@ -534,7 +545,8 @@ pub async fn give_consent_to_grant(
         r#"
             UPDATE oauth2_authorization_grants AS og
             SET
-                requires_consent = 'f'
+                requires_consent = 'f',
+                consent_given = 't'
             WHERE
                 og.id = $1
         "#,
@@ -544,14 +556,18 @@ pub async fn give_consent_to_grant(
     .await?;
 
     grant.requires_consent = false;
+    grant.consent_given = true;
 
     Ok(grant)
 }
 
+/// Mark the grant as exchanged. Returns `None` if it was already exchanged,
+/// for example by a concurrent request with the same code, in which case the
+/// code must be rejected
 pub async fn exchange_grant(
     executor: impl PgExecutor<'_>,
     mut grant: AuthorizationGrant<PostgresqlBackend>,
-) -> anyhow::Result<AuthorizationGrant<PostgresqlBackend>> {
+) -> anyhow::Result<Option<AuthorizationGrant<PostgresqlBackend>>> {
     let exchanged_at = sqlx::query_scalar!(
         r#"
             UPDATE oauth2_authorization_grants
@@ -559,15 +575,76 @@ pub async fn exchange_grant(
                 exchanged_at = NOW()
             WHERE
                 id = $1
+                AND exchanged_at IS NULL
             RETURNING exchanged_at AS "exchanged_at!: DateTime<Utc>"
         "#,
         grant.data,
     )
-    .fetch_one(executor)
+    .fetch_optional(executor)
     .await
     .context("could not mark grant as exchanged")?;
 
+    let exchanged_at = match exchanged_at {
+        Some(exchanged_at) => exchanged_at,
+        None => return Ok(None),
+    };
+
     grant.stage = grant.stage.exchange(exchanged_at)?;
+    if let Some(code) = grant.code.as_mut() {
+        code.exchanged_at = Some(exchanged_at);
+    }
 
-    Ok(grant)
+    Ok(Some(grant))
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+    use crate::oauth2::insert_test_session;
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn codes_are_only_exchanged_once() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        let session_id = insert_test_session(&mut txn, "exchange-grant").await;
+        sqlx::query(
+            r#"
+                INSERT INTO oauth2_authorization_grants
+                    (oauth2_client_id, oauth2_session_id, redirect_uri, scope,
+                     response_mode, response_type_code, response_type_token,
+                     response_type_id_token, code, fulfilled_at)
+                SELECT oauth2_client_id, id, 'https://example.com/callback', scope,
+                       'Query', TRUE, FALSE, FALSE, 'exchange-grant-code', NOW()
+                FROM oauth2_sessions
+                WHERE id = $1
+            "#,
+        )
+        .bind(session_id)
+        .execute(&mut txn)
+        .await
+        .unwrap();
+
+        // Two requests with the same code both find the grant before either
+        // exchanges it
+        let first = lookup_grant_by_code(&mut txn, "exchange-grant-code")
+            .await
+            .unwrap();
+        let second = lookup_grant_by_code(&mut txn, "exchange-grant-code")
+            .await
+            .unwrap();
+
+        let exchanged = exchange_grant(&mut txn, first).await.unwrap().unwrap();
+        assert!(matches!(
+            exchanged.stage,
+            AuthorizationGrantStage::Exchanged { .. }
+        ));
+        assert!(exchange_grant(&mut txn, second).await.unwrap().is_none());
+
+        txn.rollback().await.unwrap();
+    }
 }
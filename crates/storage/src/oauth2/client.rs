@@ -14,7 +14,7 @@
 
 use std::string::ToString;
 
-use mas_data_model::{Client, JwksOrJwksUri};
+use mas_data_model::{Client, ClientType, JwksOrJwksUri};
 use mas_iana::{
     jose::JsonWebSignatureAlg,
     oauth::{OAuthAuthorizationEndpointResponseType, OAuthClientAuthenticationMethod},
@@ -209,9 +209,12 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
             _ => return Err(ClientFetchError::BothJwksAndJwksUri),
         };
 
+        let client_type = ClientType::from_auth_method(token_endpoint_auth_method.as_ref());
+
         Ok(Client {
             data: self.id,
             client_id: self.client_id,
+            client_type,
             encrypted_client_secret: self.encrypted_client_secret,
             redirect_uris,
             response_types,
@@ -232,6 +235,11 @@ impl TryInto<Client<PostgresqlBackend>> for OAuth2ClientLookup {
     }
 }
 
+/// The channel on which the database notifies the changes to the clients,
+/// with the `client_id` of the changed client as payload, or an empty payload
+/// when all of them changed
+pub const CLIENTS_CHANNEL: &str = "oauth2_clients";
+
 pub async fn lookup_client(
     executor: impl PgExecutor<'_>,
     id: i64,
@@ -491,3 +499,98 @@ pub async fn truncate_clients(executor: impl PgExecutor<'_>) -> anyhow::Result<(
         .await?;
     Ok(())
 }
+
+/// Encrypt the client secrets again. `reencrypt` is given the client ID and
+/// the encrypted secret, and returns the new encrypted secret if it has to
+/// change. Returns how many were encrypted again
+pub async fn reencrypt_client_secrets(
+    conn: &mut PgConnection,
+    reencrypt: impl Fn(&str, &str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<u64> {
+    let clients = sqlx::query!(
+        r#"
+            SELECT id, client_id, encrypted_client_secret AS "encrypted_client_secret!"
+            FROM oauth2_clients
+            WHERE encrypted_client_secret IS NOT NULL
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut count = 0;
+    for client in clients {
+        let encrypted_client_secret =
+            match reencrypt(&client.client_id, &client.encrypted_client_secret)? {
+                Some(encrypted_client_secret) => encrypted_client_secret,
+                None => continue,
+            };
+
+        // The secret might have changed in the meantime
+        let res = sqlx::query!(
+            r#"
+                UPDATE oauth2_clients
+                SET encrypted_client_secret = $3
+                WHERE id = $1
+                  AND encrypted_client_secret = $2
+            "#,
+            client.id,
+            client.encrypted_client_secret,
+            encrypted_client_secret,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        count += res.rows_affected();
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Connection;
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn client_secrets_are_encrypted_again() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        for (client_id, secret) in [("legacy-client", "legacy"), ("bound-client", "bound")] {
+            insert_client_from_config(
+                &mut txn,
+                client_id,
+                OAuthClientAuthenticationMethod::ClientSecretBasic,
+                Some(secret),
+                None,
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+        }
+
+        // Only the legacy secrets change
+        let count = reencrypt_client_secrets(&mut txn, |client_id, encrypted| {
+            Ok((encrypted == "legacy").then(|| format!("{}:{}", client_id, encrypted)))
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        let client = lookup_client_by_client_id(&mut txn, "legacy-client")
+            .await
+            .unwrap();
+        assert_eq!(
+            client.encrypted_client_secret.as_deref(),
+            Some("legacy-client:legacy")
+        );
+        let client = lookup_client_by_client_id(&mut txn, "bound-client")
+            .await
+            .unwrap();
+        assert_eq!(client.encrypted_client_secret.as_deref(), Some("bound"));
+    }
+}
@@ -42,3 +42,48 @@ pub async fn end_oauth_session(
 
     Ok(())
 }
+
+/// Insert a user with a browser session, a client and an OAuth 2.0 session, to
+/// attach tokens to in the tests. Returns the ID of the OAuth 2.0 session
+#[cfg(test)]
+pub(crate) async fn insert_test_session(conn: &mut sqlx::PgConnection, name: &str) -> i64 {
+    let user = crate::user::insert_user(&mut *conn, name).await.unwrap();
+    let browser_session = crate::user::start_session(&mut *conn, user).await.unwrap();
+    insert_test_client_session(conn, browser_session.data, name).await
+}
+
+/// Insert a client and an OAuth 2.0 session in an existing browser session.
+/// Returns the ID of the OAuth 2.0 session
+#[cfg(test)]
+pub(crate) async fn insert_test_client_session(
+    conn: &mut sqlx::PgConnection,
+    user_session_id: i64,
+    client_id: &str,
+) -> i64 {
+    let oauth2_client_id: i64 = sqlx::query_scalar(
+        r#"
+            INSERT INTO oauth2_clients
+                (client_id, response_types, grant_type_authorization_code,
+                 grant_type_refresh_token, contacts)
+            VALUES ($1, '{}', TRUE, TRUE, '{}')
+            RETURNING id
+        "#,
+    )
+    .bind(client_id)
+    .fetch_one(&mut *conn)
+    .await
+    .unwrap();
+
+    sqlx::query_scalar(
+        r#"
+            INSERT INTO oauth2_sessions (user_session_id, oauth2_client_id, scope)
+            VALUES ($1, $2, 'openid')
+            RETURNING id
+        "#,
+    )
+    .bind(user_session_id)
+    .bind(oauth2_client_id)
+    .fetch_one(&mut *conn)
+    .await
+    .unwrap()
+}
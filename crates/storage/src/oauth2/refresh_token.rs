@@ -15,9 +15,9 @@
 use anyhow::Context;
 use chrono::{DateTime, Duration, Utc};
 use mas_data_model::{
-    AccessToken, Authentication, BrowserSession, RefreshToken, Session, User, UserEmail,
+    AccessToken, Authentication, BrowserSession, Client, RefreshToken, Session, User, UserEmail,
 };
-use sqlx::{PgConnection, PgExecutor};
+use sqlx::{postgres::types::PgInterval, PgConnection, PgExecutor};
 use thiserror::Error;
 
 use super::client::{lookup_client, ClientFetchError};
@@ -126,6 +126,7 @@ pub async fn lookup_active_refresh_token(
             FROM oauth2_refresh_tokens rt
             LEFT JOIN oauth2_access_tokens at
               ON at.id = rt.oauth2_access_token_id
+             AND at.revoked_at IS NULL
             INNER JOIN oauth2_sessions os
               ON os.id = rt.oauth2_session_id
             INNER JOIN user_sessions us
@@ -139,6 +140,7 @@ pub async fn lookup_active_refresh_token(
 
             WHERE rt.token = $1
               AND rt.next_token_id IS NULL
+              AND rt.revoked_at IS NULL
               AND us.active
               AND os.ended_at IS NULL
 
@@ -256,3 +258,170 @@ pub async fn replace_refresh_token(
         ))
     }
 }
+
+/// Revoke a refresh token, if it was issued to the given client, and end the
+/// session it belongs to, which makes all the other tokens of the session
+/// invalid. Returns `false` if the client has no such token
+pub async fn revoke_client_refresh_token(
+    conn: &mut PgConnection,
+    client: &Client<PostgresqlBackend>,
+    token: &str,
+) -> Result<bool, sqlx::Error> {
+    let session_id = sqlx::query_scalar!(
+        r#"
+            UPDATE oauth2_refresh_tokens rt
+            SET revoked_at = NOW()
+            FROM oauth2_sessions os
+            WHERE os.id = rt.oauth2_session_id
+              AND os.oauth2_client_id = $1
+              AND rt.token = $2
+              AND rt.revoked_at IS NULL
+            RETURNING rt.oauth2_session_id
+        "#,
+        client.data,
+        token,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let session_id = match session_id {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+
+    sqlx::query!(
+        r#"
+            UPDATE oauth2_sessions
+            SET ended_at = NOW()
+            WHERE id = $1
+              AND ended_at IS NULL
+        "#,
+        session_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(true)
+}
+
+/// Delete at most `batch_size` refresh tokens which were revoked or replaced
+/// by a new one more than `grace_period` ago
+///
+/// A replaced token is only deleted once the one it replaced is, so that the
+/// chain of tokens stays consistent. Long chains are deleted over several
+/// runs, starting from the oldest token.
+///
+/// Returns how many tokens were deleted
+pub async fn cleanup_revoked(
+    executor: impl PgExecutor<'_>,
+    grace_period: std::time::Duration,
+    batch_size: u32,
+) -> anyhow::Result<u64> {
+    let grace_period = PgInterval::try_from(grace_period)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM oauth2_refresh_tokens
+            WHERE id IN (
+                SELECT rt.id
+                FROM oauth2_refresh_tokens rt
+                WHERE (rt.revoked_at + $1 < now()
+                       OR (rt.next_token_id IS NOT NULL AND rt.updated_at + $1 < now()))
+                  AND NOT EXISTS (
+                      SELECT 1
+                      FROM oauth2_refresh_tokens prev
+                      WHERE prev.next_token_id = rt.id
+                  )
+                LIMIT $2
+            )
+        "#,
+        grace_period,
+        i64::from(batch_size),
+    )
+    .execute(executor)
+    .await
+    .context("could not cleanup revoked refresh tokens")?;
+
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+    use crate::oauth2::insert_test_session;
+
+    /// Insert a refresh token last updated `age` seconds ago
+    async fn insert_token(
+        conn: &mut PgConnection,
+        session_id: i64,
+        age: i32,
+        next_token_id: Option<i64>,
+        revoked: bool,
+    ) -> i64 {
+        sqlx::query_scalar(
+            r#"
+                INSERT INTO oauth2_refresh_tokens
+                    (oauth2_session_id, token, next_token_id, updated_at, revoked_at)
+                VALUES
+                    ($1, md5(random()::text), $3,
+                     now() - $2 * INTERVAL '1 second',
+                     CASE WHEN $4 THEN now() - $2 * INTERVAL '1 second' END)
+                RETURNING id
+            "#,
+        )
+        .bind(session_id)
+        .bind(f64::from(age))
+        .bind(next_token_id)
+        .bind(revoked)
+        .fetch_one(conn)
+        .await
+        .unwrap()
+    }
+
+    async fn remaining(conn: &mut PgConnection, session_id: i64) -> Vec<i64> {
+        sqlx::query_scalar(
+            "SELECT id FROM oauth2_refresh_tokens WHERE oauth2_session_id = $1 ORDER BY id",
+        )
+        .bind(session_id)
+        .fetch_all(conn)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn replaced_tokens_are_deleted_after_the_grace_period() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+        let session_id = insert_test_session(&mut txn, "cleanup-refresh-tokens").await;
+        let grace_period = std::time::Duration::from_secs(15 * 60);
+
+        // A chain of tokens, where only the last one is valid
+        let current = insert_token(&mut txn, session_id, 0, None, false).await;
+        let replaced = insert_token(&mut txn, session_id, 3600, Some(current), false).await;
+        insert_token(&mut txn, session_id, 7200, Some(replaced), false).await;
+        // Revoked 5 minutes ago, still in the grace period
+        let recently_revoked = insert_token(&mut txn, session_id, 300, None, true).await;
+        // Revoked an hour ago
+        insert_token(&mut txn, session_id, 3600, None, true).await;
+
+        // The oldest token of the chain goes first
+        cleanup_revoked(&mut txn, grace_period, 1000).await.unwrap();
+        assert_eq!(
+            remaining(&mut txn, session_id).await,
+            vec![current, replaced, recently_revoked]
+        );
+
+        cleanup_revoked(&mut txn, grace_period, 1000).await.unwrap();
+        assert_eq!(
+            remaining(&mut txn, session_id).await,
+            vec![current, recently_revoked]
+        );
+
+        txn.rollback().await.unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Check that the schema of the database matches the migrations embedded in
+//! the binary
+
+use std::collections::HashMap;
+
+use sqlx::{
+    migrate::{AppliedMigration, Migrate, MigrateError, Migrator},
+    PgConnection,
+};
+use thiserror::Error;
+
+use crate::MIGRATOR;
+
+/// The schema of the database doesn't match the one this version expects
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    /// Some migrations were not applied yet
+    #[error(
+        "the database schema is out of date: {pending} migration(s) are pending, up to version \
+         {expected}. Run `mas-cli database migrate` to apply them"
+    )]
+    Outdated {
+        /// How many migrations are pending
+        pending: usize,
+
+        /// The latest migration of this version
+        expected: i64,
+    },
+
+    /// The database was migrated by a newer version
+    #[error(
+        "the database schema has migrations unknown to this version, like {0}, it was probably \
+         migrated by a newer version"
+    )]
+    Unknown(i64),
+
+    /// An applied migration doesn't match the embedded one
+    #[error("the migration {0} was modified after being applied")]
+    Modified(i64),
+
+    /// A migration failed midway
+    #[error("the migration {0} failed midway, the schema has to be fixed manually")]
+    Dirty(i64),
+
+    /// The migrations could not be listed
+    #[error("could not read the applied migrations")]
+    Migrate(#[from] MigrateError),
+}
+
+/// Compare the migrations applied to the database with the embedded ones
+fn compare(migrator: &Migrator, applied: &[AppliedMigration]) -> Result<(), SchemaError> {
+    let expected: HashMap<i64, &[u8]> = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| (migration.version, &*migration.checksum))
+        .collect();
+
+    for migration in applied {
+        match expected.get(&migration.version) {
+            None => return Err(SchemaError::Unknown(migration.version)),
+            Some(checksum) if *checksum != &*migration.checksum => {
+                return Err(SchemaError::Modified(migration.version));
+            }
+            Some(_) => {}
+        }
+    }
+
+    let pending = expected.len() - applied.len();
+    if pending > 0 {
+        let expected = expected.keys().copied().max().unwrap_or_default();
+        return Err(SchemaError::Outdated { pending, expected });
+    }
+
+    Ok(())
+}
+
+/// Check that all the embedded migrations, and only them, were applied to the
+/// database
+///
+/// # Errors
+///
+/// Returns an error if the schema doesn't match, or if the applied migrations
+/// could not be listed
+pub async fn check_schema(conn: &mut PgConnection) -> Result<(), SchemaError> {
+    conn.ensure_migrations_table().await?;
+
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(SchemaError::Dirty(version));
+    }
+
+    let applied = conn.list_applied_migrations().await?;
+    compare(&MIGRATOR, &applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The migrations as they would be recorded by the database
+    fn applied() -> Vec<AppliedMigration> {
+        MIGRATOR
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| AppliedMigration {
+                version: migration.version,
+                checksum: migration.checksum.clone(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn up_to_date_schema() {
+        assert!(compare(&MIGRATOR, &applied()).is_ok());
+    }
+
+    #[test]
+    fn outdated_schema() {
+        let mut applied = applied();
+        let latest = applied.pop().unwrap().version;
+        applied.pop().unwrap();
+
+        match compare(&MIGRATOR, &applied) {
+            Err(SchemaError::Outdated { pending, expected }) => {
+                assert_eq!(pending, 2);
+                assert_eq!(expected, latest);
+            }
+            other => panic!("the schema should be out of date: {:?}", other),
+        }
+
+        assert!(matches!(
+            compare(&MIGRATOR, &[]),
+            Err(SchemaError::Outdated { .. })
+        ));
+    }
+
+    #[test]
+    fn newer_schema() {
+        let mut applied = applied();
+        applied.push(AppliedMigration {
+            version: 99_990_101_000_000,
+            checksum: Vec::new().into(),
+        });
+
+        assert!(matches!(
+            compare(&MIGRATOR, &applied),
+            Err(SchemaError::Unknown(99_990_101_000_000))
+        ));
+    }
+
+    #[test]
+    fn modified_migration() {
+        let mut applied = applied();
+        let version = applied[0].version;
+        applied[0].checksum = vec![0; 48].into();
+
+        match compare(&MIGRATOR, &applied) {
+            Err(SchemaError::Modified(v)) => assert_eq!(v, version),
+            other => panic!("the migration should be modified: {:?}", other),
+        }
+    }
+}
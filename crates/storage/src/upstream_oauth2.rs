@@ -0,0 +1,282 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Links between the users and their accounts on upstream OIDC
+//! providers
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use mas_data_model::User;
+use sqlx::{Acquire, PgExecutor, Postgres};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    user::{
+        add_user_email, insert_user, mark_user_email_as_verified, set_user_email_as_primary,
+        UserLookup, UserLookupError,
+    },
+    PostgresqlBackend,
+};
+
+/// Lookup the user linked to a subject of an upstream provider, if any
+#[tracing::instrument(skip(executor))]
+pub async fn lookup_user_by_upstream_subject(
+    executor: impl PgExecutor<'_>,
+    provider: &str,
+    subject: &str,
+) -> Result<Option<User<PostgresqlBackend>>, UserLookupError> {
+    let res = sqlx::query_as!(
+        UserLookup,
+        r#"
+            SELECT
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM user_upstream_links l
+
+            INNER JOIN users u
+              ON u.id = l.user_id
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE l.provider = $1
+              AND l.subject = $2
+              AND u.deleted_at IS NULL
+        "#,
+        provider,
+        subject,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Lookup upstream link"))
+    .await?;
+
+    Ok(res.map(TryInto::try_into).transpose()?)
+}
+
+/// Link a user to a subject of an upstream provider
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data))]
+pub async fn add_upstream_link(
+    executor: impl PgExecutor<'_>,
+    provider: &str,
+    subject: &str,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+            INSERT INTO user_upstream_links (provider, subject, user_id)
+            VALUES ($1, $2, $3)
+        "#,
+        provider,
+        subject,
+        user.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("Add upstream link"))
+    .await
+    .context("could not insert upstream link")?;
+
+    Ok(())
+}
+
+/// A link between a user and their subject on an upstream provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamLink {
+    pub id: i64,
+    pub provider: String,
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// List the upstream providers a user is linked to
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn get_upstream_links(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Vec<UpstreamLink>> {
+    let links = sqlx::query_as!(
+        UpstreamLink,
+        r#"
+            SELECT id, provider, subject, created_at
+            FROM user_upstream_links
+            WHERE user_id = $1
+            ORDER BY created_at
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Fetch upstream links"))
+    .await
+    .context("could not fetch upstream links")?;
+
+    Ok(links)
+}
+
+/// Unlink a user from an upstream provider. Returns whether they were linked
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data))]
+pub async fn remove_upstream_link(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    provider: &str,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM user_upstream_links
+            WHERE user_id = $1
+              AND provider = $2
+        "#,
+        user.data,
+        provider,
+    )
+    .execute(executor)
+    .instrument(info_span!("Remove upstream link"))
+    .await
+    .context("could not remove upstream link")?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+/// Create a user logging in through an upstream provider for the first time,
+/// with the email the provider verified if any, as `(email, normalized_email)`.
+/// The user has no password, and can only log in through that provider.
+#[tracing::instrument(skip(conn))]
+pub async fn register_upstream_user(
+    conn: impl Acquire<'_, Database = Postgres>,
+    provider: &str,
+    subject: &str,
+    username: &str,
+    verified_email: Option<(&str, &str)>,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let mut txn = conn.begin().await.context("could not start transaction")?;
+
+    let mut user = insert_user(&mut txn, username).await?;
+    add_upstream_link(&mut txn, provider, subject, &user).await?;
+
+    if let Some((email, normalized_email)) = verified_email {
+        let email = add_user_email(&mut txn, &user, email, normalized_email).await?;
+        let email = mark_user_email_as_verified(&mut txn, email).await?;
+        set_user_email_as_primary(&mut txn, &user, &email).await?;
+        user.primary_email = Some(email);
+    }
+
+    txn.commit().await.context("could not commit transaction")?;
+
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn upstream_user_provisioning() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        assert!(lookup_user_by_upstream_subject(&mut txn, "example", "abc")
+            .await
+            .unwrap()
+            .is_none());
+
+        // The first login registers the user, with the verified email
+        let user = register_upstream_user(
+            &mut txn,
+            "example",
+            "abc",
+            "upstream-alice",
+            Some(("Alice@example.com", "alice@example.com")),
+        )
+        .await
+        .unwrap();
+        assert!(user.has_verified_email());
+
+        // The next ones find them through the link
+        let linked = lookup_user_by_upstream_subject(&mut txn, "example", "abc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.data, user.data);
+        assert_eq!(linked.username, "upstream-alice");
+        assert!(linked.has_verified_email());
+
+        // The link can be removed, and then the subject isn't known anymore
+        assert_eq!(
+            get_upstream_links(&mut txn, &user)
+                .await
+                .unwrap()
+                .iter()
+                .map(|link| (link.provider.as_str(), link.subject.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("example", "abc")]
+        );
+        assert!(remove_upstream_link(&mut txn, &user, "example")
+            .await
+            .unwrap());
+        assert!(!remove_upstream_link(&mut txn, &user, "example")
+            .await
+            .unwrap());
+        assert!(lookup_user_by_upstream_subject(&mut txn, "example", "abc")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Subjects are per provider
+        assert!(lookup_user_by_upstream_subject(&mut txn, "other", "abc")
+            .await
+            .unwrap()
+            .is_none());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn explicit_link() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        // An existing local user links their upstream account
+        let user = insert_user(&mut txn, "local-alice").await.unwrap();
+        assert!(get_upstream_links(&mut txn, &user)
+            .await
+            .unwrap()
+            .is_empty());
+
+        add_upstream_link(&mut txn, "example", "abc", &user)
+            .await
+            .unwrap();
+
+        let linked = lookup_user_by_upstream_subject(&mut txn, "example", "abc")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.data, user.data);
+
+        // A subject can only be linked to one user
+        let other = insert_user(&mut txn, "local-bob").await.unwrap();
+        assert!(add_upstream_link(&mut txn, "example", "abc", &other)
+            .await
+            .is_err());
+
+        txn.rollback().await.unwrap();
+    }
+}
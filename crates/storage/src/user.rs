@@ -12,33 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::borrow::BorrowMut;
+use std::{borrow::BorrowMut, collections::HashMap};
 
 use anyhow::{bail, Context};
 use argon2::Argon2;
 use chrono::{DateTime, Utc};
 use mas_data_model::{
-    Authentication, BrowserSession, User, UserEmail, UserEmailVerification,
-    UserEmailVerificationState,
+    Authentication, BrowserSession, Cursor, LoginAttempts, PasswordPolicy, PasswordPolicyError,
+    PasswordReset, TotpSecret, User, UserEmail, UserEmailVerification, UserEmailVerificationState,
+    UserTotp, UsernamePolicy, UsernamePolicyError,
 };
 use password_hash::{PasswordHash, PasswordHasher, SaltString};
 use rand::rngs::OsRng;
-use sqlx::{postgres::types::PgInterval, Acquire, PgExecutor, Postgres, Transaction};
+use sqlx::{postgres::types::PgInterval, Acquire, PgConnection, PgExecutor, Postgres, Transaction};
 use thiserror::Error;
 use tokio::task;
-use tracing::{info_span, Instrument};
+use tracing::{error, info_span, Instrument};
 
 use super::{DatabaseInconsistencyError, PostgresqlBackend};
 use crate::IdAndCreationTime;
 
 #[derive(Debug, Clone)]
-struct UserLookup {
-    user_id: i64,
-    user_username: String,
-    user_email_id: Option<i64>,
-    user_email: Option<String>,
-    user_email_created_at: Option<DateTime<Utc>>,
-    user_email_confirmed_at: Option<DateTime<Utc>>,
+pub(crate) struct UserLookup {
+    pub(crate) user_id: i64,
+    pub(crate) user_username: String,
+    pub(crate) user_email_id: Option<i64>,
+    pub(crate) user_email: Option<String>,
+    pub(crate) user_email_created_at: Option<DateTime<Utc>>,
+    pub(crate) user_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<UserLookup> for User<PostgresqlBackend> {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_from(res: UserLookup) -> Result<Self, Self::Error> {
+        let primary_email = match (
+            res.user_email_id,
+            res.user_email,
+            res.user_email_created_at,
+            res.user_email_confirmed_at,
+        ) {
+            (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
+                data: id,
+                email,
+                created_at,
+                confirmed_at,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError),
+        };
+
+        Ok(User {
+            data: res.user_id,
+            username: res.user_username,
+            sub: format!("fake-sub-{}", res.user_id),
+            primary_email,
+        })
+    }
 }
 
 #[derive(Debug, Error)]
@@ -197,7 +227,7 @@ pub async fn lookup_active_session(
                 ON a.session_id = s.id
             LEFT JOIN user_emails ue
               ON ue.id = u.primary_email_id
-            WHERE s.id = $1 AND s.active
+            WHERE s.id = $1 AND s.active AND u.deleted_at IS NULL
             ORDER BY a.created_at DESC
             LIMIT 1
         "#,
@@ -258,222 +288,1540 @@ pub async fn count_active_sessions(
     Ok(res)
 }
 
-#[derive(Debug, Error)]
-pub enum AuthenticationError {
-    #[error("could not verify password")]
-    Password(#[from] password_hash::Error),
-
-    #[error("could not fetch user password hash")]
-    Fetch(sqlx::Error),
-
-    #[error("could not save session auth")]
-    Save(sqlx::Error),
-
-    #[error("runtime error")]
-    Internal(#[from] tokio::task::JoinError),
+/// When a browser session started, and when it was last used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionActivity {
+    pub created_at: DateTime<Utc>,
+    pub last_active_at: DateTime<Utc>,
 }
 
-#[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
-pub async fn authenticate_session(
-    txn: &mut Transaction<'_, Postgres>,
-    session: &mut BrowserSession<PostgresqlBackend>,
-    password: &str,
-) -> Result<(), AuthenticationError> {
-    // First, fetch the hashed password from the user associated with that session
-    let hashed_password: String = sqlx::query_scalar!(
+/// Lookup the activity of an active browser session
+#[tracing::instrument(skip_all, fields(session.id = id))]
+pub async fn lookup_session_activity(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+) -> Result<Option<SessionActivity>, sqlx::Error> {
+    sqlx::query_as!(
+        SessionActivity,
         r#"
-            SELECT up.hashed_password
-            FROM user_passwords up
-            WHERE up.user_id = $1
-            ORDER BY up.created_at DESC
-            LIMIT 1
+            SELECT created_at, last_active_at
+            FROM user_sessions
+            WHERE id = $1 AND active
         "#,
-        session.user.data,
+        id,
     )
-    .fetch_one(txn.borrow_mut())
-    .instrument(tracing::info_span!("Lookup hashed password"))
+    .fetch_optional(executor)
     .await
-    .map_err(AuthenticationError::Fetch)?;
-
-    // TODO: pass verifiers list as parameter
-    // Verify the password in a blocking thread to avoid blocking the async executor
-    let password = password.to_string();
-    task::spawn_blocking(move || {
-        let context = Argon2::default();
-        let hasher = PasswordHash::new(&hashed_password).map_err(AuthenticationError::Password)?;
-        hasher
-            .verify_password(&[&context], &password)
-            .map_err(AuthenticationError::Password)
-    })
-    .instrument(tracing::info_span!("Verify hashed password"))
-    .await??;
+}
 
-    // That went well, let's insert the auth info
-    let res = sqlx::query_as!(
-        IdAndCreationTime,
+/// Record that a browser session was just used.
+///
+/// This is only written once a minute, to avoid an update on each request
+#[tracing::instrument(skip_all, fields(session.id = id))]
+pub async fn record_session_activity(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
         r#"
-            INSERT INTO user_session_authentications (session_id)
-            VALUES ($1)
-            RETURNING id, created_at
+            UPDATE user_sessions
+            SET last_active_at = now()
+            WHERE id = $1 AND last_active_at < now() - INTERVAL '1 minute'
         "#,
-        session.data,
+        id,
     )
-    .fetch_one(txn.borrow_mut())
-    .instrument(tracing::info_span!("Save authentication"))
-    .await
-    .map_err(AuthenticationError::Save)?;
-
-    session.last_authentication = Some(Authentication {
-        data: res.id,
-        created_at: res.created_at,
-    });
+    .execute(executor)
+    .await?;
 
     Ok(())
 }
 
-#[tracing::instrument(skip(txn, phf, password))]
-pub async fn register_user(
-    txn: &mut Transaction<'_, Postgres>,
-    phf: impl PasswordHasher,
-    username: &str,
-    password: &str,
-) -> anyhow::Result<User<PostgresqlBackend>> {
-    let id: i64 = sqlx::query_scalar!(
+/// End a browser session which expired, returning whether it was still active
+#[tracing::instrument(skip_all, fields(session.id = id))]
+pub async fn expire_session(executor: impl PgExecutor<'_>, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        "UPDATE user_sessions SET active = FALSE WHERE id = $1 AND active",
+        id,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(res.rows_affected() > 0)
+}
+
+/// A session a client holds on behalf of a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientSession {
+    /// A session started through the OAuth 2.0 authorization flow
+    OAuth2 {
+        id: i64,
+        client_id: String,
+        client_name: Option<String>,
+        scope: String,
+        created_at: DateTime<Utc>,
+        last_active_at: Option<DateTime<Utc>>,
+    },
+
+    /// A session started through the Matrix compatibility layer
+    Compat {
+        id: i64,
+        device_id: String,
+        device_display_name: Option<String>,
+        created_at: DateTime<Utc>,
+        last_active_at: Option<DateTime<Utc>>,
+    },
+}
+
+impl ClientSession {
+    #[must_use]
+    pub fn created_at(&self) -> DateTime<Utc> {
+        match self {
+            Self::OAuth2 { created_at, .. } | Self::Compat { created_at, .. } => *created_at,
+        }
+    }
+
+    /// When a token was last issued in this session, which is the closest we
+    /// have to when the client was last active
+    #[must_use]
+    pub fn last_active_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Self::OAuth2 { last_active_at, .. } | Self::Compat { last_active_at, .. } => {
+                *last_active_at
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn get_active_sessions(
+    conn: &mut PgConnection,
+    user: &User<PostgresqlBackend>,
+) -> Result<Vec<ClientSession>, sqlx::Error> {
+    let oauth2_sessions = sqlx::query!(
         r#"
-            INSERT INTO users (username)
-            VALUES ($1)
-            RETURNING id
+            SELECT
+                os.id           AS "id",
+                oc.client_id    AS "client_id",
+                oc.client_name  AS "client_name",
+                os.scope        AS "scope",
+                os.created_at   AS "created_at",
+                (
+                    SELECT MAX(at.created_at)
+                    FROM oauth2_access_tokens at
+                    WHERE at.oauth2_session_id = os.id
+                )               AS "last_active_at"
+            FROM oauth2_sessions os
+            INNER JOIN user_sessions us
+              ON us.id = os.user_session_id
+            INNER JOIN oauth2_clients oc
+              ON oc.id = os.oauth2_client_id
+            WHERE us.user_id = $1
+              AND os.ended_at IS NULL
         "#,
-        username,
+        user.data,
     )
-    .fetch_one(txn.borrow_mut())
-    .instrument(info_span!("Register user"))
-    .await
-    .context("could not insert user")?;
-
-    let user = User {
-        data: id,
-        username: username.to_string(),
-        sub: format!("fake-sub-{}", id),
-        primary_email: None,
-    };
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("Fetch OAuth 2.0 sessions"))
+    .await?;
 
-    set_password(txn.borrow_mut(), phf, &user, password).await?;
+    let compat_sessions = sqlx::query!(
+        r#"
+            SELECT
+                cs.id                   AS "id",
+                cs.device_id            AS "device_id",
+                cs.device_display_name  AS "device_display_name",
+                cs.created_at           AS "created_at",
+                (
+                    SELECT MAX(cat.created_at)
+                    FROM compat_access_tokens cat
+                    WHERE cat.compat_session_id = cs.id
+                )                       AS "last_active_at"
+            FROM compat_sessions cs
+            WHERE cs.user_id = $1
+              AND cs.deleted_at IS NULL
+        "#,
+        user.data,
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("Fetch compat sessions"))
+    .await?;
 
-    Ok(user)
+    let mut sessions: Vec<ClientSession> = oauth2_sessions
+        .into_iter()
+        .map(|s| ClientSession::OAuth2 {
+            id: s.id,
+            client_id: s.client_id,
+            client_name: s.client_name,
+            scope: s.scope,
+            created_at: s.created_at,
+            last_active_at: s.last_active_at,
+        })
+        .chain(compat_sessions.into_iter().map(|s| ClientSession::Compat {
+            id: s.id,
+            device_id: s.device_id,
+            device_display_name: s.device_display_name,
+            created_at: s.created_at,
+            last_active_at: s.last_active_at,
+        }))
+        .collect();
+
+    sessions.sort_by_key(ClientSession::created_at);
+
+    Ok(sessions)
 }
 
+/// End every OAuth 2.0 and compat session of the user, which makes all the
+/// tokens issued in those sessions invalid. Returns how many sessions were
+/// ended
 #[tracing::instrument(skip_all, fields(user.id = user.data))]
-pub async fn set_password(
-    executor: impl PgExecutor<'_>,
-    phf: impl PasswordHasher,
+pub async fn revoke_all_sessions(
+    conn: &mut PgConnection,
     user: &User<PostgresqlBackend>,
-    password: &str,
-) -> anyhow::Result<()> {
-    let salt = SaltString::generate(&mut OsRng);
-    let hashed_password = PasswordHash::generate(phf, password, salt.as_str())?;
+) -> Result<u64, sqlx::Error> {
+    let oauth2_res = sqlx::query!(
+        r#"
+            UPDATE oauth2_sessions os
+            SET ended_at = NOW()
+            FROM user_sessions us
+            WHERE us.id = os.user_session_id
+              AND us.user_id = $1
+              AND os.ended_at IS NULL
+        "#,
+        user.data,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("End OAuth 2.0 sessions"))
+    .await?;
 
-    sqlx::query_scalar!(
+    let compat_res = sqlx::query!(
         r#"
-            INSERT INTO user_passwords (user_id, hashed_password)
-            VALUES ($1, $2)
+            UPDATE compat_sessions
+            SET deleted_at = NOW()
+            WHERE user_id = $1
+              AND deleted_at IS NULL
         "#,
         user.data,
-        hashed_password.to_string(),
     )
-    .execute(executor)
-    .instrument(info_span!("Save user credentials"))
-    .await
-    .context("could not insert user password")?;
+    .execute(&mut *conn)
+    .instrument(info_span!("End compat sessions"))
+    .await?;
 
-    Ok(())
+    Ok(oauth2_res.rows_affected() + compat_res.rows_affected())
 }
 
-#[tracing::instrument(skip_all, fields(session.id = session.data))]
-pub async fn end_session(
+/// End one of the user's OAuth 2.0 sessions. Returns `false` if the user has no
+/// such active session
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data))]
+pub async fn revoke_oauth2_session(
     executor: impl PgExecutor<'_>,
-    session: &BrowserSession<PostgresqlBackend>,
-) -> anyhow::Result<()> {
+    user: &User<PostgresqlBackend>,
+    id: i64,
+) -> Result<bool, sqlx::Error> {
     let res = sqlx::query!(
-        "UPDATE user_sessions SET active = FALSE WHERE id = $1",
-        session.data,
+        r#"
+            UPDATE oauth2_sessions os
+            SET ended_at = NOW()
+            FROM user_sessions us
+            WHERE us.id = os.user_session_id
+              AND us.user_id = $1
+              AND os.id = $2
+              AND os.ended_at IS NULL
+        "#,
+        user.data,
+        id,
     )
     .execute(executor)
-    .instrument(info_span!("End session"))
-    .await
-    .context("could not end session")?;
+    .await?;
 
-    match res.rows_affected() {
-        1 => Ok(()),
-        0 => Err(anyhow::anyhow!("no row affected")),
-        _ => Err(anyhow::anyhow!("too many row affected")),
-    }
+    Ok(res.rows_affected() == 1)
 }
 
-#[derive(Debug, Error)]
-#[error("failed to lookup user")]
-pub enum UserLookupError {
-    Database(#[from] sqlx::Error),
-    Inconsistency(#[from] DatabaseInconsistencyError),
+/// End one of the user's compat sessions. Returns `false` if the user has no
+/// such active session
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data))]
+pub async fn revoke_compat_session(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    id: i64,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE compat_sessions
+            SET deleted_at = NOW()
+            WHERE user_id = $1
+              AND id = $2
+              AND deleted_at IS NULL
+        "#,
+        user.data,
+        id,
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(res.rows_affected() == 1)
 }
 
-impl UserLookupError {
-    #[must_use]
-    pub fn not_found(&self) -> bool {
-        matches!(self, Self::Database(sqlx::Error::RowNotFound))
+/// How long a device display name can be, in characters
+pub const MAX_DEVICE_DISPLAY_NAME_LENGTH: usize = 100;
+
+/// Clean up a device display name chosen by the user: control characters are
+/// removed, whitespace is collapsed and the name is truncated. Returns `None`
+/// if nothing is left
+#[must_use]
+pub fn sanitize_device_display_name(name: &str) -> Option<String> {
+    let words: Vec<String> = name
+        .split_whitespace()
+        .map(|word| word.chars().filter(|c| !c.is_control()).collect())
+        .filter(|word: &String| !word.is_empty())
+        .collect();
+
+    let name: String = words
+        .join(" ")
+        .chars()
+        .take(MAX_DEVICE_DISPLAY_NAME_LENGTH)
+        .collect();
+    let name = name.trim_end();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_owned())
     }
 }
 
-#[tracing::instrument(skip(executor))]
-pub async fn lookup_user_by_username(
+/// Rename the device of one of the user's compat sessions. The name is
+/// sanitized first, and an empty name resets it to the device ID. Returns
+/// `false` if the user has no such active session
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data))]
+pub async fn set_device_display_name(
     executor: impl PgExecutor<'_>,
-    username: &str,
-) -> Result<User<PostgresqlBackend>, UserLookupError> {
-    let res = sqlx::query_as!(
-        UserLookup,
+    user: &User<PostgresqlBackend>,
+    id: i64,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let name = sanitize_device_display_name(name);
+
+    let res = sqlx::query!(
         r#"
-            SELECT 
-                u.id            AS user_id, 
-                u.username      AS user_username,
-                ue.id           AS "user_email_id?",
-                ue.email        AS "user_email?",
-                ue.created_at   AS "user_email_created_at?",
-                ue.confirmed_at AS "user_email_confirmed_at?"
-            FROM users u
+            UPDATE compat_sessions
+            SET device_display_name = $3
+            WHERE user_id = $1
+              AND id = $2
+              AND deleted_at IS NULL
+        "#,
+        user.data,
+        id,
+        name,
+    )
+    .execute(executor)
+    .await?;
 
-            LEFT JOIN user_emails ue
-              ON ue.id = u.primary_email_id
+    Ok(res.rows_affected() == 1)
+}
 
-            WHERE u.username = $1
+/// Mark the user as deleted and end all their browser and client sessions,
+/// which makes every token issued to them invalid. The user can be restored
+/// with [`undelete_user`] within a grace period
+#[tracing::instrument(skip_all, fields(user.id = user.data, %user.username))]
+pub async fn soft_delete_user(
+    conn: &mut PgConnection,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<()> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE users
+            SET deleted_at = NOW()
+            WHERE id = $1
+              AND deleted_at IS NULL
         "#,
-        username,
+        user.data,
     )
-    .fetch_one(executor)
-    .instrument(info_span!("Fetch user"))
-    .await?;
+    .execute(&mut *conn)
+    .instrument(info_span!("Mark user as deleted"))
+    .await
+    .context("could not mark user as deleted")?;
 
-    let primary_email = match (
-        res.user_email_id,
-        res.user_email,
-        res.user_email_created_at,
-        res.user_email_confirmed_at,
-    ) {
-        (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
-            data: id,
-            email,
-            created_at,
-            confirmed_at,
-        }),
-        (None, None, None, None) => None,
-        _ => return Err(DatabaseInconsistencyError.into()),
-    };
+    anyhow::ensure!(res.rows_affected() == 1, "user is already deleted");
 
-    Ok(User {
-        data: res.user_id,
-        username: res.user_username,
-        sub: format!("fake-sub-{}", res.user_id),
-        primary_email,
-    })
+    sqlx::query!(
+        r#"
+            UPDATE user_sessions
+            SET active = FALSE
+            WHERE user_id = $1
+              AND active
+        "#,
+        user.data,
+    )
+    .execute(&mut *conn)
+    .instrument(info_span!("End browser sessions"))
+    .await
+    .context("could not end browser sessions")?;
+
+    revoke_all_sessions(&mut *conn, user)
+        .await
+        .context("could not end client sessions")?;
+
+    Ok(())
+}
+
+/// The shortest grace period, in days, [`undelete_user`] accepts. A deleted
+/// user is always restorable for at least that long
+pub const MIN_UNDELETE_GRACE_PERIOD_DAYS: u32 = 1;
+
+/// Restore a user deleted with [`soft_delete_user`], if it was deleted less
+/// than `grace_period` ago. Sessions ended by the deletion stay ended
+#[tracing::instrument(skip(executor))]
+pub async fn undelete_user(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+    grace_period: chrono::Duration,
+) -> anyhow::Result<()> {
+    if grace_period < chrono::Duration::days(MIN_UNDELETE_GRACE_PERIOD_DAYS.into()) {
+        bail!(
+            "the grace period has to be at least {} day(s)",
+            MIN_UNDELETE_GRACE_PERIOD_DAYS
+        );
+    }
+
+    let grace_period = PgInterval::try_from(grace_period)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let res = sqlx::query!(
+        r#"
+            UPDATE users
+            SET deleted_at = NULL
+            WHERE username = $1
+              AND deleted_at IS NOT NULL
+              AND deleted_at + $2 > NOW()
+        "#,
+        username,
+        grace_period,
+    )
+    .execute(executor)
+    .instrument(info_span!("Restore user"))
+    .await
+    .context("could not restore user")?;
+
+    match res.rows_affected() {
+        1 => Ok(()),
+        0 => bail!("user is not deleted or its grace period is over"),
+        _ => bail!("too many row affected"),
+    }
+}
+
+/// Restore a user deleted with [`soft_delete_user`], by its ID, however long
+/// ago it was deleted. Returns `false` if there is no such deleted user.
+/// Sessions ended by the deletion stay ended
+#[tracing::instrument(skip(executor))]
+pub async fn reactivate_user(executor: impl PgExecutor<'_>, id: i64) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE users
+            SET deleted_at = NULL
+            WHERE id = $1
+              AND deleted_at IS NOT NULL
+        "#,
+        id,
+    )
+    .execute(executor)
+    .instrument(info_span!("Reactivate user"))
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Get the failed logins of the user, and lock them until the end of the
+/// transaction, so that concurrent logins wait for this one to save its outcome
+/// with [`set_login_attempts`] instead of overwriting it
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn get_login_attempts(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<LoginAttempts> {
+    let res = sqlx::query!(
+        r#"
+            SELECT failed_login_count, locked_until
+            FROM users
+            WHERE id = $1
+            FOR UPDATE
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Fetch login attempts"))
+    .await
+    .context("could not fetch login attempts")?;
+
+    Ok(LoginAttempts {
+        failed_count: res.failed_login_count.try_into()?,
+        locked_until: res.locked_until,
+    })
+}
+
+/// Get the TOTP second factor of the user, whether it is enabled or the user
+/// is still setting it up
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn lookup_user_totp(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Option<UserTotp<PostgresqlBackend>>> {
+    let res = sqlx::query!(
+        r#"
+            SELECT id, encrypted_secret, plaintext, created_at, verified_at, last_used_step
+            FROM user_totp
+            WHERE user_id = $1
+        "#,
+        user.data,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Fetch user TOTP"))
+    .await
+    .context("could not fetch user TOTP")?;
+
+    let totp = match res {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+
+    // Those are encrypted when the server starts
+    if totp.plaintext {
+        bail!("the TOTP secret was not encrypted yet");
+    }
+
+    Ok(Some(UserTotp {
+        data: totp.id,
+        encrypted_secret: totp.encrypted_secret,
+        created_at: totp.created_at,
+        verified_at: totp.verified_at,
+        last_used_step: totp
+            .last_used_step
+            .map(u64::try_from)
+            .transpose()
+            .map_err(|_| DatabaseInconsistencyError)?,
+    }))
+}
+
+/// Start setting up a TOTP second factor for the user with a new secret,
+/// already encrypted. This replaces a previous secret the user did not verify,
+/// but fails if TOTP is already enabled
+#[tracing::instrument(skip(executor, user, encrypted_secret), fields(user.id = user.data))]
+pub async fn start_user_totp_enrollment(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    encrypted_secret: String,
+) -> anyhow::Result<UserTotp<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO user_totp (user_id, encrypted_secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET encrypted_secret = EXCLUDED.encrypted_secret,
+                plaintext = FALSE,
+                last_used_step = NULL,
+                created_at = NOW()
+            WHERE user_totp.verified_at IS NULL
+            RETURNING id, created_at
+        "#,
+        user.data,
+        encrypted_secret,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Insert user TOTP"))
+    .await
+    .context("could not insert user TOTP")?;
+
+    let res = match res {
+        Some(res) => res,
+        None => bail!("TOTP is already enabled"),
+    };
+
+    Ok(UserTotp {
+        data: res.id,
+        encrypted_secret,
+        created_at: res.created_at,
+        verified_at: None,
+        last_used_step: None,
+    })
+}
+
+/// Enable the TOTP second factor, once the user proved they set it up by
+/// entering a valid code for the given time step
+#[tracing::instrument(skip_all, fields(user_totp.id = totp.data))]
+pub async fn mark_user_totp_as_verified(
+    executor: impl PgExecutor<'_>,
+    mut totp: UserTotp<PostgresqlBackend>,
+    step: u64,
+) -> anyhow::Result<UserTotp<PostgresqlBackend>> {
+    let verified_at = sqlx::query_scalar!(
+        r#"
+            UPDATE user_totp
+            SET verified_at = NOW(),
+                last_used_step = $2
+            WHERE id = $1
+              AND verified_at IS NULL
+            RETURNING verified_at AS "verified_at!"
+        "#,
+        totp.data,
+        i64::try_from(step)?,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Mark user TOTP as verified"))
+    .await
+    .context("could not mark user TOTP as verified")?;
+
+    totp.verified_at = Some(verified_at);
+    totp.last_used_step = Some(step);
+
+    Ok(totp)
+}
+
+/// Save the time step of a code accepted for a login. Returns `false` if a
+/// code for that step or a later one was already accepted, in which case the
+/// code must be rejected
+#[tracing::instrument(skip_all, fields(user_totp.id = totp.data))]
+pub async fn record_user_totp_step(
+    executor: impl PgExecutor<'_>,
+    totp: &UserTotp<PostgresqlBackend>,
+    step: u64,
+) -> anyhow::Result<bool> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_totp
+            SET last_used_step = $2
+            WHERE id = $1
+              AND (last_used_step IS NULL OR last_used_step < $2)
+        "#,
+        totp.data,
+        i64::try_from(step)?,
+    )
+    .execute(executor)
+    .instrument(info_span!("Record user TOTP step"))
+    .await
+    .context("could not record user TOTP step")?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Encrypt the TOTP secrets stored in plain text before they were encrypted.
+/// `encrypt` is given the ID of the user and the secret. Returns how many were
+/// encrypted
+#[tracing::instrument(skip_all, err)]
+pub async fn encrypt_plaintext_totp_secrets(
+    conn: &mut PgConnection,
+    encrypt: impl Fn(i64, &TotpSecret) -> anyhow::Result<String>,
+) -> anyhow::Result<u64> {
+    let plaintext = sqlx::query!(
+        r#"
+            SELECT id, user_id, encrypted_secret AS "secret"
+            FROM user_totp
+            WHERE plaintext
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("Fetch plain text TOTP secrets"))
+    .await
+    .context("could not fetch plain text TOTP secrets")?;
+
+    let mut count = 0;
+    for totp in plaintext {
+        let secret =
+            TotpSecret::from_base32(&totp.secret).map_err(|_| DatabaseInconsistencyError)?;
+        let encrypted_secret = encrypt(totp.user_id, &secret)?;
+
+        let res = sqlx::query!(
+            r#"
+                UPDATE user_totp
+                SET encrypted_secret = $2,
+                    plaintext = FALSE
+                WHERE id = $1
+                  AND plaintext
+            "#,
+            totp.id,
+            encrypted_secret,
+        )
+        .execute(&mut *conn)
+        .instrument(info_span!("Save encrypted TOTP secret"))
+        .await
+        .context("could not save encrypted TOTP secret")?;
+
+        count += res.rows_affected();
+    }
+
+    Ok(count)
+}
+
+/// Encrypt the TOTP secrets again. `reencrypt` is given the ID of the user and
+/// the encrypted secret, and returns the new encrypted secret if it has to
+/// change. Returns how many were encrypted again
+#[tracing::instrument(skip_all, err)]
+pub async fn reencrypt_totp_secrets(
+    conn: &mut PgConnection,
+    reencrypt: impl Fn(i64, &str) -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<u64> {
+    let totps = sqlx::query!(
+        r#"
+            SELECT id, user_id, encrypted_secret
+            FROM user_totp
+            WHERE NOT plaintext
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("Fetch encrypted TOTP secrets"))
+    .await
+    .context("could not fetch encrypted TOTP secrets")?;
+
+    let mut count = 0;
+    for totp in totps {
+        let encrypted_secret = match reencrypt(totp.user_id, &totp.encrypted_secret)? {
+            Some(encrypted_secret) => encrypted_secret,
+            None => continue,
+        };
+
+        // The user might have set up TOTP again in the meantime
+        let res = sqlx::query!(
+            r#"
+                UPDATE user_totp
+                SET encrypted_secret = $3
+                WHERE id = $1
+                  AND encrypted_secret = $2
+            "#,
+            totp.id,
+            totp.encrypted_secret,
+            encrypted_secret,
+        )
+        .execute(&mut *conn)
+        .instrument(info_span!("Save encrypted TOTP secret"))
+        .await
+        .context("could not save encrypted TOTP secret")?;
+
+        count += res.rows_affected();
+    }
+
+    Ok(count)
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn set_login_attempts(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    attempts: LoginAttempts,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE users
+            SET failed_login_count = $2,
+                locked_until = $3
+            WHERE id = $1
+        "#,
+        user.data,
+        i32::try_from(attempts.failed_count)?,
+        attempts.locked_until,
+    )
+    .execute(executor)
+    .instrument(info_span!("Save login attempts"))
+    .await
+    .context("could not save login attempts")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum AuthenticationError {
+    #[error("could not verify password")]
+    Password(#[from] password_hash::Error),
+
+    #[error("could not fetch user password hash")]
+    Fetch(sqlx::Error),
+
+    #[error("could not save session auth")]
+    Save(sqlx::Error),
+
+    #[error("runtime error")]
+    Internal(#[from] tokio::task::JoinError),
+}
+
+/// Check the password against the latest password hash of the user
+async fn verify_user_password(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    password: &str,
+) -> Result<(), AuthenticationError> {
+    // First, fetch the hashed password of the user
+    let hashed_password: String = sqlx::query_scalar!(
+        r#"
+            SELECT up.hashed_password
+            FROM user_passwords up
+            WHERE up.user_id = $1
+            ORDER BY up.created_at DESC
+            LIMIT 1
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(tracing::info_span!("Lookup hashed password"))
+    .await
+    .map_err(AuthenticationError::Fetch)?;
+
+    // Verify the password in a blocking thread to avoid blocking the async executor
+    let password = password.to_string();
+    task::spawn_blocking(move || verify_password_hash(&hashed_password, &password))
+        .instrument(tracing::info_span!("Verify hashed password"))
+        .await??;
+
+    Ok(())
+}
+
+// TODO: pass verifiers list as parameter
+fn verify_password_hash(hashed_password: &str, password: &str) -> Result<(), password_hash::Error> {
+    let context = Argon2::default();
+    let hasher = PasswordHash::new(hashed_password)?;
+    hasher.verify_password(&[&context], password)
+}
+
+#[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
+pub async fn authenticate_session(
+    txn: &mut Transaction<'_, Postgres>,
+    session: &mut BrowserSession<PostgresqlBackend>,
+    password: &str,
+) -> Result<(), AuthenticationError> {
+    verify_user_password(txn.borrow_mut(), &session.user, password).await?;
+
+    // That went well, let's insert the auth info
+    record_session_authentication(txn.borrow_mut(), session)
+        .await
+        .map_err(AuthenticationError::Save)?;
+
+    Ok(())
+}
+
+/// Record that the user just authenticated in this session, once their
+/// credentials were checked
+#[tracing::instrument(skip_all, fields(session.id = session.data))]
+pub async fn record_session_authentication(
+    executor: impl PgExecutor<'_>,
+    session: &mut BrowserSession<PostgresqlBackend>,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO user_session_authentications (session_id)
+            VALUES ($1)
+            RETURNING id, created_at
+        "#,
+        session.data,
+    )
+    .fetch_one(executor)
+    .instrument(tracing::info_span!("Save authentication"))
+    .await?;
+
+    session.last_authentication = Some(Authentication {
+        data: res.id,
+        created_at: res.created_at,
+    });
+
+    Ok(())
+}
+
+pub(crate) async fn insert_user(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let id: i64 = sqlx::query_scalar!(
+        r#"
+            INSERT INTO users (username)
+            VALUES ($1)
+            RETURNING id
+        "#,
+        username,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Register user"))
+    .await
+    .context("could not insert user")?;
+
+    Ok(User {
+        data: id,
+        username: username.to_string(),
+        sub: format!("fake-sub-{}", id),
+        primary_email: None,
+    })
+}
+
+#[tracing::instrument(skip(txn, phf, password))]
+pub async fn register_user(
+    txn: &mut Transaction<'_, Postgres>,
+    phf: impl PasswordHasher,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let user = insert_user(txn.borrow_mut(), username).await?;
+
+    set_password(txn.borrow_mut(), phf, &user, password).await?;
+
+    Ok(user)
+}
+
+/// Import a user from another system, with the hash of their password and an
+/// already verified primary email.
+///
+/// The import happens in its own transaction, or in a savepoint when `conn`
+/// is a transaction, so that a failure leaves the rest of it usable.
+#[tracing::instrument(skip(conn, hashed_password))]
+pub async fn import_user(
+    conn: impl Acquire<'_, Database = Postgres>,
+    username: &str,
+    hashed_password: &str,
+    email: &str,
+    normalized_email: &str,
+) -> anyhow::Result<User<PostgresqlBackend>> {
+    let mut txn = conn.begin().await.context("could not start transaction")?;
+
+    let mut user = insert_user(&mut txn, username).await?;
+    insert_password_hash(&mut txn, &user, hashed_password).await?;
+
+    let email = add_user_email(&mut txn, &user, email, normalized_email).await?;
+    let email = mark_user_email_as_verified(&mut txn, email).await?;
+    set_user_email_as_primary(&mut txn, &user, &email).await?;
+    user.primary_email = Some(email);
+
+    txn.commit().await?;
+
+    Ok(user)
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn set_password(
+    executor: impl PgExecutor<'_>,
+    phf: impl PasswordHasher,
+    user: &User<PostgresqlBackend>,
+    password: &str,
+) -> anyhow::Result<()> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hashed_password = PasswordHash::generate(phf, password, salt.as_str())?;
+
+    insert_password_hash(executor, user, &hashed_password.to_string()).await
+}
+
+async fn insert_password_hash(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    hashed_password: &str,
+) -> anyhow::Result<()> {
+    sqlx::query_scalar!(
+        r#"
+            INSERT INTO user_passwords (user_id, hashed_password)
+            VALUES ($1, $2)
+        "#,
+        user.data,
+        hashed_password,
+    )
+    .execute(executor)
+    .instrument(info_span!("Save user credentials"))
+    .await
+    .context("could not insert user password")?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum PasswordChangeError {
+    #[error("current password is incorrect")]
+    InvalidCurrentPassword,
+
+    #[error(transparent)]
+    Policy(#[from] PasswordPolicyError),
+
+    #[error("failed to change password")]
+    Other(#[from] anyhow::Error),
+}
+
+/// Change the password of the user after verifying the current one, and end
+/// every other browser session and every client session of the user. The new
+/// password is hashed with `phf`, so that it uses the current hashing
+/// parameters
+#[tracing::instrument(skip_all, fields(session.id = session.data, user.id = session.user.data))]
+pub async fn change_password(
+    txn: &mut Transaction<'_, Postgres>,
+    phf: impl PasswordHasher,
+    policy: &PasswordPolicy,
+    session: &BrowserSession<PostgresqlBackend>,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), PasswordChangeError> {
+    let user = &session.user;
+
+    match verify_user_password(txn.borrow_mut(), user, current_password).await {
+        Ok(()) => {}
+        Err(AuthenticationError::Password(password_hash::Error::Password)) => {
+            return Err(PasswordChangeError::InvalidCurrentPassword)
+        }
+        Err(e) => return Err(anyhow::Error::from(e).into()),
+    }
+
+    policy.check(new_password)?;
+
+    set_password(txn.borrow_mut(), phf, user, new_password).await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE user_sessions
+            SET active = FALSE
+            WHERE user_id = $1
+              AND id != $2
+              AND active
+        "#,
+        user.data,
+        session.data,
+    )
+    .execute(txn.borrow_mut())
+    .instrument(info_span!("End other browser sessions"))
+    .await
+    .context("could not end other browser sessions")?;
+
+    revoke_all_sessions(&mut *txn, user)
+        .await
+        .context("could not end client sessions")?;
+
+    Ok(())
+}
+
+struct VerifiedEmailLookup {
+    user_id: i64,
+    user_username: String,
+    user_email_id: Option<i64>,
+    user_email: Option<String>,
+    user_email_created_at: Option<DateTime<Utc>>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+    verified_email: String,
+}
+
+impl TryInto<(User<PostgresqlBackend>, String)> for VerifiedEmailLookup {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_into(self) -> Result<(User<PostgresqlBackend>, String), Self::Error> {
+        let primary_email = match (
+            self.user_email_id,
+            self.user_email,
+            self.user_email_created_at,
+            self.user_email_confirmed_at,
+        ) {
+            (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
+                data: id,
+                email,
+                created_at,
+                confirmed_at,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError),
+        };
+
+        let user = User {
+            data: self.user_id,
+            username: self.user_username,
+            sub: format!("fake-sub-{}", self.user_id),
+            primary_email,
+        };
+
+        Ok((user, self.verified_email))
+    }
+}
+
+/// Find the users who verified an email address, given its normalized form,
+/// along with the address as each of them added it. Depending on the email
+/// sharing policy, more than one user may have verified the same address
+#[tracing::instrument(skip(executor))]
+pub async fn lookup_users_by_verified_email(
+    executor: impl PgExecutor<'_>,
+    normalized_email: &str,
+) -> Result<Vec<(User<PostgresqlBackend>, String)>, UserLookupError> {
+    let res = sqlx::query_as!(
+        VerifiedEmailLookup,
+        r#"
+            SELECT
+                u.id            AS user_id,
+                u.username      AS user_username,
+                pe.id           AS "user_email_id?",
+                pe.email        AS "user_email?",
+                pe.created_at   AS "user_email_created_at?",
+                pe.confirmed_at AS "user_email_confirmed_at?",
+                ue.email        AS verified_email
+            FROM user_emails ue
+
+            INNER JOIN users u
+              ON u.id = ue.user_id
+
+            LEFT JOIN user_emails pe
+              ON pe.id = u.primary_email_id
+
+            WHERE ue.normalized_email = $1
+              AND ue.confirmed_at IS NOT NULL
+              AND u.deleted_at IS NULL
+        "#,
+        normalized_email,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Lookup users by verified email"))
+    .await?;
+
+    let owners = res
+        .into_iter()
+        .map(TryInto::try_into)
+        .collect::<Result<Vec<_>, DatabaseInconsistencyError>>()?;
+
+    Ok(owners)
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn add_password_reset(
+    executor: impl PgExecutor<'_>,
+    user: User<PostgresqlBackend>,
+    token: String,
+) -> anyhow::Result<PasswordReset<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO user_password_resets (user_id, token)
+            VALUES ($1, $2)
+            RETURNING id, created_at
+        "#,
+        user.data,
+        token,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Add password reset"))
+    .await
+    .context("could not insert password reset")?;
+
+    Ok(PasswordReset {
+        data: res.id,
+        token,
+        user,
+        created_at: res.created_at,
+        consumed_at: None,
+    })
+}
+
+struct PasswordResetLookup {
+    reset_id: i64,
+    reset_token: String,
+    reset_created_at: DateTime<Utc>,
+    reset_consumed_at: Option<DateTime<Utc>>,
+    user_id: i64,
+    user_username: String,
+    user_email_id: Option<i64>,
+    user_email: Option<String>,
+    user_email_created_at: Option<DateTime<Utc>>,
+    user_email_confirmed_at: Option<DateTime<Utc>>,
+}
+
+impl TryInto<PasswordReset<PostgresqlBackend>> for PasswordResetLookup {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_into(self) -> Result<PasswordReset<PostgresqlBackend>, Self::Error> {
+        let primary_email = match (
+            self.user_email_id,
+            self.user_email,
+            self.user_email_created_at,
+            self.user_email_confirmed_at,
+        ) {
+            (Some(id), Some(email), Some(created_at), confirmed_at) => Some(UserEmail {
+                data: id,
+                email,
+                created_at,
+                confirmed_at,
+            }),
+            (None, None, None, None) => None,
+            _ => return Err(DatabaseInconsistencyError),
+        };
+
+        let user = User {
+            data: self.user_id,
+            username: self.user_username,
+            sub: format!("fake-sub-{}", self.user_id),
+            primary_email,
+        };
+
+        Ok(PasswordReset {
+            data: self.reset_id,
+            token: self.reset_token,
+            user,
+            created_at: self.reset_created_at,
+            consumed_at: self.reset_consumed_at,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PasswordResetError {
+    #[error("password reset link is invalid or expired")]
+    InvalidToken,
+
+    #[error(transparent)]
+    Policy(#[from] PasswordPolicyError),
+
+    #[error("failed to reset password")]
+    Other(#[from] anyhow::Error),
+}
+
+/// Set a new password using the token of a password reset, and end every
+/// browser and client session of the user. The token can only be used once
+#[tracing::instrument(skip_all)]
+pub async fn reset_password(
+    txn: &mut Transaction<'_, Postgres>,
+    phf: impl PasswordHasher,
+    policy: &PasswordPolicy,
+    token: &str,
+    new_password: &str,
+) -> Result<User<PostgresqlBackend>, PasswordResetError> {
+    // Check the policy first, so that the link can still be used with a
+    // stronger password
+    policy.check(new_password)?;
+
+    let res = sqlx::query_as!(
+        PasswordResetLookup,
+        r#"
+            SELECT
+                pr.id           AS reset_id,
+                pr.token        AS reset_token,
+                pr.created_at   AS reset_created_at,
+                pr.consumed_at  AS reset_consumed_at,
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM user_password_resets pr
+
+            INNER JOIN users u
+              ON u.id = pr.user_id
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE pr.token = $1
+              AND u.deleted_at IS NULL
+        "#,
+        token,
+    )
+    .fetch_optional(txn.borrow_mut())
+    .instrument(info_span!("Lookup password reset"))
+    .await
+    .context("could not lookup password reset")?;
+
+    let reset: PasswordReset<PostgresqlBackend> = match res {
+        Some(res) => res.try_into().context("could not lookup password reset")?,
+        None => return Err(PasswordResetError::InvalidToken),
+    };
+
+    if !reset.is_valid(Utc::now()) {
+        return Err(PasswordResetError::InvalidToken);
+    }
+
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_password_resets
+            SET consumed_at = NOW()
+            WHERE id = $1
+              AND consumed_at IS NULL
+        "#,
+        reset.data,
+    )
+    .execute(txn.borrow_mut())
+    .instrument(info_span!("Consume password reset"))
+    .await
+    .context("could not consume password reset")?;
+
+    // Another request used the token in the meantime
+    if res.rows_affected() != 1 {
+        return Err(PasswordResetError::InvalidToken);
+    }
+
+    let user = reset.user;
+    set_password(txn.borrow_mut(), phf, &user, new_password).await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE user_sessions
+            SET active = FALSE
+            WHERE user_id = $1
+              AND active
+        "#,
+        user.data,
+    )
+    .execute(txn.borrow_mut())
+    .instrument(info_span!("End browser sessions"))
+    .await
+    .context("could not end browser sessions")?;
+
+    revoke_all_sessions(&mut *txn, &user)
+        .await
+        .context("could not end client sessions")?;
+
+    Ok(user)
+}
+
+#[tracing::instrument(skip_all, fields(session.id = session.data))]
+pub async fn end_session(
+    executor: impl PgExecutor<'_>,
+    session: &BrowserSession<PostgresqlBackend>,
+) -> anyhow::Result<()> {
+    let res = sqlx::query!(
+        "UPDATE user_sessions SET active = FALSE WHERE id = $1",
+        session.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("End session"))
+    .await
+    .context("could not end session")?;
+
+    match res.rows_affected() {
+        1 => Ok(()),
+        0 => Err(anyhow::anyhow!("no row affected")),
+        _ => Err(anyhow::anyhow!("too many row affected")),
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("failed to lookup user")]
+pub enum UserLookupError {
+    Database(#[from] sqlx::Error),
+    Inconsistency(#[from] DatabaseInconsistencyError),
+}
+
+impl UserLookupError {
+    #[must_use]
+    pub fn not_found(&self) -> bool {
+        matches!(self, Self::Database(sqlx::Error::RowNotFound))
+    }
+}
+
+#[tracing::instrument(skip(executor))]
+pub async fn lookup_user_by_username(
+    executor: impl PgExecutor<'_>,
+    username: &str,
+) -> Result<User<PostgresqlBackend>, UserLookupError> {
+    let res = sqlx::query_as!(
+        UserLookup,
+        r#"
+            SELECT 
+                u.id            AS user_id, 
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM users u
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE u.username = $1
+              AND u.deleted_at IS NULL
+        "#,
+        username,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Fetch user"))
+    .await?;
+
+    Ok(res.try_into()?)
+}
+
+/// The ID of a user, from the `sub` claim it gets in the ID tokens
+#[must_use]
+pub fn user_id_from_sub(sub: &str) -> Option<i64> {
+    sub.strip_prefix("fake-sub-")?.parse().ok()
+}
+
+/// Lookup an active user by the `sub` claim it gets in the ID tokens
+#[tracing::instrument(skip(executor))]
+pub async fn lookup_user_by_sub(
+    executor: impl PgExecutor<'_>,
+    sub: &str,
+) -> Result<User<PostgresqlBackend>, UserLookupError> {
+    let id = user_id_from_sub(sub).ok_or(sqlx::Error::RowNotFound)?;
+
+    let res = sqlx::query_as!(
+        UserLookup,
+        r#"
+            SELECT
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM users u
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE u.id = $1
+              AND u.deleted_at IS NULL
+        "#,
+        id,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Fetch user"))
+    .await?;
+
+    Ok(res.try_into()?)
+}
+
+/// A user, as listed by [`search_users`]
+#[derive(Debug, Clone)]
+pub struct UserListItem {
+    /// The user, without its primary email
+    pub user: User<PostgresqlBackend>,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+impl UserListItem {
+    /// The position of this user in the list
+    #[must_use]
+    pub fn cursor(&self) -> Cursor {
+        Cursor::new(self.created_at, self.user.data)
+    }
+}
+
+/// A `LIKE` pattern matching the strings which contain `query`
+fn contains_pattern(query: &str) -> String {
+    let mut pattern = String::with_capacity(query.len() + 2);
+    pattern.push('%');
+    for c in query.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// List the users, deleted ones included, in the order they were created.
+///
+/// With a `query`, only the users whose username or one of whose emails
+/// contains it, case-insensitively, are listed. The list starts after the
+/// `after` cursor, and holds at most `limit` users.
+#[tracing::instrument(skip(executor))]
+pub async fn search_users(
+    executor: impl PgExecutor<'_>,
+    query: Option<&str>,
+    after: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<UserListItem>, sqlx::Error> {
+    let pattern = query.map(contains_pattern);
+
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                u.id            AS "user_id",
+                u.username      AS "user_username",
+                u.created_at    AS "user_created_at",
+                u.deleted_at    AS "user_deleted_at"
+            FROM users u
+            WHERE (
+                $1::TEXT IS NULL
+                OR u.username ILIKE $1
+                OR EXISTS (
+                    SELECT 1
+                    FROM user_emails ue
+                    WHERE ue.user_id = u.id
+                      AND ue.email ILIKE $1
+                )
+            )
+              AND ($2::TIMESTAMPTZ IS NULL OR (u.created_at, u.id) > ($2, $3))
+            ORDER BY u.created_at, u.id
+            LIMIT $4
+        "#,
+        pattern,
+        after.map(|cursor| cursor.created_at),
+        after.map(|cursor| cursor.id),
+        limit,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Search users"))
+    .await?;
+
+    Ok(res
+        .into_iter()
+        .map(|row| UserListItem {
+            user: User {
+                data: row.user_id,
+                username: row.user_username,
+                sub: format!("fake-sub-{}", row.user_id),
+                primary_email: None,
+            },
+            created_at: row.user_created_at,
+            deleted_at: row.user_deleted_at,
+        })
+        .collect())
+}
+
+/// Lookup the user who verified an email, by its normalized form. Returns
+/// `None` if nobody verified it, or if several users did.
+#[tracing::instrument(skip(executor))]
+pub async fn lookup_user_by_verified_email(
+    executor: impl PgExecutor<'_>,
+    normalized_email: &str,
+) -> Result<Option<User<PostgresqlBackend>>, UserLookupError> {
+    let mut res = sqlx::query_as!(
+        UserLookup,
+        r#"
+            SELECT
+                u.id            AS user_id,
+                u.username      AS user_username,
+                ue.id           AS "user_email_id?",
+                ue.email        AS "user_email?",
+                ue.created_at   AS "user_email_created_at?",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM user_emails e
+
+            INNER JOIN users u
+              ON u.id = e.user_id
+
+            LEFT JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE e.normalized_email = $1
+              AND e.confirmed_at IS NOT NULL
+              AND u.deleted_at IS NULL
+            LIMIT 2
+        "#,
+        normalized_email,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Lookup user by verified email"))
+    .await?;
+
+    if res.len() == 1 {
+        Ok(Some(res.remove(0).try_into()?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether the user has a password, or can only log in through an upstream
+/// provider
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn user_has_password(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_passwords WHERE user_id = $1
+            ) AS "exists!"
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Check user password"))
+    .await
 }
 
 pub async fn username_exists(
@@ -492,6 +1840,38 @@ pub async fn username_exists(
     .await
 }
 
+#[derive(Debug, Error)]
+pub enum UsernameError {
+    #[error(transparent)]
+    Policy(#[from] UsernamePolicyError),
+
+    #[error("username is already taken")]
+    Taken,
+
+    #[error("could not check if the username is taken")]
+    Fetch(#[from] sqlx::Error),
+}
+
+/// Check that a new user can be created with this username: it has to meet
+/// the policy, and not be taken yet.
+///
+/// The unique constraint on the usernames still applies when inserting the
+/// user, in case the same username is registered concurrently.
+#[tracing::instrument(skip(executor, policy))]
+pub async fn check_username_available(
+    executor: impl PgExecutor<'_>,
+    policy: &UsernamePolicy,
+    localpart: &str,
+) -> Result<(), UsernameError> {
+    policy.check(localpart)?;
+
+    if username_exists(executor, localpart).await? {
+        return Err(UsernameError::Taken);
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 struct UserEmailLookup {
     user_email_id: i64,
@@ -539,12 +1919,71 @@ pub async fn get_user_emails(
     Ok(res.into_iter().map(Into::into).collect())
 }
 
+/// Fetch the primary email of each user in a single query, in the same order.
+/// Users without a primary email get `None`
+#[tracing::instrument(skip_all, fields(user.count = users.len()))]
+pub async fn get_primary_emails_for_users(
+    executor: impl PgExecutor<'_>,
+    users: &[User<PostgresqlBackend>],
+) -> Result<Vec<Option<UserEmail<PostgresqlBackend>>>, anyhow::Error> {
+    let ids: Vec<i64> = users.iter().map(|user| user.data).collect();
+
+    let res = sqlx::query!(
+        r#"
+            SELECT
+                u.id            AS "user_id!",
+                ue.id           AS "user_email_id!",
+                ue.email        AS "user_email!",
+                ue.created_at   AS "user_email_created_at!",
+                ue.confirmed_at AS "user_email_confirmed_at?"
+            FROM users u
+
+            INNER JOIN user_emails ue
+              ON ue.id = u.primary_email_id
+
+            WHERE u.id = ANY($1)
+        "#,
+        &ids[..],
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Fetch primary emails of users"))
+    .await?;
+
+    let emails: HashMap<i64, UserEmail<PostgresqlBackend>> = res
+        .into_iter()
+        .map(|row| {
+            let email = UserEmail {
+                data: row.user_email_id,
+                email: row.user_email,
+                created_at: row.user_email_created_at,
+                confirmed_at: row.user_email_confirmed_at,
+            };
+            (row.user_id, email)
+        })
+        .collect();
+
+    Ok(ids.iter().map(|id| emails.get(id).cloned()).collect())
+}
+
+#[derive(Debug, Error)]
+#[error("failed to lookup user email")]
+pub enum UserEmailLookupError {
+    Database(#[from] sqlx::Error),
+}
+
+impl UserEmailLookupError {
+    #[must_use]
+    pub fn not_found(&self) -> bool {
+        matches!(self, Self::Database(sqlx::Error::RowNotFound))
+    }
+}
+
 #[tracing::instrument(skip_all, fields(user.id = user.data, %user.username, email.id = id))]
 pub async fn get_user_email(
     executor: impl PgExecutor<'_>,
     user: &User<PostgresqlBackend>,
     id: i64,
-) -> Result<UserEmail<PostgresqlBackend>, anyhow::Error> {
+) -> Result<UserEmail<PostgresqlBackend>, UserEmailLookupError> {
     let res = sqlx::query_as!(
         UserEmailLookup,
         r#"
@@ -573,12 +2012,13 @@ pub async fn add_user_email(
     executor: impl PgExecutor<'_>,
     user: &User<PostgresqlBackend>,
     email: &str,
+    normalized_email: &str,
 ) -> anyhow::Result<UserEmail<PostgresqlBackend>> {
     let res = sqlx::query_as!(
         UserEmailLookup,
         r#"
-            INSERT INTO user_emails (user_id, email)
-            VALUES ($1, $2)
+            INSERT INTO user_emails (user_id, email, normalized_email)
+            VALUES ($1, $2, $3)
             RETURNING 
                 id           AS user_email_id,
                 email        AS user_email,
@@ -587,6 +2027,7 @@ pub async fn add_user_email(
         "#,
         user.data,
         email,
+        normalized_email,
     )
     .fetch_one(executor)
     .instrument(info_span!("Add user email"))
@@ -596,9 +2037,56 @@ pub async fn add_user_email(
     Ok(res.into())
 }
 
-#[tracing::instrument(skip(executor))]
+/// Check if the user already has an email address, given its normalized form
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data, %user.username))]
+pub async fn user_email_exists(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    normalized_email: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_emails
+                WHERE normalized_email = $1
+                  AND user_id = $2
+            ) AS "exists!"
+        "#,
+        normalized_email,
+        user.data,
+    )
+    .fetch_one(executor)
+    .await
+}
+
+/// Check if an email address was already verified by a user other than the
+/// given one, given its normalized form
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data, %user.username))]
+pub async fn is_email_verified_by_other_user(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    normalized_email: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS(
+                SELECT 1 FROM user_emails
+                WHERE normalized_email = $1
+                  AND user_id != $2
+                  AND confirmed_at IS NOT NULL
+            ) AS "exists!"
+        "#,
+        normalized_email,
+        user.data,
+    )
+    .fetch_one(executor)
+    .await
+}
+
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data, %user.username))]
 pub async fn set_user_email_as_primary(
     executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
     email: &UserEmail<PostgresqlBackend>,
 ) -> anyhow::Result<()> {
     sqlx::query!(
@@ -607,9 +2095,11 @@ pub async fn set_user_email_as_primary(
             SET primary_email_id = user_emails.id 
             FROM user_emails
             WHERE user_emails.id = $1
+              AND users.id       = $2
               AND users.id       = user_emails.user_id
         "#,
         email.data,
+        user.data,
     )
     .execute(executor)
     .instrument(info_span!("Add user email"))
@@ -619,17 +2109,20 @@ pub async fn set_user_email_as_primary(
     Ok(())
 }
 
-#[tracing::instrument(skip(executor))]
+#[tracing::instrument(skip(executor, user), fields(user.id = user.data, %user.username))]
 pub async fn remove_user_email(
     executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
     email: UserEmail<PostgresqlBackend>,
 ) -> anyhow::Result<()> {
     sqlx::query!(
         r#"
             DELETE FROM user_emails
             WHERE user_emails.id = $1
+              AND user_emails.user_id = $2
         "#,
         email.data,
+        user.data,
     )
     .execute(executor)
     .instrument(info_span!("Remove user email"))
@@ -644,7 +2137,7 @@ pub async fn lookup_user_email(
     executor: impl PgExecutor<'_>,
     user: &User<PostgresqlBackend>,
     email: &str,
-) -> anyhow::Result<UserEmail<PostgresqlBackend>> {
+) -> Result<UserEmail<PostgresqlBackend>, UserEmailLookupError> {
     let res = sqlx::query_as!(
         UserEmailLookup,
         r#"
@@ -663,8 +2156,7 @@ pub async fn lookup_user_email(
     )
     .fetch_one(executor)
     .instrument(info_span!("Lookup user email"))
-    .await
-    .context("could not lookup user email")?;
+    .await?;
 
     Ok(res.into())
 }
@@ -731,7 +2223,7 @@ struct UserEmailVerificationLookup {
     verification_consumed_at: Option<DateTime<Utc>>,
 }
 
-#[tracing::instrument(skip(executor))]
+#[tracing::instrument(skip(executor, code))]
 pub async fn lookup_user_email_verification_code(
     executor: impl PgExecutor<'_>,
     email: UserEmail<PostgresqlBackend>,
@@ -782,7 +2274,7 @@ pub async fn lookup_user_email_verification_code(
     })
 }
 
-#[tracing::instrument(skip(executor))]
+#[tracing::instrument(skip(executor, verification), fields(verification.id = verification.data))]
 pub async fn consume_email_verification(
     executor: impl PgExecutor<'_>,
     mut verification: UserEmailVerification<PostgresqlBackend>,
@@ -841,3 +2333,1034 @@ pub async fn add_user_email_verification_code(
 
     Ok(verification)
 }
+
+/// Record that a verification email is being sent for this email, unless one
+/// was already sent less than `cooldown` ago
+///
+/// Returns `false` if a verification email was sent too recently and another
+/// one should not be sent yet
+#[tracing::instrument(skip(executor, email), fields(email.id = email.data, %email.email))]
+pub async fn mark_user_email_verification_sent(
+    executor: impl PgExecutor<'_>,
+    email: &UserEmail<PostgresqlBackend>,
+    cooldown: chrono::Duration,
+) -> anyhow::Result<bool> {
+    let cooldown = PgInterval::try_from(cooldown)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    // Doing the check and the update in one query makes concurrent requests
+    // wait on the row lock, so only one of them goes through
+    let res = sqlx::query!(
+        r#"
+            UPDATE user_emails
+            SET last_verification_sent_at = NOW()
+            WHERE id = $1
+              AND (last_verification_sent_at IS NULL
+                   OR last_verification_sent_at + $2 < NOW())
+        "#,
+        email.data,
+        cooldown,
+    )
+    .execute(executor)
+    .instrument(info_span!("Mark user email verification as sent"))
+    .await
+    .context("could not mark user email verification as sent")?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Delete the email addresses which were not verified within `max_age`, along
+/// with their verification codes. Verified emails are never deleted
+///
+/// Returns how many emails were deleted
+#[tracing::instrument(skip(executor))]
+pub async fn purge_unverified_emails(
+    executor: impl PgExecutor<'_>,
+    max_age: std::time::Duration,
+) -> anyhow::Result<u64> {
+    let max_age = PgInterval::try_from(max_age)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    // The verification codes are deleted by cascade
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM user_emails
+            WHERE confirmed_at IS NULL
+              AND created_at + $1 < NOW()
+        "#,
+        max_age,
+    )
+    .execute(executor)
+    .instrument(info_span!("Purge unverified emails"))
+    .await
+    .context("could not purge unverified emails")?;
+
+    Ok(res.rows_affected())
+}
+
+/// Schedule the erasure of the user and all their data, `delay` from now. The
+/// user can cancel it with [`cancel_user_erasure`] until then
+///
+/// Returns when the user will be erased
+#[tracing::instrument(skip_all, fields(user.id = user.data, %user.username))]
+pub async fn schedule_user_erasure(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    delay: chrono::Duration,
+) -> anyhow::Result<DateTime<Utc>> {
+    let delay = PgInterval::try_from(delay)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let res = sqlx::query_scalar!(
+        r#"
+            UPDATE users
+            SET erase_after = NOW() + $2
+            WHERE id = $1
+              AND erase_after IS NULL
+            RETURNING erase_after AS "erase_after!"
+        "#,
+        user.data,
+        delay,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Schedule user erasure"))
+    .await
+    .context("could not schedule user erasure")?;
+
+    res.context("the erasure of the user is already scheduled")
+}
+
+/// When the user will be erased, if they asked for it
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn lookup_user_erasure(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT erase_after
+            FROM users
+            WHERE id = $1
+        "#,
+        user.data,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Lookup user erasure"))
+    .await
+}
+
+/// Cancel the scheduled erasure of the user. Returns `false` if there was none,
+/// or if it is already due
+#[tracing::instrument(skip_all, fields(user.id = user.data, %user.username))]
+pub async fn cancel_user_erasure(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query!(
+        r#"
+            UPDATE users
+            SET erase_after = NULL
+            WHERE id = $1
+              AND erase_after > NOW()
+        "#,
+        user.data,
+    )
+    .execute(executor)
+    .instrument(info_span!("Cancel user erasure"))
+    .await?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Erase the users whose scheduled erasure is due.
+///
+/// Their emails, password, browser sessions, OAuth 2.0 and compat sessions
+/// with their tokens, and upstream links are deleted by cascade. The users are
+/// erased one at a time, so that one which can't be erased doesn't hold back
+/// the others.
+///
+/// Returns how many users were erased
+#[tracing::instrument(skip_all)]
+pub async fn erase_scheduled_users(conn: &mut PgConnection) -> anyhow::Result<u64> {
+    let user_ids = sqlx::query_scalar!(
+        r#"
+            SELECT id
+            FROM users
+            WHERE erase_after <= NOW()
+        "#
+    )
+    .fetch_all(&mut *conn)
+    .instrument(info_span!("Fetch users to erase"))
+    .await
+    .context("could not fetch users to erase")?;
+
+    let mut count = 0;
+    for user_id in user_ids {
+        let res = sqlx::query!(
+            r#"
+                DELETE FROM users
+                WHERE id = $1
+                  AND erase_after <= NOW()
+            "#,
+            user_id,
+        )
+        .execute(&mut *conn)
+        .instrument(info_span!("Erase user", user.id = user_id))
+        .await;
+
+        match res {
+            Ok(res) => count += res.rows_affected(),
+            Err(error) => error!(user.id = user_id, ?error, "Could not erase user"),
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use mas_data_model::Device;
+    use rand::RngCore;
+    use sqlx::{
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Connection,
+    };
+
+    use super::*;
+    use crate::{
+        compat::{
+            add_compat_access_token, add_compat_refresh_token, lookup_active_compat_access_token,
+            lookup_active_compat_refresh_token, start_compat_session,
+        },
+        oauth2::{
+            access_token::lookup_active_access_token, insert_test_client_session,
+            refresh_token::lookup_active_refresh_token,
+        },
+    };
+
+    fn username_policy() -> UsernamePolicy {
+        UsernamePolicy {
+            reserved: vec!["admin".to_owned(), "support".to_owned()],
+            ..UsernamePolicy::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn usernames_against_the_policy() {
+        // The policy is checked before querying the database, so this pool
+        // never connects
+        let pool = PgPoolOptions::new().connect_lazy_with(PgConnectOptions::new().host("nowhere"));
+        let policy = username_policy();
+
+        for reserved in ["admin", "support"] {
+            assert!(matches!(
+                check_username_available(&pool, &policy, reserved).await,
+                Err(UsernameError::Policy(UsernamePolicyError::Reserved))
+            ));
+        }
+
+        for invalid in ["", "Alice", "al ice", "@alice:example.com"] {
+            assert!(
+                matches!(
+                    check_username_available(&pool, &policy, invalid).await,
+                    Err(UsernameError::Policy(
+                        UsernamePolicyError::TooShort(_) | UsernamePolicyError::InvalidCharacters
+                    ))
+                ),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn taken_username() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+        let policy = username_policy();
+
+        check_username_available(&mut txn, &policy, "taken-username")
+            .await
+            .unwrap();
+
+        insert_user(&mut txn, "taken-username").await.unwrap();
+
+        assert!(matches!(
+            check_username_available(&mut txn, &policy, "taken-username").await,
+            Err(UsernameError::Taken)
+        ));
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn session_expiration() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = insert_user(&mut txn, "expiring-alice").await.unwrap();
+        let session = start_session(&mut txn, user).await.unwrap();
+
+        let activity = lookup_session_activity(&mut txn, session.data)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(activity.created_at, session.created_at);
+        assert_eq!(activity.last_active_at, session.created_at);
+
+        // The activity is only recorded once a minute
+        record_session_activity(&mut txn, session.data)
+            .await
+            .unwrap();
+        assert_eq!(
+            lookup_session_activity(&mut txn, session.data)
+                .await
+                .unwrap(),
+            Some(activity)
+        );
+
+        assert!(expire_session(&mut txn, session.data).await.unwrap());
+        assert!(!expire_session(&mut txn, session.data).await.unwrap());
+        assert!(lookup_session_activity(&mut txn, session.data)
+            .await
+            .unwrap()
+            .is_none());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn user_by_verified_email() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = insert_user(&mut txn, "verified-alice").await.unwrap();
+        let email = add_user_email(
+            &mut txn,
+            &user,
+            "Verified-Alice@example.com",
+            "verified-alice@example.com",
+        )
+        .await
+        .unwrap();
+
+        // Unverified emails don't match
+        assert!(
+            lookup_user_by_verified_email(&mut txn, "verified-alice@example.com")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        mark_user_email_as_verified(&mut txn, email).await.unwrap();
+        let found = lookup_user_by_verified_email(&mut txn, "verified-alice@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.data, user.data);
+        assert!(!user_has_password(&mut txn, &user).await.unwrap());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn deactivation_and_reactivation() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = register_user(&mut txn, Argon2::default(), "deactivated-alice", "hunter2")
+            .await
+            .unwrap();
+        assert_eq!(
+            lookup_user_by_sub(&mut txn, &user.sub).await.unwrap().data,
+            user.data
+        );
+
+        let session = start_compat_session(&mut txn, user.clone(), Device::generate(&mut OsRng))
+            .await
+            .unwrap();
+        add_compat_access_token(&mut txn, &session, "deactivated-token".to_owned(), None)
+            .await
+            .unwrap();
+        lookup_active_compat_access_token(&mut txn, "deactivated-token")
+            .await
+            .unwrap();
+
+        // Deactivation revokes the tokens and prevents logging in
+        soft_delete_user(&mut txn, &user).await.unwrap();
+        assert!(
+            lookup_active_compat_access_token(&mut txn, "deactivated-token")
+                .await
+                .is_err()
+        );
+        assert!(lookup_user_by_sub(&mut txn, &user.sub)
+            .await
+            .unwrap_err()
+            .not_found());
+        assert!(login(&mut txn, "deactivated-alice", "hunter2")
+            .await
+            .is_err());
+
+        // Reactivation restores logging in, but not the revoked tokens
+        assert!(reactivate_user(&mut txn, user.data).await.unwrap());
+        assert!(!reactivate_user(&mut txn, user.data).await.unwrap());
+        login(&mut txn, "deactivated-alice", "hunter2")
+            .await
+            .unwrap();
+        assert!(
+            lookup_active_compat_access_token(&mut txn, "deactivated-token")
+                .await
+                .is_err()
+        );
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn concurrent_login_attempts_are_serialized() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut other_conn = PgConnection::connect(&url).await.unwrap();
+
+        // Both connections have to see the user, so it is committed, under a
+        // name unique to this run
+        let username = format!("locked-{:08x}", OsRng.next_u32());
+        let user = insert_user(&mut conn, &username).await.unwrap();
+
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+        let attempts = get_login_attempts(&mut txn, &user).await.unwrap();
+
+        // A concurrent login can't read the attempts until the first one saved
+        // its failure
+        let mut other_txn = Connection::begin(&mut other_conn).await.unwrap();
+        sqlx::query("SET LOCAL lock_timeout = '100ms'")
+            .execute(&mut other_txn)
+            .await
+            .unwrap();
+        assert!(get_login_attempts(&mut other_txn, &user).await.is_err());
+        other_txn.rollback().await.unwrap();
+
+        set_login_attempts(&mut txn, &user, attempts.record_failure(Utc::now()))
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        let mut other_txn = Connection::begin(&mut other_conn).await.unwrap();
+        let attempts = get_login_attempts(&mut other_txn, &user).await.unwrap();
+        assert_eq!(attempts.failed_count, 1);
+        other_txn.rollback().await.unwrap();
+
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user.data)
+            .execute(&mut conn)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn undeletion_within_the_grace_period() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+        let grace_period = chrono::Duration::days(30);
+
+        let user = register_user(&mut txn, Argon2::default(), "undeleted-alice", "hunter2")
+            .await
+            .unwrap();
+        let session = start_compat_session(&mut txn, user.clone(), Device::generate(&mut OsRng))
+            .await
+            .unwrap();
+        add_compat_access_token(&mut txn, &session, "undeleted-token".to_owned(), None)
+            .await
+            .unwrap();
+
+        // A deleted user is hidden, can't log in, and loses its tokens
+        soft_delete_user(&mut txn, &user).await.unwrap();
+        assert!(soft_delete_user(&mut txn, &user).await.is_err());
+        assert!(lookup_user_by_username(&mut txn, "undeleted-alice")
+            .await
+            .unwrap_err()
+            .not_found());
+        assert!(login(&mut txn, "undeleted-alice", "hunter2").await.is_err());
+        assert!(
+            lookup_active_compat_access_token(&mut txn, "undeleted-token")
+                .await
+                .is_err()
+        );
+
+        // The grace period can't be shorter than the minimum
+        assert!(
+            undelete_user(&mut txn, "undeleted-alice", chrono::Duration::zero())
+                .await
+                .is_err()
+        );
+
+        // Within the grace period, the user can log in again, but the tokens
+        // stay revoked
+        undelete_user(&mut txn, "undeleted-alice", grace_period)
+            .await
+            .unwrap();
+        assert!(undelete_user(&mut txn, "undeleted-alice", grace_period)
+            .await
+            .is_err());
+        login(&mut txn, "undeleted-alice", "hunter2").await.unwrap();
+        assert!(
+            lookup_active_compat_access_token(&mut txn, "undeleted-token")
+                .await
+                .is_err()
+        );
+
+        // Past the grace period, the user stays deleted
+        soft_delete_user(&mut txn, &user).await.unwrap();
+        sqlx::query("UPDATE users SET deleted_at = NOW() - INTERVAL '31 days' WHERE id = $1")
+            .bind(user.data)
+            .execute(&mut txn)
+            .await
+            .unwrap();
+        assert!(undelete_user(&mut txn, "undeleted-alice", grace_period)
+            .await
+            .is_err());
+        assert!(login(&mut txn, "undeleted-alice", "hunter2").await.is_err());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn revoking_all_sessions_rejects_their_tokens() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = insert_user(&mut txn, "revoked-alice").await.unwrap();
+        let browser_session = start_session(&mut txn, user.clone()).await.unwrap();
+
+        let oauth2_session =
+            insert_test_client_session(&mut txn, browser_session.data, "revoked-alice-client")
+                .await;
+        let access_token_id: i64 = sqlx::query_scalar(
+            r#"
+                INSERT INTO oauth2_access_tokens (oauth2_session_id, token, expires_after)
+                VALUES ($1, 'revoked-access-token', 3600)
+                RETURNING id
+            "#,
+        )
+        .bind(oauth2_session)
+        .fetch_one(&mut txn)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"
+                INSERT INTO oauth2_refresh_tokens
+                    (oauth2_session_id, oauth2_access_token_id, token)
+                VALUES ($1, $2, 'revoked-refresh-token')
+            "#,
+        )
+        .bind(oauth2_session)
+        .bind(access_token_id)
+        .execute(&mut txn)
+        .await
+        .unwrap();
+
+        let compat_session =
+            start_compat_session(&mut txn, user.clone(), Device::generate(&mut OsRng))
+                .await
+                .unwrap();
+        let compat_access_token = add_compat_access_token(
+            &mut txn,
+            &compat_session,
+            "revoked-compat-access-token".to_owned(),
+            None,
+        )
+        .await
+        .unwrap();
+        add_compat_refresh_token(
+            &mut txn,
+            &compat_session,
+            &compat_access_token,
+            "revoked-compat-refresh-token".to_owned(),
+        )
+        .await
+        .unwrap();
+
+        // Every token is valid before
+        lookup_active_access_token(&mut txn, "revoked-access-token")
+            .await
+            .unwrap();
+        lookup_active_refresh_token(&mut txn, "revoked-refresh-token")
+            .await
+            .unwrap();
+        lookup_active_compat_access_token(&mut txn, "revoked-compat-access-token")
+            .await
+            .unwrap();
+        lookup_active_compat_refresh_token(&mut txn, "revoked-compat-refresh-token")
+            .await
+            .unwrap();
+        assert_eq!(get_active_sessions(&mut txn, &user).await.unwrap().len(), 2);
+
+        assert_eq!(revoke_all_sessions(&mut txn, &user).await.unwrap(), 2);
+
+        // ...and none of them after
+        assert!(lookup_active_access_token(&mut txn, "revoked-access-token")
+            .await
+            .unwrap_err()
+            .not_found());
+        assert!(
+            lookup_active_refresh_token(&mut txn, "revoked-refresh-token")
+                .await
+                .unwrap_err()
+                .not_found()
+        );
+        assert!(
+            lookup_active_compat_access_token(&mut txn, "revoked-compat-access-token")
+                .await
+                .unwrap_err()
+                .not_found()
+        );
+        assert!(
+            lookup_active_compat_refresh_token(&mut txn, "revoked-compat-refresh-token")
+                .await
+                .unwrap_err()
+                .not_found()
+        );
+        assert!(get_active_sessions(&mut txn, &user)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(revoke_all_sessions(&mut txn, &user).await.unwrap(), 0);
+
+        // The browser session is not a client session, and is kept
+        assert!(lookup_session_activity(&mut txn, browser_session.data)
+            .await
+            .unwrap()
+            .is_some());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn purge_stale_unverified_emails() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = insert_user(&mut txn, "purged-alice").await.unwrap();
+        let stale = add_user_email(&mut txn, &user, "stale@example.com", "stale@example.com")
+            .await
+            .unwrap();
+        let fresh = add_user_email(&mut txn, &user, "fresh@example.com", "fresh@example.com")
+            .await
+            .unwrap();
+        let verified = add_user_email(
+            &mut txn,
+            &user,
+            "verified@example.com",
+            "verified@example.com",
+        )
+        .await
+        .unwrap();
+
+        for (email, days) in [(&stale, 10), (&fresh, 1), (&verified, 10)] {
+            sqlx::query(
+                "UPDATE user_emails SET created_at = NOW() - $2 * INTERVAL '1 day' WHERE id = $1",
+            )
+            .bind(email.data)
+            .bind(f64::from(days))
+            .execute(&mut txn)
+            .await
+            .unwrap();
+        }
+
+        add_user_email_verification_code(&mut txn, stale.clone(), "stale-code".to_owned())
+            .await
+            .unwrap();
+        mark_user_email_as_verified(&mut txn, verified.clone())
+            .await
+            .unwrap();
+
+        let purged = purge_unverified_emails(&mut txn, std::time::Duration::from_secs(7 * 86400))
+            .await
+            .unwrap();
+        assert!(purged >= 1);
+
+        assert!(lookup_user_email_by_id(&mut txn, &user, stale.data)
+            .await
+            .is_err());
+        let codes: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM user_email_verifications WHERE user_email_id = $1",
+        )
+        .bind(stale.data)
+        .fetch_one(&mut txn)
+        .await
+        .unwrap();
+        assert_eq!(codes, 0);
+
+        // Recent and verified emails are kept
+        lookup_user_email_by_id(&mut txn, &user, fresh.data)
+            .await
+            .unwrap();
+        lookup_user_email_by_id(&mut txn, &user, verified.data)
+            .await
+            .unwrap();
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[test]
+    fn search_patterns() {
+        assert_eq!(contains_pattern("alice"), "%alice%");
+        assert_eq!(contains_pattern(""), "%%");
+
+        // Wildcards in the query are matched literally
+        assert_eq!(contains_pattern("100%_a\\b"), r"%100\%\_a\\b%");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn search_and_paginate_users() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let first = insert_user(&mut txn, "searched-alice").await.unwrap();
+        let second = insert_user(&mut txn, "searched-bob").await.unwrap();
+        let third = insert_user(&mut txn, "searched-charlie").await.unwrap();
+        add_user_email(
+            &mut txn,
+            &third,
+            "Charlie@Searched-Bob.example.com",
+            "charlie@searched-bob.example.com",
+        )
+        .await
+        .unwrap();
+        soft_delete_user(&mut txn, &first).await.unwrap();
+
+        let ids = |users: &[UserListItem]| users.iter().map(|u| u.user.data).collect::<Vec<_>>();
+
+        // Deleted users are listed too
+        let all = search_users(&mut txn, Some("SEARCHED-"), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(&all), vec![first.data, second.data, third.data]);
+        assert!(all[0].deleted_at.is_some());
+        assert!(all[1].deleted_at.is_none());
+
+        // Emails match too
+        let bobs = search_users(&mut txn, Some("searched-bob"), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(ids(&bobs), vec![second.data, third.data]);
+
+        // Pages start after the cursor
+        let page = search_users(&mut txn, Some("searched-"), None, 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), vec![first.data, second.data]);
+        let page = search_users(&mut txn, Some("searched-"), Some(page[1].cursor()), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&page), vec![third.data]);
+
+        assert!(search_users(&mut txn, Some("searched_"), None, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[test]
+    fn subs_to_user_ids() {
+        assert_eq!(user_id_from_sub("fake-sub-42"), Some(42));
+        assert_eq!(user_id_from_sub("fake-sub-"), None);
+        assert_eq!(user_id_from_sub("42"), None);
+        assert_eq!(user_id_from_sub("fake-sub-42/../1"), None);
+    }
+
+    #[test]
+    fn verify_password_against_hash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let hashed_password =
+            PasswordHash::generate(Argon2::default(), "hunter2", salt.as_str()).unwrap();
+        let hashed_password = hashed_password.to_string();
+
+        assert!(verify_password_hash(&hashed_password, "hunter2").is_ok());
+        assert!(matches!(
+            verify_password_hash(&hashed_password, "hunter3"),
+            Err(password_hash::Error::Password)
+        ));
+    }
+
+    #[test]
+    fn sanitize_device_display_names() {
+        assert_eq!(
+            sanitize_device_display_name("  Work\tlaptop \n"),
+            Some("Work laptop".to_owned())
+        );
+        assert_eq!(
+            sanitize_device_display_name("Phone\u{7}\u{1b}[31m"),
+            Some("Phone[31m".to_owned())
+        );
+        assert_eq!(sanitize_device_display_name(" \u{0} \n"), None);
+        assert_eq!(sanitize_device_display_name(""), None);
+
+        let long = "a".repeat(MAX_DEVICE_DISPLAY_NAME_LENGTH * 2);
+        let name = sanitize_device_display_name(&long).unwrap();
+        assert_eq!(name.chars().count(), MAX_DEVICE_DISPLAY_NAME_LENGTH);
+
+        // Truncation counts characters, not bytes
+        let long = "é".repeat(MAX_DEVICE_DISPLAY_NAME_LENGTH + 1);
+        let name = sanitize_device_display_name(&long).unwrap();
+        assert_eq!(name.chars().count(), MAX_DEVICE_DISPLAY_NAME_LENGTH);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn totp_steps_and_plaintext_secrets() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = register_user(&mut txn, Argon2::default(), "totp-alice", "hunter2")
+            .await
+            .unwrap();
+        let totp = start_user_totp_enrollment(&mut txn, &user, "encrypted".to_owned())
+            .await
+            .unwrap();
+        assert!(!totp.is_enabled());
+        assert_eq!(totp.last_used_step, None);
+
+        // The code which enabled TOTP can't be used again, nor any code for an
+        // earlier step
+        let totp = mark_user_totp_as_verified(&mut txn, totp, 10)
+            .await
+            .unwrap();
+        assert_eq!(totp.last_used_step, Some(10));
+        assert!(!record_user_totp_step(&mut txn, &totp, 9).await.unwrap());
+        assert!(!record_user_totp_step(&mut txn, &totp, 10).await.unwrap());
+        assert!(record_user_totp_step(&mut txn, &totp, 11).await.unwrap());
+        assert!(!record_user_totp_step(&mut txn, &totp, 11).await.unwrap());
+
+        // Secrets stored before they were encrypted are refused until encrypted
+        let secret = TotpSecret::generate(&mut OsRng);
+        sqlx::query!(
+            "UPDATE user_totp SET encrypted_secret = $2, plaintext = TRUE WHERE id = $1",
+            totp.data,
+            secret.to_base32(),
+        )
+        .execute(&mut txn)
+        .await
+        .unwrap();
+        assert!(lookup_user_totp(&mut txn, &user).await.is_err());
+
+        let count = encrypt_plaintext_totp_secrets(&mut txn, |user_id, secret| {
+            Ok(format!("{}:{}", user_id, secret.to_base32()))
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        let totp = lookup_user_totp(&mut txn, &user).await.unwrap().unwrap();
+        assert_eq!(
+            totp.encrypted_secret,
+            format!("{}:{}", user.data, secret.to_base32())
+        );
+        assert_eq!(totp.last_used_step, Some(11));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn totp_secrets_are_encrypted_again() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let legacy = register_user(&mut txn, Argon2::default(), "totp-legacy", "hunter2")
+            .await
+            .unwrap();
+        let bound = register_user(&mut txn, Argon2::default(), "totp-bound", "hunter2")
+            .await
+            .unwrap();
+        start_user_totp_enrollment(&mut txn, &legacy, "legacy".to_owned())
+            .await
+            .unwrap();
+        start_user_totp_enrollment(&mut txn, &bound, "bound".to_owned())
+            .await
+            .unwrap();
+
+        // Other rows might exist in the database, only the legacy one changes
+        let count = reencrypt_totp_secrets(&mut txn, |user_id, encrypted_secret| {
+            Ok((encrypted_secret == "legacy").then(|| format!("{}:legacy", user_id)))
+        })
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        let totp = lookup_user_totp(&mut txn, &legacy).await.unwrap().unwrap();
+        assert_eq!(totp.encrypted_secret, format!("{}:legacy", legacy.data));
+        let totp = lookup_user_totp(&mut txn, &bound).await.unwrap().unwrap();
+        assert_eq!(totp.encrypted_secret, "bound");
+    }
+
+    /// Make the scheduled erasure of the user due
+    async fn make_erasure_due(conn: &mut PgConnection, user: &User<PostgresqlBackend>) {
+        sqlx::query("UPDATE users SET erase_after = NOW() - INTERVAL '1 minute' WHERE id = $1")
+            .bind(user.data)
+            .execute(conn)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn erasure_is_scheduled_and_cancelled() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = insert_user(&mut txn, "erased-alice").await.unwrap();
+        assert_eq!(lookup_user_erasure(&mut txn, &user).await.unwrap(), None);
+
+        let erase_after = schedule_user_erasure(&mut txn, &user, chrono::Duration::days(7))
+            .await
+            .unwrap();
+        assert!(erase_after > Utc::now() + chrono::Duration::days(6));
+        assert_eq!(
+            lookup_user_erasure(&mut txn, &user).await.unwrap(),
+            Some(erase_after)
+        );
+
+        // It can't be scheduled twice
+        assert!(
+            schedule_user_erasure(&mut txn, &user, chrono::Duration::days(7))
+                .await
+                .is_err()
+        );
+
+        // The user is not erased before the end of the delay
+        erase_scheduled_users(&mut txn).await.unwrap();
+        lookup_user_by_sub(&mut txn, &user.sub).await.unwrap();
+
+        // Cancelling it within the delay keeps the user
+        assert!(cancel_user_erasure(&mut txn, &user).await.unwrap());
+        assert_eq!(lookup_user_erasure(&mut txn, &user).await.unwrap(), None);
+        assert!(!cancel_user_erasure(&mut txn, &user).await.unwrap());
+
+        // Once due, it can't be cancelled anymore
+        schedule_user_erasure(&mut txn, &user, chrono::Duration::days(7))
+            .await
+            .unwrap();
+        make_erasure_due(&mut txn, &user).await;
+        assert!(!cancel_user_erasure(&mut txn, &user).await.unwrap());
+
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn erasure_deletes_the_user_data() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = Connection::begin(&mut conn).await.unwrap();
+
+        let user = register_user(&mut txn, Argon2::default(), "erased-bob", "hunter2")
+            .await
+            .unwrap();
+        let kept = insert_user(&mut txn, "kept-charlie").await.unwrap();
+
+        let email = add_user_email(&mut txn, &user, "bob@example.com", "bob@example.com")
+            .await
+            .unwrap();
+        crate::upstream_oauth2::add_upstream_link(&mut txn, "google", "erased-bob", &user)
+            .await
+            .unwrap();
+        let session = start_compat_session(&mut txn, user.clone(), Device::generate(&mut OsRng))
+            .await
+            .unwrap();
+        add_compat_access_token(&mut txn, &session, "erased-token".to_owned(), None)
+            .await
+            .unwrap();
+        let browser_session = start_session(&mut txn, user.clone()).await.unwrap();
+        let oauth2_session =
+            insert_test_client_session(&mut txn, browser_session.data, "erased-bob-client").await;
+        let access_token_id: i64 = sqlx::query_scalar(
+            r#"
+                INSERT INTO oauth2_access_tokens (oauth2_session_id, token, expires_after)
+                VALUES ($1, 'erased-access-token', 3600)
+                RETURNING id
+            "#,
+        )
+        .bind(oauth2_session)
+        .fetch_one(&mut txn)
+        .await
+        .unwrap();
+        sqlx::query(
+            r#"
+                INSERT INTO oauth2_refresh_tokens
+                    (oauth2_session_id, oauth2_access_token_id, token)
+                VALUES ($1, $2, 'erased-refresh-token')
+            "#,
+        )
+        .bind(oauth2_session)
+        .bind(access_token_id)
+        .execute(&mut txn)
+        .await
+        .unwrap();
+
+        schedule_user_erasure(&mut txn, &user, chrono::Duration::days(7))
+            .await
+            .unwrap();
+        schedule_user_erasure(&mut txn, &kept, chrono::Duration::days(7))
+            .await
+            .unwrap();
+        make_erasure_due(&mut txn, &user).await;
+
+        assert!(erase_scheduled_users(&mut txn).await.unwrap() >= 1);
+
+        assert!(lookup_user_by_sub(&mut txn, &user.sub).await.is_err());
+        assert!(lookup_user_email_by_id(&mut txn, &user, email.data)
+            .await
+            .is_err());
+        assert!(lookup_active_compat_access_token(&mut txn, "erased-token")
+            .await
+            .is_err());
+
+        for (table, column, id) in [
+            ("user_emails", "user_id", user.data),
+            ("user_passwords", "user_id", user.data),
+            ("user_upstream_links", "user_id", user.data),
+            ("compat_sessions", "user_id", user.data),
+            ("compat_access_tokens", "compat_session_id", session.data),
+            ("user_sessions", "id", browser_session.data),
+            ("oauth2_sessions", "id", oauth2_session),
+            ("oauth2_access_tokens", "oauth2_session_id", oauth2_session),
+            ("oauth2_refresh_tokens", "oauth2_session_id", oauth2_session),
+        ] {
+            let count: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM {} WHERE {} = $1",
+                table, column
+            ))
+            .bind(id)
+            .fetch_one(&mut txn)
+            .await
+            .unwrap();
+            assert_eq!(count, 0, "{} should be empty", table);
+        }
+
+        // Users whose erasure is not due yet are kept
+        lookup_user_by_sub(&mut txn, &kept.sub).await.unwrap();
+
+        txn.rollback().await.unwrap();
+    }
+}
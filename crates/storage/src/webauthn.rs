@@ -0,0 +1,333 @@
+// Copyright 2021, 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of the Web Authentication credentials (passkeys) users log in with
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use mas_data_model::{User, WebauthnCredential};
+use sqlx::PgExecutor;
+use tracing::{info_span, Instrument};
+
+use crate::{DatabaseInconsistencyError, IdAndCreationTime, PostgresqlBackend};
+
+struct WebauthnCredentialLookup {
+    id: i64,
+    credential_id: String,
+    user_handle: String,
+    passkey: String,
+    sign_count: i64,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl TryInto<WebauthnCredential<PostgresqlBackend>> for WebauthnCredentialLookup {
+    type Error = DatabaseInconsistencyError;
+
+    fn try_into(self) -> Result<WebauthnCredential<PostgresqlBackend>, Self::Error> {
+        Ok(WebauthnCredential {
+            data: self.id,
+            credential_id: self.credential_id,
+            user_handle: self.user_handle,
+            passkey: self.passkey,
+            sign_count: self
+                .sign_count
+                .try_into()
+                .map_err(|_| DatabaseInconsistencyError)?,
+            created_at: self.created_at,
+            last_used_at: self.last_used_at,
+        })
+    }
+}
+
+#[tracing::instrument(skip_all, fields(user.id = user.data))]
+pub async fn get_user_webauthn_credentials(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+) -> anyhow::Result<Vec<WebauthnCredential<PostgresqlBackend>>> {
+    let res = sqlx::query_as!(
+        WebauthnCredentialLookup,
+        r#"
+            SELECT id, credential_id, user_handle, passkey, sign_count, created_at, last_used_at
+            FROM user_webauthn_credentials
+            WHERE user_id = $1
+            ORDER BY created_at ASC
+        "#,
+        user.data,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Fetch user WebAuthn credentials"))
+    .await
+    .context("could not fetch user WebAuthn credentials")?;
+
+    let credentials: Result<Vec<_>, _> = res.into_iter().map(TryInto::try_into).collect();
+    Ok(credentials?)
+}
+
+#[tracing::instrument(skip(executor, user, passkey), fields(user.id = user.data))]
+pub async fn add_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    credential_id: String,
+    user_handle: String,
+    passkey: String,
+    sign_count: u32,
+) -> anyhow::Result<WebauthnCredential<PostgresqlBackend>> {
+    let res = sqlx::query_as!(
+        IdAndCreationTime,
+        r#"
+            INSERT INTO user_webauthn_credentials
+                (user_id, credential_id, user_handle, passkey, sign_count)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, created_at
+        "#,
+        user.data,
+        &credential_id,
+        &user_handle,
+        &passkey,
+        i64::from(sign_count),
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Insert WebAuthn credential"))
+    .await
+    .context("could not insert WebAuthn credential")?;
+
+    Ok(WebauthnCredential {
+        data: res.id,
+        credential_id,
+        user_handle,
+        passkey,
+        sign_count,
+        created_at: res.created_at,
+        last_used_at: None,
+    })
+}
+
+/// Save the state of a credential after a successful assertion.
+///
+/// The signature counter is checked again in the query, so that two
+/// concurrent assertions with the same counter can't both succeed. Returns
+/// `false` if the counter did not increase, unless both counters are zero as
+/// the authenticator does not implement it.
+#[tracing::instrument(skip(executor, credential, passkey), fields(user_webauthn_credential.id = credential.data))]
+pub async fn update_webauthn_credential(
+    executor: impl PgExecutor<'_>,
+    credential: &mut WebauthnCredential<PostgresqlBackend>,
+    passkey: String,
+    sign_count: u32,
+) -> anyhow::Result<bool> {
+    let last_used_at = sqlx::query_scalar!(
+        r#"
+            UPDATE user_webauthn_credentials
+            SET passkey = $2,
+                sign_count = $3,
+                last_used_at = NOW()
+            WHERE id = $1
+              AND (sign_count < $3 OR (sign_count = 0 AND $3 = 0))
+            RETURNING last_used_at AS "last_used_at!"
+        "#,
+        credential.data,
+        &passkey,
+        i64::from(sign_count),
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Update WebAuthn credential"))
+    .await
+    .context("could not update WebAuthn credential")?;
+
+    let last_used_at = match last_used_at {
+        Some(last_used_at) => last_used_at,
+        None => return Ok(false),
+    };
+
+    credential.passkey = passkey;
+    credential.sign_count = sign_count;
+    credential.last_used_at = Some(last_used_at);
+
+    Ok(true)
+}
+
+/// Save the state of a registration or login ceremony, which has to be
+/// finished before `expires_at`. Returns the ID to get it back with
+#[tracing::instrument(skip(executor, user, state), fields(user.id = user.data))]
+pub async fn add_webauthn_challenge(
+    executor: impl PgExecutor<'_>,
+    user: &User<PostgresqlBackend>,
+    state: &str,
+    expires_at: DateTime<Utc>,
+) -> anyhow::Result<i64> {
+    sqlx::query_scalar!(
+        r#"
+            INSERT INTO user_webauthn_challenges (user_id, state, expires_at)
+            VALUES ($1, $2, $3)
+            RETURNING id
+        "#,
+        user.data,
+        state,
+        expires_at,
+    )
+    .fetch_one(executor)
+    .instrument(info_span!("Insert WebAuthn challenge"))
+    .await
+    .context("could not insert WebAuthn challenge")
+}
+
+/// Get the state of a ceremony and delete it, so that its challenge can only
+/// be answered once. Returns `None` if it was already used or expired
+#[tracing::instrument(skip(executor))]
+pub async fn consume_webauthn_challenge(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+) -> anyhow::Result<Option<String>> {
+    sqlx::query_scalar!(
+        r#"
+            DELETE FROM user_webauthn_challenges
+            WHERE id = $1
+              AND expires_at > NOW()
+            RETURNING state
+        "#,
+        id,
+    )
+    .fetch_optional(executor)
+    .instrument(info_span!("Consume WebAuthn challenge"))
+    .await
+    .context("could not consume WebAuthn challenge")
+}
+
+/// Delete at most `batch_size` challenges which expired without being answered
+///
+/// Returns how many challenges were deleted
+pub async fn cleanup_expired_challenges(
+    executor: impl PgExecutor<'_>,
+    batch_size: u32,
+) -> anyhow::Result<u64> {
+    let res = sqlx::query!(
+        r#"
+            DELETE FROM user_webauthn_challenges
+            WHERE id IN (
+                SELECT id
+                FROM user_webauthn_challenges
+                WHERE expires_at < NOW()
+                LIMIT $1
+            )
+        "#,
+        i64::from(batch_size),
+    )
+    .execute(executor)
+    .await
+    .context("could not cleanup expired WebAuthn challenges")?;
+
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::Argon2;
+    use chrono::Duration;
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+    use crate::user::register_user;
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn challenges_are_single_use_and_expire() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        let user = register_user(&mut txn, Argon2::default(), "webauthn-alice", "hunter2")
+            .await
+            .unwrap();
+        let now = Utc::now();
+
+        let id = add_webauthn_challenge(&mut txn, &user, "state", now + Duration::minutes(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            consume_webauthn_challenge(&mut txn, id).await.unwrap(),
+            Some("state".to_owned())
+        );
+        assert_eq!(
+            consume_webauthn_challenge(&mut txn, id).await.unwrap(),
+            None
+        );
+
+        let expired = add_webauthn_challenge(&mut txn, &user, "state", now - Duration::seconds(1))
+            .await
+            .unwrap();
+        assert_eq!(
+            consume_webauthn_challenge(&mut txn, expired).await.unwrap(),
+            None
+        );
+        assert!(cleanup_expired_challenges(&mut txn, 100).await.unwrap() >= 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn sign_count_has_to_increase_unless_unsupported() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        let user = register_user(&mut txn, Argon2::default(), "webauthn-bob", "hunter2")
+            .await
+            .unwrap();
+
+        let mut counting = add_webauthn_credential(
+            &mut txn,
+            &user,
+            "counting".to_owned(),
+            "handle".to_owned(),
+            "{}".to_owned(),
+            0,
+        )
+        .await
+        .unwrap();
+        assert!(
+            update_webauthn_credential(&mut txn, &mut counting, "{}".to_owned(), 1)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !update_webauthn_credential(&mut txn, &mut counting, "{}".to_owned(), 1)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !update_webauthn_credential(&mut txn, &mut counting, "{}".to_owned(), 0)
+                .await
+                .unwrap()
+        );
+
+        // Authenticators without a counter always send zero
+        let mut without_counter = add_webauthn_credential(
+            &mut txn,
+            &user,
+            "without-counter".to_owned(),
+            "handle".to_owned(),
+            "{}".to_owned(),
+            0,
+        )
+        .await
+        .unwrap();
+        for _ in 0..2 {
+            assert!(
+                update_webauthn_credential(&mut txn, &mut without_counter, "{}".to_owned(), 0)
+                    .await
+                    .unwrap()
+            );
+        }
+    }
+}
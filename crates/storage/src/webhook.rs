@@ -0,0 +1,248 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log of the events delivered to the webhook endpoints
+//!
+//! The events are enqueued in the same transaction as the change they
+//! describe, so that they are delivered at least once if it is committed.
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::types::PgInterval, PgExecutor};
+use tracing::{info_span, Instrument};
+
+/// An event waiting to be delivered to an endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub endpoint: String,
+    pub event: String,
+    pub payload: String,
+    pub created_at: DateTime<Utc>,
+    pub attempts: i32,
+}
+
+/// Enqueue the delivery of an event to each of the endpoints
+///
+/// Returns how many deliveries were enqueued
+#[tracing::instrument(skip(executor, payload))]
+pub async fn enqueue_webhook(
+    executor: impl PgExecutor<'_>,
+    endpoints: &[String],
+    event: &str,
+    payload: &str,
+) -> anyhow::Result<u64> {
+    if endpoints.is_empty() {
+        return Ok(0);
+    }
+
+    let res = sqlx::query!(
+        r#"
+            INSERT INTO webhook_deliveries (endpoint, event, payload)
+            SELECT endpoint, $2, $3
+            FROM UNNEST($1::TEXT[]) AS endpoint
+        "#,
+        endpoints,
+        event,
+        payload,
+    )
+    .execute(executor)
+    .instrument(info_span!("Enqueue webhook"))
+    .await
+    .context("could not enqueue webhook")?;
+
+    Ok(res.rows_affected())
+}
+
+/// Claim the deliveries which are due, oldest first.
+///
+/// Their next attempt is pushed `lease` from now, so that the other instances
+/// skip them while they are attempted, and so that they are attempted again if
+/// this instance goes away before recording the outcome. This is a single
+/// statement, which should run in its own short transaction: the rows are only
+/// locked while they are claimed, not while they are attempted
+#[tracing::instrument(skip(executor))]
+pub async fn claim_pending_webhooks(
+    executor: impl PgExecutor<'_>,
+    limit: i64,
+    lease: chrono::Duration,
+) -> anyhow::Result<Vec<WebhookDelivery>> {
+    let lease = PgInterval::try_from(lease)
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    let mut res = sqlx::query_as!(
+        WebhookDelivery,
+        r#"
+            UPDATE webhook_deliveries
+            SET next_attempt_at = NOW() + $2
+            WHERE id IN (
+                SELECT id
+                FROM webhook_deliveries
+                WHERE next_attempt_at <= NOW()
+                ORDER BY next_attempt_at ASC
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, endpoint, event, payload, created_at, attempts
+        "#,
+        limit,
+        lease,
+    )
+    .fetch_all(executor)
+    .instrument(info_span!("Claim pending webhooks"))
+    .await
+    .context("could not claim pending webhooks")?;
+
+    // RETURNING doesn't keep the order of the subquery
+    res.sort_by_key(|delivery| (delivery.created_at, delivery.id));
+
+    Ok(res)
+}
+
+/// Record the successful delivery of an event
+#[tracing::instrument(skip(executor))]
+pub async fn mark_webhook_delivered(executor: impl PgExecutor<'_>, id: i64) -> anyhow::Result<()> {
+    sqlx::query!(
+        r#"
+            UPDATE webhook_deliveries
+            SET attempts = attempts + 1,
+                delivered_at = NOW(),
+                next_attempt_at = NULL,
+                last_error = NULL
+            WHERE id = $1
+        "#,
+        id,
+    )
+    .execute(executor)
+    .instrument(info_span!("Mark webhook as delivered"))
+    .await
+    .context("could not mark webhook as delivered")?;
+
+    Ok(())
+}
+
+/// Record a failed delivery attempt. The delivery is attempted again
+/// `retry_in` from now, or given up on if it is `None`
+#[tracing::instrument(skip(executor, error))]
+pub async fn mark_webhook_failed(
+    executor: impl PgExecutor<'_>,
+    id: i64,
+    error: &str,
+    retry_in: Option<chrono::Duration>,
+) -> anyhow::Result<()> {
+    let retry_in = retry_in
+        .map(PgInterval::try_from)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("failed to encode duration: {}", e))?;
+
+    sqlx::query!(
+        r#"
+            UPDATE webhook_deliveries
+            SET attempts = attempts + 1,
+                last_error = $2,
+                next_attempt_at = NOW() + $3
+            WHERE id = $1
+        "#,
+        id,
+        error,
+        retry_in,
+    )
+    .execute(executor)
+    .instrument(info_span!("Mark webhook as failed"))
+    .await
+    .context("could not mark webhook as failed")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, PgConnection};
+
+    use super::*;
+    use crate::user::insert_user;
+
+    async fn make_due(conn: &mut PgConnection, id: i64) {
+        sqlx::query(
+            "UPDATE webhook_deliveries SET next_attempt_at = NOW() - INTERVAL '1 minute' WHERE id = $1",
+        )
+        .bind(id)
+        .execute(conn)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a migrated database, set in DATABASE_URL"]
+    async fn deliveries_are_retried() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        let mut txn = conn.begin().await.unwrap();
+
+        let user = insert_user(&mut txn, "webhook-alice").await.unwrap();
+        let endpoints = vec!["first".to_owned(), "second".to_owned()];
+        let payload = format!(r#"{{"sub":"{}"}}"#, user.sub);
+        assert_eq!(
+            enqueue_webhook(&mut txn, &endpoints, "user.created", &payload)
+                .await
+                .unwrap(),
+            2
+        );
+
+        let lease = chrono::Duration::minutes(30);
+        let pending: Vec<_> = claim_pending_webhooks(&mut txn, 1000, lease)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|delivery| delivery.payload == payload)
+            .collect();
+        assert_eq!(pending.len(), 2);
+        let (first, second) = (&pending[0], &pending[1]);
+        assert_eq!(first.event, "user.created");
+        assert_eq!(first.attempts, 0);
+
+        // Once claimed, they are not claimed again until the lease expires
+        let claimed = claim_pending_webhooks(&mut txn, 1000, lease).await.unwrap();
+        assert!(claimed.iter().all(|delivery| delivery.payload != payload));
+
+        mark_webhook_delivered(&mut txn, first.id).await.unwrap();
+        mark_webhook_failed(
+            &mut txn,
+            second.id,
+            "connection refused",
+            Some(chrono::Duration::minutes(1)),
+        )
+        .await
+        .unwrap();
+
+        // Neither is pending: one was delivered, the other one is not due yet
+        let pending = claim_pending_webhooks(&mut txn, 1000, lease).await.unwrap();
+        assert!(pending.iter().all(|delivery| delivery.payload != payload));
+
+        // Once due, the failed one is attempted again
+        make_due(&mut txn, second.id).await;
+        let pending = claim_pending_webhooks(&mut txn, 1000, lease).await.unwrap();
+        let retried = pending.iter().find(|d| d.id == second.id).unwrap();
+        assert_eq!(retried.attempts, 1);
+
+        // Until it is given up on
+        mark_webhook_failed(&mut txn, second.id, "connection refused", None)
+            .await
+            .unwrap();
+        let pending = claim_pending_webhooks(&mut txn, 1000, lease).await.unwrap();
+        assert!(pending.iter().all(|delivery| delivery.id != second.id));
+
+        txn.rollback().await.unwrap();
+    }
+}
@@ -14,40 +14,223 @@
 
 //! Database-related tasks
 
+use std::time::Duration;
+
 use sqlx::{Pool, Postgres};
 use tracing::{debug, error, info};
 
 use super::Task;
 
 #[derive(Clone)]
-struct CleanupExpired(Pool<Postgres>);
+struct CleanupExpired<F> {
+    pool: Pool<Postgres>,
+    grace_period: Duration,
+    batch_size: u32,
+    on_deleted: F,
+}
 
-impl std::fmt::Debug for CleanupExpired {
+impl<F> std::fmt::Debug for CleanupExpired<F> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("CleanupExpired").finish_non_exhaustive()
+        f.debug_struct("CleanupExpired")
+            .field("grace_period", &self.grace_period)
+            .field("batch_size", &self.batch_size)
+            .finish_non_exhaustive()
     }
 }
 
 #[async_trait::async_trait]
-impl Task for CleanupExpired {
+impl<F> Task for CleanupExpired<F>
+where
+    F: Fn(&'static str, u64) + Send + Sync + 'static,
+{
     async fn run(&self) {
-        let res = mas_storage::oauth2::access_token::cleanup_expired(&self.0).await;
+        let res = mas_storage::oauth2::access_token::cleanup_expired(
+            &self.pool,
+            self.grace_period,
+            self.batch_size,
+        )
+        .await;
+        match res {
+            Ok(0) => {
+                debug!("no access token to clean up");
+            }
+            Ok(count) => {
+                info!(count, "cleaned up expired access tokens");
+                (self.on_deleted)("access_token", count);
+            }
+            Err(error) => {
+                error!(?error, "failed to cleanup expired access tokens");
+            }
+        }
+
+        let res = mas_storage::oauth2::refresh_token::cleanup_revoked(
+            &self.pool,
+            self.grace_period,
+            self.batch_size,
+        )
+        .await;
         match res {
             Ok(0) => {
-                debug!("no token to clean up");
+                debug!("no refresh token to clean up");
             }
             Ok(count) => {
-                info!(count, "cleaned up expired tokens");
+                info!(count, "cleaned up revoked refresh tokens");
+                (self.on_deleted)("refresh_token", count);
             }
             Err(error) => {
-                error!(?error, "failed to cleanup expired tokens");
+                error!(?error, "failed to cleanup revoked refresh tokens");
+            }
+        }
+
+        let res =
+            mas_storage::webauthn::cleanup_expired_challenges(&self.pool, self.batch_size).await;
+        match res {
+            Ok(0) => {
+                debug!("no WebAuthn challenge to clean up");
+            }
+            Ok(count) => {
+                info!(count, "cleaned up expired WebAuthn challenges");
+                (self.on_deleted)("webauthn_challenge", count);
+            }
+            Err(error) => {
+                error!(?error, "failed to cleanup expired WebAuthn challenges");
             }
         }
     }
 }
 
-/// Cleanup expired tokens
+/// Cleanup the access tokens which expired or were revoked, and the refresh
+/// tokens which were revoked or replaced, more than `grace_period` ago, as
+/// well as the Web Authentication challenges which expired.
+///
+/// At most `batch_size` rows of each kind are deleted on each run.
+/// `on_deleted` is called with the kind, `access_token`, `refresh_token` or
+/// `webauthn_challenge`, and the number of rows deleted, to record it in the
+/// metrics
 #[must_use]
-pub fn cleanup_expired(pool: &Pool<Postgres>) -> impl Task + Clone {
-    CleanupExpired(pool.clone())
+pub fn cleanup_expired<F>(
+    pool: &Pool<Postgres>,
+    grace_period: Duration,
+    batch_size: u32,
+    on_deleted: F,
+) -> impl Task + Clone
+where
+    F: Fn(&'static str, u64) + Clone + Send + Sync + 'static,
+{
+    CleanupExpired {
+        pool: pool.clone(),
+        grace_period,
+        batch_size,
+        on_deleted,
+    }
+}
+
+#[derive(Clone)]
+struct PurgeUnverifiedEmails<F> {
+    pool: Pool<Postgres>,
+    max_age: Duration,
+    on_purged: F,
+}
+
+impl<F> std::fmt::Debug for PurgeUnverifiedEmails<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PurgeUnverifiedEmails")
+            .field("max_age", &self.max_age)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl<F> Task for PurgeUnverifiedEmails<F>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    async fn run(&self) {
+        let res = mas_storage::user::purge_unverified_emails(&self.pool, self.max_age).await;
+        match res {
+            Ok(0) => {
+                debug!("no unverified email to purge");
+            }
+            Ok(count) => {
+                info!(count, "purged unverified emails");
+                (self.on_purged)(count);
+            }
+            Err(error) => {
+                error!(?error, "failed to purge unverified emails");
+            }
+        }
+    }
+}
+
+/// Purge the emails which were not verified within `max_age`.
+///
+/// `on_purged` is called with the number of emails deleted on each run, to
+/// record it in the metrics
+#[must_use]
+pub fn purge_unverified_emails<F>(
+    pool: &Pool<Postgres>,
+    max_age: Duration,
+    on_purged: F,
+) -> impl Task + Clone
+where
+    F: Fn(u64) + Clone + Send + Sync + 'static,
+{
+    PurgeUnverifiedEmails {
+        pool: pool.clone(),
+        max_age,
+        on_purged,
+    }
+}
+
+#[derive(Clone)]
+struct EraseScheduledUsers<F> {
+    pool: Pool<Postgres>,
+    on_erased: F,
+}
+
+impl<F> std::fmt::Debug for EraseScheduledUsers<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EraseScheduledUsers")
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl<F> Task for EraseScheduledUsers<F>
+where
+    F: Fn(u64) + Send + Sync + 'static,
+{
+    async fn run(&self) {
+        let res = match self.pool.acquire().await {
+            Ok(mut conn) => mas_storage::user::erase_scheduled_users(&mut conn).await,
+            Err(error) => Err(error.into()),
+        };
+        match res {
+            Ok(0) => {
+                debug!("no user to erase");
+            }
+            Ok(count) => {
+                info!(count, "erased users");
+                (self.on_erased)(count);
+            }
+            Err(error) => {
+                error!(?error, "failed to erase users");
+            }
+        }
+    }
+}
+
+/// Erase the users who asked for it, once their erasure is due.
+///
+/// `on_erased` is called with the number of users erased on each run, to
+/// record it in the metrics
+#[must_use]
+pub fn erase_scheduled_users<F>(pool: &Pool<Postgres>, on_erased: F) -> impl Task + Clone
+where
+    F: Fn(u64) + Clone + Send + Sync + 'static,
+{
+    EraseScheduledUsers {
+        pool: pool.clone(),
+        on_erased,
+    }
 }
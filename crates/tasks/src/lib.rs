@@ -17,24 +17,36 @@
 //! Tasks here are ran one after another to avoid having to unnecesarily lock
 //! resources and avoid database conflicts. Tasks are not persisted, which is
 //! considered "good enough" for now.
+//!
+//! A task which panics is logged and dropped, without stopping the queue:
+//! recuring tasks run again on their next interval.
 
 #![forbid(unsafe_code)]
 #![deny(clippy::all, missing_docs, rustdoc::broken_intra_doc_links)]
 #![warn(clippy::pedantic)]
 
-use std::{collections::VecDeque, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+    time::Duration,
+};
 
-use futures_util::StreamExt;
+use futures_util::{FutureExt, StreamExt};
 use tokio::{
     sync::{Mutex, Notify},
+    task::JoinHandle,
     time::Interval,
 };
 use tokio_stream::wrappers::IntervalStream;
-use tracing::debug;
+use tracing::{debug, error, info_span, Instrument};
 
 mod database;
 
-pub use self::database::cleanup_expired;
+pub use self::database::{cleanup_expired, erase_scheduled_users, purge_unverified_emails};
 
 /// A [`Task`] can be executed by a [`TaskQueue`]
 #[async_trait::async_trait]
@@ -43,36 +55,53 @@ pub trait Task: std::fmt::Debug + Send + Sync + 'static {
     async fn run(&self);
 }
 
+/// A task waiting to be run, with its name for the logs
+struct Pending {
+    name: &'static str,
+    task: Box<dyn Task>,
+}
+
 #[derive(Default)]
 struct TaskQueueInner {
-    pending_tasks: Mutex<VecDeque<Box<dyn Task>>>,
+    pending_tasks: Mutex<VecDeque<Pending>>,
     notifier: Notify,
+    stopping: AtomicBool,
 }
 
 impl TaskQueueInner {
-    async fn recuring<T: Task + Clone>(&self, interval: Interval, task: T) {
+    async fn recuring<T: Task + Clone>(&self, name: &'static str, interval: Interval, task: T) {
         let mut stream = IntervalStream::new(interval);
 
         while (stream.next()).await.is_some() {
-            self.schedule(task.clone()).await;
+            self.schedule(name, task.clone()).await;
         }
     }
 
-    async fn schedule<T: Task>(&self, task: T) {
+    async fn schedule<T: Task>(&self, name: &'static str, task: T) {
         let task = Box::new(task);
-        self.pending_tasks.lock().await.push_back(task);
+        self.pending_tasks
+            .lock()
+            .await
+            .push_back(Pending { name, task });
         self.notifier.notify_one();
     }
 
     async fn tick(&self) {
-        loop {
+        // Once stopping, the pending tasks are dropped
+        while !self.stopping.load(Ordering::Relaxed) {
             let pending = {
                 let mut tasks = self.pending_tasks.lock().await;
                 tasks.pop_front()
             };
 
-            if let Some(pending) = pending {
-                pending.run().await;
+            if let Some(Pending { name, task }) = pending {
+                let span = info_span!("task", task.name = name);
+                let res = AssertUnwindSafe(task.run().instrument(span))
+                    .catch_unwind()
+                    .await;
+                if res.is_err() {
+                    error!(task.name = name, "Task panicked");
+                }
             } else {
                 break;
             }
@@ -80,7 +109,7 @@ impl TaskQueueInner {
     }
 
     async fn run_forever(&self) {
-        loop {
+        while !self.stopping.load(Ordering::Relaxed) {
             self.notifier.notified().await;
             self.tick().await;
         }
@@ -91,29 +120,134 @@ impl TaskQueueInner {
 #[derive(Default)]
 pub struct TaskQueue {
     inner: Arc<TaskQueueInner>,
+
+    /// The task running the queue
+    runner: std::sync::Mutex<Option<JoinHandle<()>>>,
+
+    /// The tasks scheduling the recuring tasks
+    recuring: std::sync::Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl TaskQueue {
-    /// Start the task queue to run forever
+    /// Start the task queue to run until [`TaskQueue::shutdown`] is called
     pub fn start(&self) {
         let queue = self.inner.clone();
-        tokio::task::spawn(async move {
+        let handle = tokio::task::spawn(async move {
             queue.run_forever().await;
         });
+        *self.runner.lock().unwrap_or_else(PoisonError::into_inner) = Some(handle);
     }
 
     #[allow(dead_code)]
-    async fn schedule<T: Task>(&self, task: T) {
+    async fn schedule<T: Task>(&self, name: &'static str, task: T) {
         let queue = self.inner.clone();
-        queue.schedule(task).await;
+        queue.schedule(name, task).await;
     }
 
-    /// Schedule a task in the queue at regular intervals
-    pub fn recuring(&self, every: Duration, task: impl Task + Clone + std::fmt::Debug) {
-        debug!(?task, period = every.as_secs(), "Scheduling recuring task");
+    /// Schedule a task in the queue at regular intervals. The `name` of the
+    /// task is used in the logs
+    pub fn recuring(&self, name: &'static str, every: Duration, task: impl Task + Clone) {
+        debug!(
+            task.name = name,
+            ?task,
+            period = every.as_secs(),
+            "Scheduling recuring task"
+        );
         let queue = self.inner.clone();
-        tokio::task::spawn(async move {
-            queue.recuring(tokio::time::interval(every), task).await;
+        let handle = tokio::task::spawn(async move {
+            queue
+                .recuring(name, tokio::time::interval(every), task)
+                .await;
         });
+        self.recuring
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(handle);
+    }
+
+    /// Stop scheduling tasks, and wait for the one currently running to finish.
+    /// The tasks which were waiting to run are dropped
+    pub async fn shutdown(&self) {
+        // Those only wait for their next interval
+        let recuring =
+            std::mem::take(&mut *self.recuring.lock().unwrap_or_else(PoisonError::into_inner));
+        for handle in recuring {
+            handle.abort();
+        }
+
+        self.inner.stopping.store(true, Ordering::Relaxed);
+        self.inner.notifier.notify_one();
+
+        let runner = self
+            .runner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(runner) = runner {
+            let _ = runner.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Default)]
+    struct Counting(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Task for Counting {
+        async fn run(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct Panicking(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Task for Panicking {
+        async fn run(&self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            panic!("this task always fails");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recuring_task_runs() {
+        let queue = TaskQueue::default();
+        let task = Counting::default();
+        queue.recuring("counting", Duration::from_secs(10), task.clone());
+        queue.start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert!(task.0.load(Ordering::SeqCst) >= 1);
+
+        queue.shutdown().await;
+        let runs = task.0.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(task.0.load(Ordering::SeqCst), runs);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicking_task_is_isolated() {
+        let queue = TaskQueue::default();
+        let panicking = Panicking::default();
+        let counting = Counting::default();
+        queue.recuring("panicking", Duration::from_secs(10), panicking.clone());
+        queue.recuring("counting", Duration::from_secs(10), counting.clone());
+        queue.start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+
+        // The panicking task is retried on its next interval, and the other
+        // tasks still run
+        assert!(panicking.0.load(Ordering::SeqCst) >= 2);
+        assert!(counting.0.load(Ordering::SeqCst) >= 2);
+
+        queue.shutdown().await;
     }
 }
@@ -16,16 +16,18 @@
 
 #![allow(clippy::trait_duplication_in_bounds)]
 
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
+use mas_config::TemplatesConfig;
 use mas_data_model::{
-    AuthorizationGrant, BrowserSession, CompatSsoLogin, CompatSsoLoginState, StorageBackend, User,
-    UserEmail, UserEmailVerification,
+    Authentication, AuthorizationGrant, BrowserSession, CompatSsoLogin, CompatSsoLoginState,
+    Session, StorageBackend, User, UserEmail, UserEmailVerification,
 };
 use mas_router::PostAuthAction;
+use oauth2_types::scope_description::ScopeDescriptions;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use url::Url;
 
-use crate::{FormField, FormState};
+use crate::{i18n::BUILTIN_CATALOGS, FieldError, FormError, FormField, FormState};
 
 /// Helper trait to construct context wrappers
 pub trait TemplateContext: Serialize {
@@ -59,6 +61,33 @@ pub trait TemplateContext: Serialize {
         }
     }
 
+    /// Attach the application-wide metadata to the template context
+    ///
+    /// [`Templates`] does this automatically when rendering, so this is only
+    /// useful to override those values.
+    ///
+    /// [`Templates`]: crate::Templates
+    fn with_app_context(self, app: AppContext) -> WithAppContext<Self>
+    where
+        Self: Sized,
+    {
+        WithAppContext { app, inner: self }
+    }
+
+    /// Attach the locale to render the template in, as negotiated with
+    /// [`Templates::negotiate_locale`]
+    ///
+    /// [`Templates::negotiate_locale`]: crate::Templates::negotiate_locale
+    fn with_locale(self, locale: String) -> WithLocale<Self>
+    where
+        Self: Sized,
+    {
+        WithLocale {
+            locale,
+            inner: self,
+        }
+    }
+
     /// Attach a CSRF token to the template context
     fn with_csrf<C>(self, csrf_token: C) -> WithCsrf<Self>
     where
@@ -72,6 +101,19 @@ pub trait TemplateContext: Serialize {
         }
     }
 
+    /// Attach the nonce of the Content Security Policy of the response, which
+    /// the inline scripts of the template need to be run
+    fn with_csp_nonce<N>(self, csp_nonce: N) -> WithCspNonce<Self>
+    where
+        Self: Sized,
+        N: ToString,
+    {
+        WithCspNonce {
+            csp_nonce: csp_nonce.to_string(),
+            inner: self,
+        }
+    }
+
     /// Generate sample values for this context type
     ///
     /// This is then used to check for template validity in unit tests and in
@@ -90,6 +132,92 @@ impl TemplateContext for () {
     }
 }
 
+/// Metadata about the service, available to all templates as `app`
+#[derive(Serialize, Debug, Clone)]
+pub struct AppContext {
+    service_name: String,
+    support_url: Option<Url>,
+}
+
+impl AppContext {
+    /// Constructs the application-wide context
+    #[must_use]
+    pub fn new(service_name: String) -> Self {
+        Self {
+            service_name,
+            support_url: None,
+        }
+    }
+
+    /// Add a link to where users can get help
+    #[must_use]
+    pub fn with_support_url(mut self, support_url: Url) -> Self {
+        self.support_url = Some(support_url);
+        self
+    }
+}
+
+impl From<&TemplatesConfig> for AppContext {
+    fn from(config: &TemplatesConfig) -> Self {
+        Self {
+            service_name: config.service_name.clone(),
+            support_url: config.support_url.clone(),
+        }
+    }
+}
+
+/// Context with the application-wide metadata in it
+#[derive(Serialize)]
+pub struct WithAppContext<T> {
+    app: AppContext,
+
+    #[serde(flatten)]
+    inner: T,
+}
+
+impl<T: TemplateContext> TemplateContext for WithAppContext<T> {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        T::sample()
+            .into_iter()
+            .map(|inner| WithAppContext {
+                app: AppContext::new("Sample service".into())
+                    .with_support_url(Url::parse("https://example.com/support").unwrap()),
+                inner,
+            })
+            .collect()
+    }
+}
+
+/// Context with the locale to render the template in
+#[derive(Serialize)]
+pub struct WithLocale<T> {
+    locale: String,
+
+    #[serde(flatten)]
+    inner: T,
+}
+
+impl<T: TemplateContext> TemplateContext for WithLocale<T> {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        // Render the samples in each of the builtin locales
+        BUILTIN_CATALOGS
+            .into_iter()
+            .flat_map(|(locale, _)| {
+                T::sample().into_iter().map(move |inner| WithLocale {
+                    locale: locale.to_owned(),
+                    inner,
+                })
+            })
+            .collect()
+    }
+}
+
 /// Context with a CSRF token in it
 #[derive(Serialize)]
 pub struct WithCsrf<T> {
@@ -114,6 +242,31 @@ impl<T: TemplateContext> TemplateContext for WithCsrf<T> {
     }
 }
 
+/// Context with the nonce of the Content Security Policy in it, as
+/// `csp_nonce`
+#[derive(Serialize)]
+pub struct WithCspNonce<T> {
+    csp_nonce: String,
+
+    #[serde(flatten)]
+    inner: T,
+}
+
+impl<T: TemplateContext> TemplateContext for WithCspNonce<T> {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        T::sample()
+            .into_iter()
+            .map(|inner| WithCspNonce {
+                csp_nonce: "fake_csp_nonce".into(),
+                inner,
+            })
+            .collect()
+    }
+}
+
 /// Context with a user session in it
 #[derive(Serialize)]
 pub struct WithSession<T> {
@@ -262,6 +415,15 @@ pub enum PostAuthContext {
 
     /// Change the account password
     ChangePassword,
+
+    /// Manage the email addresses of the account
+    ManageEmails,
+
+    /// Export the data of the account
+    ExportAccount,
+
+    /// Delete the account
+    DeleteAccount,
 }
 
 /// Context used by the `login.html` template
@@ -269,7 +431,7 @@ pub enum PostAuthContext {
 pub struct LoginContext {
     form: FormState<LoginFormField>,
     next: Option<PostAuthContext>,
-    register_link: String,
+    register_link: Option<String>,
 }
 
 impl TemplateContext for LoginContext {
@@ -278,11 +440,15 @@ impl TemplateContext for LoginContext {
         Self: Sized,
     {
         // TODO: samples with errors
-        vec![LoginContext {
-            form: FormState::default(),
-            next: None,
-            register_link: "/register".to_string(),
-        }]
+        vec![
+            LoginContext {
+                form: FormState::default(),
+                next: None,
+                register_link: Some("/register".to_string()),
+            },
+            // Registration disabled
+            LoginContext::default(),
+        ]
     }
 }
 
@@ -306,7 +472,7 @@ impl LoginContext {
     #[must_use]
     pub fn with_register_link(self, register_link: String) -> Self {
         Self {
-            register_link,
+            register_link: Some(register_link),
             ..self
         }
     }
@@ -387,6 +553,7 @@ impl RegisterContext {
 #[derive(Serialize)]
 pub struct ConsentContext {
     grant: AuthorizationGrant<()>,
+    scope_descriptions: Vec<String>,
     action: PostAuthAction,
 }
 
@@ -395,8 +562,15 @@ impl TemplateContext for ConsentContext {
     where
         Self: Sized,
     {
-        // TODO
-        vec![]
+        let scope_descriptions = ScopeDescriptions::builtin();
+        AuthorizationGrant::<()>::samples()
+            .into_iter()
+            .map(|grant| {
+                let descriptions = scope_descriptions.describe_scope(&grant.scope, &[]);
+                Self::new(grant, PostAuthAction::continue_grant(42))
+                    .with_scope_descriptions(descriptions)
+            })
+            .collect()
     }
 }
 
@@ -409,9 +583,19 @@ impl ConsentContext {
     {
         Self {
             grant: grant.into(),
+            scope_descriptions: Vec::new(),
             action,
         }
     }
+
+    /// Set the human-readable descriptions of the requested scope
+    #[must_use]
+    pub fn with_scope_descriptions(self, scope_descriptions: Vec<String>) -> Self {
+        Self {
+            scope_descriptions,
+            ..self
+        }
+    }
 }
 
 /// Fields of the reauthentication form
@@ -511,12 +695,15 @@ impl CompatSsoContext {
 
 /// Context used by the `account/index.html` template
 #[derive(Serialize)]
-pub struct AccountContext {
+pub struct AccountOverviewContext {
     active_sessions: usize,
+    client_sessions: usize,
+    primary_email: Option<UserEmail<()>>,
+    last_authentication: Option<Authentication<()>>,
     emails: Vec<UserEmail<()>>,
 }
 
-impl AccountContext {
+impl AccountOverviewContext {
     /// Constructs a context for the "my account" page
     #[must_use]
     pub fn new<T>(active_sessions: usize, emails: Vec<T>) -> Self
@@ -525,18 +712,369 @@ impl AccountContext {
     {
         Self {
             active_sessions,
+            client_sessions: 0,
+            primary_email: None,
+            last_authentication: None,
             emails: emails.into_iter().map(Into::into).collect(),
         }
     }
+
+    /// Set the primary email of the user
+    #[must_use]
+    pub fn with_primary_email<T>(self, primary_email: Option<T>) -> Self
+    where
+        T: Into<UserEmail<()>>,
+    {
+        Self {
+            primary_email: primary_email.map(Into::into),
+            ..self
+        }
+    }
+
+    /// Set when the user last authenticated
+    #[must_use]
+    pub fn with_last_authentication<T>(self, last_authentication: Option<T>) -> Self
+    where
+        T: Into<Authentication<()>>,
+    {
+        Self {
+            last_authentication: last_authentication.map(Into::into),
+            ..self
+        }
+    }
+
+    /// Set how many sessions clients hold on behalf of the user
+    #[must_use]
+    pub fn with_client_sessions(self, client_sessions: usize) -> Self {
+        Self {
+            client_sessions,
+            ..self
+        }
+    }
 }
 
-impl TemplateContext for AccountContext {
+impl TemplateContext for AccountOverviewContext {
     fn sample() -> Vec<Self>
     where
         Self: Sized,
     {
         let emails: Vec<UserEmail<()>> = UserEmail::samples();
-        vec![Self::new(5, emails)]
+        let primary_email = emails.first().cloned();
+        let last_authentication = Authentication::<()> {
+            data: (),
+            created_at: Utc::now(),
+        };
+
+        vec![
+            Self::new(5, emails.clone())
+                .with_primary_email(primary_email)
+                .with_last_authentication(Some(last_authentication))
+                .with_client_sessions(3),
+            Self::new(1, emails),
+        ]
+    }
+}
+
+/// A session a client holds on behalf of the user, as listed on the sessions
+/// page
+#[derive(Serialize, Clone)]
+pub struct AccountSession {
+    data: String,
+    client_name: String,
+    device_name: Option<String>,
+    scope: String,
+    created_at: DateTime<Utc>,
+    last_active_at: Option<DateTime<Utc>>,
+    renamable: bool,
+}
+
+impl AccountSession {
+    /// Constructs a session entry. `data` is sent back by the forms acting on
+    /// this session
+    #[must_use]
+    pub fn new(
+        data: String,
+        client_name: String,
+        scope: String,
+        created_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            data,
+            client_name,
+            device_name: None,
+            scope,
+            created_at,
+            last_active_at: None,
+            renamable: false,
+        }
+    }
+
+    /// Set the name of the device holding this session
+    #[must_use]
+    pub fn with_device_name(self, device_name: Option<String>) -> Self {
+        Self {
+            device_name,
+            ..self
+        }
+    }
+
+    /// Set when the session was last used
+    #[must_use]
+    pub fn with_last_active_at(self, last_active_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            last_active_at,
+            ..self
+        }
+    }
+
+    /// Set whether the user can rename the device holding this session
+    #[must_use]
+    pub fn with_renamable(self, renamable: bool) -> Self {
+        Self { renamable, ..self }
+    }
+}
+
+/// Context used by the `account/sessions.html` template
+#[derive(Serialize)]
+pub struct AccountSessionsContext {
+    sessions: Vec<AccountSession>,
+}
+
+impl AccountSessionsContext {
+    /// Constructs a context for the session management page
+    #[must_use]
+    pub fn new(sessions: Vec<AccountSession>) -> Self {
+        Self { sessions }
+    }
+}
+
+impl TemplateContext for AccountSessionsContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let now = Utc::now();
+        let mut sessions: Vec<AccountSession> = Session::<()>::samples()
+            .into_iter()
+            .zip(1..)
+            .map(|(session, id)| {
+                AccountSession::new(
+                    format!("oauth2:{}", id),
+                    session
+                        .client
+                        .client_name
+                        .unwrap_or(session.client.client_id),
+                    session.scope.to_string().replace(' ', ", "),
+                    now - Duration::days(3),
+                )
+                .with_last_active_at(Some(now - Duration::hours(2)))
+            })
+            .collect();
+        sessions.push(
+            AccountSession::new(
+                "compat:1".to_owned(),
+                "Matrix client".to_owned(),
+                "Full account access".to_owned(),
+                now - Duration::days(30),
+            )
+            .with_device_name(Some("Work laptop".to_owned()))
+            .with_renamable(true),
+        );
+
+        vec![Self::new(sessions), Self::new(Vec::new())]
+    }
+}
+
+/// An upstream provider the user can link their account to, as listed on the
+/// linked accounts page
+#[derive(Serialize, Clone)]
+pub struct AccountUpstreamProvider {
+    id: String,
+    name: String,
+    linked_at: Option<DateTime<Utc>>,
+}
+
+impl AccountUpstreamProvider {
+    /// Constructs a provider entry
+    #[must_use]
+    pub fn new(id: String, name: String) -> Self {
+        Self {
+            id,
+            name,
+            linked_at: None,
+        }
+    }
+
+    /// Set when the user linked their account on this provider
+    #[must_use]
+    pub fn with_linked_at(self, linked_at: Option<DateTime<Utc>>) -> Self {
+        Self { linked_at, ..self }
+    }
+}
+
+/// Context used by the `account/upstream.html` template
+#[derive(Serialize)]
+pub struct AccountUpstreamContext {
+    providers: Vec<AccountUpstreamProvider>,
+}
+
+impl AccountUpstreamContext {
+    /// Constructs a context for the linked accounts page
+    #[must_use]
+    pub fn new(providers: Vec<AccountUpstreamProvider>) -> Self {
+        Self { providers }
+    }
+}
+
+impl TemplateContext for AccountUpstreamContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let now = Utc::now();
+        let providers = vec![
+            AccountUpstreamProvider::new("github".to_owned(), "GitHub".to_owned())
+                .with_linked_at(Some(now - Duration::days(3))),
+            AccountUpstreamProvider::new("corp".to_owned(), "Corporate SSO".to_owned()),
+        ];
+
+        vec![Self::new(providers), Self::new(Vec::new())]
+    }
+}
+
+/// Fields of the TOTP setup form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TotpFormField {
+    /// The code from the authenticator app
+    Code,
+}
+
+impl FormField for TotpFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Code => false,
+        }
+    }
+}
+
+/// Context used by the `account/totp.html` template
+#[derive(Serialize)]
+pub struct AccountTotpContext {
+    enabled: bool,
+    provisioning_uri: Option<Url>,
+    secret: Option<String>,
+    form: FormState<TotpFormField>,
+}
+
+impl AccountTotpContext {
+    /// Constructs a context for a user who already enabled TOTP
+    #[must_use]
+    pub fn enabled() -> Self {
+        Self {
+            enabled: true,
+            provisioning_uri: None,
+            secret: None,
+            form: FormState::default(),
+        }
+    }
+
+    /// Constructs a context for a user setting up TOTP. The provisioning URI
+    /// is shown as a QR code, and the secret for manual entry
+    #[must_use]
+    pub fn enrolling(provisioning_uri: Url, secret: String) -> Self {
+        Self {
+            enabled: false,
+            provisioning_uri: Some(provisioning_uri),
+            secret: Some(secret),
+            form: FormState::default(),
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<TotpFormField>) -> Self {
+        Self { form, ..self }
+    }
+}
+
+impl TemplateContext for AccountTotpContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_owned();
+        let provisioning_uri = Url::parse(&format!(
+            "otpauth://totp/example.com:alice?secret={}&issuer=example.com",
+            secret
+        ))
+        .unwrap();
+
+        let mut invalid_code = FormState::default();
+        invalid_code.add_error_on_field(TotpFormField::Code, FieldError::Invalid);
+
+        vec![
+            Self::enabled(),
+            Self::enrolling(provisioning_uri.clone(), secret.clone()),
+            Self::enrolling(provisioning_uri, secret).with_form_state(invalid_code),
+        ]
+    }
+}
+
+/// Fields of the account deletion form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccountDeleteFormField {
+    /// The username, typed again to confirm the deletion
+    Confirmation,
+}
+
+impl FormField for AccountDeleteFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Confirmation => false,
+        }
+    }
+}
+
+/// Context used by the `account/delete.html` template
+#[derive(Serialize)]
+pub struct AccountDeleteContext {
+    erase_after: Option<DateTime<Utc>>,
+    form: FormState<AccountDeleteFormField>,
+}
+
+impl AccountDeleteContext {
+    /// Constructs a context for the account deletion page, with when the
+    /// account will be erased if the user already asked for it
+    #[must_use]
+    pub fn new(erase_after: Option<DateTime<Utc>>) -> Self {
+        Self {
+            erase_after,
+            form: FormState::default(),
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<AccountDeleteFormField>) -> Self {
+        Self { form, ..self }
+    }
+}
+
+impl TemplateContext for AccountDeleteContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let mut mismatch = FormState::default();
+        mismatch.add_error_on_field(AccountDeleteFormField::Confirmation, FieldError::Invalid);
+
+        vec![
+            Self::new(None),
+            Self::new(None).with_form_state(mismatch),
+            Self::new(Some(Utc::now() + Duration::days(7))),
+        ]
     }
 }
 
@@ -545,13 +1083,23 @@ impl TemplateContext for AccountContext {
 #[serde(bound(serialize = "T: StorageBackend"))]
 pub struct AccountEmailsContext<T: StorageBackend> {
     emails: Vec<UserEmail<T>>,
+    form: FormState<EmailAddFormField>,
 }
 
 impl<T: StorageBackend> AccountEmailsContext<T> {
     /// Constructs a context for the email management page
     #[must_use]
     pub fn new(emails: Vec<UserEmail<T>>) -> Self {
-        Self { emails }
+        Self {
+            emails,
+            form: FormState::default(),
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<EmailAddFormField>) -> Self {
+        Self { form, ..self }
     }
 }
 
@@ -560,8 +1108,17 @@ impl<T: StorageBackend> TemplateContext for AccountEmailsContext<T> {
     where
         Self: Sized,
     {
-        let emails: Vec<UserEmail<T>> = UserEmail::samples();
-        vec![Self::new(emails)]
+        let mut rate_limited = FormState::default();
+        rate_limited.add_error_on_form(FormError::RateLimited);
+
+        let mut in_use = FormState::default();
+        in_use.add_error_on_field(EmailAddFormField::Email, FieldError::Exists);
+
+        vec![
+            Self::new(UserEmail::samples()),
+            Self::new(UserEmail::samples()).with_form_state(rate_limited),
+            Self::new(UserEmail::samples()).with_form_state(in_use),
+        ]
     }
 }
 
@@ -657,17 +1214,19 @@ impl TemplateContext for EmailVerificationPageContext {
     where
         Self: Sized,
     {
-        let email = UserEmail {
+        let email = UserEmail::<()> {
             data: (),
             email: "foobar@example.com".to_string(),
             created_at: Utc::now(),
             confirmed_at: None,
         };
 
-        vec![Self {
-            form: FormState::default(),
-            email,
-        }]
+        let in_use = FormState::default().with_error_on_form(FormError::EmailInUse);
+
+        vec![
+            Self::new(email.clone()),
+            Self::new(email).with_form_state(in_use),
+        ]
     }
 }
 
@@ -687,10 +1246,36 @@ impl FormField for EmailAddFormField {
     }
 }
 
+/// A challenge (CAPTCHA) users have to solve before submitting a form
+#[derive(Serialize, Debug, Clone)]
+pub struct ChallengeWidget {
+    script: String,
+    class: String,
+    site_key: String,
+}
+
+impl ChallengeWidget {
+    /// Constructs a widget loaded by the given script, which fills the
+    /// elements with the given class
+    #[must_use]
+    pub fn new(
+        script: impl Into<String>,
+        class: impl Into<String>,
+        site_key: impl Into<String>,
+    ) -> Self {
+        Self {
+            script: script.into(),
+            class: class.into(),
+            site_key: site_key.into(),
+        }
+    }
+}
+
 /// Context used by the `pages/account/verify.html` templates
 #[derive(Serialize, Default)]
 pub struct EmailAddContext {
     form: FormState<EmailAddFormField>,
+    challenge: Option<ChallengeWidget>,
 }
 
 impl EmailAddContext {
@@ -703,7 +1288,16 @@ impl EmailAddContext {
     /// Set the form state
     #[must_use]
     pub fn with_form_state(form: FormState<EmailAddFormField>) -> Self {
-        Self { form }
+        Self {
+            form,
+            challenge: None,
+        }
+    }
+
+    /// Set the challenge users have to solve, if any
+    #[must_use]
+    pub fn with_challenge(self, challenge: Option<ChallengeWidget>) -> Self {
+        Self { challenge, ..self }
     }
 }
 
@@ -712,7 +1306,171 @@ impl TemplateContext for EmailAddContext {
     where
         Self: Sized,
     {
-        vec![Self::default()]
+        let in_use =
+            FormState::default().with_error_on_field(EmailAddFormField::Email, FieldError::Exists);
+        let challenge_failed = FormState::default().with_error_on_form(FormError::ChallengeFailed);
+        let challenge = ChallengeWidget::new(
+            "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            "cf-turnstile",
+            "1x00000000000000000000AA",
+        );
+
+        vec![
+            Self::default(),
+            Self::with_form_state(in_use),
+            Self::with_form_state(challenge_failed).with_challenge(Some(challenge)),
+        ]
+    }
+}
+
+/// Fields of the password reset request form
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordResetFormField {
+    /// The email
+    Email,
+}
+
+impl FormField for PasswordResetFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::Email => true,
+        }
+    }
+}
+
+/// Context used by the `pages/password_reset.html` template
+#[derive(Serialize, Default)]
+pub struct PasswordResetContext {
+    form: FormState<PasswordResetFormField>,
+    sent: bool,
+}
+
+impl PasswordResetContext {
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<PasswordResetFormField>) -> Self {
+        Self { form, ..self }
+    }
+
+    /// Tell the user that a reset link was sent if the address is known
+    #[must_use]
+    pub fn sent(self) -> Self {
+        Self { sent: true, ..self }
+    }
+}
+
+impl TemplateContext for PasswordResetContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let invalid = FormState::default()
+            .with_error_on_field(PasswordResetFormField::Email, FieldError::Invalid);
+
+        vec![
+            Self::default(),
+            Self::default().with_form_state(invalid),
+            Self::default().sent(),
+        ]
+    }
+}
+
+/// Fields of the form used to set a new password from a reset link
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordResetCompleteFormField {
+    /// The new password
+    NewPassword,
+
+    /// The new password confirmation
+    NewPasswordConfirm,
+}
+
+impl FormField for PasswordResetCompleteFormField {
+    fn keep(&self) -> bool {
+        match self {
+            Self::NewPassword | Self::NewPasswordConfirm => false,
+        }
+    }
+}
+
+/// Context used by the `pages/password_reset_complete.html` template
+#[derive(Serialize)]
+pub struct PasswordResetCompleteContext {
+    form: FormState<PasswordResetCompleteFormField>,
+    token: String,
+}
+
+impl PasswordResetCompleteContext {
+    /// Constructs a context for the page setting a new password with the
+    /// token from a reset link
+    #[must_use]
+    pub fn new(token: String) -> Self {
+        Self {
+            form: FormState::default(),
+            token,
+        }
+    }
+
+    /// Set the form state
+    #[must_use]
+    pub fn with_form_state(self, form: FormState<PasswordResetCompleteFormField>) -> Self {
+        Self { form, ..self }
+    }
+}
+
+impl TemplateContext for PasswordResetCompleteContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        let invalid_link = FormState::default().with_error_on_form(FormError::InvalidLink);
+        let too_weak = FormState::default().with_error_on_field(
+            PasswordResetCompleteFormField::NewPassword,
+            FieldError::Policy {
+                message: "password is too weak".to_owned(),
+            },
+        );
+
+        vec![
+            Self::new("abc123".to_owned()),
+            Self::new("abc123".to_owned()).with_form_state(invalid_link),
+            Self::new("abc123".to_owned()).with_form_state(too_weak),
+        ]
+    }
+}
+
+/// Context used by the `emails/password_reset.{txt,html,subject}` templates
+#[derive(Serialize)]
+pub struct PasswordResetEmailContext {
+    user: User<()>,
+    link: Url,
+}
+
+impl PasswordResetEmailContext {
+    /// Constructs a context for the password reset email
+    #[must_use]
+    pub fn new(user: User<()>, link: Url) -> Self {
+        Self { user, link }
+    }
+}
+
+impl TemplateContext for PasswordResetEmailContext {
+    fn sample() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        User::samples()
+            .into_iter()
+            .map(|user| Self {
+                user,
+                link: Url::parse(
+                    "https://example.com/account/password/reset/complete?token=abc123",
+                )
+                .unwrap(),
+            })
+            .collect()
     }
 }
 
@@ -747,6 +1505,19 @@ impl<T> FormPostContext<T> {
             params,
         }
     }
+
+    /// Attach the nonce of the Content Security Policy, which the script
+    /// submitting the form needs.
+    ///
+    /// Unlike [`TemplateContext::with_csp_nonce`], the parameters don't have
+    /// to be a [`TemplateContext`]
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn with_csp_nonce<N: ToString>(self, csp_nonce: N) -> WithCspNonce<Self> {
+        WithCspNonce {
+            csp_nonce: csp_nonce.to_string(),
+            inner: self,
+        }
+    }
 }
 
 /// Context used by the `error.html` template
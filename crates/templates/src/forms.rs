@@ -59,6 +59,18 @@ pub enum FormError {
     /// There was an internal error
     Internal,
 
+    /// Too many attempts were made in a short time
+    RateLimited,
+
+    /// The email address is already used by another user
+    EmailInUse,
+
+    /// The link used is invalid, expired or was already used
+    InvalidLink,
+
+    /// The challenge (CAPTCHA) was not solved
+    ChallengeFailed,
+
     /// Denied by the policy
     Policy {
         /// Message for this policy violation
@@ -0,0 +1,354 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Message catalogs used to translate the templates
+//!
+//! Catalogs are written in a subset of the [Fluent] syntax: one
+//! `message-id = Text` per line, where the text can have `{ $name }`
+//! placeables, and lines starting with `#` are comments. Templates translate
+//! messages with the `t` function:
+//!
+//! ```text
+//! {{ t(key="account-title", locale=locale) }}
+//! {{ t(key="account-greeting", locale=locale, name=user.username) }}
+//! ```
+//!
+//! [Fluent]: https://projectfluent.org/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
+
+use language_tags::LanguageTag;
+use tera::Value;
+use thiserror::Error;
+
+/// The locale used when none of the languages of the user is available, and
+/// for the messages missing from the other catalogs
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// The catalogs shipped with the builtin templates, by locale
+pub(crate) const BUILTIN_CATALOGS: [(&str, &str); 2] = [
+    ("en", include_str!("res/translations/en.ftl")),
+    ("fr", include_str!("res/translations/fr.ftl")),
+];
+
+/// A message catalog could not be loaded
+#[derive(Debug, Error)]
+pub enum CatalogError {
+    /// The catalog could not be read
+    #[error("could not read the catalog")]
+    Io(#[from] std::io::Error),
+
+    /// The catalog has a syntax error
+    #[error("line {line}: {message}")]
+    Syntax {
+        /// The line of the error, starting at 1
+        line: usize,
+
+        /// What is wrong with it
+        message: &'static str,
+    },
+}
+
+/// The messages of one locale
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl FromStr for Catalog {
+    type Err = CatalogError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut messages = HashMap::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let syntax_error = |message| CatalogError::Syntax {
+                line: index + 1,
+                message,
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, text) = line
+                .split_once('=')
+                .ok_or_else(|| syntax_error("expected `message-id = Text`"))?;
+            let id = id.trim();
+            if id.is_empty()
+                || !id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(syntax_error("invalid message identifier"));
+            }
+
+            if messages
+                .insert(id.to_owned(), text.trim().to_owned())
+                .is_some()
+            {
+                return Err(syntax_error("duplicate message identifier"));
+            }
+        }
+
+        Ok(Self { messages })
+    }
+}
+
+impl Catalog {
+    /// Get the text of a message, without its placeables replaced
+    #[must_use]
+    pub fn get(&self, id: &str) -> Option<&str> {
+        self.messages.get(id).map(String::as_str)
+    }
+}
+
+/// Replace the `{ $name }` placeables of a message. Placeables without a
+/// matching argument are kept as-is
+fn format_message(text: &str, args: &HashMap<String, Value>) -> String {
+    let mut formatted = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let (before, after) = rest.split_at(start);
+        formatted.push_str(before);
+
+        let end = if let Some(end) = after.find('}') {
+            end
+        } else {
+            // An unclosed brace is kept as text, like the rest of the message
+            formatted.push_str(after);
+            return formatted;
+        };
+
+        let placeable = &after[..=end];
+        let value = placeable[1..end]
+            .trim()
+            .strip_prefix('$')
+            .and_then(|name| args.get(name));
+        match value {
+            Some(Value::String(value)) => formatted.push_str(value),
+            Some(value) => formatted.push_str(&value.to_string()),
+            None => formatted.push_str(placeable),
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    formatted.push_str(rest);
+    formatted
+}
+
+/// All the catalogs, with the negotiation of the locale used for a request
+#[derive(Debug, Clone, Default)]
+pub struct Translator {
+    /// Keyed by the lowercased locale
+    catalogs: BTreeMap<String, Catalog>,
+}
+
+impl Translator {
+    /// Add the messages of a catalog to those of its locale, replacing the
+    /// existing ones with the same identifier
+    pub fn add_catalog(&mut self, locale: &str, catalog: Catalog) {
+        self.catalogs
+            .entry(locale.to_ascii_lowercase())
+            .or_default()
+            .messages
+            .extend(catalog.messages);
+    }
+
+    /// Pick the locale to use from the languages of the user, most preferred
+    /// first.
+    ///
+    /// `fr-CA` is matched by a `fr-CA` catalog, then by a `fr` catalog, before
+    /// moving to the next language. Falls back to [`DEFAULT_LOCALE`].
+    #[must_use]
+    pub fn negotiate(&self, languages: &[LanguageTag]) -> String {
+        languages
+            .iter()
+            .find_map(|tag| {
+                [tag.as_str(), tag.primary_language()]
+                    .into_iter()
+                    .map(str::to_ascii_lowercase)
+                    .find(|locale| self.catalogs.contains_key(locale))
+            })
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_owned())
+    }
+
+    /// Translate a message, falling back to the primary language of the
+    /// locale, then to [`DEFAULT_LOCALE`]
+    #[must_use]
+    pub fn translate(
+        &self,
+        locale: &str,
+        id: &str,
+        args: &HashMap<String, Value>,
+    ) -> Option<String> {
+        let locale = locale.to_ascii_lowercase();
+        let primary_language = locale.split('-').next().unwrap_or_default();
+
+        let text = [locale.as_str(), primary_language, DEFAULT_LOCALE]
+            .into_iter()
+            .find_map(|locale| self.catalogs.get(locale)?.get(id))?;
+        Some(format_message(text, args))
+    }
+}
+
+/// The `t` function of the templates
+pub(crate) struct Translate(pub Arc<Translator>);
+
+impl tera::Function for Translate {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let key = args
+            .get("key")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("Invalid parameter `key`"))?;
+        let locale = match args.get("locale") {
+            Some(locale) => locale
+                .as_str()
+                .ok_or_else(|| tera::Error::msg("Invalid parameter `locale`"))?,
+            None => DEFAULT_LOCALE,
+        };
+
+        self.0
+            .translate(locale, key, args)
+            .map(Value::String)
+            .ok_or_else(|| tera::Error::msg(format!("Unknown message {:?}", key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translator() -> Translator {
+        let mut translator = Translator::default();
+        translator.add_catalog(
+            "en",
+            "# Comments are ignored\nhello = Hello { $name }!\nbye = Goodbye"
+                .parse()
+                .unwrap(),
+        );
+        translator.add_catalog("fr", "hello = Bonjour { $name } !".parse().unwrap());
+        translator.add_catalog("fr-CA", "bye = Bye".parse().unwrap());
+        translator
+    }
+
+    fn languages(tags: &[&str]) -> Vec<LanguageTag> {
+        tags.iter().map(|tag| tag.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_catalog() {
+        let catalog: Catalog = "\n# Comment\nfirst = One\n  second-message=  Two = 2  \n"
+            .parse()
+            .unwrap();
+        assert_eq!(catalog.get("first"), Some("One"));
+        assert_eq!(catalog.get("second-message"), Some("Two = 2"));
+        assert_eq!(catalog.get("third"), None);
+
+        for (source, line) in [
+            ("first = One\nno equal sign", 2),
+            ("= No identifier", 1),
+            ("not valid = Text", 1),
+            ("first = One\n\nfirst = Again", 3),
+        ] {
+            match source.parse::<Catalog>() {
+                Err(CatalogError::Syntax { line: l, .. }) => assert_eq!(l, line, "{}", source),
+                other => panic!("{:?} should not parse: {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        let translator = translator();
+        assert_eq!(translator.negotiate(&languages(&["fr-CA"])), "fr-ca");
+        assert_eq!(translator.negotiate(&languages(&["fr-BE"])), "fr");
+        assert_eq!(translator.negotiate(&languages(&["de", "fr", "en"])), "fr");
+        assert_eq!(translator.negotiate(&languages(&["de"])), DEFAULT_LOCALE);
+        assert_eq!(translator.negotiate(&[]), DEFAULT_LOCALE);
+    }
+
+    #[test]
+    fn translation() {
+        let translator = translator();
+        let args: HashMap<String, Value> = [("name".to_owned(), "Alice".into())].into();
+
+        assert_eq!(
+            translator.translate("fr", "hello", &args).as_deref(),
+            Some("Bonjour Alice !")
+        );
+        assert_eq!(
+            translator.translate("en", "hello", &args).as_deref(),
+            Some("Hello Alice!")
+        );
+
+        // Missing messages fall back to the primary language, then to English
+        assert_eq!(
+            translator.translate("fr-CA", "hello", &args).as_deref(),
+            Some("Bonjour Alice !")
+        );
+        assert_eq!(
+            translator.translate("fr", "bye", &args).as_deref(),
+            Some("Goodbye")
+        );
+        assert_eq!(
+            translator.translate("de", "bye", &args).as_deref(),
+            Some("Goodbye")
+        );
+        assert_eq!(translator.translate("en", "unknown", &args), None);
+
+        // Placeables without an argument are kept
+        assert_eq!(
+            translator
+                .translate("en", "hello", &HashMap::new())
+                .as_deref(),
+            Some("Hello { $name }!")
+        );
+    }
+
+    #[test]
+    fn unclosed_placeables() {
+        let args: HashMap<String, Value> = [("name".to_owned(), "Alice".into())].into();
+
+        assert_eq!(format_message("Hello { $name", &args), "Hello { $name");
+        assert_eq!(
+            format_message("{ $name } and { $name", &args),
+            "Alice and { $name"
+        );
+        assert_eq!(format_message("Trailing {", &args), "Trailing {");
+    }
+
+    #[test]
+    fn builtin_catalogs_are_complete() {
+        let default: Catalog = BUILTIN_CATALOGS[0].1.parse().unwrap();
+        for (locale, source) in BUILTIN_CATALOGS {
+            let catalog: Catalog = source.parse().unwrap();
+            for id in catalog.messages.keys() {
+                assert!(
+                    default.get(id).is_some(),
+                    "{:?} from the {:?} catalog is missing from the default one",
+                    id,
+                    locale
+                );
+            }
+        }
+    }
+}
@@ -21,46 +21,92 @@
 
 use std::{
     collections::HashSet,
+    ffi::OsStr,
     io::Cursor,
     path::{Path, PathBuf},
     string::ToString,
-    sync::Arc,
+    sync::{Arc, PoisonError, RwLock},
 };
 
 use anyhow::{bail, Context as _};
+use language_tags::LanguageTag;
 use mas_config::TemplatesConfig;
 use mas_data_model::StorageBackend;
+use oauth2_types::{
+    scope::Scope,
+    scope_description::{ScopeDescriptionRule, ScopeDescriptions},
+};
 use serde::Serialize;
 use tera::{Context, Error as TeraError, Tera};
 use thiserror::Error;
-use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::RwLock, task::JoinError};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, task::JoinError};
 use tracing::{debug, info, warn};
 
 mod context;
 mod forms;
 mod functions;
+mod i18n;
 
 #[macro_use]
 mod macros;
+mod metrics;
 
 pub use self::{
     context::{
-        AccountContext, AccountEmailsContext, CompatSsoContext, ConsentContext, EmailAddContext,
-        EmailVerificationContext, EmailVerificationPageContext, EmptyContext, ErrorContext,
-        FormPostContext, IndexContext, LoginContext, LoginFormField, PostAuthContext,
-        ReauthContext, ReauthFormField, RegisterContext, RegisterFormField, TemplateContext,
-        WithCsrf, WithOptionalSession, WithSession,
+        AccountDeleteContext, AccountDeleteFormField, AccountEmailsContext, AccountOverviewContext,
+        AccountSession, AccountSessionsContext, AccountTotpContext, AccountUpstreamContext,
+        AccountUpstreamProvider, AppContext, ChallengeWidget, CompatSsoContext, ConsentContext,
+        EmailAddContext, EmailAddFormField, EmailVerificationContext, EmailVerificationPageContext,
+        EmptyContext, ErrorContext, FormPostContext, IndexContext, LoginContext, LoginFormField,
+        PasswordResetCompleteContext, PasswordResetCompleteFormField, PasswordResetContext,
+        PasswordResetEmailContext, PasswordResetFormField, PostAuthContext, ReauthContext,
+        ReauthFormField, RegisterContext, RegisterFormField, TemplateContext, TotpFormField,
+        WithAppContext, WithCspNonce, WithCsrf, WithLocale, WithOptionalSession, WithSession,
     },
     forms::{FieldError, FormError, FormField, FormState, ToFormState},
+    i18n::{Catalog, CatalogError, Translator, DEFAULT_LOCALE},
 };
 
 /// Wrapper around [`tera::Tera`] helping rendering the various templates
 #[derive(Debug, Clone)]
 pub struct Templates {
-    tera: Arc<RwLock<Tera>>,
+    /// Replaced as a whole when reloading, so that a render never mixes two
+    /// versions of the templates or of the settings
+    state: Arc<RwLock<TemplatesState>>,
+
+    /// The configuration the templates were first loaded with. Where the
+    /// templates are loaded from can't change without a restart
     config: TemplatesConfig,
 }
 
+/// The parts of the templates which can be reloaded
+#[derive(Debug)]
+struct TemplatesState {
+    tera: Tera,
+    translator: Arc<Translator>,
+    app: AppContext,
+    scope_descriptions: ScopeDescriptions,
+}
+
+impl TemplatesState {
+    fn new((tera, translator): (Tera, Arc<Translator>), config: &TemplatesConfig) -> Self {
+        let scope_descriptions =
+            ScopeDescriptions::builtin().with_rules(config.scope_descriptions.iter().map(|rule| {
+                ScopeDescriptionRule {
+                    pattern: rule.scope.clone(),
+                    descriptions: rule.description.clone(),
+                }
+            }));
+
+        Self {
+            tera,
+            translator,
+            app: AppContext::from(config),
+            scope_descriptions,
+        }
+    }
+}
+
 /// There was an issue while loading the templates
 #[derive(Error, Debug)]
 pub enum TemplateLoadingError {
@@ -80,6 +126,17 @@ pub enum TemplateLoadingError {
         /// List of templates that were loaded
         loaded: HashSet<String>,
     },
+
+    /// A message catalog could not be loaded
+    #[error("could not load the message catalog {path:?}")]
+    Catalog {
+        /// The path of the catalog
+        path: PathBuf,
+
+        /// The underlying error
+        #[source]
+        source: CatalogError,
+    },
 }
 
 impl Templates {
@@ -130,17 +187,105 @@ impl Templates {
 
     /// Load the templates from [the config][`TemplatesConfig`]
     pub async fn load_from_config(config: &TemplatesConfig) -> Result<Self, TemplateLoadingError> {
-        let tera = Self::load(config.path.as_deref(), config.builtin).await?;
+        let loaded = Self::load(config.path.as_deref(), config.builtin).await?;
 
         Ok(Self {
-            tera: Arc::new(RwLock::new(tera)),
+            state: Arc::new(RwLock::new(TemplatesState::new(loaded, config))),
             config: config.clone(),
         })
     }
 
-    async fn load(path: Option<&str>, builtin: bool) -> Result<Tera, TemplateLoadingError> {
+    /// Describe the tokens of a scope to the user, in the first of the given
+    /// languages available
+    #[must_use]
+    pub fn describe_scope(&self, scope: &Scope, languages: &[LanguageTag]) -> Vec<String> {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .scope_descriptions
+            .describe_scope(scope, languages)
+    }
+
+    /// Pick the locale to render the templates in from the languages of the
+    /// user, most preferred first
+    #[must_use]
+    pub fn negotiate_locale(&self, languages: &[LanguageTag]) -> String {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .translator
+            .negotiate(languages)
+    }
+
+    /// Load the `*.ftl` catalogs from the `translations` folder of a template
+    /// folder, if there is one
+    async fn load_catalogs(
+        root: &Path,
+        translator: &mut Translator,
+    ) -> Result<(), TemplateLoadingError> {
+        let folder = root.join("translations");
+        let mut entries = match tokio::fs::read_dir(&folder).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(TemplateLoadingError::Catalog {
+                    path: folder,
+                    source: e.into(),
+                })
+            }
+        };
+
+        let catalog_error = |path: &Path, source: CatalogError| TemplateLoadingError::Catalog {
+            path: path.to_owned(),
+            source,
+        };
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| catalog_error(&folder, e.into()))?
+        {
+            let path = entry.path();
+            let locale = match path.file_stem().and_then(OsStr::to_str) {
+                Some(locale) if path.extension() == Some(OsStr::new("ftl")) => locale.to_owned(),
+                _ => continue,
+            };
+
+            info!(?path, "Loading message catalog");
+            let source = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|e| catalog_error(&path, e.into()))?;
+            let catalog = source
+                .parse::<Catalog>()
+                .map_err(|e| catalog_error(&path, e))?;
+            translator.add_catalog(&locale, catalog);
+        }
+
+        Ok(())
+    }
+
+    async fn load(
+        path: Option<&str>,
+        builtin: bool,
+    ) -> Result<(Tera, Arc<Translator>), TemplateLoadingError> {
         let mut teras = Vec::new();
 
+        // The catalogs of the template folders take precedence over the
+        // builtin ones, message by message
+        let mut translator = Translator::default();
+        if builtin {
+            for (locale, source) in i18n::BUILTIN_CATALOGS {
+                let catalog =
+                    source
+                        .parse::<Catalog>()
+                        .map_err(|e| TemplateLoadingError::Catalog {
+                            path: PathBuf::from(format!("translations/{}.ftl", locale)),
+                            source: e,
+                        })?;
+                translator.add_catalog(locale, catalog);
+            }
+        }
+
         let roots = Self::roots(path, builtin).await;
         for maybe_root in roots {
             let root = match maybe_root {
@@ -151,6 +296,8 @@ impl Templates {
                 }
             };
 
+            Self::load_catalogs(&root, &mut translator).await?;
+
             // This uses blocking I/Os, do that in a blocking task
             let tera = tokio::task::spawn_blocking(move || {
                 // Using `to_string_lossy` here is probably fine
@@ -179,6 +326,8 @@ impl Templates {
         tera.check_macro_files()?;
 
         self::functions::register(&mut tera);
+        let translator = Arc::new(translator);
+        tera.register_function("t", i18n::Translate(translator.clone()));
 
         let loaded: HashSet<_> = tera.get_template_names().collect();
         let needed: HashSet<_> = TEMPLATES.into_iter().map(|(name, _)| name).collect();
@@ -186,7 +335,7 @@ impl Templates {
         let missing: HashSet<_> = needed.difference(&loaded).collect();
 
         if missing.is_empty() {
-            Ok(tera)
+            Ok((tera, translator))
         } else {
             let missing = missing.into_iter().map(ToString::to_string).collect();
             let loaded = loaded.into_iter().map(ToString::to_string).collect();
@@ -195,12 +344,58 @@ impl Templates {
     }
 
     /// Reload the templates on disk
+    ///
+    /// Nothing changes if the new templates fail to load or to render.
     pub async fn reload(&self) -> anyhow::Result<()> {
-        // Prepare the new Tera instance
-        let new_tera = Self::load(self.config.path.as_deref(), self.config.builtin).await?;
+        // Prepare the new state, keeping the current settings
+        let (tera, translator) =
+            Self::load(self.config.path.as_deref(), self.config.builtin).await?;
+        let new_state = {
+            let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+            TemplatesState {
+                tera,
+                translator,
+                app: state.app.clone(),
+                scope_descriptions: state.scope_descriptions.clone(),
+            }
+        };
+
+        self.swap(new_state).await
+    }
+
+    /// Reload the templates on disk, and the settings which can change
+    /// without a restart from a new configuration: the service name, the
+    /// support link and the scope descriptions.
+    ///
+    /// Nothing changes if the new templates fail to load or to render.
+    pub async fn reload_with_config(&self, config: &TemplatesConfig) -> anyhow::Result<()> {
+        if config.path != self.config.path || config.builtin != self.config.builtin {
+            warn!("Where the templates are loaded from only changes after a restart");
+        }
+
+        // Prepare the new state
+        let loaded = Self::load(self.config.path.as_deref(), self.config.builtin).await?;
+        let new_state = TemplatesState::new(loaded, config);
+
+        self.swap(new_state).await
+    }
 
-        // Swap it
-        *self.tera.write().await = new_tera;
+    /// Swap the state, once all the templates rendered fine with it
+    async fn swap(&self, new_state: TemplatesState) -> anyhow::Result<()> {
+        let candidate = Self {
+            state: Arc::new(RwLock::new(new_state)),
+            config: self.config.clone(),
+        };
+        candidate
+            .check_render()
+            .await
+            .context("the new templates failed to render, keeping the current ones")?;
+
+        let new_state = Arc::try_unwrap(candidate.state)
+            .expect("the candidate templates are not shared")
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        *self.state.write().unwrap_or_else(PoisonError::into_inner) = new_state;
 
         Ok(())
     }
@@ -211,7 +406,14 @@ impl Templates {
             bail!("Builtin templates are not included in dev binaries")
         }
 
-        let templates = TEMPLATES.into_iter().chain(EXTRA_TEMPLATES);
+        let catalogs = i18n::BUILTIN_CATALOGS
+            .into_iter()
+            .map(|(locale, source)| (format!("translations/{}.ftl", locale), Some(source)));
+        let templates = TEMPLATES
+            .into_iter()
+            .chain(EXTRA_TEMPLATES)
+            .map(|(name, source)| (name.to_owned(), source))
+            .chain(catalogs);
 
         let mut options = OpenOptions::new();
         if overwrite {
@@ -305,12 +507,24 @@ register_templates! {
     /// Render the home page
     pub fn render_index(WithCsrf<WithOptionalSession<IndexContext>>) { "pages/index.html" }
 
-    /// Render the account management page
-    pub fn render_account_index(WithCsrf<WithSession<AccountContext>>) { "pages/account/index.html" }
+    /// Render the account overview page
+    pub fn render_account_overview(WithLocale<WithCsrf<WithSession<AccountOverviewContext>>>) { "pages/account/index.html" }
+
+    /// Render the session management page
+    pub fn render_account_sessions(WithCsrf<WithSession<AccountSessionsContext>>) { "pages/account/sessions.html" }
+
+    /// Render the linked accounts page
+    pub fn render_account_upstream(WithCsrf<WithSession<AccountUpstreamContext>>) { "pages/account/upstream.html" }
+
+    /// Render the TOTP setup page
+    pub fn render_account_totp(WithCsrf<WithSession<AccountTotpContext>>) { "pages/account/totp.html" }
 
     /// Render the password change page
     pub fn render_account_password(WithCsrf<WithSession<EmptyContext>>) { "pages/account/password.html" }
 
+    /// Render the account deletion page
+    pub fn render_account_delete(WithCsrf<WithSession<AccountDeleteContext>>) { "pages/account/delete.html" }
+
     /// Render the emails management
     pub fn render_account_emails<T: StorageBackend>(WithCsrf<WithSession<AccountEmailsContext<T>>>) { "pages/account/emails/index.html" }
 
@@ -320,11 +534,17 @@ register_templates! {
     /// Render the email verification page
     pub fn render_account_add_email(WithCsrf<WithSession<EmailAddContext>>) { "pages/account/emails/add.html" }
 
+    /// Render the password reset request page
+    pub fn render_password_reset(WithCsrf<PasswordResetContext>) { "pages/password_reset.html" }
+
+    /// Render the page setting a new password from a reset link
+    pub fn render_password_reset_complete(WithCsrf<PasswordResetCompleteContext>) { "pages/password_reset_complete.html" }
+
     /// Render the re-authentication form
     pub fn render_reauth(WithCsrf<WithSession<ReauthContext>>) { "pages/reauth.html" }
 
     /// Render the form used by the form_post response mode
-    pub fn render_form_post<T: Serialize>(FormPostContext<T>) { "form_post.html" }
+    pub fn render_form_post<T: Serialize>(WithCspNonce<FormPostContext<T>>) { "form_post.html" }
 
     /// Render the HTML error page
     pub fn render_error(ErrorContext) { "pages/error.html" }
@@ -337,6 +557,15 @@ register_templates! {
 
     /// Render the email verification subject
     pub fn render_email_verification_subject(EmailVerificationContext) { "emails/verification.subject" }
+
+    /// Render the password reset email (plain text variant)
+    pub fn render_email_password_reset_txt(PasswordResetEmailContext) { "emails/password_reset.txt" }
+
+    /// Render the password reset email (HTML text variant)
+    pub fn render_email_password_reset_html(PasswordResetEmailContext) { "emails/password_reset.html" }
+
+    /// Render the password reset email subject
+    pub fn render_email_password_reset_subject(PasswordResetEmailContext) { "emails/password_reset.subject" }
 }
 
 impl Templates {
@@ -348,23 +577,35 @@ impl Templates {
         check::render_consent(self).await?;
         check::render_sso_login(self).await?;
         check::render_index(self).await?;
-        check::render_account_index(self).await?;
+        check::render_account_overview(self).await?;
+        check::render_account_sessions(self).await?;
+        check::render_account_upstream(self).await?;
+        check::render_account_totp(self).await?;
         check::render_account_password(self).await?;
+        check::render_account_delete(self).await?;
         check::render_account_emails::<()>(self).await?;
         check::render_account_add_email(self).await?;
         check::render_account_verify_email(self).await?;
+        check::render_password_reset(self).await?;
+        check::render_password_reset_complete(self).await?;
         check::render_reauth(self).await?;
         check::render_form_post::<EmptyContext>(self).await?;
         check::render_error(self).await?;
         check::render_email_verification_txt(self).await?;
         check::render_email_verification_html(self).await?;
         check::render_email_verification_subject(self).await?;
+        check::render_email_password_reset_txt(self).await?;
+        check::render_email_password_reset_html(self).await?;
+        check::render_email_password_reset_subject(self).await?;
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use mas_config::ScopeDescriptionConfig;
+    use mas_data_model::BrowserSession;
+
     use super::*;
 
     #[tokio::test]
@@ -372,9 +613,293 @@ mod tests {
         let config = TemplatesConfig {
             path: None,
             builtin: true,
+            ..TemplatesConfig::default()
         };
 
         let templates = Templates::load_from_config(&config).await.unwrap();
         templates.check_render().await.unwrap();
     }
+
+    #[tokio::test]
+    async fn broken_templates_fail_the_render_check() {
+        // This template compiles, but can't be rendered
+        let path =
+            std::env::temp_dir().join(format!("mas-broken-templates-{}", std::process::id()));
+        tokio::fs::create_dir_all(path.join("pages")).await.unwrap();
+        tokio::fs::write(path.join("pages/index.html"), "{{ this_does_not_exist }}")
+            .await
+            .unwrap();
+
+        let config = TemplatesConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..TemplatesConfig::default()
+        };
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        tokio::fs::remove_dir_all(&path).await.unwrap();
+
+        let err = templates.check_render().await.unwrap_err();
+        assert!(format!("{:#}", err).contains("pages/index.html"));
+    }
+
+    #[tokio::test]
+    async fn templates_include_the_service_name() {
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            service_name: "Example Auth".to_owned(),
+            support_url: Some("https://example.com/help".parse().unwrap()),
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let ctx = IndexContext::sample()
+            .remove(0)
+            .maybe_with_session::<()>(None)
+            .with_csrf("csrf");
+
+        let content = templates.render_index(&ctx).await.unwrap();
+        assert!(content.contains("<title>Example Auth</title>"));
+        assert!(content.contains("Get help with Example Auth"));
+    }
+
+    #[tokio::test]
+    async fn reload_templates_and_settings() {
+        // Custom templates take precedence over the builtin ones
+        let path =
+            std::env::temp_dir().join(format!("mas-reload-templates-{}", std::process::id()));
+        tokio::fs::create_dir_all(path.join("pages")).await.unwrap();
+        let index = path.join("pages/index.html");
+        tokio::fs::write(&index, "First version of {{ app.service_name }}")
+            .await
+            .unwrap();
+
+        let config = TemplatesConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            service_name: "Example Auth".to_owned(),
+            ..TemplatesConfig::default()
+        };
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let ctx = IndexContext::sample()
+            .remove(0)
+            .maybe_with_session::<()>(None)
+            .with_csrf("csrf");
+        assert_eq!(
+            templates.render_index(&ctx).await.unwrap(),
+            "First version of Example Auth"
+        );
+
+        // Nothing changes until the templates are reloaded
+        tokio::fs::write(&index, "Second version of {{ app.service_name }}")
+            .await
+            .unwrap();
+        assert_eq!(
+            templates.render_index(&ctx).await.unwrap(),
+            "First version of Example Auth"
+        );
+        templates.reload().await.unwrap();
+        assert_eq!(
+            templates.render_index(&ctx).await.unwrap(),
+            "Second version of Example Auth"
+        );
+
+        // Broken templates are not swapped in
+        tokio::fs::write(&index, "{% if %}").await.unwrap();
+        assert!(templates.reload().await.is_err());
+        tokio::fs::write(&index, "{{ this_does_not_exist }}")
+            .await
+            .unwrap();
+        assert!(templates.reload().await.is_err());
+        assert_eq!(
+            templates.render_index(&ctx).await.unwrap(),
+            "Second version of Example Auth"
+        );
+
+        // The settings can be reloaded with the templates
+        tokio::fs::write(&index, "Third version of {{ app.service_name }}")
+            .await
+            .unwrap();
+        let new_config = TemplatesConfig {
+            service_name: "Renamed Auth".to_owned(),
+            scope_descriptions: vec![ScopeDescriptionConfig {
+                scope: "openid".to_owned(),
+                description: [("en".to_owned(), "Know who you are".to_owned())].into(),
+            }],
+            ..config
+        };
+        templates.reload_with_config(&new_config).await.unwrap();
+        tokio::fs::remove_dir_all(&path).await.unwrap();
+
+        assert_eq!(
+            templates.render_index(&ctx).await.unwrap(),
+            "Third version of Renamed Auth"
+        );
+        assert_eq!(
+            templates.describe_scope(&"openid".parse().unwrap(), &[]),
+            vec!["Know who you are".to_owned()]
+        );
+    }
+
+    #[tokio::test]
+    async fn scope_descriptions_from_config() {
+        let config = TemplatesConfig {
+            scope_descriptions: vec![ScopeDescriptionConfig {
+                scope: "urn:example:*".to_owned(),
+                description: [
+                    ("en".to_owned(), "Use the example API".to_owned()),
+                    ("fr".to_owned(), "Utiliser l'API d'exemple".to_owned()),
+                ]
+                .into(),
+            }],
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let scope: Scope = "openid urn:example:read".parse().unwrap();
+        assert_eq!(
+            templates.describe_scope(&scope, &[]),
+            vec![
+                "See your profile info".to_owned(),
+                "Use the example API".to_owned()
+            ]
+        );
+
+        let french: LanguageTag = "fr-FR".parse().unwrap();
+        assert_eq!(
+            templates.describe_scope(&scope, &[french]),
+            vec![
+                "See your profile info".to_owned(),
+                "Utiliser l'API d'exemple".to_owned()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn render_account_overview() {
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let session = BrowserSession::<()>::samples().remove(0);
+        let ctx = AccountOverviewContext::sample()
+            .remove(0)
+            .with_session(session)
+            .with_csrf("csrf")
+            .with_locale(DEFAULT_LOCALE.to_owned());
+
+        let content = templates.render_account_overview(&ctx).await.unwrap();
+        assert!(content.contains("alice@example.com"));
+        assert!(content.contains("Last login"));
+    }
+
+    #[tokio::test]
+    async fn render_translated_page() {
+        async fn render(templates: &Templates, languages: &[&str]) -> String {
+            let languages: Vec<LanguageTag> =
+                languages.iter().map(|tag| tag.parse().unwrap()).collect();
+            let session = BrowserSession::<()>::samples().remove(0);
+            let ctx = AccountOverviewContext::sample()
+                .remove(0)
+                .with_session(session)
+                .with_csrf("csrf")
+                .with_locale(templates.negotiate_locale(&languages));
+            templates.render_account_overview(&ctx).await.unwrap()
+        }
+
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let content = render(&templates, &["fr-BE", "en"]).await;
+        assert!(content.contains(r#"<html lang="fr">"#));
+        assert!(content.contains("Gérer mon compte"));
+        assert!(content.contains("Dernière connexion"));
+        assert!(!content.contains("Manage my account"));
+
+        // Unknown locales fall back to English
+        let content = render(&templates, &["tlh"]).await;
+        assert!(content.contains(r#"<html lang="en">"#));
+        assert!(content.contains("Manage my account"));
+        assert!(content.contains("Last login"));
+    }
+
+    #[tokio::test]
+    async fn render_account_sessions() {
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let session = BrowserSession::<()>::samples().remove(0);
+        let ctx = AccountSessionsContext::sample()
+            .remove(0)
+            .with_session(session)
+            .with_csrf("csrf");
+
+        let content = templates.render_account_sessions(&ctx).await.unwrap();
+        assert!(content.contains("Client 1"));
+        assert!(content.contains("email, openid, profile"));
+        assert!(content.contains("Matrix client on Work laptop"));
+        assert!(content.contains(r#"value="oauth2:1""#));
+        assert!(content.contains(r#"value="compat:1""#));
+        assert!(content.contains(r#"value="rename""#));
+    }
+
+    #[tokio::test]
+    async fn render_account_upstream() {
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let session = BrowserSession::<()>::samples().remove(0);
+        let ctx = AccountUpstreamContext::sample()
+            .remove(0)
+            .with_session(session)
+            .with_csrf("csrf");
+
+        let content = templates.render_account_upstream(&ctx).await.unwrap();
+        assert!(content.contains("GitHub"));
+        assert!(content.contains("Corporate SSO"));
+        assert!(content.contains(r#"value="github""#));
+        assert!(content.contains(r#"value="unlink""#));
+        assert!(content.contains(r#"value="link""#));
+    }
+
+    #[tokio::test]
+    async fn render_account_delete() {
+        let config = TemplatesConfig {
+            path: None,
+            builtin: true,
+            ..TemplatesConfig::default()
+        };
+
+        let templates = Templates::load_from_config(&config).await.unwrap();
+        let session = BrowserSession::<()>::samples().remove(0);
+        let mut samples = AccountDeleteContext::sample();
+
+        let ctx = samples
+            .remove(1)
+            .with_session(session.clone())
+            .with_csrf("csrf");
+        let content = templates.render_account_delete(&ctx).await.unwrap();
+        assert!(content.contains(r#"name="confirmation""#));
+        assert!(content.contains(r#"value="schedule""#));
+        assert!(content.contains("This is not your username"));
+        assert!(content.contains(&session.user.username));
+
+        let ctx = samples.remove(1).with_session(session).with_csrf("csrf");
+        let content = templates.render_account_delete(&ctx).await.unwrap();
+        assert!(content.contains(r#"value="cancel""#));
+        assert!(!content.contains(r#"value="schedule""#));
+    }
 }
@@ -79,11 +79,28 @@ macro_rules! register_templates {
                     $(< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)?
                     (&self, context: &$param)
                 -> Result<String, TemplateError> {
-                    let ctx = Context::from_serialize(context)
+                    let mut ctx = Context::from_serialize(context)
                         .map_err(|source| TemplateError::Context { template: $template, source })?;
 
-                    self.tera.read().await.render($template, &ctx)
-                        .map_err(|source| TemplateError::Render { template: $template, source })
+                    // The lock is not held across an await point
+                    let state = self.state.read().unwrap_or_else(::std::sync::PoisonError::into_inner);
+
+                    // Contexts built with `with_app_context` take precedence
+                    if !ctx.contains_key("app") {
+                        ctx.insert("app", &state.app);
+                    }
+
+                    // Contexts not built with `with_locale` are rendered in the default locale
+                    if !ctx.contains_key("locale") {
+                        ctx.insert("locale", $crate::DEFAULT_LOCALE);
+                    }
+
+                    let start = ::std::time::Instant::now();
+                    let res = state.tera.render($template, &ctx);
+                    drop(state);
+                    $crate::metrics::record_render($template, start.elapsed());
+
+                    res.map_err(|source| TemplateError::Render { template: $template, source })
                 }
             )*
         }
@@ -103,7 +120,7 @@ macro_rules! register_templates {
                     let name = $template;
                     for sample in samples {
                         let context = serde_json::to_value(&sample)?;
-                        ::tracing::info!(name, %context, "Rendering template");
+                        ::tracing::debug!(name, %context, "Rendering template");
                         templates. $name (&sample)
                             .await
                             .with_context(|| format!("Failed to render template {:?} with context {}", name, context))?;
@@ -0,0 +1,35 @@
+// Copyright 2022 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics recorded when rendering templates
+
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use opentelemetry::{global, metrics::ValueRecorder, KeyValue};
+
+static TEMPLATE_RENDER_DURATION: Lazy<ValueRecorder<f64>> = Lazy::new(|| {
+    global::meter("mas-templates")
+        .f64_value_recorder("template_render_duration_seconds")
+        .with_description("Time spent rendering a template, in seconds")
+        .init()
+});
+
+/// Record how long a template took to render
+pub(crate) fn record_render(template: &'static str, duration: Duration) {
+    TEMPLATE_RENDER_DURATION.record(
+        duration.as_secs_f64(),
+        &[KeyValue::new("template", template)],
+    );
+}
@@ -0,0 +1,40 @@
+# Copyright 2022 The Matrix.org Foundation C.I.C.
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# Shared
+never = Never
+manage = Manage
+
+# pages/account/index.html
+account-title = Manage my account
+account-username = Your username
+account-unique-identifier = Unique identifier
+account-active-sessions = Active sessions
+account-last-login = Last login
+account-primary-email = Primary email
+account-two-factor = Two-factor authentication
+account-change-password = Change password
+account-linked-accounts = Linked accounts
+account-export-data = Export my data
+account-delete = Delete my account
+account-current-session = Current session
+account-started-at = Started at
+account-last-authentication = Last authentication
+account-revalidate = Revalidate
+account-signed-in-clients = Signed-in clients
+account-active-client-sessions = Active client sessions
+account-sign-out-everywhere = Sign out everywhere
+account-emails = Emails
+account-email-confirmed = Confirmed
+account-email-unconfirmed = Unconfirmed
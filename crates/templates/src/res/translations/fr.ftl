@@ -0,0 +1,40 @@
+# Copyright 2022 The Matrix.org Foundation C.I.C.
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# Shared
+never = Jamais
+manage = Gérer
+
+# pages/account/index.html
+account-title = Gérer mon compte
+account-username = Votre nom d'utilisateur
+account-unique-identifier = Identifiant unique
+account-active-sessions = Sessions actives
+account-last-login = Dernière connexion
+account-primary-email = Adresse e-mail principale
+account-two-factor = Authentification à deux facteurs
+account-change-password = Changer le mot de passe
+account-linked-accounts = Comptes liés
+account-export-data = Exporter mes données
+account-delete = Supprimer mon compte
+account-current-session = Session en cours
+account-started-at = Commencée le
+account-last-authentication = Dernière authentification
+account-revalidate = Revalider
+account-signed-in-clients = Clients connectés
+account-active-client-sessions = Sessions client actives
+account-sign-out-everywhere = Se déconnecter partout
+account-emails = Adresses e-mail
+account-email-confirmed = Confirmée
+account-email-unconfirmed = Non confirmée